@@ -0,0 +1,368 @@
+// Address encodings: base58check for P2PKH/P2SH and bech32/bech32m (BIP173, BIP350)
+// for witness programs.
+use crate::helper::hash256;
+use crate::network::Network;
+use crate::script::{classify, witness_program, ScriptType};
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+impl Network {
+    pub fn p2pkh_prefix(&self) -> u8 {
+        match self {
+            Network::Mainnet => 0x00,
+            _ => 0x6f,
+        }
+    }
+
+    pub fn p2sh_prefix(&self) -> u8 {
+        match self {
+            Network::Mainnet => 0x05,
+            _ => 0xc4,
+        }
+    }
+
+    pub fn bech32_hrp(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "bc",
+            Network::Testnet | Network::Signet => "tb",
+            Network::Regtest => "bcrt",
+        }
+    }
+}
+
+pub fn encode_base58(data: &[u8]) -> String {
+    let zeros = data.iter().take_while(|b| **b == 0).count();
+    // Repeated division by 58 of the big endian number, least significant digit first.
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in &data[zeros..] {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let mut result = "1".repeat(zeros);
+    result.extend(
+        digits
+            .iter()
+            .rev()
+            .map(|d| BASE58_ALPHABET[*d as usize] as char),
+    );
+    result
+}
+
+pub fn encode_base58_checksum(data: &[u8]) -> String {
+    let mut payload = data.to_vec();
+    payload.extend_from_slice(&hash256(data)[..4]);
+    encode_base58(&payload)
+}
+
+pub fn decode_base58(text: &str) -> Option<Vec<u8>> {
+    let zeros = text.bytes().take_while(|c| *c == b'1').count();
+    // Repeated multiplication by 58, least significant byte first.
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in text[zeros..].bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let mut result = vec![0; zeros];
+    result.extend(bytes.iter().rev());
+    Some(result)
+}
+
+// The payload of a base58check string whose checksum matches.
+pub fn decode_base58_checksum(text: &str) -> Option<Vec<u8>> {
+    let mut payload = decode_base58(text)?;
+    if payload.len() < 4 {
+        return None;
+    }
+    let checksum = payload.split_off(payload.len() - 4);
+    (hash256(&payload)[..4] == checksum[..]).then_some(payload)
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for value in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut result: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    result.push(0);
+    result.extend(hrp.bytes().map(|b| b & 31));
+    result
+}
+
+// Regroups 8 bit bytes into 5 bit groups, padding the last one with zeros.
+fn to_base32(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for byte in data {
+        acc = (acc << 8 | *byte as u32) & 0xfff;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push((acc >> bits & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        result.push((acc << (5 - bits) & 31) as u8);
+    }
+    result
+}
+
+// Regroups 5 bit groups into bytes, None if the padding is not zeros of fewer than
+// five bits.
+fn from_base32(data: &[u8]) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for value in data {
+        acc = (acc << 5 | *value as u32) & 0xfff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((acc >> bits) as u8);
+        }
+    }
+    (bits < 5 && acc & ((1 << bits) - 1) == 0).then_some(result)
+}
+
+// Version 0 programs use bech32, later versions bech32m.
+pub fn encode_segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(to_base32(program));
+    let constant = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    values.extend([0u8; 6]);
+    let polymod = bech32_polymod(&values) ^ constant;
+    let checksum = (0..6).map(|i| (polymod >> (5 * (5 - i)) & 31) as u8);
+
+    let mut result = format!("{hrp}1");
+    result.extend(
+        data.into_iter()
+            .chain(checksum)
+            .map(|d| BECH32_CHARSET[d as usize] as char),
+    );
+    result
+}
+
+// The witness version and program of a segwit address of hrp, checking its checksum
+// is the one its version calls for.
+pub fn decode_segwit_address(hrp: &str, address: &str) -> Option<(u8, Vec<u8>)> {
+    if address.len() > 90 || address.to_lowercase() != address && address.to_uppercase() != address
+    {
+        return None;
+    }
+    let address = address.to_lowercase();
+    let (address_hrp, data) = address.rsplit_once('1')?;
+    if address_hrp != hrp || data.len() < 7 {
+        return None;
+    }
+    let data = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|b| *b == c).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    let version = data[0];
+    let constant = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    if version > 16 || bech32_polymod(&values) != constant {
+        return None;
+    }
+    let program = from_base32(&data[1..data.len() - 6])?;
+    let valid = match version {
+        0 => program.len() == 20 || program.len() == 32,
+        _ => (2..=40).contains(&program.len()),
+    };
+    valid.then_some((version, program))
+}
+
+// The script_pubkey paying address on network, None for an address of another
+// network or not valid.
+pub fn script_from_address(address: &str, network: Network) -> Option<Vec<u8>> {
+    if let Some((version, program)) = decode_segwit_address(network.bech32_hrp(), address) {
+        let mut script = match version {
+            0 => vec![0],
+            _ => vec![0x50 + version],
+        };
+        script.push(program.len() as u8);
+        script.extend(program);
+        return Some(script);
+    }
+    let payload = decode_base58_checksum(address)?;
+    let hash = payload.get(1..).filter(|hash| hash.len() == 20)?;
+    let mut script = match payload[0] {
+        prefix if prefix == network.p2pkh_prefix() => vec![0x76, 0xa9, 0x14],
+        prefix if prefix == network.p2sh_prefix() => vec![0xa9, 0x14],
+        _ => return None,
+    };
+    script.extend_from_slice(hash);
+    match payload[0] == network.p2pkh_prefix() {
+        true => script.extend([0x88, 0xac]),
+        false => script.push(0x87),
+    }
+    Some(script)
+}
+
+// Address paid by script_pubkey, None for scripts without an address form (bare
+// public keys and multisig, OP_RETURN, non-standard scripts).
+pub fn address_from_script(script_pubkey: &[u8], network: Network) -> Option<String> {
+    let script_type = classify(script_pubkey);
+    let (prefix, hash) = match script_type {
+        ScriptType::PubKeyHash(hash) => (network.p2pkh_prefix(), hash),
+        ScriptType::ScriptHash(hash) => (network.p2sh_prefix(), hash),
+        _ if script_type.is_witness() => {
+            let (version, program) = witness_program(script_pubkey)?;
+            return Some(encode_segwit_address(
+                network.bech32_hrp(),
+                version,
+                program,
+            ));
+        }
+        _ => return None,
+    };
+    let mut payload = vec![prefix];
+    payload.extend_from_slice(&hash);
+    Some(encode_base58_checksum(&payload))
+}
+
+#[cfg(test)]
+mod address_tests {
+    use super::*;
+
+    #[test]
+    fn test_base58() {
+        assert_eq!(
+            encode_base58(
+                &hex::decode("7c076ff316692a3d7eb3c3bb0f8b1488cf72e1afcd929e29307032997a838a3d")
+                    .unwrap()
+            ),
+            "9MA8fRQrT4u8Zj8ZRd6MAiiyaxb2Y1CMpvVkHQu5hVM6"
+        );
+        assert_eq!(encode_base58(&[0, 0, 1]), "112");
+        assert_eq!(encode_base58(&[]), "");
+        assert_eq!(decode_base58("112").unwrap(), [0, 0, 1]);
+        assert_eq!(decode_base58("0OIl"), None);
+    }
+
+    #[test]
+    fn test_p2pkh_and_p2sh_addresses() {
+        let h160 = hex::decode("74d691da1574e6b3c192ecfb52cc8984ee7b6c56").unwrap();
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend(&h160);
+        p2pkh.extend([0x88, 0xac]);
+        assert_eq!(
+            address_from_script(&p2pkh, Network::Mainnet).unwrap(),
+            "1BenRpVUFK65JFWcQSuHnJKzc4M8ZP8Eqa"
+        );
+        assert_eq!(
+            address_from_script(&p2pkh, Network::Testnet).unwrap(),
+            "mrAjisaT4LXL5MzE81sfcDYKU3wqWSvf9q"
+        );
+
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend(&h160);
+        p2sh.push(0x87);
+        assert_eq!(
+            address_from_script(&p2sh, Network::Mainnet).unwrap(),
+            "3CLoMMyuoDQTPRD3XYZtCvgvkadrAdvdXh"
+        );
+        assert_eq!(
+            address_from_script(&p2sh, Network::Testnet).unwrap(),
+            "2N3u1R6uwQfuobCqbCgBkpsgBxvr1tZpe7B"
+        );
+
+        // Decoding gives the scripts back, for their own network only.
+        let address = "1BenRpVUFK65JFWcQSuHnJKzc4M8ZP8Eqa";
+        assert_eq!(
+            script_from_address(address, Network::Mainnet).unwrap(),
+            p2pkh
+        );
+        assert_eq!(script_from_address(address, Network::Testnet), None);
+        let address = "2N3u1R6uwQfuobCqbCgBkpsgBxvr1tZpe7B";
+        assert_eq!(
+            script_from_address(address, Network::Testnet).unwrap(),
+            p2sh
+        );
+        assert_eq!(
+            script_from_address("1BenRpVUFK65JFWcQSuHnJKzc4M8ZP8Eqb", Network::Mainnet),
+            None
+        );
+    }
+
+    // BIP173 and BIP350 test vectors.
+    #[test]
+    fn test_segwit_addresses() {
+        let p2wpkh = hex::decode("0014751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+        assert_eq!(
+            address_from_script(&p2wpkh, Network::Mainnet).unwrap(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        let p2wsh =
+            hex::decode("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262")
+                .unwrap();
+        assert_eq!(
+            address_from_script(&p2wsh, Network::Testnet).unwrap(),
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"
+        );
+        let p2tr =
+            hex::decode("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        assert_eq!(
+            address_from_script(&p2tr, Network::Mainnet).unwrap(),
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0"
+        );
+        assert_eq!(
+            address_from_script(&[0x6a, 0x01, 0x00], Network::Mainnet),
+            None
+        );
+
+        for (script, network) in [
+            (p2wpkh, Network::Mainnet),
+            (p2wsh, Network::Testnet),
+            (p2tr, Network::Mainnet),
+        ] {
+            let address = address_from_script(&script, network).unwrap();
+            assert_eq!(script_from_address(&address, network).unwrap(), script);
+            let upper = address.to_uppercase();
+            assert_eq!(script_from_address(&upper, network).unwrap(), script);
+        }
+        // A version 1 program with a bech32 checksum is not valid.
+        let address = "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7k7grplx";
+        assert_eq!(script_from_address(address, Network::Mainnet), None);
+    }
+}
@@ -0,0 +1,78 @@
+// Calls a method of the node's JSON-RPC, as bitcoin-cli: the method and its
+// arguments follow the options, each argument sent as the JSON it parses as or else
+// as a string. Options are read as the node reads them, from the command line,
+// BITCOIN_<NAME> variables and bitcoin.conf, so both find each other with the same
+// settings: the network, -rpcconnect=<host> and -rpcport=<port>, -rpcuser=<user>
+// with -rpcpassword=<password> or else the cookie at -rpccookiefile=<file> in the
+// data directory, and the -rpcwallet=<name> of wallet methods.
+use bitcoin::config::Config;
+use bitcoin::rpc::server::COOKIE_FILE;
+use bitcoin::rpc::RpcClient;
+use bitcoin::types::errors::Errors;
+use serde_json::Value;
+use std::process::ExitCode;
+
+const OPTIONS: &[&str] = &[
+    "rpcconnect",
+    "rpccookiefile",
+    "rpcpassword",
+    "rpcport",
+    "rpcuser",
+    "rpcwallet",
+];
+
+fn client(config: &Config) -> Result<RpcClient, Errors> {
+    let network = config.network()?;
+    let host = config.get("rpcconnect").unwrap_or("127.0.0.1");
+    let port = config
+        .get_parsed("rpcport")?
+        .unwrap_or_else(|| network.default_rpc_port());
+    let mut url = format!("http://{host}:{port}");
+    if let Some(wallet) = config.get("rpcwallet") {
+        url.push_str(&format!("/wallet/{wallet}"));
+    }
+    match (config.get("rpcuser"), config.get("rpcpassword")) {
+        (Some(user), Some(password)) => Ok(RpcClient::new(&url, user, password)),
+        _ => {
+            let cookie = config.get("rpccookiefile").unwrap_or(COOKIE_FILE);
+            RpcClient::with_cookie(&url, config.datadir(network).join(cookie))
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let (config, args) = match Config::load(std::env::args().skip(1), std::env::vars(), OPTIONS) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some((method, params)) = args.split_first() else {
+        eprintln!("Usage: cli [options] <method> [params]");
+        return ExitCode::FAILURE;
+    };
+    let params = params
+        .iter()
+        .map(|param| serde_json::from_str(param).unwrap_or_else(|_| Value::from(param.as_str())))
+        .collect();
+    match client(&config).and_then(|client| client.call(method, params)) {
+        Ok(Value::Null) => ExitCode::SUCCESS,
+        Ok(Value::String(result)) => {
+            println!("{result}");
+            ExitCode::SUCCESS
+        }
+        Ok(result) => {
+            println!("{}", serde_json::to_string_pretty(&result).unwrap());
+            ExitCode::SUCCESS
+        }
+        Err(Errors::Rpc(e)) => {
+            eprintln!("error code: {}\nerror message:\n{e}", e.code());
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
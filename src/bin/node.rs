@@ -0,0 +1,433 @@
+// Runs a full node until interrupted. Options follow bitcoind's, read from the
+// command line, BITCOIN_<NAME> environment variables and bitcoin.conf as the config
+// module layers them: -testnet, -signet or -regtest pick the network, -datadir=<dir>
+// where it is kept, -connect=<host:port> peers to connect to instead of those found,
+// -maxconnections=<n> how many and -blocksonly turns transaction relay off.
+// -v2transport encrypts connections to peers speaking BIP324's v2 transport, and
+// -proxy=<ip:port> makes them through a SOCKS5 proxy such as Tor's, a circuit each
+// unless -proxyrandomize=0. The mempool holds -maxmempool=<MB> of transactions for
+// -mempoolexpiry=<hours>, paying -minrelaytxfee=<BTC/kvB>, replacing any with
+// -mempoolfullrbf, and is saved to the data directory on shutdown to be taken in
+// again at startup. JSON-RPC is served on localhost at -rpcport=<port>, to
+// -rpcuser=<user> with -rpcpassword=<password> or else to whoever reads the cookie
+// written to the data directory. -rest serves the REST endpoints on the same port.
+// -zmqpub<topic>=tcp://<host:port> publishes hashblock, hashtx, rawblock or rawtx
+// notifications there over ZMQ. -txindex and -addressindex keep the indexes
+// getrawtransaction and the address methods look transactions up in.
+// -metrics=<host:port> serves Prometheus metrics at /metrics there. -mine=<address>
+// mines a block paying there every ten seconds, on regtest only. -stratum=<address>
+// serves stratum to miners on -stratumport=<port>, paying what they find there, on
+// testnet and regtest. Events are logged to stderr at info level, -debug=<subsystem>
+// logging one of net, validation, mempool, rpc, mining, wallet, zmq or node at debug
+// level and -debug alone all of them, -loglevel=<level> or
+// -loglevel=<subsystem>:<level> setting any level, and -logjson writing each event
+// as a JSON object. Errors in the options are printed as they are, before logging
+// starts.
+use bitcoin::address::script_from_address;
+use bitcoin::config::Config;
+use bitcoin::logging::{LogFormat, Logger, SUBSYSTEMS};
+use bitcoin::mempool::Mempool;
+use bitcoin::metrics::serve_metrics;
+use bitcoin::mining::{
+    mine, serve_stratum, CpuMiner, Stratum, DEFAULT_MINE_INTERVAL, DEFAULT_STRATUM_PORT,
+};
+use bitcoin::network::Network;
+use bitcoin::node::{run, Node, DEFAULT_MAX_OUTBOUND};
+use bitcoin::notify::{publish, Topic};
+use bitcoin::p2p::version::{VersionMessage, NODE_NETWORK, NODE_WITNESS};
+use bitcoin::p2p::{PeerManager, ProxyConfig, Socks5Proxy};
+use bitcoin::policy::FeeRate;
+use bitcoin::rpc::{serve, RpcAuth, RpcServer};
+use bitcoin::types::errors::Errors;
+use bitcoin::validation::COIN;
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info};
+
+// Set by SIGINT and SIGTERM.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+// Beyond those of every binary, the zmqpub ones for each topic.
+const OPTIONS: &[&str] = &[
+    "addressindex",
+    "blocksonly",
+    "connect",
+    "debug",
+    "loglevel",
+    "logjson",
+    "maxconnections",
+    "maxmempool",
+    "mempoolexpiry",
+    "mempoolfullrbf",
+    "metrics",
+    "mine",
+    "minrelaytxfee",
+    "proxy",
+    "proxyrandomize",
+    "rest",
+    "rpcpassword",
+    "rpcport",
+    "rpcuser",
+    "stratum",
+    "stratumport",
+    "txindex",
+    "v2transport",
+    "zmqpubhashblock",
+    "zmqpubhashtx",
+    "zmqpubrawblock",
+    "zmqpubrawtx",
+];
+
+struct Options {
+    network: Network,
+    datadir: PathBuf,
+    // Resolved once the network is known, for its port.
+    connect: Vec<String>,
+    max_outbound: usize,
+    blocks_only: bool,
+    v2_transport: bool,
+    proxies: ProxyConfig,
+    mempool: Mempool,
+    rpc_port: u16,
+    rpc_user: Option<String>,
+    rpc_password: Option<String>,
+    rest: bool,
+    tx_index: bool,
+    address_index: bool,
+    // Addresses to publish each topic on, tcp:// left out.
+    zmq: Vec<(Topic, String)>,
+    metrics: Option<String>,
+    // Checked once the network is known.
+    mine: Option<String>,
+    stratum: Option<String>,
+    stratum_port: u16,
+}
+
+// As bitcoind, -debug=1 and -debug=all are every subsystem, -debug=0 none.
+fn parse_logger(config: &Config) -> Result<Logger, Errors> {
+    let mut logger = Logger::new(LevelFilter::INFO);
+    for subsystem in config.get_all("debug") {
+        match subsystem.as_str() {
+            "0" | "none" => {}
+            "" | "1" | "all" => logger = logger.with_levels("debug")?,
+            subsystem if SUBSYSTEMS.contains(&subsystem) => {
+                logger = logger.with_level(subsystem, LevelFilter::DEBUG);
+            }
+            subsystem => {
+                return Err(Errors::InvalidConfig(format!(
+                    "unknown -debug subsystem: {subsystem}"
+                )))
+            }
+        }
+    }
+    for spec in config.get_all("loglevel") {
+        logger = logger.with_levels(spec)?;
+    }
+    if config.get_bool("logjson") {
+        logger = logger.with_format(LogFormat::Json);
+    }
+    Ok(logger)
+}
+
+fn parse_options(config: &Config) -> Result<Options, Errors> {
+    let network = config.network()?;
+    let mut mempool = Mempool::new().with_full_rbf(config.get_bool("mempoolfullrbf"));
+    if let Some(megabytes) = config.get_parsed::<usize>("maxmempool")? {
+        mempool = mempool.with_max_size(megabytes * 1_000_000);
+    }
+    if let Some(hours) = config.get_parsed::<u64>("mempoolexpiry")? {
+        mempool = mempool.with_expiry(Duration::from_secs(hours * 60 * 60));
+    }
+    if let Some(btc_per_kvb) = config.get_parsed::<f64>("minrelaytxfee")? {
+        let sat_per_kvb = (btc_per_kvb * COIN as f64).round() as u64;
+        mempool = mempool.with_min_relay_feerate(FeeRate::from_sat_per_kvb(sat_per_kvb));
+    }
+    let mut proxies = ProxyConfig::new();
+    if let Some(address) = config.get_parsed::<SocketAddr>("proxy")? {
+        let mut proxy = Socks5Proxy::new(address);
+        if config.get("proxyrandomize").is_none() || config.get_bool("proxyrandomize") {
+            proxy = proxy.with_randomized_credentials();
+        }
+        proxies = proxies.with_proxy(proxy);
+    }
+    let mut zmq = Vec::new();
+    for topic in Topic::ALL {
+        let name = format!("zmqpub{}", topic.name());
+        for value in config.get_all(&name) {
+            let address = value
+                .strip_prefix("tcp://")
+                .ok_or_else(|| Errors::InvalidConfig(format!("-{name} takes a tcp:// address")))?;
+            zmq.push((topic, address.to_string()));
+        }
+    }
+    Ok(Options {
+        network,
+        datadir: config.datadir(network),
+        connect: config.get_all("connect").to_vec(),
+        max_outbound: config
+            .get_parsed("maxconnections")?
+            .unwrap_or(DEFAULT_MAX_OUTBOUND),
+        blocks_only: config.get_bool("blocksonly"),
+        v2_transport: config.get_bool("v2transport"),
+        proxies,
+        mempool,
+        rpc_port: config
+            .get_parsed("rpcport")?
+            .unwrap_or_else(|| network.default_rpc_port()),
+        rpc_user: config.get("rpcuser").map(str::to_string),
+        rpc_password: config.get("rpcpassword").map(str::to_string),
+        rest: config.get_bool("rest"),
+        tx_index: config.get_bool("txindex"),
+        address_index: config.get_bool("addressindex"),
+        zmq,
+        metrics: config.get("metrics").map(str::to_string),
+        mine: config.get("mine").map(str::to_string),
+        stratum: config.get("stratum").map(str::to_string),
+        stratum_port: config
+            .get_parsed("stratumport")?
+            .unwrap_or(DEFAULT_STRATUM_PORT),
+    })
+}
+
+// host or host:port, on the network's port by default.
+fn resolve(peer: &str, network: Network) -> Result<SocketAddr, String> {
+    let resolved = if peer.contains(':') {
+        peer.to_socket_addrs()
+    } else {
+        (peer, network.default_port()).to_socket_addrs()
+    };
+    resolved
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| format!("cannot resolve -connect: {peer}"))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let loaded = Config::load(std::env::args().skip(1), std::env::vars(), OPTIONS);
+    let (logger, options) = match loaded.and_then(|(config, rest)| match rest.first() {
+        Some(arg) => Err(Errors::InvalidConfig(format!("unexpected argument: {arg}"))),
+        None => Ok((parse_logger(&config)?, parse_options(&config)?)),
+    }) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = logger.init() {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    let mut connect = Vec::new();
+    for peer in &options.connect {
+        match resolve(peer, options.network) {
+            Ok(address) => connect.push(address),
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let miner = match &options.mine {
+        Some(_) if options.network != Network::Regtest => {
+            eprintln!("-mine is only available on regtest");
+            return ExitCode::FAILURE;
+        }
+        Some(address) => match script_from_address(address, options.network) {
+            Some(script) => Some(CpuMiner::new(script.into())),
+            None => {
+                eprintln!("invalid -mine address: {address}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let stratum = match &options.stratum {
+        Some(_) if !matches!(options.network, Network::Testnet | Network::Regtest) => {
+            eprintln!("-stratum is only available on testnet and regtest");
+            return ExitCode::FAILURE;
+        }
+        Some(address) => match script_from_address(address, options.network) {
+            Some(script) => Some(Stratum::new(script.into())),
+            None => {
+                eprintln!("invalid -stratum address: {address}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let datadir = options.datadir;
+
+    info!(target: "node", "Loading the chain from {}", datadir.display());
+    let mut node = match Node::open(options.network, &datadir) {
+        Ok(node) => node
+            .with_mempool(options.mempool)
+            .with_connect(connect.clone())
+            .with_max_outbound(options.max_outbound)
+            .with_blocks_only(options.blocks_only)
+            .with_tx_index(options.tx_index)
+            .with_address_index(options.address_index),
+        Err(e) => {
+            error!(target: "node", "Cannot open {}: {e}", datadir.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    info!(target: "node", "Chain tip at height {}", node.chain().height());
+    if let Err(e) = node.load_mempool() {
+        error!(target: "node", "Cannot load the mempool: {e}");
+    }
+    if connect.is_empty() && node.addrman().is_empty() {
+        info!(target: "node", "Found {} addresses from DNS seeds", node.seed());
+    }
+
+    let version = VersionMessage::new(NODE_NETWORK | NODE_WITNESS, node.chain().height() as i32);
+    let mut manager = PeerManager::new(options.network)
+        .with_version(version)
+        .with_max_peers(options.max_outbound.max(connect.len()))
+        .with_proxies(options.proxies);
+    if options.blocks_only {
+        manager = manager.with_blocks_only();
+    }
+    if options.v2_transport {
+        manager = manager.with_v2_transport();
+    }
+
+    // Safe as the handler only stores to an atomic.
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as *const () as libc::sighandler_t);
+    }
+    let auth = match (options.rpc_user, options.rpc_password) {
+        (Some(user), Some(password)) => RpcAuth::with_user(&user, &password),
+        (None, None) => match RpcAuth::cookie(&datadir) {
+            Ok(auth) => auth,
+            Err(e) => {
+                error!(target: "node", "Cannot write the RPC cookie: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => {
+            error!(target: "node", "-rpcuser and -rpcpassword go together");
+            return ExitCode::FAILURE;
+        }
+    };
+    let rpc_port = options.rpc_port;
+    let listener = match tokio::net::TcpListener::bind(("127.0.0.1", rpc_port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(target: "node", "Cannot listen for RPC on port {rpc_port}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let node = Arc::new(Mutex::new(node));
+    let mut server = RpcServer::new(node.clone(), manager.clone(), auth);
+    if options.rest {
+        server = server.with_rest();
+    }
+    let server = Arc::new(server);
+    tokio::spawn(serve(listener, server.clone()));
+    info!(target: "node", "Serving JSON-RPC on 127.0.0.1:{rpc_port}");
+
+    // Topics on the same address share a socket.
+    let mut zmq: BTreeMap<&str, Vec<Topic>> = BTreeMap::new();
+    for (topic, address) in &options.zmq {
+        zmq.entry(address).or_default().push(*topic);
+    }
+    for (address, topics) in zmq {
+        let listener = match tokio::net::TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(target: "node", "Cannot publish ZMQ notifications on {address}: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let notifications = node.lock().unwrap().subscribe();
+        tokio::spawn(publish(listener, notifications, topics));
+    }
+
+    if let Some(address) = &options.metrics {
+        let listener = match tokio::net::TcpListener::bind(address.as_str()).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(target: "node", "Cannot serve metrics on {address}: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        tokio::spawn(serve_metrics(listener, node.clone(), manager.clone()));
+        info!(target: "node", "Serving metrics on {address}");
+    }
+
+    let stopped = server.clone();
+    let shutdown = async move {
+        let signalled = async {
+            while !STOP.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::select! {
+            _ = signalled => {}
+            _ = stopped.stopped() => {}
+        }
+        info!(target: "node", "Shutting down");
+    };
+    if let Some(miner) = miner {
+        let (node, manager) = (node.clone(), manager.clone());
+        tokio::spawn(async move {
+            let forever = std::future::pending();
+            if let Err(e) = mine(node, manager, miner, DEFAULT_MINE_INTERVAL, forever).await {
+                error!(target: "node", "Stopped mining: {e}");
+            }
+        });
+    }
+    if let Some(stratum) = stratum {
+        let port = options.stratum_port;
+        let listener = match tokio::net::TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(target: "node", "Cannot listen for stratum on port {port}: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        tokio::spawn(serve_stratum(
+            listener,
+            node.clone(),
+            manager.clone(),
+            stratum,
+        ));
+        info!(target: "node", "Serving stratum on port {port}");
+    }
+    let status = node.clone();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            ticks.tick().await;
+            let node = status.lock().unwrap();
+            info!(
+                target: "node",
+                height = node.chain().height(), headers = node.chain().headers().height(),
+                mempool = node.mempool().len(),
+                "Status"
+            );
+        }
+    });
+    let result = run(node, manager, shutdown).await;
+    server.auth().remove_cookie();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!(target: "node", "Cannot save the node: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,487 @@
+// BIP152 compact blocks. A block is announced with its header and a 6 byte short id
+// per transaction, so a peer can rebuild it from its mempool and only ask for the
+// transactions it doesn't have. Only version 2 is supported, whose short ids commit
+// to wtxids.
+use super::{Block, BlockHeader};
+use crate::helper::{encode_varint, read_array, read_varint, sha256, siphash24};
+use crate::transaction::Transaction;
+use crate::types::errors::Errors;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+pub const SHORT_ID_SIZE: usize = 6;
+
+// More transactions than the smallest ones fitting in a block can't be announced
+// (MAX_BLOCK_WEIGHT / MIN_SERIALIZABLE_TRANSACTION_WEIGHT in Bitcoin Core).
+const MAX_COMPACT_TRANSACTIONS: usize = 4_000_000 / 40;
+
+// A transaction sent along with the short ids, at its position in the block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefilledTransaction {
+    pub index: u16,
+    pub tx: Transaction,
+}
+
+// Indexes are written as the difference from the previous one minus one, the
+// first one as is.
+fn read_index(reader: &mut impl Read, previous: Option<u16>) -> Result<u16, Errors> {
+    let delta = read_varint(reader)?;
+    let index = match previous {
+        Some(previous) => (previous as u64).saturating_add(delta).saturating_add(1),
+        None => delta,
+    };
+    u16::try_from(index).map_err(|_| Errors::InvalidCompactBlock("indexes overflow 16 bits"))
+}
+
+fn short_id(k0: u64, k1: u64, wtxid: &[u8; 32]) -> u64 {
+    siphash24(k0, k1, wtxid) & 0xffff_ffff_ffff
+}
+
+fn serialize_index(result: &mut Vec<u8>, index: u16, previous: Option<u16>) {
+    let delta = match previous {
+        Some(previous) => index - previous - 1,
+        None => index,
+    };
+    result.extend(encode_varint(delta as u64));
+}
+
+// Payload of the cmpctblock message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderAndShortIds {
+    pub header: BlockHeader,
+    // Picked by the sender so short ids collide differently for every announcement.
+    pub nonce: u64,
+    pub short_ids: Vec<u64>,
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+impl HeaderAndShortIds {
+    // Announces block with only its coinbase prefilled, which a peer can never have.
+    pub fn from_block(block: &Block, nonce: u64) -> Self {
+        let mut compact = HeaderAndShortIds {
+            header: block.header,
+            nonce,
+            short_ids: Vec::new(),
+            prefilled: Vec::new(),
+        };
+        if let Some(coinbase) = block.coinbase() {
+            compact.prefilled.push(PrefilledTransaction {
+                index: 0,
+                tx: coinbase.clone(),
+            });
+        }
+        let (k0, k1) = compact.short_id_keys();
+        compact.short_ids = block
+            .iter()
+            .skip(1)
+            .map(|tx| short_id(k0, k1, &tx.wtxid()))
+            .collect();
+        compact
+    }
+
+    // SipHash keys: the first two little endian words of sha256(header || nonce).
+    pub fn short_id_keys(&self) -> (u64, u64) {
+        let mut data = self.header.serialize().to_vec();
+        data.extend(self.nonce.to_le_bytes());
+        let hash = sha256(&data);
+        (
+            u64::from_le_bytes(hash[..8].try_into().unwrap()),
+            u64::from_le_bytes(hash[8..16].try_into().unwrap()),
+        )
+    }
+
+    // The low 6 bytes of the wtxid's SipHash.
+    pub fn short_id(&self, wtxid: &[u8; 32]) -> u64 {
+        let (k0, k1) = self.short_id_keys();
+        short_id(k0, k1, wtxid)
+    }
+
+    // Number of transactions in the announced block.
+    pub fn block_tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let header = BlockHeader::parse(reader)?;
+        let nonce = u64::from_le_bytes(read_array(reader)?);
+        let count = read_varint(reader)?;
+        if count as usize > MAX_COMPACT_TRANSACTIONS {
+            return Err(Errors::InvalidCompactBlock("too many transactions"));
+        }
+        let mut short_ids = Vec::new();
+        for _ in 0..count {
+            let mut bytes = [0u8; 8];
+            bytes[..SHORT_ID_SIZE].copy_from_slice(&read_array::<SHORT_ID_SIZE>(reader)?);
+            short_ids.push(u64::from_le_bytes(bytes));
+        }
+        let count = read_varint(reader)?;
+        if count as usize > MAX_COMPACT_TRANSACTIONS {
+            return Err(Errors::InvalidCompactBlock("too many transactions"));
+        }
+        let mut prefilled = Vec::new();
+        let mut previous: Option<u16> = None;
+        for _ in 0..count {
+            let index = read_index(reader, previous)?;
+            previous = Some(index);
+            prefilled.push(PrefilledTransaction {
+                index,
+                tx: Transaction::parse(reader)?,
+            });
+        }
+        Ok(HeaderAndShortIds {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.header.serialize().to_vec();
+        result.extend(self.nonce.to_le_bytes());
+        result.extend(encode_varint(self.short_ids.len() as u64));
+        for short_id in &self.short_ids {
+            result.extend(&short_id.to_le_bytes()[..SHORT_ID_SIZE]);
+        }
+        result.extend(encode_varint(self.prefilled.len() as u64));
+        let mut previous = None;
+        for prefilled in &self.prefilled {
+            serialize_index(&mut result, prefilled.index, previous);
+            result.extend(prefilled.tx.serialize());
+            previous = Some(prefilled.index);
+        }
+        result
+    }
+}
+
+// Payload of the getblocktxn message: the transactions of a compact block that
+// couldn't be found, by increasing index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTransactionsRequest {
+    pub block_hash: [u8; 32],
+    pub indexes: Vec<u16>,
+}
+
+impl BlockTransactionsRequest {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let block_hash = read_array(reader)?;
+        let count = read_varint(reader)?;
+        if count as usize > MAX_COMPACT_TRANSACTIONS {
+            return Err(Errors::InvalidCompactBlock("too many transactions"));
+        }
+        let mut indexes = Vec::new();
+        for _ in 0..count {
+            let index = read_index(reader, indexes.last().copied())?;
+            indexes.push(index);
+        }
+        Ok(BlockTransactionsRequest {
+            block_hash,
+            indexes,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.block_hash.to_vec();
+        result.extend(encode_varint(self.indexes.len() as u64));
+        let mut previous = None;
+        for &index in &self.indexes {
+            serialize_index(&mut result, index, previous);
+            previous = Some(index);
+        }
+        result
+    }
+
+    // The answer from block, None if it asks for an index past its end.
+    pub fn respond(&self, block: &Block) -> Option<BlockTransactions> {
+        let transactions = self
+            .indexes
+            .iter()
+            .map(|&index| block.transactions.get(index as usize).cloned())
+            .collect::<Option<Vec<_>>>()?;
+        Some(BlockTransactions {
+            block_hash: self.block_hash,
+            transactions,
+        })
+    }
+}
+
+// Payload of the blocktxn message, answering a getblocktxn in the same order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTransactions {
+    pub block_hash: [u8; 32],
+    pub transactions: Vec<Transaction>,
+}
+
+impl BlockTransactions {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let block_hash = read_array(reader)?;
+        let count = read_varint(reader)?;
+        if count as usize > MAX_COMPACT_TRANSACTIONS {
+            return Err(Errors::InvalidCompactBlock("too many transactions"));
+        }
+        let transactions = (0..count)
+            .map(|_| Transaction::parse(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(BlockTransactions {
+            block_hash,
+            transactions,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.block_hash.to_vec();
+        result.extend(encode_varint(self.transactions.len() as u64));
+        for tx in &self.transactions {
+            result.extend(tx.serialize());
+        }
+        result
+    }
+}
+
+// A compact block being rebuilt (PartiallyDownloadedBlock in Bitcoin Core).
+#[derive(Clone, Debug)]
+pub struct PartiallyDownloadedBlock {
+    header: BlockHeader,
+    slots: Vec<Option<Transaction>>,
+}
+
+impl PartiallyDownloadedBlock {
+    // Places the prefilled transactions and those of mempool matching a short id.
+    // When two mempool transactions match the same one, neither is used and the
+    // transaction is requested instead. Short ids colliding within the announcement
+    // fail, as the block then has to be downloaded in full.
+    pub fn new<'a>(
+        compact: &HeaderAndShortIds,
+        mempool: impl IntoIterator<Item = &'a Transaction>,
+    ) -> Result<Self, Errors> {
+        let count = compact.block_tx_count();
+        if count == 0 || count > MAX_COMPACT_TRANSACTIONS {
+            return Err(Errors::InvalidCompactBlock("bad transaction count"));
+        }
+        let mut slots: Vec<Option<Transaction>> = vec![None; count];
+        for prefilled in &compact.prefilled {
+            let slot = slots
+                .get_mut(prefilled.index as usize)
+                .ok_or(Errors::InvalidCompactBlock("prefilled index out of range"))?;
+            *slot = Some(prefilled.tx.clone());
+        }
+
+        let mut short_ids = HashMap::new();
+        let mut ids = compact.short_ids.iter();
+        for (index, slot) in slots.iter().enumerate() {
+            if slot.is_some() {
+                continue;
+            }
+            let short_id = *ids.next().unwrap();
+            if short_ids.insert(short_id, index).is_some() {
+                return Err(Errors::InvalidCompactBlock("short ids collide"));
+            }
+        }
+
+        let (k0, k1) = compact.short_id_keys();
+        let mut collided = HashSet::new();
+        for tx in mempool {
+            let wtxid = tx.wtxid();
+            let Some(&index) = short_ids.get(&short_id(k0, k1, &wtxid)) else {
+                continue;
+            };
+            if collided.contains(&index) {
+                continue;
+            }
+            match &slots[index] {
+                Some(placed) if placed.wtxid() != wtxid => {
+                    slots[index] = None;
+                    collided.insert(index);
+                }
+                _ => slots[index] = Some(tx.clone()),
+            }
+        }
+        Ok(PartiallyDownloadedBlock {
+            header: compact.header,
+            slots,
+        })
+    }
+
+    pub fn missing_indexes(&self) -> Vec<u16> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index as u16)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.slots.iter().all(Option::is_some)
+    }
+
+    // The getblocktxn to send, None if nothing is missing.
+    pub fn request(&self) -> Option<BlockTransactionsRequest> {
+        let indexes = self.missing_indexes();
+        (!indexes.is_empty()).then(|| BlockTransactionsRequest {
+            block_hash: self.header.hash(),
+            indexes,
+        })
+    }
+
+    // Fills in the transactions answering request (none if complete) and returns the
+    // block. A block not matching its merkle root means a short id matched the wrong
+    // transaction, so it has to be downloaded in full.
+    pub fn fill(self, received: &BlockTransactions) -> Result<Block, Errors> {
+        if received.block_hash != self.header.hash() {
+            return Err(Errors::InvalidCompactBlock(
+                "transactions for another block",
+            ));
+        }
+        let mut received_txs = received.transactions.iter();
+        let mut transactions = Vec::with_capacity(self.slots.len());
+        for slot in self.slots {
+            let tx = match slot {
+                Some(tx) => tx,
+                None => received_txs
+                    .next()
+                    .ok_or(Errors::InvalidCompactBlock("missing transactions"))?
+                    .clone(),
+            };
+            transactions.push(tx);
+        }
+        if received_txs.next().is_some() {
+            return Err(Errors::InvalidCompactBlock("too many transactions"));
+        }
+        let block = Block::new(self.header, transactions);
+        if !block.validate_merkle_root() {
+            return Err(Errors::InvalidCompactBlock(
+                "reconstructed block does not match its merkle root",
+            ));
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod compact_tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::transaction::{OutPoint, TxIn, TxOut};
+
+    fn tx(tag: u8) -> Transaction {
+        Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new([tag; 32], 0),
+                Script::new(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(tag as u64 * 1000, vec![0x51].into())],
+            0,
+        )
+    }
+
+    fn block() -> Block {
+        let mut script_sig = Script::new();
+        script_sig.push_int(1).push_int(1);
+        let coinbase = Transaction::new(
+            1,
+            vec![TxIn::new(OutPoint::null(), script_sig, 0xffffffff)],
+            vec![TxOut::new(50_0000_0000, vec![0x51].into())],
+            0,
+        );
+        let mut block = crate::network::Network::Regtest.genesis_block();
+        block.transactions = vec![coinbase, tx(1), tx(2), tx(3)];
+        block.header.merkle_root = block.compute_merkle_root();
+        block
+    }
+
+    #[test]
+    fn test_serialization_round_trip() {
+        let block = block();
+        let compact = HeaderAndShortIds::from_block(&block, 42);
+        assert_eq!(compact.block_tx_count(), 4);
+        assert!(compact.short_ids.iter().all(|id| *id < 1 << 48));
+        let bytes = compact.serialize();
+        // Header, nonce, 3 short ids and the coinbase at index 0.
+        let expected_len =
+            80 + 8 + 1 + 3 * SHORT_ID_SIZE + 1 + 1 + block.transactions[0].serialize().len();
+        assert_eq!(bytes.len(), expected_len);
+        assert_eq!(
+            HeaderAndShortIds::parse(&mut bytes.as_slice()).unwrap(),
+            compact
+        );
+
+        let request = BlockTransactionsRequest {
+            block_hash: block.hash(),
+            indexes: vec![1, 2, 5],
+        };
+        let bytes = request.serialize();
+        assert_eq!(&bytes[32..], &[3, 1, 0, 2]);
+        assert_eq!(
+            BlockTransactionsRequest::parse(&mut bytes.as_slice()).unwrap(),
+            request
+        );
+
+        let response = BlockTransactions {
+            block_hash: block.hash(),
+            transactions: vec![tx(1), tx(2)],
+        };
+        let bytes = response.serialize();
+        assert_eq!(
+            BlockTransactions::parse(&mut bytes.as_slice()).unwrap(),
+            response
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_from_mempool() {
+        let block = block();
+        let compact = HeaderAndShortIds::from_block(&block, 7);
+        let mempool = [tx(3), tx(9), tx(1)];
+        let partial = PartiallyDownloadedBlock::new(&compact, &mempool).unwrap();
+        assert_eq!(partial.missing_indexes(), vec![2]);
+
+        let request = partial.request().unwrap();
+        let response = request.respond(&block).unwrap();
+        assert_eq!(response.transactions, vec![tx(2)]);
+        assert_eq!(partial.clone().fill(&response).unwrap(), block);
+
+        // The wrong transaction gives a block not matching its header.
+        let wrong = BlockTransactions {
+            block_hash: block.hash(),
+            transactions: vec![tx(4)],
+        };
+        assert_eq!(
+            partial.fill(&wrong),
+            Err(Errors::InvalidCompactBlock(
+                "reconstructed block does not match its merkle root"
+            ))
+        );
+
+        // Everything in the mempool needs no round trip.
+        let mempool = [tx(1), tx(2), tx(3)];
+        let partial = PartiallyDownloadedBlock::new(&compact, &mempool).unwrap();
+        assert!(partial.is_complete());
+        assert_eq!(partial.request(), None);
+        let empty = BlockTransactions {
+            block_hash: block.hash(),
+            transactions: vec![],
+        };
+        assert_eq!(partial.fill(&empty).unwrap(), block);
+    }
+
+    #[test]
+    fn test_invalid_announcements() {
+        let block = block();
+        let mut compact = HeaderAndShortIds::from_block(&block, 7);
+        compact.short_ids[1] = compact.short_ids[0];
+        assert!(matches!(
+            PartiallyDownloadedBlock::new(&compact, []),
+            Err(Errors::InvalidCompactBlock("short ids collide"))
+        ));
+
+        let mut compact = HeaderAndShortIds::from_block(&block, 7);
+        compact.prefilled[0].index = 4;
+        assert!(matches!(
+            PartiallyDownloadedBlock::new(&compact, []),
+            Err(Errors::InvalidCompactBlock("prefilled index out of range"))
+        ));
+    }
+}
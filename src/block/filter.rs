@@ -0,0 +1,235 @@
+// BIP158 compact block filters: a Golomb-Rice coded set of the scripts a block
+// touches, letting light clients find the blocks they care about without telling
+// a node which scripts are theirs.
+use super::Block;
+use crate::helper::{encode_varint, hash256, read_varint, siphash24};
+use crate::script::Script;
+use crate::types::errors::Errors;
+use std::collections::BTreeSet;
+
+// The only filter type defined, covering output scripts and the scripts spent.
+pub const BASIC_FILTER_TYPE: u8 = 0;
+// Golomb-Rice parameter and false positive rate (1/M) of basic filters.
+pub const FILTER_P: u8 = 19;
+pub const FILTER_M: u64 = 784_931;
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            used: 8,
+        }
+    }
+
+    // The low count bits of value, most significant first.
+    fn write(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            if self.used == 8 {
+                self.bytes.push(0);
+                self.used = 0;
+            }
+            if (value >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+            }
+            self.used += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u8) -> Result<u64, Errors> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self
+                .bytes
+                .get(self.position / 8)
+                .ok_or(Errors::UnexpectedEof)?;
+            value = (value << 1) | ((byte >> (7 - self.position % 8)) & 1) as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+}
+
+// Maps item uniformly onto [0, n * m), keyed by the first 16 bytes of the block hash.
+fn hash_to_range(key: &[u8; 32], item: &[u8], range: u64) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..16].try_into().unwrap());
+    ((siphash24(k0, k1, item) as u128 * range as u128) >> 64) as u64
+}
+
+fn hashed_set(key: &[u8; 32], items: &BTreeSet<&[u8]>, n: u64) -> Vec<u64> {
+    let mut values: Vec<u64> = items
+        .iter()
+        .map(|item| hash_to_range(key, item, n * FILTER_M))
+        .collect();
+    values.sort_unstable();
+    values
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockFilter {
+    pub block_hash: [u8; 32],
+    // The element count followed by the Golomb-Rice coded deltas.
+    pub filter: Vec<u8>,
+}
+
+impl BlockFilter {
+    // Basic filter of block, given the scripts of the outputs it spends. Empty and
+    // OP_RETURN output scripts are left out.
+    pub fn basic<'a>(
+        block: &'a Block,
+        spent_scripts: impl IntoIterator<Item = &'a Script>,
+    ) -> Self {
+        let mut items: BTreeSet<&[u8]> = block
+            .iter()
+            .flat_map(|tx| &tx.outputs)
+            .map(|output| output.script_pubkey.as_bytes())
+            .filter(|script| !script.is_empty() && script[0] != 0x6a)
+            .collect();
+        items.extend(
+            spent_scripts
+                .into_iter()
+                .map(Script::as_bytes)
+                .filter(|script| !script.is_empty()),
+        );
+        Self::from_items(block.hash(), &items)
+    }
+
+    pub fn from_items(block_hash: [u8; 32], items: &BTreeSet<&[u8]>) -> Self {
+        let n = items.len() as u64;
+        let mut filter = encode_varint(n);
+        let mut writer = BitWriter::new();
+        let mut last = 0;
+        for value in hashed_set(&block_hash, items, n) {
+            let delta = value - last;
+            // Unary quotient, then the remainder in FILTER_P bits.
+            for _ in 0..delta >> FILTER_P {
+                writer.write(1, 1);
+            }
+            writer.write(0, 1);
+            writer.write(delta, FILTER_P);
+            last = value;
+        }
+        filter.extend(writer.bytes);
+        BlockFilter { block_hash, filter }
+    }
+
+    pub fn element_count(&self) -> Result<u64, Errors> {
+        read_varint(&mut self.filter.as_slice())
+    }
+
+    // The sorted values coded in the filter.
+    fn decode(&self) -> Result<Vec<u64>, Errors> {
+        let mut reader = self.filter.as_slice();
+        let n = read_varint(&mut reader)?;
+        let mut bits = BitReader {
+            bytes: reader,
+            position: 0,
+        };
+        let mut values = Vec::new();
+        let mut last = 0u64;
+        for _ in 0..n {
+            let mut quotient = 0u64;
+            while bits.read(1)? == 1 {
+                quotient += 1;
+            }
+            let delta = (quotient << FILTER_P) | bits.read(FILTER_P)?;
+            last = last.checked_add(delta).ok_or(Errors::ValueOutOfRange)?;
+            values.push(last);
+        }
+        Ok(values)
+    }
+
+    // Whether any of items may be in the block. False positives happen at a rate
+    // of 1/FILTER_M per item, but a script of the block is always matched.
+    pub fn match_any(&self, items: &[&[u8]]) -> Result<bool, Errors> {
+        let values = self.decode()?;
+        let n = values.len() as u64;
+        if n == 0 {
+            return Ok(false);
+        }
+        let items: BTreeSet<&[u8]> = items.iter().copied().collect();
+        let queries = hashed_set(&self.block_hash, &items, n);
+        // Both lists are sorted, so a single merge finds any common value.
+        let (mut i, mut j) = (0, 0);
+        while i < values.len() && j < queries.len() {
+            match values[i].cmp(&queries[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn filter_hash(&self) -> [u8; 32] {
+        hash256(&self.filter)
+    }
+
+    // Header of this filter in the chain of filter headers, which is all zeros
+    // before genesis.
+    pub fn header(&self, prev_header: &[u8; 32]) -> [u8; 32] {
+        filter_header(&self.filter_hash(), prev_header)
+    }
+}
+
+pub fn filter_header(filter_hash: &[u8; 32], prev_header: &[u8; 32]) -> [u8; 32] {
+    let mut data = filter_hash.to_vec();
+    data.extend_from_slice(prev_header);
+    hash256(&data)
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::transaction::txid_to_hex;
+
+    #[test]
+    fn test_testnet_genesis_filter() {
+        // First vector of BIP158.
+        let genesis = Network::Testnet.genesis_block();
+        let filter = BlockFilter::basic(&genesis, []);
+        assert_eq!(hex::encode(&filter.filter), "019dfca8");
+        assert_eq!(
+            txid_to_hex(&filter.header(&[0; 32])),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+        let script = genesis.transactions[0].outputs[0].script_pubkey.as_bytes();
+        assert_eq!(filter.match_any(&[script]), Ok(true));
+    }
+
+    #[test]
+    fn test_match_any() {
+        let scripts: Vec<Vec<u8>> = (0..100u8).map(|i| vec![0x51, i]).collect();
+        let items: BTreeSet<&[u8]> = scripts.iter().map(Vec::as_slice).collect();
+        let filter = BlockFilter::from_items([7; 32], &items);
+        assert_eq!(filter.element_count(), Ok(100));
+        assert_eq!(filter.decode().unwrap().len(), 100);
+        for script in &scripts {
+            assert_eq!(filter.match_any(&[script.as_slice()]), Ok(true));
+        }
+        let others: Vec<Vec<u8>> = (0..100u8).map(|i| vec![0x52, i]).collect();
+        let others: Vec<&[u8]> = others.iter().map(Vec::as_slice).collect();
+        assert_eq!(filter.match_any(&others), Ok(false));
+
+        let empty = BlockFilter::from_items([7; 32], &BTreeSet::new());
+        assert_eq!(empty.filter, vec![0]);
+        assert_eq!(empty.match_any(&others), Ok(false));
+
+        let mut truncated = filter.clone();
+        truncated.filter.truncate(10);
+        assert_eq!(truncated.match_any(&others), Err(Errors::UnexpectedEof));
+    }
+}
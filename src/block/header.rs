@@ -0,0 +1,116 @@
+// The 80 byte block header, whose double sha256 is the block hash and must be below
+// the target encoded in bits.
+use crate::helper::{hash256, read_array};
+use crate::transaction::txid_to_hex;
+use crate::types::errors::Errors;
+use std::io::Read;
+
+pub const BLOCK_HEADER_SIZE: usize = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlockHeader {
+    pub version: i32,
+    // Hash of the previous block and merkle root of the txids, in internal byte order.
+    pub prev_block: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub timestamp: u32,
+    // Compact encoding of the proof of work target.
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(BlockHeader {
+            version: i32::from_le_bytes(read_array(reader)?),
+            prev_block: read_array(reader)?,
+            merkle_root: read_array(reader)?,
+            timestamp: u32::from_le_bytes(read_array(reader)?),
+            bits: u32::from_le_bytes(read_array(reader)?),
+            nonce: u32::from_le_bytes(read_array(reader)?),
+        })
+    }
+
+    // Parses a header that must span the whole buffer.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Errors> {
+        let mut reader = bytes;
+        let header = BlockHeader::parse(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(Errors::TrailingData);
+        }
+        Ok(header)
+    }
+
+    pub fn from_hex(hex_str: &str) -> Result<Self, Errors> {
+        let bytes = hex::decode(hex_str.trim()).map_err(|_| Errors::InvalidHex)?;
+        BlockHeader::from_bytes(&bytes)
+    }
+
+    pub fn serialize(&self) -> [u8; BLOCK_HEADER_SIZE] {
+        let mut result = [0; BLOCK_HEADER_SIZE];
+        result[0..4].copy_from_slice(&self.version.to_le_bytes());
+        result[4..36].copy_from_slice(&self.prev_block);
+        result[36..68].copy_from_slice(&self.merkle_root);
+        result[68..72].copy_from_slice(&self.timestamp.to_le_bytes());
+        result[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        result[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+        result
+    }
+
+    // Block hash in internal byte order.
+    pub fn hash(&self) -> [u8; 32] {
+        hash256(&self.serialize())
+    }
+
+    // Block hash as shown by block explorers (byte-reversed hex).
+    pub fn hash_hex(&self) -> String {
+        txid_to_hex(&self.hash())
+    }
+}
+
+#[cfg(test)]
+mod header_tests {
+    use super::*;
+
+    // Block 471744 on mainnet, from chapter 9 of Programming Bitcoin.
+    const RAW_HEADER: &str = "020000208ec39428b17323fa0ddec8e887b4a7c53b8c0a0a220cfd0000000000000000005b0750fce0a889502d40508d39576821155e9c9e3f5c3157f961db38fd8b25be1e77a759e93c0118a4ffd71d";
+
+    #[test]
+    fn test_parse_header() {
+        let header = BlockHeader::from_hex(RAW_HEADER).unwrap();
+        assert_eq!(header.version, 0x20000002);
+        assert_eq!(
+            txid_to_hex(&header.prev_block),
+            "000000000000000000fd0c220a0a8c3bc5a7b487e8c8de0dfa2373b12894c38e"
+        );
+        assert_eq!(
+            txid_to_hex(&header.merkle_root),
+            "be258bfd38db61f957315c3f9e9c5e15216857398d50402d5089a8e0fc50075b"
+        );
+        assert_eq!(header.timestamp, 0x59a7771e);
+        assert_eq!(header.bits, 0x1801_3ce9);
+        assert_eq!(header.nonce, 0x1dd7ffa4);
+    }
+
+    #[test]
+    fn test_serialize_and_hash() {
+        let header = BlockHeader::from_hex(RAW_HEADER).unwrap();
+        assert_eq!(hex::encode(header.serialize()), RAW_HEADER);
+        assert_eq!(
+            header.hash_hex(),
+            "0000000000000000007e9e4c586439b0cdbe13b1370bdd9435d76a644d047523"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_wrong_length() {
+        let bytes = hex::decode(RAW_HEADER).unwrap();
+        assert!(BlockHeader::from_bytes(&bytes[..79]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            BlockHeader::from_bytes(&trailing),
+            Err(Errors::TrailingData)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+// Merkle trees of txids and wtxids committed to by blocks, as ComputeMerkleRoot in
+// Bitcoin Core.
+use super::Block;
+use crate::helper::hash256;
+
+// Outputs of the coinbase starting with this hold the BIP141 witness commitment.
+pub const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Root of the tree over hashes, an odd last node being paired with itself. The flag
+// tells whether two identical siblings were hashed together: a list ending in a
+// repeated pair has the same root as one without it (CVE-2012-2459), so blocks
+// doing this must be rejected without marking their hash invalid.
+pub fn merkle_root(hashes: &[[u8; 32]]) -> ([u8; 32], bool) {
+    if hashes.is_empty() {
+        return ([0; 32], false);
+    }
+    let mut level = hashes.to_vec();
+    let mut mutated = false;
+    while level.len() > 1 {
+        mutated |= level.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+        if level.len() % 2 == 1 {
+            level.push(*level.last().unwrap());
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                let mut data = pair[0].to_vec();
+                data.extend_from_slice(&pair[1]);
+                hash256(&data)
+            })
+            .collect();
+    }
+    (level[0], mutated)
+}
+
+impl Block {
+    pub fn txids(&self) -> Vec<[u8; 32]> {
+        self.iter().map(|tx| tx.txid()).collect()
+    }
+
+    // Root of the txids, never covering witness data.
+    pub fn compute_merkle_root(&self) -> [u8; 32] {
+        merkle_root(&self.txids()).0
+    }
+
+    // The header commits to these exact transactions, and no duplicated ones.
+    pub fn validate_merkle_root(&self) -> bool {
+        let (root, mutated) = merkle_root(&self.txids());
+        !mutated && root == self.header.merkle_root
+    }
+
+    // BIP141 root of the wtxids, where the coinbase counts as all zeros.
+    pub fn witness_root(&self) -> [u8; 32] {
+        let wtxids: Vec<[u8; 32]> = self
+            .iter()
+            .enumerate()
+            .map(|(i, tx)| if i == 0 { [0; 32] } else { tx.wtxid() })
+            .collect();
+        merkle_root(&wtxids).0
+    }
+
+    // Commitment in the last coinbase output carrying one, if any.
+    pub fn witness_commitment(&self) -> Option<[u8; 32]> {
+        self.coinbase()?
+            .outputs
+            .iter()
+            .rev()
+            .find(|output| {
+                output.script_pubkey.len() >= 38
+                    && output.script_pubkey[..6] == WITNESS_COMMITMENT_HEADER
+            })
+            .map(|output| output.script_pubkey[6..38].try_into().unwrap())
+    }
+
+    // Either no transaction has witness data, or the coinbase commits to the witness
+    // root together with the 32 byte reserved value in its own witness.
+    pub fn validate_witness_commitment(&self) -> bool {
+        let Some(commitment) = self.witness_commitment() else {
+            return !self.iter().any(|tx| tx.has_witness());
+        };
+        let witness = &self.transactions[0].inputs[0].witness;
+        if witness.len() != 1 || witness[0].len() != 32 {
+            return false;
+        }
+        let mut data = self.witness_root().to_vec();
+        data.extend_from_slice(&witness[0]);
+        hash256(&data) == commitment
+    }
+}
+
+#[cfg(test)]
+mod merkle_tests {
+    use super::*;
+    use crate::transaction::{txid_from_hex, txid_to_hex, Transaction, TxOut, Witness};
+
+    // Block 100000 on mainnet.
+    const BLOCK_100000_TXIDS: [&str; 4] = [
+        "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+        "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+        "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+        "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+    ];
+
+    // The mainnet genesis block.
+    const GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    // Signed native P2WPKH transaction from BIP143.
+    const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    #[test]
+    fn test_merkle_root() {
+        let txids: Vec<[u8; 32]> = BLOCK_100000_TXIDS
+            .iter()
+            .map(|txid| txid_from_hex(txid).unwrap())
+            .collect();
+        let (root, mutated) = merkle_root(&txids);
+        assert_eq!(
+            txid_to_hex(&root),
+            "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"
+        );
+        assert!(!mutated);
+        assert_eq!(merkle_root(&txids[..1]), (txids[0], false));
+        assert_eq!(merkle_root(&[]), ([0; 32], false));
+
+        // Repeating the odd last txid gives the same root, but is flagged.
+        let (odd_root, mutated) = merkle_root(&txids[..3]);
+        assert!(!mutated);
+        let mut repeated = txids[..3].to_vec();
+        repeated.push(txids[2]);
+        assert_eq!(merkle_root(&repeated), (odd_root, true));
+    }
+
+    #[test]
+    fn test_validate_merkle_root() {
+        let mut block = Block::from_hex(GENESIS_BLOCK).unwrap();
+        assert!(block.validate_merkle_root());
+        assert!(block.validate_witness_commitment());
+        assert_eq!(block.witness_commitment(), None);
+
+        let segwit_tx = Transaction::from_hex(SEGWIT_TX).unwrap();
+        block.transactions.push(segwit_tx);
+        assert!(!block.validate_merkle_root());
+        block.header.merkle_root = block.compute_merkle_root();
+        assert!(block.validate_merkle_root());
+        let coinbase = block.transactions[0].clone();
+        block.transactions.insert(1, coinbase);
+        assert!(!block.validate_merkle_root());
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let mut block = Block::from_hex(GENESIS_BLOCK).unwrap();
+        block
+            .transactions
+            .push(Transaction::from_hex(SEGWIT_TX).unwrap());
+        // Witness data without a commitment.
+        assert!(!block.validate_witness_commitment());
+
+        let reserved = [0u8; 32];
+        let mut data = block.witness_root().to_vec();
+        data.extend_from_slice(&reserved);
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend_from_slice(&hash256(&data));
+        block.transactions[0]
+            .outputs
+            .push(TxOut::new(0, script.into()));
+        // The reserved value is missing from the coinbase witness.
+        assert!(!block.validate_witness_commitment());
+        block.transactions[0].inputs[0].witness = Witness::from_elements(vec![reserved.to_vec()]);
+        assert!(block.validate_witness_commitment());
+        // The coinbase's own wtxid is not committed to.
+        assert_eq!(block.witness_root(), {
+            let wtxids = [[0; 32], block.transactions[1].wtxid()];
+            merkle_root(&wtxids).0
+        });
+
+        block.transactions[1].inputs[1].witness = Witness::from_elements(vec![vec![1]]);
+        assert!(!block.validate_witness_commitment());
+    }
+}
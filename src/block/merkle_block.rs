@@ -0,0 +1,286 @@
+// BIP37 merkleblock messages: a header together with the part of its merkle tree
+// needed to prove that some transactions are in the block, so SPV wallets get their
+// transactions without downloading whole blocks (CPartialMerkleTree in Bitcoin Core).
+use super::{Block, BlockHeader};
+use crate::helper::{
+    encode_var_bytes, encode_varint, hash256, read_array, read_var_bytes, read_varint,
+};
+use crate::transaction::Transaction;
+use crate::types::errors::Errors;
+use std::io::Read;
+
+// Smallest transactions possible, bounding how many a block can have.
+const MIN_TRANSACTION_SIZE: u32 = 60;
+const MAX_BLOCK_SIZE: u32 = 1_000_000;
+
+// A matched txid and its index in the block.
+pub type MerkleMatch = ([u8; 32], u32);
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut data = left.to_vec();
+    data.extend_from_slice(right);
+    hash256(&data)
+}
+
+// Hashes and flag bits of a depth first walk of the tree. A set flag means the node
+// is an ancestor of a matched transaction, or is one, and its children follow; a
+// clear one that its hash is given and nothing below it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    pub total_transactions: u32,
+    pub hashes: Vec<[u8; 32]>,
+    pub flags: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    // Tree over txids proving those whose matches entry is set.
+    pub fn new(txids: &[[u8; 32]], matches: &[bool]) -> Self {
+        let mut tree = PartialMerkleTree {
+            total_transactions: txids.len() as u32,
+            hashes: Vec::new(),
+            flags: Vec::new(),
+        };
+        let height = tree.tree_height();
+        tree.build(height, 0, txids, matches);
+        tree
+    }
+
+    fn tree_width(&self, height: u32) -> u32 {
+        (self.total_transactions + (1 << height) - 1) >> height
+    }
+
+    fn tree_height(&self) -> u32 {
+        let mut height = 0;
+        while self.tree_width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    // Hash of the node at position in the level height above the txids.
+    fn compute_hash(&self, height: u32, position: u32, txids: &[[u8; 32]]) -> [u8; 32] {
+        if height == 0 {
+            return txids[position as usize];
+        }
+        let left = self.compute_hash(height - 1, position * 2, txids);
+        let right = if position * 2 + 1 < self.tree_width(height - 1) {
+            self.compute_hash(height - 1, position * 2 + 1, txids)
+        } else {
+            left
+        };
+        hash_pair(&left, &right)
+    }
+
+    fn build(&mut self, height: u32, position: u32, txids: &[[u8; 32]], matches: &[bool]) {
+        let start = (position << height) as usize;
+        let end = (((position + 1) << height) as usize).min(txids.len());
+        let parent_of_match = matches[start..end].iter().any(|matched| *matched);
+        self.flags.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            self.hashes.push(self.compute_hash(height, position, txids));
+            return;
+        }
+        self.build(height - 1, position * 2, txids, matches);
+        if position * 2 + 1 < self.tree_width(height - 1) {
+            self.build(height - 1, position * 2 + 1, txids, matches);
+        }
+    }
+
+    // Walks the tree as given, collecting the matched txids with their index in the
+    // block, and returns its root.
+    fn extract(
+        &self,
+        height: u32,
+        position: u32,
+        cursor: &mut (usize, usize),
+        matches: &mut Vec<MerkleMatch>,
+    ) -> Result<[u8; 32], Errors> {
+        let flag = *self
+            .flags
+            .get(cursor.1)
+            .ok_or(Errors::InvalidMerkleBlock("not enough flag bits"))?;
+        cursor.1 += 1;
+        if height == 0 || !flag {
+            let hash = *self
+                .hashes
+                .get(cursor.0)
+                .ok_or(Errors::InvalidMerkleBlock("not enough hashes"))?;
+            cursor.0 += 1;
+            if height == 0 && flag {
+                matches.push((hash, position));
+            }
+            return Ok(hash);
+        }
+        let left = self.extract(height - 1, position * 2, cursor, matches)?;
+        let right = if position * 2 + 1 < self.tree_width(height - 1) {
+            let right = self.extract(height - 1, position * 2 + 1, cursor, matches)?;
+            // Identical siblings would let a tree prove a duplicated transaction.
+            if right == left {
+                return Err(Errors::InvalidMerkleBlock("duplicated subtree"));
+            }
+            right
+        } else {
+            left
+        };
+        Ok(hash_pair(&left, &right))
+    }
+
+    // The root the tree commits to and the matched txids, with their index in the
+    // block. The tree must be well formed and use all its hashes and flags.
+    pub fn extract_matches(&self) -> Result<([u8; 32], Vec<MerkleMatch>), Errors> {
+        if self.total_transactions == 0 {
+            return Err(Errors::InvalidMerkleBlock("no transactions"));
+        }
+        if self.total_transactions > MAX_BLOCK_SIZE / MIN_TRANSACTION_SIZE {
+            return Err(Errors::InvalidMerkleBlock("too many transactions"));
+        }
+        if self.hashes.len() > self.total_transactions as usize {
+            return Err(Errors::InvalidMerkleBlock("more hashes than transactions"));
+        }
+        if self.flags.len() < self.hashes.len() {
+            return Err(Errors::InvalidMerkleBlock("not enough flag bits"));
+        }
+        let mut cursor = (0, 0);
+        let mut matches = Vec::new();
+        let root = self.extract(self.tree_height(), 0, &mut cursor, &mut matches)?;
+        // Flags are sent in whole bytes, so only the padding may be left.
+        if cursor.0 != self.hashes.len() || cursor.1.div_ceil(8) != self.flags.len().div_ceil(8) {
+            return Err(Errors::InvalidMerkleBlock("unused hashes or flags"));
+        }
+        Ok((root, matches))
+    }
+}
+
+// Payload of the merkleblock message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub tree: PartialMerkleTree,
+}
+
+impl MerkleBlock {
+    // Proves the transactions of block for which matches returns true.
+    pub fn from_block(block: &Block, matches: impl Fn(&Transaction) -> bool) -> Self {
+        let flags: Vec<bool> = block.iter().map(matches).collect();
+        MerkleBlock {
+            header: block.header,
+            tree: PartialMerkleTree::new(&block.txids(), &flags),
+        }
+    }
+
+    // Txids proven to be in the block, in block order, as long as the tree commits
+    // to the header's merkle root.
+    pub fn verify(&self) -> Result<Vec<[u8; 32]>, Errors> {
+        let (root, matches) = self.tree.extract_matches()?;
+        if root != self.header.merkle_root {
+            return Err(Errors::InvalidMerkleBlock("merkle root mismatch"));
+        }
+        Ok(matches.into_iter().map(|(txid, _)| txid).collect())
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let header = BlockHeader::parse(reader)?;
+        let total_transactions = u32::from_le_bytes(read_array(reader)?);
+        let count = read_varint(reader)?;
+        if count > total_transactions as u64 {
+            return Err(Errors::InvalidMerkleBlock("more hashes than transactions"));
+        }
+        let hashes = (0..count)
+            .map(|_| read_array(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Bits are packed least significant first.
+        let flags = read_var_bytes(reader)?
+            .iter()
+            .flat_map(|byte| (0..8).map(move |i| (byte >> i) & 1 == 1))
+            .collect();
+        Ok(MerkleBlock {
+            header,
+            tree: PartialMerkleTree {
+                total_transactions,
+                hashes,
+                flags,
+            },
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.header.serialize().to_vec();
+        result.extend(self.tree.total_transactions.to_le_bytes());
+        result.extend(encode_varint(self.tree.hashes.len() as u64));
+        for hash in &self.tree.hashes {
+            result.extend(hash);
+        }
+        let mut flag_bytes = vec![0u8; self.tree.flags.len().div_ceil(8)];
+        for (i, flag) in self.tree.flags.iter().enumerate() {
+            if *flag {
+                flag_bytes[i / 8] |= 1 << (i % 8);
+            }
+        }
+        result.extend(encode_var_bytes(&flag_bytes));
+        result
+    }
+}
+
+#[cfg(test)]
+mod merkle_block_tests {
+    use super::*;
+    use crate::block::merkle_root;
+    use crate::transaction::txid_to_hex;
+
+    // Testnet merkleblock from Programming Bitcoin, proving one transaction.
+    const MERKLE_BLOCK: &str = "00000020df3b053dc46f162a9b00c7f0d5124e2676d47bbe7c5d0793a500000000000000ef445fef2ed495c275892206ca533e7411907971013ab83e3b47bd0d692d14d4dc7c835b67d8001ac157e670bf0d00000aba412a0d1480e370173072c9562becffe87aa661c1e4a6dbc305d38ec5dc088a7cf92e6458aca7b32edae818f9c2c98c37e06bf72ae0ce80649a38655ee1e27d34d9421d940b16732f24b94023e9d572a7f9ab8023434a4feb532d2adfc8c2c2158785d1bd04eb99df2e86c54bc13e139862897217400def5d72c280222c4cbaee7261831e1550dbb8fa82853e9fe506fc5fda3f7b919d8fe74b6282f92763cef8e625f977af7c8619c32a369b832bc2d051ecd9c73c51e76370ceabd4f25097c256597fa898d404ed53425de608ac6bfe426f6e2bb457f1c554866eb69dcb8d6bf6f880e9a59b3cd053e6c7060eeacaacf4dac6697dac20e4bd3f38a2ea2543d1ab7953e3430790a9f81e1c67f5b58c825acf46bd02848384eebe9af917274cdfbb1a28a5d58a23a17977def0de10d644258d9c54f886d47d293a411cb6226103b55635";
+
+    #[test]
+    fn test_parse_and_verify() {
+        let bytes = hex::decode(MERKLE_BLOCK).unwrap();
+        let merkle_block = MerkleBlock::parse(&mut bytes.as_slice()).unwrap();
+        assert_eq!(merkle_block.tree.total_transactions, 3519);
+        assert_eq!(merkle_block.tree.hashes.len(), 10);
+        assert_eq!(merkle_block.serialize(), bytes);
+        let matched = merkle_block.verify().unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(
+            txid_to_hex(&matched[0]),
+            "6122b61c413a297dd486f8549c8d2544d610def0de7779a1238ad5a5281abbdf"
+        );
+
+        let mut bad = merkle_block.clone();
+        bad.tree.hashes[3][0] ^= 1;
+        assert_eq!(
+            bad.verify(),
+            Err(Errors::InvalidMerkleBlock("merkle root mismatch"))
+        );
+    }
+
+    #[test]
+    fn test_build_partial_trees() {
+        let txids: Vec<[u8; 32]> = (0..7u8).map(|i| [i; 32]).collect();
+        let root = merkle_root(&txids).0;
+        for pattern in [0b0000001u8, 0b1000000, 0b0101010, 0b1111111, 0] {
+            let matches: Vec<bool> = (0..7).map(|i| (pattern >> i) & 1 == 1).collect();
+            let tree = PartialMerkleTree::new(&txids, &matches);
+            let (tree_root, found) = tree.extract_matches().unwrap();
+            assert_eq!(tree_root, root);
+            let expected: Vec<MerkleMatch> = (0..7)
+                .filter(|i| matches[*i as usize])
+                .map(|i| (txids[i as usize], i))
+                .collect();
+            assert_eq!(found, expected);
+        }
+
+        // A single transaction is its own root.
+        let tree = PartialMerkleTree::new(&txids[..1], &[true]);
+        assert_eq!(
+            tree.extract_matches().unwrap(),
+            (txids[0], vec![(txids[0], 0)])
+        );
+
+        let mut tree =
+            PartialMerkleTree::new(&txids, &[true, false, false, false, false, false, false]);
+        tree.hashes.push([9; 32]);
+        assert_eq!(
+            tree.extract_matches(),
+            Err(Errors::InvalidMerkleBlock("unused hashes or flags"))
+        );
+    }
+}
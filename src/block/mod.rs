@@ -0,0 +1,183 @@
+// Blocks: the 80 byte header committing to the chain and the transactions it confirms.
+pub mod compact;
+pub mod filter;
+pub mod header;
+pub mod merkle;
+pub mod merkle_block;
+pub mod pow;
+
+pub use compact::{
+    BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, PartiallyDownloadedBlock,
+    PrefilledTransaction,
+};
+pub use filter::{filter_header, BlockFilter, BASIC_FILTER_TYPE};
+pub use header::{BlockHeader, BLOCK_HEADER_SIZE};
+pub use merkle::merkle_root;
+pub use merkle_block::{MerkleBlock, PartialMerkleTree};
+pub use pow::{bits_to_target, block_work, difficulty, target_to_bits};
+
+use crate::helper::{encode_varint, read_varint};
+use crate::transaction::weight::WITNESS_SCALE_FACTOR;
+use crate::transaction::Transaction;
+use crate::types::errors::Errors;
+use std::io::Read;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    // The coinbase first, then the transactions it confirms.
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn new(header: BlockHeader, transactions: Vec<Transaction>) -> Self {
+        Block {
+            header,
+            transactions,
+        }
+    }
+
+    // Transactions use the segwit encoding whenever they carry witness data.
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let header = BlockHeader::parse(reader)?;
+        let count = read_varint(reader)?;
+        let transactions = (0..count)
+            .map(|_| Transaction::parse(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Block::new(header, transactions))
+    }
+
+    // Parses a block that must span the whole buffer.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Errors> {
+        let mut reader = bytes;
+        let block = Block::parse(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(Errors::TrailingData);
+        }
+        Ok(block)
+    }
+
+    pub fn from_hex(hex_str: &str) -> Result<Self, Errors> {
+        let bytes = hex::decode(hex_str.trim()).map_err(|_| Errors::InvalidHex)?;
+        Block::from_bytes(&bytes)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(Transaction::serialize)
+    }
+
+    // Serialization without any witness data, as seen by pre-segwit nodes.
+    pub fn serialize_legacy(&self) -> Vec<u8> {
+        self.serialize_with(Transaction::serialize_legacy)
+    }
+
+    fn serialize_with(&self, serialize_tx: fn(&Transaction) -> Vec<u8>) -> Vec<u8> {
+        let mut result = self.header.serialize().to_vec();
+        result.extend(encode_varint(self.transactions.len() as u64));
+        for tx in &self.transactions {
+            result.extend(serialize_tx(tx));
+        }
+        result
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.header.hash()
+    }
+
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.first()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Transaction> {
+        self.transactions.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    fn count_size(&self) -> usize {
+        BLOCK_HEADER_SIZE + encode_varint(self.transactions.len() as u64).len()
+    }
+
+    pub fn stripped_size(&self) -> usize {
+        self.count_size() + self.iter().map(Transaction::stripped_size).sum::<usize>()
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.count_size() + self.iter().map(Transaction::total_size).sum::<usize>()
+    }
+
+    // BIP141 block weight, limited to MAX_BLOCK_WEIGHT by consensus.
+    pub fn weight(&self) -> usize {
+        self.stripped_size() * (WITNESS_SCALE_FACTOR - 1) + self.total_size()
+    }
+}
+
+impl<'a> IntoIterator for &'a Block {
+    type Item = &'a Transaction;
+    type IntoIter = std::slice::Iter<'a, Transaction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod block_tests {
+    use super::*;
+    use crate::transaction::txid_to_hex;
+
+    // The mainnet genesis block.
+    const GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    // Signed native P2WPKH transaction from BIP143.
+    const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    #[test]
+    fn test_parse_genesis_block() {
+        let block = Block::from_hex(GENESIS_BLOCK).unwrap();
+        assert_eq!(block.len(), 1);
+        assert_eq!(
+            txid_to_hex(&block.hash()),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        let coinbase = block.coinbase().unwrap();
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.txid(), block.header.merkle_root);
+        assert_eq!(coinbase.outputs[0].value, 50 * 100_000_000);
+        assert_eq!(hex::encode(block.serialize()), GENESIS_BLOCK);
+        assert_eq!(block.total_size(), 285);
+        assert_eq!(block.stripped_size(), 285);
+        assert_eq!(block.weight(), 285 * 4);
+    }
+
+    #[test]
+    fn test_block_with_witness_data() {
+        let genesis = Block::from_hex(GENESIS_BLOCK).unwrap();
+        let segwit_tx = Transaction::from_hex(SEGWIT_TX).unwrap();
+        let block = Block::new(
+            genesis.header,
+            vec![genesis.transactions[0].clone(), segwit_tx.clone()],
+        );
+        let bytes = block.serialize();
+        assert_eq!(Block::from_bytes(&bytes).unwrap(), block);
+        assert_eq!(bytes.len(), block.total_size());
+        assert_eq!(block.serialize_legacy().len(), block.stripped_size());
+        assert_eq!(
+            block.weight(),
+            BLOCK_HEADER_SIZE * 4 + 4 + genesis.transactions[0].weight() + segwit_tx.weight()
+        );
+        let txids: Vec<_> = block.iter().map(Transaction::txid).collect();
+        assert_eq!(txids[1], segwit_tx.txid());
+        assert_eq!((&block).into_iter().count(), 2);
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert_eq!(Block::from_bytes(&trailing), Err(Errors::TrailingData));
+    }
+}
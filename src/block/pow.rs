@@ -0,0 +1,250 @@
+// Proof of work: the compact "bits" encoding of targets, as SetCompact/GetCompact in
+// Bitcoin Core, and checking a header hash against its target.
+use super::BlockHeader;
+use crate::types::errors::Errors;
+use num_bigint::{BigInt, Sign};
+
+const COMPACT_SIGN_BIT: u32 = 0x0080_0000;
+
+// The target is recomputed every 2016 blocks so that they take two weeks.
+pub const POW_TARGET_TIMESPAN: i64 = 14 * 24 * 60 * 60;
+pub const POW_TARGET_SPACING: i64 = 10 * 60;
+pub const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = (POW_TARGET_TIMESPAN / POW_TARGET_SPACING) as u32;
+
+// bits is a one byte size in bytes followed by a three byte mantissa whose top bit
+// is a sign. Negative and overflowing values are never valid targets.
+pub fn bits_to_target(bits: u32) -> Result<BigInt, Errors> {
+    let size = bits >> 24;
+    let word = bits & 0x007f_ffff;
+    if word != 0 && bits & COMPACT_SIGN_BIT != 0 {
+        return Err(Errors::InvalidCompactTarget(bits));
+    }
+    if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+        return Err(Errors::InvalidCompactTarget(bits));
+    }
+    Ok(if size <= 3 {
+        BigInt::from(word >> (8 * (3 - size)))
+    } else {
+        BigInt::from(word) << (8 * (size - 3))
+    })
+}
+
+// Shortest compact encoding of target. A mantissa with its top bit set would read as
+// negative, so it is shifted down a byte and the size grows by one.
+pub fn target_to_bits(target: &BigInt) -> u32 {
+    let magnitude = target.magnitude();
+    let mut size = magnitude.bits().div_ceil(8) as u32;
+    let low_word = |n: &num_bigint::BigUint| n.to_u32_digits().first().copied().unwrap_or(0);
+    let mut compact = if size <= 3 {
+        low_word(magnitude) << (8 * (3 - size))
+    } else {
+        low_word(&(magnitude >> (8 * (size - 3))))
+    };
+    if compact & COMPACT_SIGN_BIT != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact |= size << 24;
+    if target.sign() == Sign::Minus && compact & 0x007f_ffff != 0 {
+        compact |= COMPACT_SIGN_BIT;
+    }
+    compact
+}
+
+// Difficulty relative to the minimum one of 0x1d00ffff, as getdifficulty reports it.
+pub fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000_ffff as f64 / (bits & 0x00ff_ffff) as f64;
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+    difficulty
+}
+
+// Expected number of hashes needed to find a block at this target: 2^256 / (target + 1).
+// Chains are compared on the sum of the work of their blocks.
+pub fn block_work(bits: u32) -> BigInt {
+    match bits_to_target(bits) {
+        Ok(target) if target.sign() == Sign::Plus => (BigInt::from(1) << 256) / (target + 1),
+        _ => BigInt::from(0),
+    }
+}
+
+// Heights whose bits may differ from the previous block's.
+pub fn is_retarget_height(height: u32) -> bool {
+    height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
+}
+
+// CalculateNextWorkRequired: last is the final header of an epoch and
+// first_block_time the timestamp of its first one. The target scales with how long
+// the epoch took, by at most a factor of 4 either way, and never exceeds pow_limit.
+pub fn calculate_next_work_required(
+    last: &BlockHeader,
+    first_block_time: u32,
+    pow_limit: &BigInt,
+) -> Result<u32, Errors> {
+    let actual_timespan = (last.timestamp as i64 - first_block_time as i64)
+        .clamp(POW_TARGET_TIMESPAN / 4, POW_TARGET_TIMESPAN * 4);
+    let target = last.target()? * actual_timespan / POW_TARGET_TIMESPAN;
+    Ok(target_to_bits(&target.min(pow_limit.clone())))
+}
+
+// Block hashes are compared to targets as little endian numbers.
+pub fn hash_to_number(hash: &[u8; 32]) -> BigInt {
+    BigInt::from_bytes_le(Sign::Plus, hash)
+}
+
+impl BlockHeader {
+    pub fn target(&self) -> Result<BigInt, Errors> {
+        bits_to_target(self.bits)
+    }
+
+    pub fn difficulty(&self) -> f64 {
+        difficulty(self.bits)
+    }
+
+    // The hash is at most the target encoded in bits, which must be a positive number.
+    pub fn check_pow(&self) -> bool {
+        match self.target() {
+            Ok(target) if target.sign() == Sign::Plus => hash_to_number(&self.hash()) <= target,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod pow_tests {
+    use super::*;
+
+    // Block 471744 on mainnet, from chapter 9 of Programming Bitcoin.
+    const RAW_HEADER: &str = "020000208ec39428b17323fa0ddec8e887b4a7c53b8c0a0a220cfd0000000000000000005b0750fce0a889502d40508d39576821155e9c9e3f5c3157f961db38fd8b25be1e77a759e93c0118a4ffd71d";
+
+    fn number(hex_str: &str) -> BigInt {
+        BigInt::parse_bytes(hex_str.as_bytes(), 16).unwrap()
+    }
+
+    // Vectors from the arith_uint256 tests of Bitcoin Core.
+    #[test]
+    fn test_compact_encoding() {
+        for (bits, target, reencoded) in [
+            (0x00123456, "0", 0x00000000),
+            (0x01003456, "0", 0x00000000),
+            (0x02000056, "0", 0x00000000),
+            (0x03000000, "0", 0x00000000),
+            (0x01123456, "12", 0x01120000),
+            (0x02008000, "80", 0x02008000),
+            (0x05009234, "92340000", 0x05009234),
+            (0x20123456, &format!("123456{}", "0".repeat(58)), 0x20123456),
+        ] {
+            assert_eq!(bits_to_target(bits).unwrap(), number(target));
+            assert_eq!(target_to_bits(&number(target)), reencoded);
+        }
+        // A negative mantissa.
+        assert_eq!(
+            bits_to_target(0x04923456),
+            Err(Errors::InvalidCompactTarget(0x04923456))
+        );
+        assert_eq!(target_to_bits(&-number("12345600")), 0x04923456);
+        // More than 256 bits.
+        assert!(bits_to_target(0xff123456).is_err());
+        assert!(bits_to_target(0x21010000).is_err());
+        assert!(bits_to_target(0x22000001).is_ok());
+    }
+
+    #[test]
+    fn test_header_pow() {
+        let header = BlockHeader::from_hex(RAW_HEADER).unwrap();
+        assert_eq!(
+            header.target().unwrap(),
+            number("13ce9000000000000000000000000000000000000000000")
+        );
+        assert_eq!(target_to_bits(&header.target().unwrap()), header.bits);
+        assert!(header.check_pow());
+        assert_eq!(header.difficulty().floor(), 888171856257.0);
+
+        // Almost certainly above the target with any other nonce.
+        let mut other = header;
+        other.nonce += 1;
+        assert!(!other.check_pow());
+        let mut zero_target = header;
+        zero_target.bits = 0x01003456;
+        assert!(!zero_target.check_pow());
+    }
+
+    fn epoch_end(bits: u32, timestamp: u32) -> BlockHeader {
+        let mut header = BlockHeader::from_hex(RAW_HEADER).unwrap();
+        header.bits = bits;
+        header.timestamp = timestamp;
+        header
+    }
+
+    #[test]
+    fn test_retarget() {
+        let pow_limit = bits_to_target(0x1d00ffff).unwrap();
+        let start = 1_500_000_000;
+        let timespan = POW_TARGET_TIMESPAN as u32;
+        let bits = 0x1801_3ce9;
+        let retarget = |elapsed: u32| {
+            calculate_next_work_required(&epoch_end(bits, start + elapsed), start, &pow_limit)
+                .unwrap()
+        };
+        let target = bits_to_target(bits).unwrap();
+
+        assert_eq!(retarget(timespan), bits);
+        assert_eq!(retarget(timespan / 2), target_to_bits(&(&target / 2)));
+        assert_eq!(retarget(timespan * 2), target_to_bits(&(&target * 2)));
+        // Clamped to a factor of 4, even with timestamps going backwards.
+        assert_eq!(retarget(timespan * 10), target_to_bits(&(&target * 4)));
+        assert_eq!(retarget(timespan / 10), target_to_bits(&(&target / 4)));
+        assert_eq!(
+            calculate_next_work_required(&epoch_end(bits, start - 1), start, &pow_limit),
+            Ok(target_to_bits(&(&target / 4)))
+        );
+
+        // Slow epochs at the minimum difficulty stay at the limit.
+        let easiest = epoch_end(0x1d00ffff, start + timespan * 4);
+        assert_eq!(
+            calculate_next_work_required(&easiest, start, &pow_limit),
+            Ok(0x1d00ffff)
+        );
+        // The result is truncated to what the compact encoding can represent.
+        assert_eq!(retarget(timespan + 1), 0x1801_3ce9);
+
+        // The first mainnet retarget, at height 32256, from blocks 30240 and 32255.
+        assert_eq!(
+            calculate_next_work_required(
+                &epoch_end(0x1d00ffff, 1262152739),
+                1261130161,
+                &pow_limit
+            ),
+            Ok(0x1d00d86a)
+        );
+
+        assert_eq!(DIFFICULTY_ADJUSTMENT_INTERVAL, 2016);
+        assert!(is_retarget_height(32256));
+        assert!(!is_retarget_height(32255));
+    }
+
+    #[test]
+    fn test_block_work() {
+        // The minimum difficulty takes 2^32 hashes, give or take.
+        assert_eq!(block_work(0x1d00ffff), BigInt::from(0x0001_0001_0001u64));
+        assert_eq!(block_work(0x207fffff), BigInt::from(2));
+        assert_eq!(block_work(0x01003456), BigInt::from(0));
+        assert_eq!(block_work(0x04923456), BigInt::from(0));
+    }
+
+    #[test]
+    fn test_minimum_difficulty() {
+        assert_eq!(difficulty(0x1d00ffff), 1.0);
+        assert_eq!(
+            bits_to_target(0x1d00ffff).unwrap(),
+            number(&format!("ffff{}", "0".repeat(52)))
+        );
+    }
+}
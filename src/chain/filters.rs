@@ -0,0 +1,480 @@
+// BIP158 filters of the connected blocks and the BIP157 messages light clients use
+// to fetch them: filters themselves, the chain of filter headers committing to them,
+// and checkpoints of that chain to download it in parallel from several peers.
+use super::headers::{HeaderChain, HeaderEntry};
+use super::utxo::BlockUndo;
+use crate::block::filter::{filter_header, BlockFilter, BASIC_FILTER_TYPE};
+use crate::block::Block;
+use crate::helper::{encode_var_bytes, encode_varint, read_array, read_var_bytes, read_varint};
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use std::io::Read;
+
+// Most blocks a getcfilters or getcfheaders may ask for.
+pub const MAX_GETCFILTERS_SIZE: u32 = 1000;
+pub const MAX_GETCFHEADERS_SIZE: u32 = 2000;
+// cfcheckpt gives the filter header of every block at a multiple of this height.
+pub const CFCHECKPT_INTERVAL: u32 = 1000;
+
+// The items of the messages are read one by one, so a count can't be used to
+// make us allocate too much.
+fn read_hashes(reader: &mut impl Read) -> Result<Vec<[u8; 32]>, Errors> {
+    let count = read_varint(reader)?;
+    (0..count).map(|_| read_array(reader)).collect()
+}
+
+fn encode_hashes(hashes: &[[u8; 32]]) -> Vec<u8> {
+    let mut result = encode_varint(hashes.len() as u64);
+    for hash in hashes {
+        result.extend(hash);
+    }
+    result
+}
+
+// Payload of getcfilters, and of getcfheaders which has the same fields: the blocks
+// from start_height up to stop_hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GetCFilters {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: [u8; 32],
+}
+
+pub type GetCFHeaders = GetCFilters;
+
+impl GetCFilters {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(GetCFilters {
+            filter_type: read_array::<1>(reader)?[0],
+            start_height: u32::from_le_bytes(read_array(reader)?),
+            stop_hash: read_array(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.extend(self.start_height.to_le_bytes());
+        result.extend(self.stop_hash);
+        result
+    }
+}
+
+// Payload of cfilter, one per block asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CFilter {
+    pub filter_type: u8,
+    pub block_hash: [u8; 32],
+    pub filter: Vec<u8>,
+}
+
+impl CFilter {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(CFilter {
+            filter_type: read_array::<1>(reader)?[0],
+            block_hash: read_array(reader)?,
+            filter: read_var_bytes(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.extend(self.block_hash);
+        result.extend(encode_var_bytes(&self.filter));
+        result
+    }
+
+    pub fn block_filter(&self) -> BlockFilter {
+        BlockFilter {
+            block_hash: self.block_hash,
+            filter: self.filter.clone(),
+        }
+    }
+}
+
+// Payload of cfheaders: the header before the range and the filter hashes in it,
+// from which the client computes the headers and checks them against others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CFHeaders {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub previous_filter_header: [u8; 32],
+    pub filter_hashes: Vec<[u8; 32]>,
+}
+
+impl CFHeaders {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(CFHeaders {
+            filter_type: read_array::<1>(reader)?[0],
+            stop_hash: read_array(reader)?,
+            previous_filter_header: read_array(reader)?,
+            filter_hashes: read_hashes(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.extend(self.stop_hash);
+        result.extend(self.previous_filter_header);
+        result.extend(encode_hashes(&self.filter_hashes));
+        result
+    }
+
+    // Filter headers of the range, oldest first.
+    pub fn filter_headers(&self) -> Vec<[u8; 32]> {
+        let mut prev = self.previous_filter_header;
+        self.filter_hashes
+            .iter()
+            .map(|filter_hash| {
+                prev = filter_header(filter_hash, &prev);
+                prev
+            })
+            .collect()
+    }
+}
+
+// Payload of getcfcheckpt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GetCFCheckpt {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+}
+
+impl GetCFCheckpt {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(GetCFCheckpt {
+            filter_type: read_array::<1>(reader)?[0],
+            stop_hash: read_array(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.extend(self.stop_hash);
+        result
+    }
+}
+
+// Payload of cfcheckpt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CFCheckpt {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub filter_headers: Vec<[u8; 32]>,
+}
+
+impl CFCheckpt {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(CFCheckpt {
+            filter_type: read_array::<1>(reader)?[0],
+            stop_hash: read_array(reader)?,
+            filter_headers: read_hashes(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.extend(self.stop_hash);
+        result.extend(encode_hashes(&self.filter_headers));
+        result
+    }
+}
+
+// Basic filters and filter headers by block hash, for every branch connected. A
+// filter whose parent has no header yet, as above a loaded snapshot, gets its
+// header once the parent's is known.
+#[derive(Clone, Debug, Default)]
+pub struct BlockFilterIndex {
+    filters: HashMap<[u8; 32], BlockFilter>,
+    headers: HashMap<[u8; 32], [u8; 32]>,
+    // Blocks waiting for the header of their parent, by parent hash.
+    pending: HashMap<[u8; 32], Vec<[u8; 32]>>,
+}
+
+impl BlockFilterIndex {
+    // Indexes the filter of block, whose spent coins are in undo.
+    pub fn add_block(&mut self, block: &Block, undo: &BlockUndo) {
+        let spent = undo
+            .spent
+            .iter()
+            .flatten()
+            .map(|coin| &coin.output.script_pubkey);
+        let filter = BlockFilter::basic(block, spent);
+        let hash = block.hash();
+        let prev_block = block.header.prev_block;
+        self.filters.insert(hash, filter);
+        if prev_block == [0; 32] {
+            self.set_header(hash, [0; 32]);
+        } else if let Some(prev_header) = self.headers.get(&prev_block).copied() {
+            self.set_header(hash, prev_header);
+        } else {
+            self.pending.entry(prev_block).or_default().push(hash);
+        }
+    }
+
+    fn set_header(&mut self, hash: [u8; 32], prev_header: [u8; 32]) {
+        let mut queue = vec![(hash, prev_header)];
+        while let Some((hash, prev_header)) = queue.pop() {
+            let header = self.filters[&hash].header(&prev_header);
+            self.headers.insert(hash, header);
+            for child in self.pending.remove(&hash).unwrap_or_default() {
+                queue.push((child, header));
+            }
+        }
+    }
+
+    pub fn get_filter(&self, hash: &[u8; 32]) -> Option<&BlockFilter> {
+        self.filters.get(hash)
+    }
+
+    pub fn get_header(&self, hash: &[u8; 32]) -> Option<[u8; 32]> {
+        self.headers.get(hash).copied()
+    }
+
+    // The stop block of a request, the blocks being counted back from it as it
+    // needn't be on the best chain.
+    fn stop_entry<'a>(
+        &self,
+        headers: &'a HeaderChain,
+        filter_type: u8,
+        stop_hash: &[u8; 32],
+    ) -> Result<&'a HeaderEntry, Errors> {
+        if filter_type != BASIC_FILTER_TYPE {
+            return Err(Errors::BadFilterRequest("unknown filter type"));
+        }
+        headers
+            .get(stop_hash)
+            .filter(|_| self.headers.contains_key(stop_hash))
+            .ok_or(Errors::BadFilterRequest("stop block is not indexed"))
+    }
+
+    // Hashes of the blocks from start_height up to the stop block.
+    fn block_range(
+        &self,
+        headers: &HeaderChain,
+        request: &GetCFilters,
+        max_size: u32,
+    ) -> Result<Vec<[u8; 32]>, Errors> {
+        let stop = self.stop_entry(headers, request.filter_type, &request.stop_hash)?;
+        if request.start_height > stop.height {
+            return Err(Errors::BadFilterRequest(
+                "start height is after the stop block",
+            ));
+        }
+        if stop.height - request.start_height >= max_size {
+            return Err(Errors::BadFilterRequest("too many blocks requested"));
+        }
+        let mut hashes = Vec::new();
+        let mut current = stop;
+        loop {
+            hashes.push(current.hash);
+            if current.height == request.start_height {
+                break;
+            }
+            current = headers.prev(current).unwrap();
+        }
+        hashes.reverse();
+        Ok(hashes)
+    }
+
+    // Answers getcfilters with a cfilter for every block of the range.
+    pub fn handle_getcfilters(
+        &self,
+        headers: &HeaderChain,
+        request: &GetCFilters,
+    ) -> Result<Vec<CFilter>, Errors> {
+        self.block_range(headers, request, MAX_GETCFILTERS_SIZE)?
+            .into_iter()
+            .map(|hash| {
+                let filter = self
+                    .get_filter(&hash)
+                    .ok_or(Errors::BadFilterRequest("block is not indexed"))?;
+                Ok(CFilter {
+                    filter_type: BASIC_FILTER_TYPE,
+                    block_hash: hash,
+                    filter: filter.filter.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn handle_getcfheaders(
+        &self,
+        headers: &HeaderChain,
+        request: &GetCFHeaders,
+    ) -> Result<CFHeaders, Errors> {
+        let hashes = self.block_range(headers, request, MAX_GETCFHEADERS_SIZE)?;
+        let first = headers.get(&hashes[0]).unwrap();
+        let previous_filter_header = match headers.prev(first) {
+            Some(prev) => self
+                .get_header(&prev.hash)
+                .ok_or(Errors::BadFilterRequest("block is not indexed"))?,
+            None => [0; 32],
+        };
+        let filter_hashes = hashes
+            .iter()
+            .map(|hash| {
+                self.get_filter(hash)
+                    .map(BlockFilter::filter_hash)
+                    .ok_or(Errors::BadFilterRequest("block is not indexed"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(CFHeaders {
+            filter_type: BASIC_FILTER_TYPE,
+            stop_hash: request.stop_hash,
+            previous_filter_header,
+            filter_hashes,
+        })
+    }
+
+    pub fn handle_getcfcheckpt(
+        &self,
+        headers: &HeaderChain,
+        request: &GetCFCheckpt,
+    ) -> Result<CFCheckpt, Errors> {
+        let stop = self.stop_entry(headers, request.filter_type, &request.stop_hash)?;
+        let filter_headers = (1..=stop.height / CFCHECKPT_INTERVAL)
+            .map(|i| {
+                let entry = headers.ancestor(stop, i * CFCHECKPT_INTERVAL).unwrap();
+                self.get_header(&entry.hash)
+                    .ok_or(Errors::BadFilterRequest("block is not indexed"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(CFCheckpt {
+            filter_type: BASIC_FILTER_TYPE,
+            stop_hash: request.stop_hash,
+            filter_headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod filters_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_util::{coinbase, mine};
+
+    // Regtest blocks on top of genesis, each paying to a script of its own.
+    fn chain(count: u32) -> (HeaderChain, BlockFilterIndex, Vec<Block>) {
+        let network = Network::Regtest;
+        let mut headers = HeaderChain::new(network);
+        let mut index = BlockFilterIndex::default();
+        let mut blocks = vec![network.genesis_block()];
+        index.add_block(&blocks[0], &BlockUndo::default());
+        for height in 1..=count {
+            let mut coinbase = coinbase(height, 0, 50_0000_0000);
+            coinbase.outputs[0].script_pubkey = vec![0x51, height as u8].into();
+            let block = mine(&blocks.last().unwrap().header, vec![coinbase]);
+            headers.accept_header(block.header).unwrap();
+            index.add_block(&block, &BlockUndo::default());
+            blocks.push(block);
+        }
+        (headers, index, blocks)
+    }
+
+    #[test]
+    fn test_getcfilters_and_getcfheaders() {
+        let (headers, index, blocks) = chain(5);
+        let request = GetCFilters {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: 2,
+            stop_hash: blocks[4].hash(),
+        };
+        let bytes = request.serialize();
+        assert_eq!(bytes.len(), 37);
+        assert_eq!(GetCFilters::parse(&mut bytes.as_slice()).unwrap(), request);
+
+        let filters = index.handle_getcfilters(&headers, &request).unwrap();
+        assert_eq!(filters.len(), 3);
+        assert_eq!(filters[0].block_hash, blocks[2].hash());
+        let bytes = filters[0].serialize();
+        assert_eq!(CFilter::parse(&mut bytes.as_slice()).unwrap(), filters[0]);
+        let script = blocks[3].transactions[0].outputs[0]
+            .script_pubkey
+            .as_bytes();
+        assert_eq!(filters[1].block_filter().match_any(&[script]), Ok(true));
+
+        let cfheaders = index.handle_getcfheaders(&headers, &request).unwrap();
+        assert_eq!(
+            cfheaders.previous_filter_header,
+            index.get_header(&blocks[1].hash()).unwrap()
+        );
+        let expected: Vec<[u8; 32]> = blocks[2..=4]
+            .iter()
+            .map(|block| index.get_header(&block.hash()).unwrap())
+            .collect();
+        assert_eq!(cfheaders.filter_headers(), expected);
+        let bytes = cfheaders.serialize();
+        assert_eq!(CFHeaders::parse(&mut bytes.as_slice()).unwrap(), cfheaders);
+
+        // From genesis the previous header is all zeros.
+        let from_genesis = GetCFHeaders {
+            start_height: 0,
+            ..request
+        };
+        let cfheaders = index.handle_getcfheaders(&headers, &from_genesis).unwrap();
+        assert_eq!(cfheaders.previous_filter_header, [0; 32]);
+        assert_eq!(cfheaders.filter_hashes.len(), 5);
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let (headers, index, blocks) = chain(2);
+        let request = GetCFilters {
+            filter_type: 1,
+            start_height: 0,
+            stop_hash: blocks[2].hash(),
+        };
+        assert_eq!(
+            index.handle_getcfilters(&headers, &request),
+            Err(Errors::BadFilterRequest("unknown filter type"))
+        );
+        let request = GetCFilters {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: 3,
+            ..request
+        };
+        assert_eq!(
+            index.handle_getcfilters(&headers, &request),
+            Err(Errors::BadFilterRequest(
+                "start height is after the stop block"
+            ))
+        );
+        let request = GetCFilters {
+            stop_hash: [1; 32],
+            ..request
+        };
+        assert_eq!(
+            index.handle_getcfheaders(&headers, &request),
+            Err(Errors::BadFilterRequest("stop block is not indexed"))
+        );
+    }
+
+    #[test]
+    fn test_getcfcheckpt() {
+        let (headers, index, blocks) = chain(3);
+        let request = GetCFCheckpt {
+            filter_type: BASIC_FILTER_TYPE,
+            stop_hash: blocks[3].hash(),
+        };
+        let checkpt = index.handle_getcfcheckpt(&headers, &request).unwrap();
+        // Too short a chain for any checkpoint.
+        assert!(checkpt.filter_headers.is_empty());
+        let bytes = checkpt.serialize();
+        assert_eq!(CFCheckpt::parse(&mut bytes.as_slice()).unwrap(), checkpt);
+    }
+
+    #[test]
+    fn test_headers_wait_for_the_parent() {
+        let (_, full, blocks) = chain(3);
+        let mut index = BlockFilterIndex::default();
+        index.add_block(&blocks[3], &BlockUndo::default());
+        index.add_block(&blocks[2], &BlockUndo::default());
+        assert_eq!(index.get_header(&blocks[3].hash()), None);
+        index.add_block(&blocks[1], &BlockUndo::default());
+        index.add_block(&blocks[0], &BlockUndo::default());
+        assert_eq!(
+            index.get_header(&blocks[3].hash()),
+            full.get_header(&blocks[3].hash())
+        );
+    }
+}
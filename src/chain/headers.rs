@@ -0,0 +1,564 @@
+// Every valid header seen, as a tree rooted at the genesis block, together with the
+// chain of most cumulative work through it. The part of block validation that only
+// needs headers (ContextualCheckBlockHeader in Bitcoin Core) is done here.
+use crate::block::pow::{
+    calculate_next_work_required, DIFFICULTY_ADJUSTMENT_INTERVAL, POW_TARGET_SPACING,
+};
+use crate::block::{block_work, BlockHeader};
+use crate::network::Network;
+use crate::transaction::txid_to_hex;
+use crate::types::errors::ValidationError;
+use num_bigint::BigInt;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Blocks must be more recent than the median of the previous ones.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+// And no more than two hours ahead of the local clock.
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+// Median timestamp of the last MEDIAN_TIME_SPAN headers, given oldest first, or of
+// all of them near genesis. Timestamps need not increase so they are sorted first.
+// Blocks must be later than the median of their parents, and BIP113 compares
+// locktimes to it.
+pub fn median_time_past(headers: &[BlockHeader]) -> u32 {
+    let start = headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut times: Vec<u32> = headers[start..]
+        .iter()
+        .map(|header| header.timestamp)
+        .collect();
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderEntry {
+    pub header: BlockHeader,
+    pub hash: [u8; 32],
+    pub height: u32,
+    // Work of this block and all its ancestors.
+    pub chain_work: BigInt,
+}
+
+#[derive(Clone, Debug)]
+pub struct HeaderChain {
+    network: Network,
+    entries: HashMap<[u8; 32], HeaderEntry>,
+    // Block hashes of the best chain, indexed by height.
+    best: Vec<[u8; 32]>,
+    checkpoints: BTreeMap<u32, [u8; 32]>,
+}
+
+impl HeaderChain {
+    pub fn new(network: Network) -> Self {
+        let header = network.genesis_header();
+        let genesis = HeaderEntry {
+            header,
+            hash: header.hash(),
+            height: 0,
+            chain_work: block_work(header.bits),
+        };
+        HeaderChain {
+            network,
+            best: vec![genesis.hash],
+            entries: HashMap::from([(genesis.hash, genesis)]),
+            checkpoints: network.checkpoints().into_iter().collect(),
+        }
+    }
+
+    // Replaces the network's checkpoints.
+    pub fn with_checkpoints(mut self, checkpoints: Vec<(u32, [u8; 32])>) -> Self {
+        self.checkpoints = checkpoints.into_iter().collect();
+        self
+    }
+
+    // Height of the highest checkpoint already accepted.
+    pub fn last_checkpoint(&self) -> Option<u32> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .find(|(_, hash)| self.contains(hash))
+            .map(|(height, _)| *height)
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn get(&self, hash: &[u8; 32]) -> Option<&HeaderEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    // Headers known, on any branch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn tip(&self) -> &HeaderEntry {
+        &self.entries[self.best.last().unwrap()]
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tip().height
+    }
+
+    // Entry at height on the best chain.
+    pub fn at_height(&self, height: u32) -> Option<&HeaderEntry> {
+        self.best
+            .get(height as usize)
+            .map(|hash| &self.entries[hash])
+    }
+
+    pub fn is_in_best_chain(&self, hash: &[u8; 32]) -> bool {
+        self.get(hash)
+            .is_some_and(|entry| self.best.get(entry.height as usize) == Some(hash))
+    }
+
+    pub fn prev(&self, entry: &HeaderEntry) -> Option<&HeaderEntry> {
+        if entry.height == 0 {
+            return None;
+        }
+        self.entries.get(&entry.header.prev_block)
+    }
+
+    // Ancestor of entry at height, through the best chain index when entry is on it.
+    pub fn ancestor<'a>(&'a self, entry: &'a HeaderEntry, height: u32) -> Option<&'a HeaderEntry> {
+        if height > entry.height {
+            return None;
+        }
+        if self.is_in_best_chain(&entry.hash) {
+            return self.at_height(height);
+        }
+        let mut current = entry;
+        while current.height > height {
+            current = self.prev(current)?;
+        }
+        Some(current)
+    }
+
+    // Last block that entry's branch shares with the best chain.
+    pub fn fork_point<'a>(&'a self, entry: &'a HeaderEntry) -> &'a HeaderEntry {
+        let mut current = entry;
+        while !self.is_in_best_chain(&current.hash) {
+            current = self.prev(current).unwrap();
+        }
+        current
+    }
+
+    // Hashes of entry and its ancestors, densely for the last 10 and then doubling the
+    // step back to genesis, so a peer finds the fork point in a few hashes
+    // (GetLocator in Bitcoin Core).
+    pub fn locator(&self, entry: &HeaderEntry) -> Vec<[u8; 32]> {
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut current = entry;
+        loop {
+            locator.push(current.hash);
+            if current.height == 0 {
+                break;
+            }
+            let height = current.height.saturating_sub(step);
+            current = self.ancestor(current, height).unwrap();
+            if locator.len() > 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+
+    // Best chain headers following the first locator hash on it, or genesis, up to
+    // and including stop and no more than max of them. How getheaders is answered.
+    pub fn find_headers(
+        &self,
+        locator: &[[u8; 32]],
+        stop: &[u8; 32],
+        max: usize,
+    ) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .filter_map(|hash| self.get(hash))
+            .find(|entry| self.is_in_best_chain(&entry.hash))
+            .map_or(0, |entry| entry.height);
+        let mut headers = Vec::new();
+        for hash in self.best.iter().skip(start as usize + 1).take(max) {
+            headers.push(self.entries[hash].header);
+            if hash == stop {
+                break;
+            }
+        }
+        headers
+    }
+
+    // Median timestamp of entry and up to 10 of its ancestors.
+    pub fn median_time_past(&self, entry: &HeaderEntry) -> u32 {
+        let mut headers = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = Some(entry);
+        while let Some(entry) = current {
+            if headers.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            headers.push(entry.header);
+            current = self.prev(entry);
+        }
+        median_time_past(&headers)
+    }
+
+    // GetNextWorkRequired: the bits a block following prev must have.
+    pub fn next_work_required(&self, prev: &HeaderEntry, header: &BlockHeader) -> u32 {
+        let network = self.network;
+        let pow_limit_bits = network.pow_limit_bits();
+        let height = prev.height + 1;
+        if !height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+            if !network.allows_min_difficulty_blocks() {
+                return prev.header.bits;
+            }
+            if header.timestamp as i64 > prev.header.timestamp as i64 + POW_TARGET_SPACING * 2 {
+                return pow_limit_bits;
+            }
+            // Otherwise the last target that wasn't a minimum difficulty exception.
+            let mut current = prev;
+            while !current
+                .height
+                .is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
+                && current.header.bits == pow_limit_bits
+            {
+                match self.prev(current) {
+                    Some(parent) => current = parent,
+                    None => break,
+                }
+            }
+            return current.header.bits;
+        }
+        if network.no_pow_retargeting() {
+            return prev.header.bits;
+        }
+        let first = self
+            .ancestor(prev, height - DIFFICULTY_ADJUSTMENT_INTERVAL)
+            .unwrap();
+        calculate_next_work_required(&prev.header, first.header.timestamp, &network.pow_limit())
+            .unwrap_or(pow_limit_bits)
+    }
+
+    // Adds a header whose parent is known, switching the best chain to it if it now
+    // has the most work. Ties keep the chain seen first. Returns the header's hash.
+    pub fn accept_header(&mut self, header: BlockHeader) -> Result<[u8; 32], ValidationError> {
+        let hash = header.hash();
+        if self.contains(&hash) {
+            return Ok(hash);
+        }
+        let prev = self
+            .get(&header.prev_block)
+            .ok_or_else(|| ValidationError::PrevBlockNotFound(txid_to_hex(&header.prev_block)))?;
+        let height = prev.height + 1;
+        if self
+            .checkpoints
+            .get(&height)
+            .is_some_and(|checkpoint| *checkpoint != hash)
+        {
+            return Err(ValidationError::CheckpointMismatch(height));
+        }
+        // Blocks up to a known checkpoint are all known, so this is a fork below it.
+        if self.last_checkpoint().is_some_and(|last| height < last) {
+            return Err(ValidationError::ForkBeforeCheckpoint);
+        }
+        if header.bits != self.next_work_required(prev, &header) {
+            return Err(ValidationError::BadDiffBits);
+        }
+        if !header.check_pow() {
+            return Err(ValidationError::HighHash);
+        }
+        if header.timestamp <= self.median_time_past(prev) {
+            return Err(ValidationError::TimeTooOld);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if header.timestamp as u64 > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(ValidationError::TimeTooNew);
+        }
+
+        let entry = HeaderEntry {
+            header,
+            hash,
+            height,
+            chain_work: &prev.chain_work + block_work(header.bits),
+        };
+        let new_tip = entry.chain_work > self.tip().chain_work;
+        self.entries.insert(hash, entry);
+        if new_tip {
+            self.set_tip(hash);
+        }
+        Ok(hash)
+    }
+
+    // Rewrites the best chain index from the fork point up to the new tip.
+    fn set_tip(&mut self, hash: [u8; 32]) {
+        let mut branch = Vec::new();
+        let mut current = &self.entries[&hash];
+        while !self.is_in_best_chain(&current.hash) {
+            branch.push(current.hash);
+            current = self.prev(current).unwrap();
+        }
+        self.best.truncate(current.height as usize + 1);
+        self.best.extend(branch.into_iter().rev());
+    }
+}
+
+#[cfg(test)]
+mod headers_tests {
+    use super::*;
+    use crate::test_util::solve;
+
+    // Header following prev, with the first nonce meeting the regtest target.
+    fn mine(prev: &HeaderEntry, timestamp: u32, tag: u8) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: 4,
+            prev_block: prev.hash,
+            merkle_root: [tag; 32],
+            timestamp,
+            bits: Network::Regtest.pow_limit_bits(),
+            nonce: 0,
+        };
+        solve(&mut header);
+        header
+    }
+
+    // Extends the chain from the entry with hash from, returning the new hashes.
+    fn extend(chain: &mut HeaderChain, from: [u8; 32], count: u32, tag: u8) -> Vec<[u8; 32]> {
+        let mut hashes = Vec::new();
+        let mut prev = chain.get(&from).unwrap().clone();
+        for _ in 0..count {
+            let header = mine(&prev, prev.header.timestamp + 600, tag);
+            let hash = chain.accept_header(header).unwrap();
+            prev = chain.get(&hash).unwrap().clone();
+            hashes.push(hash);
+        }
+        hashes
+    }
+
+    #[test]
+    fn test_mainnet_block_one() {
+        let mut chain = HeaderChain::new(Network::Mainnet);
+        assert_eq!(chain.tip().hash, Network::Mainnet.genesis_hash());
+        let header = BlockHeader::from_hex("010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299").unwrap();
+        let hash = chain.accept_header(header).unwrap();
+        assert_eq!(
+            txid_to_hex(&hash),
+            "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048"
+        );
+        assert_eq!(chain.height(), 1);
+        assert_eq!(chain.tip().chain_work, BigInt::from(0x0002_0002_0002u64));
+        // Accepting it again changes nothing.
+        assert_eq!(chain.accept_header(header), Ok(hash));
+        assert_eq!(chain.len(), 2);
+
+        let mut bad_nonce = header;
+        bad_nonce.nonce += 1;
+        assert_eq!(
+            chain.accept_header(bad_nonce),
+            Err(ValidationError::HighHash)
+        );
+        let mut bad_bits = header;
+        bad_bits.bits = 0x1d00fffe;
+        assert_eq!(
+            chain.accept_header(bad_bits),
+            Err(ValidationError::BadDiffBits)
+        );
+        let mut orphan = header;
+        orphan.prev_block = [1; 32];
+        assert!(matches!(
+            chain.accept_header(orphan),
+            Err(ValidationError::PrevBlockNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_most_work_chain_wins() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let genesis = chain.tip().hash;
+        let main = extend(&mut chain, genesis, 3, 1);
+        assert_eq!(chain.tip().hash, main[2]);
+
+        // A branch of the same length does not replace the chain seen first.
+        let side = extend(&mut chain, main[0], 2, 2);
+        assert_eq!(chain.tip().hash, main[2]);
+        assert!(!chain.is_in_best_chain(&side[1]));
+        assert_eq!(chain.fork_point(chain.get(&side[1]).unwrap()).hash, main[0]);
+        assert_eq!(
+            chain
+                .ancestor(chain.get(&side[1]).unwrap(), 1)
+                .unwrap()
+                .hash,
+            main[0]
+        );
+
+        // One more block makes it the best chain.
+        let last = extend(&mut chain, side[1], 1, 2);
+        let side = [side, last].concat();
+        assert_eq!(chain.tip().hash, side[2]);
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.at_height(1).unwrap().hash, main[0]);
+        assert_eq!(chain.at_height(2).unwrap().hash, side[0]);
+        assert!(!chain.is_in_best_chain(&main[1]));
+        assert_eq!(chain.len(), 7);
+    }
+
+    #[test]
+    fn test_locator_and_find_headers() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let genesis = chain.tip().hash;
+        let hashes = [vec![genesis], extend(&mut chain, genesis, 30, 1)].concat();
+        let locator = chain.locator(chain.tip());
+        let heights: Vec<u32> = locator
+            .iter()
+            .map(|hash| chain.get(hash).unwrap().height)
+            .collect();
+        assert_eq!(
+            heights,
+            [30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 20, 19, 17, 13, 5, 0]
+        );
+
+        // A peer on a side branch gets the headers after the fork point.
+        let mut other = HeaderChain::new(Network::Regtest);
+        extend(&mut other, genesis, 10, 1);
+        let side = extend(&mut other, hashes[10], 5, 2);
+        let headers = chain.find_headers(&other.locator(other.tip()), &[0; 32], 2000);
+        assert_eq!(headers.len(), 20);
+        assert_eq!(headers[0].hash(), hashes[11]);
+        assert!(!side.contains(&headers[0].prev_block));
+
+        assert_eq!(chain.find_headers(&[], &hashes[3], 2000).len(), 3);
+        assert_eq!(
+            chain.find_headers(&[[7; 32]], &[0; 32], 4)[0].hash(),
+            hashes[1]
+        );
+        assert!(chain.find_headers(&[hashes[30]], &[0; 32], 2000).is_empty());
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let genesis = chain.tip().hash;
+        let hashes = extend(&mut chain, genesis, 12, 1);
+        let tip = chain.tip().clone();
+        // The 11 last blocks are 600 seconds apart, the median is the 6th from the tip.
+        assert_eq!(chain.median_time_past(&tip), tip.header.timestamp - 5 * 600);
+        let prev = chain.get(&hashes[11]).unwrap().clone();
+        let too_old = mine(&prev, chain.median_time_past(&prev), 3);
+        assert_eq!(
+            chain.accept_header(too_old),
+            Err(ValidationError::TimeTooOld)
+        );
+        let too_new = mine(&prev, u32::MAX, 3);
+        assert_eq!(
+            chain.accept_header(too_new),
+            Err(ValidationError::TimeTooNew)
+        );
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let genesis = chain.tip().hash;
+        let main = extend(&mut chain.clone(), genesis, 3, 1);
+        chain = chain.with_checkpoints(vec![(2, main[1])]);
+        assert_eq!(chain.last_checkpoint(), None);
+
+        // Before the checkpoint is reached, only the header at its height is checked.
+        let side = extend(&mut chain, genesis, 1, 2);
+        let prev = chain.get(&side[0]).unwrap().clone();
+        let wrong = mine(&prev, prev.header.timestamp + 600, 2);
+        assert_eq!(
+            chain.accept_header(wrong),
+            Err(ValidationError::CheckpointMismatch(2))
+        );
+
+        assert_eq!(extend(&mut chain, genesis, 3, 1), main);
+        assert_eq!(chain.last_checkpoint(), Some(2));
+        let genesis_entry = chain.get(&genesis).unwrap().clone();
+        let fork = mine(&genesis_entry, genesis_entry.header.timestamp + 900, 3);
+        assert_eq!(
+            chain.accept_header(fork),
+            Err(ValidationError::ForkBeforeCheckpoint)
+        );
+        // Forks at or above the checkpoint are still fine.
+        extend(&mut chain, main[1], 1, 3);
+    }
+
+    #[test]
+    fn test_median_of_headers() {
+        let genesis = Network::Regtest.genesis_header();
+        let with_times = |times: &[u32]| -> Vec<BlockHeader> {
+            times
+                .iter()
+                .map(|timestamp| BlockHeader {
+                    timestamp: *timestamp,
+                    ..genesis
+                })
+                .collect()
+        };
+        assert_eq!(median_time_past(&with_times(&[7])), 7);
+        // Out of order timestamps are sorted, and an even count takes the upper one.
+        assert_eq!(median_time_past(&with_times(&[5, 1, 9, 3])), 5);
+        // Only the last 11 count.
+        let times: Vec<u32> = (1..=20).collect();
+        assert_eq!(median_time_past(&with_times(&times)), 15);
+    }
+
+    #[test]
+    fn test_min_difficulty_exception() {
+        // On testnet a block over 20 minutes late may use the easiest target, and the
+        // ones after it go back to the last real target.
+        let chain = HeaderChain::new(Network::Testnet);
+        let mut prev = chain.tip().clone();
+        prev.height = 5;
+        prev.header.bits = 0x1c00ffff;
+        let mut late = prev.header;
+        late.timestamp += 1201;
+        assert_eq!(chain.next_work_required(&prev, &late), 0x1d00ffff);
+        late.timestamp -= 1;
+        assert_eq!(chain.next_work_required(&prev, &late), 0x1c00ffff);
+
+        let mainnet = HeaderChain::new(Network::Mainnet);
+        late.timestamp += 10_000;
+        assert_eq!(mainnet.next_work_required(&prev, &late), 0x1c00ffff);
+    }
+
+    #[test]
+    fn test_reset_after_min_difficulty_blocks() {
+        // Entries are added directly, as testnet headers can't be mined here: a block
+        // at the real target followed by two minimum difficulty ones.
+        let mut chain = HeaderChain::new(Network::Testnet);
+        let mut prev = chain.tip().clone();
+        for bits in [0x1c00ffff, 0x1d00ffff, 0x1d00ffff] {
+            let mut header = prev.header;
+            header.prev_block = prev.hash;
+            header.timestamp += 600;
+            header.bits = bits;
+            let entry = HeaderEntry {
+                header,
+                hash: header.hash(),
+                height: prev.height + 1,
+                chain_work: prev.chain_work.clone(),
+            };
+            chain.entries.insert(entry.hash, entry.clone());
+            prev = entry;
+        }
+        let mut next = prev.header;
+        next.timestamp += 600;
+        assert_eq!(chain.next_work_required(&prev, &next), 0x1c00ffff);
+        // Still late, the block may be at the minimum difficulty again.
+        next.timestamp += 601;
+        assert_eq!(chain.next_work_required(&prev, &next), 0x1d00ffff);
+    }
+}
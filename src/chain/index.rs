@@ -0,0 +1,165 @@
+// Optional indexes of the connected chain, as -txindex and -addressindex: where each
+// transaction was mined, and which transactions paid or spent each script. Both
+// follow the active chain, blocks being added as they connect and removed as they
+// disconnect.
+use super::utxo::BlockUndo;
+use crate::block::Block;
+use crate::transaction::OutPoint;
+use std::collections::HashMap;
+
+// Block of a transaction and its position in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxPosition {
+    pub block: [u8; 32],
+    pub index: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TxIndex {
+    positions: HashMap<[u8; 32], TxPosition>,
+}
+
+impl TxIndex {
+    pub fn add_block(&mut self, block: &Block) {
+        let hash = block.hash();
+        for (index, tx) in block.transactions.iter().enumerate() {
+            let position = TxPosition {
+                block: hash,
+                index: index as u32,
+            };
+            self.positions.insert(tx.txid(), position);
+        }
+    }
+
+    // A txid mined again in a later block (duplicate coinbases before BIP30) keeps
+    // pointing there.
+    pub fn remove_block(&mut self, block: &Block) {
+        let hash = block.hash();
+        for tx in &block.transactions {
+            let txid = tx.txid();
+            if self.positions.get(&txid).is_some_and(|p| p.block == hash) {
+                self.positions.remove(&txid);
+            }
+        }
+    }
+
+    pub fn get(&self, txid: &[u8; 32]) -> Option<TxPosition> {
+        self.positions.get(txid).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressEvent {
+    // Output vout of the transaction pays the script.
+    Received { vout: u32, value: u64 },
+    // An input of the transaction spends this output paying the script.
+    Spent { outpoint: OutPoint, value: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressEntry {
+    pub txid: [u8; 32],
+    pub block: [u8; 32],
+    pub height: u32,
+    pub event: AddressEvent,
+}
+
+// Entries by script_pubkey, so every script is indexed whether it has an address
+// form or not.
+#[derive(Clone, Debug, Default)]
+pub struct AddressIndex {
+    entries: HashMap<Vec<u8>, Vec<AddressEntry>>,
+}
+
+impl AddressIndex {
+    // Indexes block at height, whose spent coins are in undo.
+    pub fn add_block(&mut self, block: &Block, height: u32, undo: &BlockUndo) {
+        for (script, entry) in block_entries(block, height, undo) {
+            self.entries.entry(script).or_default().push(entry);
+        }
+    }
+
+    pub fn remove_block(&mut self, block: &Block, undo: &BlockUndo) {
+        let hash = block.hash();
+        for (script, _) in block_entries(block, 0, undo) {
+            if let Some(entries) = self.entries.get_mut(&script) {
+                entries.retain(|entry| entry.block != hash);
+                if entries.is_empty() {
+                    self.entries.remove(&script);
+                }
+            }
+        }
+    }
+
+    // Entries of script, oldest block first and in block order within each.
+    pub fn history(&self, script_pubkey: &[u8]) -> Vec<AddressEntry> {
+        let mut history = self.entries.get(script_pubkey).cloned().unwrap_or_default();
+        history.sort_by_key(|entry| entry.height);
+        history
+    }
+
+    // Transactions paying or spending script, each once, oldest first.
+    pub fn txids(&self, script_pubkey: &[u8]) -> Vec<[u8; 32]> {
+        let mut txids: Vec<[u8; 32]> = Vec::new();
+        for entry in self.history(script_pubkey) {
+            if txids.last() != Some(&entry.txid) {
+                txids.push(entry.txid);
+            }
+        }
+        txids
+    }
+
+    // What script was ever paid, and what of it is left unspent.
+    pub fn balance(&self, script_pubkey: &[u8]) -> (u64, u64) {
+        let (mut received, mut spent) = (0, 0);
+        for entry in self.entries.get(script_pubkey).into_iter().flatten() {
+            match entry.event {
+                AddressEvent::Received { value, .. } => received += value,
+                AddressEvent::Spent { value, .. } => spent += value,
+            }
+        }
+        (received, received - spent)
+    }
+}
+
+// Spends come before the outputs of each transaction, as it spends them first.
+fn block_entries(block: &Block, height: u32, undo: &BlockUndo) -> Vec<(Vec<u8>, AddressEntry)> {
+    let hash = block.hash();
+    let mut result = Vec::new();
+    for (i, tx) in block.transactions.iter().enumerate() {
+        let txid = tx.txid();
+        let entry = |event| AddressEntry {
+            txid,
+            block: hash,
+            height,
+            event,
+        };
+        // The coinbase spends nothing, so undo has a list for each transaction after it.
+        let spent = i.checked_sub(1).and_then(|i| undo.spent.get(i));
+        for (input, coin) in tx.inputs.iter().zip(spent.into_iter().flatten()) {
+            let event = AddressEvent::Spent {
+                outpoint: input.previous_output,
+                value: coin.output.value,
+            };
+            let script = coin.output.script_pubkey.as_bytes().to_vec();
+            result.push((script, entry(event)));
+        }
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            let event = AddressEvent::Received {
+                vout: vout as u32,
+                value: output.value,
+            };
+            let script = output.script_pubkey.as_bytes().to_vec();
+            result.push((script, entry(event)));
+        }
+    }
+    result
+}
@@ -0,0 +1,25 @@
+// Chain state: the tree of known headers, the most-work chain through it and the
+// outputs that chain leaves unspent.
+pub mod filters;
+pub mod headers;
+pub mod index;
+pub mod signet;
+pub mod snapshot;
+pub mod state;
+pub mod store;
+pub mod utxo;
+pub mod validation;
+
+pub use filters::{
+    BlockFilterIndex, CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
+pub use headers::{median_time_past, HeaderChain, HeaderEntry};
+pub use index::{AddressEntry, AddressEvent, AddressIndex, TxIndex, TxPosition};
+pub use signet::{check_signet_block_solution, signet_magic, signet_solution, SignetTxs};
+pub use snapshot::{AssumeUtxoData, SnapshotMetadata};
+pub use state::{ChainState, ChainUpdate, MIN_BLOCKS_TO_KEEP, MIN_PRUNE_TARGET};
+pub use store::{BlockPosition, BlockStorage, BlockStore, MemoryBlockStore};
+pub use utxo::{BlockUndo, FileBackend, MemoryBackend, UtxoBackend, UtxoSet};
+pub use validation::{
+    check_block, check_block_inputs, check_witness_commitment, contextual_check_block,
+};
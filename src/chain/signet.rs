@@ -0,0 +1,258 @@
+// BIP325 signet: blocks must also be signed, by a solution to the network's
+// challenge script carried in the coinbase witness commitment output. The signature
+// covers the block with that solution taken out, through a pair of virtual
+// transactions like those of BIP322.
+use crate::block::merkle::WITNESS_COMMITMENT_HEADER;
+use crate::block::{merkle_root, Block};
+use crate::helper::hash256;
+use crate::script::interpreter::TransactionSignatureChecker;
+use crate::script::verify::verify_script;
+use crate::script::{Instruction, Script, VerificationFlags};
+use crate::transaction::{OutPoint, Transaction, TxIn, TxOut, Witness};
+use crate::types::errors::ValidationError;
+
+// Starts the push holding the solution in the witness commitment output.
+pub const SIGNET_HEADER: [u8; 4] = [0xec, 0xc7, 0xda, 0xa2];
+
+// Flags the solution is verified with (BLOCK_SCRIPT_VERIFY_FLAGS in Bitcoin Core).
+const SIGNET_SCRIPT_FLAGS: VerificationFlags = VerificationFlags::P2SH
+    .union(VerificationFlags::WITNESS)
+    .union(VerificationFlags::DERSIG)
+    .union(VerificationFlags::NULLDUMMY);
+
+// Network magic of the signet with this challenge: the first bytes of the hash of
+// the serialized challenge.
+pub fn signet_magic(challenge: &Script) -> [u8; 4] {
+    hash256(&challenge.serialize())[..4].try_into().unwrap()
+}
+
+// Push data of a solution, to append to the witness commitment output.
+pub fn signet_solution(script_sig: &Script, witness: &Witness) -> Vec<u8> {
+    let mut result = SIGNET_HEADER.to_vec();
+    result.extend(script_sig.serialize());
+    result.extend(witness.serialize());
+    result
+}
+
+// Script with the data of the first push holding a solution cut down to the header,
+// and that data. None if there is no such push.
+fn take_solution(script: &Script) -> Option<(Script, Vec<u8>)> {
+    let mut replacement = Script::new();
+    let mut solution = None;
+    for instruction in script.instructions() {
+        // Like Bitcoin Core, whatever follows a truncated push is dropped.
+        let Ok(instruction) = instruction else {
+            break;
+        };
+        match instruction {
+            Instruction::PushBytes(data) if !data.is_empty() => {
+                if solution.is_none()
+                    && data.len() > SIGNET_HEADER.len()
+                    && data.starts_with(&SIGNET_HEADER)
+                {
+                    solution = Some(data[SIGNET_HEADER.len()..].to_vec());
+                    replacement.push_slice(&SIGNET_HEADER);
+                } else {
+                    replacement.push_slice(data);
+                }
+            }
+            Instruction::PushBytes(_) => {
+                replacement.push_slice(&[]);
+            }
+            Instruction::Op(opcode) => {
+                replacement.push_opcode(opcode);
+            }
+            Instruction::Unknown(byte) => {
+                let mut bytes = replacement.into_bytes();
+                bytes.push(byte);
+                replacement = Script::from_bytes(bytes);
+            }
+        }
+    }
+    solution.map(|solution| (replacement, solution))
+}
+
+// The virtual transactions of a block: to_spend pays to the challenge and commits
+// to the block, to_sign spends it with the block's solution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignetTxs {
+    pub to_spend: Transaction,
+    pub to_sign: Transaction,
+}
+
+impl SignetTxs {
+    pub fn new(block: &Block, challenge: &Script) -> Result<Self, ValidationError> {
+        let parse_error = || ValidationError::BadSignetSolution("solution can't be parsed".into());
+        let mut coinbase = block.coinbase().ok_or_else(parse_error)?.clone();
+        // The same output Block::witness_commitment reads.
+        let index = coinbase
+            .outputs
+            .iter()
+            .rposition(|output| {
+                output.script_pubkey.len() >= 38
+                    && output.script_pubkey[..6] == WITNESS_COMMITMENT_HEADER
+            })
+            .ok_or_else(parse_error)?;
+
+        let mut to_sign = Transaction::new(
+            0,
+            vec![TxIn::new(OutPoint::null(), Script::new(), 0)],
+            vec![TxOut::new(0, vec![0x6a].into())],
+            0,
+        );
+        // No solution is fine for challenges anyone can satisfy, like OP_TRUE.
+        let commitment = &mut coinbase.outputs[index].script_pubkey;
+        if let Some((replacement, solution)) = take_solution(commitment) {
+            *commitment = replacement;
+            let mut reader = solution.as_slice();
+            to_sign.inputs[0].script_sig = Script::parse(&mut reader).map_err(|_| parse_error())?;
+            to_sign.inputs[0].witness = Witness::parse(&mut reader).map_err(|_| parse_error())?;
+            if !reader.is_empty() {
+                return Err(parse_error());
+            }
+        }
+
+        let mut txids = vec![coinbase.txid()];
+        txids.extend(block.iter().skip(1).map(Transaction::txid));
+        let mut block_data = block.header.version.to_le_bytes().to_vec();
+        block_data.extend(block.header.prev_block);
+        block_data.extend(merkle_root(&txids).0);
+        block_data.extend(block.header.timestamp.to_le_bytes());
+        let mut script_sig = Script::new();
+        script_sig.push_int(0).push_slice(&block_data);
+        let to_spend = Transaction::new(
+            0,
+            vec![TxIn::new(OutPoint::null(), script_sig, 0)],
+            vec![TxOut::new(0, challenge.clone())],
+            0,
+        );
+        to_sign.inputs[0].previous_output = OutPoint::new(to_spend.txid(), 0);
+        Ok(SignetTxs { to_spend, to_sign })
+    }
+}
+
+// Checked along with the rest of CheckBlock on signets. Genesis needs no solution.
+pub fn check_signet_block_solution(
+    block: &Block,
+    challenge: &Script,
+) -> Result<(), ValidationError> {
+    if block.header.prev_block == [0; 32] {
+        return Ok(());
+    }
+    let txs = SignetTxs::new(block, challenge)?;
+    let input = &txs.to_sign.inputs[0];
+    let prevouts = [txs.to_spend.outputs[0].clone()];
+    let checker = TransactionSignatureChecker::new(&txs.to_sign, 0, 0).with_prevouts(&prevouts);
+    verify_script(
+        &input.script_sig,
+        challenge,
+        &input.witness,
+        SIGNET_SCRIPT_FLAGS,
+        &checker,
+    )
+    .map_err(|e| ValidationError::BadSignetSolution(e.to_string()))
+}
+
+#[cfg(test)]
+mod signet_tests {
+    use super::*;
+    use crate::ecc::PrivateKey;
+    use crate::network::Network;
+    use crate::script::{encode_push, Opcode};
+    use crate::transaction::sighash::{SigHashType, SighashCache};
+    use num_bigint::BigInt;
+
+    // Block with a witness commitment, as every signet block needs one.
+    fn block() -> Block {
+        let mut script_sig = Script::new();
+        script_sig.push_int(1).push_int(0);
+        let mut commitment = WITNESS_COMMITMENT_HEADER.to_vec();
+        commitment.extend([0; 32]);
+        let coinbase = Transaction::new(
+            1,
+            vec![TxIn::new(OutPoint::null(), script_sig, 0xffffffff)],
+            vec![
+                TxOut::new(50_0000_0000, vec![0x51].into()),
+                TxOut::new(0, commitment.into()),
+            ],
+            0,
+        );
+        let genesis = Network::Signet.genesis_block();
+        let mut block = Block::new(genesis.header, vec![coinbase]);
+        block.header.prev_block = genesis.hash();
+        block.header.merkle_root = block.compute_merkle_root();
+        block
+    }
+
+    fn add_solution(block: &mut Block, solution: &[u8]) {
+        let output = &mut block.transactions[0].outputs[1];
+        let mut script = output.script_pubkey.clone().into_bytes();
+        script.extend(encode_push(solution));
+        output.script_pubkey = script.into();
+        block.header.merkle_root = block.compute_merkle_root();
+    }
+
+    #[test]
+    fn test_default_signet_magic() {
+        let challenge = Network::Signet.signet_challenge().unwrap();
+        assert_eq!(signet_magic(&challenge), Network::Signet.magic());
+    }
+
+    #[test]
+    fn test_signed_block() {
+        let key = PrivateKey::new(BigInt::from(12345)).unwrap();
+        let mut challenge = Script::new();
+        challenge
+            .push_slice(&key.public_key().sec(true))
+            .push_opcode(Opcode::OP_CHECKSIG);
+        let mut unsigned = block();
+        assert!(check_signet_block_solution(&unsigned, &challenge).is_err());
+
+        // Signers sign the block with just the header pushed, which is what it
+        // becomes once the solution is taken out again.
+        add_solution(&mut unsigned, &SIGNET_HEADER);
+        let txs = SignetTxs::new(&unsigned, &challenge).unwrap();
+        let signature = SighashCache::new(&txs.to_sign)
+            .sign_legacy_input(0, &key, challenge.as_bytes(), SigHashType::All)
+            .unwrap();
+        let mut script_sig = Script::new();
+        script_sig.push_slice(&signature);
+        let mut block = block();
+        add_solution(&mut block, &signet_solution(&script_sig, &Witness::new()));
+        assert_eq!(
+            SignetTxs::new(&block, &challenge).unwrap().to_spend,
+            txs.to_spend
+        );
+        assert_eq!(check_signet_block_solution(&block, &challenge), Ok(()));
+
+        // Any change to the block voids the signature.
+        let mut changed = block.clone();
+        changed.header.timestamp += 1;
+        assert!(check_signet_block_solution(&changed, &challenge).is_err());
+    }
+
+    #[test]
+    fn test_trivial_challenge_and_bad_solutions() {
+        let op_true = Script::from_bytes(vec![0x51]);
+        let mut block = block();
+        assert_eq!(check_signet_block_solution(&block, &op_true), Ok(()));
+        assert_eq!(
+            check_signet_block_solution(&Network::Signet.genesis_block(), &op_true),
+            Ok(())
+        );
+
+        let mut solution = signet_solution(&Script::new(), &Witness::new());
+        solution.push(0);
+        add_solution(&mut block, &solution);
+        assert_eq!(
+            check_signet_block_solution(&block, &op_true),
+            Err(ValidationError::BadSignetSolution(
+                "solution can't be parsed".into()
+            ))
+        );
+
+        let mut no_commitment = block.clone();
+        no_commitment.transactions[0].outputs.pop();
+        assert!(check_signet_block_solution(&no_commitment, &op_true).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// UTXO set snapshots, as loaded by assumeutxo in Bitcoin Core: a node can start from
+// the coins left by a recent block instead of connecting every block before it,
+// then check the snapshot by validating the historical chain in the background.
+use crate::helper::{hash256, read_array};
+use crate::transaction::OutPoint;
+use crate::types::errors::Errors;
+use crate::validation::Coin;
+use std::fs;
+use std::path::Path;
+
+pub const SNAPSHOT_MAGIC: [u8; 5] = *b"utxo\xff";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+// Header of a snapshot file, followed by coins_count outpoints each with its coin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotMetadata {
+    pub base_block: [u8; 32],
+    pub coins_count: u64,
+}
+
+// A snapshot trusted to be at block_hash, the block at height, whose coins hash to
+// commitment. Comes from the node's configuration, not from the file itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AssumeUtxoData {
+    pub height: u32,
+    pub block_hash: [u8; 32],
+    pub commitment: [u8; 32],
+}
+
+fn serialize_coin(outpoint: &OutPoint, coin: &Coin) -> Vec<u8> {
+    let mut result = outpoint.serialize();
+    result.extend(coin.serialize());
+    result
+}
+
+// Hash256 of every outpoint and coin, sorted by outpoint.
+pub fn utxo_commitment(coins: &[(OutPoint, Coin)]) -> [u8; 32] {
+    let mut sorted: Vec<&(OutPoint, Coin)> = coins.iter().collect();
+    sorted.sort_by_key(|(outpoint, _)| *outpoint);
+    let data: Vec<u8> = sorted
+        .into_iter()
+        .flat_map(|(outpoint, coin)| serialize_coin(outpoint, coin))
+        .collect();
+    hash256(&data)
+}
+
+// Writes the coins left by base_block to path, returning their commitment.
+pub fn write_snapshot(
+    path: impl AsRef<Path>,
+    base_block: [u8; 32],
+    coins: &[(OutPoint, Coin)],
+) -> Result<[u8; 32], Errors> {
+    let mut data = SNAPSHOT_MAGIC.to_vec();
+    data.extend(SNAPSHOT_VERSION.to_le_bytes());
+    data.extend(base_block);
+    data.extend((coins.len() as u64).to_le_bytes());
+    for (outpoint, coin) in coins {
+        data.extend(serialize_coin(outpoint, coin));
+    }
+    fs::write(path, data).map_err(|e| Errors::Io(e.to_string()))?;
+    Ok(utxo_commitment(coins))
+}
+
+pub fn read_snapshot(
+    path: impl AsRef<Path>,
+) -> Result<(SnapshotMetadata, Vec<(OutPoint, Coin)>), Errors> {
+    let bytes = fs::read(path).map_err(|e| Errors::Io(e.to_string()))?;
+    let mut reader = bytes.as_slice();
+    if read_array::<5>(&mut reader)? != SNAPSHOT_MAGIC {
+        return Err(Errors::InvalidSnapshot("bad magic"));
+    }
+    if u16::from_le_bytes(read_array(&mut reader)?) != SNAPSHOT_VERSION {
+        return Err(Errors::InvalidSnapshot("unknown version"));
+    }
+    let metadata = SnapshotMetadata {
+        base_block: read_array(&mut reader)?,
+        coins_count: u64::from_le_bytes(read_array(&mut reader)?),
+    };
+    let mut coins = Vec::new();
+    for _ in 0..metadata.coins_count {
+        let outpoint = OutPoint::parse(&mut reader)?;
+        coins.push((outpoint, Coin::parse(&mut reader)?));
+    }
+    if !reader.is_empty() {
+        return Err(Errors::TrailingData);
+    }
+    Ok((metadata, coins))
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use crate::test_util::temp_path;
+    use crate::transaction::TxOut;
+
+    fn coins() -> Vec<(OutPoint, Coin)> {
+        (0..3u8)
+            .map(|i| {
+                let coin = Coin {
+                    output: TxOut::new(i as u64 * 1000, vec![0x51, i].into()),
+                    height: i as u32,
+                    is_coinbase: i == 0,
+                };
+                (OutPoint::new([i; 32], i as u32), coin)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_commitment_ignores_order() {
+        let coins = coins();
+        let mut reversed = coins.clone();
+        reversed.reverse();
+        assert_eq!(utxo_commitment(&coins), utxo_commitment(&reversed));
+        let mut changed = coins.clone();
+        changed[1].1.height += 1;
+        assert_ne!(utxo_commitment(&coins), utxo_commitment(&changed));
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let path = temp_path("snapshot_tests").with_extension("dat");
+        let coins = coins();
+        let commitment = write_snapshot(&path, [9; 32], &coins).unwrap();
+        assert_eq!(commitment, utxo_commitment(&coins));
+
+        let (metadata, read) = read_snapshot(&path).unwrap();
+        assert_eq!(
+            metadata,
+            SnapshotMetadata {
+                base_block: [9; 32],
+                coins_count: 3
+            }
+        );
+        assert_eq!(read, coins);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[0] = b'x';
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            read_snapshot(&path),
+            Err(Errors::InvalidSnapshot("bad magic"))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,994 @@
+// Blocks connected to the UTXO set along the chain of most work, switching branches
+// when another one overtakes it (ActivateBestChain in Bitcoin Core). The connected
+// chain trails the header chain while blocks are still missing.
+use super::filters::BlockFilterIndex;
+use super::headers::{HeaderChain, HeaderEntry};
+use super::index::{AddressIndex, TxIndex};
+use super::signet::check_signet_block_solution;
+use super::snapshot::{read_snapshot, utxo_commitment, write_snapshot, AssumeUtxoData};
+use super::store::{BlockStorage, MemoryBlockStore};
+use super::utxo::{BlockUndo, MemoryBackend, UtxoBackend, UtxoSet};
+use super::validation::{check_block, check_block_inputs, contextual_check_block};
+use crate::block::{Block, BlockHeader};
+use crate::metrics::{Histogram, CONNECT_BLOCK_BUCKETS};
+use crate::network::Network;
+use crate::script::{Script, SignatureCache};
+use crate::transaction::{txid_to_hex, Transaction};
+use crate::types::errors::{Errors, ValidationError};
+use crate::validation::{NoScriptVerification, ScriptInterpreter, UtxoView};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Instant;
+use tracing::{info, info_span, warn};
+
+// Blocks kept by a pruned node, so reorganizations of up to two days can still be
+// followed. A pruned node can't go back further.
+pub const MIN_BLOCKS_TO_KEEP: u32 = 288;
+// Smallest prune target Bitcoin Core accepts, in bytes.
+pub const MIN_PRUNE_TARGET: u64 = 550 * 1024 * 1024;
+
+// What a call to activate_best_chain changed, in the order it happened.
+#[derive(Debug, Default, PartialEq)]
+pub struct ChainUpdate {
+    pub disconnected: Vec<[u8; 32]>,
+    pub connected: Vec<[u8; 32]>,
+    // Transactions of disconnected blocks that aren't in the new chain, oldest block
+    // first so parents come before children. They go back to the mempool.
+    pub resurrected: Vec<Transaction>,
+    // Blocks that failed to connect, together with the reason.
+    pub invalid: Vec<([u8; 32], ValidationError)>,
+}
+
+// Validation of the blocks below a loaded snapshot, in a UTXO set of its own.
+#[derive(Debug)]
+struct BackgroundValidation {
+    utxos: UtxoSet,
+    // Last block connected to utxos.
+    height: u32,
+    snapshot: AssumeUtxoData,
+}
+
+#[derive(Debug)]
+pub struct ChainState<B: UtxoBackend = MemoryBackend, S: BlockStorage = MemoryBlockStore> {
+    headers: HeaderChain,
+    utxos: UtxoSet<B>,
+    blocks: S,
+    // Block hashes of the connected chain, indexed by height.
+    active: Vec<[u8; 32]>,
+    invalid: HashSet<[u8; 32]>,
+    signature_cache: SignatureCache,
+    // Scripts of this block and its ancestors aren't verified during sync.
+    assume_valid: Option<[u8; 32]>,
+    background: Option<BackgroundValidation>,
+    filters: Option<BlockFilterIndex>,
+    tx_index: Option<TxIndex>,
+    address_index: Option<AddressIndex>,
+    // Stored blocks are pruned down to this many bytes after connecting blocks.
+    prune_target: Option<u64>,
+    // Blocks must carry a solution to this script, on signets.
+    signet_challenge: Option<Script>,
+    // Seconds each block connected took, since startup.
+    connect_times: Histogram,
+}
+
+impl<B: UtxoBackend> ChainState<B> {
+    // Starts at the genesis block, whose outputs are never spendable.
+    pub fn new(network: Network, backend: B) -> Self {
+        Self::with_storage(network, backend, MemoryBlockStore::default())
+            .expect("blocks are always stored in memory")
+    }
+}
+
+impl<B: UtxoBackend, S: BlockStorage> ChainState<B, S> {
+    // Like new, but keeping blocks in storage, e.g. a BlockStore on disk. Blocks
+    // already there become candidates for the best chain once their headers are
+    // accepted, which is how a node picks up after a restart.
+    pub fn with_storage(network: Network, backend: B, mut blocks: S) -> Result<Self, Errors> {
+        blocks.put(&network.genesis_block())?;
+        let headers = HeaderChain::new(network);
+        Ok(ChainState {
+            active: vec![headers.tip().hash],
+            blocks,
+            headers,
+            utxos: UtxoSet::new(backend),
+            invalid: HashSet::new(),
+            signature_cache: SignatureCache::default(),
+            assume_valid: network.default_assume_valid(),
+            background: None,
+            filters: None,
+            tx_index: None,
+            address_index: None,
+            prune_target: None,
+            signet_challenge: network.signet_challenge(),
+            connect_times: Histogram::new(&CONNECT_BLOCK_BUCKETS),
+        })
+    }
+
+    // Replaces the network's checkpoints. Only meant to be used before any header
+    // is accepted.
+    pub fn with_checkpoints(mut self, checkpoints: Vec<(u32, [u8; 32])>) -> Self {
+        self.headers = self.headers.with_checkpoints(checkpoints);
+        self
+    }
+
+    // Builds the BIP158 filter of every block connected from now on, starting with
+    // genesis. Only meant to be used before any block is connected.
+    pub fn with_block_filters(mut self) -> Self {
+        let mut filters = BlockFilterIndex::default();
+        let genesis = self.headers.network().genesis_block();
+        filters.add_block(&genesis, &BlockUndo::default());
+        self.filters = Some(filters);
+        self
+    }
+
+    // Indexes the transactions of every block connected, those already connected
+    // included. Blocks pruned before can't be indexed.
+    pub fn with_tx_index(mut self) -> Self {
+        let mut index = TxIndex::default();
+        for hash in &self.active[1..] {
+            if let Ok(Some(block)) = self.blocks.get(hash) {
+                index.add_block(&block);
+            }
+        }
+        self.tx_index = Some(index);
+        self
+    }
+
+    // Like with_tx_index, for the scripts paid and spent by every block connected.
+    pub fn with_address_index(mut self) -> Self {
+        let mut index = AddressIndex::default();
+        for (height, hash) in self.active.iter().enumerate().skip(1) {
+            if let (Ok(Some(block)), Ok(Some(undo))) =
+                (self.blocks.get(hash), self.blocks.get_undo(hash))
+            {
+                index.add_block(&block, height as u32, &undo);
+            }
+        }
+        self.address_index = Some(index);
+        self
+    }
+
+    // Deletes old blocks and their undo data once the storage grows past target
+    // bytes, keeping the last MIN_BLOCKS_TO_KEEP so recent blocks can still be
+    // served and reorganized. Bitcoin Core won't go below MIN_PRUNE_TARGET.
+    pub fn with_prune_target(mut self, target: u64) -> Self {
+        self.prune_target = Some(target);
+        self
+    }
+
+    // Follows a custom signet, whose blocks are signed for another challenge.
+    pub fn with_signet_challenge(mut self, challenge: Script) -> Self {
+        self.signet_challenge = Some(challenge);
+        self
+    }
+
+    // None verifies every script.
+    pub fn with_assume_valid(mut self, assume_valid: Option<[u8; 32]>) -> Self {
+        self.assume_valid = assume_valid;
+        self
+    }
+
+    // Headers can be accepted ahead of their blocks, as during headers first sync.
+    pub fn accept_header(&mut self, header: BlockHeader) -> Result<[u8; 32], ValidationError> {
+        self.headers.accept_header(header)
+    }
+
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    pub fn utxos(&self) -> &UtxoSet<B> {
+        &self.utxos
+    }
+
+    // Read from storage, for reorgs, serving peers or rescanning a wallet.
+    pub fn get_block(&self, hash: &[u8; 32]) -> Result<Option<Block>, Errors> {
+        self.blocks.get(hash)
+    }
+
+    // A stored block the chain depends on. Failing to read it back leaves the
+    // chain state unusable, so like Bitcoin Core this stops the node.
+    fn stored_block(&self, hash: &[u8; 32]) -> Block {
+        self.blocks
+            .get(hash)
+            .ok()
+            .flatten()
+            .expect("stored blocks can be read back")
+    }
+
+    // Undo data is kept with the blocks, so it goes when they are pruned.
+    pub fn get_undo(&self, hash: &[u8; 32]) -> Result<Option<BlockUndo>, Errors> {
+        self.blocks.get_undo(hash)
+    }
+
+    // Like stored_block, for the undo data of a connected block.
+    fn stored_undo(&self, hash: &[u8; 32]) -> BlockUndo {
+        self.blocks
+            .get_undo(hash)
+            .ok()
+            .flatten()
+            .expect("undo data is stored when a block is connected")
+    }
+
+    // Failing to write undo data leaves a connected block that can't be
+    // disconnected, which also stops the node.
+    fn store_undo(&mut self, hash: &[u8; 32], undo: &BlockUndo) {
+        self.blocks
+            .put_undo(hash, undo)
+            .expect("undo data can be stored");
+    }
+
+    pub fn block_filters(&self) -> Option<&BlockFilterIndex> {
+        self.filters.as_ref()
+    }
+
+    // Script blocks must carry a solution to, on signets.
+    pub fn signet_challenge(&self) -> Option<&Script> {
+        self.signet_challenge.as_ref()
+    }
+
+    // How long the blocks connected since startup took.
+    pub fn connect_times(&self) -> &Histogram {
+        &self.connect_times
+    }
+
+    pub fn tx_index(&self) -> Option<&TxIndex> {
+        self.tx_index.as_ref()
+    }
+
+    pub fn address_index(&self) -> Option<&AddressIndex> {
+        self.address_index.as_ref()
+    }
+
+    // Shared with the mempool, which fills it as transactions are accepted.
+    pub fn signature_cache(&self) -> &SignatureCache {
+        &self.signature_cache
+    }
+
+    pub fn is_invalid(&self, hash: &[u8; 32]) -> bool {
+        self.invalid.contains(hash)
+    }
+
+    // Last connected block.
+    pub fn tip(&self) -> &HeaderEntry {
+        self.headers.get(self.active.last().unwrap()).unwrap()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tip().height
+    }
+
+    // Median time past of the tip.
+    pub fn median_time_past(&self) -> u32 {
+        self.headers.median_time_past(self.tip())
+    }
+
+    // Whether tx could go in the next block, as the mempool requires
+    // (CheckFinalTxAtTip in Bitcoin Core). Time locks are compared to the tip's
+    // median time past, as BIP113 does within blocks.
+    pub fn check_final(&self, tx: &Transaction) -> Result<(), ValidationError> {
+        if tx.is_final(self.height() + 1, self.median_time_past()) {
+            Ok(())
+        } else {
+            Err(ValidationError::NonFinalTransaction(txid_to_hex(
+                &tx.txid(),
+            )))
+        }
+    }
+
+    // Checks tx's BIP68 locks let it into the next block, outputs not in the UTXO set,
+    // those of the mempool, counting as created in it (CheckSequenceLocksAtTip).
+    pub fn check_sequence_locks(&self, tx: &Transaction) -> Result<(), ValidationError> {
+        let next_height = self.height() + 1;
+        let coin_heights: Vec<u32> = tx
+            .inputs
+            .iter()
+            .map(|input| {
+                self.utxos
+                    .get_coin(&input.previous_output)
+                    .map_or(next_height, |coin| coin.height)
+            })
+            .collect();
+        let tip = self.tip();
+        let locks = tx.sequence_locks(&coin_heights, |height| {
+            let ancestor = self.headers.ancestor(tip, height).unwrap();
+            self.headers.median_time_past(ancestor)
+        });
+        if locks.is_satisfied_by(next_height, self.median_time_past()) {
+            Ok(())
+        } else {
+            Err(ValidationError::NonFinalTransaction(txid_to_hex(
+                &tx.txid(),
+            )))
+        }
+    }
+
+    pub fn is_active(&self, hash: &[u8; 32]) -> bool {
+        self.headers
+            .get(hash)
+            .is_some_and(|entry| self.active.get(entry.height as usize) == Some(hash))
+    }
+
+    // Takes the connected chain up to the block the UTXO set was last flushed at,
+    // whose header must have been accepted, so only the blocks past it are
+    // connected again. A set never flushed leaves the chain at genesis.
+    pub fn resume(&mut self) -> Result<(), Errors> {
+        let best_block = self.utxos.best_block();
+        if best_block == [0; 32] {
+            return Ok(());
+        }
+        let entry = self.headers.get(&best_block).ok_or_else(|| {
+            Errors::Io(format!(
+                "UTXO set is at unknown block {}",
+                txid_to_hex(&best_block)
+            ))
+        })?;
+        self.active = (0..=entry.height)
+            .map(|height| self.headers.ancestor(entry, height).unwrap().hash)
+            .collect();
+        Ok(())
+    }
+
+    // Writes the UTXO set's cached changes to its backend.
+    pub fn flush(&mut self) -> Result<(), Errors> {
+        self.utxos.flush()
+    }
+
+    // Accepts the block's header, stores the block and moves to the best chain. A
+    // block failing check_block is dropped without marking its hash invalid.
+    pub fn accept_block(&mut self, block: Block) -> Result<ChainUpdate, ValidationError> {
+        self.headers.accept_header(block.header)?;
+        check_block(&block)?;
+        if let Some(challenge) = &self.signet_challenge {
+            check_signet_block_solution(&block, challenge)?;
+        }
+        self.blocks
+            .put(&block)
+            .map_err(|e| ValidationError::Storage(e.to_string()))?;
+        let update = self.activate_best_chain();
+        if let Some(target) = self.prune_target {
+            // A file that can't be deleted now is tried again after the next block.
+            let _ = self.prune_blocks(target);
+        }
+        Ok(update)
+    }
+
+    // Deletes stored blocks down to target bytes, returning their hashes. Blocks
+    // within MIN_BLOCKS_TO_KEEP of the tip, above it or still needed by background
+    // validation are kept, as are those whose header isn't known.
+    pub fn prune_blocks(&mut self, target: u64) -> Result<Vec<[u8; 32]>, Errors> {
+        let keep_from = self.height().saturating_sub(MIN_BLOCKS_TO_KEEP - 1);
+        let keep_from = match &self.background {
+            Some(background) => keep_from.min(background.height + 1),
+            None => keep_from,
+        };
+        let headers = &self.headers;
+        self.blocks.prune(target, &|hash| {
+            headers
+                .get(hash)
+                .is_none_or(|entry| entry.height >= keep_from)
+        })
+    }
+
+    // Reorganizes onto the stored branch with the most work, until no branch has
+    // more work than the connected chain. A block failing to connect is marked
+    // invalid along with everything built on it, and the search starts over.
+    pub fn activate_best_chain(&mut self) -> ChainUpdate {
+        let mut update = ChainUpdate::default();
+        let mut disconnected_blocks = Vec::new();
+        while let Some(branch) = self.best_branch() {
+            let fork_height = self.headers.get(&branch[0]).unwrap().height - 1;
+            if self.height() > fork_height {
+                info!(
+                    target: "validation",
+                    fork_height, disconnected = self.height() - fork_height,
+                    connected = branch.len(),
+                    "Reorganizing"
+                );
+            }
+            while self.height() > fork_height {
+                let hash = self.disconnect_tip();
+                update.disconnected.push(hash);
+                disconnected_blocks.push(hash);
+            }
+            for hash in branch {
+                match self.connect_block(hash) {
+                    Ok(()) => update.connected.push(hash),
+                    Err(error) => {
+                        warn!(
+                            target: "validation",
+                            hash = txid_to_hex(&hash), %error,
+                            "Block failed to connect, marking it invalid"
+                        );
+                        self.invalid.insert(hash);
+                        update.invalid.push((hash, error));
+                        break;
+                    }
+                }
+            }
+        }
+
+        if disconnected_blocks.is_empty() {
+            return update;
+        }
+        // Below the fork the chain didn't change, and its transactions can't be in
+        // the blocks disconnected.
+        let confirmed: HashSet<[u8; 32]> = update
+            .connected
+            .iter()
+            .filter(|hash| self.is_active(hash))
+            .flat_map(|hash| self.stored_block(hash).txids())
+            .collect();
+        let mut seen = HashSet::new();
+        for hash in disconnected_blocks.iter().rev() {
+            if self.is_active(hash) {
+                continue;
+            }
+            for tx in self.stored_block(hash).iter().skip(1) {
+                let txid = tx.txid();
+                if !confirmed.contains(&txid) && seen.insert(txid) {
+                    update.resurrected.push(tx.clone());
+                }
+            }
+        }
+        update
+    }
+
+    // Blocks from the fork point with the connected chain (excluded) up to the stored
+    // block of most work, if it has more work than the tip. Branches with a missing
+    // or invalid block are skipped, and so are those forking below a snapshot still
+    // being validated, as there is no undo data to go back that far.
+    fn best_branch(&self) -> Option<Vec<[u8; 32]>> {
+        let tip_work = &self.tip().chain_work;
+        let mut candidates: Vec<&HeaderEntry> = self
+            .blocks
+            .hashes()
+            .iter()
+            .filter_map(|hash| self.headers.get(hash))
+            .filter(|entry| entry.chain_work > *tip_work)
+            .collect();
+        candidates.sort_by(|a, b| b.chain_work.cmp(&a.chain_work).then(a.hash.cmp(&b.hash)));
+        candidates.into_iter().find_map(|candidate| {
+            let mut branch = Vec::new();
+            let mut current = candidate;
+            while !self.is_active(&current.hash) {
+                if self.invalid.contains(&current.hash) || !self.blocks.contains(&current.hash) {
+                    return None;
+                }
+                branch.push(current.hash);
+                current = self.headers.prev(current)?;
+            }
+            if let Some(background) = &self.background {
+                if current.height < background.snapshot.height {
+                    return None;
+                }
+            }
+            branch.reverse();
+            Some(branch)
+        })
+    }
+
+    // Fully validates the block, whose parent must be the tip, and spends its inputs.
+    fn connect_block(&mut self, hash: [u8; 32]) -> Result<(), ValidationError> {
+        let entry = self.headers.get(&hash).unwrap();
+        let _span = info_span!(
+            target: "validation",
+            "connect_block",
+            height = entry.height,
+            hash = txid_to_hex(&hash)
+        )
+        .entered();
+        let start = Instant::now();
+        let block = self.stored_block(&hash);
+        self.validate_block(entry, &block, &self.utxos)?;
+        let undo = self.utxos.apply_block(&block, entry.height)?;
+        self.index_block(&block, entry.height, &undo);
+        self.store_undo(&hash, &undo);
+        self.active.push(hash);
+        self.connect_times.observe(start.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    // Adds a block connected to the indexes kept.
+    fn index_block(&mut self, block: &Block, height: u32, undo: &BlockUndo) {
+        if let Some(filters) = &mut self.filters {
+            filters.add_block(block, undo);
+        }
+        if let Some(index) = &mut self.tx_index {
+            index.add_block(block);
+        }
+        if let Some(index) = &mut self.address_index {
+            index.add_block(block, height, undo);
+        }
+    }
+
+    // Checks the block at entry against utxos, the coins left by its parent.
+    fn validate_block(
+        &self,
+        entry: &HeaderEntry,
+        block: &Block,
+        utxos: &impl UtxoView,
+    ) -> Result<(), ValidationError> {
+        let prev = self.headers.prev(entry).unwrap();
+        contextual_check_block(block, &self.headers, prev)?;
+        if self.skips_scripts(entry) {
+            check_block_inputs(block, entry, &self.headers, utxos, &NoScriptVerification)?;
+        } else {
+            // Signatures are checked once, so cache hits are erased.
+            let verifier =
+                ScriptInterpreter::new().with_signature_cache(&self.signature_cache, false);
+            check_block_inputs(block, entry, &self.headers, utxos, &verifier)?;
+        }
+        Ok(())
+    }
+
+    // Whether entry is an ancestor of the assumed valid block, which must be on the
+    // best header chain. Everything else is still checked, only scripts are skipped.
+    fn skips_scripts(&self, entry: &HeaderEntry) -> bool {
+        let Some(assumed) = self.assume_valid.and_then(|hash| self.headers.get(&hash)) else {
+            return false;
+        };
+        self.headers.is_in_best_chain(&assumed.hash)
+            && self
+                .headers
+                .ancestor(assumed, entry.height)
+                .is_some_and(|ancestor| ancestor.hash == entry.hash)
+    }
+
+    // Replaces the UTXO set with a snapshot of the coins left by a block the caller
+    // trusts, whose header must be known. Blocks above it can be connected right
+    // away, while those below are checked by validate_background.
+    pub fn load_snapshot(
+        &mut self,
+        path: impl AsRef<Path>,
+        trusted: &AssumeUtxoData,
+    ) -> Result<(), Errors> {
+        if self.height() != 0 {
+            return Err(Errors::InvalidSnapshot("blocks are already connected"));
+        }
+        let (metadata, coins) = read_snapshot(path)?;
+        if metadata.base_block != trusted.block_hash {
+            return Err(Errors::InvalidSnapshot("unexpected base block"));
+        }
+        let base = self
+            .headers
+            .get(&trusted.block_hash)
+            .filter(|entry| entry.height == trusted.height)
+            .ok_or(Errors::InvalidSnapshot("unknown base block"))?;
+        if utxo_commitment(&coins) != trusted.commitment {
+            return Err(Errors::InvalidSnapshot("coins do not match the commitment"));
+        }
+        self.active = (0..=base.height)
+            .map(|height| self.headers.ancestor(base, height).unwrap().hash)
+            .collect();
+        self.utxos.load(coins, trusted.block_hash);
+        self.background = Some(BackgroundValidation {
+            utxos: UtxoSet::default(),
+            height: 0,
+            snapshot: *trusted,
+        });
+        Ok(())
+    }
+
+    // Writes the coins left by the tip to path, returning what a node loading it
+    // has to trust.
+    pub fn dump_snapshot(&self, path: impl AsRef<Path>) -> Result<AssumeUtxoData, Errors> {
+        let tip = self.tip();
+        let commitment = write_snapshot(path, tip.hash, &self.utxos.coins())?;
+        Ok(AssumeUtxoData {
+            height: tip.height,
+            block_hash: tip.hash,
+            commitment,
+        })
+    }
+
+    // Last block below the loaded snapshot validated so far, None once finished or
+    // if no snapshot was loaded.
+    pub fn background_height(&self) -> Option<u32> {
+        self.background.as_ref().map(|background| background.height)
+    }
+
+    // Connects the stored blocks below the snapshot to a UTXO set started from
+    // genesis, as far as blocks are available. Once it reaches the snapshot block its
+    // coins must match the snapshot's. Returns whether the snapshot is validated,
+    // which is also the case when none was loaded. Undo data of the blocks validated
+    // is kept, so the chain can be reorganized below the snapshot afterwards.
+    pub fn validate_background(&mut self) -> Result<bool, ValidationError> {
+        let Some(mut background) = self.background.take() else {
+            return Ok(true);
+        };
+        let result = self.advance_background(&mut background);
+        if !matches!(result, Ok(true)) {
+            self.background = Some(background);
+        }
+        result
+    }
+
+    fn advance_background(
+        &mut self,
+        background: &mut BackgroundValidation,
+    ) -> Result<bool, ValidationError> {
+        while background.height < background.snapshot.height {
+            let hash = self.active[background.height as usize + 1];
+            if !self.blocks.contains(&hash) {
+                return Ok(false);
+            }
+            let block = self.stored_block(&hash);
+            let entry = self.headers.get(&hash).unwrap();
+            self.validate_block(entry, &block, &background.utxos)?;
+            let height = entry.height;
+            let undo = background.utxos.apply_block(&block, height)?;
+            self.index_block(&block, height, &undo);
+            self.store_undo(&hash, &undo);
+            background.height += 1;
+        }
+        if utxo_commitment(&background.utxos.coins()) != background.snapshot.commitment {
+            return Err(ValidationError::SnapshotMismatch);
+        }
+        Ok(true)
+    }
+
+    fn disconnect_tip(&mut self) -> [u8; 32] {
+        let hash = self.active.pop().unwrap();
+        let undo = self.stored_undo(&hash);
+        let block = self.stored_block(&hash);
+        self.utxos
+            .undo_block(&block, &undo)
+            .expect("undo data is recorded when a block is connected");
+        if let Some(index) = &mut self.tx_index {
+            index.remove_block(&block);
+        }
+        if let Some(index) = &mut self.address_index {
+            index.remove_block(&block, &undo);
+        }
+        hash
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+    use crate::chain::index::AddressEvent;
+    use crate::chain::store::BlockStore;
+    use crate::test_util::{funding, mature_chain, mine_on, spend, temp_path};
+    use crate::transaction::OutPoint;
+    use crate::validation::{UtxoView, COINBASE_MATURITY};
+    use std::fs;
+
+    #[test]
+    fn test_reorg_to_branch_with_more_work() {
+        let (mut chain, coin) = mature_chain();
+        let a1 = chain.tip().hash;
+        let payment = spend(&[coin], 49_0000_0000, 0xffffffff);
+        let a2 = mine_on(&chain, a1, 1, vec![payment.clone()]);
+        let update = chain.accept_block(a2.clone()).unwrap();
+        assert_eq!(update.connected, vec![a2.hash()]);
+        assert_eq!(chain.utxos().get_coin(&coin), None);
+
+        // A branch of the same length is stored but not connected.
+        let b2 = mine_on(&chain, a1, 2, vec![]);
+        assert_eq!(chain.accept_block(b2.clone()), Ok(ChainUpdate::default()));
+        assert_eq!(chain.tip().hash, a2.hash());
+
+        let b3 = mine_on(&chain, b2.hash(), 2, vec![]);
+        let update = chain.accept_block(b3.clone()).unwrap();
+        assert_eq!(update.disconnected, vec![a2.hash()]);
+        assert_eq!(update.connected, vec![b2.hash(), b3.hash()]);
+        assert_eq!(update.resurrected, vec![payment.clone()]);
+        assert!(update.invalid.is_empty());
+        assert_eq!(chain.tip().hash, b3.hash());
+        assert_eq!(chain.height(), COINBASE_MATURITY + 3);
+        // Undo data stays with the block, in case it is connected again.
+        assert!(chain.get_undo(&a2.hash()).unwrap().is_some());
+
+        // The coin a2 spent is back and a2's own outputs are gone.
+        assert!(chain.utxos().get_coin(&coin).is_some());
+        assert_eq!(chain.utxos().get_coin(&funding(&a2)), None);
+        assert_eq!(
+            chain.utxos().get_coin(&OutPoint::new(payment.txid(), 0)),
+            None
+        );
+        assert_eq!(chain.utxos().best_block(), b3.hash());
+    }
+
+    #[test]
+    fn test_transactions_confirmed_again_are_not_resurrected() {
+        let (mut chain, coin) = mature_chain();
+        let a1 = chain.tip().hash;
+        let payment = spend(&[coin], 49_0000_0000, 0xffffffff);
+        let child = spend(
+            &[OutPoint::new(payment.txid(), 0)],
+            48_0000_0000,
+            0xffffffff,
+        );
+        let a2 = mine_on(&chain, a1, 1, vec![payment.clone(), child.clone()]);
+        chain.accept_block(a2.clone()).unwrap();
+
+        let b2 = mine_on(&chain, a1, 2, vec![payment.clone()]);
+        chain.accept_block(b2.clone()).unwrap();
+        let b3 = mine_on(&chain, b2.hash(), 2, vec![]);
+        let update = chain.accept_block(b3).unwrap();
+        assert_eq!(update.resurrected, vec![child]);
+    }
+
+    #[test]
+    fn test_indexes_follow_reorgs() {
+        let (chain, coin) = mature_chain();
+        let mut chain = chain.with_tx_index().with_address_index();
+        let first = chain.get_block(&chain.active[1]).unwrap().unwrap();
+        let position = chain.tx_index().unwrap().get(&coin.txid).unwrap();
+        assert_eq!((position.block, position.index), (first.hash(), 0));
+
+        let a1 = chain.tip().hash;
+        let payment = spend(&[coin], 49_0000_0000, 0xffffffff);
+        let a2 = mine_on(&chain, a1, 1, vec![payment.clone()]);
+        chain.accept_block(a2.clone()).unwrap();
+        let position = chain.tx_index().unwrap().get(&payment.txid()).unwrap();
+        assert_eq!((position.block, position.index), (a2.hash(), 1));
+        let addresses = chain.address_index().unwrap();
+        assert_eq!(addresses.txids(&[0x52]), vec![payment.txid()]);
+        assert_eq!(addresses.balance(&[0x52]), (49_0000_0000, 49_0000_0000));
+        let history = addresses.history(&[0x51]);
+        assert_eq!(history[0].txid, coin.txid);
+        let spent = AddressEvent::Spent {
+            outpoint: coin,
+            value: 50_0000_0000,
+        };
+        assert!(history.iter().any(|entry| entry.event == spent));
+
+        // Disconnecting a2 takes its entries out of both indexes.
+        let b2 = mine_on(&chain, a1, 2, vec![]);
+        chain.accept_block(b2.clone()).unwrap();
+        let b3 = mine_on(&chain, b2.hash(), 2, vec![]);
+        chain.accept_block(b3).unwrap();
+        assert_eq!(chain.tx_index().unwrap().get(&payment.txid()), None);
+        let addresses = chain.address_index().unwrap();
+        assert!(addresses.history(&[0x52]).is_empty());
+        assert!(!addresses.history(&[0x51]).iter().any(|e| e.event == spent));
+    }
+
+    #[test]
+    fn test_invalid_block_ends_the_branch() {
+        let (mut chain, coin) = mature_chain();
+        let a1 = chain.tip().hash;
+        let payment = spend(&[coin], 49_0000_0000, 0xffffffff);
+        let a2 = mine_on(&chain, a1, 1, vec![payment.clone()]);
+        chain.accept_block(a2.clone()).unwrap();
+
+        // b3 spends an output that doesn't exist.
+        let b2 = mine_on(&chain, a1, 2, vec![]);
+        chain.accept_block(b2.clone()).unwrap();
+        let bad = spend(&[OutPoint::new([7; 32], 0)], 1, 0xffffffff);
+        let b3 = mine_on(&chain, b2.hash(), 2, vec![bad]);
+        let update = chain.accept_block(b3.clone()).unwrap();
+
+        // The valid part of the branch has as much work as a2, so it stays connected.
+        assert_eq!(update.disconnected, vec![a2.hash()]);
+        assert_eq!(update.connected, vec![b2.hash()]);
+        assert_eq!(update.invalid.len(), 1);
+        assert_eq!(update.invalid[0].0, b3.hash());
+        assert!(matches!(
+            update.invalid[0].1,
+            ValidationError::MissingInput(_)
+        ));
+        assert_eq!(update.resurrected, vec![payment]);
+        assert_eq!(chain.tip().hash, b2.hash());
+        assert!(chain.is_invalid(&b3.hash()));
+        assert!(chain.utxos().get_coin(&coin).is_some());
+        assert_eq!(chain.utxos().best_block(), b2.hash());
+
+        // Blocks built on the invalid one are never connected.
+        let b4 = mine_on(&chain, b3.hash(), 2, vec![]);
+        assert_eq!(chain.accept_block(b4), Ok(ChainUpdate::default()));
+        assert_eq!(chain.tip().hash, b2.hash());
+    }
+
+    #[test]
+    fn test_prune_old_blocks() {
+        let dir = temp_path("state_tests_prune");
+        let _ = fs::remove_dir_all(&dir);
+        // Each file holds a few blocks.
+        let store = BlockStore::open(&dir).unwrap().with_max_file_size(1000);
+        let mut chain = ChainState::with_storage(Network::Regtest, MemoryBackend::default(), store)
+            .unwrap()
+            .with_prune_target(0);
+        let mut hashes = vec![chain.tip().hash];
+        for _ in 0..MIN_BLOCKS_TO_KEEP + 20 {
+            let block = mine_on(&chain, chain.tip().hash, 0, vec![]);
+            hashes.push(block.hash());
+            chain.accept_block(block).unwrap();
+        }
+        let keep_from = (chain.height() - MIN_BLOCKS_TO_KEEP + 1) as usize;
+        assert_eq!(chain.get_block(&hashes[1]), Ok(None));
+        assert_eq!(chain.get_undo(&hashes[1]), Ok(None));
+        for hash in &hashes[keep_from..] {
+            assert!(chain.get_block(hash).unwrap().is_some());
+        }
+        assert!(chain.get_undo(&hashes[keep_from]).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_block_filters_of_connected_blocks() {
+        let mut chain =
+            ChainState::new(Network::Regtest, MemoryBackend::default()).with_block_filters();
+        let b1 = mine_on(&chain, chain.tip().hash, 1, vec![]);
+        chain.accept_block(b1.clone()).unwrap();
+        let filters = chain.block_filters().unwrap();
+        let genesis_header = filters
+            .get_header(&Network::Regtest.genesis_hash())
+            .unwrap();
+        let filter = filters.get_filter(&b1.hash()).unwrap();
+        assert_eq!(
+            filters.get_header(&b1.hash()),
+            Some(filter.header(&genesis_header))
+        );
+        let script = b1.transactions[0].outputs[0].script_pubkey.as_bytes();
+        assert_eq!(filter.match_any(&[script]), Ok(true));
+    }
+
+    #[test]
+    fn test_blocks_kept_on_disk() {
+        let dir = temp_path("state_tests");
+        let _ = fs::remove_dir_all(&dir);
+        let store = BlockStore::open(&dir).unwrap();
+        let mut chain =
+            ChainState::with_storage(Network::Regtest, MemoryBackend::default(), store).unwrap();
+        let a1 = mine_on(&chain, chain.tip().hash, 1, vec![]);
+        chain.accept_block(a1.clone()).unwrap();
+        let b1 = mine_on(&chain, Network::Regtest.genesis_hash(), 2, vec![]);
+        chain.accept_block(b1.clone()).unwrap();
+        let b2 = mine_on(&chain, b1.hash(), 2, vec![]);
+        let update = chain.accept_block(b2.clone()).unwrap();
+        assert_eq!(update.disconnected, vec![a1.hash()]);
+        assert_eq!(chain.get_block(&a1.hash()), Ok(Some(a1.clone())));
+        assert_eq!(chain.get_undo(&a1.hash()), Ok(Some(BlockUndo::default())));
+
+        // After a restart the stored blocks connect once their headers are known.
+        drop(chain);
+        assert_eq!(
+            BlockStore::open(&dir).unwrap().read_undo(&b2.hash()),
+            Ok(Some(BlockUndo::default()))
+        );
+        let store = BlockStore::open(&dir).unwrap();
+        let mut chain =
+            ChainState::with_storage(Network::Regtest, MemoryBackend::default(), store).unwrap();
+        for block in [&a1, &b1, &b2] {
+            chain.accept_header(block.header).unwrap();
+        }
+        let update = chain.activate_best_chain();
+        assert_eq!(update.connected, vec![b1.hash(), b2.hash()]);
+        assert_eq!(chain.tip().hash, b2.hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_final_at_tip() {
+        let (chain, coin) = mature_chain();
+        let mut tx = spend(&[coin], 49_0000_0000, 0xffffffff);
+        tx.inputs[0].sequence = 0;
+        tx.locktime = chain.height() + 1;
+        assert!(chain.check_final(&tx).is_err());
+        tx.locktime = chain.height();
+        assert_eq!(chain.check_final(&tx), Ok(()));
+
+        // Tip timestamps are 600 seconds apart, so the median is 5 blocks back.
+        let mtp = chain.median_time_past();
+        assert_eq!(mtp, chain.tip().header.timestamp - 5 * 600);
+        tx.locktime = mtp;
+        assert!(chain.check_final(&tx).is_err());
+        tx.locktime = mtp - 1;
+        assert_eq!(chain.check_final(&tx), Ok(()));
+    }
+
+    #[test]
+    fn test_assume_valid_skips_scripts() {
+        let (chain, coin) = mature_chain();
+        let base = chain.tip().hash;
+        // An OP_RETURN scriptSig fails whatever it spends.
+        let mut bad = spend(&[coin], 49_0000_0000, 0xffffffff);
+        bad.inputs[0].script_sig = vec![0x6a].into();
+        let bad_block = mine_on(&chain, base, 1, vec![bad]);
+
+        let mut checked = chain;
+        let update = checked.accept_block(bad_block.clone()).unwrap();
+        assert!(matches!(
+            update.invalid[0].1,
+            ValidationError::ScriptFailed { .. }
+        ));
+
+        let (chain, _) = mature_chain();
+        let mut assumed = chain;
+        assumed.accept_header(bad_block.header).unwrap();
+        let child = mine_on(&assumed, bad_block.hash(), 1, vec![]);
+        assumed.accept_header(child.header).unwrap();
+        let mut assumed = assumed.with_assume_valid(Some(child.hash()));
+        let update = assumed.accept_block(bad_block.clone()).unwrap();
+        assert_eq!(update.connected, vec![bad_block.hash()]);
+        // The UTXO checks still run: the coin is now spent.
+        assert_eq!(assumed.utxos().get_coin(&coin), None);
+        assert_eq!(
+            assumed.accept_block(child.clone()).unwrap().connected,
+            vec![child.hash()]
+        );
+    }
+
+    // A fresh node on chain's network knowing chain's headers but no block.
+    fn headers_only(chain: &ChainState) -> ChainState {
+        let mut node = ChainState::new(Network::Regtest, MemoryBackend::default());
+        for height in 1..=chain.height() {
+            let entry = chain.headers().at_height(height).unwrap();
+            node.accept_header(entry.header).unwrap();
+        }
+        node
+    }
+
+    #[test]
+    fn test_snapshot_bootstrap() {
+        let (mut source, coin) = mature_chain();
+        let payment = spend(&[coin], 49_0000_0000, 0xffffffff);
+        let block = mine_on(&source, source.tip().hash, 1, vec![payment]);
+        source.accept_block(block).unwrap();
+        let path = temp_path("state_tests").with_extension("dat");
+        let trusted = source.dump_snapshot(&path).unwrap();
+        assert_eq!(trusted.block_hash, source.tip().hash);
+
+        let mut node = headers_only(&source);
+        let mut wrong = trusted;
+        wrong.commitment = [0; 32];
+        assert_eq!(
+            node.load_snapshot(&path, &wrong),
+            Err(Errors::InvalidSnapshot("coins do not match the commitment"))
+        );
+        node.load_snapshot(&path, &trusted).unwrap();
+        assert_eq!(node.tip().hash, trusted.block_hash);
+        assert_eq!(node.utxos().coins(), source.utxos().coins());
+        assert_eq!(node.background_height(), Some(0));
+
+        // New blocks connect on top of the snapshot straight away.
+        let next = mine_on(&node, node.tip().hash, 1, vec![]);
+        let update = node.accept_block(next.clone()).unwrap();
+        assert_eq!(update.connected, vec![next.hash()]);
+
+        // Historical blocks are validated as they arrive.
+        assert_eq!(node.validate_background(), Ok(false));
+        for height in 1..=trusted.height {
+            let hash = source.headers().at_height(height).unwrap().hash;
+            let block = source.get_block(&hash).unwrap().unwrap();
+            assert_eq!(node.accept_block(block), Ok(ChainUpdate::default()));
+        }
+        assert_eq!(node.validate_background(), Ok(true));
+        assert_eq!(node.background_height(), None);
+        assert!(node.get_undo(&trusted.block_hash).unwrap().is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_not_matching_the_chain() {
+        let (source, _) = mature_chain();
+        let path = temp_path("state_tests_bad").with_extension("dat");
+        // A snapshot with a coin too many, trusted by mistake.
+        let mut coins = source.utxos().coins();
+        let extra = coins[0].1.clone();
+        coins.push((OutPoint::new([5; 32], 0), extra));
+        let trusted = AssumeUtxoData {
+            height: source.height(),
+            block_hash: source.tip().hash,
+            commitment: write_snapshot(&path, source.tip().hash, &coins).unwrap(),
+        };
+
+        let mut node = headers_only(&source);
+        node.load_snapshot(&path, &trusted).unwrap();
+        for height in 1..=trusted.height {
+            let hash = source.headers().at_height(height).unwrap().hash;
+            node.accept_block(source.get_block(&hash).unwrap().unwrap())
+                .unwrap();
+        }
+        assert_eq!(
+            node.validate_background(),
+            Err(ValidationError::SnapshotMismatch)
+        );
+        assert_eq!(node.background_height(), Some(trusted.height));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,585 @@
+// Where the blocks of the chain state are kept. BlockStore writes them to disk like
+// the blk?????.dat files of Bitcoin Core: blocks are appended to numbered files, a
+// new one being started when the current one would grow past max_file_size, and an
+// index maps each block hash to where it was written. The undo data of a block goes
+// to the rev?????.dat file with the same number, so both are pruned together.
+use super::utxo::BlockUndo;
+use crate::block::Block;
+use crate::helper::{hash256, read_array};
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+// Index records: block hash, file number, offset and length of the block or its
+// undo data.
+const INDEX_RECORD_SIZE: usize = 32 + 4 + 8 + 4;
+const BLOCK_INDEX: &str = "index.dat";
+const UNDO_INDEX: &str = "undo_index.dat";
+
+pub trait BlockStorage {
+    fn contains(&self, hash: &[u8; 32]) -> bool;
+
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Block>, Errors>;
+
+    // Storing a block already there does nothing.
+    fn put(&mut self, block: &Block) -> Result<(), Errors>;
+
+    fn hashes(&self) -> Vec<[u8; 32]>;
+
+    // Coins spent by a stored block, recorded when it was connected.
+    fn get_undo(&self, hash: &[u8; 32]) -> Result<Option<BlockUndo>, Errors>;
+
+    // Storing undo data already there does nothing, as a block always spends the
+    // same coins.
+    fn put_undo(&mut self, hash: &[u8; 32], undo: &BlockUndo) -> Result<(), Errors>;
+
+    // Deletes stored blocks, as long as keep returns false for them, until the
+    // storage takes no more than target bytes. Returns the hashes of the blocks
+    // deleted, whose undo data is gone as well. Storage that isn't on disk keeps
+    // everything.
+    fn prune(
+        &mut self,
+        _target: u64,
+        _keep: &dyn Fn(&[u8; 32]) -> bool,
+    ) -> Result<Vec<[u8; 32]>, Errors> {
+        Ok(Vec::new())
+    }
+}
+
+// Keeps every block in memory.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBlockStore {
+    blocks: HashMap<[u8; 32], Block>,
+    undo: HashMap<[u8; 32], BlockUndo>,
+}
+
+impl BlockStorage for MemoryBlockStore {
+    fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Block>, Errors> {
+        Ok(self.blocks.get(hash).cloned())
+    }
+
+    fn put(&mut self, block: &Block) -> Result<(), Errors> {
+        self.blocks
+            .entry(block.hash())
+            .or_insert_with(|| block.clone());
+        Ok(())
+    }
+
+    fn hashes(&self) -> Vec<[u8; 32]> {
+        self.blocks.keys().copied().collect()
+    }
+
+    fn get_undo(&self, hash: &[u8; 32]) -> Result<Option<BlockUndo>, Errors> {
+        Ok(self.undo.get(hash).cloned())
+    }
+
+    fn put_undo(&mut self, hash: &[u8; 32], undo: &BlockUndo) -> Result<(), Errors> {
+        self.undo.entry(*hash).or_insert_with(|| undo.clone());
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockPosition {
+    pub file: u32,
+    // Of the serialized block or undo data, which follows its 4 byte length.
+    pub offset: u64,
+    pub len: u32,
+}
+
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    max_file_size: u64,
+    index: HashMap<[u8; 32], BlockPosition>,
+    // Positions in the rev files, whose numbers are those of the blocks' files.
+    undo_index: HashMap<[u8; 32], BlockPosition>,
+    // File being appended to and its size.
+    current_file: u32,
+    current_size: u64,
+}
+
+fn io_error(e: std::io::Error) -> Errors {
+    Errors::Io(e.to_string())
+}
+
+fn index_record(hash: &[u8; 32], position: &BlockPosition) -> Vec<u8> {
+    let mut record = hash.to_vec();
+    record.extend(position.file.to_le_bytes());
+    record.extend(position.offset.to_le_bytes());
+    record.extend(position.len.to_le_bytes());
+    record
+}
+
+fn append_index_record(
+    path: &Path,
+    hash: &[u8; 32],
+    position: &BlockPosition,
+) -> Result<(), Errors> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut index| index.write_all(&index_record(hash, position)))
+        .map_err(io_error)
+}
+
+// A record cut short by a crash is ignored, its block or undo data written again.
+fn read_index(path: &Path) -> Result<HashMap<[u8; 32], BlockPosition>, Errors> {
+    let mut index = HashMap::new();
+    if !path.exists() {
+        return Ok(index);
+    }
+    let bytes = fs::read(path).map_err(io_error)?;
+    for mut record in bytes.chunks_exact(INDEX_RECORD_SIZE) {
+        let hash = read_array(&mut record)?;
+        let position = BlockPosition {
+            file: u32::from_le_bytes(read_array(&mut record)?),
+            offset: u64::from_le_bytes(read_array(&mut record)?),
+            len: u32::from_le_bytes(read_array(&mut record)?),
+        };
+        index.insert(hash, position);
+    }
+    Ok(index)
+}
+
+// Appends data to the file with its length in front, returning where data starts.
+fn append_record(path: &Path, data: &[u8]) -> Result<u64, Errors> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(io_error)?;
+    let start = file.metadata().map_err(io_error)?.len();
+    file.write_all(&(data.len() as u32).to_le_bytes())
+        .and_then(|_| file.write_all(data))
+        .and_then(|_| file.sync_data())
+        .map_err(io_error)?;
+    Ok(start + 4)
+}
+
+fn read_record(path: &Path, position: &BlockPosition, len: usize) -> Result<Vec<u8>, Errors> {
+    let mut file = File::open(path).map_err(io_error)?;
+    file.seek(SeekFrom::Start(position.offset))
+        .map_err(io_error)?;
+    let mut data = vec![0; len];
+    file.read_exact(&mut data).map_err(io_error)?;
+    Ok(data)
+}
+
+// Undo data is followed by a checksum of it and the block hash, as in Bitcoin Core.
+fn undo_checksum(hash: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut checked = hash.to_vec();
+    checked.extend_from_slice(data);
+    hash256(&checked)
+}
+
+impl BlockStore {
+    // Opens the store in dir, creating it if needed, and reads its index.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Errors> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(io_error)?;
+        let index = read_index(&dir.join(BLOCK_INDEX))?;
+        let undo_index = read_index(&dir.join(UNDO_INDEX))?;
+        let current_file = index
+            .values()
+            .map(|position| position.file)
+            .max()
+            .unwrap_or(0);
+        let mut store = BlockStore {
+            dir,
+            max_file_size: DEFAULT_MAX_BLOCK_FILE_SIZE,
+            index,
+            undo_index,
+            current_file,
+            current_size: 0,
+        };
+        store.current_size = fs::metadata(store.file_path(current_file)).map_or(0, |m| m.len());
+        Ok(store)
+    }
+
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    pub fn undo_file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("rev{:05}.dat", file))
+    }
+
+    pub fn position(&self, hash: &[u8; 32]) -> Option<BlockPosition> {
+        self.index.get(hash).copied()
+    }
+
+    // Where the undo data of the block is, if written. len leaves out the checksum.
+    pub fn undo_position(&self, hash: &[u8; 32]) -> Option<BlockPosition> {
+        self.undo_index.get(hash).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn write_block(&mut self, block: &Block) -> Result<BlockPosition, Errors> {
+        let hash = block.hash();
+        if let Some(position) = self.position(&hash) {
+            return Ok(position);
+        }
+        let data = block.serialize();
+        let record_size = 4 + data.len() as u64;
+        if self.current_size > 0 && self.current_size + record_size > self.max_file_size {
+            self.current_file += 1;
+            self.current_size = 0;
+        }
+        let position = BlockPosition {
+            file: self.current_file,
+            offset: append_record(&self.file_path(self.current_file), &data)?,
+            len: data.len() as u32,
+        };
+        self.current_size += record_size;
+
+        // The index is only written once the block is safely on disk.
+        append_index_record(&self.dir.join(BLOCK_INDEX), &hash, &position)?;
+        self.index.insert(hash, position);
+        Ok(position)
+    }
+
+    // The block must be stored already.
+    pub fn write_undo(
+        &mut self,
+        hash: &[u8; 32],
+        undo: &BlockUndo,
+    ) -> Result<BlockPosition, Errors> {
+        if let Some(position) = self.undo_position(hash) {
+            return Ok(position);
+        }
+        let file = self
+            .position(hash)
+            .ok_or_else(|| Errors::Io("undo data of a block not stored".to_string()))?
+            .file;
+        let data = undo.serialize();
+        let mut record = data.clone();
+        record.extend(undo_checksum(hash, &data));
+        let position = BlockPosition {
+            file,
+            offset: append_record(&self.undo_file_path(file), &record)?,
+            len: data.len() as u32,
+        };
+        append_index_record(&self.dir.join(UNDO_INDEX), hash, &position)?;
+        self.undo_index.insert(*hash, position);
+        Ok(position)
+    }
+
+    pub fn read_undo(&self, hash: &[u8; 32]) -> Result<Option<BlockUndo>, Errors> {
+        let Some(position) = self.undo_position(hash) else {
+            return Ok(None);
+        };
+        let path = self.undo_file_path(position.file);
+        let mut record = read_record(&path, &position, position.len as usize + 32)?;
+        let checksum = record.split_off(position.len as usize);
+        if checksum != undo_checksum(hash, &record) {
+            return Err(Errors::Io(format!(
+                "undo file {} is corrupted",
+                position.file
+            )));
+        }
+        BlockUndo::parse(&mut record.as_slice()).map(Some)
+    }
+
+    // Bytes taken by the block and undo files.
+    pub fn disk_usage(&self) -> u64 {
+        self.files().iter().map(|file| self.file_size(*file)).sum()
+    }
+
+    fn file_size(&self, file: u32) -> u64 {
+        [self.file_path(file), self.undo_file_path(file)]
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    // Numbers of the files holding blocks, oldest first.
+    fn files(&self) -> Vec<u32> {
+        let mut files: Vec<u32> = self.index.values().map(|position| position.file).collect();
+        files.sort_unstable();
+        files.dedup();
+        files
+    }
+
+    // Rewrites an index without the records of deleted files. The new one replaces
+    // the old in a single rename, so a crash leaves either of them.
+    fn rewrite_index(
+        &self,
+        name: &str,
+        index: &HashMap<[u8; 32], BlockPosition>,
+    ) -> Result<(), Errors> {
+        let records: Vec<u8> = index
+            .iter()
+            .flat_map(|(hash, position)| index_record(hash, position))
+            .collect();
+        let temp_path = self.dir.join(format!("{}.new", name));
+        fs::write(&temp_path, records)
+            .and_then(|_| fs::rename(&temp_path, self.dir.join(name)))
+            .map_err(io_error)
+    }
+
+    pub fn read_block(&self, hash: &[u8; 32]) -> Result<Option<Block>, Errors> {
+        let Some(position) = self.position(hash) else {
+            return Ok(None);
+        };
+        let data = read_record(
+            &self.file_path(position.file),
+            &position,
+            position.len as usize,
+        )?;
+        let block = Block::from_bytes(&data)?;
+        if block.hash() != *hash {
+            return Err(Errors::Io(format!(
+                "block file {} is corrupted",
+                position.file
+            )));
+        }
+        Ok(Some(block))
+    }
+}
+
+impl BlockStorage for BlockStore {
+    fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Block>, Errors> {
+        self.read_block(hash)
+    }
+
+    fn put(&mut self, block: &Block) -> Result<(), Errors> {
+        self.write_block(block).map(|_| ())
+    }
+
+    fn hashes(&self) -> Vec<[u8; 32]> {
+        self.index.keys().copied().collect()
+    }
+
+    fn get_undo(&self, hash: &[u8; 32]) -> Result<Option<BlockUndo>, Errors> {
+        self.read_undo(hash)
+    }
+
+    fn put_undo(&mut self, hash: &[u8; 32], undo: &BlockUndo) -> Result<(), Errors> {
+        self.write_undo(hash, undo).map(|_| ())
+    }
+
+    // Whole files are deleted, oldest first, skipping those with a block to keep
+    // and the one being appended to.
+    fn prune(
+        &mut self,
+        target: u64,
+        keep: &dyn Fn(&[u8; 32]) -> bool,
+    ) -> Result<Vec<[u8; 32]>, Errors> {
+        let mut usage = self.disk_usage();
+        let mut pruned = Vec::new();
+        for file in self.files() {
+            if usage <= target || file == self.current_file {
+                break;
+            }
+            let hashes: Vec<[u8; 32]> = self
+                .index
+                .iter()
+                .filter(|(_, position)| position.file == file)
+                .map(|(hash, _)| *hash)
+                .collect();
+            if hashes.iter().any(keep) {
+                continue;
+            }
+            usage -= self.file_size(file);
+            for hash in &hashes {
+                self.index.remove(hash);
+                self.undo_index.remove(hash);
+            }
+            pruned.extend(hashes);
+            // The indexes stop pointing to the files before they disappear.
+            self.rewrite_index(UNDO_INDEX, &self.undo_index)?;
+            self.rewrite_index(BLOCK_INDEX, &self.index)?;
+            fs::remove_file(self.file_path(file)).map_err(io_error)?;
+            let undo_path = self.undo_file_path(file);
+            if undo_path.exists() {
+                fs::remove_file(undo_path).map_err(io_error)?;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod store_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_util::temp_path;
+    use crate::transaction::TxOut;
+    use crate::validation::Coin;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = temp_path(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Copies of the genesis block told apart by their nonce.
+    fn blocks(count: u32) -> Vec<Block> {
+        (0..count)
+            .map(|nonce| {
+                let mut block = Network::Regtest.genesis_block();
+                block.header.nonce = nonce;
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_write_and_read_blocks() {
+        let dir = temp_dir("store_tests");
+        let blocks = blocks(4);
+        let size = 4 + blocks[0].serialize().len() as u64;
+        // Room for two blocks per file.
+        let mut store = BlockStore::open(&dir).unwrap().with_max_file_size(2 * size);
+        let positions: Vec<BlockPosition> = blocks[..3]
+            .iter()
+            .map(|block| store.write_block(block).unwrap())
+            .collect();
+        assert_eq!(
+            positions[0],
+            BlockPosition {
+                file: 0,
+                offset: 4,
+                len: size as u32 - 4
+            }
+        );
+        assert_eq!(positions[1].offset, size + 4);
+        assert_eq!(positions[2].file, 1);
+        assert_eq!(store.write_block(&blocks[1]).unwrap(), positions[1]);
+        assert_eq!(store.len(), 3);
+        assert_eq!(fs::metadata(store.file_path(0)).unwrap().len(), 2 * size);
+
+        // Everything is found again after reopening, and appending goes on.
+        let mut store = BlockStore::open(&dir).unwrap();
+        for block in &blocks[..3] {
+            assert_eq!(store.read_block(&block.hash()), Ok(Some(block.clone())));
+        }
+        assert_eq!(store.read_block(&[0; 32]), Ok(None));
+        let more = &blocks[3];
+        assert_eq!(store.write_block(more).unwrap().file, 1);
+        assert_eq!(store.get(&more.hash()), Ok(Some(more.clone())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_and_read_undo() {
+        let dir = temp_dir("store_tests_undo");
+        let blocks = blocks(2);
+        let undo = BlockUndo {
+            spent: vec![vec![Coin {
+                output: TxOut::new(5000, vec![0x51].into()),
+                height: 7,
+                is_coinbase: true,
+            }]],
+        };
+        let mut store = BlockStore::open(&dir).unwrap();
+        let hash = blocks[0].hash();
+        assert!(store.write_undo(&hash, &undo).is_err());
+        store.put(&blocks[0]).unwrap();
+        let position = store.write_undo(&hash, &undo).unwrap();
+        assert_eq!(position.offset, 4);
+        assert_eq!(store.write_undo(&hash, &BlockUndo::default()), Ok(position));
+        assert!(store.undo_file_path(0).exists());
+        assert_eq!(store.get_undo(&blocks[1].hash()), Ok(None));
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.read_undo(&hash), Ok(Some(undo)));
+
+        // Damaged undo data fails its checksum.
+        let path = store.undo_file_path(0);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[6] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(store.read_undo(&hash).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_old_files() {
+        let dir = temp_dir("store_tests_prune");
+        let blocks = blocks(5);
+        let size = 4 + blocks[0].serialize().len() as u64;
+        let mut store = BlockStore::open(&dir).unwrap().with_max_file_size(2 * size);
+        for block in &blocks {
+            store.put(block).unwrap();
+        }
+        assert_eq!(store.disk_usage(), 5 * size);
+        store
+            .put_undo(&blocks[2].hash(), &BlockUndo::default())
+            .unwrap();
+        let undo_size = fs::metadata(store.undo_file_path(1)).unwrap().len();
+        assert_eq!(store.disk_usage(), 5 * size + undo_size);
+
+        // The first file has a block to keep, so the second one goes instead.
+        let keep = blocks[0].hash();
+        let pruned = store.prune(3 * size, &|hash| *hash == keep).unwrap();
+        assert_eq!(pruned.len(), 2);
+        assert!(!store.contains(&blocks[2].hash()));
+        assert!(!store.file_path(1).exists());
+        assert!(!store.undo_file_path(1).exists());
+        assert_eq!(store.get_undo(&blocks[2].hash()), Ok(None));
+        assert_eq!(store.disk_usage(), 3 * size);
+
+        // The file being appended to is never deleted.
+        let pruned = store.prune(0, &|_| false).unwrap();
+        assert_eq!(pruned.len(), 2);
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            store.read_block(&blocks[4].hash()),
+            Ok(Some(blocks[4].clone()))
+        );
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.contains(&blocks[4].hash()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_partial_index_record_is_ignored() {
+        let dir = temp_dir("store_tests_partial");
+        let blocks = blocks(2);
+        let mut store = BlockStore::open(&dir).unwrap();
+        store.put(&blocks[0]).unwrap();
+        store.put(&blocks[1]).unwrap();
+        let index = dir.join("index.dat");
+        let bytes = fs::read(&index).unwrap();
+        fs::write(&index, &bytes[..bytes.len() - 1]).unwrap();
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert!(store.contains(&blocks[0].hash()));
+        assert!(!store.contains(&blocks[1].hash()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,653 @@
+// The set of unspent outputs, as CCoinsViewCache over CCoinsViewDB in Bitcoin Core:
+// changes are kept in memory and written to a backend when flushed. Connecting a
+// block returns the coins it spent, which is what disconnecting it needs.
+use crate::block::Block;
+use crate::helper::{encode_varint, hash256, read_array, read_varint};
+use crate::transaction::{OutPoint, TxOut};
+use crate::types::errors::{Errors, ValidationError};
+use crate::validation::{Coin, UtxoView};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+impl Coin {
+    // Height and coinbase flag share a field as height * 2 + coinbase.
+    pub fn serialize(&self) -> Vec<u8> {
+        let code = (self.height << 1) | self.is_coinbase as u32;
+        let mut result = code.to_le_bytes().to_vec();
+        result.extend(self.output.serialize());
+        result
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let code = u32::from_le_bytes(read_array(reader)?);
+        Ok(Coin {
+            output: TxOut::parse(reader)?,
+            height: code >> 1,
+            is_coinbase: code & 1 == 1,
+        })
+    }
+}
+
+// Coins spent by a block, one list per transaction after the coinbase, in input order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    pub spent: Vec<Vec<Coin>>,
+}
+
+impl BlockUndo {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = encode_varint(self.spent.len() as u64);
+        for coins in &self.spent {
+            result.extend(encode_varint(coins.len() as u64));
+            for coin in coins {
+                result.extend(coin.serialize());
+            }
+        }
+        result
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let count = read_varint(reader)?;
+        let spent = (0..count)
+            .map(|_| {
+                let coins = read_varint(reader)?;
+                (0..coins).map(|_| Coin::parse(reader)).collect()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(BlockUndo { spent })
+    }
+}
+
+// Where flushed coins are kept. None in a change deletes the coin.
+pub trait UtxoBackend {
+    fn get(&self, outpoint: &OutPoint) -> Option<Coin>;
+
+    // Hash of the block the stored set is the result of.
+    fn best_block(&self) -> [u8; 32];
+
+    fn write(
+        &mut self,
+        changes: Vec<(OutPoint, Option<Coin>)>,
+        best_block: [u8; 32],
+    ) -> Result<(), Errors>;
+
+    // Every stored coin, in no particular order.
+    fn coins(&self) -> Vec<(OutPoint, Coin)>;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    coins: HashMap<OutPoint, Coin>,
+    best_block: [u8; 32],
+}
+
+impl UtxoBackend for MemoryBackend {
+    fn get(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.coins.get(outpoint).cloned()
+    }
+
+    fn best_block(&self) -> [u8; 32] {
+        self.best_block
+    }
+
+    fn write(
+        &mut self,
+        changes: Vec<(OutPoint, Option<Coin>)>,
+        best_block: [u8; 32],
+    ) -> Result<(), Errors> {
+        for (outpoint, coin) in changes {
+            match coin {
+                Some(coin) => self.coins.insert(outpoint, coin),
+                None => self.coins.remove(&outpoint),
+            };
+        }
+        self.best_block = best_block;
+        Ok(())
+    }
+
+    fn coins(&self) -> Vec<(OutPoint, Coin)> {
+        self.coins
+            .iter()
+            .map(|(outpoint, coin)| (*outpoint, coin.clone()))
+            .collect()
+    }
+}
+
+// Keeps the whole set in memory and a log of the flushes in a file. Each flush appends
+// a batch: its length, the best block hash, the count of changes and each outpoint
+// with its coin or a spent marker, then a checksum. A batch torn by a crash fails its
+// checksum and is dropped at open, leaving the set of the flush before. Once the log
+// holds more changes than there are coins, it is compacted into a single batch of
+// every coin, written to another file and renamed over it.
+#[derive(Clone, Debug)]
+pub struct FileBackend {
+    path: PathBuf,
+    memory: MemoryBackend,
+    // Changes in the log, to tell when compacting pays off.
+    logged: usize,
+}
+
+// Logs of fewer changes are never compacted.
+const MIN_COMPACT_CHANGES: usize = 10_000;
+
+impl FileBackend {
+    // Loads the set logged at path, or starts an empty one if there is no file yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Errors> {
+        let path = path.as_ref().to_path_buf();
+        let mut backend = FileBackend {
+            path,
+            memory: MemoryBackend::default(),
+            logged: 0,
+        };
+        if !backend.path.exists() {
+            return Ok(backend);
+        }
+        let bytes = fs::read(&backend.path).map_err(io_error)?;
+        let mut reader = bytes.as_slice();
+        while let Some((changes, best_block)) = parse_batch(&mut reader) {
+            backend.logged += changes.len();
+            backend.memory.write(changes, best_block)?;
+        }
+        if !reader.is_empty() {
+            // Torn by a crash while appending: what follows the last whole batch goes.
+            let valid = bytes.len() - reader.len();
+            OpenOptions::new()
+                .write(true)
+                .open(&backend.path)
+                .and_then(|file| file.set_len(valid as u64))
+                .map_err(io_error)?;
+        }
+        Ok(backend)
+    }
+
+    fn append(&self, batch: &[u8]) -> Result<(), Errors> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(io_error)?;
+        file.write_all(batch)
+            .and_then(|_| file.sync_data())
+            .map_err(io_error)
+    }
+
+    // Rewrites the log as one batch of every coin.
+    fn compact(&mut self) -> Result<(), Errors> {
+        let coins: Vec<(OutPoint, Option<Coin>)> = self
+            .memory
+            .coins
+            .iter()
+            .map(|(outpoint, coin)| (*outpoint, Some(coin.clone())))
+            .collect();
+        let tmp = self.path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(&serialize_batch(&coins, self.memory.best_block))?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(io_error)?;
+        self.logged = coins.len();
+        Ok(())
+    }
+}
+
+fn serialize_batch(changes: &[(OutPoint, Option<Coin>)], best_block: [u8; 32]) -> Vec<u8> {
+    let mut body = best_block.to_vec();
+    body.extend(encode_varint(changes.len() as u64));
+    for (outpoint, coin) in changes {
+        body.extend(outpoint.serialize());
+        match coin {
+            Some(coin) => {
+                body.push(1);
+                body.extend(coin.serialize());
+            }
+            None => body.push(0),
+        }
+    }
+    let mut batch = (body.len() as u32).to_le_bytes().to_vec();
+    batch.extend(&body);
+    batch.extend(hash256(&body));
+    batch
+}
+
+// The changes of a flush and the best block after it.
+type Batch = (Vec<(OutPoint, Option<Coin>)>, [u8; 32]);
+
+// The next whole batch of reader, or None at its end or at a torn batch, which is
+// left unread.
+fn parse_batch(reader: &mut &[u8]) -> Option<Batch> {
+    let mut rest = *reader;
+    let len = u32::from_le_bytes(read_array(&mut rest).ok()?) as usize;
+    if rest.len() < len + 32 {
+        return None;
+    }
+    let (mut body, rest) = rest.split_at(len);
+    let (checksum, rest) = rest.split_at(32);
+    if checksum != hash256(body) {
+        return None;
+    }
+    let best_block = read_array(&mut body).ok()?;
+    let count = read_varint(&mut body).ok()?;
+    let mut changes = Vec::new();
+    for _ in 0..count {
+        let outpoint = OutPoint::parse(&mut body).ok()?;
+        let coin = match read_array::<1>(&mut body).ok()?[0] {
+            0 => None,
+            _ => Some(Coin::parse(&mut body).ok()?),
+        };
+        changes.push((outpoint, coin));
+    }
+    if !body.is_empty() {
+        return None;
+    }
+    *reader = rest;
+    Some((changes, best_block))
+}
+
+fn io_error(e: std::io::Error) -> Errors {
+    Errors::Io(e.to_string())
+}
+
+impl UtxoBackend for FileBackend {
+    fn get(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.memory.get(outpoint)
+    }
+
+    fn best_block(&self) -> [u8; 32] {
+        self.memory.best_block()
+    }
+
+    fn write(
+        &mut self,
+        changes: Vec<(OutPoint, Option<Coin>)>,
+        best_block: [u8; 32],
+    ) -> Result<(), Errors> {
+        self.append(&serialize_batch(&changes, best_block))?;
+        self.logged += changes.len();
+        self.memory.write(changes, best_block)?;
+        if self.logged > MIN_COMPACT_CHANGES.max(2 * self.memory.coins.len()) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn coins(&self) -> Vec<(OutPoint, Coin)> {
+        self.memory.coins()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct UtxoSet<B: UtxoBackend = MemoryBackend> {
+    backend: B,
+    // Coins added or spent since the last flush, None for spent ones.
+    cache: HashMap<OutPoint, Option<Coin>>,
+    best_block: [u8; 32],
+}
+
+impl<B: UtxoBackend> UtxoSet<B> {
+    pub fn new(backend: B) -> Self {
+        UtxoSet {
+            best_block: backend.best_block(),
+            backend,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    // Hash of the last block connected, or the block disconnection went back to.
+    pub fn best_block(&self) -> [u8; 32] {
+        self.best_block
+    }
+
+    // Every unspent coin, cached changes included, sorted by outpoint.
+    pub fn coins(&self) -> Vec<(OutPoint, Coin)> {
+        let mut coins: Vec<(OutPoint, Coin)> = self
+            .backend
+            .coins()
+            .into_iter()
+            .filter(|(outpoint, _)| !self.cache.contains_key(outpoint))
+            .collect();
+        coins.extend(
+            self.cache
+                .iter()
+                .filter_map(|(outpoint, coin)| Some((*outpoint, coin.clone()?))),
+        );
+        coins.sort_by_key(|(outpoint, _)| *outpoint);
+        coins
+    }
+
+    // Coins paying one of scripts, sorted by outpoint, and how many coins there are.
+    pub fn scan(&self, scripts: &HashSet<&[u8]>) -> (usize, Vec<(OutPoint, Coin)>) {
+        let coins = self.coins();
+        let count = coins.len();
+        let found = coins
+            .into_iter()
+            .filter(|(_, coin)| scripts.contains(coin.output.script_pubkey.as_bytes()))
+            .collect();
+        (count, found)
+    }
+
+    // Starts from coins as left by best_block, replacing everything.
+    pub fn load(&mut self, coins: Vec<(OutPoint, Coin)>, best_block: [u8; 32]) {
+        self.cache = self
+            .backend
+            .coins()
+            .into_iter()
+            .map(|(outpoint, _)| (outpoint, None))
+            .collect();
+        self.cache.extend(
+            coins
+                .into_iter()
+                .map(|(outpoint, coin)| (outpoint, Some(coin))),
+        );
+        self.best_block = best_block;
+    }
+
+    // Changes not flushed yet.
+    pub fn cache_size(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn add_coin(&mut self, outpoint: OutPoint, coin: Coin) {
+        self.cache.insert(outpoint, Some(coin));
+    }
+
+    pub fn spend_coin(&mut self, outpoint: &OutPoint) -> Option<Coin> {
+        let coin = self.get_coin(outpoint)?;
+        self.cache.insert(*outpoint, None);
+        Some(coin)
+    }
+
+    // Spends the inputs and adds the outputs of every transaction of a block at
+    // height. Nothing changes if an input is missing, which includes spending an
+    // output of a later transaction of the same block.
+    pub fn apply_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+    ) -> Result<BlockUndo, ValidationError> {
+        let mut staged: HashMap<OutPoint, Option<Coin>> = HashMap::new();
+        let mut undo = BlockUndo::default();
+        for (index, tx) in block.iter().enumerate() {
+            if index > 0 {
+                let mut spent = Vec::with_capacity(tx.inputs.len());
+                for input in &tx.inputs {
+                    let outpoint = input.previous_output;
+                    let coin = match staged.get(&outpoint) {
+                        Some(coin) => coin.clone(),
+                        None => self.get_coin(&outpoint),
+                    }
+                    .ok_or_else(|| ValidationError::MissingInput(outpoint.to_string()))?;
+                    staged.insert(outpoint, None);
+                    spent.push(coin);
+                }
+                undo.spent.push(spent);
+            }
+            let txid = tx.txid();
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                // Provably unspendable outputs are never added.
+                if output.is_unspendable() {
+                    continue;
+                }
+                let coin = Coin {
+                    output: output.clone(),
+                    height,
+                    is_coinbase: index == 0,
+                };
+                staged.insert(OutPoint::new(txid, vout as u32), Some(coin));
+            }
+        }
+        self.cache.extend(staged);
+        self.best_block = block.hash();
+        Ok(undo)
+    }
+
+    // Reverses apply_block: removes the block's outputs and restores what it spent,
+    // going back to its parent.
+    pub fn undo_block(&mut self, block: &Block, undo: &BlockUndo) -> Result<(), Errors> {
+        if undo.spent.len() != block.len().saturating_sub(1) {
+            return Err(Errors::BadUndoData);
+        }
+        for (index, tx) in block.iter().enumerate().rev() {
+            let txid = tx.txid();
+            for vout in 0..tx.outputs.len() {
+                self.cache.insert(OutPoint::new(txid, vout as u32), None);
+            }
+            if index == 0 {
+                continue;
+            }
+            let spent = &undo.spent[index - 1];
+            if spent.len() != tx.inputs.len() {
+                return Err(Errors::BadUndoData);
+            }
+            for (input, coin) in tx.inputs.iter().zip(spent) {
+                self.cache.insert(input.previous_output, Some(coin.clone()));
+            }
+        }
+        self.best_block = block.header.prev_block;
+        Ok(())
+    }
+
+    // Writes cached changes to the backend.
+    pub fn flush(&mut self) -> Result<(), Errors> {
+        let changes = self.cache.drain().collect();
+        self.backend.write(changes, self.best_block)
+    }
+}
+
+impl<B: UtxoBackend> UtxoView for UtxoSet<B> {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        match self.cache.get(outpoint) {
+            Some(coin) => coin.clone(),
+            None => self.backend.get(outpoint),
+        }
+    }
+}
+
+#[cfg(test)]
+mod utxo_tests {
+    use super::*;
+    use crate::block::BlockHeader;
+    use crate::test_util::{self, spend, temp_path};
+    use crate::transaction::Transaction;
+
+    // A coinbase with an OP_RETURN output, which is not a coin, after the one paying
+    // OP_TRUE.
+    fn coinbase(height: u32) -> Transaction {
+        let mut coinbase = test_util::coinbase(height, 0, 50_0000_0000);
+        coinbase
+            .outputs
+            .push(TxOut::new(0, vec![0x6a, 0x01, 0x00].into()));
+        coinbase
+    }
+
+    fn block(prev_block: [u8; 32], transactions: Vec<Transaction>) -> Block {
+        let header = BlockHeader {
+            version: 4,
+            prev_block,
+            merkle_root: [0; 32],
+            timestamp: 0,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        Block::new(header, transactions)
+    }
+
+    // Block 1 pays a coinbase, block 2 spends it and then the output of that spend.
+    fn two_blocks() -> (Block, Block) {
+        let first = block([0; 32], vec![coinbase(1)]);
+        let funding = OutPoint::new(first.transactions[0].txid(), 0);
+        let spend_a = spend(&[funding], 40_0000_0000, 0xffffffff);
+        let spend_b = spend(
+            &[OutPoint::new(spend_a.txid(), 0)],
+            30_0000_0000,
+            0xffffffff,
+        );
+        let second = block(first.hash(), vec![coinbase(2), spend_a, spend_b]);
+        (first, second)
+    }
+
+    #[test]
+    fn test_apply_and_undo_block() {
+        let (first, second) = two_blocks();
+        let mut utxos = UtxoSet::new(MemoryBackend::default());
+        let undo_first = utxos.apply_block(&first, 1).unwrap();
+        assert_eq!(undo_first, BlockUndo::default());
+        let funding = OutPoint::new(first.transactions[0].txid(), 0);
+        let coin = utxos.get_coin(&funding).unwrap();
+        assert!(coin.is_coinbase);
+        assert_eq!(coin.height, 1);
+        // The OP_RETURN output is not a coin.
+        assert_eq!(utxos.get_coin(&OutPoint::new(funding.txid, 1)), None);
+
+        let undo = utxos.apply_block(&second, 2).unwrap();
+        assert_eq!(utxos.best_block(), second.hash());
+        assert_eq!(undo.spent.len(), 2);
+        assert_eq!(undo.spent[0], vec![coin.clone()]);
+        assert_eq!(utxos.get_coin(&funding), None);
+        let last = OutPoint::new(second.transactions[2].txid(), 0);
+        assert_eq!(utxos.get_coin(&last).unwrap().output.value, 30_0000_0000);
+        // The intermediate output was created and spent within the block.
+        let middle = OutPoint::new(second.transactions[1].txid(), 0);
+        assert_eq!(utxos.get_coin(&middle), None);
+
+        utxos.undo_block(&second, &undo).unwrap();
+        assert_eq!(utxos.best_block(), first.hash());
+        assert_eq!(utxos.get_coin(&funding), Some(coin));
+        assert_eq!(utxos.get_coin(&last), None);
+        assert_eq!(utxos.get_coin(&middle), None);
+        assert_eq!(
+            utxos.undo_block(&second, &BlockUndo::default()),
+            Err(Errors::BadUndoData)
+        );
+    }
+
+    #[test]
+    fn test_failed_apply_changes_nothing() {
+        let (first, mut second) = two_blocks();
+        // Spending an output created later in the block is not allowed.
+        second.transactions.swap(1, 2);
+        let mut utxos = UtxoSet::new(MemoryBackend::default());
+        utxos.apply_block(&first, 1).unwrap();
+        let cached = utxos.cache_size();
+        assert!(matches!(
+            utxos.apply_block(&second, 2),
+            Err(ValidationError::MissingInput(_))
+        ));
+        assert_eq!(utxos.cache_size(), cached);
+        assert_eq!(utxos.best_block(), first.hash());
+    }
+
+    #[test]
+    fn test_undo_serialization() {
+        let (first, second) = two_blocks();
+        let mut utxos = UtxoSet::new(MemoryBackend::default());
+        utxos.apply_block(&first, 1).unwrap();
+        let undo = utxos.apply_block(&second, 2).unwrap();
+        let bytes = undo.serialize();
+        assert_eq!(BlockUndo::parse(&mut bytes.as_slice()).unwrap(), undo);
+    }
+
+    #[test]
+    fn test_file_backend_persists_flushed_coins() {
+        let dir = temp_path("utxo_tests");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chainstate.dat");
+        let _ = fs::remove_file(&path);
+
+        let (first, second) = two_blocks();
+        let mut utxos = UtxoSet::new(FileBackend::open(&path).unwrap());
+        utxos.apply_block(&first, 1).unwrap();
+        utxos.apply_block(&second, 2).unwrap();
+        // Nothing is written before a flush.
+        assert!(!path.exists());
+        utxos.flush().unwrap();
+        assert_eq!(utxos.cache_size(), 0);
+
+        let reopened = UtxoSet::new(FileBackend::open(&path).unwrap());
+        assert_eq!(reopened.best_block(), second.hash());
+        let last = OutPoint::new(second.transactions[2].txid(), 0);
+        assert_eq!(reopened.get_coin(&last), utxos.get_coin(&last));
+        let funding = OutPoint::new(first.transactions[0].txid(), 0);
+        assert_eq!(reopened.get_coin(&funding), None);
+        assert!(reopened
+            .get_coin(&OutPoint::new(second.transactions[0].txid(), 0))
+            .is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn coin(height: u32) -> Coin {
+        Coin {
+            output: TxOut::new(height as u64, vec![0x51].into()),
+            height,
+            is_coinbase: false,
+        }
+    }
+
+    #[test]
+    fn test_file_backend_drops_torn_batch() {
+        let dir = temp_path("utxo_torn_tests");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chainstate.dat");
+        let _ = fs::remove_file(&path);
+
+        let first = OutPoint::new([1; 32], 0);
+        let second = OutPoint::new([2; 32], 0);
+        let mut backend = FileBackend::open(&path).unwrap();
+        backend
+            .write(vec![(first, Some(coin(1)))], [1; 32])
+            .unwrap();
+        let flushed = fs::metadata(&path).unwrap().len();
+        backend
+            .write(vec![(first, None), (second, Some(coin(2)))], [2; 32])
+            .unwrap();
+        // A crash halfway through appending the second batch.
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 20]).unwrap();
+
+        let mut reopened = FileBackend::open(&path).unwrap();
+        assert_eq!(reopened.best_block(), [1; 32]);
+        assert_eq!(reopened.get(&first), Some(coin(1)));
+        assert_eq!(reopened.get(&second), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), flushed);
+        // Later batches follow the last whole one.
+        reopened
+            .write(vec![(second, Some(coin(2)))], [3; 32])
+            .unwrap();
+        let reopened = FileBackend::open(&path).unwrap();
+        assert_eq!(reopened.best_block(), [3; 32]);
+        assert_eq!(reopened.get(&second), Some(coin(2)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_backend_compacts_log() {
+        let dir = temp_path("utxo_compact_tests");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chainstate.dat");
+        let _ = fs::remove_file(&path);
+
+        let outpoints: Vec<OutPoint> = (0..=MIN_COMPACT_CHANGES as u32)
+            .map(|index| OutPoint::new([7; 32], index))
+            .collect();
+        let mut backend = FileBackend::open(&path).unwrap();
+        let created = outpoints.iter().map(|o| (*o, Some(coin(1)))).collect();
+        backend.write(created, [1; 32]).unwrap();
+        let spent = outpoints[1..].iter().map(|o| (*o, None)).collect();
+        backend.write(spent, [2; 32]).unwrap();
+
+        // Only the remaining coin is left in the file.
+        let single = serialize_batch(&[(outpoints[0], Some(coin(1)))], [2; 32]);
+        assert_eq!(fs::read(&path).unwrap(), single);
+        let reopened = FileBackend::open(&path).unwrap();
+        assert_eq!(reopened.best_block(), [2; 32]);
+        assert_eq!(reopened.coins(), vec![(outpoints[0], coin(1))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,479 @@
+// Block validation beyond the header: the checks needing only the block itself
+// (CheckBlock in Bitcoin Core), those depending on its place in the chain
+// (ContextualCheckBlock) and those against the UTXO set (ConnectBlock).
+use super::headers::{HeaderChain, HeaderEntry};
+use crate::block::{merkle_root, Block};
+use crate::helper::hash256;
+use crate::script::{Script, VerificationFlags};
+use crate::transaction::weight::WITNESS_SCALE_FACTOR;
+use crate::transaction::{txid_to_hex, OutPoint, Transaction};
+use crate::types::errors::ValidationError;
+use crate::validation::{
+    check_transaction, legacy_sigop_count, transaction_sigop_cost, validate_transaction, Coin,
+    ScriptVerifier, UtxoView, MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_WEIGHT, MAX_MONEY,
+};
+use std::collections::HashMap;
+
+// A block failing these may be a corrupted copy of a valid one, so the failure says
+// nothing about its header.
+pub fn check_block(block: &Block) -> Result<(), ValidationError> {
+    let (root, mutated) = merkle_root(&block.txids());
+    if root != block.header.merkle_root {
+        return Err(ValidationError::BadMerkleRoot);
+    }
+    if mutated {
+        return Err(ValidationError::DuplicateTransactions);
+    }
+    if block.len() * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+        || block.stripped_size() * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+    {
+        return Err(ValidationError::BlockOversize);
+    }
+    if !block.coinbase().is_some_and(Transaction::is_coinbase) {
+        return Err(ValidationError::MissingCoinbase);
+    }
+    if block.iter().skip(1).any(Transaction::is_coinbase) {
+        return Err(ValidationError::MultipleCoinbase);
+    }
+    for tx in block {
+        check_transaction(tx)?;
+    }
+    let sigops: usize = block.iter().map(legacy_sigop_count).sum();
+    if sigops * WITNESS_SCALE_FACTOR > MAX_BLOCK_SIGOPS_COST {
+        return Err(ValidationError::TooManySigops);
+    }
+    Ok(())
+}
+
+// Checks of a block whose parent is prev, without looking at the coins it spends.
+pub fn contextual_check_block(
+    block: &Block,
+    headers: &HeaderChain,
+    prev: &HeaderEntry,
+) -> Result<(), ValidationError> {
+    let network = headers.network();
+    let height = prev.height + 1;
+    // BIP113: once CSV is active locktimes are compared to the median time past.
+    let lock_time_cutoff = if height >= network.csv_height() {
+        headers.median_time_past(prev)
+    } else {
+        block.header.timestamp
+    };
+    for tx in block {
+        if !tx.is_final(height, lock_time_cutoff) {
+            return Err(ValidationError::NonFinalTransaction(txid_to_hex(
+                &tx.txid(),
+            )));
+        }
+    }
+    if height >= network.bip34_height() {
+        let mut expected = Script::new();
+        expected.push_int(height as i64);
+        if !block.transactions[0].inputs[0]
+            .script_sig
+            .starts_with(&expected)
+        {
+            return Err(ValidationError::BadCoinbaseHeight);
+        }
+    }
+    check_witness_commitment(block, height >= network.segwit_height())?;
+    if block.weight() > MAX_BLOCK_WEIGHT {
+        return Err(ValidationError::BlockOversize);
+    }
+    Ok(())
+}
+
+// BIP141: once segwit is active, a coinbase output may commit to the witness root
+// and the reserved value in the coinbase witness. Without a commitment, or before
+// activation, no transaction can have witness data.
+pub fn check_witness_commitment(block: &Block, segwit_active: bool) -> Result<(), ValidationError> {
+    if segwit_active {
+        if let Some(commitment) = block.witness_commitment() {
+            let witness = &block.transactions[0].inputs[0].witness;
+            if witness.len() != 1 || witness[0].len() != 32 {
+                return Err(ValidationError::BadWitnessNonceSize);
+            }
+            let mut data = block.witness_root().to_vec();
+            data.extend_from_slice(&witness[0]);
+            if hash256(&data) != commitment {
+                return Err(ValidationError::BadWitnessMerkleMatch);
+            }
+            return Ok(());
+        }
+    }
+    if block.iter().any(Transaction::has_witness) {
+        return Err(ValidationError::UnexpectedWitness);
+    }
+    Ok(())
+}
+
+// The coins in utxos with the changes made by the block's transactions so far.
+struct BlockView<'a, V: UtxoView> {
+    utxos: &'a V,
+    changes: HashMap<OutPoint, Option<Coin>>,
+}
+
+impl<V: UtxoView> UtxoView for BlockView<'_, V> {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        match self.changes.get(outpoint) {
+            Some(coin) => coin.clone(),
+            None => self.utxos.get_coin(outpoint),
+        }
+    }
+}
+
+// Validates every transaction of the block at entry against utxos, the coins left by
+// its parent, running scripts with the rules in force at its height. The coinbase
+// may claim the subsidy and the fees, which are returned.
+pub fn check_block_inputs(
+    block: &Block,
+    entry: &HeaderEntry,
+    headers: &HeaderChain,
+    utxos: &impl UtxoView,
+    verifier: &impl ScriptVerifier,
+) -> Result<u64, ValidationError> {
+    let network = headers.network();
+    let height = entry.height;
+    let flags = network.script_flags(height, &entry.hash);
+    let prev_median_time = headers
+        .prev(entry)
+        .map_or(0, |prev| headers.median_time_past(prev));
+    let mut view = BlockView {
+        utxos,
+        changes: HashMap::new(),
+    };
+    let mut fees: u64 = 0;
+    let mut sigop_cost = 0;
+    for (index, tx) in block.iter().enumerate() {
+        let mut prevouts = Vec::new();
+        if index > 0 {
+            fees += validate_transaction(tx, &view, height, flags, verifier)?;
+            if fees > MAX_MONEY {
+                return Err(ValidationError::InputValueOutOfRange);
+            }
+            let coins: Vec<Coin> = tx
+                .inputs
+                .iter()
+                .map(|input| view.get_coin(&input.previous_output).unwrap())
+                .collect();
+            if flags.contains(VerificationFlags::CHECKSEQUENCEVERIFY) {
+                let coin_heights: Vec<u32> = coins.iter().map(|coin| coin.height).collect();
+                let locks = tx.sequence_locks(&coin_heights, |height| {
+                    let ancestor = headers.ancestor(entry, height).unwrap();
+                    headers.median_time_past(ancestor)
+                });
+                if !locks.is_satisfied_by(height, prev_median_time) {
+                    return Err(ValidationError::NonFinalTransaction(txid_to_hex(
+                        &tx.txid(),
+                    )));
+                }
+            }
+            for input in &tx.inputs {
+                view.changes.insert(input.previous_output, None);
+            }
+            prevouts = coins.into_iter().map(|coin| coin.output).collect();
+        }
+        sigop_cost += transaction_sigop_cost(tx, &prevouts, flags);
+        if sigop_cost > MAX_BLOCK_SIGOPS_COST {
+            return Err(ValidationError::TooManySigops);
+        }
+        let txid = tx.txid();
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            let coin = Coin {
+                output: output.clone(),
+                height,
+                is_coinbase: index == 0,
+            };
+            view.changes
+                .insert(OutPoint::new(txid, vout as u32), Some(coin));
+        }
+    }
+
+    let limit = fees + network.block_subsidy(height);
+    let value: u64 = block.transactions[0]
+        .outputs
+        .iter()
+        .map(|output| output.value)
+        .sum();
+    if value > limit {
+        return Err(ValidationError::BadCoinbaseAmount { value, limit });
+    }
+    Ok(fees)
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use super::*;
+    use crate::block::merkle::WITNESS_COMMITMENT_HEADER;
+    use crate::network::Network;
+    use crate::test_util::{coinbase, mine, spend};
+    use crate::transaction::{Sequence, TxOut, Witness};
+    use crate::validation::{ScriptInterpreter, COIN};
+
+    // Headers of a chain of count blocks, each paying 50 BTC to OP_TRUE, and the
+    // coins they created.
+    fn chain(count: u32) -> (HeaderChain, HashMap<OutPoint, Coin>) {
+        let mut headers = HeaderChain::new(Network::Regtest);
+        let mut coins = HashMap::new();
+        for height in 1..=count {
+            let block = mine(&headers.tip().header, vec![coinbase(height, 0, 50 * COIN)]);
+            let tx = &block.transactions[0];
+            coins.insert(
+                OutPoint::new(tx.txid(), 0),
+                Coin {
+                    output: tx.outputs[0].clone(),
+                    height,
+                    is_coinbase: true,
+                },
+            );
+            headers.accept_header(block.header).unwrap();
+        }
+        (headers, coins)
+    }
+
+    // Checks block as the next one of headers, adding its header first.
+    fn connect(
+        headers: &mut HeaderChain,
+        coins: &HashMap<OutPoint, Coin>,
+        block: &Block,
+    ) -> Result<u64, ValidationError> {
+        check_block(block)?;
+        let prev = headers.tip().clone();
+        contextual_check_block(block, headers, &prev)?;
+        let hash = headers.accept_header(block.header)?;
+        let entry = headers.get(&hash).unwrap().clone();
+        check_block_inputs(block, &entry, headers, coins, &ScriptInterpreter::new())
+    }
+
+    fn coin_at(coins: &HashMap<OutPoint, Coin>, height: u32) -> OutPoint {
+        *coins
+            .iter()
+            .find(|(_, coin)| coin.height == height)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn test_check_block() {
+        let (headers, _) = chain(0);
+        let good = mine(&headers.tip().header, vec![coinbase(1, 0, 50 * COIN)]);
+        assert_eq!(check_block(&good), Ok(()));
+
+        let mut bad_root = good.clone();
+        bad_root.header.merkle_root = [1; 32];
+        assert_eq!(check_block(&bad_root), Err(ValidationError::BadMerkleRoot));
+
+        let no_coinbase = mine(
+            &headers.tip().header,
+            vec![spend(&[OutPoint::new([1; 32], 0)], 1, 0xffffffff)],
+        );
+        assert_eq!(
+            check_block(&no_coinbase),
+            Err(ValidationError::MissingCoinbase)
+        );
+        let two_coinbases = mine(
+            &headers.tip().header,
+            vec![coinbase(1, 0, 50 * COIN), coinbase(2, 0, 50 * COIN)],
+        );
+        assert_eq!(
+            check_block(&two_coinbases),
+            Err(ValidationError::MultipleCoinbase)
+        );
+
+        // Repeating the last two transactions keeps the merkle root.
+        let tx = spend(&[OutPoint::new([1; 32], 0)], 1, 0xffffffff);
+        let other = spend(&[OutPoint::new([2; 32], 0)], 1, 0xffffffff);
+        let block = mine(
+            &headers.tip().header,
+            vec![coinbase(1, 0, 50 * COIN), tx.clone(), other.clone()],
+        );
+        let mut mutated = block.clone();
+        mutated.transactions.push(other);
+        assert_eq!(mutated.compute_merkle_root(), block.header.merkle_root);
+        assert_eq!(
+            check_block(&mutated),
+            Err(ValidationError::DuplicateTransactions)
+        );
+
+        // Legacy sigops are counted in every script, spent or not.
+        let limit = MAX_BLOCK_SIGOPS_COST / WITNESS_SCALE_FACTOR;
+        let mut heavy = coinbase(1, 0, 50 * COIN);
+        heavy.outputs.push(TxOut::new(0, vec![0xac; limit].into()));
+        assert_eq!(
+            check_block(&mine(&headers.tip().header, vec![heavy.clone()])),
+            Ok(())
+        );
+        heavy.outputs[1].script_pubkey = vec![0xac; limit + 1].into();
+        assert_eq!(
+            check_block(&mine(&headers.tip().header, vec![heavy])),
+            Err(ValidationError::TooManySigops)
+        );
+    }
+
+    #[test]
+    fn test_contextual_check_block() {
+        let (headers, _) = chain(3);
+        let prev = headers.tip().clone();
+        let good = mine(&prev.header, vec![coinbase(4, 0, 50 * COIN)]);
+        assert_eq!(contextual_check_block(&good, &headers, &prev), Ok(()));
+
+        let wrong_height = mine(&prev.header, vec![coinbase(5, 0, 50 * COIN)]);
+        assert_eq!(
+            contextual_check_block(&wrong_height, &headers, &prev),
+            Err(ValidationError::BadCoinbaseHeight)
+        );
+
+        // Height locks must be below the block's height.
+        let mut locked = spend(&[OutPoint::new([1; 32], 0)], 1, 0);
+        locked.locktime = 4;
+        let block = mine(
+            &prev.header,
+            vec![coinbase(4, 0, 50 * COIN), locked.clone()],
+        );
+        assert_eq!(
+            contextual_check_block(&block, &headers, &prev),
+            Err(ValidationError::NonFinalTransaction(txid_to_hex(
+                &locked.txid()
+            )))
+        );
+        locked.locktime = 3;
+        let block = mine(&prev.header, vec![coinbase(4, 0, 50 * COIN), locked]);
+        assert_eq!(contextual_check_block(&block, &headers, &prev), Ok(()));
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let (headers, _) = chain(3);
+        let prev = headers.tip().clone();
+        let mut segwit_tx = spend(&[OutPoint::new([1; 32], 0)], 1, 0xffffffff);
+        segwit_tx.inputs[0].witness = Witness::from_elements(vec![vec![1]]);
+        let block = mine(
+            &prev.header,
+            vec![coinbase(4, 0, 50 * COIN), segwit_tx.clone()],
+        );
+        assert_eq!(
+            contextual_check_block(&block, &headers, &prev),
+            Err(ValidationError::UnexpectedWitness)
+        );
+
+        let mut coinbase = coinbase(4, 0, 50 * COIN);
+        let mut data = mine(&prev.header, vec![coinbase.clone(), segwit_tx.clone()])
+            .witness_root()
+            .to_vec();
+        data.extend_from_slice(&[0; 32]);
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend_from_slice(&hash256(&data));
+        coinbase.outputs.push(TxOut::new(0, script.into()));
+        let block = mine(&prev.header, vec![coinbase.clone(), segwit_tx.clone()]);
+        assert_eq!(
+            contextual_check_block(&block, &headers, &prev),
+            Err(ValidationError::BadWitnessNonceSize)
+        );
+
+        coinbase.inputs[0].witness = Witness::from_elements(vec![vec![0; 32]]);
+        let block = mine(&prev.header, vec![coinbase.clone(), segwit_tx.clone()]);
+        assert_eq!(contextual_check_block(&block, &headers, &prev), Ok(()));
+        // Before segwit the commitment doesn't make witness data acceptable.
+        assert_eq!(
+            check_witness_commitment(&block, false),
+            Err(ValidationError::UnexpectedWitness)
+        );
+
+        segwit_tx.inputs[0].witness = Witness::from_elements(vec![vec![2]]);
+        let block = mine(&prev.header, vec![coinbase, segwit_tx]);
+        assert_eq!(
+            contextual_check_block(&block, &headers, &prev),
+            Err(ValidationError::BadWitnessMerkleMatch)
+        );
+    }
+
+    #[test]
+    fn test_coinbase_value() {
+        let (mut headers, coins) = chain(101);
+        let funding = coin_at(&coins, 1);
+        let payment = spend(&[funding], 49 * COIN, 0xffffffff);
+
+        let greedy = mine(
+            &headers.tip().header,
+            vec![coinbase(102, 0, 51 * COIN + 1), payment.clone()],
+        );
+        assert_eq!(
+            connect(&mut headers.clone(), &coins, &greedy),
+            Err(ValidationError::BadCoinbaseAmount {
+                value: 51 * COIN + 1,
+                limit: 51 * COIN
+            })
+        );
+        let block = mine(
+            &headers.tip().header,
+            vec![coinbase(102, 0, 51 * COIN), payment],
+        );
+        assert_eq!(connect(&mut headers, &coins, &block), Ok(COIN));
+    }
+
+    #[test]
+    fn test_inputs_are_validated() {
+        let (mut headers, coins) = chain(101);
+        // The coinbase of block 3 matures at height 103, so block 102 can't spend it.
+        let immature = spend(&[coin_at(&coins, 3)], 49 * COIN, 0xffffffff);
+        let block = mine(
+            &headers.tip().header,
+            vec![coinbase(102, 0, 50 * COIN), immature],
+        );
+        assert!(matches!(
+            connect(&mut headers.clone(), &coins, &block),
+            Err(ValidationError::PrematureCoinbaseSpend(_))
+        ));
+
+        // Spending an output of an earlier transaction of the same block is fine.
+        let parent = spend(&[coin_at(&coins, 1)], 49 * COIN, 0xffffffff);
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 48 * COIN, 0xffffffff);
+        let block = mine(
+            &headers.tip().header,
+            vec![coinbase(102, 0, 50 * COIN), parent, child],
+        );
+        assert_eq!(connect(&mut headers.clone(), &coins, &block), Ok(2 * COIN));
+
+        // Scripts are run: OP_FALSE never validates.
+        let mut coins = coins;
+        let funding = coin_at(&coins, 1);
+        coins.get_mut(&funding).unwrap().output.script_pubkey = vec![0x00].into();
+        let block = mine(
+            &headers.tip().header,
+            vec![
+                coinbase(102, 0, 50 * COIN),
+                spend(&[funding], 49 * COIN, 0xffffffff),
+            ],
+        );
+        assert!(matches!(
+            connect(&mut headers, &coins, &block),
+            Err(ValidationError::ScriptFailed { input_index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_sequence_locks() {
+        // A coin from block 1 spent with a relative lock of 101 blocks can first be
+        // included at height 102.
+        let (mut headers, coins) = chain(100);
+        let sequence = Sequence::from_height(101).0;
+        let locked = spend(&[coin_at(&coins, 1)], 49 * COIN, sequence);
+        let block = mine(
+            &headers.tip().header,
+            vec![coinbase(101, 0, 50 * COIN), locked.clone()],
+        );
+        assert_eq!(
+            connect(&mut headers.clone(), &coins, &block),
+            Err(ValidationError::NonFinalTransaction(txid_to_hex(
+                &locked.txid()
+            )))
+        );
+        let next = mine(&headers.tip().header, vec![coinbase(101, 0, 50 * COIN)]);
+        headers.accept_header(next.header).unwrap();
+        let block = mine(
+            &headers.tip().header,
+            vec![coinbase(102, 0, 50 * COIN), locked],
+        );
+        assert_eq!(connect(&mut headers, &coins, &block), Ok(COIN));
+    }
+}
@@ -0,0 +1,287 @@
+// Settings of the binaries, layered as bitcoind's: defaults, a bitcoin.conf file,
+// BITCOIN_<NAME> environment variables and -name=value flags, each overriding the
+// ones before. Entries of the file under [main], [test], [signet] or [regtest] only
+// apply on that network, over those outside any section. -name alone sets 1 and
+// -noname 0. Options like -connect keep every value of the layer setting them, the
+// others its last one. The file is -conf, relative to the data directory, or
+// bitcoin.conf in it; each network but mainnet keeps its data in a subdirectory.
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+pub const CONFIG_FILE: &str = "bitcoin.conf";
+pub const ENV_PREFIX: &str = "BITCOIN_";
+// Known to every binary: where the settings are and which network they are for.
+pub const COMMON_OPTIONS: &[&str] = &["chain", "conf", "datadir", "regtest", "signet", "testnet"];
+const NETWORKS: [Network; 4] = [
+    Network::Mainnet,
+    Network::Testnet,
+    Network::Signet,
+    Network::Regtest,
+];
+const NETWORK_FLAGS: [(&str, Network); 3] = [
+    ("testnet", Network::Testnet),
+    ("signet", Network::Signet),
+    ("regtest", Network::Regtest),
+];
+
+// Options set by one layer, in the order given.
+pub type Settings = Vec<(String, String)>;
+
+fn invalid(message: String) -> Errors {
+    Errors::InvalidConfig(message)
+}
+
+// noname=value is name=0 for a name that is known, as bitcoind reads it.
+fn setting(name: &str, value: &str, known: &[&str]) -> Option<(String, String)> {
+    let is_known = |name: &str| COMMON_OPTIONS.contains(&name) || known.contains(&name);
+    if is_known(name) {
+        return Some((name.to_string(), value.to_string()));
+    }
+    let negated = name.strip_prefix("no").filter(|name| is_known(name))?;
+    let value = if interpret_bool(value) { "0" } else { "1" };
+    Some((negated.to_string(), value.to_string()))
+}
+
+// As bitcoind: empty is true, then any non zero number.
+fn interpret_bool(value: &str) -> bool {
+    value.is_empty() || value.trim().parse::<i64>().is_ok_and(|n| n != 0)
+}
+
+// Flags up to the first argument not starting with '-', and the arguments from it.
+pub fn parse_args(
+    args: impl IntoIterator<Item = String>,
+    known: &[&str],
+) -> Result<(Settings, Vec<String>), Errors> {
+    let mut settings = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with('-')) {
+        let arg = arg.trim_start_matches('-');
+        let (name, value) = arg.split_once('=').unwrap_or((arg, ""));
+        let setting = setting(name, value, known)
+            .ok_or_else(|| invalid(format!("unknown option: -{name}")))?;
+        settings.push(setting);
+    }
+    Ok((settings, args.collect()))
+}
+
+// BITCOIN_RPCPORT for -rpcport, and so on for each known option.
+pub fn parse_env(vars: impl IntoIterator<Item = (String, String)>, known: &[&str]) -> Settings {
+    vars.into_iter()
+        .filter_map(|(var, value)| {
+            let name = var.strip_prefix(ENV_PREFIX)?.to_lowercase();
+            setting(&name, &value, known)
+        })
+        .collect()
+}
+
+// Entries of each section, None for those outside any, in bitcoin.conf's format of
+// name=value lines and # comments. Options other binaries take are left out.
+pub fn parse_file(text: &str, known: &[&str]) -> Result<Vec<(Option<String>, Settings)>, Errors> {
+    let mut sections: Vec<(Option<String>, Settings)> = vec![(None, Vec::new())];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(section) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            sections.push((Some(section.trim().to_string()), Vec::new()));
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("parse error on line {}: {line}", number + 1)))?;
+        let name = name.trim().trim_start_matches('-');
+        if let Some(setting) = setting(name, value.trim(), known) {
+            sections.last_mut().unwrap().1.push(setting);
+        }
+    }
+    Ok(sections)
+}
+
+// ~/.bitcoin-rust, which each network but mainnet has a subdirectory of.
+pub fn default_datadir() -> PathBuf {
+    let home = std::env::var_os("HOME").map_or_else(PathBuf::new, PathBuf::from);
+    home.join(".bitcoin-rust")
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    values: HashMap<String, Vec<String>>,
+}
+
+impl Config {
+    // Applies layers from the least important on, a layer setting an option
+    // replacing the values it had.
+    pub fn from_layers<'a>(layers: impl IntoIterator<Item = &'a Settings>) -> Self {
+        let mut values: HashMap<String, Vec<String>> = HashMap::new();
+        for layer in layers {
+            let mut replaced = Vec::new();
+            for (name, value) in layer {
+                if !replaced.contains(name) {
+                    values.remove(name);
+                    replaced.push(name.clone());
+                }
+                values.entry(name.clone()).or_default().push(value.clone());
+            }
+        }
+        Config { values }
+    }
+
+    // The settings of the command line, the environment and the config file, whose
+    // sections are picked once the network is known.
+    pub fn resolve(
+        args: Settings,
+        env: Settings,
+        file: Vec<(Option<String>, Settings)>,
+    ) -> Result<Self, Errors> {
+        let empty = Vec::new();
+        let top = file
+            .iter()
+            .find(|(section, _)| section.is_none())
+            .map_or(&empty, |(_, settings)| settings);
+        let network = Config::from_layers([top, &env, &args]).network()?;
+        let mut layers = vec![top];
+        for (section, settings) in &file {
+            if section.as_deref() == Some(network.name()) {
+                layers.push(settings);
+            }
+        }
+        layers.extend([&env, &args]);
+        Ok(Config::from_layers(layers))
+    }
+
+    // Reads the layers: args as given to the binary, the environment, and the config
+    // file they point to if it exists. Returns the arguments after the flags too.
+    pub fn load(
+        args: impl IntoIterator<Item = String>,
+        vars: impl IntoIterator<Item = (String, String)>,
+        known: &[&str],
+    ) -> Result<(Self, Vec<String>), Errors> {
+        let (args, rest) = parse_args(args, known)?;
+        let env = parse_env(vars, known);
+        let early = Config::from_layers([&env, &args]);
+        let root = early.root_datadir();
+        let path = match early.get("conf") {
+            Some(conf) => root.join(conf),
+            None => root.join(CONFIG_FILE),
+        };
+        let file = match fs::read_to_string(&path) {
+            Ok(text) => parse_file(&text, known)?,
+            Err(_) if early.get("conf").is_none() => Vec::new(),
+            Err(e) => return Err(invalid(format!("cannot read {}: {e}", path.display()))),
+        };
+        Ok((Config::resolve(args, env, file)?, rest))
+    }
+
+    // The last value of name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name)?.last().map(String::as_str)
+    }
+
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.values.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn get_bool(&self, name: &str) -> bool {
+        self.get(name).is_some_and(interpret_bool)
+    }
+
+    pub fn get_parsed<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, Errors> {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| invalid(format!("invalid -{name}: {value}"))),
+            None => Ok(None),
+        }
+    }
+
+    // -chain=<main|test|signet|regtest> or one of -testnet, -signet and -regtest.
+    pub fn network(&self) -> Result<Network, Errors> {
+        let mut selected: Vec<Network> = NETWORK_FLAGS
+            .into_iter()
+            .filter(|(flag, _)| self.get_bool(flag))
+            .map(|(_, network)| network)
+            .collect();
+        if let Some(chain) = self.get("chain") {
+            let network = NETWORKS
+                .into_iter()
+                .find(|network| network.name() == chain)
+                .ok_or_else(|| invalid(format!("unknown chain {chain}")))?;
+            selected.push(network);
+        }
+        match selected[..] {
+            [] => Ok(Network::Mainnet),
+            [network] => Ok(network),
+            _ => Err(invalid(
+                "-regtest, -signet, -testnet and -chain select more than one network".to_string(),
+            )),
+        }
+    }
+
+    // Where the config file is, and the data of each network under.
+    pub fn root_datadir(&self) -> PathBuf {
+        self.get("datadir")
+            .map_or_else(default_datadir, PathBuf::from)
+    }
+
+    pub fn datadir(&self, network: Network) -> PathBuf {
+        let root = self.root_datadir();
+        match network {
+            Network::Mainnet => root,
+            _ => root.join(network.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_layers() {
+        let known = &["connect", "rpcport", "blocksonly", "txindex"];
+        let file = parse_file(
+            "# shared with the other networks\nrpcport=1000\nconnect=a\nconnect=b\n\
+             txindex=1\n\n[regtest]\nrpcport=2000\nnoblocksonly=1\nwalletnotify=x\n",
+            known,
+        )
+        .unwrap();
+        let env = parse_env(
+            [
+                ("BITCOIN_TXINDEX".to_string(), "0".to_string()),
+                ("HOME".to_string(), "/root".to_string()),
+            ],
+            known,
+        );
+        let (args, rest) = parse_args(
+            strings(&["-regtest", "--connect=c", "getblock", "-x"]),
+            known,
+        )
+        .unwrap();
+        assert_eq!(rest, ["getblock", "-x"]);
+        let config = Config::resolve(args, env, file).unwrap();
+        assert_eq!(config.network(), Ok(Network::Regtest));
+        assert_eq!(config.get_parsed::<u16>("rpcport"), Ok(Some(2000)));
+        assert_eq!(config.get_all("connect"), ["c"]);
+        assert!(!config.get_bool("txindex"));
+        assert_eq!(config.get("blocksonly"), Some("0"));
+        assert!(config.get_parsed::<u16>("connect").is_err());
+        assert!(config.datadir(Network::Regtest).ends_with("regtest"));
+
+        assert!(parse_args(strings(&["-walletnotify=x"]), known).is_err());
+        assert!(parse_file("[main]\nrpcport", known).is_err());
+        let (args, _) = parse_args(strings(&["-signet", "-chain=regtest"]), known).unwrap();
+        assert!(Config::from_layers([&args]).network().is_err());
+    }
+}
@@ -0,0 +1,318 @@
+// Output descriptors (BIP380) of fixed scripts: addr, raw, pk, pkh, wpkh, combo,
+// multi and sortedmulti, sh and wsh around them or around a miniscript, and tr of a
+// key alone. Keys are public keys in hex, possibly after their [origin], x-only in
+// tr and compressed under segwit; extended keys, ranges and script trees aren't
+// supported. A #checksum after the descriptor is checked if there is one.
+use crate::address::script_from_address;
+use crate::ecc::S256Point;
+use crate::helper::hash160;
+use crate::miniscript::Miniscript;
+use crate::network::Network;
+use crate::script::interpreter::MAX_SCRIPT_ELEMENT_SIZE;
+use crate::script::{Opcode, Script};
+use crate::types::errors::Errors;
+use std::fmt;
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATORS: [u64; 5] = [
+    0xf5dee51989,
+    0xa9fdca3312,
+    0x1bab10e32d,
+    0x3706b1677a,
+    0x644d626ffd,
+];
+
+fn invalid(message: String) -> Errors {
+    Errors::InvalidDescriptor(message)
+}
+
+fn polymod(symbols: impl IntoIterator<Item = u64>) -> u64 {
+    let mut checksum = 1u64;
+    for value in symbols {
+        let top = checksum >> 35;
+        checksum = ((checksum & 0x7_ffff_ffff) << 5) ^ value;
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+// The eight characters following a '#' after expression, None if it has characters
+// descriptors can't.
+pub fn descriptor_checksum(expression: &str) -> Option<String> {
+    let mut symbols = Vec::new();
+    let mut groups = Vec::new();
+    for c in expression.chars() {
+        let value = INPUT_CHARSET.find(c)? as u64;
+        symbols.push(value & 31);
+        groups.push(value >> 5);
+        if groups.len() == 3 {
+            symbols.push(groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups[..] {
+        [a] => symbols.push(a),
+        [a, b] => symbols.push(a * 3 + b),
+        _ => {}
+    }
+    let checksum = polymod(symbols.into_iter().chain([0; 8])) ^ 1;
+    Some(
+        (0..8)
+            .map(|i| CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+// Where an expression is, which decides what it may be and what keys it takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Context {
+    Top,
+    Sh,
+    Wsh,
+}
+
+// name(args), split at the first parenthesis.
+fn split_call(expression: &str) -> Option<(&str, &str)> {
+    let (name, rest) = expression.split_once('(')?;
+    Some((name, rest.strip_suffix(')')?))
+}
+
+// The encoding of a key, which must be compressed under segwit.
+fn parse_key(text: &str, context: Context) -> Result<Vec<u8>, Errors> {
+    let key = match text.strip_prefix('[') {
+        Some(rest) => {
+            rest.split_once(']')
+                .ok_or_else(|| invalid(format!("key origin not closed in {text}")))?
+                .1
+        }
+        None => text,
+    };
+    let bytes = hex::decode(key)
+        .ok()
+        .filter(|bytes| S256Point::parse_sec(bytes).is_ok())
+        .ok_or_else(|| invalid(format!("key '{key}' is not a hex public key")))?;
+    if context == Context::Wsh && bytes.len() != 33 {
+        return Err(invalid(format!("key '{key}' must be compressed in wsh()")));
+    }
+    Ok(bytes)
+}
+
+fn p2pk(key: &[u8]) -> Script {
+    let mut script = Script::new();
+    script.push_slice(key).push_opcode(Opcode::OP_CHECKSIG);
+    script
+}
+
+fn p2wpkh(key: &[u8]) -> Result<Script, Errors> {
+    if key.len() != 33 {
+        return Err(invalid(format!(
+            "key '{}' must be compressed in wpkh()",
+            hex::encode(key)
+        )));
+    }
+    Ok(Script::new_p2wpkh(&hash160(key)))
+}
+
+fn multisig(args: &str, sorted: bool, context: Context) -> Result<Script, Errors> {
+    let mut args = args.split(',');
+    let required = args
+        .next()
+        .and_then(|required| required.parse().ok())
+        .ok_or_else(|| invalid("multisig threshold is not a number".to_string()))?;
+    let keys = args
+        .map(|key| parse_key(key, context))
+        .collect::<Result<Vec<_>, _>>()?;
+    let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+    Script::multisig(required, &keys, sorted).map_err(|e| invalid(e.to_string()))
+}
+
+// The scripts of expression in context: for sh and wsh those they wrap.
+fn parse_scripts(
+    expression: &str,
+    context: Context,
+    network: Network,
+) -> Result<Vec<Script>, Errors> {
+    let (name, args) = split_call(expression)
+        .ok_or_else(|| invalid(format!("'{expression}' is not a descriptor")))?;
+    let scripts = match (name, context) {
+        ("addr", Context::Top) => {
+            let script = script_from_address(args, network)
+                .ok_or_else(|| invalid(format!("address '{args}' is not valid")))?;
+            vec![script.into()]
+        }
+        ("raw", Context::Top) => {
+            let script = hex::decode(args)
+                .map_err(|_| invalid(format!("raw script '{args}' is not hex")))?;
+            vec![script.into()]
+        }
+        ("pk", _) => vec![p2pk(&parse_key(args, context)?)],
+        ("pkh", _) => vec![Script::new_p2pkh(&hash160(&parse_key(args, context)?))],
+        ("wpkh", Context::Top | Context::Sh) => vec![p2wpkh(&parse_key(args, context)?)?],
+        ("combo", Context::Top) => {
+            let key = parse_key(args, context)?;
+            let mut scripts = vec![p2pk(&key), Script::new_p2pkh(&hash160(&key))];
+            if key.len() == 33 {
+                let witness = p2wpkh(&key)?;
+                scripts.push(witness.clone());
+                scripts.push(witness.to_p2sh());
+            }
+            scripts
+        }
+        ("multi", _) => vec![multisig(args, false, context)?],
+        ("sortedmulti", _) => vec![multisig(args, true, context)?],
+        ("sh", Context::Top) => {
+            let redeem = parse_scripts(args, Context::Sh, network)?;
+            if redeem
+                .iter()
+                .any(|script| script.len() > MAX_SCRIPT_ELEMENT_SIZE)
+            {
+                return Err(invalid(format!(
+                    "redeem script of {args} is over {MAX_SCRIPT_ELEMENT_SIZE} bytes"
+                )));
+            }
+            redeem.iter().map(Script::to_p2sh).collect()
+        }
+        ("wsh", Context::Top | Context::Sh) => parse_scripts(args, Context::Wsh, network)?
+            .iter()
+            .map(Script::to_p2wsh)
+            .collect(),
+        ("tr", Context::Top) => {
+            if args.contains(',') {
+                return Err(invalid("tr() script trees are not supported".to_string()));
+            }
+            let key = args.rsplit(']').next().unwrap();
+            let bytes = hex::decode(key).map_err(|_| invalid(format!("key '{key}' is not hex")))?;
+            let internal = match bytes.len() {
+                32 => S256Point::lift_x(&bytes),
+                _ => S256Point::parse_sec(&bytes),
+            }
+            .map_err(|_| invalid(format!("key '{key}' is not a public key")))?;
+            let output = internal
+                .tap_tweak(None)
+                .map_err(|e| invalid(e.to_string()))?;
+            vec![Script::new_p2tr(&output.xonly())]
+        }
+        (_, Context::Wsh) => {
+            let miniscript: Miniscript = expression.parse()?;
+            if !miniscript.is_valid_top_level() {
+                return Err(invalid(format!(
+                    "miniscript '{expression}' is not valid at the top level"
+                )));
+            }
+            vec![miniscript.encode()]
+        }
+        (name, _) => return Err(invalid(format!("{name}() cannot be used here"))),
+    };
+    Ok(scripts)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Descriptor {
+    // As given, without its checksum.
+    expression: String,
+    script_pubkeys: Vec<Script>,
+}
+
+impl Descriptor {
+    // Addresses are decoded for network.
+    pub fn parse(text: &str, network: Network) -> Result<Self, Errors> {
+        let text = text.trim();
+        let (expression, checksum) = match text.split_once('#') {
+            Some((expression, checksum)) => (expression, Some(checksum)),
+            None => (text, None),
+        };
+        let expected = descriptor_checksum(expression)
+            .ok_or_else(|| invalid(format!("'{expression}' has invalid characters")))?;
+        if let Some(checksum) = checksum {
+            if checksum != expected {
+                return Err(invalid(format!(
+                    "checksum '{checksum}' does not match, expected '{expected}'"
+                )));
+            }
+        }
+        Ok(Descriptor {
+            expression: expression.to_string(),
+            script_pubkeys: parse_scripts(expression, Context::Top, network)?,
+        })
+    }
+
+    // Those combo() expands to, or else the single one of the descriptor.
+    pub fn script_pubkeys(&self) -> &[Script] {
+        &self.script_pubkeys
+    }
+}
+
+// With its checksum.
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let checksum = descriptor_checksum(&self.expression).unwrap();
+        write!(f, "{}#{checksum}", self.expression)
+    }
+}
+
+#[cfg(test)]
+mod descriptor_tests {
+    use super::*;
+    use crate::script::ScriptType;
+
+    const KEY: &str = "03a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd";
+
+    #[test]
+    fn test_checksums() {
+        // From BIP380.
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        let descriptor = Descriptor::parse("raw(deadbeef)#89f8spxm", Network::Mainnet).unwrap();
+        assert_eq!(descriptor.to_string(), "raw(deadbeef)#89f8spxm");
+        assert_eq!(
+            descriptor.script_pubkeys()[0].as_bytes(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert!(Descriptor::parse("raw(deadbeef)#89f8spxn", Network::Mainnet).is_err());
+    }
+
+    #[test]
+    fn test_scripts() {
+        let parse = |text: &str| Descriptor::parse(text, Network::Mainnet);
+        let key = hex::decode(KEY).unwrap();
+        let hash = hash160(&key);
+        let scripts = |text: &str| parse(text).unwrap().script_pubkeys().to_vec();
+        assert_eq!(
+            scripts(&format!("pkh([d34db33f/44'/0'/0']{KEY})")),
+            [Script::new_p2pkh(&hash)]
+        );
+        let wpkh = Script::new_p2wpkh(&hash);
+        assert_eq!(scripts(&format!("sh(wpkh({KEY}))")), [wpkh.to_p2sh()]);
+        assert_eq!(scripts(&format!("combo({KEY})")).len(), 4);
+        let multi = Script::multisig(1, &[&key], false).unwrap();
+        assert_eq!(scripts(&format!("wsh(multi(1,{KEY}))")), [multi.to_p2wsh()]);
+        assert_eq!(
+            scripts(&format!("sh(wsh(multi(1,{KEY})))")),
+            [multi.to_p2wsh().to_p2sh()]
+        );
+        let miniscript = scripts(&format!("wsh(and_v(v:pk({KEY}),older(144)))"));
+        assert!(matches!(
+            miniscript[0].classify(),
+            ScriptType::WitnessV0ScriptHash(_)
+        ));
+        let address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        let script: Script = script_from_address(address, Network::Mainnet)
+            .unwrap()
+            .into();
+        assert_eq!(scripts(&format!("addr({address})")), [script]);
+        assert!(matches!(
+            scripts(&format!("tr({})", &KEY[2..]))[0].classify(),
+            ScriptType::WitnessV1Taproot(_)
+        ));
+
+        assert!(parse(&format!("wpkh(sh({KEY}))")).is_err());
+        assert!(parse(&format!("wsh(wpkh({KEY}))")).is_err());
+        assert!(parse("pkh(xpub661MyMwAqRbcF)").is_err());
+        assert!(parse(&format!("tr({KEY},pk({KEY}))")).is_err());
+    }
+}
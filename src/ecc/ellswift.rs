@@ -0,0 +1,235 @@
+// ElligatorSwift (BIP324): 64-byte encodings of x coordinates indistinguishable from
+// random bytes, so a key exchange looks like noise on the wire. An encoding (u, t)
+// of two field elements decodes to the x coordinate F(u, t), and each x has many
+// encodings, one picked at random by encode.
+use super::keys::PrivateKey;
+use super::{from_bytes, inverse, modulo, p, to_32_bytes, S256Point};
+use crate::helper::{sha256, tagged_hash};
+use crate::types::errors::Errors;
+use num_bigint::BigInt;
+use std::sync::OnceLock;
+
+fn fe(a: BigInt) -> BigInt {
+    modulo(&a, p())
+}
+
+fn div(a: &BigInt, b: &BigInt) -> BigInt {
+    fe(a * inverse(b, p()))
+}
+
+// P % 4 == 3, so a square root is a^((P + 1) / 4) when there is one.
+fn sqrt(a: &BigInt) -> Option<BigInt> {
+    let p = p();
+    let root = a.modpow(&((p + 1u32) / 4u32), p);
+    (fe(&root * &root) == fe(a.clone())).then_some(root)
+}
+
+fn is_square(a: &BigInt) -> bool {
+    sqrt(a).is_some()
+}
+
+// x^3 + 7, the right hand side of the curve equation.
+fn curve(x: &BigInt) -> BigInt {
+    fe(x.pow(3) + 7)
+}
+
+fn sqrt_minus_3() -> &'static BigInt {
+    static ROOT: OnceLock<BigInt> = OnceLock::new();
+    ROOT.get_or_init(|| sqrt(&fe(BigInt::from(-3))).unwrap())
+}
+
+// The x coordinate an encoding decodes to, F(u, t) of the BIP.
+pub fn decode(encoding: &[u8; 64]) -> BigInt {
+    let zero = BigInt::from(0);
+    let mut u = fe(from_bytes(&encoding[..32]));
+    let mut t = fe(from_bytes(&encoding[32..]));
+    if u == zero {
+        u = BigInt::from(1);
+    }
+    if t == zero {
+        t = BigInt::from(1);
+    }
+    if fe(curve(&u) + &t * &t) == zero {
+        t = fe(t * 2);
+    }
+    let x_big = div(&fe(curve(&u) - &t * &t), &fe(&t * 2));
+    let y_big = div(&fe(&x_big + &t), &fe(sqrt_minus_3() * &u));
+    let candidate = fe(&u + 4 * &y_big * &y_big);
+    if is_square(&curve(&candidate)) {
+        return candidate;
+    }
+    let ratio = div(&x_big, &fe(&y_big * 2));
+    let half_u = div(&u, &BigInt::from(2));
+    let candidate = fe(-&ratio - &half_u);
+    if is_square(&curve(&candidate)) {
+        return candidate;
+    }
+    fe(ratio - half_u)
+}
+
+// The t for which F(u, t) = x in case c, one of eight, if there is one. G_{c,u}(x) of
+// the BIP.
+pub fn invert(x: &BigInt, u: &BigInt, case: u8) -> Option<BigInt> {
+    let zero = BigInt::from(0);
+    let (s, v) = if case & 2 == 0 {
+        if is_square(&curve(&fe(-u - x))) {
+            return None;
+        }
+        let s = div(&fe(-curve(u)), &fe(u * u + u * x + x * x));
+        (s, x.clone())
+    } else {
+        let s = fe(x - u);
+        let r = sqrt(&fe(-&s * (4 * curve(u) + 3 * &s * u * u)))?;
+        if (case & 1 == 1 && r == zero) || s == zero {
+            return None;
+        }
+        let v = div(&fe(div(&r, &s) - u), &BigInt::from(2));
+        (s, v)
+    };
+    let w = sqrt(&s)?;
+    let w = if case & 4 == 0 { w } else { fe(-w) };
+    let half = |a: BigInt| div(&a, &BigInt::from(2));
+    let t = if case & 1 == 0 {
+        &w * fe(half(sqrt_minus_3() - 1) * u - v)
+    } else {
+        &w * fe(half(sqrt_minus_3() + 1) * u + v)
+    };
+    Some(fe(t))
+}
+
+// An encoding of x picked by randomness, which must be secret and uniformly random
+// for the encoding to look so.
+pub fn encode(x: &BigInt, randomness: &[u8; 32]) -> [u8; 64] {
+    let zero = BigInt::from(0);
+    for counter in 0u32.. {
+        let mut seed = randomness.to_vec();
+        seed.extend(counter.to_le_bytes());
+        let hash = sha256(&seed);
+        let u = fe(from_bytes(&hash));
+        let case = sha256(&hash)[0] % 8;
+        if u == zero {
+            continue;
+        }
+        if let Some(t) = invert(x, &u, case) {
+            let mut result = [0u8; 64];
+            result[..32].copy_from_slice(&to_32_bytes(&u));
+            result[32..].copy_from_slice(&to_32_bytes(&t));
+            return result;
+        }
+    }
+    unreachable!()
+}
+
+// Encoding of the public key of secret.
+pub fn create(secret: &PrivateKey, randomness: &[u8; 32]) -> [u8; 64] {
+    encode(secret.public_key().x().unwrap(), randomness)
+}
+
+// BIP324 x-only ECDH of our secret with their encoded key, hashed along with both
+// encodings, the initiator's first.
+pub fn xdh(
+    secret: &PrivateKey,
+    ours: &[u8; 64],
+    theirs: &[u8; 64],
+    initiator: bool,
+) -> Result<[u8; 32], Errors> {
+    let point = S256Point::lift_x(&to_32_bytes(&decode(theirs)))?;
+    let shared = point.mul(secret.secret());
+    let x = shared.x().ok_or(Errors::InvalidPoint)?;
+    let (first, second) = if initiator {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+    let mut data = first.to_vec();
+    data.extend(second);
+    data.extend(to_32_bytes(x));
+    Ok(tagged_hash("bip324_ellswift_xonly_ecdh", &data))
+}
+
+#[cfg(test)]
+mod ellswift_tests {
+    use super::*;
+
+    fn array<const N: usize>(hex_str: &str) -> [u8; N] {
+        hex::decode(hex_str).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        // Vectors of libsecp256k1, the second with a u of P, which counts as 0.
+        for (encoding, x) in [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000000\
+                 0000000000000000000000000000000000000000000000000000000000000000",
+                "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000000\
+                 01d3475bf7655b0fb2d852921035b2ef607f49069b97454e6795251062741771",
+                "b5da00b73cd6560520e7c364086e7cd23a34bf60d0e707be9fc34d4cd5fdfa2c",
+            ),
+            (
+                "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f\
+                 0000000000000000000000000000000000000000000000000000000000000000",
+                "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c",
+            ),
+        ] {
+            assert_eq!(to_32_bytes(&decode(&array(encoding))), array::<32>(x));
+        }
+    }
+
+    #[test]
+    fn test_invert_and_encode() {
+        let u = from_bytes(&array::<32>(
+            "05ff6bdad900fc3261bc7fe34e2fb0f569f06e091ae437d3a52e9da0cbfb9590",
+        ));
+        let x = from_bytes(&array::<32>(
+            "80cdf63774ec7022c89a5a8558e373a279170285e0ab27412dbce510bdfe23fc",
+        ));
+        // Cases 0, 1, 4 and 5 have no t for this u and x.
+        let expected = [
+            None,
+            None,
+            Some("45654798ece071ba79286d04f7f3eb1c3f1d17dd883610f2ad2efd82a287466b"),
+            Some("0aeaa886f6b76c7158452418cbf5033adc5747e9e9b5d3b2303db96936528557"),
+            None,
+            None,
+            Some("ba9ab867131f8e4586d792fb080c14e3c0e2e82277c9ef0d52d1027c5d78b5c4"),
+            Some("f51557790948938ea7badbe7340afcc523a8b816164a2c4dcfc24695c9ad76d8"),
+        ];
+        for (case, t) in expected.iter().enumerate() {
+            assert_eq!(
+                invert(&x, &u, case as u8).map(|t| to_32_bytes(&t)),
+                t.map(array::<32>)
+            );
+        }
+
+        let secret = PrivateKey::new(BigInt::from(12345)).unwrap();
+        let encoding = create(&secret, &[7; 32]);
+        assert_ne!(encoding, create(&secret, &[8; 32]));
+        assert_eq!(&decode(&encoding), secret.public_key().x().unwrap());
+    }
+
+    #[test]
+    fn test_xdh() {
+        // First BIP324 vector, from the initiator's side.
+        let secret = PrivateKey::from_bytes(&array(
+            "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+        ))
+        .unwrap();
+        let ours = array(
+            "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa1\
+             86f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+        );
+        let theirs = array(
+            "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafa\
+             ffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+        );
+        assert_eq!(&decode(&ours), secret.public_key().x().unwrap());
+        assert_eq!(
+            xdh(&secret, &ours, &theirs, true).unwrap(),
+            array("c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592")
+        );
+    }
+}
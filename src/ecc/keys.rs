@@ -0,0 +1,184 @@
+// Private keys: ECDSA signing with RFC6979 nonces and BIP340 schnorr signing.
+use super::{from_bytes, inverse, modulo, n, to_32_bytes, S256Point, SchnorrSignature, Signature};
+use crate::helper::tagged_hash;
+use crate::types::errors::Errors;
+use hmac::{Hmac, Mac};
+use num_bigint::BigInt;
+use sha2::Sha256;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrivateKey {
+    secret: BigInt,
+    point: S256Point,
+}
+
+impl PrivateKey {
+    pub fn new(secret: BigInt) -> Result<Self, Errors> {
+        if secret < BigInt::from(1) || &secret >= n() {
+            return Err(Errors::InvalidPrivateKey);
+        }
+        let point = S256Point::generator().mul(&secret);
+        Ok(PrivateKey { secret, point })
+    }
+
+    pub fn from_bytes(bytes: &[u8; 32]) -> Result<Self, Errors> {
+        PrivateKey::new(from_bytes(bytes))
+    }
+
+    pub fn secret(&self) -> &BigInt {
+        &self.secret
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        to_32_bytes(&self.secret)
+    }
+
+    pub fn public_key(&self) -> &S256Point {
+        &self.point
+    }
+
+    // ECDSA signature of the message hash z. The s value is normalized to the lower
+    // half of the order, as required for standard bitcoin transactions.
+    pub fn sign(&self, z: &BigInt) -> Signature {
+        let n = n();
+        let k = self.deterministic_k(z);
+        let r = self
+            .point_x_mod_n(&k)
+            .expect("k is in [1, n) so k * G is never infinity");
+        let s = modulo(&((z + &r * &self.secret) * inverse(&k, n)), n);
+        let s = if s > n / 2u32 { n - s } else { s };
+        Signature::new(r, s)
+    }
+
+    fn point_x_mod_n(&self, k: &BigInt) -> Option<BigInt> {
+        S256Point::generator().mul(k).x().map(|x| modulo(x, n()))
+    }
+
+    // Nonce generation from RFC6979, so signing never depends on a random source.
+    fn deterministic_k(&self, z: &BigInt) -> BigInt {
+        let n = n();
+        let z = if z > n { z - n } else { z.clone() };
+        let z_bytes = to_32_bytes(&z);
+        let secret_bytes = self.secret_bytes();
+        let mut k = [0u8; 32];
+        let mut v = [1u8; 32];
+
+        k = hmac_sha256(&k, &[&v, &[0x00], &secret_bytes, &z_bytes]);
+        v = hmac_sha256(&k, &[&v]);
+        k = hmac_sha256(&k, &[&v, &[0x01], &secret_bytes, &z_bytes]);
+        v = hmac_sha256(&k, &[&v]);
+        loop {
+            v = hmac_sha256(&k, &[&v]);
+            let candidate = from_bytes(&v);
+            if candidate >= BigInt::from(1) && &candidate < n {
+                return candidate;
+            }
+            k = hmac_sha256(&k, &[&v, &[0x00]]);
+            v = hmac_sha256(&k, &[&v]);
+        }
+    }
+
+    // BIP340 signature. aux_rand is mixed into the nonce, all zeros is acceptable.
+    pub fn sign_schnorr(&self, msg: &[u8], aux_rand: &[u8; 32]) -> SchnorrSignature {
+        let n = n();
+        let d = if self.point.has_even_y() {
+            self.secret.clone()
+        } else {
+            n - &self.secret
+        };
+        let pubkey_x = self.point.xonly();
+
+        let aux_hash = tagged_hash("BIP0340/aux", aux_rand);
+        let mut t = to_32_bytes(&d);
+        for (byte, mask) in t.iter_mut().zip(aux_hash.iter()) {
+            *byte ^= mask;
+        }
+        let mut nonce_data = t.to_vec();
+        nonce_data.extend_from_slice(&pubkey_x);
+        nonce_data.extend_from_slice(msg);
+        let k0 = modulo(&from_bytes(&tagged_hash("BIP0340/nonce", &nonce_data)), n);
+        let big_r = S256Point::generator().mul(&k0);
+        let k = if big_r.has_even_y() { k0 } else { n - k0 };
+
+        let r_x = big_r.xonly();
+        let mut challenge = r_x.to_vec();
+        challenge.extend_from_slice(&pubkey_x);
+        challenge.extend_from_slice(msg);
+        let e = modulo(
+            &from_bytes(&tagged_hash("BIP0340/challenge", &challenge)),
+            n,
+        );
+
+        let mut result = [0u8; 64];
+        result[..32].copy_from_slice(&r_x);
+        result[32..].copy_from_slice(&to_32_bytes(&modulo(&(k + e * d), n)));
+        SchnorrSignature(result)
+    }
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod keys_tests {
+    use super::*;
+    use crate::helper::hash256;
+
+    #[test]
+    fn test_invalid_secret() {
+        assert_eq!(
+            PrivateKey::new(BigInt::from(0)),
+            Err(Errors::InvalidPrivateKey)
+        );
+        assert_eq!(PrivateKey::new(n().clone()), Err(Errors::InvalidPrivateKey));
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = PrivateKey::new(BigInt::from(12345)).unwrap();
+        let z = from_bytes(&hash256(b"Programming Bitcoin!"));
+        let sig = key.sign(&z);
+
+        assert!(key.public_key().verify(&z, &sig));
+        assert!(sig.s <= n() / 2u32);
+        // Deterministic nonces give the same signature every time.
+        assert_eq!(key.sign(&z), sig);
+    }
+
+    #[test]
+    fn test_schnorr_bip340_vectors() {
+        // Test vectors 0 and 1 from BIP340.
+        let key = PrivateKey::new(BigInt::from(3)).unwrap();
+        assert_eq!(
+            hex::encode(key.public_key().xonly()),
+            "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9"
+        );
+        assert_eq!(
+            hex::encode(key.sign_schnorr(&[0u8; 32], &[0u8; 32]).serialize()),
+            "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca821525f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0"
+        );
+
+        let secret: [u8; 32] =
+            hex::decode("b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let key = PrivateKey::from_bytes(&secret).unwrap();
+        let msg = hex::decode("243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89")
+            .unwrap();
+        let mut aux = [0u8; 32];
+        aux[31] = 1;
+        let sig = key.sign_schnorr(&msg, &aux);
+
+        assert_eq!(
+            hex::encode(sig.serialize()),
+            "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a"
+        );
+        assert!(sig.verify(&key.public_key().xonly(), &msg));
+    }
+}
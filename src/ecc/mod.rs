@@ -0,0 +1,398 @@
+// Elliptic curve arithmetic over secp256k1: y^2 = x^3 + 7 over the field of size P.
+pub mod ellswift;
+pub mod keys;
+pub mod signature;
+
+pub use keys::PrivateKey;
+pub use signature::{SchnorrSignature, Signature};
+
+use crate::helper::hash160;
+use crate::types::errors::Errors;
+use num_bigint::{BigInt, Sign};
+use std::ops::Add;
+use std::sync::OnceLock;
+
+const P_HEX: &str = "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f";
+const N_HEX: &str = "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+const GX_HEX: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const GY_HEX: &str = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+// Field prime.
+pub fn p() -> &'static BigInt {
+    static P: OnceLock<BigInt> = OnceLock::new();
+    P.get_or_init(|| parse_hex(P_HEX))
+}
+
+// Order of the group generated by G.
+pub fn n() -> &'static BigInt {
+    static N: OnceLock<BigInt> = OnceLock::new();
+    N.get_or_init(|| parse_hex(N_HEX))
+}
+
+fn parse_hex(hex_str: &str) -> BigInt {
+    BigInt::parse_bytes(hex_str.as_bytes(), 16).unwrap()
+}
+
+// Reduces into [0, modulus), also for negative numbers.
+pub fn modulo(a: &BigInt, modulus: &BigInt) -> BigInt {
+    let r = a % modulus;
+    if r.sign() == Sign::Minus {
+        r + modulus
+    } else {
+        r
+    }
+}
+
+// Modular inverse by Fermat's little theorem, the modulus must be prime.
+pub fn inverse(a: &BigInt, modulus: &BigInt) -> BigInt {
+    a.modpow(&(modulus - 2), modulus)
+}
+
+pub fn to_32_bytes(num: &BigInt) -> [u8; 32] {
+    let (_, bytes) = num.to_bytes_be();
+    let mut result = [0u8; 32];
+    result[32 - bytes.len()..].copy_from_slice(&bytes);
+    result
+}
+
+pub fn from_bytes(bytes: &[u8]) -> BigInt {
+    BigInt::from_bytes_be(Sign::Plus, bytes)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum S256Point {
+    Point(BigInt, BigInt),
+    Infinity,
+}
+
+impl S256Point {
+    pub fn new(x: BigInt, y: BigInt) -> Result<Self, Errors> {
+        let p = p();
+        if x.sign() == Sign::Minus || y.sign() == Sign::Minus || &x >= p || &y >= p {
+            return Err(Errors::InvalidPoint);
+        }
+        if modulo(&(&y * &y), p) != modulo(&(x.pow(3) + 7), p) {
+            return Err(Errors::InvalidPoint);
+        }
+        Ok(S256Point::Point(x, y))
+    }
+
+    pub fn generator() -> &'static S256Point {
+        static G: OnceLock<S256Point> = OnceLock::new();
+        G.get_or_init(|| S256Point::Point(parse_hex(GX_HEX), parse_hex(GY_HEX)))
+    }
+
+    pub fn is_infinity(&self) -> bool {
+        matches!(self, S256Point::Infinity)
+    }
+
+    pub fn x(&self) -> Option<&BigInt> {
+        match self {
+            S256Point::Point(x, _) => Some(x),
+            S256Point::Infinity => None,
+        }
+    }
+
+    pub fn has_even_y(&self) -> bool {
+        match self {
+            S256Point::Point(_, y) => !y.bit(0),
+            S256Point::Infinity => false,
+        }
+    }
+
+    pub fn negate(&self) -> S256Point {
+        match self {
+            S256Point::Point(x, y) => S256Point::Point(x.clone(), modulo(&-y, p())),
+            S256Point::Infinity => S256Point::Infinity,
+        }
+    }
+
+    // Scalar multiplication by double-and-add. Works in jacobian coordinates so only a
+    // single field inversion is needed at the end.
+    pub fn mul(&self, scalar: &BigInt) -> S256Point {
+        let scalar = modulo(scalar, n());
+        let (x, y) = match self {
+            S256Point::Point(x, y) => (x, y),
+            S256Point::Infinity => return S256Point::Infinity,
+        };
+        let base = Jacobian::from_affine(x, y);
+        let mut result = Jacobian::infinity();
+        for i in (0..scalar.bits()).rev() {
+            result = result.double();
+            if scalar.bit(i) {
+                result = result.add(&base);
+            }
+        }
+        result.to_affine()
+    }
+
+    // SEC serialization, 33 bytes compressed or 65 uncompressed.
+    pub fn sec(&self, compressed: bool) -> Vec<u8> {
+        let (x, y) = match self {
+            S256Point::Point(x, y) => (x, y),
+            S256Point::Infinity => return vec![0x00],
+        };
+        if compressed {
+            let prefix = if y.bit(0) { 0x03 } else { 0x02 };
+            let mut result = vec![prefix];
+            result.extend_from_slice(&to_32_bytes(x));
+            result
+        } else {
+            let mut result = vec![0x04];
+            result.extend_from_slice(&to_32_bytes(x));
+            result.extend_from_slice(&to_32_bytes(y));
+            result
+        }
+    }
+
+    pub fn parse_sec(bytes: &[u8]) -> Result<Self, Errors> {
+        match (bytes.first(), bytes.len()) {
+            (Some(0x04), 65) => S256Point::new(from_bytes(&bytes[1..33]), from_bytes(&bytes[33..])),
+            (Some(prefix @ (0x02 | 0x03)), 33) => {
+                let point = S256Point::lift_x(&bytes[1..33])?;
+                if point.has_even_y() == (*prefix == 0x02) {
+                    Ok(point)
+                } else {
+                    Ok(point.negate())
+                }
+            }
+            _ => Err(Errors::InvalidPoint),
+        }
+    }
+
+    // BIP340 x-only encoding, the point with even y is implied.
+    pub fn xonly(&self) -> [u8; 32] {
+        self.x().map(to_32_bytes).unwrap_or([0u8; 32])
+    }
+
+    // Point with the given x coordinate and even y.
+    pub fn lift_x(x_bytes: &[u8]) -> Result<Self, Errors> {
+        let p = p();
+        let x = from_bytes(x_bytes);
+        if &x >= p {
+            return Err(Errors::InvalidPoint);
+        }
+        let alpha = modulo(&(x.pow(3) + 7), p);
+        // P % 4 == 3, so a square root is alpha^((P + 1) / 4).
+        let beta = alpha.modpow(&((p + 1u32) / 4u32), p);
+        if modulo(&(&beta * &beta), p) != alpha {
+            return Err(Errors::InvalidPoint);
+        }
+        let y = if beta.bit(0) { p - &beta } else { beta };
+        Ok(S256Point::Point(x, y))
+    }
+
+    pub fn hash160(&self, compressed: bool) -> [u8; 20] {
+        hash160(&self.sec(compressed))
+    }
+
+    // ECDSA verification of z, the message hash interpreted as a number.
+    pub fn verify(&self, z: &BigInt, sig: &Signature) -> bool {
+        let n = n();
+        let zero = BigInt::from(0);
+        if sig.r <= zero || &sig.r >= n || sig.s <= zero || &sig.s >= n || self.is_infinity() {
+            return false;
+        }
+        let s_inv = inverse(&sig.s, n);
+        let u = modulo(&(z * &s_inv), n);
+        let v = modulo(&(&sig.r * &s_inv), n);
+        let total = S256Point::generator().mul(&u) + self.mul(&v);
+        match total.x() {
+            Some(x) => modulo(x, n) == sig.r,
+            None => false,
+        }
+    }
+}
+
+impl Add for S256Point {
+    type Output = S256Point;
+
+    fn add(self, other: S256Point) -> S256Point {
+        &self + &other
+    }
+}
+
+impl Add for &S256Point {
+    type Output = S256Point;
+
+    fn add(self, other: &S256Point) -> S256Point {
+        match (self, other) {
+            (S256Point::Infinity, _) => other.clone(),
+            (_, S256Point::Infinity) => self.clone(),
+            (S256Point::Point(x1, y1), S256Point::Point(x2, y2)) => Jacobian::from_affine(x1, y1)
+                .add(&Jacobian::from_affine(x2, y2))
+                .to_affine(),
+        }
+    }
+}
+
+// Point (X, Y, Z) representing the affine point (X / Z^2, Y / Z^3). Z == 0 is infinity.
+struct Jacobian {
+    x: BigInt,
+    y: BigInt,
+    z: BigInt,
+}
+
+impl Jacobian {
+    fn infinity() -> Self {
+        Jacobian {
+            x: BigInt::from(1),
+            y: BigInt::from(1),
+            z: BigInt::from(0),
+        }
+    }
+
+    fn from_affine(x: &BigInt, y: &BigInt) -> Self {
+        Jacobian {
+            x: x.clone(),
+            y: y.clone(),
+            z: BigInt::from(1),
+        }
+    }
+
+    fn is_infinity(&self) -> bool {
+        self.z.sign() == Sign::NoSign
+    }
+
+    fn double(&self) -> Self {
+        let p = p();
+        if self.is_infinity() || self.y.sign() == Sign::NoSign {
+            return Jacobian::infinity();
+        }
+        let ysq = modulo(&(&self.y * &self.y), p);
+        let s = modulo(&(4 * &self.x * &ysq), p);
+        let m = modulo(&(3 * &self.x * &self.x), p);
+        let x = modulo(&(&m * &m - 2 * &s), p);
+        let y = modulo(&(&m * (&s - &x) - 8 * &ysq * &ysq), p);
+        let z = modulo(&(2 * &self.y * &self.z), p);
+        Jacobian { x, y, z }
+    }
+
+    fn add(&self, other: &Jacobian) -> Self {
+        let p = p();
+        if self.is_infinity() {
+            return Jacobian {
+                x: other.x.clone(),
+                y: other.y.clone(),
+                z: other.z.clone(),
+            };
+        }
+        if other.is_infinity() {
+            return Jacobian {
+                x: self.x.clone(),
+                y: self.y.clone(),
+                z: self.z.clone(),
+            };
+        }
+        let z1z1 = modulo(&(&self.z * &self.z), p);
+        let z2z2 = modulo(&(&other.z * &other.z), p);
+        let u1 = modulo(&(&self.x * &z2z2), p);
+        let u2 = modulo(&(&other.x * &z1z1), p);
+        let s1 = modulo(&(&self.y * &other.z * &z2z2), p);
+        let s2 = modulo(&(&other.y * &self.z * &z1z1), p);
+        if u1 == u2 {
+            if s1 != s2 {
+                return Jacobian::infinity();
+            }
+            return self.double();
+        }
+        let h = modulo(&(&u2 - &u1), p);
+        let r = modulo(&(&s2 - &s1), p);
+        let h2 = modulo(&(&h * &h), p);
+        let h3 = modulo(&(&h * &h2), p);
+        let u1h2 = modulo(&(&u1 * &h2), p);
+        let x = modulo(&(&r * &r - &h3 - 2 * &u1h2), p);
+        let y = modulo(&(&r * (&u1h2 - &x) - &s1 * &h3), p);
+        let z = modulo(&(&h * &self.z * &other.z), p);
+        Jacobian { x, y, z }
+    }
+
+    fn to_affine(&self) -> S256Point {
+        if self.is_infinity() {
+            return S256Point::Infinity;
+        }
+        let p = p();
+        let z_inv = inverse(&self.z, p);
+        let z_inv2 = modulo(&(&z_inv * &z_inv), p);
+        let x = modulo(&(&self.x * &z_inv2), p);
+        let y = modulo(&(&self.y * &z_inv2 * &z_inv), p);
+        S256Point::Point(x, y)
+    }
+}
+
+#[cfg(test)]
+mod ecc_tests {
+    use super::*;
+
+    fn point(x: &str, y: &str) -> S256Point {
+        S256Point::new(parse_hex(x), parse_hex(y)).unwrap()
+    }
+
+    #[test]
+    fn test_generator_order() {
+        assert!(S256Point::generator().mul(n()).is_infinity());
+        assert_eq!(
+            &S256Point::generator().mul(&BigInt::from(1)),
+            S256Point::generator()
+        );
+    }
+
+    #[test]
+    fn test_add_matches_mul() {
+        let g = S256Point::generator();
+        let two_g = g + g;
+        assert_eq!(two_g, g.mul(&BigInt::from(2)));
+        assert_eq!(&two_g + g, g.mul(&BigInt::from(3)));
+        assert!((g + &g.negate()).is_infinity());
+    }
+
+    #[test]
+    fn test_invalid_point() {
+        assert_eq!(
+            S256Point::new(BigInt::from(1), BigInt::from(1)),
+            Err(Errors::InvalidPoint)
+        );
+    }
+
+    #[test]
+    fn test_public_point() {
+        // Exercise from chapter 3 of Programming Bitcoin.
+        let expected = point(
+            "027f3da1918455e03c46f659266a1bb5204e959db7364d2f473bdf8f0a13cc9d",
+            "ff87647fd023c13b4a4994f17691895806e1b40b57f4fd22581a4f46851f3b06",
+        );
+        assert_eq!(
+            S256Point::generator().mul(&BigInt::from(2018).pow(5)),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_sec_roundtrip() {
+        let point = S256Point::generator().mul(&BigInt::from(5001));
+        let compressed = point.sec(true);
+        let uncompressed = point.sec(false);
+
+        assert_eq!(
+            hex::encode(&compressed),
+            "0357a4f368868a8a6d572991e484e664810ff14c05c0fa023275251151fe0e53d1"
+        );
+        assert_eq!(S256Point::parse_sec(&compressed).unwrap(), point);
+        assert_eq!(S256Point::parse_sec(&uncompressed).unwrap(), point);
+    }
+
+    #[test]
+    fn test_verify() {
+        let point = point(
+            "887387e452b8eacc4acfde10d9aaf7f6d9a0f975aabb10d006e4da568744d06c",
+            "61de6d95231cd89026e286df3b6ae4a894a3378e393e93a0f45b666329a0ae34",
+        );
+        let z = parse_hex("ec208baa0fc1c19f708a9ca96fdeff3ac3f230bb4a7ba4aede4942ad003c0f60");
+        let sig = Signature::new(
+            parse_hex("ac8d1c87e51d0d441be8b3dd5b05c8795b48875dffe00b7ffcfac23010d3a395"),
+            parse_hex("68342ceff8935ededd102dd876ffd6ba72d6a427a3edb13d26eb0781cb423c4"),
+        );
+        assert!(point.verify(&z, &sig));
+        assert!(!point.verify(&(z + 1), &sig));
+    }
+}
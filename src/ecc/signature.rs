@@ -0,0 +1,202 @@
+// ECDSA signatures with DER encoding, and BIP340 schnorr signatures.
+use super::{from_bytes, n, to_32_bytes, S256Point};
+use crate::helper::tagged_hash;
+use crate::types::errors::Errors;
+use num_bigint::BigInt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub r: BigInt,
+    pub s: BigInt,
+}
+
+impl Signature {
+    pub fn new(r: BigInt, s: BigInt) -> Self {
+        Signature { r, s }
+    }
+
+    pub fn der(&self) -> Vec<u8> {
+        let mut body = der_integer(&self.r);
+        body.extend(der_integer(&self.s));
+        let mut result = vec![0x30, body.len() as u8];
+        result.extend(body);
+        result
+    }
+
+    pub fn parse_der(bytes: &[u8]) -> Result<Self, Errors> {
+        if bytes.len() < 8 || bytes[0] != 0x30 || bytes[1] as usize != bytes.len() - 2 {
+            return Err(Errors::InvalidSignature);
+        }
+        let (r, rest) = parse_der_integer(&bytes[2..])?;
+        let (s, rest) = parse_der_integer(rest)?;
+        if !rest.is_empty() {
+            return Err(Errors::InvalidSignature);
+        }
+        Ok(Signature { r, s })
+    }
+}
+
+// BIP66 strict DER check of a signature followed by its sighash byte, as done by
+// IsValidSignatureEncoding in Bitcoin Core. Stricter than parse_der: no padding
+// bytes, no negative numbers and consistent lengths everywhere.
+pub fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 || sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+    let valid_integer = |start: usize, len: usize| {
+        sig[start - 2] == 0x02
+            && len != 0
+            && sig[start] & 0x80 == 0
+            && !(len > 1 && sig[start] == 0x00 && sig[start + 1] & 0x80 == 0)
+    };
+    valid_integer(4, len_r) && valid_integer(len_r + 6, len_s)
+}
+
+// Big endian, without leading zeros, plus a zero byte if the high bit is set
+// so the number does not read as negative.
+fn der_integer(num: &BigInt) -> Vec<u8> {
+    let bytes = to_32_bytes(num);
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(31);
+    let mut value = bytes[start..].to_vec();
+    if value[0] & 0x80 != 0 {
+        value.insert(0, 0x00);
+    }
+    let mut result = vec![0x02, value.len() as u8];
+    result.extend(value);
+    result
+}
+
+fn parse_der_integer(bytes: &[u8]) -> Result<(BigInt, &[u8]), Errors> {
+    if bytes.len() < 2 || bytes[0] != 0x02 {
+        return Err(Errors::InvalidSignature);
+    }
+    let len = bytes[1] as usize;
+    let value = bytes.get(2..2 + len).ok_or(Errors::InvalidSignature)?;
+    if value.is_empty() || value.len() > 33 {
+        return Err(Errors::InvalidSignature);
+    }
+    Ok((from_bytes(value), &bytes[2 + len..]))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchnorrSignature(pub [u8; 64]);
+
+impl SchnorrSignature {
+    pub fn parse(bytes: &[u8]) -> Result<Self, Errors> {
+        let array: [u8; 64] = bytes.try_into().map_err(|_| Errors::InvalidSignature)?;
+        Ok(SchnorrSignature(array))
+    }
+
+    pub fn serialize(&self) -> [u8; 64] {
+        self.0
+    }
+
+    // BIP340 verification against an x-only public key.
+    pub fn verify(&self, pubkey_x: &[u8; 32], msg: &[u8]) -> bool {
+        let point = match S256Point::lift_x(pubkey_x) {
+            Ok(point) => point,
+            Err(_) => return false,
+        };
+        let r = &self.0[..32];
+        let s = from_bytes(&self.0[32..]);
+        if from_bytes(r) >= *super::p() || &s >= n() {
+            return false;
+        }
+        let mut challenge = r.to_vec();
+        challenge.extend_from_slice(pubkey_x);
+        challenge.extend_from_slice(msg);
+        let e = from_bytes(&tagged_hash("BIP0340/challenge", &challenge)) % n();
+        let big_r = S256Point::generator().mul(&s) + point.mul(&(n() - e));
+        !big_r.is_infinity() && big_r.has_even_y() && big_r.xonly() == r
+    }
+}
+
+#[cfg(test)]
+mod signature_tests {
+    use super::*;
+
+    #[test]
+    fn test_der() {
+        // Exercise from chapter 4 of Programming Bitcoin.
+        let sig = Signature::new(
+            BigInt::parse_bytes(
+                b"37206a0610995c58074999cb9767b87af4c4978db68c06e8e6e81d282047a7c6",
+                16,
+            )
+            .unwrap(),
+            BigInt::parse_bytes(
+                b"8ca63759c1157ebeaec0d03cecca119fc9a75bf8e6d0fa65c841c8e2738cdaec",
+                16,
+            )
+            .unwrap(),
+        );
+        let der = sig.der();
+
+        assert_eq!(
+            hex::encode(&der),
+            "3045022037206a0610995c58074999cb9767b87af4c4978db68c06e8e6e81d282047a7c60221008ca63759c1157ebeaec0d03cecca119fc9a75bf8e6d0fa65c841c8e2738cdaec"
+        );
+        assert_eq!(Signature::parse_der(&der).unwrap(), sig);
+    }
+
+    #[test]
+    fn test_parse_der_rejects_garbage() {
+        assert_eq!(
+            Signature::parse_der(&[0x30, 0x00]),
+            Err(Errors::InvalidSignature)
+        );
+        let mut der = Signature::new(BigInt::from(1), BigInt::from(2)).der();
+        der.push(0x00);
+        assert_eq!(Signature::parse_der(&der), Err(Errors::InvalidSignature));
+    }
+
+    #[test]
+    fn test_strict_der_encoding() {
+        let mut sig = hex::decode("3045022037206a0610995c58074999cb9767b87af4c4978db68c06e8e6e81d282047a7c60221008ca63759c1157ebeaec0d03cecca119fc9a75bf8e6d0fa65c841c8e2738cdaec").unwrap();
+        sig.push(0x01);
+        assert!(is_valid_signature_encoding(&sig));
+        // Without the sighash byte the lengths no longer add up.
+        assert!(!is_valid_signature_encoding(&sig[..sig.len() - 1]));
+
+        // r padded with a superfluous zero byte.
+        let mut padded = vec![0x30, 0x46, 0x02, 0x21, 0x00];
+        padded.extend_from_slice(&sig[4..]);
+        assert!(!is_valid_signature_encoding(&padded));
+
+        // s with its high bit set reads as negative.
+        let mut negative = sig.clone();
+        negative.remove(38);
+        negative[37] = 0x20;
+        negative[1] = 0x44;
+        assert!(!is_valid_signature_encoding(&negative));
+    }
+
+    #[test]
+    fn test_schnorr_verify_bip340_vector() {
+        // Test vector 1 from BIP340.
+        let pubkey: [u8; 32] =
+            hex::decode("dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let msg = hex::decode("243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89")
+            .unwrap();
+        let sig = SchnorrSignature::parse(
+            &hex::decode("6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a").unwrap(),
+        )
+        .unwrap();
+
+        assert!(sig.verify(&pubkey, &msg));
+        let mut other = msg.clone();
+        other[0] ^= 1;
+        assert!(!sig.verify(&pubkey, &other));
+    }
+}
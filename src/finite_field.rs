@@ -102,6 +102,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn two_field_elements_are_equal() {
         let num1 = 3;
         let num2 = 4;
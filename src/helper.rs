@@ -0,0 +1,315 @@
+// Hashing and encoding helpers shared by the serialization code.
+use crate::types::errors::Errors;
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+// Double sha256, used for txids, block hashes and signature hashes.
+pub fn hash256(data: &[u8]) -> [u8; 32] {
+    sha256(&sha256(data))
+}
+
+// ripemd160(sha256(data)), used for addresses and P2PKH/P2SH/P2WPKH scripts.
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(sha256(data)).into()
+}
+
+// BIP340 tagged hash: sha256(sha256(tag) || sha256(tag) || data).
+pub fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = sha256(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+// SipHash-2-4 keyed with (k0, k1), used for BIP152 short ids and BIP158 filters.
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+    // The last word holds the remaining bytes and the length of the data.
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    let m = u64::from_le_bytes(last) | ((data.len() as u64) << 56);
+    v[3] ^= m;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= m;
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+// MurmurHash3 (x86, 32 bit) seeded with seed, used by BIP37 bloom filters.
+pub fn murmur3_32(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let k = u32::from_le_bytes(chunk.try_into().unwrap())
+            .wrapping_mul(C1)
+            .rotate_left(15)
+            .wrapping_mul(C2);
+        h = (h ^ k)
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe6546b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k |= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+pub fn encode_varint(n: u64) -> Vec<u8> {
+    match n {
+        0..=0xfc => vec![n as u8],
+        0xfd..=0xffff => {
+            let mut result = vec![0xfd];
+            result.extend_from_slice(&(n as u16).to_le_bytes());
+            result
+        }
+        0x10000..=0xffff_ffff => {
+            let mut result = vec![0xfe];
+            result.extend_from_slice(&(n as u32).to_le_bytes());
+            result
+        }
+        _ => {
+            let mut result = vec![0xff];
+            result.extend_from_slice(&n.to_le_bytes());
+            result
+        }
+    }
+}
+
+pub fn read_varint(reader: &mut impl Read) -> Result<u64, Errors> {
+    let prefix = read_array::<1>(reader)?[0];
+    match prefix {
+        0xfd => Ok(u16::from_le_bytes(read_array(reader)?) as u64),
+        0xfe => Ok(u32::from_le_bytes(read_array(reader)?) as u64),
+        0xff => Ok(u64::from_le_bytes(read_array(reader)?)),
+        n => Ok(n as u64),
+    }
+}
+
+pub fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], Errors> {
+    let mut buffer = [0u8; N];
+    reader
+        .read_exact(&mut buffer)
+        .map_err(|_| Errors::UnexpectedEof)?;
+    Ok(buffer)
+}
+
+// Bytes from the operating system's random source, for keys and secrets.
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], Errors> {
+    let mut buffer = [0u8; N];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut buffer))
+        .map_err(|e| Errors::Io(format!("cannot read random bytes: {e}")))?;
+    Ok(buffer)
+}
+
+pub fn read_bytes(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, Errors> {
+    // Read in chunks so a bogus length prefix cannot make us allocate gigabytes up front.
+    let mut buffer = Vec::new();
+    let read = reader
+        .take(len as u64)
+        .read_to_end(&mut buffer)
+        .map_err(|_| Errors::UnexpectedEof)?;
+    if read != len {
+        return Err(Errors::UnexpectedEof);
+    }
+    Ok(buffer)
+}
+
+// Reads a varint length prefix followed by that many bytes.
+pub fn read_var_bytes(reader: &mut impl Read) -> Result<Vec<u8>, Errors> {
+    let len = read_varint(reader)?;
+    read_bytes(reader, len as usize)
+}
+
+pub fn encode_var_bytes(data: &[u8]) -> Vec<u8> {
+    let mut result = encode_varint(data.len() as u64);
+    result.extend_from_slice(data);
+    result
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Standard base64 with padding, as HTTP basic authentication uses it.
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut result = Vec::new();
+    for (index, chunk) in text.chunks(4).enumerate() {
+        let last = index == text.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, c) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        result.extend(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod helper_tests {
+    use super::*;
+
+    #[test]
+    fn test_random_bytes() {
+        let a: [u8; 32] = random_bytes().unwrap();
+        let b: [u8; 32] = random_bytes().unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_hash256() {
+        assert_eq!(
+            hex::encode(hash256(b"hello")),
+            "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("user:pass", "dXNlcjpwYXNz"),
+        ] {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded), Some(data.as_bytes().to_vec()));
+        }
+        assert_eq!(base64_decode("Zg="), None);
+        assert_eq!(base64_decode("Zg==Zm8="), None);
+        assert_eq!(base64_decode("Z!=="), None);
+    }
+
+    #[test]
+    fn test_siphash24() {
+        // Vectors from the SipHash paper, as in Bitcoin Core's hash tests.
+        let (k0, k1) = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        assert_eq!(siphash24(k0, k1, &[]), 0x726fdb47dd0e0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &data), 0xa129ca6149be45e5);
+        let data: Vec<u8> = (0..16).collect();
+        assert_eq!(siphash24(k0, k1, &data), 0x3f2acc7f57c29bdb);
+    }
+
+    #[test]
+    fn test_murmur3_32() {
+        // Vectors of Bitcoin Core's hash tests.
+        for (expected, seed, data) in [
+            (0x00000000, 0x00000000, ""),
+            (0x6a396f08, 0xfba4c795, ""),
+            (0x81f16f39, 0xffffffff, ""),
+            (0x514e28b7, 0x00000000, "00"),
+            (0xea3f0b17, 0xfba4c795, "00"),
+            (0xfd6cf10d, 0x00000000, "ff"),
+            (0x16c6b7ab, 0x00000000, "0011"),
+            (0x8eb51c3d, 0x00000000, "001122"),
+            (0xb4471bf8, 0x00000000, "00112233"),
+            (0xe2301fa8, 0x00000000, "0011223344"),
+        ] {
+            assert_eq!(murmur3_32(seed, &hex::decode(data).unwrap()), expected);
+        }
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for n in [
+            0u64,
+            0xfc,
+            0xfd,
+            0xffff,
+            0x10000,
+            0xffff_ffff,
+            0x1_0000_0000,
+        ] {
+            let encoded = encode_varint(n);
+            assert_eq!(read_varint(&mut encoded.as_slice()).unwrap(), n);
+        }
+        assert_eq!(encode_varint(0xfd), vec![0xfd, 0xfd, 0x00]);
+    }
+
+    #[test]
+    fn test_read_bytes_past_end() {
+        assert_eq!(
+            read_bytes(&mut [1u8, 2].as_slice(), 3),
+            Err(Errors::UnexpectedEof)
+        );
+    }
+}
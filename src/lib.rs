@@ -1 +1,25 @@
-pub mod types;
\ No newline at end of file
+pub mod address;
+pub mod block;
+pub mod chain;
+pub mod config;
+pub mod descriptor;
+pub mod ecc;
+pub mod helper;
+pub mod logging;
+pub mod mempool;
+pub mod metrics;
+pub mod mining;
+pub mod miniscript;
+pub mod network;
+pub mod node;
+pub mod notify;
+pub mod p2p;
+pub mod policy;
+pub mod rpc;
+pub mod script;
+pub mod taproot;
+#[cfg(test)]
+mod test_util;
+pub mod transaction;
+pub mod types;
+pub mod validation;
@@ -0,0 +1,202 @@
+// Logging of the node's subsystems through tracing. Events name their subsystem as
+// target and carry what they are about as fields, as in
+// tracing::warn!(target: "net", peer, "Disconnecting peer"). Each subsystem logs at its
+// own level, those without one at the default, and lines are written to stderr as
+// text or one JSON object each. Spans group the events of a piece of work, such as
+// connecting a block or serving a peer: their fields are added to the events logged
+// within them, across .await for the tasks instrumented with one. Records of the log
+// crate, as dependencies write them, are taken in through tracing-log.
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_log::LogTracer;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+// Targets the node logs to, as -debug=<subsystem> takes them.
+pub const SUBSYSTEMS: &[&str] = &[
+    "mempool",
+    "mining",
+    "net",
+    "node",
+    "rpc",
+    "validation",
+    "wallet",
+    "zmq",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// The subsystem of a target, the crate of those logged by dependencies.
+fn subsystem(target: &str) -> &str {
+    target.split("::").next().unwrap_or(target)
+}
+
+#[derive(Clone, Debug)]
+pub struct Logger {
+    default: LevelFilter,
+    levels: HashMap<String, LevelFilter>,
+    format: LogFormat,
+}
+
+impl Logger {
+    pub fn new(default: LevelFilter) -> Self {
+        Logger {
+            default,
+            levels: HashMap::new(),
+            format: LogFormat::Text,
+        }
+    }
+
+    pub fn with_level(mut self, subsystem: &str, level: LevelFilter) -> Self {
+        self.levels.insert(subsystem.to_string(), level);
+        self
+    }
+
+    // A level, or subsystem:level, as -loglevel takes them separated by commas.
+    pub fn with_levels(mut self, spec: &str) -> Result<Self, Errors> {
+        for item in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (subsystem, level) = match item.split_once(':') {
+                Some((subsystem, level)) => (Some(subsystem), level),
+                None => (None, item),
+            };
+            let level = level
+                .parse()
+                .map_err(|_| Errors::InvalidConfig(format!("unknown log level: {level}")))?;
+            match subsystem {
+                Some(subsystem) if !SUBSYSTEMS.contains(&subsystem) => {
+                    return Err(Errors::InvalidConfig(format!(
+                        "unknown log subsystem: {subsystem}"
+                    )));
+                }
+                Some(subsystem) => self = self.with_level(subsystem, level),
+                None => self.default = level,
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.levels
+            .get(subsystem(target))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    // A directive for the default level, and one for each subsystem's target.
+    fn filter(&self) -> EnvFilter {
+        self.levels.iter().fold(
+            EnvFilter::default().add_directive(self.default.into()),
+            |filter, (subsystem, level)| {
+                filter.add_directive(format!("{subsystem}={level}").parse().unwrap())
+            },
+        )
+    }
+
+    // Writes the events enabled to writer, a line each.
+    pub fn subscriber<W>(&self, writer: W) -> Box<dyn Subscriber + Send + Sync>
+    where
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(self.filter())
+            .with_writer(writer)
+            .with_ansi(false);
+        match self.format {
+            LogFormat::Text => Box::new(builder.finish()),
+            LogFormat::Json => Box::new(builder.json().with_span_list(false).finish()),
+        }
+    }
+
+    // Installs the logger for the process, which can only be done once.
+    pub fn init(self) -> Result<(), Errors> {
+        let error = |e: &dyn std::fmt::Display| {
+            Errors::InvalidConfig(format!("cannot install the logger: {e}"))
+        };
+        LogTracer::init().map_err(|e| error(&e))?;
+        tracing::subscriber::set_global_default(self.subscriber(std::io::stderr))
+            .map_err(|e| error(&e))
+    }
+}
+
+#[cfg(test)]
+mod logging_tests {
+    use super::*;
+    use serde_json::Value as Json;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::{debug, info_span, warn, Instrument};
+
+    // Lines written by a subscriber, kept for the test to read.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_levels() {
+        let logger = Logger::new(LevelFilter::INFO)
+            .with_levels("warn,net:debug")
+            .unwrap();
+        assert_eq!(logger.level("net"), LevelFilter::DEBUG);
+        assert_eq!(logger.level("mempool"), LevelFilter::WARN);
+        assert_eq!(logger.level("ureq::unit"), LevelFilter::WARN);
+        assert!(logger.clone().with_levels("disk:debug").is_err());
+        assert!(logger.clone().with_levels("loud").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_json_events_in_spans() {
+        let logger = Logger::new(LevelFilter::INFO)
+            .with_levels("warn,net:debug")
+            .unwrap()
+            .with_format(LogFormat::Json);
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let _default = tracing::subscriber::set_default(logger.subscriber(move || writer.clone()));
+
+        // The span's fields reach the event logged after the task yields.
+        async {
+            tokio::task::yield_now().await;
+            debug!(target: "mempool", "Below the mempool's level");
+            warn!(target: "net", command = "inv", "Disconnecting peer");
+        }
+        .instrument(info_span!(target: "net", "receive", peer = 7))
+        .await;
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let json: Json = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["target"], "net");
+        assert_eq!(json["fields"]["message"], "Disconnecting peer");
+        assert_eq!(json["fields"]["command"], "inv");
+        assert_eq!(json["span"]["name"], "receive");
+        assert_eq!(json["span"]["peer"], 7);
+    }
+}
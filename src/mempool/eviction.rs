@@ -0,0 +1,225 @@
+// Keeping the pool within its size, as Bitcoin Core's TrimToSize and Expire do. Past
+// the limit, the transaction with the lowest descendant score goes with its
+// descendants, and the feerate of what went, raised by the incremental relay fee,
+// becomes the minimum for new transactions. That minimum halves every twelve hours
+// once a block has been connected, faster while the pool is far from full.
+// Transactions older than the expiry go whatever the size.
+use super::Mempool;
+use crate::policy::FeeRate;
+use crate::transaction::Transaction;
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::{debug, info};
+
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 300_000_000;
+pub const DEFAULT_MEMPOOL_EXPIRY: Duration = Duration::from_secs(336 * 60 * 60);
+pub const ROLLING_FEE_HALFLIFE: u64 = 12 * 60 * 60;
+
+impl Mempool {
+    pub fn with_max_size(mut self, bytes: usize) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    // Bytes of the transactions held, standing for Bitcoin Core's memory usage.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // The higher of a transaction's own feerate and that of it with its
+    // descendants, so a parent paid for by its children stays. Fees are modified by
+    // their deltas.
+    fn descendant_score(&self, txid: &[u8; 32]) -> FeeRate {
+        let entry = &self.entries[txid];
+        let own_fee = self.modified_fee(txid, entry.fee);
+        let (fee, vsize) = self
+            .descendants(txid)
+            .iter()
+            .map(|descendant| (descendant, &self.entries[descendant]))
+            .fold(
+                (own_fee, entry.vsize),
+                |(fee, vsize), (descendant, other)| {
+                    (
+                        fee + self.modified_fee(descendant, other.fee),
+                        vsize + other.vsize,
+                    )
+                },
+            );
+        FeeRate::from_fee_and_vsize(own_fee, entry.vsize)
+            .max(FeeRate::from_fee_and_vsize(fee, vsize))
+    }
+
+    // Evicts packages of lowest descendant score until the pool fits max_size,
+    // returning the transactions evicted.
+    pub fn trim_to_size(&mut self) -> Vec<Transaction> {
+        let mut removed = Vec::new();
+        while self.size() > self.max_size {
+            let Some((txid, score)) = self
+                .entries
+                .keys()
+                .map(|txid| (*txid, self.descendant_score(txid)))
+                .min_by_key(|(txid, score)| (*score, *txid))
+            else {
+                break;
+            };
+            let floor = score.sat_per_kvb() + self.incremental_relay_feerate.sat_per_kvb();
+            if floor as f64 > self.rolling_min_feerate {
+                self.rolling_min_feerate = floor as f64;
+                self.block_since_bump = false;
+            }
+            removed.extend(self.remove(&txid));
+        }
+        if !removed.is_empty() {
+            info!(
+                target: "mempool",
+                evicted = removed.len(), size = self.size(),
+                min_feerate = self.rolling_min_feerate.round() as u64,
+                "Trimmed the mempool to its maximum size"
+            );
+        }
+        removed
+    }
+
+    // Evicts the transactions accepted more than the expiry before now, Unix time,
+    // with their descendants.
+    pub fn expire(&mut self, now: u64) -> Vec<Transaction> {
+        let cutoff = now.saturating_sub(self.expiry.as_secs());
+        let expired: HashSet<[u8; 32]> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.time < cutoff)
+            .map(|(txid, _)| *txid)
+            .collect();
+        // Ancestors are older, so removing those without an expired one takes the rest.
+        let roots: Vec<[u8; 32]> = expired
+            .iter()
+            .filter(|txid| {
+                !self
+                    .ancestors(txid)
+                    .iter()
+                    .any(|ancestor| expired.contains(ancestor))
+            })
+            .copied()
+            .collect();
+        let removed: Vec<Transaction> = roots.iter().flat_map(|txid| self.remove(txid)).collect();
+        if !removed.is_empty() {
+            debug!(target: "mempool", expired = removed.len(), "Expired transactions");
+        }
+        removed
+    }
+
+    // Lowest feerate accepted at now, Unix time: zero until the pool was trimmed,
+    // then decaying from the feerate evicted, and never below the incremental relay
+    // fee while above zero.
+    pub fn min_feerate(&mut self, now: u64) -> FeeRate {
+        if !self.block_since_bump || self.rolling_min_feerate == 0.0 {
+            return FeeRate::from_sat_per_kvb(self.rolling_min_feerate.round() as u64);
+        }
+        if now > self.last_rolling_update + 10 {
+            let mut halflife = ROLLING_FEE_HALFLIFE as f64;
+            let size = self.size();
+            if size < self.max_size / 4 {
+                halflife /= 4.0;
+            } else if size < self.max_size / 2 {
+                halflife /= 2.0;
+            }
+            let elapsed = (now - self.last_rolling_update) as f64;
+            self.rolling_min_feerate /= 2f64.powf(elapsed / halflife);
+            self.last_rolling_update = now;
+            let incremental = self.incremental_relay_feerate.sat_per_kvb() as f64;
+            if self.rolling_min_feerate < incremental / 2.0 {
+                self.rolling_min_feerate = 0.0;
+                return FeeRate::ZERO;
+            }
+        }
+        FeeRate::from_sat_per_kvb(self.rolling_min_feerate.round() as u64)
+            .max(self.incremental_relay_feerate)
+    }
+}
+
+#[cfg(test)]
+mod eviction_tests {
+    use super::super::mempool_tests::{accept, spend, utxos};
+    use super::super::now;
+    use super::*;
+    use crate::block::Block;
+    use crate::network::Network;
+    use crate::transaction::OutPoint;
+    use crate::types::errors::MempoolError;
+
+    fn coin(n: u8) -> OutPoint {
+        OutPoint::new([n; 32], 0)
+    }
+
+    #[test]
+    fn test_trim_by_descendant_score() {
+        let utxos = utxos();
+        let cheap = spend(&[coin(0)], 99_000, 0xffffffff);
+        let size = cheap.total_size();
+        let mut pool = Mempool::new().with_max_size(3 * size);
+        accept(&mut pool, &cheap, &utxos).unwrap();
+        // The child pays for its parent, which outscores cheap.
+        let parent = spend(&[coin(1)], 99_500, 0xffffffff);
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 80_000, 0xffffffff);
+        accept(&mut pool, &parent, &utxos).unwrap();
+        accept(&mut pool, &child, &utxos).unwrap();
+        assert_eq!(pool.len(), 3);
+
+        let rich = spend(&[coin(2)], 90_000, 0xffffffff);
+        accept(&mut pool, &rich, &utxos).unwrap();
+        assert!(!pool.contains(&cheap.txid()));
+        assert_eq!(pool.len(), 3);
+        // Below what was evicted, plus the increment, is refused now.
+        let min = pool.min_feerate(now());
+        let evicted = FeeRate::from_fee_and_vsize(1000, cheap.vsize());
+        assert_eq!(min.sat_per_kvb(), evicted.sat_per_kvb() + 1000);
+        let low = spend(&[coin(3)], 99_000, 0xffffffff);
+        assert_eq!(
+            accept(&mut pool, &low, &utxos),
+            Err(MempoolError::MempoolMinFee)
+        );
+    }
+
+    #[test]
+    fn test_rolling_minimum_decays() {
+        let utxos = utxos();
+        let tx = spend(&[coin(0)], 90_000, 0xffffffff);
+        let mut pool = Mempool::new().with_max_size(tx.total_size() - 1);
+        // Accepted, then trimmed at once.
+        assert_eq!(
+            accept(&mut pool, &tx, &utxos),
+            Err(MempoolError::MempoolFull)
+        );
+        let start = now();
+        let bumped = pool.min_feerate(start);
+        assert!(bumped > FeeRate::from_sat_per_kvb(1000));
+        // It holds until a block is connected, then halves every quarter of the
+        // half-life while the pool is nearly empty.
+        assert_eq!(pool.min_feerate(start + ROLLING_FEE_HALFLIFE), bumped);
+        pool.remove_for_block(&Block::new(Network::Regtest.genesis_header(), vec![]));
+        let decayed = pool.min_feerate(pool.last_rolling_update + ROLLING_FEE_HALFLIFE / 4);
+        assert!(decayed.sat_per_kvb().abs_diff(bumped.sat_per_kvb() / 2) <= 1);
+        assert_eq!(
+            pool.min_feerate(start + 4 * ROLLING_FEE_HALFLIFE),
+            FeeRate::ZERO
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        let utxos = utxos();
+        let mut pool = Mempool::new().with_expiry(Duration::from_secs(3600));
+        let parent = spend(&[coin(0)], 90_000, 0xffffffff);
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 80_000, 0xffffffff);
+        accept(&mut pool, &parent, &utxos).unwrap();
+        accept(&mut pool, &child, &utxos).unwrap();
+        assert!(pool.expire(now()).is_empty());
+        assert_eq!(pool.expire(now() + 3601), vec![parent, child]);
+        assert!(pool.is_empty());
+    }
+}
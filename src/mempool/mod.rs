@@ -0,0 +1,573 @@
+// Unconfirmed transactions waiting for a block. A transaction is accepted once it is
+// standard, valid on top of the chain and the transactions already in the pool, and
+// pays the minimum relay fee. One spending an output already spent in the pool
+// replaces the transactions it conflicts with under the BIP125 rules of the rbf
+// module, evicting them and their descendants in the same call. The package module
+// takes a child with the parents it pays for, and the eviction module keeps the pool
+// within its size and age limits. Fee deltas set with prioritise_transaction make a
+// transaction count as paying more, or less, than it does, for acceptance and
+// eviction. The persist module saves the pool to a file and takes it back in.
+pub mod eviction;
+pub mod package;
+pub mod persist;
+pub mod rbf;
+
+pub use eviction::{DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MEMPOOL_EXPIRY, ROLLING_FEE_HALFLIFE};
+pub use package::{check_package, MAX_PACKAGE_COUNT, MAX_PACKAGE_WEIGHT};
+pub use rbf::{signals_rbf, MAX_REPLACEMENT_CANDIDATES};
+
+use crate::block::Block;
+use crate::chain::{BlockStorage, ChainState, UtxoBackend};
+use crate::policy::{check_standard, FeeRate, DUST_RELAY_TX_FEE};
+use crate::script::flags::VerificationFlags;
+use crate::transaction::{txid_to_hex, OutPoint, Transaction};
+use crate::types::errors::MempoolError;
+use crate::validation::{validate_transaction, Coin, ScriptInterpreter, ScriptVerifier, UtxoView};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info_span;
+
+// As in Bitcoin Core, in sat/kvB.
+pub const DEFAULT_MIN_RELAY_TX_FEE: u64 = 1000;
+pub const DEFAULT_INCREMENTAL_RELAY_FEE: u64 = 1000;
+// Height given to the outputs of unconfirmed transactions.
+pub const MEMPOOL_HEIGHT: u32 = 0x7fffffff;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: u64,
+    pub vsize: usize,
+    // Unix time it was accepted.
+    pub time: u64,
+}
+
+impl MempoolEntry {
+    pub fn feerate(&self) -> FeeRate {
+        FeeRate::from_fee_and_vsize(self.fee, self.vsize)
+    }
+}
+
+// What accepting a transaction did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Accepted {
+    pub txid: [u8; 32],
+    pub fee: u64,
+    // Transactions evicted by the replacement, if it was one.
+    pub replaced: Vec<Transaction>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Mempool {
+    entries: HashMap<[u8; 32], MempoolEntry>,
+    // Txid of each wtxid.
+    wtxids: HashMap<[u8; 32], [u8; 32]>,
+    // The transaction of the pool spending each outpoint.
+    spent_by: HashMap<OutPoint, [u8; 32]>,
+    // Fee deltas by txid, kept for transactions yet to come too.
+    deltas: HashMap<[u8; 32], i64>,
+    min_relay_feerate: FeeRate,
+    incremental_relay_feerate: FeeRate,
+    dust_feerate: FeeRate,
+    // Conflicts are replaceable whether they signal or not.
+    full_rbf: bool,
+    // In bytes of transactions.
+    size: usize,
+    max_size: usize,
+    expiry: Duration,
+    // Minimum feerate set by trimming, in sat/kvB, and when it last decayed.
+    rolling_min_feerate: f64,
+    last_rolling_update: u64,
+    // Whether a block was connected since trimming raised the minimum, letting it
+    // decay.
+    block_since_bump: bool,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new()
+    }
+}
+
+// Outputs of the chain and of the pool.
+struct MempoolView<'a, V: UtxoView> {
+    pool: &'a Mempool,
+    chain: &'a V,
+}
+
+impl<V: UtxoView> UtxoView for MempoolView<'_, V> {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        match self.pool.entries.get(&outpoint.txid) {
+            Some(entry) => entry
+                .tx
+                .outputs
+                .get(outpoint.vout as usize)
+                .map(|output| Coin {
+                    output: output.clone(),
+                    height: MEMPOOL_HEIGHT,
+                    is_coinbase: false,
+                }),
+            None => self.chain.get_coin(outpoint),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            wtxids: HashMap::new(),
+            spent_by: HashMap::new(),
+            deltas: HashMap::new(),
+            min_relay_feerate: FeeRate::from_sat_per_kvb(DEFAULT_MIN_RELAY_TX_FEE),
+            incremental_relay_feerate: FeeRate::from_sat_per_kvb(DEFAULT_INCREMENTAL_RELAY_FEE),
+            dust_feerate: FeeRate::from_sat_per_kvb(DUST_RELAY_TX_FEE),
+            full_rbf: false,
+            size: 0,
+            max_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            expiry: DEFAULT_MEMPOOL_EXPIRY,
+            rolling_min_feerate: 0.0,
+            last_rolling_update: now(),
+            block_since_bump: false,
+        }
+    }
+
+    pub fn with_min_relay_feerate(mut self, feerate: FeeRate) -> Self {
+        self.min_relay_feerate = feerate;
+        self
+    }
+
+    pub fn with_incremental_relay_feerate(mut self, feerate: FeeRate) -> Self {
+        self.incremental_relay_feerate = feerate;
+        self
+    }
+
+    pub fn with_full_rbf(mut self, full_rbf: bool) -> Self {
+        self.full_rbf = full_rbf;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, txid: &[u8; 32]) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &[u8; 32]) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn get_by_wtxid(&self, wtxid: &[u8; 32]) -> Option<&MempoolEntry> {
+        self.entries.get(self.wtxids.get(wtxid)?)
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.tx)
+    }
+
+    // The transaction of the pool spending outpoint.
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<[u8; 32]> {
+        self.spent_by.get(outpoint).copied()
+    }
+
+    // Adds delta to the fee txid counts as paying, as Bitcoin Core's
+    // prioritisetransaction, whether it is in the pool or not. The total saturates
+    // rather than overflowing.
+    pub fn prioritise_transaction(&mut self, txid: [u8; 32], delta: i64) {
+        let total = self.deltas.entry(txid).or_default();
+        *total = total.saturating_add(delta);
+        if *total == 0 {
+            self.deltas.remove(&txid);
+        }
+    }
+
+    pub fn fee_delta(&self, txid: &[u8; 32]) -> i64 {
+        self.deltas.get(txid).copied().unwrap_or(0)
+    }
+
+    // Fee of txid with its delta added, never below zero.
+    pub fn modified_fee(&self, txid: &[u8; 32], fee: u64) -> u64 {
+        fee.saturating_add_signed(self.fee_delta(txid))
+    }
+
+    // Accepts tx on top of chain's tip if it could go in the next block, verifying its
+    // scripts with the standard flags. Signatures verified are kept in the chain's
+    // cache, for the block.
+    pub fn accept<B: UtxoBackend, S: BlockStorage>(
+        &mut self,
+        tx: Transaction,
+        chain: &ChainState<B, S>,
+    ) -> Result<Accepted, MempoolError> {
+        chain.check_final(&tx).map_err(MempoolError::Invalid)?;
+        chain
+            .check_sequence_locks(&tx)
+            .map_err(MempoolError::Invalid)?;
+        let verifier = ScriptInterpreter::new().with_signature_cache(chain.signature_cache(), true);
+        self.accept_with(
+            tx,
+            chain.utxos(),
+            chain.height() + 1,
+            VerificationFlags::STANDARD,
+            &verifier,
+        )
+    }
+
+    // As accept, against any view of the confirmed outputs, for transactions to go
+    // in a block at spend_height.
+    pub fn accept_with(
+        &mut self,
+        tx: Transaction,
+        utxos: &impl UtxoView,
+        spend_height: u32,
+        flags: VerificationFlags,
+        verifier: &impl ScriptVerifier,
+    ) -> Result<Accepted, MempoolError> {
+        let txid = tx.txid();
+        let _span = info_span!(target: "mempool", "accept", txid = txid_to_hex(&txid)).entered();
+        let (fee, conflicts) = self.check_transaction(&tx, utxos, spend_height, flags, verifier)?;
+        self.check_fee(self.modified_fee(&txid, fee), tx.vsize())?;
+        let evicted = if conflicts.is_empty() {
+            Vec::new()
+        } else {
+            rbf::check_replacement(self, &tx, fee, &conflicts)?
+        };
+
+        let replaced: Vec<MempoolEntry> = evicted
+            .iter()
+            .filter_map(|txid| self.remove_entry(txid))
+            .collect();
+        self.insert(tx, fee);
+        self.expire(now());
+        self.trim_to_size();
+        if !self.entries.contains_key(&txid) {
+            // Trimmed at once, so nothing is replaced after all.
+            self.restore(replaced, utxos);
+            return Err(MempoolError::MempoolFull);
+        }
+        Ok(Accepted {
+            txid,
+            fee,
+            replaced: replaced.into_iter().map(|entry| entry.tx).collect(),
+        })
+    }
+
+    // Checks tx is standard and valid on top of the pool and utxos, returning its fee
+    // and the transactions of the pool it conflicts with.
+    fn check_transaction(
+        &self,
+        tx: &Transaction,
+        utxos: &impl UtxoView,
+        spend_height: u32,
+        flags: VerificationFlags,
+        verifier: &impl ScriptVerifier,
+    ) -> Result<(u64, Vec<[u8; 32]>), MempoolError> {
+        if self.entries.contains_key(&tx.txid()) {
+            return Err(MempoolError::AlreadyInMempool);
+        }
+        check_standard(tx, self.dust_feerate).map_err(MempoolError::Policy)?;
+        let mut conflicts = Vec::new();
+        for input in &tx.inputs {
+            match self.spender(&input.previous_output) {
+                Some(conflict) if !conflicts.contains(&conflict) => conflicts.push(conflict),
+                _ => {}
+            }
+        }
+        if !self.full_rbf
+            && conflicts
+                .iter()
+                .any(|conflict| !signals_rbf(&self.entries[conflict].tx))
+        {
+            return Err(MempoolError::Conflict);
+        }
+
+        let view = MempoolView {
+            pool: self,
+            chain: utxos,
+        };
+        let fee = validate_transaction(tx, &view, spend_height, flags, verifier)
+            .map_err(MempoolError::Invalid)?;
+        Ok((fee, conflicts))
+    }
+
+    // Checks fee pays both the minimum relay feerate and the pool's for vsize.
+    fn check_fee(&mut self, fee: u64, vsize: usize) -> Result<(), MempoolError> {
+        if fee < self.min_relay_feerate.fee_for_vsize(vsize) {
+            return Err(MempoolError::MinRelayFee);
+        }
+        if fee < self.min_feerate(now()).fee_for_vsize(vsize) {
+            return Err(MempoolError::MempoolMinFee);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, tx: Transaction, fee: u64) {
+        self.insert_entry(MempoolEntry {
+            vsize: tx.vsize(),
+            tx,
+            fee,
+            time: now(),
+        });
+    }
+
+    fn insert_entry(&mut self, entry: MempoolEntry) {
+        let txid = entry.tx.txid();
+        for input in &entry.tx.inputs {
+            self.spent_by.insert(input.previous_output, txid);
+        }
+        self.size += entry.tx.total_size();
+        self.wtxids.insert(entry.tx.wtxid(), txid);
+        self.entries.insert(txid, entry);
+    }
+
+    // Puts back the entries evicted by a replacement that did not stay in the pool,
+    // but for those left spending outputs that went with the trimming.
+    fn restore(&mut self, entries: Vec<MempoolEntry>, utxos: &impl UtxoView) {
+        let txids: Vec<[u8; 32]> = entries.iter().map(|entry| entry.tx.txid()).collect();
+        for entry in entries {
+            self.insert_entry(entry);
+        }
+        for txid in txids {
+            let missing_inputs = self.entries.get(&txid).is_some_and(|entry| {
+                entry.tx.inputs.iter().any(|input| {
+                    !self.entries.contains_key(&input.previous_output.txid)
+                        && utxos.get_coin(&input.previous_output).is_none()
+                })
+            });
+            if missing_inputs {
+                self.remove(&txid);
+            }
+        }
+    }
+
+    // The transactions of the pool spending txid's outputs, and theirs, in the order
+    // found going down from txid.
+    pub fn descendants(&self, txid: &[u8; 32]) -> Vec<[u8; 32]> {
+        self.walk(txid, |pool, entry| {
+            (0..entry.tx.outputs.len() as u32)
+                .filter_map(|vout| pool.spender(&OutPoint::new(entry.tx.txid(), vout)))
+                .collect()
+        })
+    }
+
+    // The transactions of the pool txid spends outputs of, and theirs.
+    pub fn ancestors(&self, txid: &[u8; 32]) -> Vec<[u8; 32]> {
+        self.walk(txid, |pool, entry| {
+            entry
+                .tx
+                .inputs
+                .iter()
+                .map(|input| input.previous_output.txid)
+                .filter(|parent| pool.entries.contains_key(parent))
+                .collect()
+        })
+    }
+
+    fn walk(
+        &self,
+        txid: &[u8; 32],
+        next: impl Fn(&Mempool, &MempoolEntry) -> Vec<[u8; 32]>,
+    ) -> Vec<[u8; 32]> {
+        let mut seen = HashSet::from([*txid]);
+        let mut found = Vec::new();
+        let mut queue = VecDeque::from([*txid]);
+        while let Some(txid) = queue.pop_front() {
+            let Some(entry) = self.entries.get(&txid) else {
+                continue;
+            };
+            for other in next(self, entry) {
+                if seen.insert(other) {
+                    found.push(other);
+                    queue.push_back(other);
+                }
+            }
+        }
+        found
+    }
+
+    // Removes txid and its descendants, returning them.
+    pub fn remove(&mut self, txid: &[u8; 32]) -> Vec<Transaction> {
+        if !self.entries.contains_key(txid) {
+            return Vec::new();
+        }
+        let mut txids = vec![*txid];
+        txids.extend(self.descendants(txid));
+        txids
+            .iter()
+            .filter_map(|txid| self.remove_entry(txid))
+            .map(|entry| entry.tx)
+            .collect()
+    }
+
+    fn remove_entry(&mut self, txid: &[u8; 32]) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        self.size -= entry.tx.total_size();
+        self.wtxids.remove(&entry.tx.wtxid());
+        for input in &entry.tx.inputs {
+            if self.spent_by.get(&input.previous_output) == Some(txid) {
+                self.spent_by.remove(&input.previous_output);
+            }
+        }
+        Some(entry)
+    }
+
+    // Removes the transactions of a connected block, and those of the pool
+    // conflicting with them along with their descendants, returning the latter.
+    pub fn remove_for_block(&mut self, block: &Block) -> Vec<Transaction> {
+        self.block_since_bump = true;
+        self.last_rolling_update = now();
+        let mut conflicted = Vec::new();
+        for tx in &block.transactions {
+            self.remove_entry(&tx.txid());
+            self.deltas.remove(&tx.txid());
+            for input in &tx.inputs {
+                if let Some(conflict) = self.spender(&input.previous_output) {
+                    conflicted.extend(self.remove(&conflict));
+                }
+            }
+        }
+        conflicted
+    }
+}
+
+#[cfg(test)]
+mod mempool_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_util::{self, mature_chain, mine_on};
+    use crate::transaction::{TxIn, TxOut};
+    use crate::types::errors::ValidationError;
+    use crate::validation::{NoScriptVerification, COIN};
+
+    pub(super) fn p2wpkh() -> Vec<u8> {
+        let mut script = vec![0x00, 0x14];
+        script.extend_from_slice(&[0xab; 20]);
+        script
+    }
+
+    // Spends outpoints into one output of value, with a scriptSig of a realistic size.
+    pub(super) fn spend(outpoints: &[OutPoint], value: u64, sequence: u32) -> Transaction {
+        let mut script_sig = vec![72];
+        script_sig.extend_from_slice(&[0x30; 72]);
+        let inputs = outpoints
+            .iter()
+            .map(|outpoint| TxIn::new(*outpoint, script_sig.clone().into(), sequence))
+            .collect();
+        Transaction::new(2, inputs, vec![TxOut::new(value, p2wpkh().into())], 0)
+    }
+
+    // Confirmed coins of 100k sat each.
+    pub(super) fn utxos() -> HashMap<OutPoint, Coin> {
+        (0..4)
+            .map(|n| {
+                let coin = Coin {
+                    output: TxOut::new(100_000, p2wpkh().into()),
+                    height: 1,
+                    is_coinbase: false,
+                };
+                (OutPoint::new([n; 32], 0), coin)
+            })
+            .collect()
+    }
+
+    pub(super) fn accept(
+        pool: &mut Mempool,
+        tx: &Transaction,
+        utxos: &HashMap<OutPoint, Coin>,
+    ) -> Result<Accepted, MempoolError> {
+        pool.accept_with(
+            tx.clone(),
+            utxos,
+            10,
+            VerificationFlags::STANDARD,
+            &NoScriptVerification,
+        )
+    }
+
+    #[test]
+    fn test_accept_chains_of_transactions() {
+        let utxos = utxos();
+        let mut pool = Mempool::new();
+        let parent = spend(&[OutPoint::new([0; 32], 0)], 90_000, 0xffffffff);
+        let accepted = accept(&mut pool, &parent, &utxos).unwrap();
+        assert_eq!(accepted.fee, 10_000);
+        assert_eq!(
+            accept(&mut pool, &parent, &utxos),
+            Err(MempoolError::AlreadyInMempool)
+        );
+        // A child spends the parent's output from the pool.
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 80_000, 0xffffffff);
+        accept(&mut pool, &child, &utxos).unwrap();
+        assert_eq!(pool.ancestors(&child.txid()), vec![parent.txid()]);
+        assert_eq!(pool.descendants(&parent.txid()), vec![child.txid()]);
+
+        let free = spend(&[OutPoint::new([1; 32], 0)], 100_000, 0xffffffff);
+        assert_eq!(
+            accept(&mut pool, &free, &utxos),
+            Err(MempoolError::MinRelayFee)
+        );
+        let missing = spend(&[OutPoint::new([9; 32], 0)], 1000, 0xffffffff);
+        assert!(matches!(
+            accept(&mut pool, &missing, &utxos),
+            Err(MempoolError::Invalid(_))
+        ));
+
+        // Confirming the parent leaves the child, removing it takes nothing else.
+        let block = Block::new(Network::Regtest.genesis_header(), vec![parent.clone()]);
+        assert!(pool.remove_for_block(&block).is_empty());
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.remove(&child.txid()), vec![child]);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_block_conflicts_are_removed() {
+        let utxos = utxos();
+        let mut pool = Mempool::new();
+        let parent = spend(&[OutPoint::new([0; 32], 0)], 90_000, 0xffffffff);
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 80_000, 0xffffffff);
+        accept(&mut pool, &parent, &utxos).unwrap();
+        accept(&mut pool, &child, &utxos).unwrap();
+        // A block spending the same coin differently evicts both.
+        let other = spend(&[OutPoint::new([0; 32], 0)], 50_000, 0xffffffff);
+        let block = Block::new(Network::Regtest.genesis_header(), vec![other]);
+        let removed = pool.remove_for_block(&block);
+        assert_eq!(removed, vec![parent, child]);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_sequence_locks_at_tip() {
+        let (mut chain, coin) = mature_chain();
+        let funding = test_util::spend(&[coin], 49 * COIN, 0xffffffff);
+        let block = mine_on(&chain, chain.tip().hash, 0, vec![funding.clone()]);
+        chain.accept_block(block).unwrap();
+        // Spending the coin, which has one confirmation, ten blocks after it.
+        let mut locked = test_util::spend(&[OutPoint::new(funding.txid(), 0)], 48 * COIN, 10);
+        locked.outputs[0].script_pubkey = p2wpkh().into();
+        let mut pool = Mempool::new();
+        for _ in 0..9 {
+            assert!(matches!(
+                pool.accept(locked.clone(), &chain),
+                Err(MempoolError::Invalid(ValidationError::NonFinalTransaction(
+                    _
+                )))
+            ));
+            let block = mine_on(&chain, chain.tip().hash, 0, vec![]);
+            chain.accept_block(block).unwrap();
+        }
+        pool.accept(locked.clone(), &chain).unwrap();
+        assert!(pool.contains(&locked.txid()));
+    }
+}
@@ -0,0 +1,298 @@
+// Packages of a child with its unconfirmed parents, accepted together as Bitcoin
+// Core's AcceptPackage does, so a child can pay for parents paying too little to go
+// in alone, such as the zero-fee commitment transactions of Lightning. A package is
+// at most MAX_PACKAGE_COUNT transactions sorted parents first, ending in the child,
+// which spends each of the others. They are validated on top of the pool and of one
+// another, those already in the pool skipped; those paying less than the minimum
+// feerate alone must reach it with the child. Either all of them enter the pool or
+// none does, and a package replaces nothing of the pool.
+use super::{Accepted, Mempool, MEMPOOL_HEIGHT};
+use crate::chain::{BlockStorage, ChainState, UtxoBackend};
+use crate::script::flags::VerificationFlags;
+use crate::transaction::{txid_to_hex, OutPoint, Transaction};
+use crate::types::errors::MempoolError;
+use crate::validation::{Coin, ScriptInterpreter, ScriptVerifier, UtxoView};
+use std::collections::{HashMap, HashSet};
+use tracing::info_span;
+
+pub const MAX_PACKAGE_COUNT: usize = 25;
+// In weight units, 101 kvB.
+pub const MAX_PACKAGE_WEIGHT: usize = 404_000;
+
+// Outputs of the transactions of a package validated so far, then of chain.
+struct PackageView<'a, V: UtxoView> {
+    outputs: HashMap<OutPoint, Coin>,
+    chain: &'a V,
+}
+
+impl<V: UtxoView> PackageView<'_, V> {
+    fn add(&mut self, tx: &Transaction) {
+        let txid = tx.txid();
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            let coin = Coin {
+                output: output.clone(),
+                height: MEMPOOL_HEIGHT,
+                is_coinbase: false,
+            };
+            self.outputs.insert(OutPoint::new(txid, vout as u32), coin);
+        }
+    }
+}
+
+impl<V: UtxoView> UtxoView for PackageView<'_, V> {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        match self.outputs.get(outpoint) {
+            Some(coin) => Some(coin.clone()),
+            None => self.chain.get_coin(outpoint),
+        }
+    }
+}
+
+// Checks txs are a package: within the limits, sorted, without duplicates or two
+// spending the same output, and a child with its parents. Rejections are named after
+// Bitcoin Core's.
+pub fn check_package(txs: &[Transaction]) -> Result<(), MempoolError> {
+    if txs.len() > MAX_PACKAGE_COUNT {
+        return Err(MempoolError::InvalidPackage(
+            "package-too-many-transactions",
+        ));
+    }
+    if txs.iter().map(Transaction::weight).sum::<usize>() > MAX_PACKAGE_WEIGHT {
+        return Err(MempoolError::InvalidPackage("package-too-large"));
+    }
+    let txids: Vec<[u8; 32]> = txs.iter().map(Transaction::txid).collect();
+    if txids.iter().collect::<HashSet<_>>().len() != txids.len() {
+        return Err(MempoolError::InvalidPackage("package-contains-duplicates"));
+    }
+    // None spends a transaction after it.
+    for (i, tx) in txs.iter().enumerate() {
+        if tx
+            .inputs
+            .iter()
+            .any(|input| txids[i + 1..].contains(&input.previous_output.txid))
+        {
+            return Err(MempoolError::InvalidPackage("package-not-sorted"));
+        }
+    }
+    let mut spent = HashSet::new();
+    for input in txs.iter().flat_map(|tx| &tx.inputs) {
+        if !spent.insert(input.previous_output) {
+            return Err(MempoolError::InvalidPackage("conflict-in-package"));
+        }
+    }
+    let Some((child, parents)) = txs.split_last() else {
+        return Err(MempoolError::InvalidPackage(
+            "package-not-child-with-parents",
+        ));
+    };
+    let spends: HashSet<[u8; 32]> = child
+        .inputs
+        .iter()
+        .map(|input| input.previous_output.txid)
+        .collect();
+    if !parents.iter().all(|parent| spends.contains(&parent.txid())) {
+        return Err(MempoolError::InvalidPackage(
+            "package-not-child-with-parents",
+        ));
+    }
+    Ok(())
+}
+
+impl Mempool {
+    // Accepts the package txs on top of chain's tip, as accept does a transaction,
+    // returning what went in of it.
+    pub fn accept_package<B: UtxoBackend, S: BlockStorage>(
+        &mut self,
+        txs: Vec<Transaction>,
+        chain: &ChainState<B, S>,
+    ) -> Result<Vec<Accepted>, MempoolError> {
+        for tx in &txs {
+            chain.check_final(tx).map_err(MempoolError::Invalid)?;
+            chain
+                .check_sequence_locks(tx)
+                .map_err(MempoolError::Invalid)?;
+        }
+        let verifier = ScriptInterpreter::new().with_signature_cache(chain.signature_cache(), true);
+        self.accept_package_with(
+            txs,
+            chain.utxos(),
+            chain.height() + 1,
+            VerificationFlags::STANDARD,
+            &verifier,
+        )
+    }
+
+    // As accept_package, against any view of the confirmed outputs.
+    pub fn accept_package_with(
+        &mut self,
+        txs: Vec<Transaction>,
+        utxos: &impl UtxoView,
+        spend_height: u32,
+        flags: VerificationFlags,
+        verifier: &impl ScriptVerifier,
+    ) -> Result<Vec<Accepted>, MempoolError> {
+        check_package(&txs)?;
+        let child = txs
+            .last()
+            .map(|tx| txid_to_hex(&tx.txid()))
+            .unwrap_or_default();
+        let _span = info_span!(
+            target: "mempool",
+            "accept_package",
+            child,
+            transactions = txs.len()
+        )
+        .entered();
+        let txs: Vec<Transaction> = txs
+            .into_iter()
+            .filter(|tx| !self.contains(&tx.txid()))
+            .collect();
+        let mut view = PackageView {
+            outputs: HashMap::new(),
+            chain: utxos,
+        };
+        let mut fees = Vec::new();
+        for tx in &txs {
+            let (fee, conflicts) =
+                self.check_transaction(tx, &view, spend_height, flags, verifier)?;
+            if !conflicts.is_empty() {
+                return Err(MempoolError::Conflict);
+            }
+            view.add(tx);
+            fees.push(fee);
+        }
+        let Some(child) = txs.len().checked_sub(1) else {
+            return Ok(Vec::new());
+        };
+
+        // The child pays for those paying too little, a parent never pays for it.
+        let modified: Vec<u64> = txs
+            .iter()
+            .zip(&fees)
+            .map(|(tx, fee)| self.modified_fee(&tx.txid(), *fee))
+            .collect();
+        let short: Vec<usize> = (0..txs.len())
+            .filter(|&i| self.check_fee(modified[i], txs[i].vsize()).is_err())
+            .collect();
+        if !short.is_empty() {
+            let paid_for: HashSet<usize> = short.into_iter().chain([child]).collect();
+            let fee = paid_for.iter().map(|&i| modified[i]).sum();
+            let vsize = paid_for.iter().map(|&i| txs[i].vsize()).sum();
+            self.check_fee(fee, vsize)?;
+        }
+
+        let txids: Vec<[u8; 32]> = txs.iter().map(Transaction::txid).collect();
+        for (tx, fee) in txs.into_iter().zip(&fees) {
+            self.insert(tx, *fee);
+        }
+        self.expire(super::now());
+        self.trim_to_size();
+        if !txids.iter().all(|txid| self.contains(txid)) {
+            for txid in &txids {
+                self.remove(txid);
+            }
+            return Err(MempoolError::MempoolFull);
+        }
+        Ok(txids
+            .into_iter()
+            .zip(fees)
+            .map(|(txid, fee)| Accepted {
+                txid,
+                fee,
+                replaced: Vec::new(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod package_tests {
+    use super::super::mempool_tests::{accept, spend, utxos};
+    use super::*;
+    use crate::validation::NoScriptVerification;
+
+    const FINAL: u32 = 0xffffffff;
+
+    fn coin(n: u8) -> OutPoint {
+        OutPoint::new([n; 32], 0)
+    }
+
+    fn accept_package(
+        pool: &mut Mempool,
+        txs: &[Transaction],
+        utxos: &HashMap<OutPoint, Coin>,
+    ) -> Result<Vec<Accepted>, MempoolError> {
+        pool.accept_package_with(
+            txs.to_vec(),
+            utxos,
+            10,
+            VerificationFlags::STANDARD,
+            &NoScriptVerification,
+        )
+    }
+
+    #[test]
+    fn test_check_package() {
+        let parent = spend(&[coin(0)], 90_000, FINAL);
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 80_000, FINAL);
+        assert_eq!(check_package(&[parent.clone(), child.clone()]), Ok(()));
+        assert_eq!(
+            check_package(&[child.clone(), parent.clone()]),
+            Err(MempoolError::InvalidPackage("package-not-sorted"))
+        );
+        assert_eq!(
+            check_package(&[parent.clone(), parent.clone()]),
+            Err(MempoolError::InvalidPackage("package-contains-duplicates"))
+        );
+        let double_spend = spend(&[coin(0)], 70_000, FINAL);
+        assert_eq!(
+            check_package(&[parent.clone(), double_spend]),
+            Err(MempoolError::InvalidPackage("conflict-in-package"))
+        );
+        // The child must spend every other transaction of the package.
+        let unrelated = spend(&[coin(1)], 90_000, FINAL);
+        assert_eq!(
+            check_package(&[unrelated, parent, child]),
+            Err(MempoolError::InvalidPackage(
+                "package-not-child-with-parents"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let utxos = utxos();
+        let mut pool = Mempool::new();
+        let parent = spend(&[coin(0)], 100_000, FINAL);
+        assert_eq!(
+            accept(&mut pool, &parent, &utxos),
+            Err(MempoolError::MinRelayFee)
+        );
+        // Paying too little for both, then enough.
+        let stingy = spend(&[OutPoint::new(parent.txid(), 0)], 99_800, FINAL);
+        assert_eq!(
+            accept_package(&mut pool, &[parent.clone(), stingy], &utxos),
+            Err(MempoolError::MinRelayFee)
+        );
+        assert!(pool.is_empty());
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 90_000, FINAL);
+        let accepted = accept_package(&mut pool, &[parent.clone(), child.clone()], &utxos).unwrap();
+        assert_eq!(accepted.len(), 2);
+        assert_eq!((accepted[0].fee, accepted[1].fee), (0, 10_000));
+        assert_eq!(pool.ancestors(&child.txid()), vec![parent.txid()]);
+        // Already there, nothing more goes in.
+        assert_eq!(
+            accept_package(&mut pool, &[parent, child], &utxos),
+            Ok(Vec::new())
+        );
+
+        // A parent paying for itself doesn't pay for a child paying nothing.
+        let paying = spend(&[coin(1)], 90_000, FINAL);
+        let free = spend(&[OutPoint::new(paying.txid(), 0)], 90_000, FINAL);
+        assert_eq!(
+            accept_package(&mut pool, &[paying, free], &utxos),
+            Err(MempoolError::MinRelayFee)
+        );
+        assert_eq!(pool.len(), 2);
+    }
+}
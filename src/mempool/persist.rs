@@ -0,0 +1,239 @@
+// Keeping the pool across restarts, as Bitcoin Core's mempool.dat. Each transaction
+// is saved with the time it was accepted and its fee delta, parents before their
+// children, followed by the deltas of transactions not in the pool. Loading takes
+// them in again as if relayed, so those no longer valid on top of the chain are
+// dropped, keeping the times saved so they still expire when they would have. A
+// parent paying too little alone goes in as a package with the child paying for it.
+use super::{now, Accepted, Mempool};
+use crate::chain::{BlockStorage, ChainState, UtxoBackend};
+use crate::helper::{encode_varint, hash256, read_array, read_varint};
+use crate::script::flags::VerificationFlags;
+use crate::transaction::{txid_to_hex, Transaction};
+use crate::types::errors::{Errors, MempoolError};
+use crate::validation::{ScriptVerifier, UtxoView};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tracing::debug;
+
+impl Mempool {
+    // Writes the transactions and fee deltas to path, followed by a checksum of them.
+    // The file is replaced in a single rename.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Errors> {
+        let path = path.as_ref();
+        // Fewer ancestors in the pool first, which puts parents before children.
+        let mut txids: Vec<[u8; 32]> = self.entries.keys().copied().collect();
+        txids.sort_by_key(|txid| (self.ancestors(txid).len(), *txid));
+        let mut data = encode_varint(txids.len() as u64);
+        for txid in &txids {
+            let entry = &self.entries[txid];
+            data.extend(entry.tx.serialize());
+            data.extend(entry.time.to_le_bytes());
+            data.extend(self.fee_delta(txid).to_le_bytes());
+        }
+        let mut deltas: Vec<(&[u8; 32], &i64)> = self
+            .deltas
+            .iter()
+            .filter(|(txid, _)| !self.entries.contains_key(*txid))
+            .collect();
+        deltas.sort();
+        data.extend(encode_varint(deltas.len() as u64));
+        for (txid, delta) in deltas {
+            data.extend(txid);
+            data.extend(delta.to_le_bytes());
+        }
+        data.extend(hash256(&data));
+        let temp_path = path.with_extension("new");
+        fs::write(&temp_path, data)
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|e| Errors::Io(e.to_string()))
+    }
+
+    // Takes in the transactions saved with save on top of chain's tip, returning how
+    // many were accepted.
+    pub fn load<B: UtxoBackend, S: BlockStorage>(
+        &mut self,
+        path: impl AsRef<Path>,
+        chain: &ChainState<B, S>,
+    ) -> Result<usize, Errors> {
+        self.load_by(path, |pool, mut txs| match txs.len() {
+            1 => Ok(vec![pool.accept(txs.remove(0), chain)?]),
+            _ => pool.accept_package(txs, chain),
+        })
+    }
+
+    // As load, against any view of the confirmed outputs.
+    pub fn load_with(
+        &mut self,
+        path: impl AsRef<Path>,
+        utxos: &impl UtxoView,
+        spend_height: u32,
+        flags: VerificationFlags,
+        verifier: &impl ScriptVerifier,
+    ) -> Result<usize, Errors> {
+        self.load_by(path, |pool, mut txs| match txs.len() {
+            1 => Ok(vec![pool.accept_with(
+                txs.remove(0),
+                utxos,
+                spend_height,
+                flags,
+                verifier,
+            )?]),
+            _ => pool.accept_package_with(txs, utxos, spend_height, flags, verifier),
+        })
+    }
+
+    // As load, accepting a transaction alone or a child with its parents with accept.
+    fn load_by(
+        &mut self,
+        path: impl AsRef<Path>,
+        mut accept: impl FnMut(&mut Mempool, Vec<Transaction>) -> Result<Vec<Accepted>, MempoolError>,
+    ) -> Result<usize, Errors> {
+        let corrupted = || Errors::Io("mempool file is corrupted".to_string());
+        let mut data = fs::read(path.as_ref()).map_err(|e| Errors::Io(e.to_string()))?;
+        if data.len() < 34 {
+            return Err(corrupted());
+        }
+        let checksum = data.split_off(data.len() - 32);
+        if checksum != hash256(&data) {
+            return Err(corrupted());
+        }
+        let mut reader = data.as_slice();
+        let mut saved = Vec::new();
+        for _ in 0..read_varint(&mut reader)? {
+            let tx = Transaction::parse(&mut reader)?;
+            let time = u64::from_le_bytes(read_array(&mut reader)?);
+            let delta = i64::from_le_bytes(read_array(&mut reader)?);
+            saved.push((tx, time, delta));
+        }
+        for _ in 0..read_varint(&mut reader)? {
+            let txid = read_array(&mut reader)?;
+            self.prioritise_transaction(txid, i64::from_le_bytes(read_array(&mut reader)?));
+        }
+        if !reader.is_empty() {
+            return Err(corrupted());
+        }
+
+        let mut times = HashMap::new();
+        // Refused for their fee, for a child to pay for them.
+        let mut low_fee: HashMap<[u8; 32], (Transaction, MempoolError)> = HashMap::new();
+        let mut loaded = Vec::new();
+        for (tx, time, delta) in saved {
+            let txid = tx.txid();
+            times.insert(txid, time);
+            self.prioritise_transaction(txid, delta);
+            let mut txs: Vec<Transaction> = tx
+                .inputs
+                .iter()
+                .filter_map(|input| low_fee.remove(&input.previous_output.txid))
+                .map(|(tx, _)| tx)
+                .collect();
+            txs.push(tx.clone());
+            let alone = txs.len() == 1;
+            match accept(self, txs) {
+                Ok(accepted) => {
+                    for accepted in accepted {
+                        if let Some(entry) = self.entries.get_mut(&accepted.txid) {
+                            entry.time = times[&accepted.txid];
+                            loaded.push(accepted.txid);
+                        }
+                    }
+                }
+                Err(e @ (MempoolError::MinRelayFee | MempoolError::MempoolMinFee)) if alone => {
+                    low_fee.insert(txid, (tx, e));
+                }
+                Err(e) => {
+                    debug!(
+                        target: "mempool",
+                        txid = txid_to_hex(&txid), reason = %e,
+                        "Dropped saved transaction"
+                    );
+                }
+            }
+        }
+        // No child came to pay for these.
+        for (txid, (_, e)) in low_fee {
+            debug!(
+                target: "mempool",
+                txid = txid_to_hex(&txid), reason = %e,
+                "Dropped saved transaction"
+            );
+        }
+        // Those expired while the node was down go now.
+        self.expire(now());
+        Ok(loaded.iter().filter(|txid| self.contains(txid)).count())
+    }
+}
+
+#[cfg(test)]
+mod persist_tests {
+    use super::super::mempool_tests::{accept, spend, utxos};
+    use super::*;
+    use crate::test_util::temp_path;
+    use crate::transaction::OutPoint;
+    use crate::validation::NoScriptVerification;
+
+    #[test]
+    fn test_save_and_load() {
+        let utxos = utxos();
+        let mut pool = Mempool::new();
+        let parent = spend(&[OutPoint::new([0; 32], 0)], 90_000, 0xffffffff);
+        let child = spend(&[OutPoint::new(parent.txid(), 0)], 80_000, 0xffffffff);
+        accept(&mut pool, &parent, &utxos).unwrap();
+        accept(&mut pool, &child, &utxos).unwrap();
+        pool.entries.get_mut(&child.txid()).unwrap().time -= 60;
+        pool.prioritise_transaction(child.txid(), 5000);
+        // A delta for a transaction yet to come is kept too.
+        let free = spend(&[OutPoint::new([1; 32], 0)], 100_000, 0xffffffff);
+        pool.prioritise_transaction(free.txid(), 10_000);
+
+        let path = temp_path("mempool").with_extension("dat");
+        pool.save(&path).unwrap();
+        let mut loaded = Mempool::new();
+        let loaded_count = loaded
+            .load_with(
+                &path,
+                &utxos,
+                10,
+                VerificationFlags::STANDARD,
+                &NoScriptVerification,
+            )
+            .unwrap();
+        assert_eq!(loaded_count, 2);
+        assert_eq!(loaded.get(&parent.txid()), pool.get(&parent.txid()));
+        assert_eq!(loaded.get(&child.txid()), pool.get(&child.txid()));
+        assert_eq!(loaded.fee_delta(&child.txid()), 5000);
+        // It pays no fee, but the delta lets it in.
+        accept(&mut loaded, &free, &utxos).unwrap();
+
+        // What is no longer valid is dropped.
+        let mut spent = utxos.clone();
+        spent.remove(&OutPoint::new([0; 32], 0));
+        let mut revalidated = Mempool::new();
+        let revalidated_count = revalidated
+            .load_with(
+                &path,
+                &spent,
+                10,
+                VerificationFlags::STANDARD,
+                &NoScriptVerification,
+            )
+            .unwrap();
+        assert_eq!(revalidated_count, 0);
+        assert!(revalidated.is_empty());
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[10] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(Mempool::new()
+            .load_with(
+                &path,
+                &utxos,
+                10,
+                VerificationFlags::STANDARD,
+                &NoScriptVerification,
+            )
+            .is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+// BIP125 replacement, as Bitcoin Core's ReplacementChecks apply it. A transaction
+// conflicting with some of the pool replaces them if they signal it (or the pool
+// runs full RBF), it adds no unconfirmed input they didn't have, pays a higher
+// feerate than each of them, more fees than all they take with them plus relay for
+// itself, and evicts no more than MAX_REPLACEMENT_CANDIDATES transactions.
+use super::Mempool;
+use crate::policy::FeeRate;
+use crate::transaction::{txid_to_hex, Sequence, Transaction};
+use crate::types::errors::MempoolError;
+use std::collections::HashSet;
+
+pub const MAX_REPLACEMENT_CANDIDATES: usize = 100;
+
+// Whether tx opts in to replacement: one of its sequences is below 0xfffffffe.
+pub fn signals_rbf(tx: &Transaction) -> bool {
+    tx.inputs
+        .iter()
+        .any(|input| Sequence(input.sequence).signals_rbf())
+}
+
+// Checks tx, paying fee, may replace conflicts, the transactions of pool spending the
+// same outputs. Returns what it evicts: the conflicts and their descendants.
+pub(super) fn check_replacement(
+    pool: &Mempool,
+    tx: &Transaction,
+    fee: u64,
+    conflicts: &[[u8; 32]],
+) -> Result<Vec<[u8; 32]>, MempoolError> {
+    let txid = txid_to_hex(&tx.txid());
+    let mut evicted = Vec::new();
+    let mut seen = HashSet::new();
+    for conflict in conflicts {
+        for txid in std::iter::once(*conflict).chain(pool.descendants(conflict)) {
+            if seen.insert(txid) {
+                evicted.push(txid);
+            }
+        }
+    }
+    if evicted.len() > MAX_REPLACEMENT_CANDIDATES {
+        return Err(MempoolError::TooManyReplacements(evicted.len()));
+    }
+
+    // It can't build on what it replaces.
+    let parents: Vec<[u8; 32]> = tx
+        .inputs
+        .iter()
+        .map(|input| input.previous_output.txid)
+        .filter(|parent| pool.contains(parent))
+        .collect();
+    let spends_evicted = parents.iter().any(|parent| {
+        seen.contains(parent)
+            || pool
+                .ancestors(parent)
+                .iter()
+                .any(|ancestor| seen.contains(ancestor))
+    });
+    if spends_evicted {
+        return Err(MempoolError::SpendsConflictingTx);
+    }
+
+    let feerate = FeeRate::from_fee_and_vsize(fee, tx.vsize());
+    for conflict in conflicts {
+        let old = pool.get(conflict).unwrap().feerate();
+        if feerate <= old {
+            return Err(MempoolError::InsufficientFee(format!(
+                "rejecting replacement {txid}; new feerate {feerate} <= old feerate {old}"
+            )));
+        }
+    }
+
+    let conflict_parents: HashSet<[u8; 32]> = conflicts
+        .iter()
+        .flat_map(|conflict| &pool.get(conflict).unwrap().tx.inputs)
+        .map(|input| input.previous_output.txid)
+        .collect();
+    if parents
+        .iter()
+        .any(|parent| !conflict_parents.contains(parent))
+    {
+        return Err(MempoolError::ReplacementAddsUnconfirmed);
+    }
+
+    let replaced_fees: u64 = evicted.iter().map(|txid| pool.get(txid).unwrap().fee).sum();
+    if fee < replaced_fees {
+        return Err(MempoolError::InsufficientFee(format!(
+            "rejecting replacement {txid}, less fees than conflicting txs; {fee} < {replaced_fees}"
+        )));
+    }
+    let relay_fee = pool.incremental_relay_feerate.fee_for_vsize(tx.vsize());
+    if fee - replaced_fees < relay_fee {
+        return Err(MempoolError::InsufficientFee(format!(
+            "rejecting replacement {txid}, not enough additional fees to relay; {} < {relay_fee}",
+            fee - replaced_fees
+        )));
+    }
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod rbf_tests {
+    use super::super::mempool_tests::{accept, spend, utxos};
+    use super::*;
+    use crate::transaction::OutPoint;
+
+    const RBF: u32 = 0xfffffffd;
+    const FINAL: u32 = 0xffffffff;
+
+    fn coin(n: u8) -> OutPoint {
+        OutPoint::new([n; 32], 0)
+    }
+
+    #[test]
+    fn test_replacement_fees_and_signaling() {
+        let utxos = utxos();
+        let mut pool = Mempool::new();
+        let original = spend(&[coin(0)], 90_000, RBF);
+        let child = spend(&[OutPoint::new(original.txid(), 0)], 85_000, FINAL);
+        accept(&mut pool, &original, &utxos).unwrap();
+        accept(&mut pool, &child, &utxos).unwrap();
+
+        // Paying the replaced fees isn't enough, relaying itself must be paid too.
+        let same_fees = spend(&[coin(0)], 85_000, FINAL);
+        assert!(matches!(
+            accept(&mut pool, &same_fees, &utxos),
+            Err(MempoolError::InsufficientFee(_))
+        ));
+        let replacement = spend(&[coin(0)], 84_000, FINAL);
+        let accepted = accept(&mut pool, &replacement, &utxos).unwrap();
+        assert_eq!(accepted.replaced, vec![original.clone(), child]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.spender(&coin(0)), Some(replacement.txid()));
+
+        // The replacement doesn't signal, so it stays unless the pool runs full RBF.
+        let higher = spend(&[coin(0)], 70_000, RBF);
+        assert_eq!(
+            accept(&mut pool, &higher, &utxos),
+            Err(MempoolError::Conflict)
+        );
+        let mut full = Mempool::new().with_full_rbf(true);
+        accept(&mut full, &replacement, &utxos).unwrap();
+        assert_eq!(
+            accept(&mut full, &higher, &utxos).unwrap().replaced,
+            vec![replacement]
+        );
+    }
+
+    #[test]
+    fn test_trimmed_replacement_replaces_nothing() {
+        let utxos = utxos();
+        let original = spend(&[coin(0)], 99_000, RBF);
+        let rich = spend(&[coin(1)], 50_000, FINAL);
+        let max_size = original.total_size() + rich.total_size();
+        let mut pool = Mempool::new().with_max_size(max_size);
+        accept(&mut pool, &original, &utxos).unwrap();
+        accept(&mut pool, &rich, &utxos).unwrap();
+
+        // It pays more than the original, but is larger and outscored by rich, so
+        // trimming the pool to size evicts it.
+        let replacement = spend(&[coin(0), coin(2)], 190_000, FINAL);
+        assert_eq!(
+            accept(&mut pool, &replacement, &utxos),
+            Err(MempoolError::MempoolFull)
+        );
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.spender(&coin(0)), Some(original.txid()));
+        assert_eq!(pool.spender(&coin(2)), None);
+        assert_eq!(pool.size(), max_size);
+    }
+
+    #[test]
+    fn test_replacement_inputs_and_limits() {
+        let utxos = utxos();
+        let mut pool = Mempool::new();
+        let original = spend(&[coin(0)], 90_000, RBF);
+        let other = spend(&[coin(1)], 90_000, RBF);
+        accept(&mut pool, &original, &utxos).unwrap();
+        accept(&mut pool, &other, &utxos).unwrap();
+
+        // New unconfirmed inputs are refused, new confirmed ones are fine.
+        let adds_unconfirmed = spend(&[coin(0), OutPoint::new(other.txid(), 0)], 150_000, RBF);
+        assert_eq!(
+            accept(&mut pool, &adds_unconfirmed, &utxos),
+            Err(MempoolError::ReplacementAddsUnconfirmed)
+        );
+        let spends_replaced = spend(&[coin(0), OutPoint::new(original.txid(), 0)], 150_000, RBF);
+        assert_eq!(
+            accept(&mut pool, &spends_replaced, &utxos),
+            Err(MempoolError::SpendsConflictingTx)
+        );
+        let adds_confirmed = spend(&[coin(0), coin(2)], 150_000, RBF);
+        let accepted = accept(&mut pool, &adds_confirmed, &utxos).unwrap();
+        assert_eq!(accepted.replaced, vec![original]);
+        assert!(pool.contains(&other.txid()));
+
+        // A chain of more than a hundred descendants can't be replaced.
+        let mut pool = Mempool::new().with_min_relay_feerate(FeeRate::ZERO);
+        let mut tip = spend(&[coin(3)], 99_000, RBF);
+        accept(&mut pool, &tip, &utxos).unwrap();
+        for n in 0..MAX_REPLACEMENT_CANDIDATES as u64 {
+            tip = spend(&[OutPoint::new(tip.txid(), 0)], 98_000 - n, FINAL);
+            accept(&mut pool, &tip, &utxos).unwrap();
+        }
+        let replacement = spend(&[coin(3)], 10_000, FINAL);
+        assert_eq!(
+            accept(&mut pool, &replacement, &utxos),
+            Err(MempoolError::TooManyReplacements(
+                MAX_REPLACEMENT_CANDIDATES + 1
+            ))
+        );
+        assert!(signals_rbf(&spend(&[coin(3)], 1, RBF)));
+        assert!(!signals_rbf(&spend(&[coin(3)], 1, 0xfffffffe)));
+    }
+}
@@ -0,0 +1,285 @@
+// Metrics of a running node in Prometheus's text format, served over HTTP at
+// /metrics for monitoring to scrape: gauges of the peers, the chain and the mempool,
+// counters of the traffic with peers and a histogram of how long blocks take to
+// connect. Writing them is separate from serving them so tests can read the text.
+use crate::node::Node;
+use crate::p2p::{PeerManager, TrafficStats};
+use crate::rpc::http::{read_request, write_response};
+use crate::rpc::HttpResponse;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+
+pub const METRICS_PATH: &str = "/metrics";
+// Version 0.0.4 of the text format.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// Upper bounds in seconds of the buckets of block connection times.
+pub const CONNECT_BLOCK_BUCKETS: [f64; 10] =
+    [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+// Counts of observations up to each bound, and above the last one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    // Observations up to each bound, then the count of all for +Inf.
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .chain([&f64::INFINITY])
+            .zip(&self.counts)
+            .map(|(bound, count)| {
+                total += count;
+                (*bound, total)
+            })
+            .collect()
+    }
+}
+
+// Text of metrics, each family with its help and type lines.
+#[derive(Clone, Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        self.text
+            .push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.text
+            .push_str(&format!("{name}{} {value}\n", format_labels(labels)));
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl Display) -> &mut Self {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+        self
+    }
+
+    // A counter with a sample for each value of label, which ends in _total.
+    pub fn counter<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        samples: impl IntoIterator<Item = (&'a str, u64)>,
+    ) -> &mut Self {
+        self.family(name, "counter", help);
+        for (value, count) in samples {
+            self.sample(name, &[(label, value)], count);
+        }
+        self
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) -> &mut Self {
+        self.family(name, "histogram", help);
+        for (bound, count) in histogram.cumulative() {
+            let le = if bound.is_infinite() {
+                "+Inf".to_string()
+            } else {
+                bound.to_string()
+            };
+            self.sample(&format!("{name}_bucket"), &[("le", &le)], count);
+        }
+        self.sample(&format!("{name}_sum"), &[], histogram.sum());
+        self.sample(&format!("{name}_count"), &[], histogram.count());
+        self
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+fn by_command(bytes: &BTreeMap<String, u64>) -> impl Iterator<Item = (&str, u64)> {
+    bytes
+        .iter()
+        .map(|(command, bytes)| (command.as_str(), *bytes))
+}
+
+// The metrics of node, which has peers connected with traffic between them.
+pub fn render(node: &Node, peers: usize, traffic: &TrafficStats) -> String {
+    let chain = node.chain();
+    let mempool = node.mempool();
+    let mut exposition = Exposition::new();
+    exposition
+        .gauge("bitcoin_peers", "Peers connected.", peers)
+        .gauge(
+            "bitcoin_blocks_height",
+            "Height of the connected chain's tip.",
+            chain.height(),
+        )
+        .gauge(
+            "bitcoin_headers_height",
+            "Height of the best header chain.",
+            chain.headers().height(),
+        )
+        .gauge(
+            "bitcoin_initial_block_download",
+            "Whether the node is still syncing, 1 or 0.",
+            u8::from(node.is_syncing()),
+        )
+        .gauge(
+            "bitcoin_mempool_transactions",
+            "Transactions in the mempool.",
+            mempool.len(),
+        )
+        .gauge(
+            "bitcoin_mempool_bytes",
+            "Serialized size of the transactions in the mempool.",
+            mempool.size(),
+        )
+        .counter(
+            "bitcoin_net_sent_bytes_total",
+            "Bytes sent to peers, by message.",
+            "command",
+            by_command(&traffic.bytes_sent_per_command),
+        )
+        .counter(
+            "bitcoin_net_received_bytes_total",
+            "Bytes received from peers, by message.",
+            "command",
+            by_command(&traffic.bytes_received_per_command),
+        )
+        .histogram(
+            "bitcoin_block_connect_seconds",
+            "Time taken to validate and connect a block.",
+            chain.connect_times(),
+        );
+    exposition.finish()
+}
+
+// Serves the metrics of node and the peers of manager on listener until the task is
+// dropped, a task per connection. Anything but GET /metrics is not found.
+pub async fn serve_metrics(listener: TcpListener, node: Arc<Mutex<Node>>, manager: PeerManager) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let (node, manager) = (node.clone(), manager.clone());
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            loop {
+                let (response, keep_alive) = match read_request(&mut reader).await {
+                    Ok(Some(request))
+                        if request.method == "GET" && request.path == METRICS_PATH =>
+                    {
+                        let (peers, traffic) = (manager.peers().len(), manager.totals());
+                        let text = render(&node.lock().unwrap(), peers, &traffic);
+                        let response = HttpResponse::new(200, METRICS_CONTENT_TYPE, text);
+                        (response, request.keep_alive())
+                    }
+                    Ok(Some(request)) => (
+                        HttpResponse::new(404, "text/plain", ""),
+                        request.keep_alive(),
+                    ),
+                    Ok(None) => return,
+                    Err(e) => (HttpResponse::new(400, "text/plain", e.to_string()), false),
+                };
+                if write_response(&mut writer, &response, keep_alive)
+                    .await
+                    .is_err()
+                    || !keep_alive
+                {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_util::temp_path;
+    use std::fs;
+
+    #[test]
+    fn test_exposition() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        for value in [0.05, 0.1, 0.5, 3.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(
+            histogram.cumulative(),
+            [(0.1, 2), (1.0, 3), (f64::INFINITY, 4)]
+        );
+
+        let dir = temp_path("metrics_tests");
+        let _ = fs::remove_dir_all(&dir);
+        let node = Node::open(Network::Regtest, &dir).unwrap();
+        let mut traffic = TrafficStats::default();
+        traffic
+            .bytes_sent_per_command
+            .insert("ping".to_string(), 32);
+        let text = render(&node, 2, &traffic);
+        for line in [
+            "# TYPE bitcoin_peers gauge",
+            "bitcoin_peers 2",
+            "bitcoin_blocks_height 0",
+            "bitcoin_net_sent_bytes_total{command=\"ping\"} 32",
+            "# TYPE bitcoin_block_connect_seconds histogram",
+            "bitcoin_block_connect_seconds_bucket{le=\"+Inf\"} 0",
+            "bitcoin_block_connect_seconds_count 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line}");
+        }
+        assert_eq!(format_labels(&[("a", "x\"y")]), "{a=\"x\\\"y\"}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
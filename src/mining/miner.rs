@@ -0,0 +1,285 @@
+// A CPU miner for regtest and signets, whose targets take few hashes. A template is
+// completed with a coinbase paying the miner's script, signed for the signet
+// challenge if there is one, and the nonce ground until the header meets the
+// target, moving on to the next extra nonce of the coinbase when nonces run out.
+use super::template::{BlockAssembler, BlockTemplate};
+use crate::block::pow::hash_to_number;
+use crate::block::{Block, BlockHeader};
+use crate::chain::signet::{signet_solution, SignetTxs, SIGNET_HEADER};
+use crate::ecc::PrivateKey;
+use crate::helper::hash160;
+use crate::node::{apply, Node, NodeAction};
+use crate::p2p::PeerManager;
+use crate::script::templates::ScriptType;
+use crate::script::{encode_push, Opcode, Script};
+use crate::transaction::sighash::{SigHashType, SighashCache};
+use crate::transaction::{txid_to_hex, Witness};
+use crate::types::errors::Errors;
+use num_bigint::Sign;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+// Hashes tried before giving up, as the maxtries of Bitcoin Core's generate RPCs.
+pub const DEFAULT_MAX_TRIES: u64 = 1_000_000;
+// How often mine finds a block.
+pub const DEFAULT_MINE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as u32)
+}
+
+// Tries nonces from the header's on, at most tries of them. Whether one met the
+// target, which is then left in the header.
+pub fn grind(header: &mut BlockHeader, tries: u64) -> bool {
+    let target = match header.target() {
+        Ok(target) if target.sign() == Sign::Plus => target,
+        _ => return false,
+    };
+    for _ in 0..tries {
+        if hash_to_number(&header.hash()) <= target {
+            return true;
+        }
+        header.nonce = match header.nonce.checked_add(1) {
+            Some(nonce) => nonce,
+            None => return false,
+        };
+    }
+    false
+}
+
+#[derive(Clone, Debug)]
+pub struct CpuMiner {
+    script_pubkey: Script,
+    signet_key: Option<PrivateKey>,
+    max_tries: u64,
+}
+
+impl CpuMiner {
+    // Mining to script_pubkey.
+    pub fn new(script_pubkey: Script) -> Self {
+        CpuMiner {
+            script_pubkey,
+            signet_key: None,
+            max_tries: DEFAULT_MAX_TRIES,
+        }
+    }
+
+    // Signs blocks for a signet whose challenge is a public key, a 1 of n multisig
+    // holding it or a P2WPKH of it. Challenges anyone can satisfy need no key.
+    pub fn with_signet_key(mut self, key: PrivateKey) -> Self {
+        self.signet_key = Some(key);
+        self
+    }
+
+    pub fn with_max_tries(mut self, max_tries: u64) -> Self {
+        self.max_tries = max_tries;
+        self
+    }
+
+    // A block completing template, or None if max_tries hashes didn't find one.
+    pub fn solve(
+        &self,
+        template: &BlockTemplate,
+        challenge: Option<&Script>,
+    ) -> Result<Option<Block>, Errors> {
+        let mut tries = self.max_tries;
+        self.solve_with(template, challenge, &mut tries)
+    }
+
+    // solve, taking the hashes it makes from tries.
+    fn solve_with(
+        &self,
+        template: &BlockTemplate,
+        challenge: Option<&Script>,
+        tries: &mut u64,
+    ) -> Result<Option<Block>, Errors> {
+        let mut extra_nonce: u32 = 0;
+        while *tries > 0 {
+            let coinbase =
+                template.coinbase(self.script_pubkey.clone(), &extra_nonce.to_le_bytes());
+            let mut block = template.block(coinbase);
+            if let Some(challenge) = challenge {
+                self.sign(&mut block, challenge)?;
+            }
+            let found = grind(&mut block.header, (*tries).min(u32::MAX as u64 + 1));
+            // The nonces tried are those up to the one left in the header.
+            *tries -= (block.header.nonce as u64 + 1).min(*tries);
+            if found {
+                return Ok(Some(block));
+            }
+            extra_nonce += 1;
+        }
+        Ok(None)
+    }
+
+    // Adds the BIP325 solution to the witness commitment of block, which signs the
+    // block with the solution's push cut down to its header.
+    fn sign(&self, block: &mut Block, challenge: &Script) -> Result<(), Errors> {
+        let push_solution = |block: &mut Block, solution: &[u8]| {
+            let coinbase = &mut block.transactions[0];
+            let output = coinbase.outputs.last_mut().unwrap();
+            let mut script = output.script_pubkey.clone().into_bytes();
+            script.extend(encode_push(solution));
+            output.script_pubkey = script.into();
+            block.header.merkle_root = block.compute_merkle_root();
+        };
+        // OP_TRUE, which anyone can satisfy with an empty solution.
+        if challenge.as_bytes() == [Opcode::OP_1.to_u8()] {
+            return Ok(());
+        }
+        let key = self
+            .signet_key
+            .as_ref()
+            .ok_or_else(|| Errors::MissingKey(challenge.to_asm()))?;
+        let pubkey = key.public_key().sec(true);
+        let mut unsigned = block.clone();
+        push_solution(&mut unsigned, &SIGNET_HEADER);
+        let txs = SignetTxs::new(&unsigned, challenge).map_err(|_| {
+            Errors::UnsupportedScript("block without a witness commitment".to_string())
+        })?;
+        let sighash = SighashCache::new(&txs.to_sign);
+        let mut script_sig = Script::new();
+        let mut witness = Witness::new();
+        match challenge.classify() {
+            ScriptType::PubKey(key_bytes) if key_bytes == pubkey => {
+                script_sig.push_slice(&sighash.sign_legacy_input(
+                    0,
+                    key,
+                    challenge,
+                    SigHashType::All,
+                )?);
+            }
+            ScriptType::Multisig {
+                required: 1,
+                pubkeys,
+            } if pubkeys.contains(&&pubkey[..]) => {
+                let signature = sighash.sign_legacy_input(0, key, challenge, SigHashType::All)?;
+                script_sig.push_opcode(Opcode::OP_0).push_slice(&signature);
+            }
+            ScriptType::WitnessV0KeyHash(hash) if hash == hash160(&pubkey) => {
+                let script_code = Script::new_p2pkh(&hash);
+                let signature =
+                    sighash.sign_segwit_v0_input(0, key, &script_code, 0, SigHashType::All)?;
+                witness = Witness::p2wpkh(&signature, &pubkey);
+            }
+            ScriptType::PubKey(_)
+            | ScriptType::Multisig { .. }
+            | ScriptType::WitnessV0KeyHash(_) => {
+                return Err(Errors::MissingKey(challenge.to_asm()));
+            }
+            _ => return Err(Errors::UnsupportedScript(challenge.to_asm())),
+        }
+        push_solution(block, &signet_solution(&script_sig, &witness));
+        Ok(())
+    }
+
+    // Mines count blocks on node's tip with the transactions of its mempool,
+    // returning their hashes and the announcements to make. Fewer blocks are mined
+    // if max_tries runs out, the tries being shared by all of them.
+    pub fn generate(
+        &self,
+        node: &mut Node,
+        count: u32,
+    ) -> Result<(Vec<[u8; 32]>, Vec<NodeAction>), Errors> {
+        let mut tries = self.max_tries;
+        let mut hashes = Vec::new();
+        let mut actions = Vec::new();
+        while hashes.len() < count as usize && tries > 0 {
+            let chain = node.chain();
+            let template = BlockAssembler::new().create(chain, node.mempool(), now());
+            let challenge = chain.signet_challenge().cloned();
+            let Some(block) = self.solve_with(&template, challenge.as_ref(), &mut tries)? else {
+                break;
+            };
+            let hash = block.hash();
+            actions.extend(node.submit_block(block).map_err(Errors::BlockRejected)?);
+            hashes.push(hash);
+        }
+        Ok((hashes, actions))
+    }
+}
+
+// Mines a block on node every interval until shutdown, announcing each to the peers
+// of manager. Stops at the first block failing, as mining on would fail the same way.
+pub async fn mine(
+    node: Arc<Mutex<Node>>,
+    manager: PeerManager,
+    miner: CpuMiner,
+    interval: Duration,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Errors> {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => return Ok(()),
+            _ = ticks.tick() => {
+                let (hashes, actions) = miner.generate(&mut node.lock().unwrap(), 1)?;
+                for hash in &hashes {
+                    info!(target: "mining", hash = txid_to_hex(hash), "Mined a block");
+                }
+                apply(&manager, actions);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod miner_tests {
+    use super::*;
+    use crate::chain::{check_signet_block_solution, ChainState, MemoryBackend};
+    use crate::mempool::Mempool;
+    use crate::network::Network;
+    use crate::test_util::temp_path;
+    use num_bigint::BigInt;
+    use std::fs;
+
+    #[test]
+    fn test_generate_on_regtest() {
+        let dir = temp_path("miner_tests");
+        let _ = fs::remove_dir_all(&dir);
+        let mut node = Node::open(Network::Regtest, &dir).unwrap();
+        let miner = CpuMiner::new(vec![0x51].into());
+        let (hashes, _) = miner.generate(&mut node, 3).unwrap();
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(node.chain().height(), 3);
+        assert_eq!(node.chain().tip().hash, hashes[2]);
+        // Out of tries, giving up without error.
+        let (hashes, _) = miner.with_max_tries(0).generate(&mut node, 1).unwrap();
+        assert!(hashes.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_signed_signet_blocks() {
+        let key = PrivateKey::new(BigInt::from(777)).unwrap();
+        let mut challenge = Script::new();
+        challenge
+            .push_int(1)
+            .push_slice(&key.public_key().sec(true))
+            .push_int(1)
+            .push_opcode(Opcode::OP_CHECKMULTISIG);
+        let chain = ChainState::new(Network::Signet, MemoryBackend::default())
+            .with_signet_challenge(challenge.clone());
+        let mut template = BlockAssembler::new().create(&chain, &Mempool::new(), 0);
+        // Signet's limit takes millions of hashes, regtest's a couple.
+        template.header.bits = Network::Regtest.pow_limit_bits();
+        let miner = CpuMiner::new(vec![0x51].into());
+        assert!(matches!(
+            miner.solve(&template, Some(&challenge)),
+            Err(Errors::MissingKey(_))
+        ));
+        let block = miner
+            .with_signet_key(key)
+            .solve(&template, Some(&challenge))
+            .unwrap()
+            .unwrap();
+        assert!(block.header.check_pow());
+        assert_eq!(check_signet_block_solution(&block, &challenge), Ok(()));
+    }
+}
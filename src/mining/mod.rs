@@ -0,0 +1,14 @@
+// Mining on top of the node's chain: templates of the next block for miners to
+// complete, from the mempool's best paying transactions, and a CPU miner completing
+// them on regtest and signets, and a stratum server handing them out to mining
+// hardware.
+pub mod miner;
+pub mod stratum;
+pub mod template;
+
+pub use miner::{mine, CpuMiner, DEFAULT_MAX_TRIES, DEFAULT_MINE_INTERVAL};
+pub use stratum::{serve_stratum, Stratum, DEFAULT_SHARE_DIFFICULTY, DEFAULT_STRATUM_PORT};
+pub use template::{
+    BlockAssembler, BlockTemplate, TemplateTransaction, DEFAULT_BLOCK_MAX_WEIGHT,
+    DEFAULT_BLOCK_MIN_TX_FEE,
+};
@@ -0,0 +1,588 @@
+// A stratum v1 server, for mining hardware and proxies to work on the node's block
+// templates. Each template becomes a job: the coinbase split around the extranonce,
+// which the server starts with a part of its own for every connection and miners
+// fill in the rest of, and the merkle branch from the coinbase to the root. Miners
+// submit shares, headers meeting a difficulty set for their connection that is
+// adjusted to a share every SHARE_INTERVAL; a share meeting the block's target too
+// is a block. Messages are JSON, one object per line.
+use super::template::{BlockAssembler, BlockTemplate};
+use crate::block::Block;
+use crate::chain::headers::MAX_FUTURE_BLOCK_TIME;
+use crate::helper::{encode_varint, hash256};
+use crate::node::{apply, Node};
+use crate::p2p::PeerManager;
+use crate::script::Script;
+use crate::types::errors::StratumError;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Notify};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+pub const DEFAULT_STRATUM_PORT: u16 = 3333;
+pub const EXTRANONCE1_SIZE: usize = 4;
+pub const EXTRANONCE2_SIZE: usize = 4;
+pub const DEFAULT_SHARE_DIFFICULTY: f64 = 1.0;
+// Below this a share takes about a hash.
+pub const MIN_SHARE_DIFFICULTY: f64 = 1.0 / 4294967296.0;
+// Vardiff aims at a share this often, retargeting when RETARGET_SHARES came in or
+// RETARGET_INTERVAL went by, by at most a factor of 4.
+pub const SHARE_INTERVAL: u32 = 10;
+pub const RETARGET_SHARES: u32 = 30;
+pub const RETARGET_INTERVAL: u32 = 120;
+// Jobs kept for late shares once newer ones are out.
+pub const MAX_JOBS: usize = 8;
+// New transactions make it into a job this often, a new tip at once.
+pub const JOB_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const TIP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// The longest line a miner sends, a mining.submit being well under.
+const MAX_LINE: usize = 4096;
+
+impl StratumError {
+    pub fn code(&self) -> i32 {
+        match self {
+            StratumError::Other(_) => 20,
+            StratumError::JobNotFound => 21,
+            StratumError::DuplicateShare => 22,
+            StratumError::LowDifficulty => 23,
+            StratumError::Unauthorized => 24,
+            StratumError::NotSubscribed => 25,
+        }
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as u32)
+}
+
+// Difficulty of a header hash, relative to the target of difficulty 1 as pools
+// count it (0xffff << 208).
+pub fn share_difficulty(hash: &[u8; 32]) -> f64 {
+    let value = hash
+        .iter()
+        .rev()
+        .fold(0.0, |value, &byte| value * 256.0 + byte as f64);
+    65535.0 * 2f64.powi(208) / value
+}
+
+// Hashes on the path from the first leaf to the root, the sibling at each level.
+pub fn merkle_branch(hashes: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    let mut level = hashes.to_vec();
+    let mut branch = Vec::new();
+    while level.len() > 1 {
+        branch.push(level[1]);
+        if level.len() % 2 == 1 {
+            level.push(*level.last().unwrap());
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| hash256(&[pair[0], pair[1]].concat()))
+            .collect();
+    }
+    branch
+}
+
+// The previous block hash as stratum sends it: each 4 byte word reversed.
+fn stratum_prevhash(hash: &[u8; 32]) -> String {
+    let words: Vec<u8> = hash
+        .chunks(4)
+        .flat_map(|word| word.iter().rev().copied())
+        .collect();
+    hex::encode(words)
+}
+
+fn parse_u32(value: &Value) -> Option<u32> {
+    u32::from_str_radix(value.as_str()?, 16).ok()
+}
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub template: BlockTemplate,
+    // The coinbase without its witness, before and after the extranonce.
+    pub coinb1: Vec<u8>,
+    pub coinb2: Vec<u8>,
+    pub branch: Vec<[u8; 32]>,
+    // Extranonces, times and nonces of the shares found, to refuse them twice.
+    shares: HashSet<(Vec<u8>, u32, u32)>,
+}
+
+impl Job {
+    pub fn new(id: String, template: BlockTemplate, script_pubkey: &Script) -> Self {
+        let extranonce = [0; EXTRANONCE1_SIZE + EXTRANONCE2_SIZE];
+        let coinbase = template.coinbase(script_pubkey.clone(), &extranonce);
+        let serialized = coinbase.serialize_legacy();
+        // The extranonce ends the scriptSig, which follows the version, the input
+        // count and the outpoint.
+        let script_sig = coinbase.inputs[0].script_sig.len();
+        let end = 4 + 1 + 36 + encode_varint(script_sig as u64).len() + script_sig;
+        let start = end - extranonce.len();
+        let mut txids = vec![[0; 32]];
+        txids.extend(template.transactions.iter().map(|tx| tx.tx.txid()));
+        Job {
+            id,
+            coinb1: serialized[..start].to_vec(),
+            coinb2: serialized[end..].to_vec(),
+            branch: merkle_branch(&txids),
+            template,
+            shares: HashSet::new(),
+        }
+    }
+
+    // The mining.notify of the job, clean telling miners to drop the older ones.
+    pub fn notify(&self, clean: bool) -> Value {
+        let header = &self.template.header;
+        let branch: Vec<String> = self.branch.iter().map(hex::encode).collect();
+        json!({
+            "id": null,
+            "method": "mining.notify",
+            "params": [
+                self.id,
+                stratum_prevhash(&header.prev_block),
+                hex::encode(&self.coinb1),
+                hex::encode(&self.coinb2),
+                branch,
+                format!("{:08x}", header.version),
+                format!("{:08x}", header.bits),
+                format!("{:08x}", header.timestamp),
+                clean,
+            ],
+        })
+    }
+
+    pub fn block(&self, script_pubkey: &Script, extranonce: &[u8], time: u32, nonce: u32) -> Block {
+        let coinbase = self.template.coinbase(script_pubkey.clone(), extranonce);
+        let mut block = self.template.block(coinbase);
+        block.header.timestamp = time;
+        block.header.nonce = nonce;
+        block
+    }
+}
+
+// A miner's connection. Shares of jobs sent before a retarget may meet the
+// difficulty before it.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub extranonce1: [u8; EXTRANONCE1_SIZE],
+    pub subscribed: bool,
+    pub workers: Vec<String>,
+    pub difficulty: f64,
+    previous_difficulty: f64,
+    shares: u32,
+    retargeted: u32,
+}
+
+impl Session {
+    fn set_difficulty(&self) -> Value {
+        json!({"id": null, "method": "mining.set_difficulty", "params": [self.difficulty]})
+    }
+}
+
+// What a request was answered with, and the block found by a share.
+#[derive(Debug, Default)]
+pub struct StratumReply {
+    pub messages: Vec<Value>,
+    pub block: Option<Block>,
+}
+
+// Jobs and sessions of a server paying script_pubkey. Workers authorize with any
+// name and password, as all they find goes there.
+#[derive(Debug)]
+pub struct Stratum {
+    script_pubkey: Script,
+    difficulty: f64,
+    jobs: Vec<Job>,
+    next_job: u64,
+    next_extranonce1: u32,
+}
+
+impl Stratum {
+    pub fn new(script_pubkey: Script) -> Self {
+        Stratum {
+            script_pubkey,
+            difficulty: DEFAULT_SHARE_DIFFICULTY,
+            jobs: Vec::new(),
+            next_job: 0,
+            next_extranonce1: 0,
+        }
+    }
+
+    // Difficulty new sessions start at.
+    pub fn with_difficulty(mut self, difficulty: f64) -> Self {
+        self.difficulty = difficulty.max(MIN_SHARE_DIFFICULTY);
+        self
+    }
+
+    pub fn session(&mut self, now: u32) -> Session {
+        let extranonce1 = self.next_extranonce1.to_be_bytes();
+        self.next_extranonce1 = self.next_extranonce1.wrapping_add(1);
+        Session {
+            extranonce1,
+            subscribed: false,
+            workers: Vec::new(),
+            difficulty: self.difficulty,
+            previous_difficulty: self.difficulty,
+            shares: 0,
+            retargeted: now,
+        }
+    }
+
+    pub fn job(&self) -> Option<&Job> {
+        self.jobs.last()
+    }
+
+    // Makes a job of template, returning its notify. A template on a new tip makes
+    // the older jobs stale.
+    pub fn update(&mut self, template: BlockTemplate) -> Value {
+        let clean = self
+            .job()
+            .is_none_or(|job| job.template.header.prev_block != template.header.prev_block);
+        if clean {
+            self.jobs.clear();
+        } else if self.jobs.len() >= MAX_JOBS {
+            self.jobs.remove(0);
+        }
+        let id = format!("{:x}", self.next_job);
+        self.next_job += 1;
+        let job = Job::new(id, template, &self.script_pubkey);
+        let notify = job.notify(clean);
+        self.jobs.push(job);
+        notify
+    }
+
+    // Vardiff: the session's mining.set_difficulty if shares came in too fast or
+    // too slow, going by those since the last retarget.
+    pub fn retarget(&self, session: &mut Session, now: u32) -> Option<Value> {
+        let elapsed = now.saturating_sub(session.retargeted);
+        if session.shares < RETARGET_SHARES && elapsed < RETARGET_INTERVAL {
+            return None;
+        }
+        let expected = session.shares.max(1) as f64 * SHARE_INTERVAL as f64;
+        let factor = (expected / elapsed.max(1) as f64).clamp(0.25, 4.0);
+        let shares = std::mem::take(&mut session.shares);
+        session.retargeted = now;
+        // Off by less than half isn't worth a change.
+        if shares > 0 && (0.5..=2.0).contains(&factor) {
+            return None;
+        }
+        let difficulty = (session.difficulty * factor).max(MIN_SHARE_DIFFICULTY);
+        if difficulty == session.difficulty {
+            return None;
+        }
+        session.previous_difficulty = session.difficulty;
+        session.difficulty = difficulty;
+        Some(session.set_difficulty())
+    }
+
+    // Answers a line of session's miner.
+    pub fn handle(&mut self, session: &mut Session, line: &str, now: u32) -> StratumReply {
+        let mut reply = StratumReply::default();
+        let Ok(request) = serde_json::from_str::<Value>(line) else {
+            let error = StratumError::Other("Parse error".to_string());
+            reply.messages.push(response(&Value::Null, Err(error)));
+            return reply;
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let params = request.get("params").cloned().unwrap_or(json!([]));
+        let params = params
+            .as_array()
+            .map_or(&[][..], |params| params.as_slice());
+        let result = match request.get("method").and_then(Value::as_str) {
+            Some("mining.subscribe") => {
+                session.subscribed = true;
+                let subscription = hex::encode(session.extranonce1);
+                Ok(json!([
+                    [
+                        ["mining.set_difficulty", subscription],
+                        ["mining.notify", subscription]
+                    ],
+                    hex::encode(session.extranonce1),
+                    EXTRANONCE2_SIZE,
+                ]))
+            }
+            Some("mining.authorize") => match params.first().and_then(Value::as_str) {
+                Some(worker) => {
+                    session.workers.push(worker.to_string());
+                    Ok(json!(true))
+                }
+                None => Err(StratumError::Other("Missing worker name".to_string())),
+            },
+            // BIP310, turning down version rolling which shares can't carry here.
+            Some("mining.configure") => Ok(json!({"version-rolling": false})),
+            Some("mining.extranonce.subscribe") => Ok(json!(false)),
+            Some("mining.submit") => self.submit(session, params, now).map(|block| {
+                reply.block = block;
+                json!(true)
+            }),
+            _ => Err(StratumError::Other("Method not found".to_string())),
+        };
+        let subscribed = result.is_ok() && request["method"] == "mining.subscribe";
+        reply.messages.push(response(&id, result));
+        if subscribed {
+            reply.messages.push(session.set_difficulty());
+            if let Some(job) = self.job() {
+                reply.messages.push(job.notify(true));
+            }
+        } else if let Some(set_difficulty) = self.retarget(session, now) {
+            reply.messages.push(set_difficulty);
+        }
+        reply
+    }
+
+    // Checks a share of params [worker, job id, extranonce2, ntime, nonce],
+    // returning the block if it is one.
+    fn submit(
+        &mut self,
+        session: &mut Session,
+        params: &[Value],
+        now: u32,
+    ) -> Result<Option<Block>, StratumError> {
+        if !session.subscribed {
+            return Err(StratumError::NotSubscribed);
+        }
+        let worker = params.first().and_then(Value::as_str);
+        if !worker.is_some_and(|worker| session.workers.iter().any(|w| w == worker)) {
+            return Err(StratumError::Unauthorized);
+        }
+        let invalid = |what: &str| StratumError::Other(format!("Invalid {what}"));
+        let job_id = params.get(1).and_then(Value::as_str);
+        let job = self
+            .jobs
+            .iter_mut()
+            .find(|job| Some(job.id.as_str()) == job_id)
+            .ok_or(StratumError::JobNotFound)?;
+        let extranonce2 = params
+            .get(2)
+            .and_then(Value::as_str)
+            .and_then(|extranonce2| hex::decode(extranonce2).ok())
+            .filter(|extranonce2| extranonce2.len() == EXTRANONCE2_SIZE)
+            .ok_or_else(|| invalid("extranonce2"))?;
+        let time = params
+            .get(3)
+            .and_then(parse_u32)
+            .ok_or_else(|| invalid("ntime"))?;
+        if time < job.template.min_time || time as u64 > now as u64 + MAX_FUTURE_BLOCK_TIME {
+            return Err(StratumError::Other("ntime out of range".to_string()));
+        }
+        let nonce = params
+            .get(4)
+            .and_then(parse_u32)
+            .ok_or_else(|| invalid("nonce"))?;
+        let extranonce = [&session.extranonce1[..], &extranonce2].concat();
+        if !job.shares.insert((extranonce.clone(), time, nonce)) {
+            return Err(StratumError::DuplicateShare);
+        }
+        let block = job.block(&self.script_pubkey, &extranonce, time, nonce);
+        let difficulty = session.difficulty.min(session.previous_difficulty);
+        if share_difficulty(&block.hash()) < difficulty {
+            return Err(StratumError::LowDifficulty);
+        }
+        session.shares += 1;
+        Ok(block.header.check_pow().then_some(block))
+    }
+}
+
+fn response(id: &Value, result: Result<Value, StratumError>) -> Value {
+    match result {
+        Ok(result) => json!({"id": id, "result": result, "error": null}),
+        Err(e) => json!({"id": id, "result": null, "error": [e.code(), e.to_string(), null]}),
+    }
+}
+
+fn template(node: &Mutex<Node>) -> BlockTemplate {
+    let node = node.lock().unwrap();
+    BlockAssembler::new().create(node.chain(), node.mempool(), now())
+}
+
+async fn write_message(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    message: &Value,
+) -> std::io::Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+// Answers the miner on stream and relays every new job to it, until it goes away.
+async fn serve_miner(
+    stream: TcpStream,
+    node: Arc<Mutex<Node>>,
+    manager: PeerManager,
+    stratum: Arc<Mutex<Stratum>>,
+    mut jobs: broadcast::Receiver<Value>,
+    found: Arc<Notify>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut session = stratum.lock().unwrap().session(now());
+    loop {
+        let messages = tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                if line.len() > MAX_LINE {
+                    return Ok(());
+                }
+                let reply = stratum.lock().unwrap().handle(&mut session, &line, now());
+                if let Some(block) = reply.block {
+                    let hash = block.header.hash_hex();
+                    match node.lock().unwrap().submit_block(block) {
+                        Ok(actions) => {
+                            info!(target: "mining", hash, "Stratum miner found a block");
+                            apply(&manager, actions);
+                        }
+                        Err(e) => {
+                            warn!(target: "mining", hash, error = %e, "Stratum miner's block was rejected");
+                        }
+                    }
+                    found.notify_one();
+                }
+                reply.messages
+            }
+            job = jobs.recv() => match job {
+                Ok(notify) if session.subscribed => {
+                    // A new difficulty applies from the next job on.
+                    let retarget = stratum.lock().unwrap().retarget(&mut session, now());
+                    retarget.into_iter().chain([notify]).collect()
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        for message in &messages {
+            write_message(&mut writer, message).await?;
+        }
+    }
+}
+
+// Serves stratum on listener until the task is dropped, with jobs of the templates
+// of node and blocks found announced to the peers of manager.
+pub async fn serve_stratum(
+    listener: TcpListener,
+    node: Arc<Mutex<Node>>,
+    manager: PeerManager,
+    stratum: Stratum,
+) {
+    let stratum = Arc::new(Mutex::new(stratum));
+    let (jobs, _) = broadcast::channel(MAX_JOBS);
+    let found = Arc::new(Notify::new());
+    let mut tip = node.lock().unwrap().chain().tip().hash;
+    let first = template(&node);
+    stratum.lock().unwrap().update(first);
+    let mut polls = tokio::time::interval(TIP_POLL_INTERVAL);
+    let mut refreshed = tokio::time::Instant::now();
+    loop {
+        tokio::select! {
+            connection = listener.accept() => {
+                if let Ok((stream, address)) = connection {
+                    debug!(target: "mining", %address, "Stratum miner connected");
+                    let (node, manager) = (node.clone(), manager.clone());
+                    let miner = serve_miner(stream, node, manager, stratum.clone(), jobs.subscribe(), found.clone());
+                    tokio::spawn(miner.instrument(info_span!(target: "mining", "miner", %address)));
+                }
+                continue;
+            }
+            _ = polls.tick() => {}
+            _ = found.notified() => {}
+        }
+        let current = node.lock().unwrap().chain().tip().hash;
+        if current == tip && refreshed.elapsed() < JOB_REFRESH_INTERVAL {
+            continue;
+        }
+        tip = current;
+        refreshed = tokio::time::Instant::now();
+        let template = template(&node);
+        let notify = stratum.lock().unwrap().update(template);
+        let _ = jobs.send(notify);
+    }
+}
+
+#[cfg(test)]
+mod stratum_tests {
+    use super::*;
+    use crate::block::merkle::merkle_root;
+    use crate::chain::{ChainState, MemoryBackend};
+    use crate::mempool::Mempool;
+    use crate::network::Network;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_jobs() {
+        let hashes: Vec<[u8; 32]> = (0..5u8).map(|i| [i; 32]).collect();
+        let mut root = hashes[0];
+        for sibling in merkle_branch(&hashes) {
+            root = hash256(&[root, sibling].concat());
+        }
+        assert_eq!(root, merkle_root(&hashes).0);
+
+        let chain = ChainState::new(Network::Regtest, MemoryBackend::default());
+        let template = BlockAssembler::new().create(&chain, &Mempool::new(), 1_700_000_000);
+        let job = Job::new("0".to_string(), template, &vec![0x51].into());
+        let extranonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        let block = job.block(&vec![0x51].into(), &extranonce, 1_700_000_000, 0);
+        let coinbase = [&job.coinb1[..], &extranonce, &job.coinb2].concat();
+        let parsed = Transaction::parse(&mut coinbase.as_slice()).unwrap();
+        assert_eq!(parsed.txid(), block.transactions[0].txid());
+        let params = &job.notify(true)["params"];
+        assert_eq!(params[0], "0");
+        assert_eq!(params[7], "6553f100");
+        assert_eq!(params[8], true);
+    }
+
+    #[test]
+    fn test_session() {
+        let chain = ChainState::new(Network::Regtest, MemoryBackend::default());
+        let template = BlockAssembler::new().create(&chain, &Mempool::new(), 1_700_000_000);
+        let mut stratum = Stratum::new(vec![0x51].into()).with_difficulty(MIN_SHARE_DIFFICULTY);
+        stratum.update(template);
+        let now = 1_700_000_000;
+        let mut session = stratum.session(now);
+        let submit = |nonce: u32| {
+            format!(
+                r#"{{"id":3,"method":"mining.submit","params":["w","0","00000000","6553f100","{nonce:08x}"]}}"#
+            )
+        };
+        let reply = stratum.handle(&mut session, &submit(0), now);
+        assert_eq!(reply.messages[0]["error"][0], 25);
+
+        let reply = stratum.handle(
+            &mut session,
+            r#"{"id":1,"method":"mining.subscribe","params":[]}"#,
+            now,
+        );
+        assert_eq!(reply.messages[0]["result"][1], "00000000");
+        assert_eq!(reply.messages[1]["method"], "mining.set_difficulty");
+        assert_eq!(reply.messages[2]["method"], "mining.notify");
+        let reply = stratum.handle(&mut session, &submit(0), now);
+        assert_eq!(reply.messages[0]["error"][0], 24);
+        stratum.handle(
+            &mut session,
+            r#"{"id":2,"method":"mining.authorize","params":["w","x"]}"#,
+            now,
+        );
+
+        // At regtest's target about every other hash is a block.
+        let nonce = (0..)
+            .find(|&nonce| {
+                let job = stratum.job().unwrap();
+                let extranonce = [0; 8];
+                job.block(&vec![0x51].into(), &extranonce, 1_700_000_000, nonce)
+                    .header
+                    .check_pow()
+            })
+            .unwrap();
+        let reply = stratum.handle(&mut session, &submit(nonce), now);
+        assert_eq!(reply.messages[0]["result"], true);
+        let block = reply.block.unwrap();
+        assert_eq!(block.header.nonce, nonce);
+        assert!(block.validate_merkle_root());
+        let reply = stratum.handle(&mut session, &submit(nonce), now);
+        assert_eq!(reply.messages[0]["error"][0], 22);
+    }
+}
@@ -0,0 +1,445 @@
+// Block templates for miners, as Bitcoin Core's BlockAssembler builds them. Mempool
+// transactions are picked by the feerate of their package, themselves with the
+// ancestors not yet picked, so a child paying for its parent brings it in (CPFP).
+// The picks stay within the block weight and sigop limits, reserving room for the
+// coinbase, which is left for the miner to fill in with the template's value and
+// witness commitment.
+use crate::block::merkle::WITNESS_COMMITMENT_HEADER;
+use crate::block::{merkle_root, Block, BlockHeader};
+use crate::chain::{BlockStorage, ChainState, UtxoBackend};
+use crate::helper::hash256;
+use crate::mempool::Mempool;
+use crate::policy::FeeRate;
+use crate::script::{Script, VerificationFlags};
+use crate::transaction::{OutPoint, Transaction, TxIn, TxOut, Witness};
+use crate::validation::{
+    transaction_sigop_cost, UtxoView, MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_WEIGHT,
+};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+// Weight and sigops kept for the coinbase.
+pub const COINBASE_RESERVED_WEIGHT: usize = 4000;
+pub const COINBASE_RESERVED_SIGOPS: usize = 400;
+// As Bitcoin Core's DEFAULT_BLOCK_MAX_WEIGHT and DEFAULT_BLOCK_MIN_TX_FEE, in sat/kvB.
+pub const DEFAULT_BLOCK_MAX_WEIGHT: usize = MAX_BLOCK_WEIGHT - COINBASE_RESERVED_WEIGHT;
+pub const DEFAULT_BLOCK_MIN_TX_FEE: u64 = 1000;
+// BIP9 versions signal no deployment with these top bits alone.
+pub const VERSIONBITS_TOP_BITS: i32 = 0x20000000;
+// Packages that don't fit may be skipped this many times in a row once the block is
+// nearly full, before giving up on filling it.
+const MAX_CONSECUTIVE_FAILURES: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateTransaction {
+    pub tx: Transaction,
+    pub fee: u64,
+    pub sigop_cost: usize,
+    // Positions in the template of the transactions whose outputs it spends.
+    pub depends: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTemplate {
+    // Everything but the merkle root, which depends on the coinbase, and the nonce.
+    pub header: BlockHeader,
+    pub height: u32,
+    pub transactions: Vec<TemplateTransaction>,
+    // Subsidy and fees, for the coinbase to claim.
+    pub coinbase_value: u64,
+    // Output of the coinbase committing to the witnesses, once segwit is active.
+    pub witness_commitment: Option<TxOut>,
+    // Earliest timestamp the block may have.
+    pub min_time: u32,
+    // Of the transactions without the coinbase.
+    pub weight: usize,
+    pub sigop_cost: usize,
+}
+
+// Fees and sizes of a transaction together with its ancestors not yet picked.
+#[derive(Clone, Debug)]
+struct Package {
+    ancestors: HashSet<[u8; 32]>,
+    fee: u64,
+    vsize: usize,
+    weight: usize,
+    sigop_cost: usize,
+}
+
+impl Package {
+    // Compared as fee / vsize without rounding.
+    fn cmp_feerate(&self, other: &Package) -> Ordering {
+        let (fee, other_fee) = (self.fee as u128, other.fee as u128);
+        (fee * other.vsize as u128).cmp(&(other_fee * self.vsize as u128))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockAssembler {
+    max_weight: usize,
+    min_feerate: FeeRate,
+}
+
+impl Default for BlockAssembler {
+    fn default() -> Self {
+        BlockAssembler::new()
+    }
+}
+
+impl BlockAssembler {
+    pub fn new() -> Self {
+        BlockAssembler {
+            max_weight: DEFAULT_BLOCK_MAX_WEIGHT,
+            min_feerate: FeeRate::from_sat_per_kvb(DEFAULT_BLOCK_MIN_TX_FEE),
+        }
+    }
+
+    // As -blockmaxweight, kept within what leaves room for the coinbase.
+    pub fn with_max_weight(mut self, max_weight: usize) -> Self {
+        self.max_weight = max_weight.clamp(COINBASE_RESERVED_WEIGHT, DEFAULT_BLOCK_MAX_WEIGHT);
+        self
+    }
+
+    // Packages paying less are left out, as -blockmintxfee.
+    pub fn with_min_feerate(mut self, feerate: FeeRate) -> Self {
+        self.min_feerate = feerate;
+        self
+    }
+
+    // A template for the block on top of chain's tip, timestamped time unless the
+    // median time past calls for later.
+    pub fn create<B: UtxoBackend, S: BlockStorage>(
+        &self,
+        chain: &ChainState<B, S>,
+        mempool: &Mempool,
+        time: u32,
+    ) -> BlockTemplate {
+        let tip = chain.tip();
+        let height = tip.height + 1;
+        let network = chain.headers().network();
+        let min_time = chain.median_time_past() + 1;
+        let mut header = BlockHeader {
+            version: VERSIONBITS_TOP_BITS,
+            prev_block: tip.hash,
+            merkle_root: [0; 32],
+            timestamp: time.max(min_time),
+            bits: 0,
+            nonce: 0,
+        };
+        header.bits = chain.headers().next_work_required(tip, &header);
+        // BIP113, as contextual_check_block applies it to the block.
+        let lock_time_cutoff = match height >= network.csv_height() {
+            true => chain.median_time_past(),
+            false => header.timestamp,
+        };
+        let flags = network.script_flags(height, &[0; 32]);
+        // BIP68 too, which the pool may not hold to after a reorg.
+        let csv = flags.contains(VerificationFlags::CHECKSEQUENCEVERIFY);
+        let candidates: Vec<&Transaction> = mempool
+            .transactions()
+            .filter(|tx| tx.is_final(height, lock_time_cutoff))
+            .filter(|tx| !csv || chain.check_sequence_locks(tx).is_ok())
+            .collect();
+        let transactions = self.select(mempool, chain.utxos(), &candidates, flags);
+        let fees: u64 = transactions.iter().map(|tx| tx.fee).sum();
+        let segwit_active = height >= network.segwit_height();
+        let mut template = BlockTemplate {
+            header,
+            height,
+            coinbase_value: network.block_subsidy(height) + fees,
+            witness_commitment: None,
+            min_time,
+            weight: transactions.iter().map(|tx| tx.tx.weight()).sum(),
+            sigop_cost: transactions.iter().map(|tx| tx.sigop_cost).sum(),
+            transactions,
+        };
+        if segwit_active {
+            template.witness_commitment = Some(template.commitment_output());
+        }
+        template
+    }
+
+    // Picks packages of candidates by feerate, each in an order spending parents
+    // first. A package whose ancestors aren't all candidates is left out.
+    fn select(
+        &self,
+        mempool: &Mempool,
+        utxos: &impl UtxoView,
+        candidates: &[&Transaction],
+        flags: VerificationFlags,
+    ) -> Vec<TemplateTransaction> {
+        let mut costs: HashMap<[u8; 32], (u64, usize, usize, usize)> = HashMap::new();
+        for tx in candidates {
+            let prevouts: Option<Vec<TxOut>> = tx
+                .inputs
+                .iter()
+                .map(|input| {
+                    let outpoint = &input.previous_output;
+                    match mempool.get(&outpoint.txid) {
+                        Some(parent) => parent.tx.outputs.get(outpoint.vout as usize).cloned(),
+                        None => utxos.get_coin(outpoint).map(|coin| coin.output),
+                    }
+                })
+                .collect();
+            let (Some(prevouts), Some(entry)) = (prevouts, mempool.get(&tx.txid())) else {
+                continue;
+            };
+            let sigop_cost = transaction_sigop_cost(tx, &prevouts, flags);
+            costs.insert(tx.txid(), (entry.fee, entry.vsize, tx.weight(), sigop_cost));
+        }
+        let mut packages: HashMap<[u8; 32], Package> = HashMap::new();
+        for txid in costs.keys() {
+            let ancestors: HashSet<[u8; 32]> = mempool.ancestors(txid).into_iter().collect();
+            if !ancestors
+                .iter()
+                .all(|ancestor| costs.contains_key(ancestor))
+            {
+                continue;
+            }
+            let mut package = Package {
+                ancestors,
+                fee: 0,
+                vsize: 0,
+                weight: 0,
+                sigop_cost: 0,
+            };
+            for member in package.ancestors.iter().chain([txid]) {
+                let (fee, vsize, weight, sigop_cost) = costs[member];
+                package.fee += fee;
+                package.vsize += vsize;
+                package.weight += weight;
+                package.sigop_cost += sigop_cost;
+            }
+            packages.insert(*txid, package);
+        }
+
+        let mut selected: Vec<TemplateTransaction> = Vec::new();
+        let mut positions: HashMap<[u8; 32], usize> = HashMap::new();
+        let (mut weight, mut sigop_cost) = (COINBASE_RESERVED_WEIGHT, COINBASE_RESERVED_SIGOPS);
+        let mut failures = 0;
+        // Ties go to the lower txid, so templates don't depend on hashing order.
+        while let Some((txid, package)) = packages
+            .iter()
+            .max_by(|(a, pa), (b, pb)| pa.cmp_feerate(pb).then(b.cmp(a)))
+            .map(|(txid, package)| (*txid, package.clone()))
+        {
+            if FeeRate::from_fee_and_vsize(package.fee, package.vsize) < self.min_feerate {
+                break;
+            }
+            if weight + package.weight > self.max_weight
+                || sigop_cost + package.sigop_cost > MAX_BLOCK_SIGOPS_COST
+            {
+                packages.remove(&txid);
+                failures += 1;
+                if failures > MAX_CONSECUTIVE_FAILURES
+                    && weight > self.max_weight - COINBASE_RESERVED_WEIGHT
+                {
+                    break;
+                }
+                continue;
+            }
+            failures = 0;
+            // Fewer ancestors in the pool first, which puts parents before children.
+            let mut members: Vec<[u8; 32]> = package.ancestors.iter().copied().collect();
+            members.push(txid);
+            members.sort_by_key(|member| (mempool.ancestors(member).len(), *member));
+            for member in members {
+                let entry = mempool.get(&member).unwrap();
+                let (fee, vsize, member_weight, member_sigops) = costs[&member];
+                let mut depends: Vec<usize> = entry
+                    .tx
+                    .inputs
+                    .iter()
+                    .filter_map(|input| positions.get(&input.previous_output.txid).copied())
+                    .collect();
+                depends.sort_unstable();
+                depends.dedup();
+                positions.insert(member, selected.len());
+                selected.push(TemplateTransaction {
+                    tx: entry.tx.clone(),
+                    fee,
+                    sigop_cost: member_sigops,
+                    depends,
+                });
+                weight += member_weight;
+                sigop_cost += member_sigops;
+                packages.remove(&member);
+                // Descendants left no longer carry it in their packages.
+                for descendant in mempool.descendants(&member) {
+                    if let Some(package) = packages.get_mut(&descendant) {
+                        package.ancestors.remove(&member);
+                        package.fee -= fee;
+                        package.vsize -= vsize;
+                        package.weight -= member_weight;
+                        package.sigop_cost -= member_sigops;
+                    }
+                }
+            }
+        }
+        selected
+    }
+}
+
+impl BlockTemplate {
+    // BIP141 commitment to the wtxids, the coinbase's counting as zeros, with an all
+    // zeros reserved value.
+    fn commitment_output(&self) -> TxOut {
+        let wtxids: Vec<[u8; 32]> = [[0; 32]]
+            .into_iter()
+            .chain(self.transactions.iter().map(|tx| tx.tx.wtxid()))
+            .collect();
+        let mut data = merkle_root(&wtxids).0.to_vec();
+        data.extend([0; 32]);
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend(hash256(&data));
+        TxOut::new(0, script.into())
+    }
+
+    // A coinbase paying the template's value to script_pubkey. Its scriptSig starts
+    // with the height, as BIP34 requires, followed by extra_nonce, which miners vary
+    // to get more headers to hash.
+    pub fn coinbase(&self, script_pubkey: Script, extra_nonce: &[u8]) -> Transaction {
+        let mut script_sig = Script::new();
+        script_sig
+            .push_int(self.height as i64)
+            .push_slice(extra_nonce);
+        let mut input = TxIn::new(OutPoint::null(), script_sig, 0xffffffff);
+        let mut outputs = vec![TxOut::new(self.coinbase_value, script_pubkey)];
+        if let Some(commitment) = &self.witness_commitment {
+            input.witness = Witness::from_elements(vec![vec![0; 32]]);
+            outputs.push(commitment.clone());
+        }
+        Transaction::new(2, vec![input], outputs, 0)
+    }
+
+    // The block of coinbase and the template's transactions, left to find a nonce
+    // for.
+    pub fn block(&self, coinbase: Transaction) -> Block {
+        let transactions = [coinbase]
+            .into_iter()
+            .chain(self.transactions.iter().map(|tx| tx.tx.clone()))
+            .collect();
+        let mut block = Block::new(self.header, transactions);
+        block.header.merkle_root = block.compute_merkle_root();
+        block
+    }
+}
+
+#[cfg(test)]
+mod template_tests {
+    use super::*;
+    use crate::chain::{check_block, MemoryBackend};
+    use crate::network::Network;
+    use crate::test_util::{self, mature_chain, mine_on, solve};
+    use crate::validation::{NoScriptVerification, COIN, COINBASE_MATURITY};
+
+    fn p2wpkh() -> Script {
+        let mut script = vec![0x00, 0x14];
+        script.extend([0xab; 20]);
+        script.into()
+    }
+
+    fn mine(chain: &mut ChainState, block: Block) {
+        let mut block = block;
+        solve(&mut block.header);
+        let update = chain.accept_block(block.clone()).unwrap();
+        assert_eq!(update.connected, vec![block.hash()]);
+    }
+
+    fn spend(outpoint: OutPoint, value: u64) -> Transaction {
+        let mut script_sig = vec![72];
+        script_sig.extend([0x30; 72]);
+        let input = TxIn::new(outpoint, script_sig.into(), 0xffffffff);
+        Transaction::new(2, vec![input], vec![TxOut::new(value, p2wpkh())], 0)
+    }
+
+    #[test]
+    fn test_packages_by_feerate() {
+        let mut chain = ChainState::new(Network::Regtest, MemoryBackend::default());
+        let mut coinbases = Vec::new();
+        for _ in 0..COINBASE_MATURITY + 2 {
+            let template = BlockAssembler::new().create(&chain, &Mempool::new(), 0);
+            let coinbase = template.coinbase(p2wpkh(), &[]);
+            coinbases.push(coinbase.txid());
+            mine(&mut chain, template.block(coinbase));
+        }
+        let subsidy = Network::Regtest.block_subsidy(1);
+        let mut mempool = Mempool::new();
+        let mut accept = |tx: &Transaction| {
+            let height = chain.height() + 1;
+            mempool
+                .accept_with(
+                    tx.clone(),
+                    chain.utxos(),
+                    height,
+                    VerificationFlags::STANDARD,
+                    &NoScriptVerification,
+                )
+                .unwrap();
+        };
+        // The parent pays little, but with its child more than the other transaction.
+        let parent = spend(OutPoint::new(coinbases[0], 0), subsidy - 200);
+        let child = spend(OutPoint::new(parent.txid(), 0), subsidy - 20_200);
+        let other = spend(OutPoint::new(coinbases[1], 0), subsidy - 5_000);
+        for tx in [&parent, &child, &other] {
+            accept(tx);
+        }
+
+        let template = BlockAssembler::new().create(&chain, &mempool, 0);
+        let txids: Vec<[u8; 32]> = template
+            .transactions
+            .iter()
+            .map(|tx| tx.tx.txid())
+            .collect();
+        assert_eq!(txids, [parent.txid(), child.txid(), other.txid()]);
+        assert_eq!(template.transactions[1].depends, [0]);
+        assert_eq!(template.coinbase_value, subsidy + 25_200);
+        assert_eq!(template.height, COINBASE_MATURITY + 3);
+        assert!(template.header.timestamp >= template.min_time);
+        let block = template.block(template.coinbase(p2wpkh(), b"extra"));
+        assert_eq!(check_block(&block), Ok(()));
+        assert!(block.validate_witness_commitment());
+
+        // Only the other transaction fits in less weight than both of the package.
+        let max_weight = other.weight() + COINBASE_RESERVED_WEIGHT;
+        let assembler = BlockAssembler::new().with_max_weight(max_weight);
+        let template = assembler.create(&chain, &mempool, 0);
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(template.transactions[0].tx, other);
+        let assembler = BlockAssembler::new().with_min_feerate(FeeRate::from_sat_per_vb(100));
+        assert!(assembler
+            .create(&chain, &mempool, 0)
+            .transactions
+            .is_empty());
+    }
+
+    #[test]
+    fn test_sequence_locked_transactions_wait() {
+        let (mut chain, coin) = mature_chain();
+        let funding = test_util::spend(&[coin], 49 * COIN, 0xffffffff);
+        let block = mine_on(&chain, chain.tip().hash, 0, vec![funding.clone()]);
+        chain.accept_block(block).unwrap();
+        // Spending the coin, which has one confirmation, two blocks after it.
+        let mut locked = test_util::spend(&[OutPoint::new(funding.txid(), 0)], 48 * COIN, 2);
+        locked.outputs[0].script_pubkey = p2wpkh();
+        let mut mempool = Mempool::new();
+        mempool
+            .accept_with(
+                locked.clone(),
+                chain.utxos(),
+                chain.height() + 1,
+                VerificationFlags::STANDARD,
+                &NoScriptVerification,
+            )
+            .unwrap();
+
+        let template = BlockAssembler::new().create(&chain, &mempool, 0);
+        assert!(template.transactions.is_empty());
+        mine(&mut chain, template.block(template.coinbase(p2wpkh(), &[])));
+        let template = BlockAssembler::new().create(&chain, &mempool, 0);
+        assert_eq!(template.transactions[0].tx, locked);
+        mine(&mut chain, template.block(template.coinbase(p2wpkh(), &[])));
+        let locked_output = OutPoint::new(locked.txid(), 0);
+        assert!(chain.utxos().get_coin(&locked_output).is_some());
+    }
+}
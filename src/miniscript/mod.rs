@@ -0,0 +1,736 @@
+// Miniscript: a structured subset of script that can be analyzed for correctness and
+// malleability, and satisfied generically. Expressions use the syntax of
+// bitcoin.sipa.be/miniscript and Bitcoin Core, in the P2WSH context, with keys given
+// as compressed public keys in hex.
+pub mod satisfy;
+pub mod types;
+
+use crate::ecc::S256Point;
+use crate::policy::MAX_STANDARD_P2WSH_SCRIPT_SIZE;
+use crate::script::{Opcode, Script};
+use crate::types::errors::Errors;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use types::{t, Type};
+
+// Most keys in multi(), as for OP_CHECKMULTISIG.
+pub const MAX_MULTI_KEYS: usize = 20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Terminal {
+    False,
+    True,
+    PkK(S256Point),
+    PkH(S256Point),
+    Older(u32),
+    After(u32),
+    Sha256([u8; 32]),
+    Hash256([u8; 32]),
+    Ripemd160([u8; 20]),
+    Hash160([u8; 20]),
+    // Wrappers a:, s:, c:, d:, v:, j: and n:.
+    Alt(Box<Miniscript>),
+    Swap(Box<Miniscript>),
+    Check(Box<Miniscript>),
+    DupIf(Box<Miniscript>),
+    Verify(Box<Miniscript>),
+    NonZero(Box<Miniscript>),
+    ZeroNotEqual(Box<Miniscript>),
+    AndV(Box<Miniscript>, Box<Miniscript>),
+    AndB(Box<Miniscript>, Box<Miniscript>),
+    AndOr(Box<Miniscript>, Box<Miniscript>, Box<Miniscript>),
+    OrB(Box<Miniscript>, Box<Miniscript>),
+    OrC(Box<Miniscript>, Box<Miniscript>),
+    OrD(Box<Miniscript>, Box<Miniscript>),
+    OrI(Box<Miniscript>, Box<Miniscript>),
+    Thresh(usize, Vec<Miniscript>),
+    Multi(usize, Vec<S256Point>),
+}
+
+// A fragment together with its type. Only well typed fragments can be built.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Miniscript {
+    node: Terminal,
+    ty: Type,
+}
+
+impl Miniscript {
+    pub fn new(node: Terminal) -> Result<Self, Errors> {
+        match &node {
+            Terminal::Older(n) | Terminal::After(n) if *n == 0 || *n >= 1 << 31 => {
+                return Err(Errors::InvalidMiniscript(format!("timelock {n}")));
+            }
+            Terminal::Thresh(k, subs) if *k == 0 || *k > subs.len() => {
+                return Err(Errors::InvalidMiniscript(format!("threshold {k}")));
+            }
+            Terminal::Multi(k, keys)
+                if *k == 0 || *k > keys.len() || keys.len() > MAX_MULTI_KEYS =>
+            {
+                return Err(Errors::InvalidMiniscript(format!("threshold {k}")));
+            }
+            _ => {}
+        }
+        let ty = compute_type(&node);
+        let miniscript = Miniscript { node, ty };
+        if !ty.is_valid() {
+            return Err(Errors::InvalidMiniscript(format!(
+                "{miniscript} is not well typed"
+            )));
+        }
+        Ok(miniscript)
+    }
+
+    pub fn node(&self) -> &Terminal {
+        &self.node
+    }
+
+    pub fn ty(&self) -> Type {
+        self.ty
+    }
+
+    // Only B expressions can be used as a whole script.
+    pub fn is_valid_top_level(&self) -> bool {
+        self.ty.has(t("B"))
+    }
+
+    pub fn requires_signature(&self) -> bool {
+        self.ty.has(t("s"))
+    }
+
+    pub fn is_non_malleable(&self) -> bool {
+        self.ty.has(t("m"))
+    }
+
+    // Valid at the top level, needs a signature on every path, can be satisfied
+    // without malleability, does not mix height and time locks, repeats no key and
+    // fits in a standard P2WSH script. The per path opcode limit is not checked.
+    pub fn is_sane(&self) -> bool {
+        let keys = self.keys();
+        let unique: HashSet<Vec<u8>> = keys.iter().map(|key| key.sec(true)).collect();
+        self.is_valid_top_level()
+            && self.requires_signature()
+            && self.is_non_malleable()
+            && self.ty.has(t("k"))
+            && unique.len() == keys.len()
+            && self.script_size() <= MAX_STANDARD_P2WSH_SCRIPT_SIZE
+    }
+
+    // Every key in the expression, in order of appearance.
+    pub fn keys(&self) -> Vec<&S256Point> {
+        let mut keys = Vec::new();
+        self.collect_keys(&mut keys);
+        keys
+    }
+
+    fn collect_keys<'a>(&'a self, keys: &mut Vec<&'a S256Point>) {
+        match &self.node {
+            Terminal::PkK(key) | Terminal::PkH(key) => keys.push(key),
+            Terminal::Multi(_, multi) => keys.extend(multi),
+            _ => self.subs().for_each(|sub| sub.collect_keys(keys)),
+        }
+    }
+
+    // The direct sub expressions.
+    pub fn subs(&self) -> impl Iterator<Item = &Miniscript> {
+        let subs: Vec<&Miniscript> = match &self.node {
+            Terminal::Alt(x)
+            | Terminal::Swap(x)
+            | Terminal::Check(x)
+            | Terminal::DupIf(x)
+            | Terminal::Verify(x)
+            | Terminal::NonZero(x)
+            | Terminal::ZeroNotEqual(x) => vec![x],
+            Terminal::AndV(x, y)
+            | Terminal::AndB(x, y)
+            | Terminal::OrB(x, y)
+            | Terminal::OrC(x, y)
+            | Terminal::OrD(x, y)
+            | Terminal::OrI(x, y) => vec![x, y],
+            Terminal::AndOr(x, y, z) => vec![x, y, z],
+            Terminal::Thresh(_, subs) => subs.iter().collect(),
+            _ => vec![],
+        };
+        subs.into_iter()
+    }
+
+    pub fn encode(&self) -> Script {
+        let mut script = Script::new();
+        self.encode_into(&mut script);
+        script
+    }
+
+    pub fn script_size(&self) -> usize {
+        self.encode().len()
+    }
+
+    fn encode_into(&self, script: &mut Script) {
+        match &self.node {
+            Terminal::False => {
+                script.push_opcode(Opcode::OP_0);
+            }
+            Terminal::True => {
+                script.push_opcode(Opcode::OP_1);
+            }
+            Terminal::PkK(key) => {
+                script.push_slice(&key.sec(true));
+            }
+            Terminal::PkH(key) => {
+                script
+                    .push_opcode(Opcode::OP_DUP)
+                    .push_opcode(Opcode::OP_HASH160)
+                    .push_slice(&key.hash160(true))
+                    .push_opcode(Opcode::OP_EQUALVERIFY);
+            }
+            Terminal::Older(n) => {
+                script
+                    .push_int(*n as i64)
+                    .push_opcode(Opcode::OP_CHECKSEQUENCEVERIFY);
+            }
+            Terminal::After(n) => {
+                script
+                    .push_int(*n as i64)
+                    .push_opcode(Opcode::OP_CHECKLOCKTIMEVERIFY);
+            }
+            Terminal::Sha256(hash) => encode_hash(script, Opcode::OP_SHA256, hash),
+            Terminal::Hash256(hash) => encode_hash(script, Opcode::OP_HASH256, hash),
+            Terminal::Ripemd160(hash) => encode_hash(script, Opcode::OP_RIPEMD160, hash),
+            Terminal::Hash160(hash) => encode_hash(script, Opcode::OP_HASH160, hash),
+            Terminal::Alt(x) => {
+                script.push_opcode(Opcode::OP_TOALTSTACK);
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_FROMALTSTACK);
+            }
+            Terminal::Swap(x) => {
+                script.push_opcode(Opcode::OP_SWAP);
+                x.encode_into(script);
+            }
+            Terminal::Check(x) => {
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_CHECKSIG);
+            }
+            Terminal::DupIf(x) => {
+                script
+                    .push_opcode(Opcode::OP_DUP)
+                    .push_opcode(Opcode::OP_IF);
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_ENDIF);
+            }
+            Terminal::Verify(x) => {
+                x.encode_into(script);
+                if x.ty.has(t("x")) {
+                    script.push_opcode(Opcode::OP_VERIFY);
+                } else {
+                    // EQUAL, CHECKSIG, CHECKMULTISIG and NUMEQUAL are each followed
+                    // by their VERIFY form.
+                    let mut bytes = std::mem::take(script).into_bytes();
+                    if let Some(last) = bytes.last_mut() {
+                        *last += 1;
+                    }
+                    *script = Script::from_bytes(bytes);
+                }
+            }
+            Terminal::NonZero(x) => {
+                script
+                    .push_opcode(Opcode::OP_SIZE)
+                    .push_opcode(Opcode::OP_0NOTEQUAL)
+                    .push_opcode(Opcode::OP_IF);
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_ENDIF);
+            }
+            Terminal::ZeroNotEqual(x) => {
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_0NOTEQUAL);
+            }
+            Terminal::AndV(x, y) => {
+                x.encode_into(script);
+                y.encode_into(script);
+            }
+            Terminal::AndB(x, y) => {
+                x.encode_into(script);
+                y.encode_into(script);
+                script.push_opcode(Opcode::OP_BOOLAND);
+            }
+            Terminal::AndOr(x, y, z) => {
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_NOTIF);
+                z.encode_into(script);
+                script.push_opcode(Opcode::OP_ELSE);
+                y.encode_into(script);
+                script.push_opcode(Opcode::OP_ENDIF);
+            }
+            Terminal::OrB(x, z) => {
+                x.encode_into(script);
+                z.encode_into(script);
+                script.push_opcode(Opcode::OP_BOOLOR);
+            }
+            Terminal::OrC(x, z) => {
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_NOTIF);
+                z.encode_into(script);
+                script.push_opcode(Opcode::OP_ENDIF);
+            }
+            Terminal::OrD(x, z) => {
+                x.encode_into(script);
+                script
+                    .push_opcode(Opcode::OP_IFDUP)
+                    .push_opcode(Opcode::OP_NOTIF);
+                z.encode_into(script);
+                script.push_opcode(Opcode::OP_ENDIF);
+            }
+            Terminal::OrI(x, z) => {
+                script.push_opcode(Opcode::OP_IF);
+                x.encode_into(script);
+                script.push_opcode(Opcode::OP_ELSE);
+                z.encode_into(script);
+                script.push_opcode(Opcode::OP_ENDIF);
+            }
+            Terminal::Thresh(k, subs) => {
+                for (i, sub) in subs.iter().enumerate() {
+                    sub.encode_into(script);
+                    if i > 0 {
+                        script.push_opcode(Opcode::OP_ADD);
+                    }
+                }
+                script.push_int(*k as i64).push_opcode(Opcode::OP_EQUAL);
+            }
+            Terminal::Multi(k, keys) => {
+                script.push_int(*k as i64);
+                for key in keys {
+                    script.push_slice(&key.sec(true));
+                }
+                script
+                    .push_int(keys.len() as i64)
+                    .push_opcode(Opcode::OP_CHECKMULTISIG);
+            }
+        }
+    }
+
+    // The wrapper letter and wrapped expression, including the t:, l: and u: forms
+    // of and_v and or_i. c: around a key is printed as pk() or pkh() instead.
+    fn as_wrapper(&self) -> Option<(char, &Miniscript)> {
+        match &self.node {
+            Terminal::Alt(x) => Some(('a', x)),
+            Terminal::Swap(x) => Some(('s', x)),
+            Terminal::Check(x) if !matches!(x.node, Terminal::PkK(_) | Terminal::PkH(_)) => {
+                Some(('c', x))
+            }
+            Terminal::DupIf(x) => Some(('d', x)),
+            Terminal::Verify(x) => Some(('v', x)),
+            Terminal::NonZero(x) => Some(('j', x)),
+            Terminal::ZeroNotEqual(x) => Some(('n', x)),
+            Terminal::AndV(x, y) if y.node == Terminal::True => Some(('t', x)),
+            Terminal::OrI(x, y) if x.node == Terminal::False => Some(('l', y)),
+            Terminal::OrI(x, y) if y.node == Terminal::False => Some(('u', x)),
+            _ => None,
+        }
+    }
+}
+
+fn compute_type(node: &Terminal) -> Type {
+    match node {
+        Terminal::False => types::FALSE,
+        Terminal::True => types::TRUE,
+        Terminal::PkK(_) => types::PK_K,
+        Terminal::PkH(_) => types::PK_H,
+        Terminal::Older(n) => types::older(*n),
+        Terminal::After(n) => types::after(*n),
+        Terminal::Sha256(_)
+        | Terminal::Hash256(_)
+        | Terminal::Ripemd160(_)
+        | Terminal::Hash160(_) => types::HASH,
+        Terminal::Alt(x) => types::alt(x.ty),
+        Terminal::Swap(x) => types::swap(x.ty),
+        Terminal::Check(x) => types::check(x.ty),
+        Terminal::DupIf(x) => types::dup_if(x.ty),
+        Terminal::Verify(x) => types::verify(x.ty),
+        Terminal::NonZero(x) => types::non_zero(x.ty),
+        Terminal::ZeroNotEqual(x) => types::zero_not_equal(x.ty),
+        Terminal::AndV(x, y) => types::and_v(x.ty, y.ty),
+        Terminal::AndB(x, y) => types::and_b(x.ty, y.ty),
+        Terminal::AndOr(x, y, z) => types::and_or(x.ty, y.ty, z.ty),
+        Terminal::OrB(x, y) => types::or_b(x.ty, y.ty),
+        Terminal::OrC(x, y) => types::or_c(x.ty, y.ty),
+        Terminal::OrD(x, y) => types::or_d(x.ty, y.ty),
+        Terminal::OrI(x, y) => types::or_i(x.ty, y.ty),
+        Terminal::Thresh(k, subs) => {
+            types::thresh(*k, &subs.iter().map(|sub| sub.ty).collect::<Vec<_>>())
+        }
+        Terminal::Multi(..) => types::MULTI,
+    }
+}
+
+// SIZE <32> EQUALVERIFY <hash op> <hash> EQUAL, so preimages must be 32 bytes.
+fn encode_hash(script: &mut Script, opcode: Opcode, hash: &[u8]) {
+    script
+        .push_opcode(Opcode::OP_SIZE)
+        .push_int(32)
+        .push_opcode(Opcode::OP_EQUALVERIFY)
+        .push_opcode(opcode)
+        .push_slice(hash)
+        .push_opcode(Opcode::OP_EQUAL);
+}
+
+impl FromStr for Miniscript {
+    type Err = Errors;
+
+    fn from_str(expression: &str) -> Result<Self, Errors> {
+        parse(expression.trim())
+    }
+}
+
+fn parse(expression: &str) -> Result<Miniscript, Errors> {
+    let invalid = || Errors::InvalidMiniscript(expression.to_string());
+    let open = expression.find('(').unwrap_or(expression.len());
+    let (wrappers, body) = match expression[..open].find(':') {
+        Some(colon) => (&expression[..colon], &expression[colon + 1..]),
+        None => ("", expression),
+    };
+    let (name, args) = match body.find('(') {
+        Some(open) if body.ends_with(')') => {
+            let args = split_args(&body[open + 1..body.len() - 1]).ok_or_else(invalid)?;
+            (&body[..open], args)
+        }
+        Some(_) => return Err(invalid()),
+        None => (body, Vec::new()),
+    };
+    let mut miniscript = fragment(name, &args)?;
+    for wrapper in wrappers.chars().rev() {
+        miniscript = wrap(wrapper, miniscript)?;
+    }
+    Ok(miniscript)
+}
+
+fn fragment(name: &str, args: &[&str]) -> Result<Miniscript, Errors> {
+    let sub = |i: usize| parse(args[i]).map(Box::new);
+    let node = match (name, args.len()) {
+        ("0", 0) => Terminal::False,
+        ("1", 0) => Terminal::True,
+        ("pk_k", 1) => Terminal::PkK(parse_key(args[0])?),
+        ("pk_h", 1) => Terminal::PkH(parse_key(args[0])?),
+        ("pk", 1) => return wrap('c', Miniscript::new(Terminal::PkK(parse_key(args[0])?))?),
+        ("pkh", 1) => return wrap('c', Miniscript::new(Terminal::PkH(parse_key(args[0])?))?),
+        ("older", 1) => Terminal::Older(parse_number(args[0])?),
+        ("after", 1) => Terminal::After(parse_number(args[0])?),
+        ("sha256", 1) => Terminal::Sha256(parse_hash(args[0])?),
+        ("hash256", 1) => Terminal::Hash256(parse_hash(args[0])?),
+        ("ripemd160", 1) => Terminal::Ripemd160(parse_hash(args[0])?),
+        ("hash160", 1) => Terminal::Hash160(parse_hash(args[0])?),
+        ("and_v", 2) => Terminal::AndV(sub(0)?, sub(1)?),
+        ("and_b", 2) => Terminal::AndB(sub(0)?, sub(1)?),
+        ("and_n", 2) => Terminal::AndOr(
+            sub(0)?,
+            sub(1)?,
+            Box::new(Miniscript::new(Terminal::False)?),
+        ),
+        ("andor", 3) => Terminal::AndOr(sub(0)?, sub(1)?, sub(2)?),
+        ("or_b", 2) => Terminal::OrB(sub(0)?, sub(1)?),
+        ("or_c", 2) => Terminal::OrC(sub(0)?, sub(1)?),
+        ("or_d", 2) => Terminal::OrD(sub(0)?, sub(1)?),
+        ("or_i", 2) => Terminal::OrI(sub(0)?, sub(1)?),
+        ("thresh", n) if n >= 2 => Terminal::Thresh(
+            parse_number(args[0])? as usize,
+            args[1..]
+                .iter()
+                .map(|arg| parse(arg))
+                .collect::<Result<_, _>>()?,
+        ),
+        ("multi", n) if n >= 2 => Terminal::Multi(
+            parse_number(args[0])? as usize,
+            args[1..]
+                .iter()
+                .map(|arg| parse_key(arg))
+                .collect::<Result<_, _>>()?,
+        ),
+        _ => {
+            return Err(Errors::InvalidMiniscript(format!(
+                "unknown fragment {name}"
+            )))
+        }
+    };
+    Miniscript::new(node)
+}
+
+fn wrap(wrapper: char, x: Miniscript) -> Result<Miniscript, Errors> {
+    let x = Box::new(x);
+    let node = match wrapper {
+        'a' => Terminal::Alt(x),
+        's' => Terminal::Swap(x),
+        'c' => Terminal::Check(x),
+        'd' => Terminal::DupIf(x),
+        'v' => Terminal::Verify(x),
+        'j' => Terminal::NonZero(x),
+        'n' => Terminal::ZeroNotEqual(x),
+        't' => Terminal::AndV(x, Box::new(Miniscript::new(Terminal::True)?)),
+        'l' => Terminal::OrI(Box::new(Miniscript::new(Terminal::False)?), x),
+        'u' => Terminal::OrI(x, Box::new(Miniscript::new(Terminal::False)?)),
+        _ => {
+            return Err(Errors::InvalidMiniscript(format!(
+                "unknown wrapper {wrapper}:"
+            )))
+        }
+    };
+    Miniscript::new(node)
+}
+
+// Splits on the commas outside of parentheses, None if they are unbalanced.
+fn split_args(args: &str) -> Option<Vec<&str>> {
+    let mut result = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (i, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                result.push(&args[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(&args[start..]);
+    (depth == 0).then_some(result)
+}
+
+fn parse_key(arg: &str) -> Result<S256Point, Errors> {
+    match hex::decode(arg) {
+        Ok(bytes) if bytes.len() == 33 => S256Point::parse_sec(&bytes),
+        _ => Err(Errors::InvalidMiniscript(format!("key {arg}"))),
+    }
+}
+
+fn parse_number(arg: &str) -> Result<u32, Errors> {
+    if arg.is_empty() || !arg.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Errors::InvalidMiniscript(format!("number {arg}")));
+    }
+    arg.parse()
+        .map_err(|_| Errors::InvalidMiniscript(format!("number {arg}")))
+}
+
+fn parse_hash<const N: usize>(arg: &str) -> Result<[u8; N], Errors> {
+    hex::decode(arg)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Errors::InvalidMiniscript(format!("hash {arg}")))
+}
+
+impl fmt::Display for Miniscript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut node = self;
+        let mut wrappers = String::new();
+        while let Some((wrapper, sub)) = node.as_wrapper() {
+            wrappers.push(wrapper);
+            node = sub;
+        }
+        if !wrappers.is_empty() {
+            write!(f, "{wrappers}:")?;
+        }
+        let key = |key: &S256Point| hex::encode(key.sec(true));
+        match &node.node {
+            Terminal::False => write!(f, "0"),
+            Terminal::True => write!(f, "1"),
+            Terminal::PkK(k) => write!(f, "pk_k({})", key(k)),
+            Terminal::PkH(k) => write!(f, "pk_h({})", key(k)),
+            Terminal::Check(x) => match &x.node {
+                Terminal::PkK(k) => write!(f, "pk({})", key(k)),
+                Terminal::PkH(k) => write!(f, "pkh({})", key(k)),
+                _ => unreachable!("other c: expressions are wrappers"),
+            },
+            Terminal::Older(n) => write!(f, "older({n})"),
+            Terminal::After(n) => write!(f, "after({n})"),
+            Terminal::Sha256(hash) => write!(f, "sha256({})", hex::encode(hash)),
+            Terminal::Hash256(hash) => write!(f, "hash256({})", hex::encode(hash)),
+            Terminal::Ripemd160(hash) => write!(f, "ripemd160({})", hex::encode(hash)),
+            Terminal::Hash160(hash) => write!(f, "hash160({})", hex::encode(hash)),
+            Terminal::AndV(x, y) => write!(f, "and_v({x},{y})"),
+            Terminal::AndB(x, y) => write!(f, "and_b({x},{y})"),
+            Terminal::AndOr(x, y, z) if z.node == Terminal::False => write!(f, "and_n({x},{y})"),
+            Terminal::AndOr(x, y, z) => write!(f, "andor({x},{y},{z})"),
+            Terminal::OrB(x, y) => write!(f, "or_b({x},{y})"),
+            Terminal::OrC(x, y) => write!(f, "or_c({x},{y})"),
+            Terminal::OrD(x, y) => write!(f, "or_d({x},{y})"),
+            Terminal::OrI(x, y) => write!(f, "or_i({x},{y})"),
+            Terminal::Thresh(k, subs) => {
+                write!(f, "thresh({k}")?;
+                subs.iter().try_for_each(|sub| write!(f, ",{sub}"))?;
+                write!(f, ")")
+            }
+            Terminal::Multi(k, keys) => {
+                write!(f, "multi({k}")?;
+                keys.iter().try_for_each(|k| write!(f, ",{}", key(k)))?;
+                write!(f, ")")
+            }
+            _ => unreachable!("wrappers are printed as prefixes"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod miniscript_tests {
+    use super::*;
+    use crate::ecc::PrivateKey;
+    use crate::helper::sha256;
+    use num_bigint::BigInt;
+
+    fn key(secret: u32) -> String {
+        let key = PrivateKey::new(BigInt::from(secret)).unwrap();
+        hex::encode(key.public_key().sec(true))
+    }
+
+    fn parse(expression: &str) -> Miniscript {
+        expression.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        let (a, b) = (key(1), key(2));
+        for expression in [
+            format!("pk({a})"),
+            format!("pkh({a})"),
+            format!("and_v(v:pk({a}),or_d(pk({b}),older(12960)))"),
+            format!("thresh(2,pk({a}),s:pk({b}),sln:older(12))"),
+            format!("and_n(pk({a}),after(500001))"),
+            format!("or_i(tv:pk({a}),u:pk({b}))"),
+            format!("multi(1,{a},{b})"),
+            format!("c:and_v(v:sha256({}),pk_k({a}))", hex::encode([7; 32])),
+        ] {
+            assert_eq!(parse(&expression).to_string(), expression);
+        }
+        // Aliases are printed in their short form.
+        let long = format!("c:pk_k({a})");
+        assert_eq!(parse(&long).to_string(), format!("pk({a})"));
+        let long = format!("or_i(0,pk({a}))");
+        assert_eq!(parse(&long).to_string(), format!("l:pk({a})"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let a = key(1);
+        for expression in [
+            format!("pk({a}"),
+            format!("pk({a},{a})"),
+            "pk(02)".to_string(),
+            "older(0)".to_string(),
+            "older(+5)".to_string(),
+            format!("thresh(3,pk({a}),s:pk({a}))"),
+            format!("multi(0,{a})"),
+            format!("x:pk({a})"),
+            "unknown(1)".to_string(),
+            "sha256(00)".to_string(),
+        ] {
+            assert!(
+                expression.parse::<Miniscript>().is_err(),
+                "{expression} should not parse"
+            );
+        }
+        // and_v needs a V on the left.
+        assert!(matches!(
+            format!("and_v(pk({a}),pk({a}))").parse::<Miniscript>(),
+            Err(Errors::InvalidMiniscript(_))
+        ));
+        // or_b needs a W on the right.
+        assert!(format!("or_b(pk({a}),pk({a}))")
+            .parse::<Miniscript>()
+            .is_err());
+        assert!(format!("or_b(pk({a}),s:pk({a}))")
+            .parse::<Miniscript>()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_types() {
+        let (a, b) = (key(1), key(2));
+        assert_eq!(parse(&format!("pk({a})")).ty().to_string(), "Bonduesmk");
+        assert_eq!(parse(&format!("v:pk({a})")).ty().to_string(), "Vonfsmxk");
+        assert_eq!(parse("older(144)").ty().to_string(), "Bzfmxhk");
+
+        let sane = parse(&format!("and_v(v:pk({a}),or_d(pk({b}),older(12)))"));
+        assert!(sane.is_sane());
+        assert_eq!(sane.keys().len(), 2);
+
+        // The timelock path needs no signature.
+        let unsigned = parse(&format!("or_d(pk({a}),older(12))"));
+        assert!(unsigned.is_valid_top_level() && unsigned.is_non_malleable());
+        assert!(!unsigned.requires_signature() && !unsigned.is_sane());
+
+        // Either preimage can be swapped for the other by a third party.
+        let hashes = format!(
+            "and_v(v:pk({a}),or_b(sha256({}),a:sha256({})))",
+            hex::encode([1; 32]),
+            hex::encode([2; 32])
+        );
+        assert!(!parse(&hashes).is_non_malleable());
+
+        // Heights and times cannot both be required.
+        let mixed = parse(&format!(
+            "and_v(v:pk({a}),and_v(v:after(100),after(500000001)))"
+        ));
+        assert!(!mixed.ty().has(t("k")) && !mixed.is_sane());
+
+        let repeated = parse(&format!("and_v(v:pk({a}),pk({a}))"));
+        assert!(!repeated.is_sane());
+    }
+
+    #[test]
+    fn test_encode() {
+        let (a, b) = (key(1), key(2));
+        let (key_a, key_b) = (hex::decode(&a).unwrap(), hex::decode(&b).unwrap());
+
+        let miniscript = parse(&format!("and_v(v:pk({a}),or_d(pk({b}),older(12)))"));
+        let mut expected = Script::new();
+        expected
+            .push_slice(&key_a)
+            .push_opcode(Opcode::OP_CHECKSIGVERIFY)
+            .push_slice(&key_b)
+            .push_opcode(Opcode::OP_CHECKSIG)
+            .push_opcode(Opcode::OP_IFDUP)
+            .push_opcode(Opcode::OP_NOTIF)
+            .push_int(12)
+            .push_opcode(Opcode::OP_CHECKSEQUENCEVERIFY)
+            .push_opcode(Opcode::OP_ENDIF);
+        assert_eq!(miniscript.encode(), expected);
+        assert_eq!(miniscript.script_size(), expected.len());
+
+        let miniscript = parse(&format!("thresh(2,pk({a}),s:pk({b}),sln:older(12))"));
+        let mut expected = Script::new();
+        expected
+            .push_slice(&key_a)
+            .push_opcode(Opcode::OP_CHECKSIG)
+            .push_opcode(Opcode::OP_SWAP)
+            .push_slice(&key_b)
+            .push_opcode(Opcode::OP_CHECKSIG)
+            .push_opcode(Opcode::OP_ADD)
+            .push_opcode(Opcode::OP_SWAP)
+            .push_opcode(Opcode::OP_IF)
+            .push_opcode(Opcode::OP_0)
+            .push_opcode(Opcode::OP_ELSE)
+            .push_int(12)
+            .push_opcode(Opcode::OP_CHECKSEQUENCEVERIFY)
+            .push_opcode(Opcode::OP_0NOTEQUAL)
+            .push_opcode(Opcode::OP_ENDIF)
+            .push_opcode(Opcode::OP_ADD)
+            .push_int(2)
+            .push_opcode(Opcode::OP_EQUAL);
+        assert_eq!(miniscript.encode(), expected);
+
+        // v: merges into a final EQUAL, and needs OP_VERIFY after anything else.
+        let hash = sha256(&[5; 32]);
+        let miniscript = parse(&format!("and_v(v:sha256({}),pkh({a}))", hex::encode(hash)));
+        let script = miniscript.encode();
+        assert_eq!(script[..4], [0x82, 0x01, 0x20, 0x88]);
+        assert_eq!(script[38], Opcode::OP_EQUALVERIFY.to_u8());
+        assert_eq!(
+            script[39..42],
+            [0x76, 0xa9, 0x14],
+            "pkh() follows the hash check"
+        );
+        assert_eq!(
+            parse("and_v(v:older(1),1)").encode().as_bytes(),
+            [0x51, 0xb2, 0x69, 0x51]
+        );
+        assert_eq!(
+            parse(&format!("multi(1,{a},{b})")).encode()[0],
+            Opcode::OP_1.to_u8()
+        );
+    }
+}
@@ -0,0 +1,700 @@
+// Witness generation for miniscripts, following ProduceInput in Bitcoin Core's
+// miniscript.h: every fragment gets its best satisfaction and dissatisfaction,
+// built bottom-up and chosen so third parties cannot malleate the result.
+use super::{Miniscript, Terminal};
+use crate::ecc::S256Point;
+use crate::helper::{hash160, hash256, sha256};
+use crate::transaction::{LockTime, Sequence, Witness};
+use crate::types::errors::Errors;
+use ripemd::{Digest, Ripemd160};
+use std::fmt;
+
+// What a satisfaction can draw on. Signatures include the sighash byte.
+pub trait Satisfier {
+    fn signature(&self, _key: &S256Point) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn sha256_preimage(&self, _hash: &[u8; 32]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn hash256_preimage(&self, _hash: &[u8; 32]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn ripemd160_preimage(&self, _hash: &[u8; 20]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn hash160_preimage(&self, _hash: &[u8; 20]) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Whether the spending input's nSequence satisfies older(n).
+    fn check_older(&self, _n: u32) -> bool {
+        false
+    }
+
+    // Whether the spending transaction's nLockTime satisfies after(n).
+    fn check_after(&self, _n: u32) -> bool {
+        false
+    }
+}
+
+// Signatures and preimages collected for a spend, with the timelock fields of the
+// spending transaction.
+#[derive(Clone, Debug, Default)]
+pub struct SatisfactionData {
+    signatures: Vec<(Vec<u8>, Vec<u8>)>,
+    preimages: Vec<Vec<u8>>,
+    sequence: Option<Sequence>,
+    locktime: Option<LockTime>,
+}
+
+impl SatisfactionData {
+    pub fn new() -> Self {
+        SatisfactionData::default()
+    }
+
+    pub fn with_signature(mut self, key: &S256Point, signature: Vec<u8>) -> Self {
+        self.signatures.push((key.sec(true), signature));
+        self
+    }
+
+    // Matched against every hash fragment, whatever the hash function.
+    pub fn with_preimage(mut self, preimage: Vec<u8>) -> Self {
+        self.preimages.push(preimage);
+        self
+    }
+
+    pub fn with_sequence(mut self, sequence: Sequence) -> Self {
+        self.sequence = Some(sequence);
+        self
+    }
+
+    pub fn with_locktime(mut self, locktime: LockTime) -> Self {
+        self.locktime = Some(locktime);
+        self
+    }
+
+    fn find_preimage(&self, hash: &[u8], hash_fn: impl Fn(&[u8]) -> Vec<u8>) -> Option<Vec<u8>> {
+        self.preimages
+            .iter()
+            .find(|preimage| hash_fn(preimage) == hash)
+            .cloned()
+    }
+}
+
+impl Satisfier for SatisfactionData {
+    fn signature(&self, key: &S256Point) -> Option<Vec<u8>> {
+        let sec = key.sec(true);
+        self.signatures
+            .iter()
+            .find(|(signer, _)| *signer == sec)
+            .map(|(_, signature)| signature.clone())
+    }
+
+    fn sha256_preimage(&self, hash: &[u8; 32]) -> Option<Vec<u8>> {
+        self.find_preimage(hash, |data| sha256(data).to_vec())
+    }
+
+    fn hash256_preimage(&self, hash: &[u8; 32]) -> Option<Vec<u8>> {
+        self.find_preimage(hash, |data| hash256(data).to_vec())
+    }
+
+    fn ripemd160_preimage(&self, hash: &[u8; 20]) -> Option<Vec<u8>> {
+        self.find_preimage(hash, |data| Ripemd160::digest(data).to_vec())
+    }
+
+    fn hash160_preimage(&self, hash: &[u8; 20]) -> Option<Vec<u8>> {
+        self.find_preimage(hash, |data| hash160(data).to_vec())
+    }
+
+    // Same rules as OP_CHECKSEQUENCEVERIFY, without the transaction version check.
+    fn check_older(&self, n: u32) -> bool {
+        let (Some(required), Some(actual)) = (
+            Sequence(n).relative_lock_time(),
+            self.sequence
+                .and_then(|sequence| sequence.relative_lock_time()),
+        ) else {
+            return false;
+        };
+        required.is_same_unit(&actual) && required.value() <= actual.value()
+    }
+
+    fn check_after(&self, n: u32) -> bool {
+        let required = LockTime::from_consensus(n);
+        self.locktime.is_some_and(|locktime| {
+            required.is_same_unit(&locktime) && n <= locktime.to_consensus_u32()
+        })
+    }
+}
+
+// Something a satisfaction needs that the satisfier could not provide.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Requirement {
+    Signature(S256Point),
+    Sha256([u8; 32]),
+    Hash256([u8; 32]),
+    Ripemd160([u8; 20]),
+    Hash160([u8; 20]),
+    Older(u32),
+    After(u32),
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Requirement::Signature(key) => {
+                write!(f, "signature for {}", hex::encode(key.sec(true)))
+            }
+            Requirement::Sha256(hash) => write!(f, "sha256 preimage of {}", hex::encode(hash)),
+            Requirement::Hash256(hash) => write!(f, "hash256 preimage of {}", hex::encode(hash)),
+            Requirement::Ripemd160(hash) => {
+                write!(f, "ripemd160 preimage of {}", hex::encode(hash))
+            }
+            Requirement::Hash160(hash) => write!(f, "hash160 preimage of {}", hex::encode(hash)),
+            Requirement::Older(n) => write!(f, "older({n})"),
+            Requirement::After(n) => write!(f, "after({n})"),
+        }
+    }
+}
+
+// A candidate witness stack, bottom element first. Stacks that need something the
+// satisfier lacks are kept with the missing items, so the best of them can be
+// reported, and placeholders are used for the missing elements.
+#[derive(Clone, Debug)]
+struct InputStack {
+    available: bool,
+    missing: Vec<Requirement>,
+    has_sig: bool,
+    malleable: bool,
+    // Never produced by honest signers, only by third parties.
+    non_canon: bool,
+    size: usize,
+    stack: Vec<Vec<u8>>,
+}
+
+impl InputStack {
+    fn empty() -> Self {
+        InputStack {
+            available: true,
+            missing: Vec::new(),
+            has_sig: false,
+            malleable: false,
+            non_canon: false,
+            size: 0,
+            stack: Vec::new(),
+        }
+    }
+
+    fn invalid() -> Self {
+        InputStack {
+            available: false,
+            ..InputStack::empty()
+        }
+    }
+
+    fn push(element: Vec<u8>) -> Self {
+        InputStack {
+            size: element.len() + 1,
+            stack: vec![element],
+            ..InputStack::empty()
+        }
+    }
+
+    fn zero() -> Self {
+        InputStack::push(vec![])
+    }
+
+    fn one() -> Self {
+        InputStack::push(vec![1])
+    }
+
+    // An element the satisfier may or may not have.
+    fn maybe(element: Option<Vec<u8>>, requirement: Requirement, placeholder_len: usize) -> Self {
+        match element {
+            Some(element) => InputStack::push(element),
+            None => InputStack {
+                missing: vec![requirement],
+                ..InputStack::push(vec![0; placeholder_len])
+            },
+        }
+    }
+
+    fn with_sig(mut self) -> Self {
+        self.has_sig = true;
+        self
+    }
+
+    fn malleable(mut self) -> Self {
+        self.malleable = true;
+        self
+    }
+
+    fn non_canon(mut self) -> Self {
+        self.non_canon = true;
+        self
+    }
+
+    // self below other on the stack: other is consumed first.
+    fn then(mut self, other: InputStack) -> Self {
+        self.available &= other.available;
+        self.missing.extend(other.missing);
+        self.has_sig |= other.has_sig;
+        self.malleable |= other.malleable;
+        self.non_canon |= other.non_canon;
+        self.size += other.size;
+        self.stack.extend(other.stack);
+        self
+    }
+
+    // The better of two alternatives, as operator| in Core.
+    fn or(mut self, mut other: InputStack) -> Self {
+        if !self.available || !other.available {
+            return if self.available { self } else { other };
+        }
+        if self.missing.is_empty() != other.missing.is_empty() {
+            return if self.missing.is_empty() { self } else { other };
+        }
+        if self.non_canon != other.non_canon {
+            return if other.non_canon { self } else { other };
+        }
+        // A third party can always swap in an option needing no signature.
+        if !self.has_sig && other.has_sig {
+            return self;
+        }
+        if self.has_sig && !other.has_sig {
+            return other;
+        }
+        if !self.has_sig && !other.has_sig {
+            self.malleable = true;
+            other.malleable = true;
+        } else if self.malleable != other.malleable {
+            return if other.malleable { self } else { other };
+        }
+        let key = |stack: &InputStack| (stack.missing.len(), stack.size);
+        if key(&self) <= key(&other) {
+            self
+        } else {
+            other
+        }
+    }
+}
+
+// Best (dissatisfaction, satisfaction) of a fragment.
+struct InputResult {
+    nsat: InputStack,
+    sat: InputStack,
+}
+
+fn result(nsat: InputStack, sat: InputStack) -> InputResult {
+    InputResult { nsat, sat }
+}
+
+impl Miniscript {
+    // The witness stack of a non-malleable satisfaction, without the witness script.
+    pub fn satisfy(&self, satisfier: &dyn Satisfier) -> Result<Vec<Vec<u8>>, Errors> {
+        let sat = self.produce_input(satisfier).sat;
+        if !sat.available {
+            return Err(Errors::MiniscriptUnsatisfiable);
+        }
+        if !sat.missing.is_empty() {
+            let missing: Vec<String> = sat.missing.iter().map(|item| item.to_string()).collect();
+            return Err(Errors::MiniscriptMissing(missing.join(", ")));
+        }
+        if sat.malleable || !sat.has_sig {
+            return Err(Errors::MiniscriptMalleable);
+        }
+        Ok(sat.stack)
+    }
+
+    // The complete P2WSH witness: the satisfaction followed by the witness script.
+    pub fn p2wsh_witness(&self, satisfier: &dyn Satisfier) -> Result<Witness, Errors> {
+        let mut elements = self.satisfy(satisfier)?;
+        elements.push(self.encode().into_bytes());
+        Ok(Witness::from_elements(elements))
+    }
+
+    // What the cheapest satisfaction still needs. Empty when satisfy can succeed, or
+    // when the expression cannot be satisfied at all.
+    pub fn missing(&self, satisfier: &dyn Satisfier) -> Vec<Requirement> {
+        let sat = self.produce_input(satisfier).sat;
+        if sat.available {
+            sat.missing
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn produce_input(&self, satisfier: &dyn Satisfier) -> InputResult {
+        let sign = |key: &S256Point| {
+            let signature = satisfier.signature(key);
+            InputStack::maybe(signature, Requirement::Signature(key.clone()), 72).with_sig()
+        };
+        // A 32 byte dissatisfaction of hash fragments, which anyone can produce.
+        let zero32 = || InputStack::push(vec![0; 32]).malleable();
+        let sub = |miniscript: &Miniscript| miniscript.produce_input(satisfier);
+        match &self.node {
+            Terminal::False => result(InputStack::empty(), InputStack::invalid()),
+            Terminal::True => result(InputStack::invalid(), InputStack::empty()),
+            Terminal::PkK(key) => result(InputStack::zero(), sign(key)),
+            Terminal::PkH(key) => {
+                let pubkey = InputStack::push(key.sec(true));
+                result(
+                    InputStack::zero().then(pubkey.clone()),
+                    sign(key).then(pubkey),
+                )
+            }
+            Terminal::Older(n) => {
+                let sat = if satisfier.check_older(*n) {
+                    InputStack::empty()
+                } else {
+                    InputStack {
+                        missing: vec![Requirement::Older(*n)],
+                        ..InputStack::empty()
+                    }
+                };
+                result(InputStack::invalid(), sat)
+            }
+            Terminal::After(n) => {
+                let sat = if satisfier.check_after(*n) {
+                    InputStack::empty()
+                } else {
+                    InputStack {
+                        missing: vec![Requirement::After(*n)],
+                        ..InputStack::empty()
+                    }
+                };
+                result(InputStack::invalid(), sat)
+            }
+            Terminal::Sha256(hash) => result(
+                zero32(),
+                InputStack::maybe(
+                    satisfier.sha256_preimage(hash),
+                    Requirement::Sha256(*hash),
+                    32,
+                ),
+            ),
+            Terminal::Hash256(hash) => result(
+                zero32(),
+                InputStack::maybe(
+                    satisfier.hash256_preimage(hash),
+                    Requirement::Hash256(*hash),
+                    32,
+                ),
+            ),
+            Terminal::Ripemd160(hash) => result(
+                zero32(),
+                InputStack::maybe(
+                    satisfier.ripemd160_preimage(hash),
+                    Requirement::Ripemd160(*hash),
+                    32,
+                ),
+            ),
+            Terminal::Hash160(hash) => result(
+                zero32(),
+                InputStack::maybe(
+                    satisfier.hash160_preimage(hash),
+                    Requirement::Hash160(*hash),
+                    32,
+                ),
+            ),
+            Terminal::Alt(x)
+            | Terminal::Swap(x)
+            | Terminal::Check(x)
+            | Terminal::ZeroNotEqual(x) => sub(x),
+            Terminal::DupIf(x) => result(InputStack::zero(), sub(x).sat.then(InputStack::one())),
+            Terminal::Verify(x) => result(InputStack::invalid(), sub(x).sat),
+            Terminal::NonZero(x) => {
+                let x = sub(x);
+                // A dissatisfaction of X with a nonzero top element would be a second
+                // way to dissatisfy, which is not tracked.
+                let mut nsat = InputStack::zero();
+                nsat.malleable = x.nsat.available && !x.nsat.has_sig;
+                result(nsat, x.sat)
+            }
+            Terminal::AndV(x, y) => {
+                let (x, y) = (sub(x), sub(y));
+                result(y.nsat.then(x.sat.clone()).non_canon(), y.sat.then(x.sat))
+            }
+            Terminal::AndB(x, y) => {
+                let (x, y) = (sub(x), sub(y));
+                let nsat = y
+                    .nsat
+                    .clone()
+                    .then(x.nsat.clone())
+                    .or(y.sat.clone().then(x.nsat).malleable().non_canon())
+                    .or(y.nsat.then(x.sat.clone()).malleable().non_canon());
+                result(nsat, y.sat.then(x.sat))
+            }
+            Terminal::OrB(x, z) => {
+                let (x, z) = (sub(x), sub(z));
+                let sat = z
+                    .nsat
+                    .clone()
+                    .then(x.sat.clone())
+                    .or(z.sat.clone().then(x.nsat.clone()))
+                    .or(z.sat.then(x.sat).malleable().non_canon());
+                result(z.nsat.then(x.nsat), sat)
+            }
+            Terminal::OrC(x, z) => {
+                let (x, z) = (sub(x), sub(z));
+                result(InputStack::invalid(), x.sat.or(z.sat.then(x.nsat)))
+            }
+            Terminal::OrD(x, z) => {
+                let (x, z) = (sub(x), sub(z));
+                result(z.nsat.then(x.nsat.clone()), x.sat.or(z.sat.then(x.nsat)))
+            }
+            Terminal::OrI(x, z) => {
+                let (x, z) = (sub(x), sub(z));
+                result(
+                    x.nsat
+                        .then(InputStack::one())
+                        .or(z.nsat.then(InputStack::zero())),
+                    x.sat
+                        .then(InputStack::one())
+                        .or(z.sat.then(InputStack::zero())),
+                )
+            }
+            Terminal::AndOr(x, y, z) => {
+                let (x, y, z) = (sub(x), sub(y), sub(z));
+                result(
+                    y.nsat
+                        .then(x.sat.clone())
+                        .non_canon()
+                        .or(z.nsat.then(x.nsat.clone())),
+                    y.sat.then(x.sat).or(z.sat.then(x.nsat)),
+                )
+            }
+            Terminal::Thresh(k, subs) => {
+                // sats[j] is the best stack satisfying exactly j of the subs seen so
+                // far. The last sub is at the bottom of the stack, so go backwards.
+                let mut sats = vec![InputStack::empty()];
+                for res in subs.iter().rev().map(sub) {
+                    let mut next = vec![sats[0].clone().then(res.nsat.clone())];
+                    for j in 1..sats.len() {
+                        next.push(
+                            sats[j]
+                                .clone()
+                                .then(res.nsat.clone())
+                                .or(sats[j - 1].clone().then(res.sat.clone())),
+                        );
+                    }
+                    next.push(sats[sats.len() - 1].clone().then(res.sat));
+                    sats = next;
+                }
+                // Dissatisfying with some subs satisfied is overcomplete.
+                let mut nsat = InputStack::invalid();
+                for (i, stack) in sats.iter().enumerate() {
+                    if i == *k {
+                        continue;
+                    }
+                    let stack = if i == 0 {
+                        stack.clone()
+                    } else {
+                        stack.clone().malleable().non_canon()
+                    };
+                    nsat = nsat.or(stack);
+                }
+                result(nsat, sats.swap_remove(*k))
+            }
+            Terminal::Multi(k, keys) => {
+                // The same as thresh, with signatures in key order above the dummy.
+                let mut sats = vec![InputStack::zero()];
+                for key in keys {
+                    let sat = sign(key);
+                    let mut next = vec![sats[0].clone()];
+                    for j in 1..sats.len() {
+                        next.push(sats[j].clone().or(sats[j - 1].clone().then(sat.clone())));
+                    }
+                    next.push(sats[sats.len() - 1].clone().then(sat));
+                    sats = next;
+                }
+                let nsat =
+                    (0..*k).fold(InputStack::zero(), |nsat, _| nsat.then(InputStack::zero()));
+                result(nsat, sats.swap_remove(*k))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod satisfy_tests {
+    use super::*;
+    use crate::ecc::{from_bytes, PrivateKey};
+    use crate::script::interpreter::TransactionSignatureChecker;
+    use crate::script::verify::verify_script;
+    use crate::script::{Script, VerificationFlags};
+    use crate::transaction::sighash::SighashCache;
+    use crate::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use num_bigint::BigInt;
+
+    fn key(secret: u32) -> PrivateKey {
+        PrivateKey::new(BigInt::from(secret)).unwrap()
+    }
+
+    fn pubkey(secret: u32) -> String {
+        hex::encode(key(secret).public_key().sec(true))
+    }
+
+    fn spending_tx(sequence: u32) -> Transaction {
+        let input = TxIn::new(OutPoint::new([1; 32], 0), Script::new(), sequence);
+        let output = TxOut::new(90_000, Script::new_p2wpkh(&[2; 20]));
+        Transaction::new(2, vec![input], vec![output], 0)
+    }
+
+    // Signatures from the given keys over the P2WSH spend of the first input.
+    fn signatures(
+        miniscript: &Miniscript,
+        tx: &Transaction,
+        secrets: &[u32],
+        data: SatisfactionData,
+    ) -> SatisfactionData {
+        let script = miniscript.encode();
+        let z = SighashCache::new(tx)
+            .segwit_v0_signature_hash(0, &script, 100_000, 1)
+            .unwrap();
+        secrets.iter().fold(data, |data, secret| {
+            let mut signature = key(*secret).sign(&from_bytes(&z)).der();
+            signature.push(1);
+            data.with_signature(key(*secret).public_key(), signature)
+        })
+    }
+
+    fn verify(miniscript: &Miniscript, tx: &Transaction, witness: Witness) -> bool {
+        let script_pubkey = Script::new_p2wsh(&miniscript.encode());
+        let mut tx = tx.clone();
+        tx.inputs[0].witness = witness;
+        let checker = TransactionSignatureChecker::new(&tx, 0, 100_000);
+        verify_script(
+            &Script::new(),
+            &script_pubkey,
+            &tx.inputs[0].witness,
+            VerificationFlags::STANDARD,
+            &checker,
+        )
+        .is_ok()
+    }
+
+    #[test]
+    fn test_satisfy_keys_and_timelock() {
+        let miniscript: Miniscript = format!(
+            "or_d(multi(2,{},{},{}),and_v(v:pk({}),older(144)))",
+            pubkey(1),
+            pubkey(2),
+            pubkey(3),
+            pubkey(4)
+        )
+        .parse()
+        .unwrap();
+        assert!(miniscript.is_sane());
+
+        // Two of the three multisig keys.
+        let tx = spending_tx(0xffffffff);
+        let data = signatures(&miniscript, &tx, &[1, 3], SatisfactionData::new());
+        let stack = miniscript.satisfy(&data).unwrap();
+        assert_eq!(stack.len(), 3);
+        assert!(stack[0].is_empty());
+        assert!(verify(
+            &miniscript,
+            &tx,
+            miniscript.p2wsh_witness(&data).unwrap()
+        ));
+
+        // One signature and the recovery key, before the timelock: the recovery path
+        // is the smaller witness still missing only one thing.
+        let data = signatures(&miniscript, &tx, &[1, 4], SatisfactionData::new());
+        assert_eq!(miniscript.missing(&data), vec![Requirement::Older(144)]);
+        let data = signatures(&miniscript, &tx, &[1], SatisfactionData::new());
+        assert_eq!(miniscript.missing(&data).len(), 1);
+        assert!(matches!(
+            miniscript.satisfy(&data),
+            Err(Errors::MiniscriptMissing(_))
+        ));
+
+        // After the timelock, the recovery key alone is enough.
+        let tx = spending_tx(144);
+        let data = signatures(
+            &miniscript,
+            &tx,
+            &[4],
+            SatisfactionData::new().with_sequence(Sequence(144)),
+        );
+        let stack = miniscript.satisfy(&data).unwrap();
+        // Recovery signature, then the multisig dissatisfaction.
+        assert_eq!(stack.len(), 4);
+        assert!(verify(
+            &miniscript,
+            &tx,
+            miniscript.p2wsh_witness(&data).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_satisfy_preimage() {
+        let preimage = vec![7u8; 32];
+        let miniscript: Miniscript = format!(
+            "andor(pk({}),sha256({}),and_v(v:pkh({}),after(500)))",
+            pubkey(1),
+            hex::encode(sha256(&preimage)),
+            pubkey(2)
+        )
+        .parse()
+        .unwrap();
+        let tx = spending_tx(0xfffffffe);
+
+        let data = signatures(&miniscript, &tx, &[1], SatisfactionData::new());
+        assert_eq!(
+            miniscript.missing(&data),
+            vec![Requirement::Sha256(sha256(&preimage))]
+        );
+        let data = data.with_preimage(preimage.clone());
+        let stack = miniscript.satisfy(&data).unwrap();
+        assert_eq!(stack[0], preimage);
+        assert!(verify(
+            &miniscript,
+            &tx,
+            miniscript.p2wsh_witness(&data).unwrap()
+        ));
+
+        // The other branch dissatisfies pk() with an empty signature.
+        let mut tx = spending_tx(0xfffffffe);
+        tx.locktime = 500;
+        let data = signatures(
+            &miniscript,
+            &tx,
+            &[2],
+            SatisfactionData::new().with_locktime(LockTime::Blocks(500)),
+        );
+        let stack = miniscript.satisfy(&data).unwrap();
+        assert_eq!(stack.last().unwrap(), &Vec::<u8>::new());
+        assert!(verify(
+            &miniscript,
+            &tx,
+            miniscript.p2wsh_witness(&data).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_satisfy_malleable() {
+        // Anyone can pick either preimage branch once both are public.
+        let (a, b) = (vec![1u8; 32], vec![2u8; 32]);
+        let miniscript: Miniscript = format!(
+            "or_i(sha256({}),sha256({}))",
+            hex::encode(sha256(&a)),
+            hex::encode(sha256(&b))
+        )
+        .parse()
+        .unwrap();
+        let data = SatisfactionData::new().with_preimage(a).with_preimage(b);
+        assert_eq!(miniscript.satisfy(&data), Err(Errors::MiniscriptMalleable));
+
+        let miniscript: Miniscript = "and_v(v:older(1),0)".parse().unwrap();
+        assert_eq!(
+            miniscript.satisfy(&SatisfactionData::new()),
+            Err(Errors::MiniscriptUnsatisfiable)
+        );
+    }
+}
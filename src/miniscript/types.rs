@@ -0,0 +1,320 @@
+// The miniscript type system, following ComputeType in Bitcoin Core's miniscript.cpp.
+//
+// Base types: B pushes a nonzero value on success and zero on failure, V pushes
+// nothing and aborts on failure, K pushes a key for a signature check, and W works
+// like B one element below the top of the stack.
+//
+// Properties: z consumes no stack elements, o consumes one, n never needs a zero
+// top element, d can be dissatisfied, u leaves exactly 1 on success. Malleability:
+// e every dissatisfaction is non-malleable, f cannot be dissatisfied without a
+// signature, s every satisfaction needs a signature, m a non-malleable satisfaction
+// exists. x means the last opcode is not EQUAL, CHECKSIG, CHECKMULTISIG or NUMEQUAL.
+// Timelocks: g relative by time, h relative by height, i absolute by time, j
+// absolute by height, and k no path mixes timelocks by height and by time.
+use std::fmt;
+use std::ops::{BitAnd, BitOr};
+
+const LETTERS: &[u8] = b"BVKWzondufesmxghijk";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Type(u32);
+
+// A type from its letters, as in the "Bdu"_mst literals of Bitcoin Core.
+pub const fn t(letters: &str) -> Type {
+    let letters = letters.as_bytes();
+    let mut bits = 0;
+    let mut i = 0;
+    while i < letters.len() {
+        let mut j = 0;
+        while LETTERS[j] != letters[i] {
+            j += 1;
+        }
+        bits |= 1 << j;
+        i += 1;
+    }
+    Type(bits)
+}
+
+impl Type {
+    // True if every property of other is present.
+    pub fn has(self, other: Type) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // self if the condition holds, no properties otherwise.
+    fn when(self, condition: bool) -> Type {
+        if condition {
+            self
+        } else {
+            Type(0)
+        }
+    }
+
+    // Exactly one of the base types is set.
+    pub fn is_valid(self) -> bool {
+        (self.0 & t("BVKW").0).count_ones() == 1
+    }
+}
+
+impl BitOr for Type {
+    type Output = Type;
+
+    fn bitor(self, other: Type) -> Type {
+        Type(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Type {
+    type Output = Type;
+
+    fn bitand(self, other: Type) -> Type {
+        Type(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, letter) in LETTERS.iter().enumerate() {
+            if self.0 & (1 << i) != 0 {
+                write!(f, "{}", *letter as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Leaf fragment types.
+pub const FALSE: Type = t("Bzudemsxk");
+pub const TRUE: Type = t("Bzufmxk");
+pub const PK_K: Type = t("Konudemsxk");
+pub const PK_H: Type = t("Knudemsxk");
+pub const HASH: Type = t("Bonudmk");
+pub const MULTI: Type = t("Bnudemsk");
+
+// Relative timelocks with the type flag set count time, otherwise blocks.
+pub fn older(sequence: u32) -> Type {
+    let kind = if sequence & (1 << 22) != 0 { "g" } else { "h" };
+    t(kind) | t("Bzfmxk")
+}
+
+pub fn after(locktime: u32) -> Type {
+    let kind = if locktime >= 500_000_000 { "i" } else { "j" };
+    t(kind) | t("Bzfmxk")
+}
+
+pub fn alt(x: Type) -> Type {
+    t("W").when(x.has(t("B"))) | (x & t("ghijk")) | (x & t("udfems")) | t("x")
+}
+
+pub fn swap(x: Type) -> Type {
+    t("W").when(x.has(t("Bo"))) | (x & t("ghijk")) | (x & t("udfemsx"))
+}
+
+pub fn check(x: Type) -> Type {
+    t("B").when(x.has(t("K"))) | (x & t("ghijk")) | (x & t("ondfem")) | t("us")
+}
+
+// OP_IF is only required to be minimal by policy in P2WSH, so d: is not u.
+pub fn dup_if(x: Type) -> Type {
+    t("B").when(x.has(t("Vz")))
+        | t("o").when(x.has(t("z")))
+        | t("e").when(x.has(t("f")))
+        | (x & t("ghijk"))
+        | (x & t("ms"))
+        | t("ndx")
+}
+
+pub fn verify(x: Type) -> Type {
+    t("V").when(x.has(t("B"))) | (x & t("ghijk")) | (x & t("zonms")) | t("fx")
+}
+
+pub fn non_zero(x: Type) -> Type {
+    t("B").when(x.has(t("Bn")))
+        | t("e").when(x.has(t("f")))
+        | (x & t("ghijk"))
+        | (x & t("oums"))
+        | t("ndx")
+}
+
+pub fn zero_not_equal(x: Type) -> Type {
+    (x & t("ghijk")) | (x & t("Bzondfems")) | t("ux")
+}
+
+// No path needs both a height and a time lock of the same kind.
+fn no_timelock_mix(x: Type, y: Type) -> bool {
+    !(x.has(t("g")) && y.has(t("h"))
+        || x.has(t("h")) && y.has(t("g"))
+        || x.has(t("i")) && y.has(t("j"))
+        || x.has(t("j")) && y.has(t("i")))
+}
+
+pub fn and_v(x: Type, y: Type) -> Type {
+    (y & t("KVB")).when(x.has(t("V")))
+        | (x & t("n"))
+        | (y & t("n")).when(x.has(t("z")))
+        | ((x | y) & t("o")).when((x | y).has(t("z")))
+        | (x & y & t("dmz"))
+        | ((x | y) & t("s"))
+        | t("f").when(y.has(t("f")) || x.has(t("s")))
+        | (y & t("ux"))
+        | ((x | y) & t("ghij"))
+        | t("k").when((x & y).has(t("k")) && no_timelock_mix(x, y))
+}
+
+pub fn and_b(x: Type, y: Type) -> Type {
+    (x & t("B")).when(y.has(t("W")))
+        | ((x | y) & t("o")).when((x | y).has(t("z")))
+        | (x & t("n"))
+        | (y & t("n")).when(x.has(t("z")))
+        | (x & y & t("e")).when((x & y).has(t("s")))
+        | (x & y & t("dzm"))
+        | t("f").when((x & y).has(t("f")) || x.has(t("sf")) || y.has(t("sf")))
+        | ((x | y) & t("s"))
+        | t("ux")
+        | ((x | y) & t("ghij"))
+        | t("k").when((x & y).has(t("k")) && no_timelock_mix(x, y))
+}
+
+pub fn or_b(x: Type, y: Type) -> Type {
+    t("B").when(x.has(t("Bd")) && y.has(t("Wd")))
+        | ((x | y) & t("o")).when((x | y).has(t("z")))
+        | (x & y & t("m")).when((x | y).has(t("s")) && (x & y).has(t("e")))
+        | (x & y & t("zse"))
+        | t("dux")
+        | ((x | y) & t("ghij"))
+        | (x & y & t("k"))
+}
+
+pub fn or_c(x: Type, y: Type) -> Type {
+    (y & t("V")).when(x.has(t("Bdu")))
+        | (x & t("o")).when(y.has(t("z")))
+        | (x & y & t("m")).when(x.has(t("e")) && (x | y).has(t("s")))
+        | (x & y & t("zs"))
+        | t("fx")
+        | ((x | y) & t("ghij"))
+        | (x & y & t("k"))
+}
+
+pub fn or_d(x: Type, y: Type) -> Type {
+    (y & t("B")).when(x.has(t("Bdu")))
+        | (x & t("o")).when(y.has(t("z")))
+        | (x & y & t("m")).when(x.has(t("e")) && (x | y).has(t("s")))
+        | (x & y & t("zs"))
+        | (y & t("ufde"))
+        | t("x")
+        | ((x | y) & t("ghij"))
+        | (x & y & t("k"))
+}
+
+pub fn or_i(x: Type, y: Type) -> Type {
+    (x & y & t("VBKufs"))
+        | t("o").when((x & y).has(t("z")))
+        | ((x | y) & t("e")).when((x | y).has(t("f")))
+        | (x & y & t("m")).when((x | y).has(t("s")))
+        | ((x | y) & t("d"))
+        | t("x")
+        | ((x | y) & t("ghij"))
+        | (x & y & t("k"))
+}
+
+pub fn and_or(x: Type, y: Type, z: Type) -> Type {
+    (y & z & t("BKV")).when(x.has(t("Bdu")))
+        | (x & y & z & t("z"))
+        | ((x | (y & z)) & t("o")).when((x | (y & z)).has(t("z")))
+        | (y & z & t("u"))
+        | (z & t("f")).when(x.has(t("s")) || y.has(t("f")))
+        | (z & t("d"))
+        | (z & t("e")).when(x.has(t("s")) || y.has(t("f")))
+        | (x & y & z & t("m")).when(x.has(t("e")) && (x | y | z).has(t("s")))
+        | (z & (x | y) & t("s"))
+        | t("x")
+        | ((x | y | z) & t("ghij"))
+        | t("k").when((x & y & z).has(t("k")) && no_timelock_mix(x, y))
+}
+
+// The first sub must be Bdu and the others Wdu, or the result is invalid.
+pub fn thresh(k: usize, subs: &[Type]) -> Type {
+    let (mut all_e, mut all_m) = (true, true);
+    let (mut args, mut num_s) = (0, 0);
+    let mut timelocks = t("k");
+    for (i, sub) in subs.iter().enumerate() {
+        let required = if i == 0 { t("Bdu") } else { t("Wdu") };
+        if !sub.has(required) {
+            return Type::default();
+        }
+        all_e &= sub.has(t("e"));
+        all_m &= sub.has(t("m"));
+        num_s += sub.has(t("s")) as usize;
+        args += if sub.has(t("z")) {
+            0
+        } else if sub.has(t("o")) {
+            1
+        } else {
+            2
+        };
+        let mixes = k > 1 && !no_timelock_mix(timelocks, *sub);
+        timelocks = ((timelocks | *sub) & t("ghij"))
+            | t("k").when((timelocks & *sub).has(t("k")) && !mixes);
+    }
+    let n = subs.len();
+    t("Bdu")
+        | t("z").when(args == 0)
+        | t("o").when(args == 1)
+        | t("e").when(all_e && num_s == n)
+        | t("m").when(all_e && all_m && num_s + k >= n)
+        | t("s").when(num_s + k > n)
+        | timelocks
+}
+
+#[cfg(test)]
+mod types_tests {
+    use super::*;
+
+    #[test]
+    fn test_letters() {
+        assert_eq!(t("Bdu").to_string(), "Bdu");
+        assert_eq!(t("udB").to_string(), "Bdu");
+        assert!(t("Bdu").has(t("Bd")));
+        assert!(!t("Bd").has(t("Bdu")));
+        assert!(t("Bdu").is_valid());
+        assert!(!t("du").is_valid());
+        assert!(!t("BV").is_valid());
+    }
+
+    #[test]
+    fn test_wrapped_keys() {
+        // pk(K) = c:pk_k(K) and pkh(K) = c:pk_h(K).
+        assert_eq!(check(PK_K).to_string(), "Bonduesmk");
+        assert_eq!(check(PK_H).to_string(), "Bnduesmk");
+        // v:pk(K) is V, and s:pk(K) is W.
+        assert_eq!(verify(check(PK_K)).to_string(), "Vonfsmxk");
+        assert!(swap(check(PK_K)).has(t("Wdu")));
+        // c: needs a K.
+        assert!(!check(TRUE).is_valid());
+    }
+
+    #[test]
+    fn test_timelock_mixing() {
+        let height = after(100);
+        let time = after(500_000_001);
+        let key = check(PK_K);
+        assert!(and_b(key, alt(height)).has(t("k")));
+        assert!(!and_v(verify(height), time).has(t("k")));
+        // Either branch alone is fine.
+        assert!(or_i(height, time).has(t("k")));
+        assert!(older(1 << 22).has(t("g")));
+        assert!(older(144).has(t("h")));
+    }
+
+    #[test]
+    fn test_thresh() {
+        let key = check(PK_K);
+        let wrapped = swap(key);
+        let ty = thresh(2, &[key, wrapped, wrapped]);
+        assert!(ty.has(t("Bdusemk")));
+        // One of three without signatures would be possible if k was too small.
+        assert!(!thresh(1, &[key, alt(HASH), wrapped]).has(t("s")));
+        // The first sub must be B.
+        assert!(!thresh(1, &[wrapped, wrapped]).is_valid());
+    }
+}
@@ -0,0 +1,456 @@
+// The Bitcoin networks this crate knows about, and the parameters they differ in.
+use crate::block::{bits_to_target, Block, BlockHeader};
+use crate::script::{Script, VerificationFlags};
+use crate::transaction::{txid_from_hex, Transaction};
+use crate::validation::COIN;
+use num_bigint::BigInt;
+
+// Every network's genesis block has the same single coinbase transaction, with the
+// Times headline in its scriptSig.
+const GENESIS_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+// Mainnet blocks that were valid under the rules of their time but not under one
+// enforced from genesis now.
+const BIP16_EXCEPTION: &str = "00000000000002dc756eebf4f49723ed8d30cc28a5f108eb94b1ba88ac4f9c22";
+const TAPROOT_EXCEPTION: &str = "0000000000000000000f14c35b2d841e986ab5441de8c585d5ffe55ea1e395ad";
+
+// 1-of-2 multisig that signs the blocks of the default signet.
+const DEFAULT_SIGNET_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+// Blocks known to be in the best chain, as listed in Bitcoin Core's chainparams.
+const MAINNET_CHECKPOINTS: [(u32, &str); 13] = [
+    (
+        11111,
+        "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+    ),
+    (
+        33333,
+        "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6",
+    ),
+    (
+        74000,
+        "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20",
+    ),
+    (
+        105000,
+        "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+    ),
+    (
+        134444,
+        "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe",
+    ),
+    (
+        168000,
+        "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763",
+    ),
+    (
+        193000,
+        "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317",
+    ),
+    (
+        210000,
+        "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+    ),
+    (
+        216116,
+        "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e",
+    ),
+    (
+        225430,
+        "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932",
+    ),
+    (
+        250000,
+        "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+    ),
+    (
+        279000,
+        "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40",
+    ),
+    (
+        295000,
+        "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983",
+    ),
+];
+
+const TESTNET_CHECKPOINTS: [(u32, &str); 1] = [(
+    546,
+    "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "main",
+            Network::Testnet => "test",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    // Start of every P2P message, telling networks apart. A custom signet's is
+    // derived from its challenge by signet_magic.
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Signet => [0x0a, 0x03, 0xcf, 0x40],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 8333,
+            Network::Testnet => 18333,
+            Network::Signet => 38333,
+            Network::Regtest => 18444,
+        }
+    }
+
+    // Where bitcoind serves JSON-RPC by default.
+    pub fn default_rpc_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 8332,
+            Network::Testnet => 18332,
+            Network::Signet => 38332,
+            Network::Regtest => 18443,
+        }
+    }
+
+    // Hosts resolving to nodes to start from, those of Bitcoin Core.
+    pub fn dns_seeds(&self) -> &'static [&'static str] {
+        match self {
+            Network::Mainnet => &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+                "seed.mainnet.achownodes.xyz",
+            ],
+            Network::Testnet => &[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+                "seed.testnet.achownodes.xyz",
+            ],
+            Network::Signet => &[
+                "seed.signet.bitcoin.sprovoost.nl",
+                "seed.signet.achownodes.xyz",
+            ],
+            Network::Regtest => &[],
+        }
+    }
+
+    // Easiest target allowed, in compact form.
+    pub fn pow_limit_bits(&self) -> u32 {
+        match self {
+            Network::Mainnet | Network::Testnet => 0x1d00ffff,
+            Network::Signet => 0x1e0377ae,
+            Network::Regtest => 0x207fffff,
+        }
+    }
+
+    pub fn pow_limit(&self) -> BigInt {
+        bits_to_target(self.pow_limit_bits()).unwrap()
+    }
+
+    // Testnet accepts a block at the minimum difficulty when it comes more than 20
+    // minutes after the previous one.
+    pub fn allows_min_difficulty_blocks(&self) -> bool {
+        matches!(self, Network::Testnet | Network::Regtest)
+    }
+
+    // Script every block's signet solution must satisfy. Custom signets share the
+    // default signet's other parameters and only use another challenge.
+    pub fn signet_challenge(&self) -> Option<Script> {
+        match self {
+            Network::Signet => Some(Script::from_bytes(
+                hex::decode(DEFAULT_SIGNET_CHALLENGE).unwrap(),
+            )),
+            _ => None,
+        }
+    }
+
+    // Regtest keeps the same target forever.
+    pub fn no_pow_retargeting(&self) -> bool {
+        *self == Network::Regtest
+    }
+
+    // Blocks between each halving of the block subsidy.
+    pub fn subsidy_halving_interval(&self) -> u32 {
+        match self {
+            Network::Regtest => 150,
+            _ => 210_000,
+        }
+    }
+
+    // New coins a block at height may pay itself, halved every interval until the
+    // shift would become undefined.
+    pub fn block_subsidy(&self, height: u32) -> u64 {
+        let halvings = height / self.subsidy_halving_interval();
+        if halvings >= 64 {
+            return 0;
+        }
+        (50 * COIN) >> halvings
+    }
+
+    // Coins created by the subsidies of blocks 0 to height, the genesis coinbase
+    // included even though it can't be spent.
+    pub fn total_supply(&self, height: u32) -> u64 {
+        let interval = self.subsidy_halving_interval() as u64;
+        let blocks = height as u64 + 1;
+        let mut supply = 0;
+        for halvings in 0..64u64 {
+            let start = halvings * interval;
+            if start >= blocks {
+                break;
+            }
+            supply += (blocks - start).min(interval) * ((50 * COIN) >> halvings);
+        }
+        supply
+    }
+
+    // Supply once the subsidy has dropped to zero, just under 21 million coins.
+    pub fn max_supply(&self) -> u64 {
+        self.total_supply(u32::MAX)
+    }
+
+    // Heights the buried soft forks activated at: BIP34, BIP66, BIP65, CSV and segwit.
+    pub fn bip34_height(&self) -> u32 {
+        match self {
+            Network::Mainnet => 227_931,
+            Network::Testnet => 21_111,
+            Network::Signet | Network::Regtest => 1,
+        }
+    }
+
+    pub fn bip66_height(&self) -> u32 {
+        match self {
+            Network::Mainnet => 363_725,
+            Network::Testnet => 330_776,
+            Network::Signet | Network::Regtest => 1,
+        }
+    }
+
+    pub fn bip65_height(&self) -> u32 {
+        match self {
+            Network::Mainnet => 388_381,
+            Network::Testnet => 581_885,
+            Network::Signet | Network::Regtest => 1,
+        }
+    }
+
+    pub fn csv_height(&self) -> u32 {
+        match self {
+            Network::Mainnet => 419_328,
+            Network::Testnet => 770_112,
+            Network::Signet | Network::Regtest => 1,
+        }
+    }
+
+    pub fn segwit_height(&self) -> u32 {
+        match self {
+            Network::Mainnet => 481_824,
+            Network::Testnet => 834_624,
+            Network::Signet => 1,
+            Network::Regtest => 0,
+        }
+    }
+
+    // Script rules a block at height is validated with (GetBlockScriptFlags in
+    // Bitcoin Core). P2SH, segwit and taproot are enforced from genesis as no earlier
+    // block breaks them, except for one block each on mainnet.
+    pub fn script_flags(&self, height: u32, hash: &[u8; 32]) -> VerificationFlags {
+        let mut flags =
+            VerificationFlags::P2SH | VerificationFlags::WITNESS | VerificationFlags::TAPROOT;
+        if *self == Network::Mainnet {
+            if *hash == txid_from_hex(BIP16_EXCEPTION).unwrap() {
+                flags = VerificationFlags::NONE;
+            } else if *hash == txid_from_hex(TAPROOT_EXCEPTION).unwrap() {
+                flags = VerificationFlags::P2SH | VerificationFlags::WITNESS;
+            }
+        }
+        if height >= self.bip66_height() {
+            flags |= VerificationFlags::DERSIG;
+        }
+        if height >= self.bip65_height() {
+            flags |= VerificationFlags::CHECKLOCKTIMEVERIFY;
+        }
+        if height >= self.csv_height() {
+            flags |= VerificationFlags::CHECKSEQUENCEVERIFY;
+        }
+        if height >= self.segwit_height() {
+            flags |= VerificationFlags::NULLDUMMY;
+        }
+        flags
+    }
+
+    // Heights and hashes headers must match. No header may fork the chain below the
+    // last checkpoint it contains.
+    pub fn checkpoints(&self) -> Vec<(u32, [u8; 32])> {
+        let list: &[(u32, &str)] = match self {
+            Network::Mainnet => &MAINNET_CHECKPOINTS,
+            Network::Testnet => &TESTNET_CHECKPOINTS,
+            Network::Signet | Network::Regtest => &[],
+        };
+        list.iter()
+            .map(|(height, hash)| (*height, txid_from_hex(hash).unwrap()))
+            .collect()
+    }
+
+    // Block whose ancestors' scripts are not verified by default: the last checkpoint.
+    pub fn default_assume_valid(&self) -> Option<[u8; 32]> {
+        self.checkpoints().last().map(|(_, hash)| *hash)
+    }
+
+    // Serialized genesis header and its hash in display order. Only the time, bits
+    // and nonce differ between networks.
+    fn genesis_data(&self) -> (&'static str, &'static str) {
+        match self {
+            Network::Mainnet => (
+                "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            ),
+            Network::Testnet => (
+                "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff001d1aa4ae18",
+                "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            ),
+            Network::Signet => (
+                "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a008f4d5fae77031e8ad22203",
+                "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+            ),
+            Network::Regtest => (
+                "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000",
+                "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            ),
+        }
+    }
+
+    pub fn genesis_header(&self) -> BlockHeader {
+        BlockHeader::from_hex(self.genesis_data().0).unwrap()
+    }
+
+    // Genesis block hash in internal byte order.
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        txid_from_hex(self.genesis_data().1).unwrap()
+    }
+
+    pub fn genesis_block(&self) -> Block {
+        Block::new(
+            self.genesis_header(),
+            vec![Transaction::from_hex(GENESIS_COINBASE).unwrap()],
+        )
+    }
+}
+
+#[cfg(test)]
+mod network_tests {
+    use super::*;
+
+    const NETWORKS: [Network; 4] = [
+        Network::Mainnet,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ];
+
+    #[test]
+    fn test_genesis_blocks() {
+        for network in NETWORKS {
+            let block = network.genesis_block();
+            assert_eq!(block.hash(), network.genesis_hash());
+            assert_eq!(block.header.prev_block, [0; 32]);
+            assert!(block.validate_merkle_root());
+            assert!(block.header.check_pow());
+            assert_eq!(block.header.bits, network.pow_limit_bits());
+        }
+    }
+
+    #[test]
+    fn test_genesis_headers_differ() {
+        let mainnet = Network::Mainnet.genesis_header();
+        let regtest = Network::Regtest.genesis_header();
+        assert_eq!(mainnet.merkle_root, regtest.merkle_root);
+        assert_eq!(mainnet.timestamp, 1231006505);
+        assert_eq!(regtest.bits, 0x207fffff);
+        assert_eq!(Network::Signet.genesis_header().bits, 0x1e0377ae);
+        assert_ne!(
+            Network::Testnet.genesis_hash(),
+            Network::Regtest.genesis_hash()
+        );
+    }
+
+    #[test]
+    fn test_script_flags_by_height() {
+        let mainnet = Network::Mainnet;
+        let hash = [0; 32];
+        let base =
+            VerificationFlags::P2SH | VerificationFlags::WITNESS | VerificationFlags::TAPROOT;
+        assert_eq!(mainnet.script_flags(1, &hash), base);
+        assert_eq!(
+            mainnet.script_flags(363_725, &hash),
+            base | VerificationFlags::DERSIG
+        );
+        let all = mainnet.script_flags(481_824, &hash);
+        assert_eq!(all, VerificationFlags::MANDATORY);
+        assert_eq!(
+            mainnet.script_flags(170_060, &txid_from_hex(BIP16_EXCEPTION).unwrap()),
+            VerificationFlags::NONE
+        );
+        let taproot_exception = txid_from_hex(TAPROOT_EXCEPTION).unwrap();
+        assert!(!mainnet
+            .script_flags(692_261, &taproot_exception)
+            .contains(VerificationFlags::TAPROOT));
+        // The exception blocks are only special on mainnet.
+        assert_eq!(
+            Network::Regtest.script_flags(1, &taproot_exception),
+            VerificationFlags::MANDATORY
+        );
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let mainnet = Network::Mainnet;
+        assert_eq!(mainnet.block_subsidy(0), 50 * COIN);
+        assert_eq!(mainnet.block_subsidy(209_999), 50 * COIN);
+        assert_eq!(mainnet.block_subsidy(210_000), 25 * COIN);
+        assert_eq!(mainnet.block_subsidy(840_000), 3_1250_0000);
+        // The last satoshi is paid in the 33rd era, the shift can't go past 63.
+        assert_eq!(mainnet.block_subsidy(32 * 210_000), 1);
+        assert_eq!(mainnet.block_subsidy(33 * 210_000), 0);
+        assert_eq!(mainnet.block_subsidy(u32::MAX), 0);
+        assert_eq!(Network::Regtest.block_subsidy(150), 25 * COIN);
+    }
+
+    #[test]
+    fn test_total_supply() {
+        let mainnet = Network::Mainnet;
+        assert_eq!(mainnet.total_supply(0), 50 * COIN);
+        assert_eq!(mainnet.total_supply(209_999), 210_000 * 50 * COIN);
+        assert_eq!(
+            mainnet.total_supply(210_000),
+            210_000 * 50 * COIN + 25 * COIN
+        );
+        assert_eq!(mainnet.max_supply(), 2_099_999_997_690_000);
+        assert!(mainnet.max_supply() < crate::validation::MAX_MONEY);
+        assert_eq!(
+            Network::Regtest.max_supply(),
+            (0..=150 * 64)
+                .map(|height| Network::Regtest.block_subsidy(height))
+                .sum::<u64>()
+        );
+    }
+}
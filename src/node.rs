@@ -0,0 +1,1268 @@
+// A full node, tying the chain state, the mempool and the peer-to-peer protocol
+// together. Blocks are kept on disk in a data directory, and the UTXO set flushed
+// there picked up at startup; the addresses known, the fee estimates and the
+// mempool are saved there on shutdown, the mempool taken in again by load_mempool.
+// Headers are synced from every peer and the blocks of the best header chain
+// downloaded from all of them at once. Once synced, transactions peers relay go
+// through the mempool and on to the other peers, those missing parents kept as
+// orphans until the parents come and those paying too little until a child pays for
+// them as a package. New blocks are announced, and what peers ask for is served
+// from the chain and the mempool. Blocks connected and transactions taken in are
+// notified to subscribers. Node works on envelopes and is told the time; run drives
+// one with the peers of a PeerManager.
+use crate::block::{Block, BlockHeader};
+use crate::chain::{BlockStorage, BlockStore, ChainState, ChainUpdate, FileBackend};
+use crate::mempool::{Accepted, Mempool};
+use crate::network::Network;
+use crate::notify::{Notification, NOTIFICATION_CAPACITY};
+use crate::p2p::addrman::AddrMan;
+use crate::p2p::download::BlockDownloader;
+use crate::p2p::envelope::NetworkEnvelope;
+use crate::p2p::inventory::{GetData, Inv, Inventory, InventoryType, NotFound};
+use crate::p2p::manager::{PeerEvent, PeerId, PeerManager};
+use crate::p2p::message::{GetAddr, GetHeaders, Headers, Message, MAX_HEADERS_RESULTS};
+use crate::p2p::orphans::OrphanPool;
+use crate::p2p::seeds::DnsSeeder;
+use crate::p2p::version::PeerInfo;
+use crate::policy::{FeeEstimator, FeeRate};
+use crate::transaction::{txid_to_hex, Transaction};
+use crate::types::errors::{Errors, MempoolError, ValidationError};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, info_span, warn};
+
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+// Transactions asked for and not received in time may be asked of another peer, as
+// GETDATA_TX_INTERVAL in Bitcoin Core.
+pub const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// Transactions refused for their fee kept for a child to pay for them, the oldest
+// dropped past it.
+pub const MAX_LOW_FEE_PARENTS: usize = 100;
+// How often run checks for timeouts and connects to more peers.
+const TICK_INTERVAL: Duration = Duration::from_millis(500);
+const BLOCKS_DIR: &str = "blocks";
+const CHAINSTATE_FILE: &str = "chainstate.dat";
+const PEERS_FILE: &str = "peers.dat";
+const FEE_ESTIMATES_FILE: &str = "fee_estimates.dat";
+const MEMPOOL_FILE: &str = "mempool.dat";
+
+// What a node asks of the peers it is connected to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeAction {
+    Send(PeerId, NetworkEnvelope),
+    Disconnect(PeerId),
+}
+
+#[derive(Clone, Debug)]
+struct NodePeer {
+    address: SocketAddr,
+    info: PeerInfo,
+}
+
+pub struct Node {
+    network: Network,
+    datadir: PathBuf,
+    chain: ChainState<FileBackend, BlockStore>,
+    mempool: Mempool,
+    fees: FeeEstimator,
+    addrman: AddrMan,
+    downloader: BlockDownloader,
+    peers: BTreeMap<PeerId, NodePeer>,
+    // Transactions asked for, by the hash asked with, and when.
+    requested: HashMap<[u8; 32], Instant>,
+    // Transactions relayed to us paying too little to be accepted alone, by txid, in
+    // the order received.
+    low_fee: HashMap<[u8; 32], Transaction>,
+    low_fee_order: VecDeque<[u8; 32]>,
+    orphans: OrphanPool,
+    // Addresses to stay connected to instead of those of addrman.
+    connect: Vec<SocketAddr>,
+    max_outbound: usize,
+    blocks_only: bool,
+    notifications: broadcast::Sender<Notification>,
+}
+
+impl Node {
+    // Opens the node kept in datadir, creating it if needed. The chain picks up from
+    // the UTXO set saved by flush, connecting again only the stored blocks past it.
+    pub fn open(network: Network, datadir: impl AsRef<Path>) -> Result<Self, Errors> {
+        let datadir = datadir.as_ref().to_path_buf();
+        let store = BlockStore::open(datadir.join(BLOCKS_DIR))?;
+        let mut children: HashMap<[u8; 32], Vec<BlockHeader>> = HashMap::new();
+        for hash in store.hashes() {
+            if let Some(block) = store.get(&hash)? {
+                children
+                    .entry(block.header.prev_block)
+                    .or_default()
+                    .push(block.header);
+            }
+        }
+        let utxos = FileBackend::open(datadir.join(CHAINSTATE_FILE))?;
+        let mut chain = ChainState::with_storage(network, utxos, store)?;
+        // Parents first, so every header connects.
+        let mut parents = vec![network.genesis_hash()];
+        while let Some(parent) = parents.pop() {
+            for header in children.remove(&parent).unwrap_or_default() {
+                match chain.accept_header(header) {
+                    Ok(hash) => parents.push(hash),
+                    Err(e) => warn!(
+                        target: "validation",
+                        hash = header.hash_hex(), error = %e,
+                        "Stored block has an invalid header"
+                    ),
+                }
+            }
+        }
+        chain.resume()?;
+        chain.activate_best_chain();
+
+        let peers_path = datadir.join(PEERS_FILE);
+        let addrman = if peers_path.exists() {
+            AddrMan::load(&peers_path, network)?
+        } else {
+            AddrMan::new(network)
+        };
+        let fees_path = datadir.join(FEE_ESTIMATES_FILE);
+        let fees = if fees_path.exists() {
+            FeeEstimator::load(&fees_path)?
+        } else {
+            FeeEstimator::new()
+        };
+        Ok(Node {
+            network,
+            datadir,
+            chain,
+            mempool: Mempool::new(),
+            fees,
+            addrman,
+            downloader: BlockDownloader::new(network),
+            peers: BTreeMap::new(),
+            requested: HashMap::new(),
+            low_fee: HashMap::new(),
+            low_fee_order: VecDeque::new(),
+            orphans: OrphanPool::new(),
+            connect: Vec::new(),
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+            blocks_only: false,
+            notifications: broadcast::channel(NOTIFICATION_CAPACITY).0,
+        })
+    }
+
+    pub fn with_mempool(mut self, mempool: Mempool) -> Self {
+        self.mempool = mempool;
+        self
+    }
+
+    // Connects to these peers only, as -connect in Bitcoin Core.
+    pub fn with_connect(mut self, peers: Vec<SocketAddr>) -> Self {
+        self.connect = peers;
+        self
+    }
+
+    pub fn with_max_outbound(mut self, max_outbound: usize) -> Self {
+        self.max_outbound = max_outbound;
+        self
+    }
+
+    // Ignores the transactions of peers. The PeerManager driving the node should be
+    // blocks-only too, so peers don't send any.
+    pub fn with_blocks_only(mut self, blocks_only: bool) -> Self {
+        self.blocks_only = blocks_only;
+        self
+    }
+
+    // Keeps the transaction index, so any transaction mined can be looked up.
+    pub fn with_tx_index(mut self, tx_index: bool) -> Self {
+        if tx_index {
+            self.chain = self.chain.with_tx_index();
+        }
+        self
+    }
+
+    // Keeps the index of the transactions paying and spending each script.
+    pub fn with_address_index(mut self, address_index: bool) -> Self {
+        if address_index {
+            self.chain = self.chain.with_address_index();
+        }
+        self
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn datadir(&self) -> &Path {
+        &self.datadir
+    }
+
+    pub fn chain(&self) -> &ChainState<FileBackend, BlockStore> {
+        &self.chain
+    }
+
+    pub fn orphans(&self) -> &OrphanPool {
+        &self.orphans
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    pub fn fee_estimator(&self) -> &FeeEstimator {
+        &self.fees
+    }
+
+    pub fn addrman(&self) -> &AddrMan {
+        &self.addrman
+    }
+
+    // Whether blocks of the best header chain are still to be connected.
+    pub fn is_syncing(&self) -> bool {
+        self.chain.tip().hash != self.chain.headers().tip().hash
+    }
+
+    // Notifications of what happens from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.notifications.subscribe()
+    }
+
+    // Fills an empty address manager from the network's DNS seeds, blocking on the
+    // lookups. How many addresses were added.
+    pub fn seed(&mut self) -> usize {
+        DnsSeeder::new(self.network).bootstrap(&mut self.addrman)
+    }
+
+    // Writes the UTXO set, addresses, fee estimates and mempool to the data
+    // directory. Blocks are written as they come.
+    pub fn flush(&mut self) -> Result<(), Errors> {
+        self.chain.flush()?;
+        self.addrman.save(self.datadir.join(PEERS_FILE))?;
+        self.fees.save(self.datadir.join(FEE_ESTIMATES_FILE))?;
+        self.mempool.save(self.datadir.join(MEMPOOL_FILE))
+    }
+
+    // Takes the mempool saved by flush back in, revalidating its transactions on top
+    // of the chain's tip, once the mempool's options are set. They aren't tracked for
+    // fee estimation, as when they went in is unknown. How many were taken in.
+    pub fn load_mempool(&mut self) -> Result<usize, Errors> {
+        let path = self.datadir.join(MEMPOOL_FILE);
+        if !path.exists() {
+            return Ok(0);
+        }
+        let loaded = self.mempool.load(&path, &self.chain)?;
+        info!(
+            target: "mempool",
+            loaded, size = self.mempool.len(),
+            "Loaded the mempool"
+        );
+        Ok(loaded)
+    }
+
+    fn get_headers(&self) -> NetworkEnvelope {
+        let headers = self.chain.headers();
+        GetHeaders::new(headers.locator(headers.tip()), [0; 32]).to_envelope(self.network)
+    }
+
+    // Starts syncing headers with a peer that finished its handshake.
+    pub fn connected(
+        &mut self,
+        peer: PeerId,
+        address: SocketAddr,
+        info: PeerInfo,
+    ) -> Vec<NodeAction> {
+        info!(
+            target: "net",
+            peer, %address, version = info.version, user_agent = info.user_agent.as_str(),
+            start_height = info.start_height,
+            "Peer connected"
+        );
+        self.addrman.good(&address);
+        self.downloader.add_peer(peer, &info);
+        self.peers.insert(peer, NodePeer { address, info });
+        vec![
+            NodeAction::Send(peer, self.get_headers()),
+            NodeAction::Send(peer, GetAddr.to_envelope(self.network)),
+        ]
+    }
+
+    pub fn disconnected(&mut self, peer: PeerId) {
+        if self.peers.remove(&peer).is_some() {
+            debug!(target: "net", peer, "Peer disconnected");
+        }
+        self.downloader.remove_peer(peer);
+        self.orphans.remove_for_peer(peer);
+    }
+
+    // Handles a message of peer, returning what it calls for. Peers sending messages
+    // that don't parse, invalid headers or invalid blocks are disconnected.
+    pub fn receive(
+        &mut self,
+        peer: PeerId,
+        envelope: &NetworkEnvelope,
+        now: Instant,
+    ) -> Vec<NodeAction> {
+        let _span = info_span!(
+            target: "net",
+            "receive",
+            peer,
+            command = envelope.command.as_str()
+        )
+        .entered();
+        let Some(address) = self.peers.get_mut(&peer).map(|node_peer| {
+            node_peer.info.update(envelope);
+            node_peer.address
+        }) else {
+            return Vec::new();
+        };
+        let result = if let Some(headers) = envelope.message::<Headers>() {
+            headers.map(|headers| self.receive_headers(peer, headers))
+        } else if let Some(getheaders) = envelope.message::<GetHeaders>() {
+            getheaders.map(|getheaders| self.receive_getheaders(peer, getheaders))
+        } else if let Some(inv) = envelope.message::<Inv>() {
+            inv.map(|inv| self.receive_inv(peer, inv, now))
+        } else if let Some(getdata) = envelope.message::<GetData>() {
+            getdata.map(|getdata| self.receive_getdata(peer, getdata))
+        } else if let Some(notfound) = envelope.message::<NotFound>() {
+            notfound.map(|notfound| {
+                for item in notfound.inventory {
+                    self.requested.remove(&item.hash);
+                }
+                Vec::new()
+            })
+        } else if let Some(tx) = envelope.message::<Transaction>() {
+            tx.map(|tx| self.receive_transaction(peer, tx, now))
+        } else if let Some(block) = envelope.message::<Block>() {
+            block.map(|block| {
+                self.downloader.receive(peer, block);
+                let mut actions = self.process_blocks();
+                actions.extend(self.request_blocks(now));
+                actions
+            })
+        } else {
+            self.addrman.receive(envelope, address).map(|replies| {
+                replies
+                    .into_iter()
+                    .map(|reply| NodeAction::Send(peer, reply))
+                    .collect()
+            })
+        };
+        result.unwrap_or_else(|e| {
+            warn!(
+                target: "net",
+                peer, command = envelope.command.as_str(), error = %e,
+                "Disconnecting peer sending a malformed message"
+            );
+            vec![NodeAction::Disconnect(peer)]
+        })
+    }
+
+    // Asks for more blocks and drops stalling peers and requests timed out. Expired
+    // transactions leave the mempool.
+    pub fn tick(&mut self, now: Instant) -> Vec<NodeAction> {
+        let mut actions = self.request_blocks(now);
+        for peer in self.downloader.check_timeouts(now) {
+            info!(target: "net", peer, "Disconnecting peer stalling block download");
+            actions.push(NodeAction::Disconnect(peer));
+        }
+        self.requested
+            .retain(|_, asked| now.duration_since(*asked) < TX_REQUEST_TIMEOUT);
+        self.orphans.expire(now);
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        self.mempool.expire(unix_time);
+        let mempool = &self.mempool;
+        self.fees.retain_transactions(|txid| mempool.contains(txid));
+        actions
+    }
+
+    fn request_blocks(&mut self, now: Instant) -> Vec<NodeAction> {
+        self.downloader
+            .request(now)
+            .into_iter()
+            .map(|(peer, envelope)| NodeAction::Send(peer, envelope))
+            .collect()
+    }
+
+    fn receive_headers(&mut self, peer: PeerId, headers: Headers) -> Vec<NodeAction> {
+        let batch = headers.headers;
+        let Some(first) = batch.first() else {
+            return Vec::new();
+        };
+        // Announced blocks we miss the parents of: sync up to them first.
+        if !self.chain.headers().contains(&first.prev_block) {
+            return vec![NodeAction::Send(peer, self.get_headers())];
+        }
+        if batch
+            .windows(2)
+            .any(|pair| pair[1].prev_block != pair[0].hash())
+        {
+            warn!(target: "net", peer, "Disconnecting peer sending non-continuous headers");
+            return vec![NodeAction::Disconnect(peer)];
+        }
+        let mut last = None;
+        for header in &batch {
+            match self.chain.accept_header(*header) {
+                Ok(hash) => last = Some(hash),
+                Err(e) => {
+                    warn!(
+                        target: "validation",
+                        peer, hash = header.hash_hex(), error = %e,
+                        "Disconnecting peer sending an invalid header"
+                    );
+                    return vec![NodeAction::Disconnect(peer)];
+                }
+            }
+        }
+        debug!(
+            target: "net",
+            peer, count = batch.len(), height = self.chain.headers().height(),
+            "Received headers"
+        );
+        let mut actions = Vec::new();
+        if batch.len() == MAX_HEADERS_RESULTS {
+            let headers = self.chain.headers();
+            let entry = headers.get(&last.unwrap()).unwrap();
+            let getheaders = GetHeaders::new(headers.locator(entry), [0; 32]);
+            actions.push(NodeAction::Send(peer, getheaders.to_envelope(self.network)));
+        }
+        let headers = self.chain.headers();
+        let start = headers.fork_point(self.chain.tip()).height + 1;
+        let wanted: Vec<[u8; 32]> = (start..=headers.height())
+            .map(|height| headers.at_height(height).unwrap().hash)
+            .filter(|hash| !self.chain.is_invalid(hash))
+            .collect();
+        self.downloader.want(wanted);
+        actions
+    }
+
+    fn receive_getheaders(&self, peer: PeerId, getheaders: GetHeaders) -> Vec<NodeAction> {
+        let headers = self.chain.headers().find_headers(
+            &getheaders.locator,
+            &getheaders.stop,
+            MAX_HEADERS_RESULTS,
+        );
+        vec![NodeAction::Send(
+            peer,
+            Headers { headers }.to_envelope(self.network),
+        )]
+    }
+
+    // Blocks announced are looked for with getheaders, and transactions asked for
+    // unless syncing or blocks-only.
+    fn receive_inv(&mut self, peer: PeerId, inv: Inv, now: Instant) -> Vec<NodeAction> {
+        let mut actions = Vec::new();
+        if inv.inventory.iter().any(|item| item.kind.is_block()) {
+            actions.push(NodeAction::Send(peer, self.get_headers()));
+        }
+        if self.blocks_only || self.is_syncing() {
+            return actions;
+        }
+        let mut wanted = Vec::new();
+        for item in inv.inventory {
+            let (kind, known) = match item.kind {
+                InventoryType::Tx => (
+                    InventoryType::WitnessTx,
+                    self.mempool.contains(&item.hash) || self.orphans.contains(&item.hash),
+                ),
+                InventoryType::Wtx => (
+                    InventoryType::Wtx,
+                    self.mempool.get_by_wtxid(&item.hash).is_some()
+                        || self.orphans.contains_wtxid(&item.hash),
+                ),
+                _ => continue,
+            };
+            if !known && !self.requested.contains_key(&item.hash) {
+                self.requested.insert(item.hash, now);
+                wanted.push(Inventory::new(kind, item.hash));
+            }
+        }
+        if !wanted.is_empty() {
+            let getdata = GetData { inventory: wanted };
+            actions.push(NodeAction::Send(peer, getdata.to_envelope(self.network)));
+        }
+        actions
+    }
+
+    // Sends what we have of what was asked for, stripped of witness data unless asked
+    // with it, and a notfound for the rest.
+    fn receive_getdata(&self, peer: PeerId, getdata: GetData) -> Vec<NodeAction> {
+        let mut actions = Vec::new();
+        let mut missing = Vec::new();
+        for item in getdata.inventory {
+            let witness = item.kind.is_witness();
+            let envelope = if item.kind.is_transaction() {
+                let entry = match item.kind {
+                    InventoryType::Wtx => self.mempool.get_by_wtxid(&item.hash),
+                    _ => self.mempool.get(&item.hash),
+                };
+                entry.map(|entry| {
+                    let payload = if witness {
+                        entry.tx.serialize()
+                    } else {
+                        entry.tx.serialize_legacy()
+                    };
+                    NetworkEnvelope::new(self.network, Transaction::COMMAND, payload)
+                })
+            } else if item.kind.is_block() {
+                self.chain
+                    .get_block(&item.hash)
+                    .ok()
+                    .flatten()
+                    .map(|block| {
+                        let payload = if witness {
+                            block.serialize()
+                        } else {
+                            block.serialize_legacy()
+                        };
+                        NetworkEnvelope::new(self.network, Block::COMMAND, payload)
+                    })
+            } else {
+                None
+            };
+            match envelope {
+                Some(envelope) => actions.push(NodeAction::Send(peer, envelope)),
+                None => missing.push(item),
+            }
+        }
+        if !missing.is_empty() {
+            let notfound = NotFound { inventory: missing };
+            actions.push(NodeAction::Send(peer, notfound.to_envelope(self.network)));
+        }
+        actions
+    }
+
+    fn receive_transaction(
+        &mut self,
+        peer: PeerId,
+        tx: Transaction,
+        now: Instant,
+    ) -> Vec<NodeAction> {
+        self.requested.remove(&tx.txid());
+        self.requested.remove(&tx.wtxid());
+        if self.blocks_only || self.is_syncing() {
+            return Vec::new();
+        }
+        let txid = tx.txid_hex();
+        let e = match self.accept_transaction(tx.clone()) {
+            Ok(accepted) => {
+                let mut actions = self.announce_transaction(&accepted.txid, Some(peer));
+                actions.extend(self.process_orphans(&[accepted]));
+                return actions;
+            }
+            Err(e) => e,
+        };
+        let package = match e {
+            // An orphan of it may pay for it, or a child still to come.
+            MempoolError::MinRelayFee | MempoolError::MempoolMinFee => {
+                for (child, _) in self.orphans.children(&tx) {
+                    if let Ok(accepted) = self.accept_package(vec![tx.clone(), child.clone()]) {
+                        self.orphans.remove(&child.txid());
+                        let mut actions = self.announce_package(&accepted, Some(peer));
+                        actions.extend(self.process_orphans(&accepted));
+                        return actions;
+                    }
+                }
+                self.keep_low_fee(tx);
+                None
+            }
+            // It may spend parents waiting for a child to pay for them, or else
+            // parents yet to come.
+            MempoolError::Invalid(ValidationError::MissingInput(_)) => {
+                let mut package: Vec<Transaction> = Vec::new();
+                for input in &tx.inputs {
+                    if let Some(parent) = self.low_fee.get(&input.previous_output.txid) {
+                        if !package.contains(parent) {
+                            package.push(parent.clone());
+                        }
+                    }
+                }
+                if package.is_empty() {
+                    return self.keep_orphan(peer, tx, now);
+                }
+                package.push(tx);
+                Some(package)
+            }
+            _ => None,
+        };
+        if let Some(package) = package {
+            return match self.accept_package(package) {
+                Ok(accepted) => {
+                    let mut actions = self.announce_package(&accepted, Some(peer));
+                    actions.extend(self.process_orphans(&accepted));
+                    actions
+                }
+                Err(e) => {
+                    debug!(target: "mempool", peer, txid, reason = %e, "Rejected package");
+                    Vec::new()
+                }
+            };
+        }
+        debug!(target: "mempool", peer, txid, reason = %e, "Rejected transaction");
+        Vec::new()
+    }
+
+    fn keep_low_fee(&mut self, tx: Transaction) {
+        let txid = tx.txid();
+        if self.low_fee.insert(txid, tx).is_none() {
+            self.low_fee_order.push_back(txid);
+        }
+        while self.low_fee.len() > MAX_LOW_FEE_PARENTS {
+            if let Some(oldest) = self.low_fee_order.pop_front() {
+                self.low_fee.remove(&oldest);
+            }
+        }
+    }
+
+    // Keeps a transaction of peer missing parents until they come, asking peer for
+    // those not asked for yet.
+    fn keep_orphan(&mut self, peer: PeerId, tx: Transaction, now: Instant) -> Vec<NodeAction> {
+        let mut missing = Vec::new();
+        for input in &tx.inputs {
+            let parent = input.previous_output.txid;
+            if !self.mempool.contains(&parent)
+                && !self.requested.contains_key(&parent)
+                && !missing.contains(&parent)
+            {
+                missing.push(parent);
+            }
+        }
+        let txid = tx.txid_hex();
+        if !self.orphans.add(tx, peer, now) {
+            return Vec::new();
+        }
+        debug!(
+            target: "mempool",
+            peer, txid, missing = missing.len(), orphans = self.orphans.len(),
+            "Keeping orphan transaction"
+        );
+        if missing.is_empty() {
+            return Vec::new();
+        }
+        let inventory = missing
+            .into_iter()
+            .map(|parent| {
+                self.requested.insert(parent, now);
+                Inventory::new(InventoryType::WitnessTx, parent)
+            })
+            .collect();
+        let getdata = GetData { inventory };
+        vec![NodeAction::Send(peer, getdata.to_envelope(self.network))]
+    }
+
+    // Accepts the orphans of the transactions accepted, and theirs in turn. Those
+    // still missing parents stay, the others refused are dropped.
+    fn process_orphans(&mut self, accepted: &[Accepted]) -> Vec<NodeAction> {
+        let mut actions = Vec::new();
+        let mut parents: VecDeque<Transaction> = accepted
+            .iter()
+            .filter_map(|accepted| self.mempool.get(&accepted.txid))
+            .map(|entry| entry.tx.clone())
+            .collect();
+        while let Some(parent) = parents.pop_front() {
+            for (child, from) in self.orphans.children(&parent) {
+                let txid = child.txid();
+                match self.accept_transaction(child.clone()) {
+                    Ok(_) => {
+                        self.orphans.remove(&txid);
+                        actions.extend(self.announce_transaction(&txid, Some(from)));
+                        parents.push_back(child);
+                    }
+                    Err(MempoolError::Invalid(ValidationError::MissingInput(_))) => {}
+                    Err(e) => {
+                        self.orphans.remove(&txid);
+                        debug!(
+                            target: "mempool",
+                            peer = from, txid = txid_to_hex(&txid), reason = %e,
+                            "Rejected orphan transaction"
+                        );
+                        if matches!(e, MempoolError::MinRelayFee | MempoolError::MempoolMinFee) {
+                            self.keep_low_fee(child);
+                        }
+                    }
+                }
+            }
+        }
+        actions
+    }
+
+    // Adds delta to the fee the mempool counts txid as paying, in the pool or not.
+    pub fn prioritise_transaction(&mut self, txid: [u8; 32], delta: i64) {
+        self.mempool.prioritise_transaction(txid, delta);
+        debug!(
+            target: "mempool",
+            txid = txid_to_hex(&txid), delta, total = self.mempool.fee_delta(&txid),
+            "Prioritised transaction"
+        );
+    }
+
+    // Accepts a transaction of ours into the mempool, announcing it to every peer.
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<Vec<NodeAction>, MempoolError> {
+        let accepted = self.accept_transaction(tx)?;
+        let mut actions = self.announce_transaction(&accepted.txid, None);
+        actions.extend(self.process_orphans(&[accepted]));
+        Ok(actions)
+    }
+
+    // Accepts a package of ours, a child with its parents, announcing what went in to
+    // every peer.
+    pub fn submit_package(
+        &mut self,
+        txs: Vec<Transaction>,
+    ) -> Result<Vec<NodeAction>, MempoolError> {
+        let accepted = self.accept_package(txs)?;
+        let mut actions = self.announce_package(&accepted, None);
+        actions.extend(self.process_orphans(&accepted));
+        Ok(actions)
+    }
+
+    // Connects a block of ours, such as a miner's, announcing it to every peer once
+    // it extends the best chain. Fails if the block, or one it made the chain switch
+    // to, is invalid.
+    pub fn submit_block(&mut self, block: Block) -> Result<Vec<NodeAction>, ValidationError> {
+        let hash = block.hash();
+        let mut update = self.chain.accept_block(block)?;
+        let invalid = update
+            .invalid
+            .iter()
+            .position(|(invalid, _)| *invalid == hash);
+        let error = invalid.map(|i| update.invalid.remove(i).1);
+        let actions = self.update_mempool(update);
+        match error {
+            Some(e) => Err(e),
+            None => Ok(actions),
+        }
+    }
+
+    // Adds tx to the mempool, tracking it for fee estimation.
+    fn accept_transaction(&mut self, tx: Transaction) -> Result<Accepted, MempoolError> {
+        let accepted = self.mempool.accept(tx, &self.chain)?;
+        let feerate = self.mempool.get(&accepted.txid).unwrap().feerate();
+        self.fees
+            .process_transaction(accepted.txid, feerate, self.chain.height());
+        for replaced in &accepted.replaced {
+            self.fees.remove_transaction(&replaced.txid());
+        }
+        debug!(
+            target: "mempool",
+            txid = txid_to_hex(&accepted.txid), feerate = %feerate,
+            replaced = accepted.replaced.len(), size = self.mempool.len(),
+            "Accepted transaction"
+        );
+        let tx = self.mempool.get(&accepted.txid).unwrap().tx.clone();
+        let _ = self.notifications.send(Notification::TransactionAdded(tx));
+        Ok(accepted)
+    }
+
+    // Adds the transactions of a package to the mempool. Their feerates are the
+    // package's rather than their own, so fee estimation leaves them out.
+    fn accept_package(&mut self, txs: Vec<Transaction>) -> Result<Vec<Accepted>, MempoolError> {
+        let accepted = self.mempool.accept_package(txs, &self.chain)?;
+        for accepted in &accepted {
+            if self.low_fee.remove(&accepted.txid).is_some() {
+                self.low_fee_order.retain(|txid| *txid != accepted.txid);
+            }
+            let tx = self.mempool.get(&accepted.txid).unwrap().tx.clone();
+            let _ = self.notifications.send(Notification::TransactionAdded(tx));
+        }
+        debug!(
+            target: "mempool",
+            accepted = accepted.len(), size = self.mempool.len(),
+            "Accepted package"
+        );
+        Ok(accepted)
+    }
+
+    // Announces a transaction of the mempool to the peers taking transactions at its
+    // feerate, but the one it came from.
+    fn announce_transaction(&self, txid: &[u8; 32], from: Option<PeerId>) -> Vec<NodeAction> {
+        match self.mempool.get(txid) {
+            Some(entry) => self.announce_at(txid, entry.feerate(), from),
+            None => Vec::new(),
+        }
+    }
+
+    // Announces the transactions accepted of a package at the feerate of them all,
+    // which a parent paying less than peers take wouldn't get to alone.
+    fn announce_package(&self, accepted: &[Accepted], from: Option<PeerId>) -> Vec<NodeAction> {
+        let entries: Vec<_> = accepted
+            .iter()
+            .filter_map(|accepted| self.mempool.get(&accepted.txid))
+            .collect();
+        let fee = entries.iter().map(|entry| entry.fee).sum();
+        let vsize = entries.iter().map(|entry| entry.vsize).sum();
+        let feerate = FeeRate::from_fee_and_vsize(fee, vsize);
+        accepted
+            .iter()
+            .flat_map(|accepted| self.announce_at(&accepted.txid, feerate, from))
+            .collect()
+    }
+
+    fn announce_at(
+        &self,
+        txid: &[u8; 32],
+        feerate: FeeRate,
+        from: Option<PeerId>,
+    ) -> Vec<NodeAction> {
+        let Some(entry) = self.mempool.get(txid) else {
+            return Vec::new();
+        };
+        self.peers
+            .iter()
+            .filter(|(id, peer)| {
+                Some(**id) != from && peer.info.relay && peer.info.accepts_feerate(feerate)
+            })
+            .map(|(id, peer)| {
+                let item = if peer.info.wtxid_relay {
+                    Inventory::new(InventoryType::Wtx, entry.tx.wtxid())
+                } else {
+                    Inventory::new(InventoryType::Tx, *txid)
+                };
+                let inv = Inv {
+                    inventory: vec![item],
+                };
+                NodeAction::Send(*id, inv.to_envelope(self.network))
+            })
+            .collect()
+    }
+
+    // Connects the blocks downloaded in order. Peers sending invalid blocks are
+    // dropped, the blocks asked of others.
+    fn process_blocks(&mut self) -> Vec<NodeAction> {
+        let mut actions = Vec::new();
+        for (peer, block) in self.downloader.take_ready() {
+            let hash = block.hash();
+            match self.chain.accept_block(block) {
+                Ok(update) => {
+                    if update.invalid.iter().any(|(invalid, _)| *invalid == hash) {
+                        warn!(
+                            target: "validation",
+                            peer, hash = txid_to_hex(&hash),
+                            "Disconnecting peer sending an invalid block"
+                        );
+                        actions.push(NodeAction::Disconnect(peer));
+                    }
+                    actions.extend(self.update_mempool(update));
+                }
+                Err(e) => {
+                    warn!(
+                        target: "validation",
+                        peer, hash = txid_to_hex(&hash), error = %e,
+                        "Disconnecting peer sending an invalid block"
+                    );
+                    actions.push(NodeAction::Disconnect(peer));
+                    self.downloader.remove_peer(peer);
+                    self.downloader.retry(hash);
+                }
+            }
+        }
+        actions
+    }
+
+    // Follows the chain moving: what blocks confirmed leaves the mempool, what
+    // blocks disconnected went back to it, and the new tip is announced once synced.
+    fn update_mempool(&mut self, update: ChainUpdate) -> Vec<NodeAction> {
+        let mut headers = Vec::new();
+        for hash in &update.connected {
+            let Ok(Some(block)) = self.chain.get_block(hash) else {
+                continue;
+            };
+            let height = self.chain.headers().get(hash).unwrap().height;
+            for tx in self.mempool.remove_for_block(&block) {
+                self.fees.remove_transaction(&tx.txid());
+            }
+            self.orphans.remove_for_block(&block);
+            self.fees
+                .process_block(height, block.transactions.iter().map(Transaction::txid));
+            info!(
+                target: "validation",
+                hash = block.header.hash_hex(), height, txs = block.transactions.len(),
+                mempool = self.mempool.len(),
+                "New tip"
+            );
+            headers.push(block.header);
+            let _ = self.notifications.send(Notification::BlockConnected(block));
+        }
+        for tx in update.resurrected {
+            let _ = self.accept_transaction(tx);
+        }
+        if headers.is_empty() || self.is_syncing() {
+            return Vec::new();
+        }
+        self.peers
+            .iter()
+            .map(|(id, peer)| {
+                let envelope = if peer.info.send_headers {
+                    Headers {
+                        headers: headers.clone(),
+                    }
+                    .to_envelope(self.network)
+                } else {
+                    Inv {
+                        inventory: headers
+                            .iter()
+                            .map(|header| Inventory::new(InventoryType::Block, header.hash()))
+                            .collect(),
+                    }
+                    .to_envelope(self.network)
+                };
+                NodeAction::Send(*id, envelope)
+            })
+            .collect()
+    }
+}
+
+// Carries out actions through the peers of manager.
+pub fn apply(manager: &PeerManager, actions: Vec<NodeAction>) {
+    for action in actions {
+        match action {
+            NodeAction::Send(peer, envelope) => {
+                let _ = manager.send_envelope(peer, envelope);
+            }
+            NodeAction::Disconnect(peer) => manager.disconnect(peer),
+        }
+    }
+}
+
+// Drives node with the peers of manager until shutdown completes, then disconnects
+// them and flushes the node. The peers of with_connect are kept connected; without
+// them, new addresses of the address manager are tried one at a time while there
+// are fewer than max_outbound peers.
+pub async fn run(
+    node: Arc<Mutex<Node>>,
+    manager: PeerManager,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Errors> {
+    let mut events = manager.subscribe();
+    let connect = node.lock().unwrap().connect.clone();
+    for address in &connect {
+        manager.add_peer(*address);
+    }
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    let mut attempt: Option<tokio::task::JoinHandle<()>> = None;
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            event = events.recv() => {
+                let actions = match event {
+                    Ok(PeerEvent::Connected(peer, info)) => {
+                        let address = manager
+                            .peers()
+                            .into_iter()
+                            .find(|(id, _, _)| *id == peer)
+                            .map(|(_, address, _)| address);
+                        match address {
+                            Some(address) => node.lock().unwrap().connected(peer, address, info),
+                            None => Vec::new(),
+                        }
+                    }
+                    Ok(PeerEvent::Message(peer, envelope)) => {
+                        node.lock().unwrap().receive(peer, &envelope, Instant::now())
+                    }
+                    Ok(PeerEvent::Disconnected(peer)) => {
+                        node.lock().unwrap().disconnected(peer);
+                        Vec::new()
+                    }
+                    Err(RecvError::Lagged(_)) => Vec::new(),
+                    Err(RecvError::Closed) => break,
+                };
+                apply(&manager, actions);
+            }
+            _ = ticks.tick() => {
+                let actions = node.lock().unwrap().tick(Instant::now());
+                apply(&manager, actions);
+                if !connect.is_empty() || attempt.as_ref().is_some_and(|task| !task.is_finished()) {
+                    continue;
+                }
+                let peers = manager.peers();
+                let mut node = node.lock().unwrap();
+                if peers.len() >= node.max_outbound {
+                    continue;
+                }
+                let Some(address) = node
+                    .addrman
+                    .select(false)
+                    .map(|info| info.address.socket_addr())
+                    .filter(|address| peers.iter().all(|(_, connected, _)| connected != address))
+                else {
+                    continue;
+                };
+                node.addrman.attempt(&address);
+                let manager = manager.clone();
+                attempt = Some(tokio::spawn(async move {
+                    let _ = manager.connect(address).await;
+                }));
+            }
+        }
+    }
+    for (peer, address, _) in manager.peers() {
+        manager.remove_peer(address);
+        manager.disconnect(peer);
+    }
+    node.lock().unwrap().flush()
+}
+
+#[cfg(test)]
+mod node_tests {
+    use super::*;
+    use crate::helper::sha256;
+    use crate::p2p::version::{NODE_NETWORK, NODE_WITNESS};
+    use crate::script::Script;
+    use crate::test_util::{blocks, solve, temp_path};
+    use crate::transaction::{OutPoint, TxIn, TxOut, Witness};
+    use std::fs;
+
+    fn datadir(name: &str) -> PathBuf {
+        let dir = temp_path(&format!("node_tests_{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn full_node() -> PeerInfo {
+        PeerInfo {
+            services: NODE_NETWORK | NODE_WITNESS,
+            relay: true,
+            ..PeerInfo::default()
+        }
+    }
+
+    fn sent<M: Message>(actions: &[NodeAction], to: PeerId) -> Vec<M> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                NodeAction::Send(peer, envelope) if *peer == to => envelope.message::<M>(),
+                _ => None,
+            })
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    // Spends outpoint, of OP_TRUE or with witness of a P2WSH of it, to a P2WSH of
+    // OP_TRUE.
+    fn spend(outpoint: OutPoint, value: u64, witness: bool) -> Transaction {
+        let mut script = vec![0x00, 0x20];
+        script.extend(sha256(&[0x51]));
+        let mut input = TxIn::new(outpoint, Script::new(), 0xffffffff);
+        if witness {
+            input.witness = Witness::from_elements(vec![vec![0x51]]);
+        }
+        Transaction::new(2, vec![input], vec![TxOut::new(value, script.into())], 0)
+    }
+
+    // Syncs node from peer 1 with a chain of blocks.
+    fn sync(node: &mut Node, blocks: &[Block]) {
+        let now = Instant::now();
+        let headers = Headers {
+            headers: blocks.iter().map(|block| block.header).collect(),
+        };
+        node.receive(1, &headers.to_envelope(Network::Regtest), now);
+        let getdata = sent::<GetData>(&node.tick(now), 1);
+        assert_eq!(getdata[0].inventory.len(), blocks.len().min(16));
+        for block in blocks {
+            node.receive(1, &block.to_envelope(Network::Regtest), now);
+        }
+        assert!(!node.is_syncing());
+    }
+
+    #[test]
+    fn test_sync_and_restart() {
+        let dir = datadir("sync");
+        let address: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let mut node = Node::open(Network::Regtest, &dir).unwrap();
+        let actions = node.connected(1, address, full_node());
+        assert_eq!(sent::<GetHeaders>(&actions, 1).len(), 1);
+        let blocks = blocks(25);
+        sync(&mut node, &blocks[..20]);
+        assert_eq!(node.chain().height(), 20);
+        // Another peer finds the chain served.
+        node.connected(2, address, full_node());
+        let getheaders = GetHeaders::new(vec![blocks[9].hash()], [0; 32]);
+        let actions = node.receive(2, &getheaders.to_envelope(Network::Regtest), Instant::now());
+        assert_eq!(sent::<Headers>(&actions, 2)[0].headers.len(), 10);
+        let getdata = GetData {
+            inventory: vec![Inventory::new(
+                InventoryType::WitnessBlock,
+                blocks[4].hash(),
+            )],
+        };
+        let actions = node.receive(2, &getdata.to_envelope(Network::Regtest), Instant::now());
+        assert_eq!(sent::<Block>(&actions, 2), vec![blocks[4].clone()]);
+        node.flush().unwrap();
+        sync(&mut node, &blocks[20..]);
+
+        // The chain picks up from the UTXO set flushed, connecting again the stored
+        // blocks past it.
+        drop(node);
+        let node = Node::open(Network::Regtest, &dir).unwrap();
+        assert_eq!(node.chain().tip().hash, blocks[24].hash());
+        assert_eq!(node.chain().connect_times().count(), 5);
+        assert!(!node.is_syncing());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relays_transactions_and_blocks() {
+        let dir = datadir("relay");
+        let address: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let mut node = Node::open(Network::Regtest, &dir).unwrap();
+        node.connected(1, address, full_node());
+        let mut blocks = blocks(102);
+        let last = blocks.pop().unwrap();
+        sync(&mut node, &blocks);
+        node.connected(2, address, full_node());
+        let mut notifications = node.subscribe();
+
+        // A transaction announced by one peer is asked for, accepted and announced to
+        // the other.
+        let now = Instant::now();
+        let coinbase = &blocks[0].transactions[0];
+        let tx = Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new(coinbase.txid(), 0),
+                Script::new(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(coinbase.outputs[0].value - 10_000, {
+                let mut script = vec![0x00, 0x14];
+                script.extend([7; 20]);
+                script.into()
+            })],
+            0,
+        );
+        let inv = Inv {
+            inventory: vec![Inventory::new(InventoryType::Tx, tx.txid())],
+        };
+        let actions = node.receive(1, &inv.to_envelope(Network::Regtest), now);
+        assert_eq!(sent::<GetData>(&actions, 1)[0].inventory[0].hash, tx.txid());
+        let actions = node.receive(1, &tx.to_envelope(Network::Regtest), now);
+        assert!(node.mempool().contains(&tx.txid()));
+        assert!(sent::<Inv>(&actions, 1).is_empty());
+        assert_eq!(sent::<Inv>(&actions, 2)[0].inventory[0].hash, tx.txid());
+        assert!(node.fee_estimator().estimate_fee(1).is_none());
+        assert_eq!(
+            notifications.try_recv(),
+            Ok(Notification::TransactionAdded(tx.clone()))
+        );
+
+        // A new block confirms it, and is announced as it is no more syncing.
+        let mut block = last;
+        block.transactions.push(tx.clone());
+        block.header.merkle_root = block.compute_merkle_root();
+        solve(&mut block.header);
+        let headers = Headers {
+            headers: vec![block.header],
+        };
+        node.receive(1, &headers.to_envelope(Network::Regtest), now);
+        node.tick(now);
+        let actions = node.receive(1, &block.to_envelope(Network::Regtest), now);
+        assert_eq!(node.chain().height(), 102);
+        assert!(node.mempool().is_empty());
+        assert_eq!(sent::<Inv>(&actions, 2)[0].inventory[0].hash, block.hash());
+        assert_eq!(node.fee_estimator().best_height(), 102);
+        assert_eq!(
+            notifications.try_recv(),
+            Ok(Notification::BlockConnected(block))
+        );
+
+        // Peers sending what doesn't parse are dropped.
+        let garbage = NetworkEnvelope::new(Network::Regtest, Headers::COMMAND, vec![0xff]);
+        assert_eq!(
+            node.receive(2, &garbage, now),
+            vec![NodeAction::Disconnect(2)]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relays_packages() {
+        let dir = datadir("package");
+        let address: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let mut node = Node::open(Network::Regtest, &dir).unwrap();
+        node.connected(1, address, full_node());
+        let blocks = blocks(101);
+        sync(&mut node, &blocks);
+        node.connected(2, address, full_node());
+
+        // A parent paying nothing is refused, then taken with a child paying for it.
+        let now = Instant::now();
+        let coinbase = &blocks[0].transactions[0];
+        let parent = spend(
+            OutPoint::new(coinbase.txid(), 0),
+            coinbase.outputs[0].value,
+            false,
+        );
+        let actions = node.receive(1, &parent.to_envelope(Network::Regtest), now);
+        assert!(actions.is_empty());
+        assert!(node.mempool().is_empty());
+        let child = spend(
+            OutPoint::new(parent.txid(), 0),
+            parent.outputs[0].value - 10_000,
+            true,
+        );
+        let actions = node.receive(1, &child.to_envelope(Network::Regtest), now);
+        assert!(node.mempool().contains(&parent.txid()));
+        assert!(node.mempool().contains(&child.txid()));
+        let announced: Vec<[u8; 32]> = sent::<Inv>(&actions, 2)
+            .iter()
+            .map(|inv| inv.inventory[0].hash)
+            .collect();
+        assert_eq!(announced, vec![parent.txid(), child.txid()]);
+
+        // Both are back after a restart, the child paying for the parent again.
+        node.flush().unwrap();
+        drop(node);
+        let mut node = Node::open(Network::Regtest, &dir).unwrap();
+        assert_eq!(node.load_mempool().unwrap(), 2);
+        assert!(node.mempool().contains(&parent.txid()));
+        assert!(node.mempool().contains(&child.txid()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolves_orphans() {
+        let dir = datadir("orphans");
+        let address: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let mut node = Node::open(Network::Regtest, &dir).unwrap();
+        node.connected(1, address, full_node());
+        let blocks = blocks(101);
+        sync(&mut node, &blocks);
+
+        // Children arriving first are kept and their parent asked for.
+        let now = Instant::now();
+        let coinbase = &blocks[0].transactions[0];
+        let value = (coinbase.outputs[0].value - 10_000) / 2;
+        let mut parent = spend(OutPoint::new(coinbase.txid(), 0), value, false);
+        parent.outputs.push(parent.outputs[0].clone());
+        let child = spend(OutPoint::new(parent.txid(), 0), value - 10_000, true);
+        let invalid = spend(OutPoint::new(parent.txid(), 1), value - 10_000, false);
+        let actions = node.receive(1, &child.to_envelope(Network::Regtest), now);
+        assert_eq!(
+            sent::<GetData>(&actions, 1)[0].inventory[0].hash,
+            parent.txid()
+        );
+        node.receive(1, &invalid.to_envelope(Network::Regtest), now);
+        assert_eq!(node.orphans().len(), 2);
+
+        // The parent brings in the child, the one failing its script is dropped.
+        node.receive(1, &parent.to_envelope(Network::Regtest), now);
+        assert!(node.mempool().contains(&parent.txid()));
+        assert!(node.mempool().contains(&child.txid()));
+        assert!(!node.mempool().contains(&invalid.txid()));
+        assert!(node.orphans().is_empty());
+
+        // Those of a peer gone go with it.
+        let orphan = spend(OutPoint::new([9; 32], 0), 1000, false);
+        node.receive(1, &orphan.to_envelope(Network::Regtest), now);
+        assert_eq!(node.orphans().len(), 1);
+        node.disconnected(1);
+        assert!(node.orphans().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
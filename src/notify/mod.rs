@@ -0,0 +1,112 @@
+// Chain events for indexers and wallets to follow, as Bitcoin Core publishes them
+// over ZMQ. The node sends a Notification to its subscribers for every block it
+// connects and every transaction its mempool takes in; each turns into messages of
+// the hashblock, rawblock, hashtx and rawtx topics, which the zmq module publishes.
+pub mod zmq;
+
+pub use zmq::{publish, DEFAULT_ZMQ_HIGH_WATER_MARK};
+
+use crate::block::Block;
+use crate::transaction::Transaction;
+
+// Notifications a subscriber may fall behind by before missing some.
+pub const NOTIFICATION_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notification {
+    BlockConnected(Block),
+    TransactionAdded(Transaction),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Topic {
+    HashBlock,
+    HashTx,
+    RawBlock,
+    RawTx,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 4] = [
+        Topic::HashBlock,
+        Topic::HashTx,
+        Topic::RawBlock,
+        Topic::RawTx,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Topic::HashBlock => "hashblock",
+            Topic::HashTx => "hashtx",
+            Topic::RawBlock => "rawblock",
+            Topic::RawTx => "rawtx",
+        }
+    }
+
+    // Parses the name of bitcoind's -zmqpub<topic> options.
+    pub fn from_name(name: &str) -> Option<Topic> {
+        Topic::ALL.into_iter().find(|topic| topic.name() == name)
+    }
+}
+
+// Hashes go in their displayed byte order, as bitcoind sends them.
+fn reversed(hash: [u8; 32]) -> Vec<u8> {
+    let mut hash = hash.to_vec();
+    hash.reverse();
+    hash
+}
+
+fn transaction_messages(tx: &Transaction) -> [(Topic, Vec<u8>); 2] {
+    [
+        (Topic::HashTx, reversed(tx.txid())),
+        (Topic::RawTx, tx.serialize()),
+    ]
+}
+
+impl Notification {
+    // The message bodies of each topic. A block connected is followed by each of its
+    // transactions, mined without passing through the mempool or not.
+    pub fn messages(&self) -> Vec<(Topic, Vec<u8>)> {
+        match self {
+            Notification::BlockConnected(block) => {
+                let mut messages = vec![
+                    (Topic::HashBlock, reversed(block.hash())),
+                    (Topic::RawBlock, block.serialize()),
+                ];
+                messages.extend(block.transactions.iter().flat_map(transaction_messages));
+                messages
+            }
+            Notification::TransactionAdded(tx) => transaction_messages(tx).to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod notify_tests {
+    use super::*;
+    use crate::network::Network;
+
+    #[test]
+    fn test_messages() {
+        let genesis = Network::Regtest.genesis_block();
+        let messages = Notification::BlockConnected(genesis.clone()).messages();
+        let topics: Vec<Topic> = messages.iter().map(|(topic, _)| *topic).collect();
+        assert_eq!(
+            topics,
+            [
+                Topic::HashBlock,
+                Topic::RawBlock,
+                Topic::HashTx,
+                Topic::RawTx
+            ]
+        );
+        assert_eq!(hex::encode(&messages[0].1), genesis.header.hash_hex());
+        assert_eq!(messages[1].1, genesis.serialize());
+        let coinbase = &genesis.transactions[0];
+        assert_eq!(hex::encode(&messages[2].1), coinbase.txid_hex());
+        let messages = Notification::TransactionAdded(coinbase.clone()).messages();
+        assert_eq!(messages[1], (Topic::RawTx, coinbase.serialize()));
+        assert_eq!(Topic::from_name("rawblock"), Some(Topic::RawBlock));
+        assert_eq!(Topic::from_name("sequence"), None);
+    }
+}
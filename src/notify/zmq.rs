@@ -0,0 +1,385 @@
+// Publishing notifications as a ZMQ PUB socket, for the SUB sockets of any ZMQ
+// library to connect to as they would to bitcoind. Only what that takes of ZMTP 3.0
+// is spoken: the greeting, the NULL mechanism's READY handshake, subscriptions and
+// heartbeats. Every message is in three frames as bitcoind's: the topic, the body
+// and a little-endian sequence number counting that topic's messages.
+use super::{Notification, Topic};
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info_span, Instrument};
+
+// As Bitcoin Core's -zmqpub<topic>hwm default: messages a subscriber may fall
+// behind by before missing some.
+pub const DEFAULT_ZMQ_HIGH_WATER_MARK: usize = 1000;
+pub const GREETING_SIZE: usize = 64;
+// Subscribers only send subscriptions and heartbeats, never anything large.
+const MAX_INBOUND_FRAME: u64 = 4096;
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub more: bool,
+    pub command: bool,
+    pub body: Vec<u8>,
+}
+
+// ZMTP 3.0 with the NULL mechanism, as the server of neither side.
+pub fn greeting() -> [u8; GREETING_SIZE] {
+    let mut greeting = [0; GREETING_SIZE];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting
+}
+
+pub fn check_greeting(greeting: &[u8; GREETING_SIZE]) -> Result<(), Errors> {
+    if greeting[0] != 0xff || greeting[9] & 1 == 0 {
+        return Err(Errors::InvalidZmq("bad signature"));
+    }
+    if greeting[10] < 3 {
+        return Err(Errors::InvalidZmq("unsupported version"));
+    }
+    if &greeting[12..32] != b"NULL\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0" {
+        return Err(Errors::InvalidZmq("unsupported mechanism"));
+    }
+    Ok(())
+}
+
+pub fn encode_frame(body: &[u8], more: bool, command: bool) -> Vec<u8> {
+    let mut flags = 0;
+    if more {
+        flags |= FLAG_MORE;
+    }
+    if command {
+        flags |= FLAG_COMMAND;
+    }
+    let mut result = Vec::with_capacity(body.len() + 9);
+    match u8::try_from(body.len()) {
+        Ok(size) => result.extend([flags, size]),
+        Err(_) => {
+            result.push(flags | FLAG_LONG);
+            result.extend((body.len() as u64).to_be_bytes());
+        }
+    }
+    result.extend(body);
+    result
+}
+
+pub async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<Frame, Errors> {
+    let io = |e: std::io::Error| Errors::Io(e.to_string());
+    let flags = reader.read_u8().await.map_err(io)?;
+    let size = match flags & FLAG_LONG {
+        0 => reader.read_u8().await.map_err(io)? as u64,
+        _ => reader.read_u64().await.map_err(io)?,
+    };
+    if size > MAX_INBOUND_FRAME {
+        return Err(Errors::InvalidZmq("frame too large"));
+    }
+    let mut body = vec![0; size as usize];
+    reader.read_exact(&mut body).await.map_err(io)?;
+    Ok(Frame {
+        more: flags & FLAG_MORE != 0,
+        command: flags & FLAG_COMMAND != 0,
+        body,
+    })
+}
+
+// The body of a command frame.
+pub fn command(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut body = vec![name.len() as u8];
+    body.extend(name.as_bytes());
+    body.extend(data);
+    body
+}
+
+pub fn parse_command(body: &[u8]) -> Result<(&str, &[u8]), Errors> {
+    let invalid = Errors::InvalidZmq("bad command");
+    let (&length, rest) = body.split_first().ok_or(invalid)?;
+    if rest.len() < length as usize {
+        return Err(Errors::InvalidZmq("bad command"));
+    }
+    let (name, data) = rest.split_at(length as usize);
+    let name = std::str::from_utf8(name).map_err(|_| Errors::InvalidZmq("bad command"))?;
+    Ok((name, data))
+}
+
+// The READY command of a socket_type socket.
+pub fn ready(socket_type: &str) -> Vec<u8> {
+    let mut properties = vec![b"Socket-Type".len() as u8];
+    properties.extend(b"Socket-Type");
+    properties.extend((socket_type.len() as u32).to_be_bytes());
+    properties.extend(socket_type.as_bytes());
+    command("READY", &properties)
+}
+
+// The Socket-Type property of a READY command's data.
+fn socket_type(mut properties: &[u8]) -> Option<&[u8]> {
+    while let Some((&length, rest)) = properties.split_first() {
+        let (name, rest) = rest.split_at_checked(length as usize)?;
+        let (size, rest) = rest.split_at_checked(4)?;
+        let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+        let (value, rest) = rest.split_at_checked(size)?;
+        if name.eq_ignore_ascii_case(b"Socket-Type") {
+            return Some(value);
+        }
+        properties = rest;
+    }
+    None
+}
+
+// What a subscriber asked for, relayed from the task reading it to the one writing.
+enum Request {
+    Subscribe(Vec<u8>),
+    Unsubscribe(Vec<u8>),
+    // A heartbeat, with the context to answer with.
+    Ping(Vec<u8>),
+}
+
+fn request(frame: Frame) -> Result<Option<Request>, Errors> {
+    if !frame.command {
+        return Ok(match frame.body.split_first() {
+            Some((1, topic)) => Some(Request::Subscribe(topic.to_vec())),
+            Some((0, topic)) => Some(Request::Unsubscribe(topic.to_vec())),
+            _ => None,
+        });
+    }
+    let (name, data) = parse_command(&frame.body)?;
+    Ok(match name {
+        "SUBSCRIBE" => Some(Request::Subscribe(data.to_vec())),
+        "CANCEL" => Some(Request::Unsubscribe(data.to_vec())),
+        "PING" if data.len() >= 2 => Some(Request::Ping(data[2..].to_vec())),
+        _ => None,
+    })
+}
+
+// Relays the requests of a subscriber from reader until it goes away or sends a bad
+// frame. Those arriving while requests is full are dropped, as ZMQ does past the
+// high water mark, so a subscriber flooding pings is held to that many.
+async fn read_requests(mut reader: impl AsyncRead + Unpin, requests: mpsc::Sender<Request>) {
+    while let Ok(frame) = read_frame(&mut reader).await {
+        match request(frame) {
+            Ok(Some(request)) => match requests.try_send(request) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    debug!(target: "zmq", "Dropped a request past the high water mark");
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+            },
+            Ok(None) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// Relays the multipart messages to the subscriber on stream that subscribed to a
+// prefix of their topic, until either side goes away.
+async fn serve_subscriber(
+    stream: TcpStream,
+    mut messages: broadcast::Receiver<Arc<Vec<Vec<u8>>>>,
+) -> Result<(), Errors> {
+    let io = |e: std::io::Error| Errors::Io(e.to_string());
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    writer.write_all(&greeting()).await.map_err(io)?;
+    let mut theirs = [0; GREETING_SIZE];
+    reader.read_exact(&mut theirs).await.map_err(io)?;
+    check_greeting(&theirs)?;
+    writer
+        .write_all(&encode_frame(&ready("PUB"), false, true))
+        .await
+        .map_err(io)?;
+    let frame = read_frame(&mut reader).await?;
+    let (name, properties) = parse_command(&frame.body)?;
+    if !frame.command || name != "READY" {
+        return Err(Errors::InvalidZmq("expected READY"));
+    }
+    if !matches!(socket_type(properties), Some(b"SUB") | Some(b"XSUB")) {
+        return Err(Errors::InvalidZmq("not a subscriber"));
+    }
+
+    // Frames are read apart, for a message to be written while one is half read.
+    let (requests, mut received) = mpsc::channel(DEFAULT_ZMQ_HIGH_WATER_MARK);
+    tokio::spawn(read_requests(reader, requests));
+    let mut subscriptions: Vec<Vec<u8>> = Vec::new();
+    loop {
+        let data = tokio::select! {
+            request = received.recv() => match request {
+                Some(Request::Subscribe(topic)) => {
+                    subscriptions.push(topic);
+                    continue;
+                }
+                Some(Request::Unsubscribe(topic)) => {
+                    if let Some(index) = subscriptions.iter().position(|sub| *sub == topic) {
+                        subscriptions.remove(index);
+                    }
+                    continue;
+                }
+                Some(Request::Ping(context)) => encode_frame(&command("PONG", &context), false, true),
+                None => return Ok(()),
+            },
+            message = messages.recv() => match message {
+                Ok(parts) => {
+                    if !subscriptions.iter().any(|sub| parts[0].starts_with(sub)) {
+                        continue;
+                    }
+                    parts
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(index, part)| encode_frame(part, index + 1 < parts.len(), false))
+                        .collect()
+                }
+                // Past the high water mark, messages are dropped as ZMQ does.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        writer.write_all(&data).await.map_err(io)?;
+    }
+}
+
+// Publishes the messages of topics from notifications to every subscriber
+// connecting to listener, until notifications close.
+pub async fn publish(
+    listener: TcpListener,
+    mut notifications: broadcast::Receiver<Notification>,
+    topics: Vec<Topic>,
+) {
+    let (messages, _) = broadcast::channel(DEFAULT_ZMQ_HIGH_WATER_MARK);
+    let mut sequences: HashMap<Topic, u32> = HashMap::new();
+    loop {
+        tokio::select! {
+            connection = listener.accept() => {
+                if let Ok((stream, address)) = connection {
+                    debug!(target: "zmq", %address, "Subscriber connected");
+                    let subscriber = serve_subscriber(stream, messages.subscribe());
+                    tokio::spawn(subscriber.instrument(info_span!(target: "zmq", "subscriber", %address)));
+                }
+            }
+            notification = notifications.recv() => {
+                let notification = match notification {
+                    Ok(notification) => notification,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                for (topic, body) in notification.messages() {
+                    if !topics.contains(&topic) {
+                        continue;
+                    }
+                    let sequence = sequences.entry(topic).or_default();
+                    let parts = vec![topic.name().as_bytes().to_vec(), body, sequence.to_le_bytes().to_vec()];
+                    *sequence = sequence.wrapping_add(1);
+                    let _ = messages.send(Arc::new(parts));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod zmq_tests {
+    use super::*;
+    use crate::network::Network;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_frames_and_commands() {
+        let mut data = encode_frame(b"rawtx", true, false);
+        assert_eq!(data[..2], [FLAG_MORE, 5]);
+        data.extend(encode_frame(&[7; 300], false, true));
+        assert_eq!(data[7], FLAG_LONG | FLAG_COMMAND);
+        let mut reader = data.as_slice();
+        let frame = read_frame(&mut reader).await.unwrap();
+        assert_eq!((frame.more, frame.command), (true, false));
+        assert_eq!(frame.body, b"rawtx");
+        let frame = read_frame(&mut reader).await.unwrap();
+        assert_eq!(frame.body, vec![7; 300]);
+
+        let body = ready("SUB");
+        let (name, properties) = parse_command(&body).unwrap();
+        assert_eq!(name, "READY");
+        assert_eq!(socket_type(properties), Some(&b"SUB"[..]));
+        assert!(parse_command(&[9, b'R']).is_err());
+        assert_eq!(check_greeting(&greeting()), Ok(()));
+        let mut curve = greeting();
+        curve[12..17].copy_from_slice(b"CURVE");
+        assert!(check_greeting(&curve).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drops_requests_past_high_water_mark() {
+        let mut data = Vec::new();
+        for context in 0u8..10 {
+            data.extend(encode_frame(
+                &command("PING", &[0, 0, context]),
+                false,
+                true,
+            ));
+        }
+        // Nobody takes the requests, and reading still runs to the end.
+        let (requests, mut received) = mpsc::channel(4);
+        read_requests(data.as_slice(), requests).await;
+        for context in 0u8..4 {
+            match received.recv().await {
+                Some(Request::Ping(answer)) => assert_eq!(answer, [context]),
+                _ => panic!("expected a ping"),
+            }
+        }
+        assert!(received.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publishes_to_subscribers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (notifications, receiver) = broadcast::channel(16);
+        tokio::spawn(publish(
+            listener,
+            receiver,
+            vec![Topic::HashBlock, Topic::RawTx],
+        ));
+
+        // Subscribes as a ZMQ SUB socket would.
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&greeting()).await.unwrap();
+        let mut theirs = [0; GREETING_SIZE];
+        stream.read_exact(&mut theirs).await.unwrap();
+        check_greeting(&theirs).unwrap();
+        stream
+            .write_all(&encode_frame(&ready("SUB"), false, true))
+            .await
+            .unwrap();
+        let frame = read_frame(&mut stream).await.unwrap();
+        assert_eq!(parse_command(&frame.body).unwrap().0, "READY");
+        stream
+            .write_all(&encode_frame(b"\x01rawtx", false, false))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let genesis = Network::Regtest.genesis_block();
+        let coinbase = genesis.transactions[0].clone();
+        notifications
+            .send(Notification::TransactionAdded(coinbase.clone()))
+            .unwrap();
+        notifications
+            .send(Notification::BlockConnected(genesis))
+            .unwrap();
+        // Only rawtx was subscribed to, its sequence counting on.
+        for sequence in 0u32..2 {
+            let topic = read_frame(&mut stream).await.unwrap();
+            let body = read_frame(&mut stream).await.unwrap();
+            let number = read_frame(&mut stream).await.unwrap();
+            assert_eq!(topic.body, b"rawtx");
+            assert!(topic.more && body.more && !number.more);
+            assert_eq!(body.body, coinbase.serialize());
+            assert_eq!(number.body, sequence.to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,563 @@
+// Address manager, after Bitcoin Core's addrman. Addresses heard of go in the new
+// table and those we connected to in the tried one. Buckets of both tables are
+// picked by hashes keyed with a secret, over the network group of the address and
+// of who told us about it, so a single peer can only fill a few of them. An
+// address only takes a full slot from a terrible one.
+use super::envelope::NetworkEnvelope;
+use super::message::{GetAddr, Message};
+use super::version::{random_nonce, NetworkAddress};
+use crate::helper::{encode_varint, hash256, read_array, read_varint};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const NEW_BUCKET_COUNT: u64 = 1024;
+pub const TRIED_BUCKET_COUNT: u64 = 256;
+pub const BUCKET_SIZE: u64 = 64;
+// Buckets the addresses from one source group, and the tried addresses of one
+// group, are spread over.
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+// Most addresses in an addr message, and the share of ours answering a getaddr.
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+const MAX_GETADDR_PERCENT: usize = 23;
+// When addresses become terrible: not heard of in a month, never connected to in
+// three attempts, or failing ten times without success for a week.
+const HORIZON_SECS: u32 = 30 * 24 * 60 * 60;
+const RETRIES: u32 = 3;
+const MAX_FAILURES: u32 = 10;
+const MIN_FAIL_SECS: u32 = 7 * 24 * 60 * 60;
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as u32)
+}
+
+// An address with when it was last heard of, as sent in addr messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimestampedAddress {
+    pub timestamp: u32,
+    pub address: NetworkAddress,
+}
+
+impl TimestampedAddress {
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(TimestampedAddress {
+            timestamp: u32::from_le_bytes(read_array(reader)?),
+            address: NetworkAddress::parse(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.timestamp.to_le_bytes().to_vec();
+        result.extend(self.address.serialize());
+        result
+    }
+}
+
+// Addresses of nodes, sent unasked or in answer to getaddr.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Addr {
+    pub addresses: Vec<TimestampedAddress>,
+}
+
+impl Message for Addr {
+    const COMMAND: &'static str = "addr";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let count = read_varint(reader)?;
+        if count > MAX_ADDR_TO_SEND as u64 {
+            return Err(Errors::ValueOutOfRange);
+        }
+        let addresses = (0..count)
+            .map(|_| TimestampedAddress::parse(reader))
+            .collect::<Result<_, _>>()?;
+        Ok(Addr { addresses })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = encode_varint(self.addresses.len() as u64);
+        for address in &self.addresses {
+            result.extend(address.serialize());
+        }
+        result
+    }
+}
+
+// What is known about an address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddrInfo {
+    pub address: NetworkAddress,
+    // When it was last heard of.
+    pub timestamp: u32,
+    // The node that told us about it.
+    pub source: Ipv6Addr,
+    pub last_try: u32,
+    pub last_success: u32,
+    // Failed connection attempts since the last success.
+    pub attempts: u32,
+    pub tried: bool,
+}
+
+impl AddrInfo {
+    // Whether it is worth neither keeping nor sharing.
+    pub fn is_terrible(&self, now: u32) -> bool {
+        // Just tried, give it a chance.
+        if self.last_try != 0 && now.saturating_sub(self.last_try) < 60 {
+            return false;
+        }
+        self.timestamp > now + 10 * 60
+            || now.saturating_sub(self.timestamp) > HORIZON_SECS
+            || (self.last_success == 0 && self.attempts >= RETRIES)
+            || (now.saturating_sub(self.last_success) > MIN_FAIL_SECS
+                && self.attempts >= MAX_FAILURES)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = TimestampedAddress {
+            timestamp: self.timestamp,
+            address: self.address,
+        }
+        .serialize();
+        result.extend(self.source.octets());
+        result.extend(self.last_try.to_le_bytes());
+        result.extend(self.last_success.to_le_bytes());
+        result.extend(self.attempts.to_le_bytes());
+        result.push(self.tried as u8);
+        result
+    }
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let TimestampedAddress { timestamp, address } = TimestampedAddress::parse(reader)?;
+        Ok(AddrInfo {
+            address,
+            timestamp,
+            source: Ipv6Addr::from(read_array::<16>(reader)?),
+            last_try: u32::from_le_bytes(read_array(reader)?),
+            last_success: u32::from_le_bytes(read_array(reader)?),
+            attempts: u32::from_le_bytes(read_array(reader)?),
+            tried: read_array::<1>(reader)?[0] != 0,
+        })
+    }
+}
+
+// The /16 of IPv4 addresses and the /32 of IPv6 ones, tagged with which it is.
+fn group(ip: &Ipv6Addr) -> Vec<u8> {
+    match ip.to_ipv4_mapped() {
+        Some(ip) => vec![1, ip.octets()[0], ip.octets()[1]],
+        None => [&[2], &ip.octets()[..4]].concat(),
+    }
+}
+
+fn address_bytes(address: &NetworkAddress) -> Vec<u8> {
+    let mut result = address.ip.octets().to_vec();
+    result.extend(address.port.to_be_bytes());
+    result
+}
+
+// A slot of the new or tried table, by bucket and position in it.
+type Slot = (u64, u64);
+
+pub struct AddrMan {
+    network: Network,
+    key: [u8; 32],
+    entries: HashMap<SocketAddr, AddrInfo>,
+    new_table: HashMap<Slot, SocketAddr>,
+    tried_table: HashMap<Slot, SocketAddr>,
+}
+
+impl AddrMan {
+    pub fn new(network: Network) -> Self {
+        let mut key = [0; 32];
+        for chunk in key.chunks_exact_mut(8) {
+            chunk.copy_from_slice(&random_nonce().to_le_bytes());
+        }
+        AddrMan {
+            network,
+            key,
+            entries: HashMap::new(),
+            new_table: HashMap::new(),
+            tried_table: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn new_count(&self) -> usize {
+        self.new_table.len()
+    }
+
+    pub fn tried_count(&self) -> usize {
+        self.tried_table.len()
+    }
+
+    pub fn get(&self, address: &SocketAddr) -> Option<&AddrInfo> {
+        self.entries.get(address)
+    }
+
+    fn keyed_hash(&self, parts: &[&[u8]]) -> u64 {
+        let mut data = self.key.to_vec();
+        for part in parts {
+            data.extend_from_slice(part);
+        }
+        u64::from_le_bytes(hash256(&data)[..8].try_into().unwrap())
+    }
+
+    fn new_slot(&self, info: &AddrInfo) -> Slot {
+        let source_group = group(&info.source);
+        let spread = self.keyed_hash(&[&group(&info.address.ip), &source_group])
+            % NEW_BUCKETS_PER_SOURCE_GROUP;
+        let bucket = self.keyed_hash(&[&source_group, &spread.to_le_bytes()]) % NEW_BUCKET_COUNT;
+        (bucket, self.position(false, bucket, &info.address))
+    }
+
+    fn tried_slot(&self, info: &AddrInfo) -> Slot {
+        let spread = self.keyed_hash(&[&address_bytes(&info.address)]) % TRIED_BUCKETS_PER_GROUP;
+        let bucket = self.keyed_hash(&[&group(&info.address.ip), &spread.to_le_bytes()])
+            % TRIED_BUCKET_COUNT;
+        (bucket, self.position(true, bucket, &info.address))
+    }
+
+    fn position(&self, tried: bool, bucket: u64, address: &NetworkAddress) -> u64 {
+        self.keyed_hash(&[
+            &[tried as u8],
+            &bucket.to_le_bytes(),
+            &address_bytes(address),
+        ]) % BUCKET_SIZE
+    }
+
+    // Puts info in its new slot, evicting a terrible occupant. Whether it was.
+    fn insert_new(&mut self, info: AddrInfo, now: u32) -> bool {
+        let slot = self.new_slot(&info);
+        if let Some(occupant) = self.new_table.get(&slot) {
+            if !self.entries[occupant].is_terrible(now) {
+                return false;
+            }
+            let occupant = *occupant;
+            self.entries.remove(&occupant);
+        }
+        let address = info.address.socket_addr();
+        self.new_table.insert(slot, address);
+        self.entries.insert(address, info);
+        true
+    }
+
+    // Adds addresses heard of from source, returning how many were new.
+    pub fn add(&mut self, addresses: &[TimestampedAddress], source: SocketAddr) -> usize {
+        let source = NetworkAddress::new(source, 0).ip;
+        let now = now();
+        let mut added = 0;
+        for heard in addresses {
+            let address = heard.address.socket_addr();
+            if address.port() == 0 || address.ip().is_unspecified() {
+                continue;
+            }
+            if let Some(info) = self.entries.get_mut(&address) {
+                info.timestamp = info.timestamp.max(heard.timestamp);
+                info.address.services |= heard.address.services;
+                continue;
+            }
+            let info = AddrInfo {
+                address: heard.address,
+                timestamp: heard.timestamp,
+                source,
+                last_try: 0,
+                last_success: 0,
+                attempts: 0,
+                tried: false,
+            };
+            if self.insert_new(info, now) {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    // Counts a connection attempt, failed unless good follows.
+    pub fn attempt(&mut self, address: &SocketAddr) {
+        if let Some(info) = self.entries.get_mut(address) {
+            info.attempts += 1;
+            info.last_try = now();
+        }
+    }
+
+    // Moves an address we connected to into the tried table. The address whose slot
+    // it takes goes back to the new table, or is forgotten if its slot there is taken.
+    pub fn good(&mut self, address: &SocketAddr) {
+        let now = now();
+        let Some(info) = self.entries.get_mut(address) else {
+            return;
+        };
+        info.last_try = now;
+        info.last_success = now;
+        info.attempts = 0;
+        if info.tried {
+            return;
+        }
+        info.tried = true;
+        let info = info.clone();
+        let new_slot = self.new_slot(&info);
+        self.new_table.remove(&new_slot);
+        let tried_slot = self.tried_slot(&info);
+        if let Some(evicted) = self.tried_table.insert(tried_slot, *address) {
+            let mut evicted = self.entries.remove(&evicted).unwrap();
+            evicted.tried = false;
+            self.insert_new(evicted, now);
+        }
+    }
+
+    // An address to connect to, from either table with even odds unless new_only,
+    // skipping terrible ones.
+    pub fn select(&self, new_only: bool) -> Option<&AddrInfo> {
+        let now = now();
+        let use_tried = !new_only
+            && !self.tried_table.is_empty()
+            && (self.new_table.is_empty() || random_nonce().is_multiple_of(2));
+        let table = if use_tried {
+            &self.tried_table
+        } else {
+            &self.new_table
+        };
+        let candidates: Vec<&AddrInfo> = table
+            .values()
+            .map(|address| &self.entries[address])
+            .filter(|info| !info.is_terrible(now))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[(random_nonce() % candidates.len() as u64) as usize])
+    }
+
+    // What a getaddr gets: part of the addresses that aren't terrible.
+    pub fn get_addr(&self) -> Vec<TimestampedAddress> {
+        let now = now();
+        let count = MAX_ADDR_TO_SEND.min(self.entries.len() * MAX_GETADDR_PERCENT / 100);
+        self.entries
+            .values()
+            .filter(|info| !info.is_terrible(now))
+            .take(count)
+            .map(|info| TimestampedAddress {
+                timestamp: info.timestamp,
+                address: info.address,
+            })
+            .collect()
+    }
+
+    // Handles getaddr and addr messages from peer, returning the replies to send it.
+    // Other commands are ignored.
+    pub fn receive(
+        &mut self,
+        envelope: &NetworkEnvelope,
+        peer: SocketAddr,
+    ) -> Result<Vec<NetworkEnvelope>, Errors> {
+        if let Some(getaddr) = envelope.message::<GetAddr>() {
+            getaddr?;
+            let addr = Addr {
+                addresses: self.get_addr(),
+            };
+            return Ok(vec![addr.to_envelope(self.network)]);
+        }
+        if let Some(addr) = envelope.message::<Addr>() {
+            self.add(&addr?.addresses, peer);
+        }
+        Ok(Vec::new())
+    }
+
+    // Writes the key and every address to path, followed by a checksum of them. The
+    // file is replaced in a single rename.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Errors> {
+        let path = path.as_ref();
+        let mut data = self.key.to_vec();
+        data.extend(encode_varint(self.entries.len() as u64));
+        // Tried addresses first, so they get their slots back before new ones.
+        let mut entries: Vec<&AddrInfo> = self.entries.values().collect();
+        entries.sort_by_key(|info| !info.tried);
+        for info in entries {
+            data.extend(info.serialize());
+        }
+        data.extend(hash256(&data));
+        let temp_path = path.with_extension("new");
+        fs::write(&temp_path, data)
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|e| Errors::Io(e.to_string()))
+    }
+
+    // Reads addresses saved with save. Slots are worked out again from the key.
+    pub fn load(path: impl AsRef<Path>, network: Network) -> Result<Self, Errors> {
+        let mut data = fs::read(path.as_ref()).map_err(|e| Errors::Io(e.to_string()))?;
+        if data.len() < 64 {
+            return Err(Errors::Io("addresses file is corrupted".to_string()));
+        }
+        let checksum = data.split_off(data.len() - 32);
+        if checksum != hash256(&data) {
+            return Err(Errors::Io("addresses file is corrupted".to_string()));
+        }
+        let mut reader = data.as_slice();
+        let mut addrman = AddrMan {
+            network,
+            key: read_array(&mut reader)?,
+            entries: HashMap::new(),
+            new_table: HashMap::new(),
+            tried_table: HashMap::new(),
+        };
+        let now = now();
+        for _ in 0..read_varint(&mut reader)? {
+            let mut info = AddrInfo::parse(&mut reader)?;
+            let address = info.address.socket_addr();
+            let slot = addrman.tried_slot(&info);
+            if info.tried && !addrman.tried_table.contains_key(&slot) {
+                addrman.tried_table.insert(slot, address);
+                addrman.entries.insert(address, info);
+            } else {
+                info.tried = false;
+                addrman.insert_new(info, now);
+            }
+        }
+        if !reader.is_empty() {
+            return Err(Errors::TrailingData);
+        }
+        Ok(addrman)
+    }
+}
+
+#[cfg(test)]
+mod addrman_tests {
+    use super::*;
+    use crate::test_util::temp_path;
+
+    fn heard(ip: [u8; 4], port: u16) -> TimestampedAddress {
+        TimestampedAddress {
+            timestamp: now() - 60,
+            address: NetworkAddress::new(SocketAddr::from((ip, port)), 1),
+        }
+    }
+
+    #[test]
+    fn test_addr_message() {
+        let addr = Addr {
+            addresses: vec![heard([10, 0, 0, 1], 8333)],
+        };
+        let bytes = addr.serialize();
+        assert_eq!(bytes.len(), 1 + 30);
+        assert_eq!(Addr::parse(&mut bytes.as_slice()), Ok(addr));
+    }
+
+    #[test]
+    fn test_add_good_and_select() {
+        let mut addrman = AddrMan::new(Network::Regtest);
+        let source = SocketAddr::from(([1, 2, 3, 4], 8333));
+        let addresses: Vec<_> = (0..10u8).map(|i| heard([i + 20, 0, 0, 1], 8333)).collect();
+        // From sources of different groups, so they can't take the same slot.
+        for (i, address) in addresses.iter().enumerate() {
+            let source = SocketAddr::from(([i as u8 + 1, 0, 0, 1], 8333));
+            assert_eq!(addrman.add(&[*address], source), 1);
+        }
+        // Known and unroutable ones aren't added.
+        assert_eq!(addrman.add(&addresses[..2], source), 0);
+        assert_eq!(addrman.add(&[heard([0, 0, 0, 0], 8333)], source), 0);
+        assert_eq!((addrman.new_count(), addrman.tried_count()), (10, 0));
+
+        let first = addresses[0].address.socket_addr();
+        addrman.attempt(&first);
+        addrman.good(&first);
+        assert_eq!((addrman.new_count(), addrman.tried_count()), (9, 1));
+        assert!(addrman.get(&first).unwrap().tried);
+        assert!(addrman.select(false).is_some());
+        assert!(!addrman.select(true).unwrap().tried);
+    }
+
+    #[test]
+    fn test_terrible_addresses() {
+        let mut info = AddrInfo {
+            address: heard([8, 8, 8, 8], 8333).address,
+            timestamp: now(),
+            source: Ipv6Addr::UNSPECIFIED,
+            last_try: 0,
+            last_success: 0,
+            attempts: 0,
+            tried: false,
+        };
+        assert!(!info.is_terrible(now()));
+        info.attempts = RETRIES;
+        assert!(info.is_terrible(now()));
+        info.attempts = 0;
+        info.timestamp = now() - HORIZON_SECS - 1;
+        assert!(info.is_terrible(now()));
+    }
+
+    #[test]
+    fn test_one_source_fills_few_buckets() {
+        let mut addrman = AddrMan::new(Network::Regtest);
+        let source = SocketAddr::from(([1, 2, 3, 4], 8333));
+        let addresses: Vec<_> = (0..5000u32)
+            .map(|i| heard([(i >> 8) as u8 + 10, i as u8, 0, 1], 8333))
+            .collect();
+        for chunk in addresses.chunks(MAX_ADDR_TO_SEND) {
+            addrman.add(chunk, source);
+        }
+        let buckets: std::collections::HashSet<u64> = addrman
+            .new_table
+            .keys()
+            .map(|(bucket, _)| *bucket)
+            .collect();
+        assert!(buckets.len() as u64 <= NEW_BUCKETS_PER_SOURCE_GROUP);
+        assert!(addrman.len() as u64 <= NEW_BUCKETS_PER_SOURCE_GROUP * BUCKET_SIZE);
+    }
+
+    #[test]
+    fn test_getaddr_and_persistence() {
+        let mut addrman = AddrMan::new(Network::Regtest);
+        let peer = SocketAddr::from(([1, 2, 3, 4], 8333));
+        let addr = Addr {
+            addresses: vec![heard([9, 9, 9, 9], 8333)],
+        };
+        assert!(addrman
+            .receive(&addr.to_envelope(Network::Regtest), peer)
+            .unwrap()
+            .is_empty());
+        assert_eq!(addrman.len(), 1);
+        // Some may land on the slot of an earlier one, depending on the key.
+        let added: usize = (0..99u8)
+            .map(|i| {
+                let source = SocketAddr::from(([i + 100, 0, 0, 1], 8333));
+                addrman.add(&[heard([i + 20, i, 0, 1], 8333)], source)
+            })
+            .sum();
+        assert_eq!(addrman.len(), 1 + added);
+        let replies = addrman
+            .receive(&GetAddr.to_envelope(Network::Regtest), peer)
+            .unwrap();
+        let answer = replies[0].message::<Addr>().unwrap().unwrap();
+        assert_eq!(
+            answer.addresses.len(),
+            addrman.len() * MAX_GETADDR_PERCENT / 100
+        );
+
+        addrman.good(&addr.addresses[0].address.socket_addr());
+        let path = temp_path("peers").with_extension("dat");
+        addrman.save(&path).unwrap();
+        let loaded = AddrMan::load(&path, Network::Regtest).unwrap();
+        assert_eq!(loaded.entries, addrman.entries);
+        assert_eq!(loaded.new_table, addrman.new_table);
+        assert_eq!(loaded.tried_table, addrman.tried_table);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[40] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(AddrMan::load(&path, Network::Regtest).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
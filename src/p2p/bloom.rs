@@ -0,0 +1,216 @@
+// BIP37 bloom filters: a light client loads one with filterload, and peers only
+// announce and send it the transactions matching it, with merkleblock proofs for
+// the blocks confirming them. False positives hide which are really its own.
+use super::message::Message;
+use crate::block::MerkleBlock;
+use crate::helper::{encode_var_bytes, murmur3_32, read_array, read_var_bytes};
+use crate::types::errors::Errors;
+use std::io::Read;
+
+// Largest filter and most hash functions peers accept.
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+// Largest element filteradd takes, that of a script push.
+pub const MAX_FILTERADD_SIZE: usize = 520;
+// Seeds of the hash functions are i * BIP37_SEED_STEP + tweak.
+const BIP37_SEED_STEP: u32 = 0xfba4c795;
+
+// What a peer adds to the filter when an output matches, so spends of it match too.
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+// Only outputs paying to a public key or to multisig.
+pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl BloomFilter {
+    // A filter of size bytes, not updated by peers.
+    pub fn new(size: usize, hash_funcs: u32, tweak: u32) -> Self {
+        BloomFilter {
+            data: vec![0; size.clamp(1, MAX_BLOOM_FILTER_SIZE)],
+            hash_funcs: hash_funcs.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+            flags: BLOOM_UPDATE_NONE,
+        }
+    }
+
+    // The smallest filter matching elements others with probability fp_rate, sized
+    // as Bitcoin Core does.
+    pub fn for_elements(elements: usize, fp_rate: f64, tweak: u32) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let elements = elements.max(1) as f64;
+        let bits = (-1.0 / (ln2 * ln2) * elements * fp_rate.ln()) as usize;
+        let size = bits.min(MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        let hash_funcs = ((size * 8) as f64 / elements * ln2) as u32;
+        BloomFilter::new(size, hash_funcs, tweak)
+    }
+
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    fn bit_indexes<'a>(&'a self, element: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let bits = self.data.len() as u64 * 8;
+        (0..self.hash_funcs).map(move |i| {
+            let seed = i.wrapping_mul(BIP37_SEED_STEP).wrapping_add(self.tweak);
+            (murmur3_32(seed, element) as u64 % bits) as usize
+        })
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        let indexes: Vec<usize> = self.bit_indexes(element).collect();
+        for index in indexes {
+            self.data[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    // Whether element matches, as it does once inserted and by chance otherwise.
+    pub fn contains(&self, element: &[u8]) -> bool {
+        self.bit_indexes(element)
+            .all(|index| self.data[index / 8] & (1 << (index % 8)) != 0)
+    }
+
+    pub fn filterload(&self) -> FilterLoad {
+        FilterLoad {
+            filter: self.clone(),
+        }
+    }
+}
+
+// Replaces the filter the peer applies to what it sends us.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterLoad {
+    pub filter: BloomFilter,
+}
+
+impl Message for FilterLoad {
+    const COMMAND: &'static str = "filterload";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let data = read_var_bytes(reader)?;
+        let hash_funcs = u32::from_le_bytes(read_array(reader)?);
+        if data.len() > MAX_BLOOM_FILTER_SIZE || hash_funcs > MAX_HASH_FUNCS {
+            return Err(Errors::ValueOutOfRange);
+        }
+        Ok(FilterLoad {
+            filter: BloomFilter {
+                data,
+                hash_funcs,
+                tweak: u32::from_le_bytes(read_array(reader)?),
+                flags: read_array::<1>(reader)?[0],
+            },
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = encode_var_bytes(&self.filter.data);
+        result.extend(self.filter.hash_funcs.to_le_bytes());
+        result.extend(self.filter.tweak.to_le_bytes());
+        result.push(self.filter.flags);
+        result
+    }
+}
+
+// Adds an element to the loaded filter, sparing sending it whole again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterAdd {
+    pub element: Vec<u8>,
+}
+
+impl Message for FilterAdd {
+    const COMMAND: &'static str = "filteradd";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let element = read_var_bytes(reader)?;
+        if element.len() > MAX_FILTERADD_SIZE {
+            return Err(Errors::ValueOutOfRange);
+        }
+        Ok(FilterAdd { element })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        encode_var_bytes(&self.element)
+    }
+}
+
+// Sent for getdata entries of type FilteredBlock, followed by the matching
+// transactions.
+impl Message for MerkleBlock {
+    const COMMAND: &'static str = "merkleblock";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        MerkleBlock::parse(reader)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        MerkleBlock::serialize(self)
+    }
+}
+
+#[cfg(test)]
+mod bloom_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::p2p::message::FilterClear;
+
+    #[test]
+    fn test_insert_and_serialize() {
+        // Vectors of Bitcoin Core's bloom tests.
+        let elements = [
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        ]
+        .map(|element| hex::decode(element).unwrap());
+        for (tweak, expected) in [
+            (0, "03614e9b050000000000000001"),
+            (2147483649, "03ce4299050000000100008001"),
+        ] {
+            let mut filter = BloomFilter::for_elements(3, 0.01, tweak).with_flags(BLOOM_UPDATE_ALL);
+            filter.insert(&elements[0]);
+            assert!(filter.contains(&elements[0]));
+            assert!(
+                !filter.contains(&hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap())
+            );
+            filter.insert(&elements[1]);
+            filter.insert(&elements[2]);
+            assert!(elements.iter().all(|element| filter.contains(element)));
+            let filterload = filter.filterload();
+            assert_eq!(hex::encode(filterload.serialize()), expected);
+            assert_eq!(
+                FilterLoad::parse(&mut filterload.serialize().as_slice()),
+                Ok(filterload)
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter_messages() {
+        // Example of Programming Bitcoin.
+        let mut filter = BloomFilter::new(10, 5, 99);
+        filter.insert(b"Hello World");
+        filter.insert(b"Goodbye!");
+        assert_eq!(
+            hex::encode(filter.filterload().serialize()),
+            "0a4000600a080000010940050000006300000000"
+        );
+
+        let filteradd = FilterAdd {
+            element: vec![1; MAX_FILTERADD_SIZE + 1],
+        };
+        assert_eq!(
+            filteradd
+                .to_envelope(Network::Regtest)
+                .message::<FilterAdd>(),
+            Some(Err(Errors::ValueOutOfRange))
+        );
+        assert!(FilterClear.to_envelope(Network::Regtest).payload.is_empty());
+    }
+}
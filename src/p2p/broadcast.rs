@@ -0,0 +1,397 @@
+// Getting our own transactions mined: each is announced to a few peers, sent to
+// those asking for it, and counts as accepted once another peer announces it back,
+// which peers only do for transactions in their mempool. Until it is in a block
+// it is announced again every rebroadcast interval, as peers may have dropped it.
+use super::envelope::NetworkEnvelope;
+use super::inventory::{GetData, Inv, Inventory, InventoryType};
+use super::manager::{PeerEvent, PeerId, PeerManager};
+use super::message::Message;
+use super::version::PeerInfo;
+use crate::block::Block;
+use crate::network::Network;
+use crate::transaction::Transaction;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+// Peers a transaction is announced to each time, the others being left to relay it
+// back to us.
+pub const DEFAULT_FANOUT: usize = 4;
+pub const DEFAULT_REBROADCAST_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// How often run checks for transactions due for rebroadcast.
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BroadcastStatus {
+    // Announced, but not asked for yet.
+    Announced,
+    // Sent to that many peers asking for it.
+    Sent(usize),
+    // Announced back by that many peers.
+    Relayed(usize),
+    // In the block of that hash.
+    Confirmed([u8; 32]),
+}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    tx: Transaction,
+    announced_to: HashSet<PeerId>,
+    sent_to: HashSet<PeerId>,
+    relayed_by: HashSet<PeerId>,
+    last_broadcast: Instant,
+}
+
+impl Pending {
+    fn status(&self) -> BroadcastStatus {
+        if !self.relayed_by.is_empty() {
+            BroadcastStatus::Relayed(self.relayed_by.len())
+        } else if !self.sent_to.is_empty() {
+            BroadcastStatus::Sent(self.sent_to.len())
+        } else {
+            BroadcastStatus::Announced
+        }
+    }
+}
+
+// Works on envelopes and is told the time, so it serves any connection. run drives
+// one with the peers of a PeerManager.
+#[derive(Clone, Debug)]
+pub struct Broadcaster {
+    network: Network,
+    fanout: usize,
+    interval: Duration,
+    // Unconfirmed, by txid.
+    pending: HashMap<[u8; 32], Pending>,
+    wtxids: HashMap<[u8; 32], [u8; 32]>,
+    confirmed: HashMap<[u8; 32], [u8; 32]>,
+}
+
+impl Broadcaster {
+    pub fn new(network: Network) -> Self {
+        Broadcaster {
+            network,
+            fanout: DEFAULT_FANOUT,
+            interval: DEFAULT_REBROADCAST_INTERVAL,
+            pending: HashMap::new(),
+            wtxids: HashMap::new(),
+            confirmed: HashMap::new(),
+        }
+    }
+
+    pub fn with_fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout.max(1);
+        self
+    }
+
+    pub fn with_rebroadcast_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn status(&self, txid: &[u8; 32]) -> Option<BroadcastStatus> {
+        if let Some(block_hash) = self.confirmed.get(txid) {
+            return Some(BroadcastStatus::Confirmed(*block_hash));
+        }
+        self.pending.get(txid).map(Pending::status)
+    }
+
+    // Transactions not in a block yet.
+    pub fn unconfirmed(&self) -> Vec<&Transaction> {
+        self.pending.values().map(|pending| &pending.tx).collect()
+    }
+
+    fn txid_of(&self, hash: &[u8; 32]) -> Option<[u8; 32]> {
+        if self.pending.contains_key(hash) {
+            return Some(*hash);
+        }
+        self.wtxids.get(hash).copied()
+    }
+
+    // Adds tx and announces it to up to fanout of peers. Returns the announcements
+    // to send.
+    pub fn broadcast(
+        &mut self,
+        tx: Transaction,
+        peers: &[(PeerId, PeerInfo)],
+        now: Instant,
+    ) -> Vec<(PeerId, NetworkEnvelope)> {
+        let txid = tx.txid();
+        if self.confirmed.contains_key(&txid) {
+            return Vec::new();
+        }
+        self.wtxids.insert(tx.wtxid(), txid);
+        self.pending.entry(txid).or_insert(Pending {
+            tx,
+            announced_to: HashSet::new(),
+            sent_to: HashSet::new(),
+            relayed_by: HashSet::new(),
+            last_broadcast: now,
+        });
+        self.announce(txid, peers, now)
+    }
+
+    // Announcements of the transactions due for rebroadcast, each to peers it was
+    // announced to the least.
+    pub fn rebroadcast(
+        &mut self,
+        peers: &[(PeerId, PeerInfo)],
+        now: Instant,
+    ) -> Vec<(PeerId, NetworkEnvelope)> {
+        let due: Vec<[u8; 32]> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.last_broadcast) >= self.interval)
+            .map(|(txid, _)| *txid)
+            .collect();
+        due.into_iter()
+            .flat_map(|txid| self.announce(txid, peers, now))
+            .collect()
+    }
+
+    fn announce(
+        &mut self,
+        txid: [u8; 32],
+        peers: &[(PeerId, PeerInfo)],
+        now: Instant,
+    ) -> Vec<(PeerId, NetworkEnvelope)> {
+        let network = self.network;
+        let fanout = self.fanout;
+        let pending = self.pending.get_mut(&txid).unwrap();
+        pending.last_broadcast = now;
+        // Peers not announced to yet first, then the others again.
+        let mut chosen: Vec<&(PeerId, PeerInfo)> = peers.iter().collect();
+        chosen.sort_by_key(|(id, _)| pending.announced_to.contains(id));
+        chosen.truncate(fanout);
+        chosen
+            .into_iter()
+            .map(|(id, info)| {
+                pending.announced_to.insert(*id);
+                let item = if info.wtxid_relay {
+                    Inventory::new(InventoryType::Wtx, pending.tx.wtxid())
+                } else {
+                    Inventory::new(InventoryType::Tx, txid)
+                };
+                let inv = Inv {
+                    inventory: vec![item],
+                };
+                (*id, inv.to_envelope(network))
+            })
+            .collect()
+    }
+
+    // Marks the transactions in block confirmed, no longer rebroadcasting them.
+    pub fn confirm(&mut self, block: &Block) {
+        let block_hash = block.hash();
+        for tx in &block.transactions {
+            let txid = tx.txid();
+            if let Some(pending) = self.pending.remove(&txid) {
+                self.wtxids.remove(&pending.tx.wtxid());
+                self.confirmed.insert(txid, block_hash);
+            }
+        }
+    }
+
+    // Handles a message from peer, returning the replies to send it: our
+    // transactions for its getdata, with witness data if asked for. Announcements
+    // of them count as relay, and blocks confirm them.
+    pub fn receive(&mut self, peer: PeerId, envelope: &NetworkEnvelope) -> Vec<NetworkEnvelope> {
+        if let Some(Ok(getdata)) = envelope.message::<GetData>() {
+            let mut replies = Vec::new();
+            for item in getdata.inventory {
+                if !item.kind.is_transaction() {
+                    continue;
+                }
+                let Some(txid) = self.txid_of(&item.hash) else {
+                    continue;
+                };
+                let pending = self.pending.get_mut(&txid).unwrap();
+                pending.sent_to.insert(peer);
+                let payload = if item.kind.is_witness() {
+                    pending.tx.serialize()
+                } else {
+                    pending.tx.serialize_legacy()
+                };
+                replies.push(NetworkEnvelope::new(
+                    self.network,
+                    Transaction::COMMAND,
+                    payload,
+                ));
+            }
+            return replies;
+        }
+        if let Some(Ok(inv)) = envelope.message::<Inv>() {
+            for item in inv.inventory {
+                if let Some(txid) = self.txid_of(&item.hash) {
+                    self.pending.get_mut(&txid).unwrap().relayed_by.insert(peer);
+                }
+            }
+        } else if let Some(Ok(block)) = envelope.message::<Block>() {
+            self.confirm(&block);
+        }
+        Vec::new()
+    }
+}
+
+// Drives broadcaster with the peers of manager: answers their messages, announces
+// to peers connecting the transactions not announced to any peer yet, and
+// rebroadcasts. Returns once the manager is dropped.
+pub async fn run(broadcaster: Arc<Mutex<Broadcaster>>, manager: PeerManager) {
+    let mut events = manager.subscribe();
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => Some(event),
+            _ = ticks.tick() => None,
+        };
+        let peers: Vec<(PeerId, PeerInfo)> = manager
+            .peers()
+            .into_iter()
+            .map(|(id, _, info)| (id, info))
+            .collect();
+        let mut broadcaster = broadcaster.lock().unwrap();
+        let sends = match event {
+            None => broadcaster.rebroadcast(&peers, Instant::now()),
+            Some(Ok(PeerEvent::Message(peer, envelope))) => {
+                let replies = broadcaster.receive(peer, &envelope);
+                replies.into_iter().map(|reply| (peer, reply)).collect()
+            }
+            Some(Ok(PeerEvent::Connected(..))) => {
+                let unannounced: Vec<Transaction> = broadcaster
+                    .pending
+                    .values()
+                    .filter(|pending| pending.announced_to.is_empty())
+                    .map(|pending| pending.tx.clone())
+                    .collect();
+                unannounced
+                    .into_iter()
+                    .flat_map(|tx| broadcaster.broadcast(tx, &peers, Instant::now()))
+                    .collect()
+            }
+            Some(Ok(PeerEvent::Disconnected(_)) | Err(RecvError::Lagged(_))) => Vec::new(),
+            Some(Err(RecvError::Closed)) => return,
+        };
+        for (peer, envelope) in sends {
+            let _ = manager.send_envelope(peer, envelope);
+        }
+    }
+}
+
+#[cfg(test)]
+mod broadcast_tests {
+    use super::*;
+    use crate::p2p::inventory::InventoryRelay;
+    use crate::p2p::manager::{async_handshake, read_envelope};
+    use crate::p2p::version::VersionMessage;
+    use crate::script::Script;
+    use crate::transaction::{OutPoint, TxIn, TxOut, Witness};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn segwit_tx() -> Transaction {
+        let mut input = TxIn::new(OutPoint::new([1; 32], 0), Script::new(), 0xffffffff);
+        input.witness = Witness::from(vec![vec![1, 2, 3]]);
+        Transaction::new(2, vec![input], vec![TxOut::new(1000, vec![0x51].into())], 0)
+    }
+
+    fn peers(count: u64) -> Vec<(PeerId, PeerInfo)> {
+        (0..count)
+            .map(|id| {
+                let info = PeerInfo {
+                    wtxid_relay: id.is_multiple_of(2),
+                    ..PeerInfo::default()
+                };
+                (id, info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_broadcast_until_confirmed() {
+        let tx = segwit_tx();
+        let txid = tx.txid();
+        let peers = peers(6);
+        let start = Instant::now();
+        let mut broadcaster = Broadcaster::new(Network::Regtest).with_fanout(3);
+        let sends = broadcaster.broadcast(tx.clone(), &peers, start);
+        assert_eq!(sends.len(), 3);
+        assert_eq!(broadcaster.status(&txid), Some(BroadcastStatus::Announced));
+
+        // A peer asks for it as an InventoryRelay does, and gets it with witness data.
+        let (first, inv) = &sends[0];
+        let mut relay = InventoryRelay::new(Network::Regtest);
+        let (getdata, _) = relay.receive(inv).unwrap();
+        let replies = broadcaster.receive(*first, &getdata[0]);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].message::<Transaction>(), Some(Ok(tx.clone())));
+        assert_eq!(broadcaster.status(&txid), Some(BroadcastStatus::Sent(1)));
+
+        // Another announces it back.
+        let relayed = relay.announce_transaction(tx.clone(), &peers[4].1);
+        assert!(broadcaster.receive(4, &relayed).is_empty());
+        assert_eq!(broadcaster.status(&txid), Some(BroadcastStatus::Relayed(1)));
+
+        // Rebroadcast once due, to the peers not announced to before.
+        assert!(broadcaster.rebroadcast(&peers, start).is_empty());
+        let interval = DEFAULT_REBROADCAST_INTERVAL;
+        let sends = broadcaster.rebroadcast(&peers, start + interval);
+        let mut again: Vec<PeerId> = sends.iter().map(|(id, _)| *id).collect();
+        again.sort();
+        assert_eq!(again, vec![3, 4, 5]);
+
+        let mut block = Network::Regtest.genesis_block();
+        block.transactions.push(tx.clone());
+        broadcaster.receive(2, &block.to_envelope(Network::Regtest));
+        assert_eq!(
+            broadcaster.status(&txid),
+            Some(BroadcastStatus::Confirmed(block.hash()))
+        );
+        assert!(broadcaster.unconfirmed().is_empty());
+        assert!(broadcaster
+            .rebroadcast(&peers, start + 2 * interval)
+            .is_empty());
+        assert!(broadcaster.broadcast(tx, &peers, start).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_with_manager() {
+        let tx = segwit_tx();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let manager = PeerManager::new(Network::Regtest);
+        let broadcaster = Arc::new(Mutex::new(Broadcaster::new(Network::Regtest)));
+        // Broadcast before any peer is connected, so it is announced on connection.
+        broadcaster
+            .lock()
+            .unwrap()
+            .broadcast(tx.clone(), &[], Instant::now());
+        tokio::spawn(run(broadcaster.clone(), manager.clone()));
+
+        let (_, mut stream) = tokio::join!(manager.connect(address), async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            async_handshake(&mut stream, Network::Regtest, VersionMessage::new(0, 0))
+                .await
+                .unwrap();
+            stream
+        });
+        let mut relay = InventoryRelay::new(Network::Regtest);
+        loop {
+            let envelope = read_envelope(&mut stream, Network::Regtest.magic())
+                .await
+                .unwrap();
+            let (replies, received) = relay.receive(&envelope).unwrap();
+            for reply in replies {
+                stream.write_all(&reply.serialize()).await.unwrap();
+            }
+            if received.is_some() {
+                break;
+            }
+        }
+        assert_eq!(relay.transaction(&tx.txid()), Some(&tx));
+        assert_eq!(
+            broadcaster.lock().unwrap().status(&tx.txid()),
+            Some(BroadcastStatus::Sent(1))
+        );
+    }
+}
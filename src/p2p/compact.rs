@@ -0,0 +1,580 @@
+// BIP152 compact block relay. Peers supporting it are sent sendcmpct once connected
+// and blocks they announce are asked for as cmpctblock, rebuilt from the mempool
+// with the transactions missing asked for in getblocktxn. The few peers that last
+// gave us a new block first are asked to push new blocks as cmpctblock right away
+// (high bandwidth mode), saving the inv and getdata round trip; the others
+// announce them as usual and are asked for them (low bandwidth mode).
+use super::envelope::NetworkEnvelope;
+use super::inventory::{GetData, Inv, Inventory, InventoryType};
+use super::manager::PeerId;
+use super::message::{Headers, Message};
+use super::version::{random_nonce, PeerInfo, NODE_WITNESS, SHORT_IDS_BLOCKS_VERSION};
+use crate::block::{
+    Block, BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, PartiallyDownloadedBlock,
+};
+use crate::helper::read_array;
+use crate::network::Network;
+use crate::transaction::Transaction;
+use crate::types::errors::Errors;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+
+// The only version supported, with short ids of wtxids.
+pub const COMPACT_BLOCKS_VERSION: u64 = 2;
+// Peers asked to push blocks at once, as in Bitcoin Core.
+pub const DEFAULT_MAX_HIGH_BANDWIDTH_PEERS: usize = 3;
+// Blocks kept to answer getblocktxn, which peers only send for recent ones.
+const MAX_RECENT_BLOCKS: usize = 10;
+
+// Messages to send, by peer, and the block completed if any.
+type Outcome = (Vec<(PeerId, NetworkEnvelope)>, Option<Block>);
+
+// Announces compact block support, asking for new blocks to be pushed as cmpctblock
+// when announce is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendCmpct {
+    pub announce: bool,
+    pub version: u64,
+}
+
+impl Message for SendCmpct {
+    const COMMAND: &'static str = "sendcmpct";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let announce = read_array::<1>(reader)?[0] != 0;
+        Ok(SendCmpct {
+            announce,
+            version: u64::from_le_bytes(read_array(reader)?),
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.announce as u8];
+        result.extend(self.version.to_le_bytes());
+        result
+    }
+}
+
+impl Message for HeaderAndShortIds {
+    const COMMAND: &'static str = "cmpctblock";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        HeaderAndShortIds::parse(reader)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        HeaderAndShortIds::serialize(self)
+    }
+}
+
+impl Message for BlockTransactionsRequest {
+    const COMMAND: &'static str = "getblocktxn";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        BlockTransactionsRequest::parse(reader)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        BlockTransactionsRequest::serialize(self)
+    }
+}
+
+impl Message for BlockTransactions {
+    const COMMAND: &'static str = "blocktxn";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        BlockTransactions::parse(reader)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        BlockTransactions::serialize(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct CompactPeer {
+    // Sent sendcmpct for our version.
+    supported: bool,
+    // Asked us to push new blocks.
+    wants_push: bool,
+}
+
+// Works on envelopes like InventoryRelay, the mempool to rebuild blocks from being
+// passed in with each message.
+#[derive(Clone, Debug)]
+pub struct CompactBlockRelay {
+    network: Network,
+    max_high_bandwidth: usize,
+    peers: HashMap<PeerId, CompactPeer>,
+    // Peers we asked to push blocks, the one that gave us a block last at the end.
+    high_bandwidth: Vec<PeerId>,
+    // Blocks being rebuilt, with the peer asked for their missing transactions.
+    partial: HashMap<[u8; 32], (PeerId, PartiallyDownloadedBlock)>,
+    requested: HashSet<[u8; 32]>,
+    received: HashSet<[u8; 32]>,
+    recent: VecDeque<Block>,
+}
+
+impl CompactBlockRelay {
+    pub fn new(network: Network) -> Self {
+        CompactBlockRelay {
+            network,
+            max_high_bandwidth: DEFAULT_MAX_HIGH_BANDWIDTH_PEERS,
+            peers: HashMap::new(),
+            high_bandwidth: Vec::new(),
+            partial: HashMap::new(),
+            requested: HashSet::new(),
+            received: HashSet::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn with_max_high_bandwidth(mut self, max: usize) -> Self {
+        self.max_high_bandwidth = max;
+        self
+    }
+
+    // Whether we asked peer to push new blocks to us.
+    pub fn is_high_bandwidth(&self, peer: PeerId) -> bool {
+        self.high_bandwidth.contains(&peer)
+    }
+
+    // The sendcmpct to send a peer just connected, in low bandwidth mode, if it can
+    // serve compact blocks with witnesses.
+    pub fn connected(&mut self, peer: PeerId, info: &PeerInfo) -> Option<NetworkEnvelope> {
+        if info.version < SHORT_IDS_BLOCKS_VERSION || info.services & NODE_WITNESS == 0 {
+            return None;
+        }
+        self.peers.entry(peer).or_default();
+        let sendcmpct = SendCmpct {
+            announce: false,
+            version: COMPACT_BLOCKS_VERSION,
+        };
+        Some(sendcmpct.to_envelope(self.network))
+    }
+
+    pub fn disconnected(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+        self.high_bandwidth.retain(|id| *id != peer);
+        self.partial.retain(|_, (id, _)| *id != peer);
+    }
+
+    // Keeps block to serve getblocktxn and compact getdata for it.
+    pub fn add_block(&mut self, block: Block) {
+        self.received.insert(block.hash());
+        if self
+            .recent
+            .iter()
+            .any(|recent| recent.hash() == block.hash())
+        {
+            return;
+        }
+        if self.recent.len() == MAX_RECENT_BLOCKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(block);
+    }
+
+    // Adds block and pushes it as cmpctblock to those of peers that asked for it.
+    // The others are left to the inv or headers announcing it.
+    pub fn announce(&mut self, block: Block, peers: &[PeerId]) -> Vec<(PeerId, NetworkEnvelope)> {
+        let compact = HeaderAndShortIds::from_block(&block, random_nonce());
+        self.add_block(block);
+        peers
+            .iter()
+            .filter(|id| {
+                self.peers
+                    .get(id)
+                    .is_some_and(|peer| peer.supported && peer.wants_push)
+            })
+            .map(|id| (*id, compact.to_envelope(self.network)))
+            .collect()
+    }
+
+    // Handles a message from peer, returning the messages to send, to it or to
+    // peers leaving or entering high bandwidth mode, and the block it completed, if
+    // any. Blocks that can't be rebuilt are asked for in full.
+    pub fn receive<'a>(
+        &mut self,
+        peer: PeerId,
+        envelope: &NetworkEnvelope,
+        mempool: impl IntoIterator<Item = &'a Transaction>,
+    ) -> Result<Outcome, Errors> {
+        if let Some(sendcmpct) = envelope.message::<SendCmpct>() {
+            let sendcmpct = sendcmpct?;
+            if sendcmpct.version == COMPACT_BLOCKS_VERSION {
+                let state = self.peers.entry(peer).or_default();
+                state.supported = true;
+                state.wants_push = sendcmpct.announce;
+            }
+            return Ok((Vec::new(), None));
+        }
+        if let Some(inv) = envelope.message::<Inv>() {
+            let hashes = inv?
+                .inventory
+                .into_iter()
+                .filter(|item| item.kind.is_block())
+                .map(|item| item.hash)
+                .collect();
+            return Ok((self.request_compact(peer, hashes), None));
+        }
+        // A single header is a new block announced (BIP130), longer lists answer
+        // getheaders.
+        if let Some(headers) = envelope.message::<Headers>() {
+            let headers = headers?.headers;
+            if headers.len() != 1 {
+                return Ok((Vec::new(), None));
+            }
+            return Ok((self.request_compact(peer, vec![headers[0].hash()]), None));
+        }
+        if let Some(compact) = envelope.message::<HeaderAndShortIds>() {
+            return Ok(self.receive_compact(peer, compact?, mempool));
+        }
+        if let Some(received) = envelope.message::<BlockTransactions>() {
+            let received = received?;
+            let hash = received.block_hash;
+            if self.partial.get(&hash).is_none_or(|(id, _)| *id != peer) {
+                return Ok((Vec::new(), None));
+            }
+            let (_, partial) = self.partial.remove(&hash).unwrap();
+            return Ok(match partial.fill(&received) {
+                Ok(block) => self.complete(peer, block),
+                Err(_) => (vec![(peer, self.getdata_block(hash))], None),
+            });
+        }
+        if let Some(request) = envelope.message::<BlockTransactionsRequest>() {
+            let request = request?;
+            let replies = self
+                .recent_block(&request.block_hash)
+                .and_then(|block| request.respond(block))
+                .map(|response| (peer, response.to_envelope(self.network)))
+                .into_iter()
+                .collect();
+            return Ok((replies, None));
+        }
+        if let Some(getdata) = envelope.message::<GetData>() {
+            let replies = getdata?
+                .inventory
+                .into_iter()
+                .filter(|item| item.kind == InventoryType::CompactBlock)
+                .filter_map(|item| self.recent_block(&item.hash))
+                .map(|block| {
+                    let compact = HeaderAndShortIds::from_block(block, random_nonce());
+                    (peer, compact.to_envelope(self.network))
+                })
+                .collect();
+            return Ok((replies, None));
+        }
+        if let Some(block) = envelope.message::<Block>() {
+            let block = block?;
+            if self.received.contains(&block.hash()) {
+                return Ok((Vec::new(), None));
+            }
+            self.partial.remove(&block.hash());
+            return Ok(self.complete(peer, block));
+        }
+        Ok((Vec::new(), None))
+    }
+
+    fn recent_block(&self, hash: &[u8; 32]) -> Option<&Block> {
+        self.recent.iter().find(|block| &block.hash() == hash)
+    }
+
+    fn getdata_block(&self, hash: [u8; 32]) -> NetworkEnvelope {
+        GetData {
+            inventory: vec![Inventory::new(InventoryType::WitnessBlock, hash)],
+        }
+        .to_envelope(self.network)
+    }
+
+    // Asks peer for the blocks of hashes not received or asked for yet, as compact
+    // blocks if it supports them.
+    fn request_compact(
+        &mut self,
+        peer: PeerId,
+        hashes: Vec<[u8; 32]>,
+    ) -> Vec<(PeerId, NetworkEnvelope)> {
+        if !self.peers.get(&peer).is_some_and(|state| state.supported) {
+            return Vec::new();
+        }
+        let inventory: Vec<Inventory> = hashes
+            .into_iter()
+            .filter(|hash| !self.received.contains(hash) && self.requested.insert(*hash))
+            .map(|hash| Inventory::new(InventoryType::CompactBlock, hash))
+            .collect();
+        if inventory.is_empty() {
+            return Vec::new();
+        }
+        vec![(peer, GetData { inventory }.to_envelope(self.network))]
+    }
+
+    fn receive_compact<'a>(
+        &mut self,
+        peer: PeerId,
+        compact: HeaderAndShortIds,
+        mempool: impl IntoIterator<Item = &'a Transaction>,
+    ) -> Outcome {
+        let hash = compact.header.hash();
+        if self.received.contains(&hash) || self.partial.contains_key(&hash) {
+            return (Vec::new(), None);
+        }
+        self.requested.insert(hash);
+        let Ok(partial) = PartiallyDownloadedBlock::new(&compact, mempool) else {
+            return (vec![(peer, self.getdata_block(hash))], None);
+        };
+        let Some(request) = partial.request() else {
+            let empty = BlockTransactions {
+                block_hash: hash,
+                transactions: Vec::new(),
+            };
+            return match partial.fill(&empty) {
+                Ok(block) => self.complete(peer, block),
+                Err(_) => (vec![(peer, self.getdata_block(hash))], None),
+            };
+        };
+        self.partial.insert(hash, (peer, partial));
+        (vec![(peer, request.to_envelope(self.network))], None)
+    }
+
+    // Keeps block, received first from peer, and moves peer to high bandwidth mode,
+    // taking the peer that gave us a block the longest ago out of it if that makes
+    // too many.
+    fn complete(&mut self, peer: PeerId, block: Block) -> Outcome {
+        self.requested.remove(&block.hash());
+        self.add_block(block.clone());
+        let mut sends = Vec::new();
+        let supported = self.peers.get(&peer).is_some_and(|state| state.supported);
+        if !supported || self.max_high_bandwidth == 0 {
+            return (sends, Some(block));
+        }
+        if self.is_high_bandwidth(peer) {
+            self.high_bandwidth.retain(|id| *id != peer);
+        } else {
+            let sendcmpct = SendCmpct {
+                announce: true,
+                version: COMPACT_BLOCKS_VERSION,
+            };
+            sends.push((peer, sendcmpct.to_envelope(self.network)));
+        }
+        self.high_bandwidth.push(peer);
+        if self.high_bandwidth.len() > self.max_high_bandwidth {
+            let oldest = self.high_bandwidth.remove(0);
+            let sendcmpct = SendCmpct {
+                announce: false,
+                version: COMPACT_BLOCKS_VERSION,
+            };
+            sends.push((oldest, sendcmpct.to_envelope(self.network)));
+        }
+        (sends, Some(block))
+    }
+}
+
+#[cfg(test)]
+mod compact_relay_tests {
+    use super::*;
+    use crate::p2p::version::PROTOCOL_VERSION;
+    use crate::script::Script;
+    use crate::transaction::{OutPoint, TxIn, TxOut};
+
+    fn tx(tag: u8) -> Transaction {
+        Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new([tag; 32], 0),
+                Script::new(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(tag as u64 * 1000, vec![0x51].into())],
+            0,
+        )
+    }
+
+    fn block(tags: &[u8]) -> Block {
+        let mut script_sig = Script::new();
+        script_sig.push_int(tags[0] as i64).push_int(1);
+        let coinbase = Transaction::new(
+            1,
+            vec![TxIn::new(OutPoint::null(), script_sig, 0xffffffff)],
+            vec![TxOut::new(50_0000_0000, vec![0x51].into())],
+            0,
+        );
+        let mut block = Network::Regtest.genesis_block();
+        block.transactions = vec![coinbase];
+        block.transactions.extend(tags.iter().map(|tag| tx(*tag)));
+        block.header.merkle_root = block.compute_merkle_root();
+        block
+    }
+
+    fn info() -> PeerInfo {
+        PeerInfo {
+            version: PROTOCOL_VERSION,
+            services: NODE_WITNESS,
+            ..PeerInfo::default()
+        }
+    }
+
+    // Connects both sides as peer 0 of each other, delivering their sendcmpct.
+    fn connect(ours: &mut CompactBlockRelay, theirs: &mut CompactBlockRelay) {
+        let sendcmpct = ours.connected(0, &info()).unwrap();
+        theirs.receive(0, &sendcmpct, []).unwrap();
+        let sendcmpct = theirs.connected(0, &info()).unwrap();
+        ours.receive(0, &sendcmpct, []).unwrap();
+    }
+
+    // Delivers sends to relay as coming from peer 0, returning its answers.
+    fn deliver(
+        relay: &mut CompactBlockRelay,
+        sends: Vec<(PeerId, NetworkEnvelope)>,
+        mempool: &[Transaction],
+    ) -> Outcome {
+        let mut replies = Vec::new();
+        let mut completed = None;
+        for (_, envelope) in sends {
+            let (sends, block) = relay.receive(0, &envelope, mempool).unwrap();
+            replies.extend(sends);
+            completed = completed.or(block);
+        }
+        (replies, completed)
+    }
+
+    #[test]
+    fn test_messages() {
+        let sendcmpct = SendCmpct {
+            announce: true,
+            version: 2,
+        };
+        let envelope = sendcmpct.to_envelope(Network::Regtest);
+        assert_eq!(envelope.payload, [1, 2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(envelope.message::<SendCmpct>(), Some(Ok(sendcmpct)));
+
+        let compact = HeaderAndShortIds::from_block(&block(&[1, 2]), 5);
+        let envelope = compact.to_envelope(Network::Regtest);
+        assert_eq!(envelope.command, "cmpctblock");
+        assert_eq!(envelope.message::<HeaderAndShortIds>(), Some(Ok(compact)));
+
+        // Peers too old or without witnesses aren't offered compact blocks.
+        let mut relay = CompactBlockRelay::new(Network::Regtest);
+        let old = PeerInfo {
+            version: SHORT_IDS_BLOCKS_VERSION - 1,
+            ..info()
+        };
+        assert_eq!(relay.connected(1, &old), None);
+        let legacy = PeerInfo {
+            services: 0,
+            ..info()
+        };
+        assert_eq!(relay.connected(2, &legacy), None);
+    }
+
+    #[test]
+    fn test_low_then_high_bandwidth() {
+        let mut ours = CompactBlockRelay::new(Network::Regtest);
+        let mut theirs = CompactBlockRelay::new(Network::Regtest);
+        connect(&mut ours, &mut theirs);
+
+        // Low bandwidth: the block is announced with inv and asked for as compact.
+        let first = block(&[1, 2, 3]);
+        assert!(theirs.announce(first.clone(), &[0]).is_empty());
+        let inv = Inv {
+            inventory: vec![Inventory::new(InventoryType::Block, first.hash())],
+        };
+        let (getdata, _) = deliver(&mut ours, vec![(0, inv.to_envelope(Network::Regtest))], &[]);
+        assert_eq!(
+            getdata[0]
+                .1
+                .message::<GetData>()
+                .unwrap()
+                .unwrap()
+                .inventory,
+            vec![Inventory::new(InventoryType::CompactBlock, first.hash())]
+        );
+        let (cmpctblock, _) = deliver(&mut theirs, getdata, &[]);
+
+        // Only tx(2) is missing from the mempool and asked for with getblocktxn.
+        let mempool = [tx(3), tx(1), tx(9)];
+        let (getblocktxn, _) = deliver(&mut ours, cmpctblock, &mempool);
+        assert_eq!(
+            getblocktxn[0]
+                .1
+                .message::<BlockTransactionsRequest>()
+                .unwrap()
+                .unwrap()
+                .indexes,
+            vec![2]
+        );
+        let (blocktxn, _) = deliver(&mut theirs, getblocktxn, &[]);
+        let (sendcmpct, completed) = deliver(&mut ours, blocktxn, &mempool);
+        assert_eq!(completed, Some(first));
+
+        // Having given us the block, the peer is asked to push the next ones.
+        assert!(ours.is_high_bandwidth(0));
+        deliver(&mut theirs, sendcmpct, &[]);
+        let second = block(&[4, 5]);
+        let pushed = theirs.announce(second.clone(), &[0]);
+        assert_eq!(pushed.len(), 1);
+        let (replies, completed) = deliver(&mut ours, pushed, &[tx(4), tx(5)]);
+        assert!(replies.is_empty());
+        assert_eq!(completed, Some(second));
+    }
+
+    #[test]
+    fn test_fallback_and_high_bandwidth_limit() {
+        let mut relay = CompactBlockRelay::new(Network::Regtest).with_max_high_bandwidth(2);
+        let sendcmpct = SendCmpct {
+            announce: false,
+            version: COMPACT_BLOCKS_VERSION,
+        }
+        .to_envelope(Network::Regtest);
+        for peer in 0..3 {
+            relay.connected(peer, &info());
+            relay.receive(peer, &sendcmpct, []).unwrap();
+        }
+
+        // Colliding short ids get the block asked for in full.
+        let first = block(&[1, 2]);
+        let mut compact = HeaderAndShortIds::from_block(&first, 1);
+        compact.short_ids[1] = compact.short_ids[0];
+        let (sends, _) = relay
+            .receive(0, &compact.to_envelope(Network::Regtest), [])
+            .unwrap();
+        assert_eq!(
+            sends[0].1.message::<GetData>().unwrap().unwrap().inventory,
+            vec![Inventory::new(InventoryType::WitnessBlock, first.hash())]
+        );
+        let (sends, completed) = relay
+            .receive(0, &first.to_envelope(Network::Regtest), [])
+            .unwrap();
+        assert_eq!(completed, Some(first.clone()));
+        assert_eq!(sends.len(), 1);
+
+        // A third peer giving us a block first takes the first out of high bandwidth.
+        for (peer, tag) in [(1, 3), (2, 4)] {
+            let block = block(&[tag]);
+            let compact = HeaderAndShortIds::from_block(&block, 1);
+            let (sends, completed) = relay
+                .receive(peer, &compact.to_envelope(Network::Regtest), &[tx(tag)])
+                .unwrap();
+            assert_eq!(completed, Some(block));
+            assert_eq!(sends[0].0, peer);
+            if peer == 2 {
+                assert_eq!(sends[1].0, 0);
+                assert_eq!(
+                    sends[1].1.message::<SendCmpct>(),
+                    Some(Ok(SendCmpct {
+                        announce: false,
+                        version: COMPACT_BLOCKS_VERSION
+                    }))
+                );
+            }
+        }
+        assert!(!relay.is_high_bandwidth(0));
+        assert!(relay.is_high_bandwidth(1) && relay.is_high_bandwidth(2));
+
+        // Blocks already received are ignored.
+        let compact = HeaderAndShortIds::from_block(&first, 2);
+        let (sends, completed) = relay
+            .receive(1, &compact.to_envelope(Network::Regtest), [])
+            .unwrap();
+        assert!(sends.is_empty() && completed.is_none());
+    }
+}
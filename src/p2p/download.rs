@@ -0,0 +1,470 @@
+// Parallel block download for the initial sync, as in Bitcoin Core: the blocks of
+// the best header chain are asked for a few at a time from every peer, and only
+// within a window above the first one not processed yet, so blocks received out of
+// order don't pile up. The peer holding up the window is dropped for stalling once
+// it keeps the others idle too long, and requests left unanswered for too long go
+// to another peer.
+use super::envelope::NetworkEnvelope;
+use super::inventory::{GetData, Inventory, InventoryType};
+use super::manager::{PeerEvent, PeerId, PeerManager};
+use super::message::Message;
+use super::version::{PeerInfo, NODE_NETWORK};
+use crate::block::Block;
+use crate::chain::{BlockStorage, ChainState, UtxoBackend};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+// As in Bitcoin Core.
+pub const BLOCK_DOWNLOAD_WINDOW: usize = 1024;
+pub const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
+pub const BLOCK_STALLING_TIMEOUT: Duration = Duration::from_secs(2);
+// Bitcoin Core scales it with the number of peers downloading, fixed here.
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// How often download_blocks checks for stalling peers and timed out requests.
+const TICK_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, Default)]
+struct DownloadPeer {
+    in_flight: usize,
+    // Since when the block it was asked for holds up the window.
+    stalling_since: Option<Instant>,
+}
+
+// Works on envelopes and is told the time; download_blocks drives one with the peers
+// of a PeerManager.
+#[derive(Clone, Debug)]
+pub struct BlockDownloader {
+    network: Network,
+    window: usize,
+    max_in_flight: usize,
+    stalling_timeout: Duration,
+    block_timeout: Duration,
+    // In the order to process them, from the first not taken by take_ready.
+    wanted: VecDeque<[u8; 32]>,
+    wanted_set: HashSet<[u8; 32]>,
+    // By whom and since when each requested block is awaited.
+    in_flight: HashMap<[u8; 32], (PeerId, Instant)>,
+    received: HashMap<[u8; 32], (PeerId, Block)>,
+    peers: BTreeMap<PeerId, DownloadPeer>,
+}
+
+impl BlockDownloader {
+    pub fn new(network: Network) -> Self {
+        BlockDownloader {
+            network,
+            window: BLOCK_DOWNLOAD_WINDOW,
+            max_in_flight: MAX_BLOCKS_IN_TRANSIT_PER_PEER,
+            stalling_timeout: BLOCK_STALLING_TIMEOUT,
+            block_timeout: BLOCK_DOWNLOAD_TIMEOUT,
+            wanted: VecDeque::new(),
+            wanted_set: HashSet::new(),
+            in_flight: HashMap::new(),
+            received: HashMap::new(),
+            peers: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    pub fn with_max_in_flight(mut self, max: usize) -> Self {
+        self.max_in_flight = max.max(1);
+        self
+    }
+
+    pub fn with_stalling_timeout(mut self, timeout: Duration) -> Self {
+        self.stalling_timeout = timeout;
+        self
+    }
+
+    pub fn with_block_timeout(mut self, timeout: Duration) -> Self {
+        self.block_timeout = timeout;
+        self
+    }
+
+    // Adds blocks to download, to be processed after those already wanted.
+    pub fn want(&mut self, hashes: impl IntoIterator<Item = [u8; 32]>) {
+        for hash in hashes {
+            if self.wanted_set.insert(hash) {
+                self.wanted.push_back(hash);
+            }
+        }
+    }
+
+    // Blocks not taken by take_ready yet.
+    pub fn remaining(&self) -> usize {
+        self.wanted.len()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.wanted.is_empty()
+    }
+
+    pub fn in_flight(&self, peer: PeerId) -> usize {
+        self.peers.get(&peer).map_or(0, |state| state.in_flight)
+    }
+
+    // Downloads from peer if it serves the whole chain. Whether it does.
+    pub fn add_peer(&mut self, peer: PeerId, info: &PeerInfo) -> bool {
+        if info.services & NODE_NETWORK == 0 {
+            return false;
+        }
+        self.peers.entry(peer).or_default();
+        true
+    }
+
+    // Its requests go to other peers.
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+        self.in_flight.retain(|_, (id, _)| *id != peer);
+    }
+
+    // The getdata to send each peer with room for more requests, for the blocks of
+    // the window not asked for yet. A peer left idle by a full window marks the one
+    // awaited for its first block as stalling.
+    pub fn request(&mut self, now: Instant) -> Vec<(PeerId, NetworkEnvelope)> {
+        let mut free = self
+            .wanted
+            .iter()
+            .take(self.window)
+            .filter(|hash| {
+                !self.in_flight.contains_key(*hash) && !self.received.contains_key(*hash)
+            })
+            .copied()
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut sends = Vec::new();
+        let mut idle = false;
+        for (id, state) in &mut self.peers {
+            let mut inventory = Vec::new();
+            while state.in_flight < self.max_in_flight {
+                let Some(hash) = free.next() else {
+                    idle = true;
+                    break;
+                };
+                state.in_flight += 1;
+                self.in_flight.insert(hash, (*id, now));
+                inventory.push(Inventory::new(InventoryType::WitnessBlock, hash));
+            }
+            if !inventory.is_empty() {
+                sends.push((*id, GetData { inventory }.to_envelope(self.network)));
+            }
+        }
+        if idle && self.wanted.len() > self.window {
+            let first = self
+                .wanted
+                .iter()
+                .find(|hash| !self.received.contains_key(*hash));
+            if let Some((holder, _)) = first.and_then(|hash| self.in_flight.get(hash)) {
+                if let Some(state) = self.peers.get_mut(holder) {
+                    state.stalling_since.get_or_insert(now);
+                }
+            }
+        }
+        sends
+    }
+
+    // Keeps block from peer if it is wanted. Whether it was.
+    pub fn receive(&mut self, peer: PeerId, block: Block) -> bool {
+        let hash = block.hash();
+        if !self.wanted_set.contains(&hash) || self.received.contains_key(&hash) {
+            return false;
+        }
+        if let Some((requested_from, _)) = self.in_flight.remove(&hash) {
+            if let Some(state) = self.peers.get_mut(&requested_from) {
+                state.in_flight -= 1;
+                state.stalling_since = None;
+            }
+        }
+        self.received.insert(hash, (peer, block));
+        true
+    }
+
+    // The blocks received next in order, with the peer each came from. They leave
+    // the window, letting it move on.
+    pub fn take_ready(&mut self) -> Vec<(PeerId, Block)> {
+        let mut ready = Vec::new();
+        while let Some(entry) = self
+            .wanted
+            .front()
+            .and_then(|hash| self.received.remove(hash))
+        {
+            self.wanted_set.remove(&self.wanted.pop_front().unwrap());
+            ready.push(entry);
+        }
+        ready
+    }
+
+    // Downloads a block taken by take_ready again, first, as the one received was
+    // bad.
+    pub fn retry(&mut self, hash: [u8; 32]) {
+        if self.wanted_set.insert(hash) {
+            self.wanted.push_front(hash);
+        }
+    }
+
+    // Frees requests awaited longer than the block timeout, and removes the peers
+    // stalling longer than the stalling timeout, returning them to be disconnected.
+    pub fn check_timeouts(&mut self, now: Instant) -> Vec<PeerId> {
+        let stalling: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| {
+                state
+                    .stalling_since
+                    .is_some_and(|since| now.duration_since(since) >= self.stalling_timeout)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for peer in &stalling {
+            self.remove_peer(*peer);
+        }
+        let timeout = self.block_timeout;
+        let peers = &mut self.peers;
+        self.in_flight.retain(|_, (id, sent)| {
+            if now.duration_since(*sent) < timeout {
+                return true;
+            }
+            if let Some(state) = peers.get_mut(id) {
+                state.in_flight -= 1;
+            }
+            false
+        });
+        stalling
+    }
+}
+
+// Downloads the blocks of the best header chain that chain lacks from the peers of
+// manager, accepting them in order. Peers stalling the download or sending blocks
+// failing check_block are disconnected. Returns how many blocks were accepted.
+pub async fn download_blocks<B: UtxoBackend, S: BlockStorage>(
+    manager: &PeerManager,
+    chain: &mut ChainState<B, S>,
+) -> Result<usize, Errors> {
+    let headers = chain.headers();
+    let start = headers.fork_point(chain.tip()).height + 1;
+    let mut downloader = BlockDownloader::new(manager.network());
+    downloader
+        .want((start..=headers.height()).map(|height| headers.at_height(height).unwrap().hash));
+    download_with(manager, chain, downloader).await
+}
+
+// As download_blocks, with downloader already told the blocks wanted.
+pub async fn download_with<B: UtxoBackend, S: BlockStorage>(
+    manager: &PeerManager,
+    chain: &mut ChainState<B, S>,
+    mut downloader: BlockDownloader,
+) -> Result<usize, Errors> {
+    if downloader.is_done() {
+        return Ok(0);
+    }
+    let mut events = manager.subscribe();
+    for (id, _, info) in manager.peers() {
+        downloader.add_peer(id, &info);
+    }
+    if manager.peers().is_empty() {
+        return Err(Errors::NoPeers);
+    }
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    let mut accepted = 0;
+    while !downloader.is_done() {
+        for (peer, envelope) in downloader.request(Instant::now()) {
+            let _ = manager.send_envelope(peer, envelope);
+        }
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(PeerEvent::Connected(id, info)) => {
+                    downloader.add_peer(id, &info);
+                }
+                Ok(PeerEvent::Disconnected(id)) => downloader.remove_peer(id),
+                Ok(PeerEvent::Message(id, envelope)) => {
+                    if let Some(Ok(block)) = envelope.message::<Block>() {
+                        downloader.receive(id, block);
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => {
+                    return Err(Errors::Io("peer manager is gone".to_string()));
+                }
+            },
+            _ = ticks.tick() => {
+                for peer in downloader.check_timeouts(Instant::now()) {
+                    manager.disconnect(peer);
+                }
+            }
+        }
+        for (peer, block) in downloader.take_ready() {
+            let hash = block.hash();
+            match chain.accept_block(block) {
+                Ok(update) => {
+                    if let Some((_, e)) = update.invalid.into_iter().next() {
+                        return Err(Errors::InvalidPeerBlock(e));
+                    }
+                    accepted += 1;
+                }
+                Err(_) => {
+                    manager.disconnect(peer);
+                    downloader.remove_peer(peer);
+                    downloader.retry(hash);
+                }
+            }
+        }
+    }
+    Ok(accepted)
+}
+
+#[cfg(test)]
+mod download_tests {
+    use super::*;
+    use crate::chain::MemoryBackend;
+    use crate::p2p::manager::{async_handshake, read_envelope};
+    use crate::p2p::version::VersionMessage;
+    use crate::test_util::blocks;
+    use tokio::io::AsyncWriteExt;
+
+    fn full_node() -> PeerInfo {
+        PeerInfo {
+            services: NODE_NETWORK,
+            ..PeerInfo::default()
+        }
+    }
+
+    fn hashes(count: u8) -> Vec<[u8; 32]> {
+        (0..count).map(|i| [i; 32]).collect()
+    }
+
+    fn requested(sends: &[(PeerId, NetworkEnvelope)], peer: PeerId) -> Vec<[u8; 32]> {
+        sends
+            .iter()
+            .filter(|(id, _)| *id == peer)
+            .flat_map(|(_, envelope)| envelope.message::<GetData>().unwrap().unwrap().inventory)
+            .map(|item| item.hash)
+            .collect()
+    }
+
+    #[test]
+    fn test_window_and_in_flight_limits() {
+        let start = Instant::now();
+        let mut downloader = BlockDownloader::new(Network::Regtest)
+            .with_window(6)
+            .with_max_in_flight(2);
+        downloader.want(hashes(10));
+        assert!(!downloader.add_peer(9, &PeerInfo::default()));
+        for peer in 0..4 {
+            downloader.add_peer(peer, &full_node());
+        }
+
+        // Each peer gets its own range, the fourth nothing as the window is full.
+        let sends = downloader.request(start);
+        assert_eq!(requested(&sends, 0), hashes(2));
+        assert_eq!(requested(&sends, 1), [[2; 32], [3; 32]]);
+        assert_eq!(requested(&sends, 2), [[4; 32], [5; 32]]);
+        assert!(requested(&sends, 3).is_empty());
+        assert!(downloader.request(start).is_empty());
+
+        // Blocks out of order wait for the first ones.
+        let mut block = Network::Regtest.genesis_block();
+        assert!(!downloader.receive(1, block.clone()));
+        let mut downloader = BlockDownloader::new(Network::Regtest).with_max_in_flight(2);
+        let mut chain = Vec::new();
+        for nonce in 0..3 {
+            block.header.nonce = nonce;
+            chain.push(block.clone());
+        }
+        downloader.want(chain.iter().map(Block::hash));
+        downloader.add_peer(0, &full_node());
+        downloader.request(start);
+        assert!(downloader.receive(0, chain[1].clone()));
+        assert!(downloader.take_ready().is_empty());
+        assert!(downloader.receive(0, chain[0].clone()));
+        assert_eq!(downloader.in_flight(0), 0);
+        assert_eq!(
+            downloader.take_ready(),
+            vec![(0, chain[0].clone()), (0, chain[1].clone())]
+        );
+        assert_eq!(requested(&downloader.request(start), 0), [chain[2].hash()]);
+        assert_eq!(downloader.remaining(), 1);
+    }
+
+    #[test]
+    fn test_stalling_and_timeouts() {
+        let start = Instant::now();
+        let mut downloader = BlockDownloader::new(Network::Regtest)
+            .with_window(4)
+            .with_max_in_flight(2);
+        downloader.want(hashes(8));
+        for peer in 0..3 {
+            downloader.add_peer(peer, &full_node());
+        }
+        // Peer 2 is idle with the window full, so peer 0 holding its start stalls.
+        downloader.request(start);
+        assert!(downloader
+            .check_timeouts(start + Duration::from_secs(1))
+            .is_empty());
+        let later = start + BLOCK_STALLING_TIMEOUT;
+        assert_eq!(downloader.check_timeouts(later), vec![0]);
+        assert_eq!(requested(&downloader.request(later), 2), hashes(2));
+
+        // Requests unanswered for too long are asked from another peer.
+        let mut downloader = BlockDownloader::new(Network::Regtest).with_max_in_flight(1);
+        downloader.want(hashes(2));
+        downloader.add_peer(0, &full_node());
+        downloader.request(start);
+        downloader.add_peer(1, &full_node());
+        assert_eq!(requested(&downloader.request(start), 1), [[1; 32]]);
+        let expired = start + BLOCK_DOWNLOAD_TIMEOUT;
+        assert!(downloader.check_timeouts(expired).is_empty());
+        assert_eq!(downloader.in_flight(0), 0);
+        let sends = downloader.request(expired);
+        assert_eq!(requested(&sends, 0), [[0; 32]]);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_peers() {
+        let blocks = blocks(40);
+        let mut chain = ChainState::new(Network::Regtest, MemoryBackend::default());
+        for block in &blocks {
+            chain.accept_header(block.header).unwrap();
+        }
+        let manager = PeerManager::new(Network::Regtest);
+        // Two peers serving the blocks asked for.
+        for _ in 0..2 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let blocks = blocks.clone();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let version = VersionMessage::new(NODE_NETWORK, 40);
+                async_handshake(&mut stream, Network::Regtest, version)
+                    .await
+                    .unwrap();
+                while let Ok(envelope) = read_envelope(&mut stream, Network::Regtest.magic()).await
+                {
+                    let Some(Ok(getdata)) = envelope.message::<GetData>() else {
+                        continue;
+                    };
+                    for item in getdata.inventory {
+                        let block = blocks.iter().find(|block| block.hash() == item.hash);
+                        let envelope = block.unwrap().to_envelope(Network::Regtest);
+                        stream.write_all(&envelope.serialize()).await.unwrap();
+                    }
+                }
+            });
+            manager.connect(address).await.unwrap();
+        }
+
+        let mut downloader = BlockDownloader::new(Network::Regtest).with_max_in_flight(4);
+        downloader.want(blocks.iter().map(Block::hash));
+        assert_eq!(
+            download_with(&manager, &mut chain, downloader).await,
+            Ok(40)
+        );
+        assert_eq!(chain.height(), 40);
+        assert_eq!(download_blocks(&manager, &mut chain).await, Ok(0));
+    }
+}
@@ -0,0 +1,125 @@
+// Every P2P message is framed by the network magic, a command name, the payload
+// length and a checksum (CMessageHeader in Bitcoin Core).
+use crate::helper::{hash256, read_array, read_bytes};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::io::Read;
+
+// Command names are ASCII, padded with zeros to this size.
+pub const COMMAND_SIZE: usize = 12;
+// Largest payload accepted, as MAX_PROTOCOL_MESSAGE_LENGTH in Bitcoin Core.
+pub const MAX_PAYLOAD_SIZE: u32 = 4_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkEnvelope {
+    pub magic: [u8; 4],
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    hash256(payload)[..4].try_into().unwrap()
+}
+
+impl NetworkEnvelope {
+    pub fn new(network: Network, command: &str, payload: Vec<u8>) -> Self {
+        NetworkEnvelope {
+            magic: network.magic(),
+            command: command.to_string(),
+            payload,
+        }
+    }
+
+    // Reads an envelope sent on the network with magic, as custom signets have their
+    // own. The payload is checked against the checksum, not parsed.
+    pub fn parse(reader: &mut impl Read, magic: [u8; 4]) -> Result<Self, Errors> {
+        if read_array::<4>(reader)? != magic {
+            return Err(Errors::InvalidEnvelope("magic of another network"));
+        }
+        let command = read_array::<COMMAND_SIZE>(reader)?;
+        // The name is followed by nothing but padding.
+        let len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+        if command[len..].iter().any(|b| *b != 0) || !command[..len].is_ascii() {
+            return Err(Errors::InvalidEnvelope("malformed command"));
+        }
+        let length = u32::from_le_bytes(read_array(reader)?);
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(Errors::InvalidEnvelope("payload too large"));
+        }
+        let expected = read_array::<4>(reader)?;
+        let payload = read_bytes(reader, length as usize)?;
+        if checksum(&payload) != expected {
+            return Err(Errors::InvalidEnvelope("checksum mismatch"));
+        }
+        Ok(NetworkEnvelope {
+            magic,
+            command: String::from_utf8(command[..len].to_vec()).unwrap(),
+            payload,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.magic.to_vec();
+        let mut command = [0u8; COMMAND_SIZE];
+        command[..self.command.len()].copy_from_slice(self.command.as_bytes());
+        result.extend(command);
+        result.extend((self.payload.len() as u32).to_le_bytes());
+        result.extend(checksum(&self.payload));
+        result.extend(&self.payload);
+        result
+    }
+}
+
+#[cfg(test)]
+mod envelope_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_serialize() {
+        let verack = hex::decode("f9beb4d976657261636b000000000000000000005df6e0e2").unwrap();
+        let envelope =
+            NetworkEnvelope::parse(&mut verack.as_slice(), Network::Mainnet.magic()).unwrap();
+        assert_eq!(envelope.command, "verack");
+        assert!(envelope.payload.is_empty());
+        assert_eq!(envelope.serialize(), verack);
+
+        let version = hex::decode("f9beb4d976657273696f6e0000000000650000005f1a69d2721101000100000000000000bc8f5e5400000000010000000000000000000000000000000000ffffc61b6409208d010000000000000000000000000000000000ffffcb0071c0208d128035cbc97953f80f2f5361746f7368693a302e392e332fcf05050001").unwrap();
+        let envelope =
+            NetworkEnvelope::parse(&mut version.as_slice(), Network::Mainnet.magic()).unwrap();
+        assert_eq!(envelope.command, "version");
+        assert_eq!(envelope.payload, version[24..].to_vec());
+        assert_eq!(envelope.serialize(), version);
+    }
+
+    #[test]
+    fn test_invalid_envelopes() {
+        let envelope = NetworkEnvelope::new(Network::Testnet, "ping", vec![1; 8]);
+        let bytes = envelope.serialize();
+        assert_eq!(&bytes[..4], &[0x0b, 0x11, 0x09, 0x07]);
+        assert_eq!(
+            NetworkEnvelope::parse(&mut bytes.as_slice(), Network::Testnet.magic()),
+            Ok(envelope)
+        );
+        assert_eq!(
+            NetworkEnvelope::parse(&mut bytes.as_slice(), Network::Mainnet.magic()),
+            Err(Errors::InvalidEnvelope("magic of another network"))
+        );
+
+        let mut bad = bytes.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            NetworkEnvelope::parse(&mut bad.as_slice(), Network::Testnet.magic()),
+            Err(Errors::InvalidEnvelope("checksum mismatch"))
+        );
+        let mut bad = bytes.clone();
+        bad[10] = b'x';
+        assert_eq!(
+            NetworkEnvelope::parse(&mut bad.as_slice(), Network::Testnet.magic()),
+            Err(Errors::InvalidEnvelope("malformed command"))
+        );
+        assert_eq!(
+            NetworkEnvelope::parse(&mut &bytes[..30], Network::Testnet.magic()),
+            Err(Errors::UnexpectedEof)
+        );
+    }
+}
@@ -0,0 +1,294 @@
+// BIP133 fee filters: a peer sends the lowest feerate of the transactions it would
+// accept into its mempool, and isn't announced transactions paying less. Ours
+// follows the minimum feerate of our mempool, rounded at random to the bucket above
+// or below it, so it doesn't tell exactly what our mempool holds.
+use super::envelope::NetworkEnvelope;
+use super::manager::PeerId;
+use super::message::Message;
+use super::version::{random_nonce, PeerInfo, FEEFILTER_VERSION};
+use crate::helper::read_array;
+use crate::mempool::DEFAULT_INCREMENTAL_RELAY_FEE;
+use crate::network::Network;
+use crate::policy::FeeRate;
+use crate::types::errors::Errors;
+use crate::validation::MAX_MONEY;
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+// As in Bitcoin Core: filters are sent every ten minutes on average, and within five
+// once the mempool minimum moves by more than a third.
+pub const AVG_FEEFILTER_BROADCAST_INTERVAL: Duration = Duration::from_secs(10 * 60);
+pub const MAX_FEEFILTER_CHANGE_DELAY: Duration = Duration::from_secs(5 * 60);
+// Buckets of the rounding, 10% apart up to this many sat/kvB.
+const MAX_FILTER_FEERATE: u64 = 10_000_000;
+const FEE_FILTER_SPACING: f64 = 1.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeFilter {
+    pub feerate: FeeRate,
+}
+
+impl Message for FeeFilter {
+    const COMMAND: &'static str = "feefilter";
+
+    // In sat/kvB, refused outside the range of amounts.
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let feerate = i64::from_le_bytes(read_array(reader)?);
+        if feerate < 0 || feerate as u64 > MAX_MONEY {
+            return Err(Errors::ValueOutOfRange);
+        }
+        Ok(FeeFilter {
+            feerate: FeeRate::from_sat_per_kvb(feerate as u64),
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        (self.feerate.sat_per_kvb() as i64).to_le_bytes().to_vec()
+    }
+}
+
+impl PeerInfo {
+    // Whether a transaction paying feerate may be announced to the peer.
+    pub fn accepts_feerate(&self, feerate: FeeRate) -> bool {
+        feerate >= self.fee_filter
+    }
+}
+
+// FeeFilterRounder of Bitcoin Core.
+#[derive(Clone, Debug)]
+pub struct FeeFilterRounder {
+    // In sat/kvB, increasing from zero.
+    buckets: Vec<u64>,
+}
+
+impl FeeFilterRounder {
+    pub fn new(incremental_relay_fee: FeeRate) -> Self {
+        let mut buckets = vec![0];
+        let mut bucket = (incremental_relay_fee.sat_per_kvb() / 2).max(1) as f64;
+        while bucket <= MAX_FILTER_FEERATE as f64 {
+            buckets.push(bucket as u64);
+            bucket *= FEE_FILTER_SPACING;
+        }
+        FeeFilterRounder { buckets }
+    }
+
+    // The lowest bucket at least feerate or, two times out of three, the one below
+    // it. The highest bucket once feerate exceeds them all.
+    pub fn round(&self, feerate: FeeRate) -> FeeRate {
+        let above = self
+            .buckets
+            .partition_point(|bucket| *bucket < feerate.sat_per_kvb());
+        let index =
+            if above == self.buckets.len() || (above > 0 && !random_nonce().is_multiple_of(3)) {
+                above - 1
+            } else {
+                above
+            };
+        FeeRate::from_sat_per_kvb(self.buckets[index])
+    }
+}
+
+// Half the default incremental relay fee is the lowest bucket above zero.
+impl Default for FeeFilterRounder {
+    fn default() -> Self {
+        FeeFilterRounder::new(FeeRate::from_sat_per_kvb(DEFAULT_INCREMENTAL_RELAY_FEE))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Schedule {
+    sent: Option<FeeRate>,
+    next: Instant,
+}
+
+// A delay averaging interval, so peers can't tell when the mempool changed.
+fn random_delay(interval: Duration) -> Duration {
+    let range = interval.as_millis() as u64 * 2;
+    Duration::from_millis(random_nonce() % range.max(1))
+}
+
+// Sends our filter to the peers knowing feefilter, told the time and the mempool's
+// minimum feerate.
+#[derive(Clone, Debug)]
+pub struct FeeFilterSender {
+    network: Network,
+    rounder: FeeFilterRounder,
+    peers: HashMap<PeerId, Schedule>,
+}
+
+impl FeeFilterSender {
+    pub fn new(network: Network) -> Self {
+        FeeFilterSender {
+            network,
+            rounder: FeeFilterRounder::default(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn with_rounder(mut self, rounder: FeeFilterRounder) -> Self {
+        self.rounder = rounder;
+        self
+    }
+
+    // Schedules a first filter for peer at once, if its version knows them.
+    pub fn connected(&mut self, peer: PeerId, info: &PeerInfo, now: Instant) {
+        if info.version >= FEEFILTER_VERSION {
+            self.peers.insert(
+                peer,
+                Schedule {
+                    sent: None,
+                    next: now,
+                },
+            );
+        }
+    }
+
+    pub fn disconnected(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+    }
+
+    // The filter last sent to peer.
+    pub fn sent(&self, peer: PeerId) -> Option<FeeRate> {
+        self.peers.get(&peer)?.sent
+    }
+
+    // The filters due, for a mempool accepting transactions paying min_feerate. One
+    // is only sent when the rounded rate changed, and brought forward when it moved
+    // far from the last one sent.
+    pub fn tick(&mut self, min_feerate: FeeRate, now: Instant) -> Vec<(PeerId, NetworkEnvelope)> {
+        let mut sends = Vec::new();
+        for (peer, schedule) in &mut self.peers {
+            if now >= schedule.next {
+                let feerate = self.rounder.round(min_feerate);
+                if schedule.sent != Some(feerate) {
+                    schedule.sent = Some(feerate);
+                    sends.push((*peer, FeeFilter { feerate }.to_envelope(self.network)));
+                }
+                schedule.next = now + random_delay(AVG_FEEFILTER_BROADCAST_INTERVAL);
+                continue;
+            }
+            let Some(sent) = schedule.sent else {
+                continue;
+            };
+            let (current, sent) = (min_feerate.sat_per_kvb(), sent.sat_per_kvb());
+            let moved = current * 4 < sent * 3 || current * 3 > sent * 4;
+            if moved && schedule.next > now + MAX_FEEFILTER_CHANGE_DELAY {
+                schedule.next = now + random_delay(MAX_FEEFILTER_CHANGE_DELAY / 2);
+            }
+        }
+        sends.sort_by_key(|(peer, _)| *peer);
+        sends
+    }
+}
+
+#[cfg(test)]
+mod feefilter_tests {
+    use super::*;
+    use crate::p2p::inventory::{Inv, InventoryRelay};
+    use crate::p2p::version::PROTOCOL_VERSION;
+    use crate::script::Script;
+    use crate::transaction::{OutPoint, Transaction, TxIn, TxOut};
+
+    fn info() -> PeerInfo {
+        PeerInfo {
+            version: PROTOCOL_VERSION,
+            ..PeerInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_message_and_announcements() {
+        let filter = FeeFilter {
+            feerate: FeeRate::from_sat_per_kvb(1000),
+        };
+        let envelope = filter.to_envelope(Network::Regtest);
+        assert_eq!(hex::encode(&envelope.payload), "e803000000000000");
+        assert_eq!(envelope.message::<FeeFilter>(), Some(Ok(filter)));
+        let negative = (-1i64).to_le_bytes();
+        assert_eq!(
+            FeeFilter::parse(&mut negative.as_slice()),
+            Err(Errors::ValueOutOfRange)
+        );
+
+        // Transactions below the peer's filter aren't announced to it.
+        let peer = PeerInfo {
+            fee_filter: filter.feerate,
+            ..info()
+        };
+        let tx = Transaction::new(
+            2,
+            vec![TxIn::new(OutPoint::new([1; 32], 0), Script::new(), 0)],
+            vec![TxOut::new(1000, vec![0x51].into())],
+            0,
+        );
+        let mut relay = InventoryRelay::new(Network::Regtest);
+        let low = FeeRate::from_sat_per_kvb(999);
+        assert!(!peer.accepts_feerate(low));
+        assert_eq!(relay.announce_transaction_at(tx.clone(), low, &peer), None);
+        assert_eq!(relay.transaction(&tx.txid()), Some(&tx));
+        let inv = relay.announce_transaction_at(tx.clone(), filter.feerate, &peer);
+        assert_eq!(
+            inv.unwrap()
+                .message::<Inv>()
+                .unwrap()
+                .unwrap()
+                .inventory
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_rounder() {
+        let rounder = FeeFilterRounder::default();
+        assert_eq!(rounder.buckets[..3], [0, 500, 550]);
+        assert_eq!(rounder.round(FeeRate::ZERO), FeeRate::ZERO);
+        // 550 is a bucket, rounded to itself or to 500.
+        for _ in 0..20 {
+            let rounded = rounder.round(FeeRate::from_sat_per_kvb(550)).sat_per_kvb();
+            assert!(rounded == 550 || rounded == 500);
+            let rounded = rounder.round(FeeRate::from_sat_per_kvb(560)).sat_per_kvb();
+            assert!(rounded == 605 || rounded == 550);
+        }
+        let highest = *rounder.buckets.last().unwrap();
+        assert_eq!(
+            rounder.round(FeeRate::from_sat_per_kvb(u64::MAX / 1000)),
+            FeeRate::from_sat_per_kvb(highest)
+        );
+    }
+
+    #[test]
+    fn test_sender_schedule() {
+        let start = Instant::now();
+        let mut sender = FeeFilterSender::new(Network::Regtest);
+        let old = PeerInfo {
+            version: FEEFILTER_VERSION - 1,
+            ..info()
+        };
+        sender.connected(0, &info(), start);
+        sender.connected(1, &old, start);
+
+        let min = FeeRate::from_sat_per_kvb(1000);
+        let sends = sender.tick(min, start);
+        assert_eq!(sends.len(), 1);
+        let sent = sends[0].1.message::<FeeFilter>().unwrap().unwrap().feerate;
+        // The buckets around it.
+        assert!([974, 1071].contains(&sent.sat_per_kvb()));
+        assert_eq!(sender.sent(0), Some(sent));
+        assert_eq!(sender.sent(1), None);
+        // Not sent again before it is due.
+        assert!(sender.tick(min, start).is_empty());
+
+        // A large move brings the next one within the change delay.
+        let high = FeeRate::from_sat_per_kvb(5000);
+        sender.tick(high, start);
+        let sends = sender.tick(high, start + MAX_FEEFILTER_CHANGE_DELAY);
+        assert_eq!(sends.len(), 1);
+        assert!(sender.sent(0).unwrap() >= FeeRate::from_sat_per_kvb(4000));
+
+        sender.disconnected(0);
+        let later = start + 3 * AVG_FEEFILTER_BROADCAST_INTERVAL;
+        assert!(sender.tick(FeeRate::ZERO, later).is_empty());
+    }
+}
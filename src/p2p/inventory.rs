@@ -0,0 +1,551 @@
+// Inventory relay: objects are announced with inv, asked for with getdata and sent
+// in tx and block messages, or in notfound when they are gone.
+use super::envelope::NetworkEnvelope;
+use super::message::{Headers, Message};
+use super::version::PeerInfo;
+use crate::block::Block;
+use crate::helper::{encode_varint, read_array, read_varint};
+use crate::network::Network;
+use crate::policy::FeeRate;
+use crate::transaction::Transaction;
+use crate::types::errors::Errors;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+// Most entries in one inv, getdata or notfound message.
+pub const MAX_INV_SIZE: u64 = 50_000;
+// Set in the type of getdata entries asking for witness data (BIP144).
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InventoryType {
+    Error,
+    Tx,
+    Block,
+    FilteredBlock,
+    CompactBlock,
+    // Transactions announced by wtxid (BIP339).
+    Wtx,
+    WitnessTx,
+    WitnessBlock,
+    WitnessFilteredBlock,
+    Unknown(u32),
+}
+
+impl InventoryType {
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => InventoryType::Error,
+            1 => InventoryType::Tx,
+            2 => InventoryType::Block,
+            3 => InventoryType::FilteredBlock,
+            4 => InventoryType::CompactBlock,
+            5 => InventoryType::Wtx,
+            0x40000001 => InventoryType::WitnessTx,
+            0x40000002 => InventoryType::WitnessBlock,
+            0x40000003 => InventoryType::WitnessFilteredBlock,
+            other => InventoryType::Unknown(other),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            InventoryType::Error => 0,
+            InventoryType::Tx => 1,
+            InventoryType::Block => 2,
+            InventoryType::FilteredBlock => 3,
+            InventoryType::CompactBlock => 4,
+            InventoryType::Wtx => 5,
+            InventoryType::WitnessTx => 0x40000001,
+            InventoryType::WitnessBlock => 0x40000002,
+            InventoryType::WitnessFilteredBlock => 0x40000003,
+            InventoryType::Unknown(other) => other,
+        }
+    }
+
+    pub fn is_transaction(self) -> bool {
+        matches!(
+            self,
+            InventoryType::Tx | InventoryType::Wtx | InventoryType::WitnessTx
+        )
+    }
+
+    pub fn is_block(self) -> bool {
+        matches!(self, InventoryType::Block | InventoryType::WitnessBlock)
+    }
+
+    // Whether the object is to be sent with its witness data.
+    pub fn is_witness(self) -> bool {
+        self.to_u32() & MSG_WITNESS_FLAG != 0 || self == InventoryType::Wtx
+    }
+}
+
+// An object, by type and txid, wtxid or block hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: InventoryType,
+    pub hash: [u8; 32],
+}
+
+impl Inventory {
+    pub fn new(kind: InventoryType, hash: [u8; 32]) -> Self {
+        Inventory { kind, hash }
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(Inventory {
+            kind: InventoryType::from_u32(u32::from_le_bytes(read_array(reader)?)),
+            hash: read_array(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.kind.to_u32().to_le_bytes().to_vec();
+        result.extend(self.hash);
+        result
+    }
+}
+
+fn parse_inventory(reader: &mut impl Read) -> Result<Vec<Inventory>, Errors> {
+    let count = read_varint(reader)?;
+    if count > MAX_INV_SIZE {
+        return Err(Errors::ValueOutOfRange);
+    }
+    (0..count).map(|_| Inventory::parse(reader)).collect()
+}
+
+fn serialize_inventory(inventory: &[Inventory]) -> Vec<u8> {
+    let mut result = encode_varint(inventory.len() as u64);
+    for item in inventory {
+        result.extend(item.serialize());
+    }
+    result
+}
+
+// Messages made of a list of inventory.
+macro_rules! inventory_messages {
+    ($($(#[$meta:meta])* $name:ident => $command:literal,)*) => {
+        $(
+            $(#[$meta])*
+            #[derive(Clone, Debug, Default, PartialEq, Eq)]
+            pub struct $name {
+                pub inventory: Vec<Inventory>,
+            }
+
+            impl Message for $name {
+                const COMMAND: &'static str = $command;
+
+                fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+                    Ok($name {
+                        inventory: parse_inventory(reader)?,
+                    })
+                }
+
+                fn serialize(&self) -> Vec<u8> {
+                    serialize_inventory(&self.inventory)
+                }
+            }
+        )*
+    };
+}
+
+inventory_messages! {
+    // Announces objects the sender has.
+    Inv => "inv",
+    // Asks for announced objects.
+    GetData => "getdata",
+    // Answers getdata entries the sender doesn't have.
+    NotFound => "notfound",
+}
+
+impl Message for Transaction {
+    const COMMAND: &'static str = "tx";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Transaction::parse(reader)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        Transaction::serialize(self)
+    }
+}
+
+impl Message for Block {
+    const COMMAND: &'static str = "block";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Block::parse(reader)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        Block::serialize(self)
+    }
+}
+
+// An object received from a peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Received {
+    Transaction(Transaction),
+    Block(Block),
+}
+
+// What a blocks-only node keeps of an envelope received: invs without their
+// transactions, and nothing of transactions or invs of nothing else, as it asked
+// peers not to relay any in its version.
+pub fn without_transactions(envelope: &NetworkEnvelope) -> Option<NetworkEnvelope> {
+    if envelope.command == Transaction::COMMAND {
+        return None;
+    }
+    let Some(Ok(mut inv)) = envelope.message::<Inv>() else {
+        return Some(envelope.clone());
+    };
+    let before = inv.inventory.len();
+    inv.inventory.retain(|item| !item.kind.is_transaction());
+    if inv.inventory.is_empty() {
+        return None;
+    }
+    if inv.inventory.len() == before {
+        return Some(envelope.clone());
+    }
+    Some(NetworkEnvelope {
+        payload: inv.serialize(),
+        ..envelope.clone()
+    })
+}
+
+// Objects we relay, and those asked for from peers and not received yet. Works on
+// envelopes, so it serves blocking and async connections alike. Blocks-only, the
+// transactions peers announce or send are ignored, ours still relayed.
+#[derive(Clone, Debug)]
+pub struct InventoryRelay {
+    network: Network,
+    // By txid and by wtxid.
+    transactions: HashMap<[u8; 32], Transaction>,
+    wtxids: HashMap<[u8; 32], [u8; 32]>,
+    blocks: HashMap<[u8; 32], Block>,
+    requested: HashSet<[u8; 32]>,
+    blocks_only: bool,
+}
+
+impl InventoryRelay {
+    pub fn new(network: Network) -> Self {
+        InventoryRelay {
+            network,
+            transactions: HashMap::new(),
+            wtxids: HashMap::new(),
+            blocks: HashMap::new(),
+            requested: HashSet::new(),
+            blocks_only: false,
+        }
+    }
+
+    pub fn with_blocks_only(mut self, blocks_only: bool) -> Self {
+        self.blocks_only = blocks_only;
+        self
+    }
+
+    pub fn transaction(&self, hash: &[u8; 32]) -> Option<&Transaction> {
+        self.transactions
+            .get(hash)
+            .or_else(|| self.transactions.get(self.wtxids.get(hash)?))
+    }
+
+    pub fn block(&self, hash: &[u8; 32]) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    // Whether the object is known or already asked for.
+    pub fn knows(&self, hash: &[u8; 32]) -> bool {
+        self.transaction(hash).is_some()
+            || self.blocks.contains_key(hash)
+            || self.requested.contains(hash)
+    }
+
+    pub fn add_transaction(&mut self, tx: Transaction) {
+        let txid = tx.txid();
+        self.requested.remove(&txid);
+        self.requested.remove(&tx.wtxid());
+        self.wtxids.insert(tx.wtxid(), txid);
+        self.transactions.insert(txid, tx);
+    }
+
+    pub fn add_block(&mut self, block: Block) {
+        self.requested.remove(&block.hash());
+        self.blocks.insert(block.hash(), block);
+    }
+
+    // Stores tx and returns its announcement to peer, by wtxid if it asked for that.
+    pub fn announce_transaction(&mut self, tx: Transaction, peer: &PeerInfo) -> NetworkEnvelope {
+        let item = if peer.wtxid_relay {
+            Inventory::new(InventoryType::Wtx, tx.wtxid())
+        } else {
+            Inventory::new(InventoryType::Tx, tx.txid())
+        };
+        self.add_transaction(tx);
+        Inv {
+            inventory: vec![item],
+        }
+        .to_envelope(self.network)
+    }
+
+    // As announce_transaction for tx paying feerate, None below the peer's fee
+    // filter. tx is stored either way, for other peers.
+    pub fn announce_transaction_at(
+        &mut self,
+        tx: Transaction,
+        feerate: FeeRate,
+        peer: &PeerInfo,
+    ) -> Option<NetworkEnvelope> {
+        if !peer.accepts_feerate(feerate) {
+            self.add_transaction(tx);
+            return None;
+        }
+        Some(self.announce_transaction(tx, peer))
+    }
+
+    // Stores block and returns its announcement to peer, by header if it sent
+    // sendheaders.
+    pub fn announce_block(&mut self, block: Block, peer: &PeerInfo) -> NetworkEnvelope {
+        let envelope = if peer.send_headers {
+            Headers {
+                headers: vec![block.header],
+            }
+            .to_envelope(self.network)
+        } else {
+            Inv {
+                inventory: vec![Inventory::new(InventoryType::Block, block.hash())],
+            }
+            .to_envelope(self.network)
+        };
+        self.add_block(block);
+        envelope
+    }
+
+    // Handles an inventory message from a peer, returning the replies to send it and
+    // the object it carried, if any. Other commands are ignored.
+    pub fn receive(
+        &mut self,
+        envelope: &NetworkEnvelope,
+    ) -> Result<(Vec<NetworkEnvelope>, Option<Received>), Errors> {
+        let kept;
+        let envelope = if self.blocks_only {
+            let Some(envelope) = without_transactions(envelope) else {
+                return Ok((Vec::new(), None));
+            };
+            kept = envelope;
+            &kept
+        } else {
+            envelope
+        };
+        if let Some(inv) = envelope.message::<Inv>() {
+            return Ok((self.receive_inv(inv?), None));
+        }
+        if let Some(getdata) = envelope.message::<GetData>() {
+            return Ok((self.receive_getdata(getdata?), None));
+        }
+        if let Some(notfound) = envelope.message::<NotFound>() {
+            for item in notfound?.inventory {
+                self.requested.remove(&item.hash);
+            }
+            return Ok((Vec::new(), None));
+        }
+        if let Some(tx) = envelope.message::<Transaction>() {
+            let tx = tx?;
+            self.add_transaction(tx.clone());
+            return Ok((Vec::new(), Some(Received::Transaction(tx))));
+        }
+        if let Some(block) = envelope.message::<Block>() {
+            let block = block?;
+            self.add_block(block.clone());
+            return Ok((Vec::new(), Some(Received::Block(block))));
+        }
+        Ok((Vec::new(), None))
+    }
+
+    // Asks for the announced objects we don't have, with their witness data.
+    fn receive_inv(&mut self, inv: Inv) -> Vec<NetworkEnvelope> {
+        let mut wanted = Vec::new();
+        for item in inv.inventory {
+            let kind = match item.kind {
+                InventoryType::Tx => InventoryType::WitnessTx,
+                InventoryType::Wtx => InventoryType::Wtx,
+                InventoryType::Block => InventoryType::WitnessBlock,
+                _ => continue,
+            };
+            if !self.knows(&item.hash) {
+                self.requested.insert(item.hash);
+                wanted.push(Inventory::new(kind, item.hash));
+            }
+        }
+        if wanted.is_empty() {
+            return Vec::new();
+        }
+        vec![GetData { inventory: wanted }.to_envelope(self.network)]
+    }
+
+    // Sends what we have of what was asked for, stripped of witness data unless
+    // asked with it, and a notfound for the rest.
+    fn receive_getdata(&self, getdata: GetData) -> Vec<NetworkEnvelope> {
+        let mut replies = Vec::new();
+        let mut missing = Vec::new();
+        for item in getdata.inventory {
+            let witness = item.kind.is_witness();
+            let payload = if item.kind.is_transaction() {
+                self.transaction(&item.hash).map(|tx| {
+                    if witness {
+                        tx.serialize()
+                    } else {
+                        tx.serialize_legacy()
+                    }
+                })
+            } else if item.kind.is_block() {
+                self.blocks.get(&item.hash).map(|block| {
+                    if witness {
+                        block.serialize()
+                    } else {
+                        block.serialize_legacy()
+                    }
+                })
+            } else {
+                None
+            };
+            let command = if item.kind.is_transaction() {
+                Transaction::COMMAND
+            } else {
+                Block::COMMAND
+            };
+            match payload {
+                Some(payload) => replies.push(NetworkEnvelope::new(self.network, command, payload)),
+                None => missing.push(item),
+            }
+        }
+        if !missing.is_empty() {
+            replies.push(NotFound { inventory: missing }.to_envelope(self.network));
+        }
+        replies
+    }
+}
+
+#[cfg(test)]
+mod inventory_tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::transaction::{OutPoint, TxIn, TxOut, Witness};
+
+    fn segwit_tx() -> Transaction {
+        let mut input = TxIn::new(OutPoint::new([1; 32], 0), Script::new(), 0xffffffff);
+        input.witness = Witness::from(vec![vec![1, 2, 3]]);
+        Transaction::new(2, vec![input], vec![TxOut::new(1000, vec![0x51].into())], 0)
+    }
+
+    #[test]
+    fn test_inventory_messages() {
+        let getdata = GetData {
+            inventory: vec![
+                Inventory::new(InventoryType::WitnessBlock, [2; 32]),
+                Inventory::new(InventoryType::Unknown(7), [3; 32]),
+            ],
+        };
+        let bytes = getdata.serialize();
+        assert_eq!(&bytes[..5], [2, 0x02, 0, 0, 0x40]);
+        assert_eq!(GetData::parse(&mut bytes.as_slice()), Ok(getdata));
+
+        let tx = segwit_tx();
+        let envelope = tx.to_envelope(Network::Regtest);
+        assert_eq!(envelope.command, "tx");
+        assert_eq!(envelope.message::<Transaction>(), Some(Ok(tx)));
+    }
+
+    #[test]
+    fn test_relay_between_peers() {
+        let tx = segwit_tx();
+        let block = Network::Regtest.genesis_block();
+        let mut ours = InventoryRelay::new(Network::Regtest);
+        let mut theirs = InventoryRelay::new(Network::Regtest);
+        let peer = PeerInfo {
+            wtxid_relay: true,
+            ..PeerInfo::default()
+        };
+
+        let inv = ours.announce_transaction(tx.clone(), &peer);
+        assert_eq!(
+            inv.message::<Inv>().unwrap().unwrap().inventory,
+            vec![Inventory::new(InventoryType::Wtx, tx.wtxid())]
+        );
+        let (getdata, _) = theirs.receive(&inv).unwrap();
+        assert_eq!(getdata.len(), 1);
+        // Announced again, it isn't asked for twice.
+        assert_eq!(theirs.receive(&inv).unwrap(), (Vec::new(), None));
+
+        let (replies, _) = ours.receive(&getdata[0]).unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            theirs.receive(&replies[0]).unwrap(),
+            (Vec::new(), Some(Received::Transaction(tx.clone())))
+        );
+        assert_eq!(theirs.transaction(&tx.txid()), Some(&tx));
+
+        let inv = theirs.announce_block(block.clone(), &peer);
+        let (getdata, _) = ours.receive(&inv).unwrap();
+        let (replies, _) = theirs.receive(&getdata[0]).unwrap();
+        assert_eq!(
+            ours.receive(&replies[0]).unwrap().1,
+            Some(Received::Block(block))
+        );
+    }
+
+    #[test]
+    fn test_getdata_without_witness_and_notfound() {
+        let tx = segwit_tx();
+        let mut relay = InventoryRelay::new(Network::Regtest);
+        relay.add_transaction(tx.clone());
+        let getdata = GetData {
+            inventory: vec![
+                Inventory::new(InventoryType::Tx, tx.txid()),
+                Inventory::new(InventoryType::Block, [9; 32]),
+            ],
+        };
+        let (replies, _) = relay
+            .receive(&getdata.to_envelope(Network::Regtest))
+            .unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].payload, tx.serialize_legacy());
+        assert_eq!(
+            replies[1].message::<NotFound>().unwrap().unwrap().inventory,
+            vec![Inventory::new(InventoryType::Block, [9; 32])]
+        );
+    }
+
+    #[test]
+    fn test_blocks_only() {
+        let tx = segwit_tx();
+        let block = Network::Regtest.genesis_block();
+        let mut relay = InventoryRelay::new(Network::Regtest).with_blocks_only(true);
+        let inv = Inv {
+            inventory: vec![
+                Inventory::new(InventoryType::Wtx, tx.wtxid()),
+                Inventory::new(InventoryType::Block, block.hash()),
+            ],
+        }
+        .to_envelope(Network::Regtest);
+        let kept = without_transactions(&inv).unwrap();
+        assert_eq!(
+            kept.message::<Inv>().unwrap().unwrap().inventory,
+            vec![Inventory::new(InventoryType::Block, block.hash())]
+        );
+
+        // Only the block is asked for, and transactions sent anyway are dropped.
+        let (getdata, _) = relay.receive(&inv).unwrap();
+        assert_eq!(
+            getdata[0].message::<GetData>().unwrap().unwrap().inventory,
+            vec![Inventory::new(InventoryType::WitnessBlock, block.hash())]
+        );
+        let envelope = tx.to_envelope(Network::Regtest);
+        assert_eq!(without_transactions(&envelope), None);
+        assert_eq!(relay.receive(&envelope).unwrap(), (Vec::new(), None));
+        assert_eq!(relay.transaction(&tx.txid()), None);
+        // Ours are still announced.
+        relay.announce_transaction(tx.clone(), &PeerInfo::default());
+        assert_eq!(relay.transaction(&tx.txid()), Some(&tx));
+    }
+}
@@ -0,0 +1,1083 @@
+// Connections to many peers at once on tokio. Each peer gets a task reading its
+// messages and one writing to it, messages received are published to subscribers,
+// and peers added with add_peer are reconnected to whenever they drop. Peers are
+// pinged regularly, measuring latency, and dropped when they stop answering.
+// Handlers registered for a command answer its messages straight from the reader,
+// so applications can speak commands of their own. The bytes exchanged are counted
+// per peer and in total, and may be limited by rates and an upload target.
+// Blocks-only, peers are asked not to relay transactions, and those they announce
+// anyway are dropped before reaching handlers and subscribers. Peers letting more
+// than the send buffer's worth of messages to them back up are disconnected. With
+// the v2 transport on, connections are encrypted as BIP324 has it, those to peers
+// hanging up on our key made again with v1. Peers are dialed through the proxy set
+// for their network, if any, such as Tor.
+use super::cipher::TAG_SIZE;
+use super::envelope::{NetworkEnvelope, MAX_PAYLOAD_SIZE};
+use super::inventory::without_transactions;
+use super::message::{Message, Ping, Pong};
+use super::proxy::{ProxyConfig, ProxyNetwork};
+use super::traffic::{
+    serves_historical_block, wire_size, RateLimiter, TrafficStats, UploadTarget, UploadTargetStatus,
+};
+use super::transport::{
+    decode_contents, encode_contents, PacketCipher, Transport, LENGTH_SIZE, MAX_CONTENTS_SIZE,
+};
+use super::version::{
+    handshake_over, random_nonce, Handshake, NetworkAddress, PeerInfo, VersionMessage,
+};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, Instrument};
+
+pub const DEFAULT_MAX_PEERS: usize = 8;
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Peers leaving us waiting this long during the handshake are dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+// As in Bitcoin Core: a ping every two minutes, and peers leaving one unanswered
+// for twenty are dropped.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+// As Bitcoin Core's -maxsendbuffer default, in bytes.
+pub const DEFAULT_MAX_SEND_BUFFER: u64 = 1_000_000;
+// Events a subscriber can fall behind by before missing some.
+const EVENT_CAPACITY: usize = 1024;
+
+pub type PeerId = u64;
+pub type HandlerId = u64;
+
+// Given each message of its command, returns the envelopes to send back. It runs on
+// the peer's reader, holding up its next messages until it returns.
+pub type MessageHandler =
+    Arc<dyn Fn(PeerId, &NetworkEnvelope) -> Vec<NetworkEnvelope> + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    Connected(PeerId, PeerInfo),
+    Message(PeerId, NetworkEnvelope),
+    Disconnected(PeerId),
+}
+
+fn io_error(e: std::io::Error) -> Errors {
+    Errors::Io(e.to_string())
+}
+
+// The header is read first, so an oversized payload is refused before reading it.
+pub async fn read_envelope(
+    reader: &mut (impl AsyncRead + Unpin),
+    magic: [u8; 4],
+) -> Result<NetworkEnvelope, Errors> {
+    let mut data = vec![0; 24];
+    reader.read_exact(&mut data).await.map_err(io_error)?;
+    let length = u32::from_le_bytes(data[16..20].try_into().unwrap());
+    if length > MAX_PAYLOAD_SIZE {
+        return Err(Errors::InvalidEnvelope("payload too large"));
+    }
+    data.resize(24 + length as usize, 0);
+    reader.read_exact(&mut data[24..]).await.map_err(io_error)?;
+    NetworkEnvelope::parse(&mut data.as_slice(), magic)
+}
+
+// The next message of a v2 connection, skipping decoys and messages we don't know.
+async fn read_packet(
+    reader: &mut (impl AsyncRead + Unpin),
+    cipher: &mut PacketCipher,
+    magic: [u8; 4],
+) -> Result<NetworkEnvelope, Errors> {
+    loop {
+        let mut length = [0; LENGTH_SIZE];
+        reader.read_exact(&mut length).await.map_err(io_error)?;
+        let length = cipher.decrypt_length(length);
+        if length > MAX_CONTENTS_SIZE {
+            return Err(Errors::InvalidEnvelope("payload too large"));
+        }
+        let mut packet = vec![0; 1 + length + TAG_SIZE];
+        reader.read_exact(&mut packet).await.map_err(io_error)?;
+        if let Some(contents) = cipher.decrypt(&packet, &[])? {
+            if let Some(envelope) = decode_contents(&contents, magic)? {
+                return Ok(envelope);
+            }
+        }
+    }
+}
+
+async fn write_envelope(
+    writer: &mut (impl AsyncWrite + Unpin),
+    envelope: &NetworkEnvelope,
+) -> Result<(), Errors> {
+    writer
+        .write_all(&envelope.serialize())
+        .await
+        .map_err(io_error)
+}
+
+// Connects to address, through its network's proxy in proxies if any, and
+// handshakes, over the v2 transport if v2. Blocking, each read and write giving up
+// after HANDSHAKE_TIMEOUT.
+fn open(
+    address: SocketAddr,
+    proxies: &ProxyConfig,
+    network: Network,
+    v2: bool,
+    version: VersionMessage,
+) -> Result<(std::net::TcpStream, Transport, PeerInfo), Errors> {
+    let host = address.ip().to_string();
+    let connect = || {
+        let stream = match proxies.proxy(ProxyNetwork::of(&host)) {
+            Some(proxy) => proxy.connect(&host, address.port())?,
+            None => std::net::TcpStream::connect_timeout(&address, HANDSHAKE_TIMEOUT)
+                .map_err(io_error)?,
+        };
+        stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))
+            .map_err(io_error)?;
+        Ok(stream)
+    };
+    let (mut stream, mut transport) = if v2 {
+        Transport::initiate(connect()?, network, connect)?
+    } else {
+        (connect()?, Transport::V1(Vec::new()))
+    };
+    let info = handshake_over(&mut stream, &mut transport, network, &version)?;
+    stream
+        .set_read_timeout(None)
+        .and_then(|_| stream.set_write_timeout(None))
+        .map_err(io_error)?;
+    Ok((stream, transport, info))
+}
+
+pub async fn async_handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    network: Network,
+    version: VersionMessage,
+) -> Result<PeerInfo, Errors> {
+    let mut handshake = Handshake::new(network, version);
+    write_envelope(stream, &handshake.start()).await?;
+    loop {
+        let envelope = read_envelope(stream, network.magic()).await?;
+        for reply in handshake.receive(&envelope)? {
+            write_envelope(stream, &reply).await?;
+        }
+        if let Some(info) = handshake.peer_info() {
+            return Ok(info.clone());
+        }
+    }
+}
+
+struct Peer {
+    address: SocketAddr,
+    info: PeerInfo,
+    v2: bool,
+    sender: mpsc::UnboundedSender<NetworkEnvelope>,
+    // Bytes sent to sender that the writer is yet to write.
+    queued: u64,
+    // Dropped with the peer, telling its reader to stop.
+    _shutdown: oneshot::Sender<()>,
+    // Nonce of the ping waiting for its pong, and when it was sent.
+    ping: Option<(u64, Instant)>,
+    // Round trip of the last answered ping.
+    latency: Option<Duration>,
+    // Since the handshake.
+    traffic: TrafficStats,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashMap<PeerId, Peer>,
+    next_id: PeerId,
+    // Addresses kept connected to.
+    persistent: HashSet<SocketAddr>,
+    // By command, in the order added.
+    handlers: HashMap<String, Vec<(HandlerId, MessageHandler)>>,
+    next_handler: HandlerId,
+    // Of every peer since the start, those gone included.
+    totals: TrafficStats,
+    upload_target: Option<UploadTarget>,
+}
+
+#[derive(Clone)]
+pub struct PeerManager {
+    network: Network,
+    version: VersionMessage,
+    max_peers: usize,
+    reconnect_delay: Duration,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    // In bytes a second to each peer, unlimited if None.
+    max_upload_rate: Option<u64>,
+    max_download_rate: Option<u64>,
+    max_send_buffer: u64,
+    blocks_only: bool,
+    v2_transport: bool,
+    proxies: ProxyConfig,
+    state: Arc<Mutex<State>>,
+    events: broadcast::Sender<PeerEvent>,
+}
+
+impl PeerManager {
+    pub fn new(network: Network) -> Self {
+        PeerManager {
+            network,
+            version: VersionMessage::new(0, 0),
+            max_peers: DEFAULT_MAX_PEERS,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            max_upload_rate: None,
+            max_download_rate: None,
+            max_send_buffer: DEFAULT_MAX_SEND_BUFFER,
+            blocks_only: false,
+            v2_transport: false,
+            proxies: ProxyConfig::new(),
+            state: Arc::new(Mutex::new(State::default())),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    // Sent to every peer, with the receiver filled in. A fresh nonce is drawn for
+    // each connection.
+    pub fn with_version(mut self, version: VersionMessage) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+
+    pub fn with_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn with_ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    pub fn with_max_upload_rate(mut self, bytes_per_second: u64) -> Self {
+        self.max_upload_rate = Some(bytes_per_second);
+        self
+    }
+
+    pub fn with_max_download_rate(mut self, bytes_per_second: u64) -> Self {
+        self.max_download_rate = Some(bytes_per_second);
+        self
+    }
+
+    // A message still goes to a peer with nothing queued, however large.
+    pub fn with_max_send_buffer(mut self, bytes: u64) -> Self {
+        self.max_send_buffer = bytes;
+        self
+    }
+
+    // Sets relay off in our version, so must come after with_version.
+    pub fn with_blocks_only(mut self) -> Self {
+        self.version.relay = false;
+        self.blocks_only = true;
+        self
+    }
+
+    // Connects with the v2 transport, and with v1 to peers hanging up on our key.
+    pub fn with_v2_transport(mut self) -> Self {
+        self.v2_transport = true;
+        self
+    }
+
+    // Dials peers through the proxy set for their network, directly if none is.
+    pub fn with_proxies(mut self, proxies: ProxyConfig) -> Self {
+        self.proxies = proxies;
+        self
+    }
+
+    // Peers asking for blocks older than a week once the target leaves too little
+    // for new ones are disconnected instead of served.
+    pub fn with_upload_target(self, target: UploadTarget) -> Self {
+        self.state.lock().unwrap().upload_target = Some(target);
+        self
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    // Events from now on. A subscriber lagging more than EVENT_CAPACITY events
+    // behind misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn peers(&self) -> Vec<(PeerId, SocketAddr, PeerInfo)> {
+        let state = self.state.lock().unwrap();
+        let mut peers: Vec<_> = state
+            .peers
+            .iter()
+            .map(|(id, peer)| (*id, peer.address, peer.info.clone()))
+            .collect();
+        peers.sort_by_key(|(id, _, _)| *id);
+        peers
+    }
+
+    // Whether the connection to the peer is encrypted with the v2 transport.
+    pub fn is_v2(&self, peer: PeerId) -> Option<bool> {
+        Some(self.state.lock().unwrap().peers.get(&peer)?.v2)
+    }
+
+    // Round trip of the peer's last pong, None until it answers a ping.
+    pub fn latency(&self, peer: PeerId) -> Option<Duration> {
+        self.state.lock().unwrap().peers.get(&peer)?.latency
+    }
+
+    pub fn traffic(&self, peer: PeerId) -> Option<TrafficStats> {
+        Some(self.state.lock().unwrap().peers.get(&peer)?.traffic.clone())
+    }
+
+    pub fn totals(&self) -> TrafficStats {
+        self.state.lock().unwrap().totals.clone()
+    }
+
+    pub fn upload_target(&self) -> Option<UploadTargetStatus> {
+        let mut state = self.state.lock().unwrap();
+        Some(state.upload_target.as_mut()?.status(Instant::now()))
+    }
+
+    // Connects once, without reconnecting.
+    pub async fn connect(&self, address: SocketAddr) -> Result<PeerId, Errors> {
+        self.start_peer(address).await.map(|(id, _)| id)
+    }
+
+    // Keeps a connection to address, trying again reconnect_delay after every
+    // failure or disconnection, until remove_peer.
+    pub fn add_peer(&self, address: SocketAddr) {
+        if !self.state.lock().unwrap().persistent.insert(address) {
+            return;
+        }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            while manager.is_persistent(&address) {
+                match manager.start_peer(address).await {
+                    Ok((_, reader)) => {
+                        let _ = reader.await;
+                    }
+                    Err(e) => {
+                        debug!(target: "net", %address, error = %e, "Cannot connect to peer")
+                    }
+                }
+                tokio::time::sleep(manager.reconnect_delay).await;
+            }
+        });
+    }
+
+    // Stops reconnecting to address and disconnects from it.
+    pub fn remove_peer(&self, address: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.persistent.remove(&address);
+        // Dropping the sender ends the writer, which closes the connection.
+        state.peers.retain(|_, peer| peer.address != address);
+    }
+
+    fn is_persistent(&self, address: &SocketAddr) -> bool {
+        self.state.lock().unwrap().persistent.contains(address)
+    }
+
+    pub fn disconnect(&self, peer: PeerId) {
+        self.state.lock().unwrap().peers.remove(&peer);
+    }
+
+    pub fn send(&self, peer: PeerId, message: &impl Message) -> Result<(), Errors> {
+        self.send_envelope(peer, message.to_envelope(self.network))
+    }
+
+    pub fn send_envelope(&self, peer: PeerId, envelope: NetworkEnvelope) -> Result<(), Errors> {
+        let mut state = self.state.lock().unwrap();
+        let serves_historical = serves_historical_block(&envelope);
+        if let Some(target) = state.upload_target.as_mut() {
+            if serves_historical && target.reached(true, Instant::now()) {
+                state.peers.remove(&peer);
+                return Err(Errors::UploadTargetReached);
+            }
+        }
+        self.queue(&mut state, peer, envelope)
+    }
+
+    // Hands envelope to the peer's writer, or disconnects the peer if that would
+    // take its queue past the send buffer.
+    fn queue(
+        &self,
+        state: &mut State,
+        id: PeerId,
+        envelope: NetworkEnvelope,
+    ) -> Result<(), Errors> {
+        let peer = state
+            .peers
+            .get_mut(&id)
+            .ok_or_else(|| Errors::Io("peer is not connected".to_string()))?;
+        let size = wire_size(&envelope);
+        if peer.queued > 0 && peer.queued + size > self.max_send_buffer {
+            // Its reader then stops and reports the disconnection.
+            info!(target: "net", peer = id, queued = peer.queued, "Disconnecting peer with a full send buffer");
+            state.peers.remove(&id);
+            return Err(Errors::SendBufferFull);
+        }
+        peer.sender
+            .send(envelope)
+            .map_err(|_| Errors::Io("peer is not connected".to_string()))?;
+        peer.queued += size;
+        Ok(())
+    }
+
+    // Calls handler with the messages of command from every peer, sending it back
+    // the envelopes returned. The messages are still published to subscribers.
+    pub fn add_handler(
+        &self,
+        command: &str,
+        handler: impl Fn(PeerId, &NetworkEnvelope) -> Vec<NetworkEnvelope> + Send + Sync + 'static,
+    ) -> HandlerId {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_handler;
+        state.next_handler += 1;
+        state
+            .handlers
+            .entry(command.to_string())
+            .or_default()
+            .push((id, Arc::new(handler)));
+        id
+    }
+
+    // As add_handler for the messages of M, which may be a type of the
+    // application's. Those not parsing as M aren't given to handler.
+    pub fn add_message_handler<M: Message + 'static>(
+        &self,
+        handler: impl Fn(PeerId, M) -> Vec<NetworkEnvelope> + Send + Sync + 'static,
+    ) -> HandlerId {
+        self.add_handler(M::COMMAND, move |peer, envelope| {
+            match envelope.message::<M>() {
+                Some(Ok(message)) => handler(peer, message),
+                _ => Vec::new(),
+            }
+        })
+    }
+
+    // Whether there was such a handler.
+    pub fn remove_handler(&self, id: HandlerId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let mut found = false;
+        for handlers in state.handlers.values_mut() {
+            let before = handlers.len();
+            handlers.retain(|(handler, _)| *handler != id);
+            found |= handlers.len() != before;
+        }
+        state.handlers.retain(|_, handlers| !handlers.is_empty());
+        found
+    }
+
+    // Runs the handlers of the envelope's command, outside the lock as they may use
+    // the manager.
+    fn run_handlers(&self, id: PeerId, envelope: &NetworkEnvelope) {
+        let handlers: Vec<MessageHandler> =
+            match self.state.lock().unwrap().handlers.get(&envelope.command) {
+                Some(handlers) => handlers
+                    .iter()
+                    .map(|(_, handler)| handler.clone())
+                    .collect(),
+                None => return,
+            };
+        for handler in handlers {
+            for reply in handler(id, envelope) {
+                let _ = self.send_envelope(id, reply);
+            }
+        }
+    }
+
+    // Sends to every connected peer.
+    pub fn broadcast(&self, message: &impl Message) {
+        let envelope = message.to_envelope(self.network);
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<PeerId> = state.peers.keys().copied().collect();
+        for id in ids {
+            let _ = self.queue(&mut state, id, envelope.clone());
+        }
+    }
+
+    // Connects and handshakes, then leaves the peer to its tasks. Returns the
+    // reader's handle, which finishes once the peer is gone.
+    async fn start_peer(&self, address: SocketAddr) -> Result<(PeerId, JoinHandle<()>), Errors> {
+        if self.state.lock().unwrap().peers.len() >= self.max_peers {
+            return Err(Errors::TooManyPeers);
+        }
+        let mut version = self.version.clone();
+        version.nonce = random_nonce();
+        let version = version.with_receiver(NetworkAddress::new(address, 0));
+        let (network, v2, proxies) = (self.network, self.v2_transport, self.proxies.clone());
+        let (stream, transport, info) =
+            tokio::task::spawn_blocking(move || open(address, &proxies, network, v2, version))
+                .await
+                .map_err(|_| Errors::HandshakeFailed("interrupted"))??;
+        stream.set_nonblocking(true).map_err(io_error)?;
+        let stream = TcpStream::from_std(stream).map_err(io_error)?;
+        let (mut sending, mut receiving) = match transport {
+            Transport::V1(_) => (None, None),
+            Transport::V2(transport) => {
+                let (send, receive) = transport.into_ciphers();
+                (Some(send), Some(receive))
+            }
+        };
+
+        let (mut reader, mut writer) = stream.into_split();
+        let (sender, mut receiver) = mpsc::unbounded_channel::<NetworkEnvelope>();
+        let (shutdown, mut stopped) = oneshot::channel();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            // Others may have connected during the handshake.
+            if state.peers.len() >= self.max_peers {
+                return Err(Errors::TooManyPeers);
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            state.peers.insert(
+                id,
+                Peer {
+                    address,
+                    info: info.clone(),
+                    v2: sending.is_some(),
+                    sender,
+                    queued: 0,
+                    _shutdown: shutdown,
+                    ping: None,
+                    latency: None,
+                    traffic: TrafficStats::default(),
+                },
+            );
+            id
+        };
+        let _ = self.events.send(PeerEvent::Connected(id, info));
+
+        // Around the peer's tasks, for their events to say which peer they are about.
+        let span = info_span!(target: "net", "peer", peer = id, %address);
+        let manager = self.clone();
+        let writing = async move {
+            let mut limiter = manager
+                .max_upload_rate
+                .map(|rate| RateLimiter::new(rate, Instant::now()));
+            while let Some(envelope) = receiver.recv().await {
+                if let Some(limiter) = &mut limiter {
+                    let delay = limiter.delay(wire_size(&envelope), Instant::now());
+                    tokio::time::sleep(delay).await;
+                }
+                let data = match &mut sending {
+                    Some(cipher) => cipher.encrypt(&encode_contents(&envelope), &[], false),
+                    None => envelope.serialize(),
+                };
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+                manager.record_sent(id, &envelope);
+            }
+        };
+        tokio::spawn(writing.instrument(span.clone()));
+        let manager = self.clone();
+        let reading = async move {
+            let magic = manager.network.magic();
+            let mut limiter = manager
+                .max_download_rate
+                .map(|rate| RateLimiter::new(rate, Instant::now()));
+            loop {
+                // Stops when the connection fails or the peer is disconnected.
+                let envelope = tokio::select! {
+                    envelope = async {
+                        match &mut receiving {
+                            Some(cipher) => read_packet(&mut reader, cipher, magic).await,
+                            None => read_envelope(&mut reader, magic).await,
+                        }
+                    } => envelope,
+                    _ = &mut stopped => break,
+                };
+                let envelope = match envelope {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        debug!(target: "net", peer = id, error = %e, "Connection closed");
+                        break;
+                    }
+                };
+                let size = manager.record_received(id, &envelope);
+                // Not reading on lets the peer's sends back up.
+                if let Some(limiter) = &mut limiter {
+                    tokio::time::sleep(limiter.delay(size, Instant::now())).await;
+                }
+                let envelope = if manager.blocks_only {
+                    match without_transactions(&envelope) {
+                        Some(kept) => kept,
+                        None => continue,
+                    }
+                } else {
+                    envelope
+                };
+                if let Some(Ok(ping)) = envelope.message::<Ping>() {
+                    let _ = manager.send(id, &Pong { nonce: ping.nonce });
+                    continue;
+                }
+                if let Some(Ok(pong)) = envelope.message::<Pong>() {
+                    if manager.receive_pong(id, pong) {
+                        continue;
+                    }
+                }
+                manager.update_info(id, &envelope);
+                manager.run_handlers(id, &envelope);
+                let _ = manager.events.send(PeerEvent::Message(id, envelope));
+            }
+            manager.disconnect(id);
+            let _ = manager.events.send(PeerEvent::Disconnected(id));
+        };
+        let reader = tokio::spawn(reading.instrument(span));
+        let manager = self.clone();
+        tokio::spawn(async move { while manager.keep_alive(id).await {} });
+        Ok((id, reader))
+    }
+
+    // Waits a ping interval, then pings the peer unless a ping is still out, or
+    // drops it if that one timed out. False once the peer is gone.
+    async fn keep_alive(&self, id: PeerId) -> bool {
+        tokio::time::sleep(self.ping_interval).await;
+        let nonce = random_nonce();
+        let mut state = self.state.lock().unwrap();
+        let Some(peer) = state.peers.get_mut(&id) else {
+            return false;
+        };
+        match peer.ping {
+            Some((_, sent)) if sent.elapsed() >= self.ping_timeout => {
+                // Its reader then stops and reports the disconnection.
+                info!(target: "net", peer = id, "Disconnecting peer not answering pings");
+                state.peers.remove(&id);
+                return false;
+            }
+            Some(_) => {}
+            None => {
+                peer.ping = Some((nonce, Instant::now()));
+                let ping = Ping { nonce }.to_envelope(self.network);
+                return self.queue(&mut state, id, ping).is_ok();
+            }
+        }
+        true
+    }
+
+    // Keeps what the peer asked for in its info, for relays deciding how and what to
+    // announce it.
+    fn update_info(&self, id: PeerId, envelope: &NetworkEnvelope) {
+        if let Some(peer) = self.state.lock().unwrap().peers.get_mut(&id) {
+            peer.info.update(envelope);
+        }
+    }
+
+    fn record_sent(&self, id: PeerId, envelope: &NetworkEnvelope) {
+        let mut state = self.state.lock().unwrap();
+        let bytes = state.totals.record_sent(envelope);
+        if let Some(target) = state.upload_target.as_mut() {
+            target.record(bytes, Instant::now());
+        }
+        if let Some(peer) = state.peers.get_mut(&id) {
+            peer.traffic.record_sent(envelope);
+            peer.queued = peer.queued.saturating_sub(wire_size(envelope));
+        }
+    }
+
+    fn record_received(&self, id: PeerId, envelope: &NetworkEnvelope) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if let Some(peer) = state.peers.get_mut(&id) {
+            peer.traffic.record_received(envelope);
+        }
+        state.totals.record_received(envelope)
+    }
+
+    // Records the latency if pong answers our ping. Whether it did.
+    fn receive_pong(&self, id: PeerId, pong: Pong) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(peer) = state.peers.get_mut(&id) else {
+            return false;
+        };
+        match peer.ping {
+            Some((nonce, sent)) if nonce == pong.nonce => {
+                peer.latency = Some(sent.elapsed());
+                peer.ping = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod manager_tests {
+    use super::*;
+    use crate::p2p::message::SendHeaders;
+    use crate::p2p::proxy::Socks5Proxy;
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Accepts a connection and handshakes as a peer would, up to the sendheaders
+    // sent after it.
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        async_handshake(&mut stream, Network::Regtest, VersionMessage::new(0, 5))
+            .await
+            .unwrap();
+        let envelope = read_envelope(&mut stream, Network::Regtest.magic()).await;
+        assert_eq!(envelope.unwrap().command, SendHeaders::COMMAND);
+        stream
+    }
+
+    // Skips the peer's own sendheaders.
+    async fn next_event(events: &mut broadcast::Receiver<PeerEvent>) -> PeerEvent {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if !matches!(&event, PeerEvent::Message(_, envelope) if envelope.command == SendHeaders::COMMAND)
+            {
+                return event;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_routed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let manager = PeerManager::new(Network::Regtest).with_max_peers(1);
+        let mut events = manager.subscribe();
+        let (id, mut peer) = tokio::join!(manager.connect(address), accept(&listener));
+        let id = id.unwrap();
+        assert!(
+            matches!(next_event(&mut events).await, PeerEvent::Connected(i, info) if i == id && info.start_height == 5)
+        );
+        assert_eq!(manager.peers().len(), 1);
+        assert_eq!(manager.connect(address).await, Err(Errors::TooManyPeers));
+
+        // Pings are answered without bothering subscribers.
+        write_envelope(&mut peer, &Ping { nonce: 3 }.to_envelope(Network::Regtest))
+            .await
+            .unwrap();
+        let pong = read_envelope(&mut peer, Network::Regtest.magic())
+            .await
+            .unwrap();
+        assert_eq!(pong.message(), Some(Ok(Pong { nonce: 3 })));
+        let inv = NetworkEnvelope::new(Network::Regtest, "inv", vec![0]);
+        write_envelope(&mut peer, &inv).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Message(id, inv));
+
+        manager.send(id, &Ping { nonce: 4 }).unwrap();
+        let ping = read_envelope(&mut peer, Network::Regtest.magic())
+            .await
+            .unwrap();
+        assert_eq!(ping.message(), Some(Ok(Ping { nonce: 4 })));
+
+        drop(peer);
+        assert_eq!(next_event(&mut events).await, PeerEvent::Disconnected(id));
+        assert!(manager.peers().is_empty());
+        assert!(manager.send(id, &Ping { nonce: 5 }).is_err());
+    }
+
+    // A command of the application's own.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct Hello {
+        count: u32,
+    }
+
+    impl Message for Hello {
+        const COMMAND: &'static str = "hello";
+
+        fn parse(reader: &mut impl std::io::Read) -> Result<Self, Errors> {
+            Ok(Hello {
+                count: u32::from_le_bytes(crate::helper::read_array(reader)?),
+            })
+        }
+
+        fn serialize(&self) -> Vec<u8> {
+            self.count.to_le_bytes().to_vec()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_handlers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let manager = PeerManager::new(Network::Regtest);
+        let handler = manager.add_message_handler(|_, hello: Hello| {
+            let reply = Hello {
+                count: hello.count + 1,
+            };
+            vec![reply.to_envelope(Network::Regtest)]
+        });
+        let mut events = manager.subscribe();
+        let (id, mut peer) = tokio::join!(manager.connect(address), accept(&listener));
+        let id = id.unwrap();
+        next_event(&mut events).await;
+
+        let hello = Hello { count: 1 }.to_envelope(Network::Regtest);
+        write_envelope(&mut peer, &hello).await.unwrap();
+        let reply = read_envelope(&mut peer, Network::Regtest.magic())
+            .await
+            .unwrap();
+        assert_eq!(reply.message(), Some(Ok(Hello { count: 2 })));
+        assert_eq!(
+            next_event(&mut events).await,
+            PeerEvent::Message(id, hello.clone())
+        );
+
+        // Once removed, only the ping sent after it is answered.
+        assert!(manager.remove_handler(handler));
+        assert!(!manager.remove_handler(handler));
+        write_envelope(&mut peer, &hello).await.unwrap();
+        write_envelope(&mut peer, &Ping { nonce: 7 }.to_envelope(Network::Regtest))
+            .await
+            .unwrap();
+        let reply = read_envelope(&mut peer, Network::Regtest.magic())
+            .await
+            .unwrap();
+        assert_eq!(reply.message(), Some(Ok(Pong { nonce: 7 })));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let manager = PeerManager::new(Network::Regtest)
+            .with_ping_interval(Duration::from_millis(20))
+            .with_ping_timeout(Duration::from_millis(100));
+        let mut events = manager.subscribe();
+        let (id, mut peer) = tokio::join!(manager.connect(address), accept(&listener));
+        let id = id.unwrap();
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Connected(..)
+        ));
+        assert_eq!(manager.latency(id), None);
+
+        // Answered pongs give the latency and aren't published.
+        let ping = read_envelope(&mut peer, Network::Regtest.magic())
+            .await
+            .unwrap();
+        let Some(Ok(Ping { nonce })) = ping.message() else {
+            panic!("expected a ping");
+        };
+        write_envelope(&mut peer, &Pong { nonce }.to_envelope(Network::Regtest))
+            .await
+            .unwrap();
+        let inv = NetworkEnvelope::new(Network::Regtest, "inv", vec![0]);
+        write_envelope(&mut peer, &inv).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Message(id, inv));
+        assert!(manager.latency(id).is_some());
+
+        // Pings left unanswered get the peer dropped.
+        assert_eq!(next_event(&mut events).await, PeerEvent::Disconnected(id));
+        assert!(manager.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let manager =
+            PeerManager::new(Network::Regtest).with_reconnect_delay(Duration::from_millis(10));
+        let mut events = manager.subscribe();
+        manager.add_peer(address);
+        let peer = accept(&listener).await;
+        let PeerEvent::Connected(first, _) = next_event(&mut events).await else {
+            panic!("expected a connection");
+        };
+        drop(peer);
+        assert_eq!(
+            next_event(&mut events).await,
+            PeerEvent::Disconnected(first)
+        );
+
+        let _peer = accept(&listener).await;
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Connected(second, _) if second != first
+        ));
+        manager.remove_peer(address);
+        assert!(manager.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_traffic_and_upload_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Too small to leave room for historical blocks at all.
+        let target =
+            UploadTarget::new(1000, Instant::now()).with_timeframe(Duration::from_secs(600));
+        let manager = PeerManager::new(Network::Regtest).with_upload_target(target);
+        let mut events = manager.subscribe();
+        let (id, mut peer) = tokio::join!(manager.connect(address), accept(&listener));
+        let id = id.unwrap();
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Connected(..)
+        ));
+
+        manager.send(id, &Ping { nonce: 1 }).unwrap();
+        read_envelope(&mut peer, Network::Regtest.magic())
+            .await
+            .unwrap();
+        let inv = NetworkEnvelope::new(Network::Regtest, "inv", vec![0]);
+        write_envelope(&mut peer, &inv).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Message(id, inv));
+        let traffic = manager.traffic(id).unwrap();
+        assert_eq!(traffic.bytes_sent_per_command["ping"], 32);
+        assert_eq!(traffic.bytes_received_per_command["inv"], 25);
+        assert_eq!(manager.totals().bytes_received, traffic.bytes_received);
+
+        let status = manager.upload_target().unwrap();
+        assert!(!status.target_reached && !status.serve_historical_blocks);
+        let genesis = Network::Regtest.genesis_block();
+        assert_eq!(manager.send(id, &genesis), Err(Errors::UploadTargetReached));
+        assert_eq!(next_event(&mut events).await, PeerEvent::Disconnected(id));
+        // Totals outlive the peer.
+        assert!(manager.traffic(id).is_none());
+        assert!(manager.totals().bytes_sent >= 32);
+    }
+
+    #[tokio::test]
+    async fn test_v2_transport() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // A v2 peer, answering our ping and then announcing something.
+        let peer = tokio::task::spawn_blocking(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let network = Network::Regtest;
+            let mut transport = Transport::accept(&mut stream, network).unwrap();
+            assert!(transport.is_v2());
+            let version = VersionMessage::new(0, 5);
+            handshake_over(&mut stream, &mut transport, network, &version).unwrap();
+            loop {
+                let envelope = transport.read(&mut stream, network.magic()).unwrap();
+                if let Some(Ok(ping)) = envelope.message::<Ping>() {
+                    let pong = Pong { nonce: ping.nonce }.to_envelope(network);
+                    transport.write(&mut stream, &pong).unwrap();
+                    break;
+                }
+            }
+            let inv = NetworkEnvelope::new(network, "inv", vec![0]);
+            transport.write(&mut stream, &inv).unwrap();
+            stream
+        });
+        let manager = PeerManager::new(Network::Regtest)
+            .with_v2_transport()
+            .with_ping_interval(Duration::from_millis(20));
+        let mut events = manager.subscribe();
+        let id = manager.connect(address).await.unwrap();
+        assert_eq!(manager.is_v2(id), Some(true));
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Connected(..)
+        ));
+        let inv = NetworkEnvelope::new(Network::Regtest, "inv", vec![0]);
+        assert_eq!(next_event(&mut events).await, PeerEvent::Message(id, inv));
+        assert!(manager.latency(id).is_some());
+        drop(peer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_v2_falls_back_to_v1() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let manager = PeerManager::new(Network::Regtest).with_v2_transport();
+        let (id, _peer) = tokio::join!(manager.connect(address), async {
+            // A v1 peer hangs up on our key once it reads a header with the wrong magic.
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0; 24];
+            stream.read_exact(&mut header).await.unwrap();
+            assert_ne!(header[..4], Network::Regtest.magic());
+            drop(stream);
+            accept(&listener).await
+        });
+        assert_eq!(manager.is_v2(id.unwrap()), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_v2_through_proxy() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        let peer = tokio::task::spawn_blocking(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let network = Network::Regtest;
+            let mut transport = Transport::accept(&mut stream, network).unwrap();
+            let version = VersionMessage::new(0, 5);
+            handshake_over(&mut stream, &mut transport, network, &version).unwrap();
+            (stream, transport.is_v2())
+        });
+        // A SOCKS5 proxy without authentication, relaying a single connection.
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxies = ProxyConfig::new().with_proxy(Socks5Proxy::new(proxy.local_addr().unwrap()));
+        let (requested, dialed) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut client, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            client.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0; 10];
+            client.read_exact(&mut request).await.unwrap();
+            let ip: [u8; 4] = request[4..8].try_into().unwrap();
+            let address = SocketAddr::from((ip, u16::from_be_bytes([request[8], request[9]])));
+            let mut server = TcpStream::connect(address).await.unwrap();
+            client
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            requested.send(address).unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+        });
+
+        let manager = PeerManager::new(Network::Regtest)
+            .with_v2_transport()
+            .with_proxies(proxies);
+        let id = manager.connect(target).await.unwrap();
+        assert_eq!(dialed.await.unwrap(), target);
+        assert_eq!(manager.is_v2(id), Some(true));
+        assert_eq!(manager.peers()[0].2.start_height, 5);
+        let (_stream, v2) = peer.await.unwrap();
+        assert!(v2);
+    }
+
+    #[tokio::test]
+    async fn test_send_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // The first ping holds up the writer long enough for the rest to queue.
+        let manager = PeerManager::new(Network::Regtest)
+            .with_max_upload_rate(1)
+            .with_max_send_buffer(100);
+        let mut events = manager.subscribe();
+        let (id, _peer) = tokio::join!(manager.connect(address), accept(&listener));
+        let id = id.unwrap();
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Connected(..)
+        ));
+
+        // Three pings of 32 bytes fit, and the fourth does not.
+        for nonce in 0..3 {
+            manager.send(id, &Ping { nonce }).unwrap();
+        }
+        assert_eq!(
+            manager.send(id, &Ping { nonce: 3 }),
+            Err(Errors::SendBufferFull)
+        );
+        assert_eq!(next_event(&mut events).await, PeerEvent::Disconnected(id));
+        assert!(manager.peers().is_empty());
+    }
+}
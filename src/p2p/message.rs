@@ -0,0 +1,248 @@
+// Payloads of P2P messages, each sent under its own command name.
+use super::envelope::NetworkEnvelope;
+use super::version::PROTOCOL_VERSION;
+use crate::block::BlockHeader;
+use crate::helper::{encode_varint, read_array, read_varint};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::io::Read;
+
+pub trait Message: Sized {
+    const COMMAND: &'static str;
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors>;
+
+    fn serialize(&self) -> Vec<u8>;
+
+    fn to_envelope(&self, network: Network) -> NetworkEnvelope {
+        NetworkEnvelope::new(network, Self::COMMAND, self.serialize())
+    }
+}
+
+impl NetworkEnvelope {
+    // The payload as a message of type M, which must be the whole payload. None if
+    // the envelope carries another command.
+    pub fn message<M: Message>(&self) -> Option<Result<M, Errors>> {
+        if self.command != M::COMMAND {
+            return None;
+        }
+        let mut reader = self.payload.as_slice();
+        Some(M::parse(&mut reader).and_then(|message| {
+            if reader.is_empty() {
+                Ok(message)
+            } else {
+                Err(Errors::TrailingData)
+            }
+        }))
+    }
+}
+
+// Messages without a payload.
+macro_rules! empty_messages {
+    ($($(#[$meta:meta])* $name:ident => $command:literal,)*) => {
+        $(
+            $(#[$meta])*
+            #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+            pub struct $name;
+
+            impl Message for $name {
+                const COMMAND: &'static str = $command;
+
+                fn parse(_reader: &mut impl Read) -> Result<Self, Errors> {
+                    Ok($name)
+                }
+
+                fn serialize(&self) -> Vec<u8> {
+                    Vec::new()
+                }
+            }
+        )*
+    };
+}
+
+empty_messages! {
+    // Acknowledges the peer's version message.
+    VerAck => "verack",
+    // Asks for new blocks to be announced with headers rather than inv (BIP130).
+    SendHeaders => "sendheaders",
+    // Sent before verack: transactions are announced by wtxid (BIP339).
+    WtxidRelay => "wtxidrelay",
+    // Sent before verack: addresses may be sent in addrv2 messages (BIP155).
+    SendAddrV2 => "sendaddrv2",
+    // Asks for addresses of other nodes, answered with addr.
+    GetAddr => "getaddr",
+    // Drops the bloom filter loaded with filterload (BIP37).
+    FilterClear => "filterclear",
+}
+
+// Keeps the connection alive. The nonce is sent back in the pong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ping {
+    pub nonce: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pong {
+    pub nonce: u64,
+}
+
+impl Message for Ping {
+    const COMMAND: &'static str = "ping";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(Ping {
+            nonce: u64::from_le_bytes(read_array(reader)?),
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        self.nonce.to_le_bytes().to_vec()
+    }
+}
+
+impl Message for Pong {
+    const COMMAND: &'static str = "pong";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(Pong {
+            nonce: u64::from_le_bytes(read_array(reader)?),
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        self.nonce.to_le_bytes().to_vec()
+    }
+}
+
+// Most headers sent in answer to one getheaders.
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+// Largest locator accepted, as MAX_LOCATOR_SZ in Bitcoin Core.
+pub const MAX_LOCATOR_SIZE: u64 = 101;
+
+// Asks for the headers following the first locator hash the peer knows, up to
+// stop, or as many as fit in one headers message when stop is zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GetHeaders {
+    pub version: u32,
+    pub locator: Vec<[u8; 32]>,
+    pub stop: [u8; 32],
+}
+
+impl GetHeaders {
+    pub fn new(locator: Vec<[u8; 32]>, stop: [u8; 32]) -> Self {
+        GetHeaders {
+            version: PROTOCOL_VERSION as u32,
+            locator,
+            stop,
+        }
+    }
+}
+
+impl Message for GetHeaders {
+    const COMMAND: &'static str = "getheaders";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let version = u32::from_le_bytes(read_array(reader)?);
+        let count = read_varint(reader)?;
+        if count > MAX_LOCATOR_SIZE {
+            return Err(Errors::ValueOutOfRange);
+        }
+        let locator = (0..count)
+            .map(|_| read_array(reader))
+            .collect::<Result<_, _>>()?;
+        Ok(GetHeaders {
+            version,
+            locator,
+            stop: read_array(reader)?,
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.extend(encode_varint(self.locator.len() as u64));
+        for hash in &self.locator {
+            result.extend(hash);
+        }
+        result.extend(self.stop);
+        result
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Headers {
+    pub headers: Vec<BlockHeader>,
+}
+
+impl Message for Headers {
+    const COMMAND: &'static str = "headers";
+
+    // Each header is followed by a transaction count, always zero.
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let count = read_varint(reader)?;
+        if count > MAX_HEADERS_RESULTS as u64 {
+            return Err(Errors::ValueOutOfRange);
+        }
+        let mut headers = Vec::new();
+        for _ in 0..count {
+            headers.push(BlockHeader::parse(reader)?);
+            if read_varint(reader)? != 0 {
+                return Err(Errors::ValueOutOfRange);
+            }
+        }
+        Ok(Headers { headers })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = encode_varint(self.headers.len() as u64);
+        for header in &self.headers {
+            result.extend(header.serialize());
+            result.push(0);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod message_tests {
+    use super::*;
+    use crate::transaction::txid_from_hex;
+
+    #[test]
+    fn test_envelope_messages() {
+        let envelope = VerAck.to_envelope(Network::Mainnet);
+        assert_eq!(
+            hex::encode(envelope.serialize()),
+            "f9beb4d976657261636b000000000000000000005df6e0e2"
+        );
+        assert_eq!(envelope.message::<VerAck>(), Some(Ok(VerAck)));
+        assert_eq!(envelope.message::<SendHeaders>(), None);
+
+        let mut envelope = WtxidRelay.to_envelope(Network::Regtest);
+        envelope.payload.push(0);
+        assert_eq!(
+            envelope.message::<WtxidRelay>(),
+            Some(Err(Errors::TrailingData))
+        );
+    }
+
+    #[test]
+    fn test_headers_messages() {
+        // Vectors of Programming Bitcoin.
+        let start =
+            txid_from_hex("0000000000000000001237f46acddf58578a37e213d2a6edc4884a2fcad05ba3")
+                .unwrap();
+        let mut getheaders = GetHeaders::new(vec![start], [0; 32]);
+        getheaders.version = 70015;
+        assert_eq!(hex::encode(getheaders.serialize()), "7f11010001a35bd0ca2f4a88c4eda6d213e2378a5758dfcd6af437120000000000000000000000000000000000000000000000000000000000000000000000000000000000");
+        assert_eq!(
+            GetHeaders::parse(&mut getheaders.serialize().as_slice()),
+            Ok(getheaders)
+        );
+
+        let bytes = hex::decode("0200000020df3b053dc46f162a9b00c7f0d5124e2676d47bbe7c5d0793a500000000000000ef445fef2ed495c275892206ca533e7411907971013ab83e3b47bd0d692d14d4dc7c835b67d8001ac157e670000000002030eb2540c41025690160a1014c577061596e32e426b712c7ca00000000000000768b89f07044e6130ead292a3f51951adbd2202df447d98789339937fd006bd44880835b67d8001ade09204600").unwrap();
+        let headers = Headers::parse(&mut bytes.as_slice()).unwrap();
+        assert_eq!(headers.headers.len(), 2);
+        assert_eq!(headers.headers[1].prev_block, headers.headers[0].hash());
+        assert_eq!(headers.serialize(), bytes);
+    }
+}
@@ -0,0 +1,55 @@
+// The Bitcoin peer-to-peer protocol: messages, the envelope they travel in, the
+// handshake opening every connection and the connections themselves, blocking to a
+// single peer or async to many.
+pub mod addrman;
+pub mod bloom;
+pub mod broadcast;
+pub mod cipher;
+pub mod compact;
+pub mod download;
+pub mod envelope;
+pub mod feefilter;
+pub mod inventory;
+pub mod manager;
+pub mod message;
+pub mod node;
+pub mod orphans;
+pub mod proxy;
+pub mod seeds;
+pub mod spv;
+pub mod sync;
+pub mod traffic;
+pub mod transport;
+pub mod version;
+
+pub use addrman::{Addr, AddrInfo, AddrMan, TimestampedAddress};
+pub use bloom::{BloomFilter, FilterAdd, FilterLoad};
+pub use broadcast::{BroadcastStatus, Broadcaster};
+pub use compact::{CompactBlockRelay, SendCmpct};
+pub use download::{download_blocks, download_with, BlockDownloader};
+pub use envelope::{NetworkEnvelope, COMMAND_SIZE, MAX_PAYLOAD_SIZE};
+pub use feefilter::{FeeFilter, FeeFilterRounder, FeeFilterSender};
+pub use inventory::{
+    without_transactions, GetData, Inv, Inventory, InventoryRelay, InventoryType, NotFound,
+    Received, MAX_INV_SIZE,
+};
+pub use manager::{HandlerId, MessageHandler, PeerEvent, PeerId, PeerManager};
+pub use message::{
+    FilterClear, GetAddr, GetHeaders, Headers, Message, Ping, Pong, SendAddrV2, SendHeaders,
+    VerAck, WtxidRelay,
+};
+pub use node::SimpleNode;
+pub use orphans::{
+    OrphanPool, MAX_ORPHANS_PER_PEER, MAX_ORPHAN_TRANSACTIONS, ORPHAN_TX_EXPIRE_TIME,
+};
+pub use proxy::{ProxyConfig, ProxyNetwork, Socks5Proxy};
+pub use seeds::{seed_host, DnsSeeder};
+pub use spv::{HistoryEntry, SpvClient};
+pub use sync::{
+    process_announcement, sync_headers, sync_headers_from_best_peer, HeadersAnnouncement,
+};
+pub use traffic::{RateLimiter, TrafficStats, UploadTarget, UploadTargetStatus};
+pub use transport::{SessionKeys, Transport, V2Transport};
+pub use version::{
+    handshake, Handshake, NetworkAddress, PeerInfo, VersionMessage, PROTOCOL_VERSION,
+};
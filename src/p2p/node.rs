@@ -0,0 +1,234 @@
+// A blocking connection to a single peer, as the SimpleNode of Programming Bitcoin:
+// enough to ask a node for headers, blocks or transactions and wait for the answer.
+use super::envelope::NetworkEnvelope;
+use super::message::{Message, Ping, Pong};
+use super::proxy::ProxyConfig;
+use super::transport::Transport;
+use super::version::{handshake_over, NetworkAddress, PeerInfo, VersionMessage};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+
+pub struct SimpleNode {
+    stream: TcpStream,
+    transport: Transport,
+    network: Network,
+    peer: PeerInfo,
+}
+
+fn io_error(e: std::io::Error) -> Errors {
+    Errors::Io(e.to_string())
+}
+
+// The peer's address as receiver, unless host is a name, the connection being to the
+// proxy.
+fn with_peer_receiver(version: VersionMessage, host: &str, port: u16) -> VersionMessage {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => version.with_receiver(NetworkAddress::new(SocketAddr::new(ip, port), 0)),
+        Err(_) => version,
+    }
+}
+
+impl SimpleNode {
+    // Connects and completes the handshake, telling the peer we are at height 0 and
+    // offer no services.
+    pub fn connect(address: impl ToSocketAddrs, network: Network) -> Result<Self, Errors> {
+        Self::connect_with(address, network, VersionMessage::new(0, 0))
+    }
+
+    pub fn connect_with(
+        address: impl ToSocketAddrs,
+        network: Network,
+        version: VersionMessage,
+    ) -> Result<Self, Errors> {
+        let stream = TcpStream::connect(address).map_err(io_error)?;
+        let receiver = NetworkAddress::new(stream.peer_addr().map_err(io_error)?, 0);
+        let version = version.with_receiver(receiver);
+        Self::open(stream, Transport::V1(Vec::new()), network, version)
+    }
+
+    // Connects to host, a name or an IP address, through the proxy proxies set for
+    // its network, if any.
+    pub fn connect_through(
+        proxies: &ProxyConfig,
+        host: &str,
+        port: u16,
+        network: Network,
+        version: VersionMessage,
+    ) -> Result<Self, Errors> {
+        let stream = proxies.connect(host, port)?;
+        let version = with_peer_receiver(version, host, port);
+        Self::open(stream, Transport::V1(Vec::new()), network, version)
+    }
+
+    // As connect_through with the v2 transport, falling back to v1 as connect_v2 does
+    // on a new connection through the proxy.
+    pub fn connect_v2_through(
+        proxies: &ProxyConfig,
+        host: &str,
+        port: u16,
+        network: Network,
+        version: VersionMessage,
+    ) -> Result<Self, Errors> {
+        let connect = || proxies.connect(host, port);
+        let (stream, transport) = Transport::initiate(connect()?, network, connect)?;
+        let version = with_peer_receiver(version, host, port);
+        Self::open(stream, transport, network, version)
+    }
+
+    // Connects with the v2 transport, encrypting the connection, falling back to v1
+    // on a new connection if the peer hangs up on our key as v1 peers do.
+    pub fn connect_v2(
+        address: impl ToSocketAddrs,
+        network: Network,
+        version: VersionMessage,
+    ) -> Result<Self, Errors> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs().map_err(io_error)?.collect();
+        let connect = || TcpStream::connect(&addresses[..]).map_err(io_error);
+        let (stream, transport) = Transport::initiate(connect()?, network, connect)?;
+        let receiver = NetworkAddress::new(stream.peer_addr().map_err(io_error)?, 0);
+        Self::open(stream, transport, network, version.with_receiver(receiver))
+    }
+
+    fn open(
+        mut stream: TcpStream,
+        mut transport: Transport,
+        network: Network,
+        version: VersionMessage,
+    ) -> Result<Self, Errors> {
+        let peer = handshake_over(&mut stream, &mut transport, network, &version)?;
+        Ok(SimpleNode {
+            stream,
+            transport,
+            network,
+            peer,
+        })
+    }
+
+    pub fn peer(&self) -> &PeerInfo {
+        &self.peer
+    }
+
+    // Whether the connection is encrypted with the v2 transport.
+    pub fn is_v2(&self) -> bool {
+        self.transport.is_v2()
+    }
+
+    pub fn send(&mut self, message: &impl Message) -> Result<(), Errors> {
+        self.send_envelope(&message.to_envelope(self.network))
+    }
+
+    pub fn send_envelope(&mut self, envelope: &NetworkEnvelope) -> Result<(), Errors> {
+        self.transport.write(&mut self.stream, envelope)
+    }
+
+    // What the peer asks for once connected is kept in its info.
+    pub fn read(&mut self) -> Result<NetworkEnvelope, Errors> {
+        let envelope = self
+            .transport
+            .read(&mut self.stream, self.network.magic())?;
+        self.peer.update(&envelope);
+        Ok(envelope)
+    }
+
+    // Reads until a message with one of commands arrives, answering pings on the
+    // way so the peer doesn't drop the connection. Other messages are discarded.
+    pub fn wait_for(&mut self, commands: &[&str]) -> Result<NetworkEnvelope, Errors> {
+        loop {
+            let envelope = self.read()?;
+            if commands.contains(&envelope.command.as_str()) {
+                return Ok(envelope);
+            }
+            if let Some(ping) = envelope.message::<Ping>() {
+                self.send(&Pong { nonce: ping?.nonce })?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod node_tests {
+    use super::*;
+    use crate::p2p::message::VerAck;
+    use crate::p2p::version::handshake;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn read(stream: &mut TcpStream) -> NetworkEnvelope {
+        NetworkEnvelope::parse(stream, Network::Testnet.magic()).unwrap()
+    }
+
+    fn send(stream: &mut TcpStream, message: &impl Message) {
+        stream
+            .write_all(&message.to_envelope(Network::Testnet).serialize())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_talk_to_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // A peer that completes the handshake, pings and then answers a ping.
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read(&mut stream).command, "version");
+            send(&mut stream, &VersionMessage::new(0, 100));
+            send(&mut stream, &VerAck);
+            for command in ["wtxidrelay", "sendaddrv2", "verack", "sendheaders"] {
+                assert_eq!(read(&mut stream).command, command);
+            }
+            send(&mut stream, &Ping { nonce: 7 });
+            // The node pings before it reads ours.
+            let mut pong = None;
+            while pong.is_none() {
+                let envelope = read(&mut stream);
+                if let Some(ping) = envelope.message::<Ping>() {
+                    send(
+                        &mut stream,
+                        &Pong {
+                            nonce: ping.unwrap().nonce,
+                        },
+                    );
+                }
+                pong = envelope.message::<Pong>();
+            }
+            assert_eq!(pong, Some(Ok(Pong { nonce: 7 })));
+        });
+
+        let mut node = SimpleNode::connect(address, Network::Testnet).unwrap();
+        assert_eq!(node.peer().start_height, 100);
+        node.send(&Ping { nonce: 9 }).unwrap();
+        let pong = node.wait_for(&["pong"]).unwrap();
+        assert_eq!(pong.message(), Some(Ok(Pong { nonce: 9 })));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_v2() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let version = VersionMessage::new(0, 7);
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut transport = Transport::accept(&mut stream, Network::Testnet).unwrap();
+            assert!(transport.is_v2());
+            handshake_over(&mut stream, &mut transport, Network::Testnet, &version).unwrap();
+            // A v1 peer, hanging up on what isn't an envelope.
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 24]).unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, Network::Testnet, &version).unwrap();
+        });
+
+        let version = VersionMessage::new(0, 0);
+        let node = SimpleNode::connect_v2(address, Network::Testnet, version.clone()).unwrap();
+        assert!(node.is_v2());
+        assert_eq!(node.peer().start_height, 7);
+        let node = SimpleNode::connect_v2(address, Network::Testnet, version).unwrap();
+        assert!(!node.is_v2());
+        assert_eq!(node.peer().start_height, 7);
+        peer.join().unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+// Transactions relayed before the parents they spend, as Bitcoin Core's
+// TxOrphanage keeps them: each held for the peer it came from until its parents are
+// accepted, it expires or is confirmed or conflicted by a block. Each peer holds at
+// most MAX_ORPHANS_PER_PEER and all of them MAX_ORPHAN_TRANSACTIONS, the oldest
+// going first past either, so a peer can't fill the pool for the others. Orphans too
+// heavy to be standard aren't kept at all.
+use super::manager::PeerId;
+use crate::block::Block;
+use crate::policy::MAX_STANDARD_TX_WEIGHT;
+use crate::transaction::{OutPoint, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// As in Bitcoin Core.
+pub const MAX_ORPHAN_TRANSACTIONS: usize = 100;
+pub const ORPHAN_TX_EXPIRE_TIME: Duration = Duration::from_secs(20 * 60);
+pub const MAX_ORPHANS_PER_PEER: usize = 25;
+
+#[derive(Clone, Debug)]
+struct Orphan {
+    tx: Transaction,
+    peer: PeerId,
+    received: Instant,
+}
+
+#[derive(Clone, Debug)]
+pub struct OrphanPool {
+    orphans: HashMap<[u8; 32], Orphan>,
+    // The orphans spending each outpoint.
+    by_prev: HashMap<OutPoint, HashSet<[u8; 32]>>,
+    max_orphans: usize,
+    max_per_peer: usize,
+    expiry: Duration,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new()
+    }
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            by_prev: HashMap::new(),
+            max_orphans: MAX_ORPHAN_TRANSACTIONS,
+            max_per_peer: MAX_ORPHANS_PER_PEER,
+            expiry: ORPHAN_TX_EXPIRE_TIME,
+        }
+    }
+
+    pub fn with_max_orphans(mut self, max: usize) -> Self {
+        self.max_orphans = max;
+        self
+    }
+
+    pub fn with_max_per_peer(mut self, max: usize) -> Self {
+        self.max_per_peer = max;
+        self
+    }
+
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, txid: &[u8; 32]) -> bool {
+        self.orphans.contains_key(txid)
+    }
+
+    pub fn contains_wtxid(&self, wtxid: &[u8; 32]) -> bool {
+        self.orphans
+            .values()
+            .any(|orphan| orphan.tx.wtxid() == *wtxid)
+    }
+
+    // Keeps tx, received from peer at now, evicting the oldest orphans past the
+    // limits. Returns whether it was kept.
+    pub fn add(&mut self, tx: Transaction, peer: PeerId, now: Instant) -> bool {
+        let txid = tx.txid();
+        if self.orphans.contains_key(&txid) || tx.weight() > MAX_STANDARD_TX_WEIGHT {
+            return false;
+        }
+        for input in &tx.inputs {
+            self.by_prev
+                .entry(input.previous_output)
+                .or_default()
+                .insert(txid);
+        }
+        self.orphans.insert(
+            txid,
+            Orphan {
+                tx,
+                peer,
+                received: now,
+            },
+        );
+        while self.peer_count(peer) > self.max_per_peer {
+            self.evict_oldest(Some(peer));
+        }
+        while self.orphans.len() > self.max_orphans {
+            self.evict_oldest(None);
+        }
+        self.orphans.contains_key(&txid)
+    }
+
+    fn peer_count(&self, peer: PeerId) -> usize {
+        self.orphans
+            .values()
+            .filter(|orphan| orphan.peer == peer)
+            .count()
+    }
+
+    // Of peer's, or of anyone's.
+    fn evict_oldest(&mut self, peer: Option<PeerId>) {
+        let oldest = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| peer.is_none_or(|peer| orphan.peer == peer))
+            .min_by_key(|(_, orphan)| orphan.received)
+            .map(|(txid, _)| *txid);
+        if let Some(txid) = oldest {
+            self.remove(&txid);
+        }
+    }
+
+    pub fn remove(&mut self, txid: &[u8; 32]) -> Option<(Transaction, PeerId)> {
+        let orphan = self.orphans.remove(txid)?;
+        for input in &orphan.tx.inputs {
+            if let Some(spenders) = self.by_prev.get_mut(&input.previous_output) {
+                spenders.remove(txid);
+                if spenders.is_empty() {
+                    self.by_prev.remove(&input.previous_output);
+                }
+            }
+        }
+        Some((orphan.tx, orphan.peer))
+    }
+
+    // The orphans spending an output of tx, and the peers they came from.
+    pub fn children(&self, tx: &Transaction) -> Vec<(Transaction, PeerId)> {
+        let txid = tx.txid();
+        let mut seen = HashSet::new();
+        (0..tx.outputs.len() as u32)
+            .filter_map(|vout| self.by_prev.get(&OutPoint::new(txid, vout)))
+            .flatten()
+            .filter(|child| seen.insert(**child))
+            .map(|child| {
+                let orphan = &self.orphans[child];
+                (orphan.tx.clone(), orphan.peer)
+            })
+            .collect()
+    }
+
+    // Drops the orphans of a peer gone. Returns how many.
+    pub fn remove_for_peer(&mut self, peer: PeerId) -> usize {
+        let txids: Vec<[u8; 32]> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.peer == peer)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in &txids {
+            self.remove(txid);
+        }
+        txids.len()
+    }
+
+    // Drops the orphans block confirms, and those spending what it spends.
+    pub fn remove_for_block(&mut self, block: &Block) -> usize {
+        let mut txids = HashSet::new();
+        for tx in &block.transactions {
+            txids.insert(tx.txid());
+            for input in &tx.inputs {
+                if let Some(spenders) = self.by_prev.get(&input.previous_output) {
+                    txids.extend(spenders);
+                }
+            }
+        }
+        txids
+            .iter()
+            .filter(|txid| self.remove(txid).is_some())
+            .count()
+    }
+
+    // Drops the orphans held longer than the expiry at now. Returns how many.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<[u8; 32]> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| now.duration_since(orphan.received) >= self.expiry)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in &expired {
+            self.remove(txid);
+        }
+        expired.len()
+    }
+}
+
+#[cfg(test)]
+mod orphans_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::transaction::{TxIn, TxOut};
+
+    fn spend(n: u8) -> Transaction {
+        Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new([n; 32], 0),
+                vec![0x51].into(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(1000, vec![0x51].into())],
+            0,
+        )
+    }
+
+    #[test]
+    fn test_limits_and_expiry() {
+        let now = Instant::now();
+        let mut pool = OrphanPool::new().with_max_orphans(3).with_max_per_peer(2);
+        let secs = |s| now + Duration::from_secs(s);
+        assert!(pool.add(spend(0), 1, secs(0)));
+        assert!(!pool.add(spend(0), 1, secs(0)));
+        assert!(pool.add(spend(1), 1, secs(1)));
+        // Past its limit, the peer's oldest goes.
+        assert!(pool.add(spend(2), 1, secs(2)));
+        assert!(!pool.contains(&spend(0).txid()));
+        // Past the pool's, the oldest of all.
+        assert!(pool.add(spend(3), 2, secs(3)));
+        assert!(pool.add(spend(4), 2, secs(4)));
+        assert_eq!(pool.len(), 3);
+        assert!(!pool.contains(&spend(1).txid()));
+
+        assert_eq!(pool.remove_for_peer(2), 2);
+        assert_eq!(pool.expire(secs(2) + ORPHAN_TX_EXPIRE_TIME), 1);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_children_and_blocks() {
+        let now = Instant::now();
+        let mut pool = OrphanPool::new();
+        let parent = spend(0);
+        let child = Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new(parent.txid(), 0),
+                vec![0x51].into(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(500, vec![0x51].into())],
+            0,
+        );
+        pool.add(child.clone(), 7, now);
+        pool.add(spend(1), 7, now);
+        assert_eq!(pool.children(&parent), vec![(child.clone(), 7)]);
+        assert_eq!(pool.remove(&child.txid()), Some((child, 7)));
+        assert!(pool.children(&parent).is_empty());
+
+        // A block spending the same coin conflicts the orphan out.
+        let mut conflict = spend(1);
+        conflict.outputs[0].value = 900;
+        let block = Block::new(Network::Regtest.genesis_header(), vec![conflict]);
+        assert_eq!(pool.remove_for_block(&block), 1);
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,330 @@
+// Outbound connections through a SOCKS5 proxy (RFC 1928), as Tor offers: the proxy
+// connects on our behalf and resolves names itself, so .onion peers are reachable
+// and our IP is never seen by peers.
+use super::version::random_nonce;
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+fn io_error(e: std::io::Error) -> Errors {
+    Errors::Io(e.to_string())
+}
+
+fn read_exact<const N: usize>(stream: &mut impl Read) -> Result<[u8; N], Errors> {
+    let mut buffer = [0u8; N];
+    stream.read_exact(&mut buffer).map_err(io_error)?;
+    Ok(buffer)
+}
+
+// Meaning of the reply codes of a connect request.
+fn reply_error(code: u8) -> Errors {
+    Errors::ProxyFailed(match code {
+        1 => "general failure",
+        2 => "connection not allowed",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    address: SocketAddr,
+    credentials: Option<(String, String)>,
+    randomize_credentials: bool,
+}
+
+impl Socks5Proxy {
+    pub fn new(address: SocketAddr) -> Self {
+        Socks5Proxy {
+            address,
+            credentials: None,
+            randomize_credentials: false,
+        }
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    // Authenticates every connection with fresh random credentials, from which Tor
+    // builds a separate circuit for each, as -proxyrandomize in Bitcoin Core.
+    pub fn with_randomized_credentials(mut self) -> Self {
+        self.randomize_credentials = true;
+        self
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // A connection to host, a name or an IP address, through the proxy. Names, .onion
+    // ones included, are resolved by the proxy.
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Errors> {
+        let mut stream = TcpStream::connect(self.address).map_err(io_error)?;
+        let credentials = if self.randomize_credentials {
+            let random = random_nonce().to_string();
+            Some((random.clone(), random))
+        } else {
+            self.credentials.clone()
+        };
+        self.authenticate(&mut stream, credentials)?;
+
+        let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(ATYP_IPV4);
+                request.extend(ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(ATYP_IPV6);
+                request.extend(ip.octets());
+            }
+            Err(_) => {
+                if host.is_empty() || host.len() > 255 {
+                    return Err(Errors::ProxyFailed("invalid host name"));
+                }
+                request.extend([ATYP_DOMAIN, host.len() as u8]);
+                request.extend(host.as_bytes());
+            }
+        }
+        request.extend(port.to_be_bytes());
+        stream.write_all(&request).map_err(io_error)?;
+
+        let [version, code, _, atyp] = read_exact::<4>(&mut stream)?;
+        if version != SOCKS_VERSION {
+            return Err(Errors::ProxyFailed("not a SOCKS5 proxy"));
+        }
+        if code != 0 {
+            return Err(reply_error(code));
+        }
+        // The address the proxy bound, of no use to us.
+        let bound_len = match atyp {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => read_exact::<1>(&mut stream)?[0] as usize,
+            _ => return Err(Errors::ProxyFailed("malformed reply")),
+        };
+        let mut bound = vec![0u8; bound_len + 2];
+        stream.read_exact(&mut bound).map_err(io_error)?;
+        Ok(stream)
+    }
+
+    fn authenticate(
+        &self,
+        stream: &mut TcpStream,
+        credentials: Option<(String, String)>,
+    ) -> Result<(), Errors> {
+        let method = if credentials.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTHENTICATION
+        };
+        stream
+            .write_all(&[SOCKS_VERSION, 1, method])
+            .map_err(io_error)?;
+        match read_exact::<2>(stream)? {
+            [SOCKS_VERSION, NO_ACCEPTABLE_METHOD] => {
+                Err(Errors::ProxyFailed("no acceptable authentication method"))
+            }
+            [SOCKS_VERSION, chosen] if chosen == method => {
+                let Some((username, password)) = credentials else {
+                    return Ok(());
+                };
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(Errors::ProxyFailed("credentials too long"));
+                }
+                // RFC 1929 negotiation, of its own version 1.
+                let mut request = vec![1, username.len() as u8];
+                request.extend(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend(password.as_bytes());
+                stream.write_all(&request).map_err(io_error)?;
+                match read_exact::<2>(stream)? {
+                    [1, 0] => Ok(()),
+                    _ => Err(Errors::ProxyFailed("authentication failed")),
+                }
+            }
+            _ => Err(Errors::ProxyFailed("not a SOCKS5 proxy")),
+        }
+    }
+}
+
+// Networks a peer address may be on, each with its own proxy if any.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProxyNetwork {
+    Ipv4,
+    Ipv6,
+    Onion,
+}
+
+impl ProxyNetwork {
+    // Network of host. Other names than .onion ones count as IPv4, whose proxy
+    // resolves them as -proxy does in Bitcoin Core.
+    pub fn of(host: &str) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => ProxyNetwork::Ipv6,
+            Ok(IpAddr::V4(_)) => ProxyNetwork::Ipv4,
+            Err(_) if host.ends_with(".onion") => ProxyNetwork::Onion,
+            Err(_) => ProxyNetwork::Ipv4,
+        }
+    }
+}
+
+// How to connect to peers of each network: directly, or through the proxy set for it.
+// Onion peers are only reachable through a proxy.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyConfig {
+    proxies: HashMap<ProxyNetwork, Socks5Proxy>,
+}
+
+impl ProxyConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Proxy for every network, as the -proxy option.
+    pub fn with_proxy(self, proxy: Socks5Proxy) -> Self {
+        self.with_network_proxy(ProxyNetwork::Ipv4, proxy.clone())
+            .with_network_proxy(ProxyNetwork::Ipv6, proxy.clone())
+            .with_network_proxy(ProxyNetwork::Onion, proxy)
+    }
+
+    pub fn with_network_proxy(mut self, network: ProxyNetwork, proxy: Socks5Proxy) -> Self {
+        self.proxies.insert(network, proxy);
+        self
+    }
+
+    pub fn proxy(&self, network: ProxyNetwork) -> Option<&Socks5Proxy> {
+        self.proxies.get(&network)
+    }
+
+    pub fn is_reachable(&self, host: &str) -> bool {
+        let network = ProxyNetwork::of(host);
+        network != ProxyNetwork::Onion || self.proxies.contains_key(&network)
+    }
+
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Errors> {
+        let network = ProxyNetwork::of(host);
+        match self.proxy(network) {
+            Some(proxy) => proxy.connect(host, port),
+            None if network == ProxyNetwork::Onion => {
+                Err(Errors::ProxyFailed("no proxy to reach onion peers"))
+            }
+            None => TcpStream::connect((host, port)).map_err(io_error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod proxy_tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const ONION: &str = "bitcoinzxo4jcfnfas6jjvt3mlh6lxhpdx5jgtrgmsq36xmzowfuoid.onion";
+
+    type Request = (Vec<u8>, Vec<u8>);
+
+    // A proxy accepting a single connection, which it answers with reply and then
+    // echoes. Returns the address requested and the credentials given.
+    fn proxy(reply: u8) -> (SocketAddr, thread::JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let [_, count] = read_exact::<2>(&mut stream).unwrap();
+            let mut methods = vec![0; count as usize];
+            stream.read_exact(&mut methods).unwrap();
+            let mut credentials = Vec::new();
+            if methods.contains(&USERNAME_PASSWORD) {
+                stream.write_all(&[5, USERNAME_PASSWORD]).unwrap();
+                let [_, len] = read_exact::<2>(&mut stream).unwrap();
+                credentials = vec![0; len as usize + 1];
+                stream.read_exact(&mut credentials).unwrap();
+                let mut password = vec![0; *credentials.last().unwrap() as usize];
+                stream.read_exact(&mut password).unwrap();
+                credentials.extend(password);
+                stream.write_all(&[1, 0]).unwrap();
+            } else {
+                stream.write_all(&[5, NO_AUTHENTICATION]).unwrap();
+            }
+            let [_, _, _, atyp] = read_exact::<4>(&mut stream).unwrap();
+            assert_eq!(atyp, ATYP_DOMAIN);
+            let [len] = read_exact::<1>(&mut stream).unwrap();
+            let mut requested = vec![0; len as usize + 2];
+            stream.read_exact(&mut requested).unwrap();
+            stream
+                .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+            let mut echo = [0; 4];
+            if reply == 0 {
+                stream.read_exact(&mut echo).unwrap();
+                stream.write_all(&echo).unwrap();
+            }
+            (requested, credentials)
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_connect_through_proxy() {
+        let (address, handle) = proxy(0);
+        let config =
+            ProxyConfig::new().with_network_proxy(ProxyNetwork::Onion, Socks5Proxy::new(address));
+        let mut stream = config.connect(ONION, 8333).unwrap();
+        stream.write_all(b"ping").unwrap();
+        assert_eq!(read_exact::<4>(&mut stream).unwrap(), *b"ping");
+        let (requested, credentials) = handle.join().unwrap();
+        assert_eq!(&requested[..ONION.len()], ONION.as_bytes());
+        assert_eq!(requested[ONION.len()..], 8333u16.to_be_bytes());
+        assert!(credentials.is_empty());
+
+        let (address, handle) = proxy(5);
+        let proxy = Socks5Proxy::new(address).with_credentials("user", "secret");
+        assert_eq!(
+            proxy.connect("example.com", 8333).unwrap_err(),
+            Errors::ProxyFailed("connection refused")
+        );
+        let (_, credentials) = handle.join().unwrap();
+        assert_eq!(credentials, b"user\x06secret");
+    }
+
+    #[test]
+    fn test_proxy_config() {
+        assert_eq!(ProxyNetwork::of(ONION), ProxyNetwork::Onion);
+        assert_eq!(ProxyNetwork::of("::1"), ProxyNetwork::Ipv6);
+        assert_eq!(ProxyNetwork::of("seed.bitcoin.sipa.be"), ProxyNetwork::Ipv4);
+
+        let config = ProxyConfig::new();
+        assert!(!config.is_reachable(ONION));
+        assert_eq!(
+            config.connect(ONION, 8333).unwrap_err(),
+            Errors::ProxyFailed("no proxy to reach onion peers")
+        );
+        // Without a proxy other peers are connected to directly.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(config.connect("127.0.0.1", port).is_ok());
+
+        let proxy = Socks5Proxy::new(listener.local_addr().unwrap());
+        let config = ProxyConfig::new().with_proxy(proxy.clone());
+        assert!(config.is_reachable(ONION));
+        assert_eq!(config.proxy(ProxyNetwork::Ipv6), Some(&proxy));
+    }
+}
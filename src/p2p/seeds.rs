@@ -0,0 +1,130 @@
+// Bootstrapping from DNS seeds: hosts resolving to nodes of the network, used to fill
+// an empty address manager. Seeds answer for x<services in hex>.<seed> with nodes
+// offering those services only.
+use super::addrman::{AddrMan, TimestampedAddress};
+use super::version::{random_nonce, NetworkAddress, NODE_NETWORK, NODE_WITNESS};
+use crate::network::Network;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Services asked of seeded nodes unless told otherwise.
+pub const DEFAULT_SEED_SERVICES: u64 = NODE_NETWORK | NODE_WITNESS;
+
+// Host to resolve for nodes of seed offering services.
+pub fn seed_host(seed: &str, services: u64) -> String {
+    if services == 0 {
+        seed.to_string()
+    } else {
+        format!("x{:x}.{}", services, seed)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DnsSeeder {
+    seeds: Vec<String>,
+    port: u16,
+    services: u64,
+}
+
+impl DnsSeeder {
+    pub fn new(network: Network) -> Self {
+        DnsSeeder {
+            seeds: network
+                .dns_seeds()
+                .iter()
+                .map(|seed| seed.to_string())
+                .collect(),
+            port: network.default_port(),
+            services: DEFAULT_SEED_SERVICES,
+        }
+    }
+
+    pub fn with_seeds(mut self, seeds: Vec<String>) -> Self {
+        self.seeds = seeds;
+        self
+    }
+
+    // Zero asks seeds for nodes regardless of their services.
+    pub fn with_services(mut self, services: u64) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    // Addresses seed resolves to. Lookup failures give none, the other seeds being
+    // there for that.
+    pub fn resolve(&self, seed: &str) -> Vec<SocketAddr> {
+        (seed_host(seed, self.services).as_str(), self.port)
+            .to_socket_addrs()
+            .map(|addresses| addresses.collect())
+            .unwrap_or_default()
+    }
+
+    // Adds the nodes of every seed to addrman when it knows of none. Returns how many
+    // were added.
+    pub fn bootstrap(&self, addrman: &mut AddrMan) -> usize {
+        if !addrman.is_empty() {
+            return 0;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as u32);
+        let mut added = 0;
+        for seed in &self.seeds {
+            let resolved = self.resolve(seed);
+            let Some(source) = resolved.first() else {
+                continue;
+            };
+            // As in Bitcoin Core, seeded nodes count as last seen three to seven days
+            // ago, so addresses peers tell us about are preferred.
+            let addresses: Vec<_> = resolved
+                .iter()
+                .map(|address| TimestampedAddress {
+                    timestamp: now
+                        - 3 * 24 * 60 * 60
+                        - (random_nonce() % (4 * 24 * 60 * 60)) as u32,
+                    address: NetworkAddress::new(*address, self.services),
+                })
+                .collect();
+            added += addrman.add(&addresses, *source);
+        }
+        added
+    }
+}
+
+#[cfg(test)]
+mod seeds_tests {
+    use super::*;
+
+    #[test]
+    fn test_seed_host() {
+        assert_eq!(
+            seed_host("seed.bitcoin.sipa.be", DEFAULT_SEED_SERVICES),
+            "x9.seed.bitcoin.sipa.be"
+        );
+        assert_eq!(seed_host("seed.bitcoin.sipa.be", 0), "seed.bitcoin.sipa.be");
+        assert!(Network::Regtest.dns_seeds().is_empty());
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let seeder = DnsSeeder::new(Network::Regtest)
+            .with_seeds(vec!["localhost".to_string(), "seed.invalid".to_string()])
+            .with_services(0);
+        let mut addrman = AddrMan::new(Network::Regtest);
+        assert!(seeder.bootstrap(&mut addrman) >= 1);
+        let address = SocketAddr::from(([127, 0, 0, 1], Network::Regtest.default_port()));
+        assert!(!addrman.get(&address).unwrap().is_terrible(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32
+        ));
+        // Only done while no addresses are known.
+        assert_eq!(seeder.bootstrap(&mut addrman), 0);
+    }
+}
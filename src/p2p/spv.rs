@@ -0,0 +1,538 @@
+// Simplified payment verification: a wallet client keeping only headers. Peers are
+// given a bloom filter of its scripts and send the filtered blocks, whose merkle
+// proofs tie the matching transactions to headers of the best chain. Balance and
+// history only count transactions in blocks still on the best chain, so reorgs
+// undo them until the new blocks are scanned. Running with a full node, the client
+// can instead rescan the node's stored blocks, as after importing scripts.
+use super::bloom::{BloomFilter, BLOOM_UPDATE_ALL};
+use super::inventory::{GetData, Inventory, InventoryType};
+use super::message::Message;
+use super::node::SimpleNode;
+use super::sync::sync_headers;
+use super::version::random_nonce;
+use crate::block::MerkleBlock;
+use crate::chain::{BlockStorage, ChainState, HeaderChain, HeaderEntry, UtxoBackend};
+use crate::network::Network;
+use crate::script::{Instruction, Script};
+use crate::transaction::{txid_to_hex, OutPoint, Transaction, TxOut};
+use crate::types::errors::Errors;
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, info, info_span};
+
+// Filtered blocks asked for in one getdata.
+const FILTERED_BLOCK_BATCH: usize = 500;
+// Rate of irrelevant transactions the filter lets through, hiding ours among them.
+const FILTER_FP_RATE: f64 = 0.0001;
+
+// A transaction affecting the wallet, with what it paid to and spent from it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub txid: [u8; 32],
+    pub height: u32,
+    pub received: u64,
+    pub sent: u64,
+}
+
+pub struct SpvClient {
+    headers: HeaderChain,
+    scripts: HashSet<Script>,
+    tweak: u32,
+    // Transactions paying to or spending from our scripts, with their block.
+    transactions: HashMap<[u8; 32], (Transaction, [u8; 32])>,
+    // Last block scanned for them.
+    scanned: [u8; 32],
+}
+
+impl SpvClient {
+    pub fn new(network: Network) -> Self {
+        let headers = HeaderChain::new(network);
+        let scanned = headers.tip().hash;
+        SpvClient {
+            headers,
+            scripts: HashSet::new(),
+            tweak: random_nonce() as u32,
+            transactions: HashMap::new(),
+            scanned,
+        }
+    }
+
+    // Scripts added after blocks were scanned are only looked for in later ones, or
+    // in those rescanned.
+    pub fn add_script(&mut self, script: Script) {
+        self.scripts.insert(script);
+    }
+
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    // Matches our scripts by their pushes, as BIP37 matches outputs, and our outputs
+    // so their spends match. Peers add the outputs matching from then on.
+    pub fn bloom_filter(&self) -> BloomFilter {
+        let mut elements: Vec<Vec<u8>> = self
+            .scripts
+            .iter()
+            .flat_map(|script| script.instructions())
+            .filter_map(|instruction| match instruction {
+                Ok(Instruction::PushBytes(data)) if !data.is_empty() => Some(data.to_vec()),
+                _ => None,
+            })
+            .collect();
+        elements.extend(
+            self.unspent()
+                .iter()
+                .map(|(outpoint, _, _)| outpoint.serialize()),
+        );
+        let mut filter = BloomFilter::for_elements(elements.len(), FILTER_FP_RATE, self.tweak)
+            .with_flags(BLOOM_UPDATE_ALL);
+        for element in &elements {
+            filter.insert(element);
+        }
+        filter
+    }
+
+    fn is_mine(&self, output: &TxOut) -> bool {
+        self.scripts.contains(&output.script_pubkey)
+    }
+
+    // Keeps the transactions of a filtered block that are proven in it and are ours,
+    // false positives of the filter being dropped. The block must be in our headers.
+    pub fn receive_merkle_block(
+        &mut self,
+        merkle_block: &MerkleBlock,
+        transactions: &[Transaction],
+    ) -> Result<(), Errors> {
+        let hash = merkle_block.header.hash();
+        if !self.headers.contains(&hash) {
+            return Err(Errors::InvalidMerkleBlock("unknown block"));
+        }
+        let proven = merkle_block.verify()?;
+        for tx in transactions {
+            let txid = tx.txid();
+            if !proven.contains(&txid) {
+                return Err(Errors::InvalidMerkleBlock("transaction not in block"));
+            }
+            self.scan_transaction(tx, hash);
+        }
+        Ok(())
+    }
+
+    // Keeps tx of block if it pays to or spends from us.
+    fn scan_transaction(&mut self, tx: &Transaction, block: [u8; 32]) {
+        let spends_ours = tx
+            .inputs
+            .iter()
+            .any(|input| self.transactions.contains_key(&input.previous_output.txid));
+        if spends_ours || tx.outputs.iter().any(|output| self.is_mine(output)) {
+            debug!(
+                target: "wallet",
+                txid = tx.txid_hex(), block = txid_to_hex(&block),
+                "Found a transaction of ours"
+            );
+            self.transactions.insert(tx.txid(), (tx.clone(), block));
+        }
+    }
+
+    // Replays the blocks chain has stored from from_height to its tip through our
+    // scripts. What was found in those blocks before is found again, so outputs and
+    // history are rebuilt from there with scripts added since. With block filters,
+    // only the blocks they match are read; spends of ours match as well, a filter
+    // holding the scripts spent. Headers we lack are taken from chain.
+    pub fn rescan<B: UtxoBackend, S: BlockStorage>(
+        &mut self,
+        chain: &ChainState<B, S>,
+        from_height: u32,
+    ) -> Result<(), Errors> {
+        let _span = info_span!(target: "wallet", "rescan", from_height).entered();
+        let active: Vec<&HeaderEntry> = (0..=chain.height())
+            .filter_map(|height| chain.headers().at_height(height))
+            .collect();
+        for entry in &active[1..] {
+            if !self.headers.contains(&entry.hash) {
+                self.headers
+                    .accept_header(entry.header)
+                    .map_err(|e| Errors::Rescan(e.to_string()))?;
+            }
+        }
+        let headers = &self.headers;
+        self.transactions.retain(|_, (_, block)| {
+            !headers.is_in_best_chain(block) || headers.get(block).unwrap().height < from_height
+        });
+        let scripts: Vec<&[u8]> = self
+            .scripts
+            .iter()
+            .map(|script| script.as_bytes())
+            .collect();
+        let mut matched = Vec::new();
+        for entry in active.iter().skip(from_height as usize) {
+            let filter = chain
+                .block_filters()
+                .and_then(|filters| filters.get_filter(&entry.hash));
+            if let Some(filter) = filter {
+                if !filter.match_any(&scripts)? {
+                    continue;
+                }
+            }
+            matched.push(entry.hash);
+        }
+        info!(
+            target: "wallet",
+            to_height = chain.height(), blocks = matched.len(),
+            "Rescanning blocks"
+        );
+        for hash in matched {
+            let block = chain
+                .get_block(&hash)?
+                .ok_or_else(|| Errors::Rescan(format!("block {} is pruned", txid_to_hex(&hash))))?;
+            for tx in &block.transactions {
+                self.scan_transaction(tx, hash);
+            }
+        }
+        let scanned = self.headers.get(&self.scanned).unwrap();
+        if self.headers.fork_point(scanned).height <= chain.height() {
+            self.scanned = chain.tip().hash;
+        }
+        Ok(())
+    }
+
+    // Our transactions in the best chain, by height.
+    fn confirmed(&self) -> Vec<(&Transaction, u32)> {
+        let mut confirmed: Vec<_> = self
+            .transactions
+            .values()
+            .filter(|(_, block)| self.headers.is_in_best_chain(block))
+            .map(|(tx, block)| (tx, self.headers.get(block).unwrap().height))
+            .collect();
+        confirmed.sort_by_key(|(_, height)| *height);
+        confirmed
+    }
+
+    // Outputs to our scripts not spent in the best chain, with their height.
+    pub fn unspent(&self) -> Vec<(OutPoint, TxOut, u32)> {
+        let confirmed = self.confirmed();
+        let spent: HashSet<OutPoint> = confirmed
+            .iter()
+            .flat_map(|(tx, _)| tx.inputs.iter().map(|input| input.previous_output))
+            .collect();
+        let mut unspent = Vec::new();
+        for (tx, height) in confirmed {
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint::new(tx.txid(), vout as u32);
+                if self.is_mine(output) && !spent.contains(&outpoint) {
+                    unspent.push((outpoint, output.clone(), height));
+                }
+            }
+        }
+        unspent
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.unspent()
+            .iter()
+            .map(|(_, output, _)| output.value)
+            .sum()
+    }
+
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        let confirmed = self.confirmed();
+        let outputs: HashMap<OutPoint, u64> = confirmed
+            .iter()
+            .flat_map(|(tx, _)| {
+                let txid = tx.txid();
+                tx.outputs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, output)| self.is_mine(output))
+                    .map(move |(vout, output)| (OutPoint::new(txid, vout as u32), output.value))
+            })
+            .collect();
+        confirmed
+            .iter()
+            .map(|(tx, height)| {
+                let txid = tx.txid();
+                HistoryEntry {
+                    txid,
+                    height: *height,
+                    received: (0..tx.outputs.len() as u32)
+                        .filter_map(|vout| outputs.get(&OutPoint::new(txid, vout)))
+                        .sum(),
+                    sent: tx
+                        .inputs
+                        .iter()
+                        .filter_map(|input| outputs.get(&input.previous_output))
+                        .sum(),
+                }
+            })
+            .collect()
+    }
+
+    // Loads our filter into the peer, syncs headers and scans the blocks after the
+    // last one scanned, or after where it left the best chain.
+    pub fn sync(&mut self, node: &mut SimpleNode) -> Result<(), Errors> {
+        node.send(&self.bloom_filter().filterload())?;
+        let received = sync_headers(node, &mut self.headers)?;
+        let scanned = self.headers.get(&self.scanned).unwrap();
+        let start = self.headers.fork_point(scanned).height + 1;
+        info!(
+            target: "wallet",
+            headers = received, from_height = start, to_height = self.headers.height(),
+            "Scanning filtered blocks"
+        );
+        let hashes: Vec<[u8; 32]> = (start..=self.headers.height())
+            .map(|height| self.headers.at_height(height).unwrap().hash)
+            .collect();
+        for batch in hashes.chunks(FILTERED_BLOCK_BATCH) {
+            let getdata = GetData {
+                inventory: batch
+                    .iter()
+                    .map(|hash| Inventory::new(InventoryType::FilteredBlock, *hash))
+                    .collect(),
+            };
+            node.send(&getdata)?;
+            self.receive_filtered_blocks(node, batch.len())?;
+            self.scanned = *batch.last().unwrap();
+        }
+        Ok(())
+    }
+
+    // Each merkleblock is followed by the transactions it matched.
+    fn receive_filtered_blocks(
+        &mut self,
+        node: &mut SimpleNode,
+        count: usize,
+    ) -> Result<(), Errors> {
+        let mut current: Option<(MerkleBlock, Vec<[u8; 32]>, Vec<Transaction>)> = None;
+        let mut received = 0;
+        loop {
+            let waiting = current
+                .as_ref()
+                .is_some_and(|(_, matched, txs)| txs.len() < matched.len());
+            if !waiting {
+                if let Some((merkle_block, _, txs)) = current.take() {
+                    self.receive_merkle_block(&merkle_block, &txs)?;
+                }
+                if received == count {
+                    return Ok(());
+                }
+            }
+            let envelope = node.wait_for(&[MerkleBlock::COMMAND, Transaction::COMMAND])?;
+            if let Some(merkle_block) = envelope.message::<MerkleBlock>() {
+                // A peer may leave out matched transactions it already sent.
+                if let Some((previous, _, txs)) = current.take() {
+                    self.receive_merkle_block(&previous, &txs)?;
+                }
+                let merkle_block = merkle_block?;
+                let matched = merkle_block.verify()?;
+                current = Some((merkle_block, matched, Vec::new()));
+                received += 1;
+            } else if let Some(tx) = envelope.message::<Transaction>() {
+                let tx = tx?;
+                if let Some((_, matched, txs)) = current.as_mut() {
+                    if matched.contains(&tx.txid()) {
+                        txs.push(tx);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod spv_tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::chain::{MemoryBackend, MemoryBlockStore};
+    use crate::p2p::bloom::FilterLoad;
+    use crate::p2p::envelope::NetworkEnvelope;
+    use crate::p2p::message::{GetHeaders, Headers, MAX_HEADERS_RESULTS};
+    use crate::p2p::version::{handshake, VersionMessage};
+    use crate::test_util::{coinbase, mine};
+    use crate::transaction::TxIn;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn script(byte: u8) -> Script {
+        let mut script = Script::new();
+        script.push_slice(&[byte; 20]);
+        script
+    }
+
+    fn coinbase_to(height: u32, to: Script) -> Transaction {
+        let mut coinbase = coinbase(height, 0, 50_0000_0000);
+        coinbase.outputs[0].script_pubkey = to;
+        coinbase
+    }
+
+    // Five blocks: we are paid in the second, spend part of it in the fourth.
+    fn blocks() -> Vec<Block> {
+        let mut blocks = vec![Network::Regtest.genesis_block()];
+        for height in 1..=5 {
+            let mut transactions =
+                vec![coinbase_to(height, script(if height == 2 { 1 } else { 9 }))];
+            if height == 4 {
+                let paid = blocks[2].transactions[0].txid();
+                transactions.push(Transaction::new(
+                    2,
+                    vec![TxIn::new(OutPoint::new(paid, 0), Script::new(), 0)],
+                    vec![
+                        TxOut::new(30_0000_0000, script(9)),
+                        TxOut::new(19_0000_0000, script(1)),
+                    ],
+                    0,
+                ));
+            }
+            let block = mine(&blocks.last().unwrap().header, transactions);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    // Serves headers and filtered blocks as a full node would.
+    fn serve(listener: TcpListener, blocks: Vec<Block>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake(&mut stream, Network::Regtest, &VersionMessage::new(0, 5)).unwrap();
+        let mut chain = HeaderChain::new(Network::Regtest);
+        for block in &blocks[1..] {
+            chain.accept_header(block.header).unwrap();
+        }
+        let mut filter = None;
+        while let Ok(envelope) = NetworkEnvelope::parse(&mut stream, Network::Regtest.magic()) {
+            let mut replies = Vec::new();
+            if let Some(filterload) = envelope.message::<FilterLoad>() {
+                filter = Some(filterload.unwrap().filter);
+            } else if let Some(getheaders) = envelope.message::<GetHeaders>() {
+                let getheaders = getheaders.unwrap();
+                let headers =
+                    chain.find_headers(&getheaders.locator, &getheaders.stop, MAX_HEADERS_RESULTS);
+                replies.push(Headers { headers }.to_envelope(Network::Regtest));
+            } else if let Some(getdata) = envelope.message::<GetData>() {
+                let filter: BloomFilter = filter.clone().unwrap();
+                for item in getdata.unwrap().inventory {
+                    let block = blocks
+                        .iter()
+                        .find(|block| block.hash() == item.hash)
+                        .unwrap();
+                    // Matches outputs by their pushes, and spends by outpoint.
+                    let matches = |tx: &Transaction| {
+                        tx.outputs.iter().any(|output| {
+                            output.script_pubkey.instructions().any(|instruction| {
+                                matches!(instruction, Ok(Instruction::PushBytes(data)) if filter.contains(data))
+                            })
+                        }) || tx
+                            .inputs
+                            .iter()
+                            .any(|input| filter.contains(&input.previous_output.serialize()))
+                    };
+                    let merkle_block = MerkleBlock::from_block(block, matches);
+                    replies.push(merkle_block.to_envelope(Network::Regtest));
+                    for tx in block.iter().filter(|tx| matches(tx)) {
+                        replies.push(tx.to_envelope(Network::Regtest));
+                    }
+                }
+            }
+            for reply in replies {
+                stream.write_all(&reply.serialize()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_balance_and_history() {
+        let blocks = blocks();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let served = blocks.clone();
+        let peer = thread::spawn(move || serve(listener, served));
+
+        let mut client = SpvClient::new(Network::Regtest);
+        client.add_script(script(1));
+        let mut node = SimpleNode::connect(address, Network::Regtest).unwrap();
+        client.sync(&mut node).unwrap();
+        assert_eq!(client.headers().height(), 5);
+        assert_eq!(client.balance(), 19_0000_0000);
+        let spend = blocks[4].transactions[1].txid();
+        assert_eq!(
+            client.history(),
+            vec![
+                HistoryEntry {
+                    txid: blocks[2].transactions[0].txid(),
+                    height: 2,
+                    received: 50_0000_0000,
+                    sent: 0,
+                },
+                HistoryEntry {
+                    txid: spend,
+                    height: 4,
+                    received: 19_0000_0000,
+                    sent: 50_0000_0000,
+                },
+            ]
+        );
+        assert_eq!(client.unspent()[0].0, OutPoint::new(spend, 1));
+        // Nothing new to scan.
+        client.sync(&mut node).unwrap();
+        assert_eq!(client.history().len(), 2);
+        drop(node);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_rescan_stored_blocks() {
+        let mut chain = ChainState::with_storage(
+            Network::Regtest,
+            MemoryBackend::default(),
+            MemoryBlockStore::default(),
+        )
+        .unwrap()
+        .with_block_filters();
+        let mut blocks = vec![Network::Regtest.genesis_block()];
+        for height in 1..=4 {
+            let to = script(if height % 2 == 0 { 1 } else { 9 });
+            let block = mine(
+                &blocks.last().unwrap().header,
+                vec![coinbase_to(height, to)],
+            );
+            chain.accept_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+
+        let mut client = SpvClient::new(Network::Regtest);
+        client.rescan(&chain, 0).unwrap();
+        assert_eq!(client.headers().height(), 4);
+        assert!(client.history().is_empty());
+        // Imported scripts are only found by rescanning far enough back.
+        client.add_script(script(1));
+        client.rescan(&chain, 3).unwrap();
+        assert_eq!(client.balance(), 50_0000_0000);
+        client.rescan(&chain, 0).unwrap();
+        assert_eq!(client.balance(), 100_0000_0000);
+        let heights: Vec<u32> = client.history().iter().map(|entry| entry.height).collect();
+        assert_eq!(heights, [2, 4]);
+        assert_eq!(client.scanned, blocks[4].hash());
+    }
+
+    #[test]
+    fn test_unproven_transactions_are_refused() {
+        let blocks = blocks();
+        let mut client = SpvClient::new(Network::Regtest);
+        client.add_script(script(1));
+        let merkle_block = MerkleBlock::from_block(&blocks[2], |_| true);
+        assert_eq!(
+            client.receive_merkle_block(&merkle_block, &blocks[2].transactions),
+            Err(Errors::InvalidMerkleBlock("unknown block"))
+        );
+        for block in &blocks[1..] {
+            client.headers.accept_header(block.header).unwrap();
+        }
+        let other = MerkleBlock::from_block(&blocks[3], |_| true);
+        assert_eq!(
+            client.receive_merkle_block(&other, &blocks[2].transactions),
+            Err(Errors::InvalidMerkleBlock("transaction not in block"))
+        );
+        client
+            .receive_merkle_block(&merkle_block, &blocks[2].transactions)
+            .unwrap();
+        assert_eq!(client.balance(), 50_0000_0000);
+    }
+}
@@ -0,0 +1,298 @@
+// Headers first synchronization: headers are asked for with a locator of our best
+// chain, each batch is checked to connect and then added to the header chain, which
+// validates proof of work and retargeting, and a full batch means the peer has more.
+use super::manager::{PeerEvent, PeerManager};
+use super::message::{GetHeaders, Headers, Message, MAX_HEADERS_RESULTS};
+use super::node::SimpleNode;
+use crate::chain::HeaderChain;
+use crate::types::errors::{Errors, ValidationError};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+// Peers taking longer to answer a getheaders are given up on.
+pub const HEADERS_RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// First request of a sync, from the tip of chain.
+pub fn get_headers(chain: &HeaderChain) -> GetHeaders {
+    GetHeaders::new(chain.locator(chain.tip()), [0; 32])
+}
+
+// Adds a batch received in answer to getheaders, returning the next request while
+// the peer may have more.
+pub fn process_headers(
+    chain: &mut HeaderChain,
+    headers: &Headers,
+) -> Result<Option<GetHeaders>, ValidationError> {
+    let batch = &headers.headers;
+    if batch
+        .windows(2)
+        .any(|pair| pair[1].prev_block != pair[0].hash())
+    {
+        return Err(ValidationError::NonContinuousHeaders);
+    }
+    let mut last = None;
+    for header in batch {
+        last = Some(chain.accept_header(*header)?);
+    }
+    // Continue from the end of the batch, which may not be our best chain yet.
+    Ok(last
+        .filter(|_| batch.len() == MAX_HEADERS_RESULTS)
+        .map(|hash| GetHeaders::new(chain.locator(chain.get(&hash).unwrap()), [0; 32])))
+}
+
+// What unrequested headers, announcing new blocks (BIP130), call for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeadersAnnouncement {
+    // Added to chain: those of them now in its best chain, whose blocks to fetch.
+    NewBlocks(Vec<[u8; 32]>),
+    // The first doesn't connect, chain missing the blocks before: the getheaders
+    // asking for them.
+    Unconnected(GetHeaders),
+}
+
+pub fn process_announcement(
+    chain: &mut HeaderChain,
+    headers: &Headers,
+) -> Result<HeadersAnnouncement, ValidationError> {
+    if let Some(first) = headers.headers.first() {
+        if !chain.contains(&first.prev_block) {
+            return Ok(HeadersAnnouncement::Unconnected(get_headers(chain)));
+        }
+    }
+    let new: Vec<[u8; 32]> = headers
+        .headers
+        .iter()
+        .map(|header| header.hash())
+        .filter(|hash| !chain.contains(hash))
+        .collect();
+    process_headers(chain, headers)?;
+    Ok(HeadersAnnouncement::NewBlocks(
+        new.into_iter()
+            .filter(|hash| chain.is_in_best_chain(hash))
+            .collect(),
+    ))
+}
+
+// Syncs chain with the peer's until it has no more headers. Returns how many were
+// added.
+pub fn sync_headers(node: &mut SimpleNode, chain: &mut HeaderChain) -> Result<usize, Errors> {
+    let start = chain.len();
+    let mut request = Some(get_headers(chain));
+    while let Some(getheaders) = request {
+        node.send(&getheaders)?;
+        let envelope = node.wait_for(&[Headers::COMMAND])?;
+        let headers = envelope.message::<Headers>().unwrap()?;
+        request = process_headers(chain, &headers).map_err(Errors::InvalidPeerHeaders)?;
+    }
+    Ok(chain.len() - start)
+}
+
+// Like sync_headers, with the connected peer claiming the highest start height.
+pub async fn sync_headers_from_best_peer(
+    manager: &PeerManager,
+    chain: &mut HeaderChain,
+) -> Result<usize, Errors> {
+    let (peer, _, _) = manager
+        .peers()
+        .into_iter()
+        .max_by_key(|(id, _, info)| (info.start_height, std::cmp::Reverse(*id)))
+        .ok_or(Errors::NoPeers)?;
+    let mut events = manager.subscribe();
+    let start = chain.len();
+    let mut request = Some(get_headers(chain));
+    while let Some(getheaders) = request {
+        manager.send(peer, &getheaders)?;
+        let headers = tokio::time::timeout(HEADERS_RESPONSE_TIMEOUT, async {
+            loop {
+                match events.recv().await {
+                    Ok(PeerEvent::Message(id, envelope)) if id == peer => {
+                        if let Some(headers) = envelope.message::<Headers>() {
+                            return headers;
+                        }
+                    }
+                    Ok(PeerEvent::Disconnected(id)) if id == peer => {
+                        return Err(Errors::Io("peer disconnected".to_string()));
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(Errors::Io("peer manager is gone".to_string()));
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| Errors::PeerTimeout)??;
+        request = process_headers(chain, &headers).map_err(Errors::InvalidPeerHeaders)?;
+    }
+    Ok(chain.len() - start)
+}
+
+#[cfg(test)]
+mod sync_tests {
+    use super::*;
+    use crate::block::BlockHeader;
+    use crate::network::Network;
+    use crate::p2p::envelope::NetworkEnvelope;
+    use crate::p2p::inventory::InventoryRelay;
+    use crate::p2p::manager::{async_handshake, read_envelope};
+    use crate::p2p::version::{handshake, PeerInfo, VersionMessage};
+    use crate::test_util::solve;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio::io::AsyncWriteExt;
+
+    // A regtest chain of count headers.
+    fn chain(count: u32) -> HeaderChain {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        for _ in 0..count {
+            let tip = chain.tip();
+            let mut header = BlockHeader {
+                version: 4,
+                prev_block: tip.hash,
+                merkle_root: [1; 32],
+                timestamp: tip.header.timestamp + 600,
+                bits: Network::Regtest.pow_limit_bits(),
+                nonce: 0,
+            };
+            solve(&mut header);
+            chain.accept_header(header).unwrap();
+        }
+        chain
+    }
+
+    // Answers a getheaders envelope as a node with chain would.
+    fn answer(chain: &HeaderChain, envelope: &NetworkEnvelope) -> Option<NetworkEnvelope> {
+        let getheaders = envelope.message::<GetHeaders>()?.unwrap();
+        let headers =
+            chain.find_headers(&getheaders.locator, &getheaders.stop, MAX_HEADERS_RESULTS);
+        Some(Headers { headers }.to_envelope(Network::Regtest))
+    }
+
+    #[test]
+    fn test_process_headers() {
+        let theirs = chain(2100);
+        let mut ours = HeaderChain::new(Network::Regtest);
+        let request = get_headers(&ours);
+        let first = theirs.find_headers(&request.locator, &request.stop, MAX_HEADERS_RESULTS);
+        let next = process_headers(&mut ours, &Headers { headers: first })
+            .unwrap()
+            .unwrap();
+        assert_eq!(ours.height(), 2000);
+        assert_eq!(next.locator[0], ours.tip().hash);
+        let rest = theirs.find_headers(&next.locator, &next.stop, MAX_HEADERS_RESULTS);
+        assert_eq!(rest.len(), 100);
+        assert_eq!(
+            process_headers(&mut ours, &Headers { headers: rest }),
+            Ok(None)
+        );
+        assert_eq!(ours.tip().hash, theirs.tip().hash);
+
+        let mut gap = theirs.find_headers(&[], &[0; 32], 3);
+        gap.remove(1);
+        assert_eq!(
+            process_headers(
+                &mut HeaderChain::new(Network::Regtest),
+                &Headers { headers: gap }
+            ),
+            Err(ValidationError::NonContinuousHeaders)
+        );
+    }
+
+    #[test]
+    fn test_process_announcement() {
+        let theirs = chain(7);
+        let mut ours = chain(5);
+        let peer = PeerInfo {
+            send_headers: true,
+            ..PeerInfo::default()
+        };
+
+        // Announced alone, the last block doesn't connect to ours.
+        let mut relay = InventoryRelay::new(Network::Regtest);
+        let last = theirs.at_height(7).unwrap().header;
+        let mut block = Network::Regtest.genesis_block();
+        block.header = last;
+        let envelope = relay.announce_block(block, &peer);
+        let headers = envelope.message::<Headers>().unwrap().unwrap();
+        assert_eq!(
+            process_announcement(&mut ours, &headers),
+            Ok(HeadersAnnouncement::Unconnected(get_headers(&ours)))
+        );
+
+        let headers = Headers {
+            headers: theirs.find_headers(&[ours.tip().hash], &[0; 32], 2),
+        };
+        let expected: Vec<[u8; 32]> = headers.headers.iter().map(|h| h.hash()).collect();
+        assert_eq!(
+            process_announcement(&mut ours, &headers),
+            Ok(HeadersAnnouncement::NewBlocks(expected))
+        );
+        assert_eq!(ours.height(), 7);
+        assert_eq!(
+            process_announcement(&mut ours, &headers),
+            Ok(HeadersAnnouncement::NewBlocks(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn test_sync_with_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let theirs = chain(2001);
+        let tip = theirs.tip().hash;
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, Network::Regtest, &VersionMessage::new(0, 2001)).unwrap();
+            while let Ok(envelope) = NetworkEnvelope::parse(&mut stream, Network::Regtest.magic()) {
+                if let Some(reply) = answer(&theirs, &envelope) {
+                    stream.write_all(&reply.serialize()).unwrap();
+                }
+            }
+        });
+        let mut node = SimpleNode::connect(address, Network::Regtest).unwrap();
+        let mut ours = HeaderChain::new(Network::Regtest);
+        assert_eq!(sync_headers(&mut node, &mut ours), Ok(2001));
+        assert_eq!(ours.tip().hash, tip);
+        assert_eq!(sync_headers(&mut node, &mut ours), Ok(0));
+        drop(node);
+        peer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_best_peer() {
+        let manager = PeerManager::new(Network::Regtest);
+        let mut ours = HeaderChain::new(Network::Regtest);
+        assert_eq!(
+            sync_headers_from_best_peer(&manager, &mut ours).await,
+            Err(Errors::NoPeers)
+        );
+
+        // Two peers, the one claiming more blocks being synced from.
+        let mut tasks = Vec::new();
+        for count in [10, 2001] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let theirs = chain(count);
+            tasks.push(tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let version = VersionMessage::new(0, theirs.height() as i32);
+                async_handshake(&mut stream, Network::Regtest, version)
+                    .await
+                    .unwrap();
+                while let Ok(envelope) = read_envelope(&mut stream, Network::Regtest.magic()).await
+                {
+                    if let Some(reply) = answer(&theirs, &envelope) {
+                        stream.write_all(&reply.serialize()).await.unwrap();
+                    }
+                }
+            }));
+            manager.connect(address).await.unwrap();
+        }
+        assert_eq!(
+            sync_headers_from_best_peer(&manager, &mut ours).await,
+            Ok(2001)
+        );
+        assert_eq!(ours.height(), 2001);
+    }
+}
@@ -0,0 +1,239 @@
+// Accounting of the bytes exchanged with peers, and the limits PeerManager puts on
+// them: a rate per peer each way, made a token bucket, and Bitcoin Core's upload
+// target, a budget of bytes sent per day past which blocks older than a week aren't
+// served any more, keeping what is left for peers following the tip.
+use super::envelope::NetworkEnvelope;
+use super::message::Message;
+use crate::block::{Block, BlockHeader, HeaderAndShortIds, MerkleBlock};
+use crate::validation::MAX_BLOCK_WEIGHT;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// As in Bitcoin Core.
+pub const UPLOAD_TARGET_TIMEFRAME: Duration = Duration::from_secs(24 * 60 * 60);
+pub const HISTORICAL_BLOCK_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Envelope header bytes counted with each payload.
+const ENVELOPE_HEADER_SIZE: u64 = 24;
+
+// Bytes envelope takes on the wire.
+pub fn wire_size(envelope: &NetworkEnvelope) -> u64 {
+    ENVELOPE_HEADER_SIZE + envelope.payload.len() as u64
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent_per_command: BTreeMap<String, u64>,
+    pub bytes_received_per_command: BTreeMap<String, u64>,
+}
+
+impl TrafficStats {
+    // Counts envelope, envelope header included. The bytes counted.
+    pub fn record_sent(&mut self, envelope: &NetworkEnvelope) -> u64 {
+        let bytes = wire_size(envelope);
+        self.bytes_sent += bytes;
+        self.messages_sent += 1;
+        *self
+            .bytes_sent_per_command
+            .entry(envelope.command.clone())
+            .or_default() += bytes;
+        bytes
+    }
+
+    pub fn record_received(&mut self, envelope: &NetworkEnvelope) -> u64 {
+        let bytes = wire_size(envelope);
+        self.bytes_received += bytes;
+        self.messages_received += 1;
+        *self
+            .bytes_received_per_command
+            .entry(envelope.command.clone())
+            .or_default() += bytes;
+        bytes
+    }
+}
+
+// A token bucket of rate bytes a second, holding at most a second of them. A message
+// larger than what is left still goes, putting the bucket in debt.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, now: Instant) -> Self {
+        let rate = rate.max(1);
+        RateLimiter {
+            rate,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    // Takes bytes from the bucket, returning how long to wait before using them.
+    pub fn delay(&mut self, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now.max(self.last);
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+// Where a cycle of the upload target stands, as Bitcoin Core's getnettotals has it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UploadTargetStatus {
+    pub target: u64,
+    pub timeframe: Duration,
+    pub target_reached: bool,
+    pub serve_historical_blocks: bool,
+    pub bytes_left_in_cycle: u64,
+    pub time_left_in_cycle: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct UploadTarget {
+    target: u64,
+    timeframe: Duration,
+    cycle_start: Instant,
+    sent_in_cycle: u64,
+}
+
+impl UploadTarget {
+    pub fn new(target: u64, now: Instant) -> Self {
+        UploadTarget {
+            target,
+            timeframe: UPLOAD_TARGET_TIMEFRAME,
+            cycle_start: now,
+            sent_in_cycle: 0,
+        }
+    }
+
+    pub fn with_timeframe(mut self, timeframe: Duration) -> Self {
+        self.timeframe = timeframe.max(Duration::from_secs(1));
+        self
+    }
+
+    // Starts a new cycle once the current one is over.
+    fn roll(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.cycle_start) >= self.timeframe {
+            self.cycle_start = now;
+            self.sent_in_cycle = 0;
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64, now: Instant) {
+        self.roll(now);
+        self.sent_in_cycle += bytes;
+    }
+
+    // Whether the target is reached or, for historical blocks, whether what is left
+    // would no longer cover a full block every ten minutes until the cycle ends.
+    pub fn reached(&mut self, historical: bool, now: Instant) -> bool {
+        self.roll(now);
+        let buffer = if historical {
+            self.timeframe.as_secs() / 600 * MAX_BLOCK_WEIGHT as u64
+        } else {
+            0
+        };
+        self.sent_in_cycle + buffer >= self.target
+    }
+
+    pub fn status(&mut self, now: Instant) -> UploadTargetStatus {
+        self.roll(now);
+        UploadTargetStatus {
+            target: self.target,
+            timeframe: self.timeframe,
+            target_reached: self.reached(false, now),
+            serve_historical_blocks: !self.reached(true, now),
+            bytes_left_in_cycle: self.target.saturating_sub(self.sent_in_cycle),
+            time_left_in_cycle: (self.cycle_start + self.timeframe).saturating_duration_since(now),
+        }
+    }
+}
+
+// Whether envelope serves a block older than HISTORICAL_BLOCK_AGE, in full or
+// filtered, the kind of message the upload target stops.
+pub fn serves_historical_block(envelope: &NetworkEnvelope) -> bool {
+    let serves_block = [
+        Block::COMMAND,
+        MerkleBlock::COMMAND,
+        HeaderAndShortIds::COMMAND,
+    ]
+    .contains(&envelope.command.as_str());
+    if !serves_block {
+        return false;
+    }
+    let Ok(header) = BlockHeader::parse(&mut envelope.payload.as_slice()) else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (header.timestamp as u64) + HISTORICAL_BLOCK_AGE.as_secs() < now.as_secs()
+}
+
+#[cfg(test)]
+mod traffic_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::p2p::message::Ping;
+
+    #[test]
+    fn test_stats_and_rate_limiter() {
+        let mut stats = TrafficStats::default();
+        let ping = Ping { nonce: 1 }.to_envelope(Network::Regtest);
+        assert_eq!(stats.record_sent(&ping), 32);
+        stats.record_sent(&ping);
+        stats.record_received(&ping);
+        assert_eq!(stats.bytes_sent, 64);
+        assert_eq!(stats.messages_sent, 2);
+        assert_eq!(stats.bytes_sent_per_command["ping"], 64);
+        assert_eq!(stats.bytes_received_per_command["ping"], 32);
+
+        // A second of bytes goes at once, more waits for the bucket to refill.
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(1000, start);
+        assert_eq!(limiter.delay(600, start), Duration::ZERO);
+        assert_eq!(limiter.delay(900, start), Duration::from_millis(500));
+        let later = start + Duration::from_secs(2);
+        assert_eq!(limiter.delay(500, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_upload_target() {
+        let start = Instant::now();
+        let timeframe = Duration::from_secs(600);
+        let mut target =
+            UploadTarget::new(MAX_BLOCK_WEIGHT as u64 + 1000, start).with_timeframe(timeframe);
+        assert!(!target.reached(true, start));
+        target.record(1000, start);
+        // What is left no longer covers a block, historical blocks stop first.
+        assert!(target.reached(true, start));
+        assert!(!target.reached(false, start));
+        let status = target.status(start + Duration::from_secs(60));
+        assert!(!status.serve_historical_blocks && !status.target_reached);
+        assert_eq!(status.bytes_left_in_cycle, MAX_BLOCK_WEIGHT as u64);
+        assert_eq!(status.time_left_in_cycle, Duration::from_secs(540));
+
+        target.record(MAX_BLOCK_WEIGHT as u64, start);
+        assert!(target.reached(false, start));
+        // A new cycle starts with the timeframe.
+        assert!(!target.reached(true, start + timeframe));
+
+        let genesis = Network::Regtest.genesis_block();
+        assert!(serves_historical_block(
+            &genesis.to_envelope(Network::Regtest)
+        ));
+        let ping = Ping { nonce: 1 }.to_envelope(Network::Regtest);
+        assert!(!serves_historical_block(&ping));
+    }
+}
@@ -0,0 +1,571 @@
+// The v2 transport of BIP324: both sides send an ElligatorSwift encoded key, and
+// everything after the resulting ECDH is encrypted, so a passive observer sees only
+// random bytes. Random garbage after the keys and decoy packets hide the traffic
+// shape too. Nodes not speaking it are still reached with the v1 transport, the
+// plaintext envelopes, by reconnecting when they hang up on our key.
+use super::cipher::{FSChaCha20, FSChaCha20Poly1305, TAG_SIZE};
+use super::envelope::{NetworkEnvelope, COMMAND_SIZE, MAX_PAYLOAD_SIZE};
+use super::version::random_nonce;
+use crate::ecc::keys::hmac_sha256;
+use crate::ecc::{ellswift, PrivateKey};
+use crate::helper::{random_bytes, read_array, read_bytes};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::io::{Read, Write};
+
+pub const ELLSWIFT_KEY_SIZE: usize = 64;
+pub const GARBAGE_TERMINATOR_SIZE: usize = 16;
+// Most garbage sent after a key.
+pub const MAX_GARBAGE_SIZE: usize = 4095;
+pub const LENGTH_SIZE: usize = 3;
+// Set in the header byte of packets to be ignored.
+const DECOY_FLAG: u8 = 128;
+// Largest contents accepted: a long command id and the largest payload.
+pub const MAX_CONTENTS_SIZE: usize = 1 + COMMAND_SIZE + MAX_PAYLOAD_SIZE as usize;
+
+// Commands with a one byte id, the index plus one. Others are sent as a zero
+// followed by the command name.
+const SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+fn io_error(e: std::io::Error) -> Errors {
+    Errors::Io(e.to_string())
+}
+
+// Keys derived from the ECDH secret, by HKDF-SHA256 salted with the network magic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionKeys {
+    // Identifies the connection, to compare out of band against a man in the middle.
+    pub session_id: [u8; 32],
+    pub initiator_length: [u8; 32],
+    pub initiator_contents: [u8; 32],
+    pub responder_length: [u8; 32],
+    pub responder_contents: [u8; 32],
+    pub initiator_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+    pub responder_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+}
+
+impl SessionKeys {
+    pub fn new(shared_secret: &[u8; 32], magic: [u8; 4]) -> Self {
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend(magic);
+        let prk = hmac_sha256(&salt, &[shared_secret]);
+        // All outputs are a single block of the expansion.
+        let expand = |info: &str| hmac_sha256(&prk, &[info.as_bytes(), &[1]]);
+        let terminators = expand("garbage_terminators");
+        SessionKeys {
+            session_id: expand("session_id"),
+            initiator_length: expand("initiator_L"),
+            initiator_contents: expand("initiator_P"),
+            responder_length: expand("responder_L"),
+            responder_contents: expand("responder_P"),
+            initiator_terminator: terminators[..16].try_into().unwrap(),
+            responder_terminator: terminators[16..].try_into().unwrap(),
+        }
+    }
+}
+
+// Encrypts or decrypts the packets sent one way: a 3-byte length of the contents,
+// then the header byte and the contents with their tag.
+#[derive(Clone, Debug)]
+pub struct PacketCipher {
+    length: FSChaCha20,
+    contents: FSChaCha20Poly1305,
+}
+
+impl PacketCipher {
+    pub fn new(length_key: [u8; 32], contents_key: [u8; 32]) -> Self {
+        PacketCipher {
+            length: FSChaCha20::new(length_key),
+            contents: FSChaCha20Poly1305::new(contents_key),
+        }
+    }
+
+    pub fn encrypt(&mut self, contents: &[u8], aad: &[u8], decoy: bool) -> Vec<u8> {
+        let mut result = (contents.len() as u32).to_le_bytes()[..LENGTH_SIZE].to_vec();
+        self.length.crypt(&mut result);
+        let mut plaintext = vec![if decoy { DECOY_FLAG } else { 0 }];
+        plaintext.extend(contents);
+        result.extend(self.contents.encrypt(aad, &plaintext));
+        result
+    }
+
+    // Length of the contents following, whose packet is one header byte and the tag
+    // longer.
+    pub fn decrypt_length(&mut self, mut length: [u8; LENGTH_SIZE]) -> usize {
+        self.length.crypt(&mut length);
+        u32::from_le_bytes([length[0], length[1], length[2], 0]) as usize
+    }
+
+    // The contents of packet, None for decoys.
+    pub fn decrypt(&mut self, packet: &[u8], aad: &[u8]) -> Result<Option<Vec<u8>>, Errors> {
+        let mut plaintext = self.contents.decrypt(aad, packet)?;
+        if plaintext.is_empty() {
+            return Err(Errors::InvalidEnvelope("packet without header"));
+        }
+        if plaintext[0] & DECOY_FLAG != 0 {
+            return Ok(None);
+        }
+        plaintext.remove(0);
+        Ok(Some(plaintext))
+    }
+}
+
+// Contents of the packet carrying envelope.
+pub fn encode_contents(envelope: &NetworkEnvelope) -> Vec<u8> {
+    let mut result = match SHORT_IDS
+        .iter()
+        .position(|command| *command == envelope.command)
+    {
+        Some(index) => vec![index as u8 + 1],
+        None => {
+            let mut command = vec![0u8; 1 + COMMAND_SIZE];
+            command[1..1 + envelope.command.len()].copy_from_slice(envelope.command.as_bytes());
+            command
+        }
+    };
+    result.extend(&envelope.payload);
+    result
+}
+
+// The envelope in contents, None for short ids we don't know, which are ignored as
+// those of later protocol versions.
+pub fn decode_contents(contents: &[u8], magic: [u8; 4]) -> Result<Option<NetworkEnvelope>, Errors> {
+    let (command, payload) = match contents.first() {
+        None => return Err(Errors::InvalidEnvelope("empty packet")),
+        Some(0) => {
+            if contents.len() < 1 + COMMAND_SIZE {
+                return Err(Errors::UnexpectedEof);
+            }
+            let command = &contents[1..1 + COMMAND_SIZE];
+            let len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+            if command[len..].iter().any(|b| *b != 0) || !command[..len].is_ascii() {
+                return Err(Errors::InvalidEnvelope("malformed command"));
+            }
+            (
+                String::from_utf8(command[..len].to_vec()).unwrap(),
+                &contents[1 + COMMAND_SIZE..],
+            )
+        }
+        Some(id) => match SHORT_IDS.get(*id as usize - 1) {
+            Some(command) => (command.to_string(), &contents[1..]),
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(NetworkEnvelope {
+        magic,
+        command,
+        payload: payload.to_vec(),
+    }))
+}
+
+// An established v2 connection.
+#[derive(Clone, Debug)]
+pub struct V2Transport {
+    session_id: [u8; 32],
+    send: PacketCipher,
+    receive: PacketCipher,
+}
+
+impl V2Transport {
+    // Ciphers of a connection we opened if initiator, else accepted.
+    pub fn new(keys: &SessionKeys, initiator: bool) -> Self {
+        let initiator_cipher = PacketCipher::new(keys.initiator_length, keys.initiator_contents);
+        let responder_cipher = PacketCipher::new(keys.responder_length, keys.responder_contents);
+        let (send, receive) = if initiator {
+            (initiator_cipher, responder_cipher)
+        } else {
+            (responder_cipher, initiator_cipher)
+        };
+        V2Transport {
+            session_id: keys.session_id,
+            send,
+            receive,
+        }
+    }
+
+    pub fn session_id(&self) -> &[u8; 32] {
+        &self.session_id
+    }
+
+    // The ciphers sending and receiving, for a writer and a reader apart.
+    pub fn into_ciphers(self) -> (PacketCipher, PacketCipher) {
+        (self.send, self.receive)
+    }
+
+    // Sends our key and garbage on a connection we opened, then finishes the handshake.
+    // Fails with an Io or UnexpectedEof error when a v1 peer hangs up on us.
+    pub fn initiate(stream: &mut (impl Read + Write), network: Network) -> Result<Self, Errors> {
+        let (secret, ours, garbage) = Self::key_and_garbage()?;
+        let mut first = ours.to_vec();
+        first.extend(&garbage);
+        stream.write_all(&first).map_err(io_error)?;
+        let theirs = read_array::<ELLSWIFT_KEY_SIZE>(stream)?;
+        Self::establish(stream, network, &secret, &ours, &theirs, true, &garbage)
+    }
+
+    // Finishes the handshake on a connection accepted, whose first bytes were read
+    // already to tell it apart from v1.
+    pub fn respond(
+        stream: &mut (impl Read + Write),
+        network: Network,
+        read: &[u8],
+    ) -> Result<Self, Errors> {
+        let mut theirs = [0u8; ELLSWIFT_KEY_SIZE];
+        theirs[..read.len()].copy_from_slice(read);
+        stream
+            .read_exact(&mut theirs[read.len()..])
+            .map_err(|_| Errors::UnexpectedEof)?;
+        let (secret, ours, garbage) = Self::key_and_garbage()?;
+        let mut first = ours.to_vec();
+        first.extend(&garbage);
+        stream.write_all(&first).map_err(io_error)?;
+        Self::establish(stream, network, &secret, &ours, &theirs, false, &garbage)
+    }
+
+    // The key and the randomness encoding it come from the operating system; failing
+    // to read it fails the handshake.
+    fn key_and_garbage() -> Result<(PrivateKey, [u8; ELLSWIFT_KEY_SIZE], Vec<u8>), Errors> {
+        let secret = loop {
+            if let Ok(secret) = PrivateKey::from_bytes(&random_bytes()?) {
+                break secret;
+            }
+        };
+        let key = ellswift::create(&secret, &random_bytes()?);
+        let garbage_len = random_nonce() as usize % (MAX_GARBAGE_SIZE + 1);
+        let garbage = (0..garbage_len.div_ceil(8))
+            .flat_map(|_| random_nonce().to_le_bytes())
+            .take(garbage_len)
+            .collect();
+        Ok((secret, key, garbage))
+    }
+
+    // Once keys are exchanged, each side sends its garbage terminator and the version
+    // packet, authenticating its garbage, and reads the peer's.
+    fn establish(
+        stream: &mut (impl Read + Write),
+        network: Network,
+        secret: &PrivateKey,
+        ours: &[u8; ELLSWIFT_KEY_SIZE],
+        theirs: &[u8; ELLSWIFT_KEY_SIZE],
+        initiator: bool,
+        garbage: &[u8],
+    ) -> Result<Self, Errors> {
+        let shared_secret = ellswift::xdh(secret, ours, theirs, initiator)?;
+        let keys = SessionKeys::new(&shared_secret, network.magic());
+        let mut transport = V2Transport::new(&keys, initiator);
+        let (our_terminator, their_terminator) = if initiator {
+            (keys.initiator_terminator, keys.responder_terminator)
+        } else {
+            (keys.responder_terminator, keys.initiator_terminator)
+        };
+        let mut version = our_terminator.to_vec();
+        version.extend(transport.send.encrypt(&[], garbage, false));
+        stream.write_all(&version).map_err(io_error)?;
+
+        let mut received = read_array::<GARBAGE_TERMINATOR_SIZE>(stream)?.to_vec();
+        while received[received.len() - GARBAGE_TERMINATOR_SIZE..] != their_terminator {
+            if received.len() == MAX_GARBAGE_SIZE + GARBAGE_TERMINATOR_SIZE {
+                return Err(Errors::HandshakeFailed("garbage terminator not found"));
+            }
+            received.push(read_array::<1>(stream)?[0]);
+        }
+        received.truncate(received.len() - GARBAGE_TERMINATOR_SIZE);
+        // Decoys may come before the version packet, the first packet having the
+        // garbage as aad whichever it is. Its contents are for later versions.
+        let mut aad = received;
+        while transport.read_packet(stream, &aad)?.is_none() {
+            aad.clear();
+        }
+        Ok(transport)
+    }
+
+    fn read_packet(
+        &mut self,
+        stream: &mut impl Read,
+        aad: &[u8],
+    ) -> Result<Option<Vec<u8>>, Errors> {
+        let length = self.receive.decrypt_length(read_array(stream)?);
+        if length > MAX_CONTENTS_SIZE {
+            return Err(Errors::InvalidEnvelope("payload too large"));
+        }
+        let packet = read_bytes(stream, 1 + length + TAG_SIZE)?;
+        self.receive.decrypt(&packet, aad)
+    }
+
+    pub fn write(
+        &mut self,
+        stream: &mut impl Write,
+        envelope: &NetworkEnvelope,
+    ) -> Result<(), Errors> {
+        let packet = self.send.encrypt(&encode_contents(envelope), &[], false);
+        stream.write_all(&packet).map_err(io_error)
+    }
+
+    // A decoy packet of len random bytes, ignored by the peer.
+    pub fn write_decoy(&mut self, stream: &mut impl Write, len: usize) -> Result<(), Errors> {
+        let contents: Vec<u8> = (0..len).map(|_| random_nonce() as u8).collect();
+        let packet = self.send.encrypt(&contents, &[], true);
+        stream.write_all(&packet).map_err(io_error)
+    }
+
+    // The next message, skipping decoys and messages we don't know.
+    pub fn read(
+        &mut self,
+        stream: &mut impl Read,
+        magic: [u8; 4],
+    ) -> Result<NetworkEnvelope, Errors> {
+        loop {
+            if let Some(contents) = self.read_packet(stream, &[])? {
+                if let Some(envelope) = decode_contents(&contents, magic)? {
+                    return Ok(envelope);
+                }
+            }
+        }
+    }
+}
+
+// The transport of a connection, over which its envelopes are sent.
+#[derive(Clone, Debug)]
+pub enum Transport {
+    // Holds the bytes of the peer's first envelope read to tell which transport it
+    // speaks.
+    V1(Vec<u8>),
+    V2(Box<V2Transport>),
+}
+
+impl Transport {
+    // Transport of a connection accepted: v1 when it opens with a version envelope
+    // of network, as no v2 key can, v2 otherwise.
+    pub fn accept(stream: &mut (impl Read + Write), network: Network) -> Result<Self, Errors> {
+        let read = read_array::<16>(stream)?;
+        let mut v1_prefix = network.magic().to_vec();
+        v1_prefix.extend(b"version\0\0\0\0\0");
+        if read[..] == v1_prefix[..] {
+            return Ok(Transport::V1(read.to_vec()));
+        }
+        Ok(Transport::V2(Box::new(V2Transport::respond(
+            stream, network, &read,
+        )?)))
+    }
+
+    // Transport of a connection we opened: v2, or else v1 on the connection reconnect
+    // opens if the peer hangs up on our key as v1 peers do.
+    pub fn initiate<S: Read + Write>(
+        mut stream: S,
+        network: Network,
+        reconnect: impl FnOnce() -> Result<S, Errors>,
+    ) -> Result<(S, Self), Errors> {
+        match V2Transport::initiate(&mut stream, network) {
+            Ok(transport) => Ok((stream, Transport::V2(Box::new(transport)))),
+            Err(Errors::Io(_) | Errors::UnexpectedEof) => {
+                Ok((reconnect()?, Transport::V1(Vec::new())))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn is_v2(&self) -> bool {
+        matches!(self, Transport::V2(_))
+    }
+
+    pub fn write(
+        &mut self,
+        stream: &mut impl Write,
+        envelope: &NetworkEnvelope,
+    ) -> Result<(), Errors> {
+        match self {
+            Transport::V1(_) => stream.write_all(&envelope.serialize()).map_err(io_error),
+            Transport::V2(transport) => transport.write(stream, envelope),
+        }
+    }
+
+    pub fn read(
+        &mut self,
+        stream: &mut impl Read,
+        magic: [u8; 4],
+    ) -> Result<NetworkEnvelope, Errors> {
+        match self {
+            Transport::V1(read) => {
+                let read = std::mem::take(read);
+                NetworkEnvelope::parse(&mut read.as_slice().chain(stream), magic)
+            }
+            Transport::V2(transport) => transport.read(stream, magic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::*;
+    use crate::p2p::message::{Message, Ping};
+    use crate::p2p::VersionMessage;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn array<const N: usize>(hex_str: &str) -> [u8; N] {
+        hex::decode(hex_str).unwrap().try_into().unwrap()
+    }
+
+    // Session of a BIP324 vector, from the side of the party with secret.
+    fn session(
+        secret: &str,
+        ours: &str,
+        theirs: &str,
+        initiator: bool,
+    ) -> (SessionKeys, V2Transport) {
+        let secret = PrivateKey::from_bytes(&array(secret)).unwrap();
+        let shared_secret =
+            ellswift::xdh(&secret, &array(ours), &array(theirs), initiator).unwrap();
+        let keys = SessionKeys::new(&shared_secret, Network::Mainnet.magic());
+        let transport = V2Transport::new(&keys, initiator);
+        (keys, transport)
+    }
+
+    #[test]
+    fn test_packet_vectors() {
+        let (_, mut alice) = session(
+            "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+            "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa1\
+             86f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+            "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafa\
+             ffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+            true,
+        );
+        alice.send.encrypt(&[0; 100], &[], false);
+        assert_eq!(
+            hex::encode(alice.send.encrypt(&[0x8e], &[], false)),
+            "7530d2a18720162ac09c25329a60d75adf36eda3c3"
+        );
+
+        // As responder, after the contents keys were rekeyed four times.
+        let (keys, mut alice) = session(
+            "6f312890ec83bbb26798abaadd574684a53e74ccef7953b790fcc29409080246",
+            "a8785af31c029efc82fa9fc677d7118031358d7c6a25b5779a9b900e5ccd94aa\
+             c97eb36a3c5dbcdb2ca5843cc4c2fe0aaa46d10eb3d233a81c3dde476da00eef",
+            "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f\
+             0000000000000000000000000000000000000000000000000000000000000000",
+            false,
+        );
+        assert_eq!(
+            keys.session_id,
+            array("b0490e26111cb2d55bbff2ace00f7f644f64006539abb4e7513f05107bb10608")
+        );
+        for _ in 0..999 {
+            alice.send.encrypt(&[], &[], false);
+        }
+        let contents = hex::decode("3eb1d4e98035cfd8eeb29bac969ed3824a").unwrap();
+        assert_eq!(
+            hex::encode(alice.send.encrypt(&contents, &[], false)),
+            "d78adbcba0eebfb15cfbd8142c84dc729d233d0dc11b1d851e46a114122b8d5b96b7d59317"
+        );
+
+        // A decoy right after the first rekeying.
+        let (keys, mut alice) = session(
+            "c0f15820459f64d98e5c48681d13340572c574533dd9f7161b85fcc8224fdf30",
+            "682871104d694baca8b9c7990ae6288f49e1ff4feb21dd5cffad67db7752fdfb\
+             6c3608d6996c54be04b35feef037da09ee4d9dca2363b343bc2d4f6d0ea609da",
+            "56bd0c06f10352c3a1a9f4b4c92f6fa2b26df124b57878353c1fc691c51abea7\
+             7c8817daeeb9fa546b77c8daf79d89b22b0e1b87574ece42371f00237aa9d83a",
+            false,
+        );
+        assert_eq!(
+            keys.session_id,
+            array("279a96e6ce08e5074608fcad77d6a78f90c8b618a4520575435b1a37b1c56df9")
+        );
+        for _ in 0..223 {
+            alice.send.encrypt(&[], &[], true);
+        }
+        let contents = hex::decode(
+            "7e0e78eb6990b059e6cf0ded66ea93ef82e72aa2f18ac24f2fc6ebab561ae557\
+             420729da103f64cecfa20527e15f9fb669a49bbbf274ef0389b3e43c8c44e5f6\
+             0bf2ac38e2b55e7ec4273dba15ba41d21f8f5b3ee1688b3c29951218caf847a9\
+             7fb50d75a86515d445699497d968164bf740012679b8962de573be941c62b7ef",
+        )
+        .unwrap();
+        assert!(
+            hex::encode(alice.send.encrypt(&contents, &[], true)).ends_with(
+                "5afbd61f6e989833df2f12ff70c98f1a20ebe84acba2a05429cc6a57238dba87\
+             cdc432474f378889b2d0e95ade9f892eb1a1f6b03b73f903682476537f653f73\
+             8f7a9f1cc9856ed75f3d69122bdeb00af48e66a64872f639a67fc109ee5ca124\
+             d0ee183da3c2b8f2da828850b50976b491f1add78d7f01e07565570621266852"
+            )
+        );
+    }
+
+    #[test]
+    fn test_contents() {
+        let magic = Network::Regtest.magic();
+        let ping = Ping { nonce: 3 }.to_envelope(Network::Regtest);
+        let contents = encode_contents(&ping);
+        assert_eq!(contents[0], 18);
+        assert_eq!(decode_contents(&contents, magic), Ok(Some(ping)));
+        let version = VersionMessage::new(0, 0).to_envelope(Network::Regtest);
+        let contents = encode_contents(&version);
+        assert_eq!(&contents[..8], b"\0version");
+        assert_eq!(decode_contents(&contents, magic), Ok(Some(version)));
+        assert_eq!(decode_contents(&[200, 1, 2], magic), Ok(None));
+    }
+
+    #[test]
+    fn test_handshake_and_fallback() {
+        let network = Network::Regtest;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut transport = Transport::accept(&mut stream, network).unwrap();
+            assert!(transport.is_v2());
+            let ping = transport.read(&mut stream, network.magic()).unwrap();
+            transport.write(&mut stream, &ping).unwrap();
+            // Then a v1 connection.
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut transport = Transport::accept(&mut stream, network).unwrap();
+            assert!(!transport.is_v2());
+            let version = transport.read(&mut stream, network.magic()).unwrap();
+            assert_eq!(version.command, "version");
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut transport = V2Transport::initiate(&mut stream, network).unwrap();
+        transport.write_decoy(&mut stream, 10).unwrap();
+        let ping = Ping { nonce: 5 }.to_envelope(network);
+        transport.write(&mut stream, &ping).unwrap();
+        assert_eq!(transport.read(&mut stream, network.magic()), Ok(ping));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let version = VersionMessage::new(0, 0).to_envelope(network);
+        Transport::V1(Vec::new())
+            .write(&mut stream, &version)
+            .unwrap();
+        peer.join().unwrap();
+    }
+}
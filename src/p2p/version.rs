@@ -0,0 +1,475 @@
+// The version message each side sends when connecting, and the handshake agreeing
+// on the protocol version and optional features before anything else is exchanged.
+use super::envelope::NetworkEnvelope;
+use super::feefilter::FeeFilter;
+use super::message::{Message, SendAddrV2, SendHeaders, VerAck, WtxidRelay};
+use super::transport::Transport;
+use crate::helper::{encode_var_bytes, read_array, read_var_bytes};
+use crate::network::Network;
+use crate::policy::FeeRate;
+use crate::types::errors::Errors;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const PROTOCOL_VERSION: i32 = 70016;
+// Oldest version peers may use, as MIN_PEER_PROTO_VERSION in Bitcoin Core.
+pub const MIN_PEER_PROTO_VERSION: i32 = 31800;
+// Versions from which the features negotiated in the handshake are known.
+pub const SENDHEADERS_VERSION: i32 = 70012;
+pub const FEEFILTER_VERSION: i32 = 70013;
+pub const SHORT_IDS_BLOCKS_VERSION: i32 = 70014;
+pub const WTXID_RELAY_VERSION: i32 = 70016;
+
+// Service bits peers advertise.
+pub const NODE_NETWORK: u64 = 1;
+pub const NODE_BLOOM: u64 = 1 << 2;
+pub const NODE_WITNESS: u64 = 1 << 3;
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+// Serves only the last MIN_BLOCKS_TO_KEEP blocks, as pruned nodes do (BIP159).
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+// Accepts v2 encrypted connections (BIP324).
+pub const NODE_P2P_V2: u64 = 1 << 11;
+
+// Address of a node as in version messages, IPv4 addresses being mapped to IPv6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkAddress {
+    pub services: u64,
+    pub ip: Ipv6Addr,
+    pub port: u16,
+}
+
+impl NetworkAddress {
+    pub fn new(address: SocketAddr, services: u64) -> Self {
+        let ip = match address.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        NetworkAddress {
+            services,
+            ip,
+            port: address.port(),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        let ip = match self.ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(self.ip),
+        };
+        SocketAddr::new(ip, self.port)
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(NetworkAddress {
+            services: u64::from_le_bytes(read_array(reader)?),
+            ip: Ipv6Addr::from(read_array::<16>(reader)?),
+            // The only big endian field of the protocol.
+            port: u16::from_be_bytes(read_array(reader)?),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.services.to_le_bytes().to_vec();
+        result.extend(self.ip.octets());
+        result.extend(self.port.to_be_bytes());
+        result
+    }
+}
+
+impl Default for NetworkAddress {
+    // 0.0.0.0:0, what nodes send when they don't know or don't tell.
+    fn default() -> Self {
+        NetworkAddress::new(SocketAddr::from(([0, 0, 0, 0], 0)), 0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub version: i32,
+    pub services: u64,
+    pub timestamp: i64,
+    pub receiver: NetworkAddress,
+    pub sender: NetworkAddress,
+    // Random, so a node connecting to itself notices it.
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    // Whether transactions should be announced before a BIP37 filter is loaded.
+    pub relay: bool,
+}
+
+// Nonce of version and ping messages.
+pub fn random_nonce() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+impl VersionMessage {
+    // Version of a node at start_height offering services, timestamped now.
+    pub fn new(services: u64, start_height: i32) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            services,
+            timestamp,
+            receiver: NetworkAddress::default(),
+            sender: NetworkAddress::default(),
+            nonce: random_nonce(),
+            user_agent: format!("/{}:{}/", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            start_height,
+            relay: true,
+        }
+    }
+
+    pub fn with_receiver(mut self, receiver: NetworkAddress) -> Self {
+        self.receiver = receiver;
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn with_relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+}
+
+impl Message for VersionMessage {
+    const COMMAND: &'static str = "version";
+
+    fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let version = i32::from_le_bytes(read_array(reader)?);
+        let services = u64::from_le_bytes(read_array(reader)?);
+        let timestamp = i64::from_le_bytes(read_array(reader)?);
+        let receiver = NetworkAddress::parse(reader)?;
+        let sender = NetworkAddress::parse(reader)?;
+        let nonce = u64::from_le_bytes(read_array(reader)?);
+        let user_agent = String::from_utf8_lossy(&read_var_bytes(reader)?).into_owned();
+        let start_height = i32::from_le_bytes(read_array(reader)?);
+        // Peers older than BIP37 leave the relay flag out.
+        let relay = read_array::<1>(reader).map_or(true, |flag| flag[0] != 0);
+        Ok(VersionMessage {
+            version,
+            services,
+            timestamp,
+            receiver,
+            sender,
+            nonce,
+            user_agent,
+            start_height,
+            relay,
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.extend(self.services.to_le_bytes());
+        result.extend(self.timestamp.to_le_bytes());
+        result.extend(self.receiver.serialize());
+        result.extend(self.sender.serialize());
+        result.extend(self.nonce.to_le_bytes());
+        result.extend(encode_var_bytes(self.user_agent.as_bytes()));
+        result.extend(self.start_height.to_le_bytes());
+        result.push(self.relay as u8);
+        result
+    }
+}
+
+// What was learned about a peer in the handshake.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    // The lower of both versions, which both sides then speak.
+    pub version: i32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+    pub wtxid_relay: bool,
+    pub addrv2: bool,
+    // Asked for new blocks to be announced with headers rather than inv (BIP130).
+    pub send_headers: bool,
+    // Lowest feerate of the transactions it wants announced, set by its feefilter
+    // once connected (BIP133).
+    pub fee_filter: FeeRate,
+}
+
+impl PeerInfo {
+    // Keeps what the peer asks for in messages sent once connected: announcements
+    // by header and its fee filter.
+    pub fn update(&mut self, envelope: &NetworkEnvelope) {
+        if envelope.command == SendHeaders::COMMAND {
+            self.send_headers = true;
+        } else if let Some(Ok(filter)) = envelope.message::<FeeFilter>() {
+            self.fee_filter = filter.feerate;
+        }
+    }
+}
+
+// The handshake as seen from our side, fed the peer's messages as they arrive, so
+// it works over blocking and async connections alike. version goes out first; the
+// peer's is answered with verack, announcing wtxidrelay and sendaddrv2 before it
+// when the peer knows them. Once the peer's verack arrives, sendheaders asks for
+// new blocks to be announced by header. Other messages sent before then are ignored.
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    network: Network,
+    version: VersionMessage,
+    peer: Option<PeerInfo>,
+    complete: bool,
+}
+
+impl Handshake {
+    pub fn new(network: Network, version: VersionMessage) -> Self {
+        Handshake {
+            network,
+            version,
+            peer: None,
+            complete: false,
+        }
+    }
+
+    pub fn start(&self) -> NetworkEnvelope {
+        self.version.to_envelope(self.network)
+    }
+
+    // Messages to send in answer to envelope.
+    pub fn receive(&mut self, envelope: &NetworkEnvelope) -> Result<Vec<NetworkEnvelope>, Errors> {
+        let network = self.network;
+        if let Some(theirs) = envelope.message::<VersionMessage>() {
+            let theirs = theirs?;
+            if self.peer.is_some() {
+                return Err(Errors::HandshakeFailed("version sent twice"));
+            }
+            if theirs.version < MIN_PEER_PROTO_VERSION {
+                return Err(Errors::HandshakeFailed("peer version is too old"));
+            }
+            if theirs.nonce == self.version.nonce {
+                return Err(Errors::HandshakeFailed("connected to ourselves"));
+            }
+            let negotiated = theirs.version.min(self.version.version);
+            let mut replies = Vec::new();
+            if negotiated >= WTXID_RELAY_VERSION {
+                replies.push(WtxidRelay.to_envelope(network));
+                replies.push(SendAddrV2.to_envelope(network));
+            }
+            replies.push(VerAck.to_envelope(network));
+            self.peer = Some(PeerInfo {
+                version: negotiated,
+                services: theirs.services,
+                user_agent: theirs.user_agent,
+                start_height: theirs.start_height,
+                relay: theirs.relay,
+                wtxid_relay: false,
+                addrv2: false,
+                send_headers: false,
+                fee_filter: FeeRate::ZERO,
+            });
+            return Ok(replies);
+        }
+        let Some(info) = &mut self.peer else {
+            if envelope.command == VerAck::COMMAND {
+                return Err(Errors::HandshakeFailed("verack before version"));
+            }
+            return Ok(Vec::new());
+        };
+        match envelope.command.as_str() {
+            WtxidRelay::COMMAND => info.wtxid_relay = info.version >= WTXID_RELAY_VERSION,
+            SendAddrV2::COMMAND => info.addrv2 = true,
+            SendHeaders::COMMAND => info.send_headers = true,
+            VerAck::COMMAND if !self.complete => {
+                self.complete = true;
+                if info.version >= SENDHEADERS_VERSION {
+                    return Ok(vec![SendHeaders.to_envelope(network)]);
+                }
+            }
+            _ => {}
+        }
+        Ok(Vec::new())
+    }
+
+    // The peer, once both veracks are exchanged.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer.as_ref().filter(|_| self.complete)
+    }
+}
+
+// Runs the whole handshake over a blocking stream.
+pub fn handshake(
+    stream: &mut (impl Read + Write),
+    network: Network,
+    version: &VersionMessage,
+) -> Result<PeerInfo, Errors> {
+    handshake_over(stream, &mut Transport::V1(Vec::new()), network, version)
+}
+
+// The handshake on a connection whose transport is established.
+pub fn handshake_over(
+    stream: &mut (impl Read + Write),
+    transport: &mut Transport,
+    network: Network,
+    version: &VersionMessage,
+) -> Result<PeerInfo, Errors> {
+    let mut handshake = Handshake::new(network, version.clone());
+    transport.write(stream, &handshake.start())?;
+    loop {
+        let envelope = transport.read(stream, network.magic())?;
+        for reply in handshake.receive(&envelope)? {
+            transport.write(stream, &reply)?;
+        }
+        if let Some(info) = handshake.peer_info() {
+            return Ok(info.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod version_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Replays what the peer sends and records what we send.
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(messages: &[NetworkEnvelope]) -> Self {
+            MockStream {
+                input: Cursor::new(messages.iter().flat_map(|m| m.serialize()).collect()),
+                output: Vec::new(),
+            }
+        }
+
+        fn sent(&self) -> Vec<String> {
+            let mut reader = self.output.as_slice();
+            let mut commands = Vec::new();
+            while !reader.is_empty() {
+                let envelope = NetworkEnvelope::parse(&mut reader, Network::Regtest.magic());
+                commands.push(envelope.unwrap().command);
+            }
+            commands
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_and_serialize() {
+        // Version message of Programming Bitcoin, with a zero timestamp and nonce.
+        let bytes = hex::decode("7f11010000000000000000000000000000000000000000000000000000000000000000000000ffff00000000208d000000000000000000000000000000000000ffff00000000208d0000000000000000182f70726f6772616d6d696e67626974636f696e3a302e312f0000000000").unwrap();
+        let message = VersionMessage::parse(&mut bytes.as_slice()).unwrap();
+        assert_eq!(message.version, 70015);
+        assert_eq!(
+            message.receiver.socket_addr(),
+            "0.0.0.0:8333".parse().unwrap()
+        );
+        assert_eq!(message.user_agent, "/programmingbitcoin:0.1/");
+        assert!(!message.relay);
+        assert_eq!(message.serialize(), bytes);
+
+        // Without the relay flag, as sent before BIP37.
+        let message = VersionMessage::parse(&mut &bytes[..bytes.len() - 1]).unwrap();
+        assert!(message.relay);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let ours = VersionMessage::new(NODE_NETWORK | NODE_WITNESS, 0);
+        let theirs = VersionMessage::new(NODE_NETWORK_LIMITED, 120).with_user_agent("/peer/");
+        let mut stream = MockStream::new(&[
+            theirs.to_envelope(Network::Regtest),
+            WtxidRelay.to_envelope(Network::Regtest),
+            SendAddrV2.to_envelope(Network::Regtest),
+            VerAck.to_envelope(Network::Regtest),
+        ]);
+        let info = handshake(&mut stream, Network::Regtest, &ours).unwrap();
+        assert_eq!(
+            info,
+            PeerInfo {
+                version: PROTOCOL_VERSION,
+                services: NODE_NETWORK_LIMITED,
+                user_agent: "/peer/".to_string(),
+                start_height: 120,
+                relay: true,
+                wtxid_relay: true,
+                addrv2: true,
+                send_headers: false,
+                fee_filter: FeeRate::ZERO,
+            }
+        );
+        assert_eq!(
+            stream.sent(),
+            [
+                "version",
+                "wtxidrelay",
+                "sendaddrv2",
+                "verack",
+                "sendheaders"
+            ]
+        );
+
+        // Older peers get none of the newer messages.
+        let mut old = theirs.clone();
+        old.version = 70001;
+        let mut stream = MockStream::new(&[
+            old.to_envelope(Network::Regtest),
+            WtxidRelay.to_envelope(Network::Regtest),
+            VerAck.to_envelope(Network::Regtest),
+        ]);
+        let info = handshake(&mut stream, Network::Regtest, &ours).unwrap();
+        assert_eq!(info.version, 70001);
+        assert!(!info.wtxid_relay);
+        assert_eq!(stream.sent(), ["version", "verack"]);
+    }
+
+    #[test]
+    fn test_failed_handshakes() {
+        let ours = VersionMessage::new(NODE_NETWORK, 0);
+        let mut old = VersionMessage::new(NODE_NETWORK, 0);
+        old.version = 209;
+        for (messages, error) in [
+            (
+                vec![VerAck.to_envelope(Network::Regtest)],
+                Errors::HandshakeFailed("verack before version"),
+            ),
+            (
+                vec![old.to_envelope(Network::Regtest)],
+                Errors::HandshakeFailed("peer version is too old"),
+            ),
+            (
+                vec![ours.to_envelope(Network::Regtest)],
+                Errors::HandshakeFailed("connected to ourselves"),
+            ),
+            (
+                vec![VerAck.to_envelope(Network::Mainnet)],
+                Errors::InvalidEnvelope("magic of another network"),
+            ),
+            (vec![], Errors::UnexpectedEof),
+        ] {
+            let mut stream = MockStream::new(&messages);
+            assert_eq!(handshake(&mut stream, Network::Regtest, &ours), Err(error));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Dust: outputs worth less than what it would cost to spend them.
+use super::FeeRate;
+use crate::script::witness_program;
+use crate::transaction::weight::WITNESS_SCALE_FACTOR;
+use crate::transaction::TxOut;
+
+// Default feerate for the dust rule, in sat/kvB.
+pub const DUST_RELAY_TX_FEE: u64 = 3000;
+
+// Outputs that can never be spent are exempt from the dust rule.
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+impl TxOut {
+    pub fn is_unspendable(&self) -> bool {
+        self.script_pubkey.first() == Some(&0x6a) || self.script_pubkey.len() > MAX_SCRIPT_SIZE
+    }
+
+    // GetDustThreshold from Bitcoin Core: the fee, at dust_feerate, of this output plus
+    // a typical input spending it. Witness programs are spent with witness data that is
+    // discounted by the weight formula, so their threshold is lower.
+    pub fn dust_threshold(&self, dust_feerate: FeeRate) -> u64 {
+        if self.is_unspendable() {
+            return 0;
+        }
+        // outpoint (32 + 4), scriptSig length (1) and sequence (4).
+        let input_base = 32 + 4 + 1 + 4;
+        // A signature and compressed pubkey push take about 107 bytes.
+        let spend_data = if witness_program(&self.script_pubkey).is_some() {
+            107 / WITNESS_SCALE_FACTOR
+        } else {
+            107
+        };
+        dust_feerate.fee_for_vsize(self.serialize().len() + input_base + spend_data)
+    }
+
+    pub fn is_dust(&self, dust_feerate: FeeRate) -> bool {
+        self.value < self.dust_threshold(dust_feerate)
+    }
+}
+
+#[cfg(test)]
+mod dust_tests {
+    use super::*;
+
+    fn dust_rate() -> FeeRate {
+        FeeRate::from_sat_per_kvb(DUST_RELAY_TX_FEE)
+    }
+
+    fn p2pkh() -> Vec<u8> {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend_from_slice(&[0xab; 20]);
+        script.extend_from_slice(&[0x88, 0xac]);
+        script
+    }
+
+    fn witness_program(version_op: u8, len: u8) -> Vec<u8> {
+        let mut script = vec![version_op, len];
+        script.extend(std::iter::repeat_n(0xab, len as usize));
+        script
+    }
+
+    #[test]
+    fn test_well_known_thresholds() {
+        assert_eq!(
+            TxOut::new(0, p2pkh().into()).dust_threshold(dust_rate()),
+            546
+        );
+        assert_eq!(
+            TxOut::new(0, witness_program(0x00, 20).into()).dust_threshold(dust_rate()),
+            294
+        );
+        assert_eq!(
+            TxOut::new(0, witness_program(0x00, 32).into()).dust_threshold(dust_rate()),
+            330
+        );
+        assert_eq!(
+            TxOut::new(0, witness_program(0x51, 32).into()).dust_threshold(dust_rate()),
+            330
+        );
+    }
+
+    #[test]
+    fn test_is_dust() {
+        assert!(TxOut::new(545, p2pkh().into()).is_dust(dust_rate()));
+        assert!(!TxOut::new(546, p2pkh().into()).is_dust(dust_rate()));
+        assert!(!TxOut::new(545, p2pkh().into()).is_dust(FeeRate::ZERO));
+    }
+
+    #[test]
+    fn test_unspendable_outputs_are_never_dust() {
+        let op_return = TxOut::new(0, vec![0x6a, 0x01, 0x00].into());
+        assert!(op_return.is_unspendable());
+        assert!(!op_return.is_dust(dust_rate()));
+        assert!(!TxOut::new(0, vec![0x51; 10_001].into()).is_dust(dust_rate()));
+    }
+
+    #[test]
+    fn test_threshold_scales_with_feerate() {
+        let output = TxOut::new(0, witness_program(0x00, 20).into());
+        assert_eq!(output.dust_threshold(FeeRate::from_sat_per_vb(10)), 980);
+    }
+}
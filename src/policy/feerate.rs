@@ -0,0 +1,83 @@
+// Fee rates expressed in satoshis per 1000 virtual bytes, like Bitcoin Core's CFeeRate.
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FeeRate {
+    sat_per_kvb: u64,
+}
+
+impl FeeRate {
+    pub const ZERO: FeeRate = FeeRate { sat_per_kvb: 0 };
+
+    pub fn from_sat_per_kvb(sat_per_kvb: u64) -> Self {
+        FeeRate { sat_per_kvb }
+    }
+
+    // Saturates at the highest rate rather than overflowing on rates given by users.
+    pub fn from_sat_per_vb(sat_per_vb: u64) -> Self {
+        FeeRate {
+            sat_per_kvb: sat_per_vb.saturating_mul(1000),
+        }
+    }
+
+    // Rate paid by a transaction of vsize vbytes paying fee satoshis.
+    pub fn from_fee_and_vsize(fee: u64, vsize: usize) -> Self {
+        if vsize == 0 {
+            return FeeRate::ZERO;
+        }
+        FeeRate {
+            sat_per_kvb: fee.saturating_mul(1000) / vsize as u64,
+        }
+    }
+
+    pub fn sat_per_kvb(&self) -> u64 {
+        self.sat_per_kvb
+    }
+
+    // Fee for a given virtual size. As in Core, a non-zero rate never yields a zero fee.
+    pub fn fee_for_vsize(&self, vsize: usize) -> u64 {
+        let fee = self.sat_per_kvb.saturating_mul(vsize as u64) / 1000;
+        if fee == 0 && vsize != 0 && self.sat_per_kvb > 0 {
+            return 1;
+        }
+        fee
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:03} sat/vB",
+            self.sat_per_kvb / 1000,
+            self.sat_per_kvb % 1000
+        )
+    }
+}
+
+#[cfg(test)]
+mod feerate_tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_for_vsize() {
+        let rate = FeeRate::from_sat_per_vb(2);
+        assert_eq!(rate.sat_per_kvb(), 2000);
+        assert_eq!(rate.fee_for_vsize(250), 500);
+        assert_eq!(FeeRate::from_sat_per_kvb(1).fee_for_vsize(1), 1);
+        assert_eq!(FeeRate::ZERO.fee_for_vsize(1000), 0);
+        let max = FeeRate::from_sat_per_vb(u64::MAX);
+        assert_eq!(max, FeeRate::from_sat_per_kvb(u64::MAX));
+        assert_eq!(max.fee_for_vsize(1000), u64::MAX / 1000);
+    }
+
+    #[test]
+    fn test_from_fee_and_vsize() {
+        assert_eq!(
+            FeeRate::from_fee_and_vsize(1000, 250),
+            FeeRate::from_sat_per_vb(4)
+        );
+        assert_eq!(FeeRate::from_fee_and_vsize(1000, 0), FeeRate::ZERO);
+        assert_eq!(FeeRate::from_sat_per_kvb(1500).to_string(), "1.500 sat/vB");
+    }
+}
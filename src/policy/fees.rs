@@ -0,0 +1,298 @@
+// Fee estimation from the blocks seen, after Bitcoin Core's CBlockPolicyEstimator.
+// Transactions entering the mempool are tracked in buckets of feerate 5% apart, and
+// once confirmed, counted as confirmed within every target from the blocks they
+// took. Counts decay with each block, so old blocks weigh less. The estimate for a
+// target is the median feerate of the lowest buckets whose transactions, grouped
+// until there are enough, confirmed within the target often enough.
+use super::FeeRate;
+use crate::helper::{hash256, read_array};
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// As the medium horizon of Bitcoin Core, which answers targets up to 48 blocks.
+pub const MAX_CONFIRM_TARGET: usize = 48;
+pub const DECAY: f64 = 0.9952;
+// Share of a range's transactions confirmed within the target for it to pass.
+pub const SUCCESS_PCT: f64 = 0.85;
+// Transactions a range needs, in the long run, per block.
+pub const SUFFICIENT_FEETXS: f64 = 0.1;
+// Bucket bounds, in sat/kvB.
+const MIN_BUCKET_FEERATE: f64 = 1000.0;
+const MAX_BUCKET_FEERATE: f64 = 10_000_000.0;
+const FEE_SPACING: f64 = 1.05;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeEstimator {
+    // Upper bound of each bucket, the last one unbounded.
+    buckets: Vec<f64>,
+    // By target minus one, then bucket: transactions confirmed within the target.
+    confirmed: Vec<Vec<f64>>,
+    // By bucket: transactions confirmed, and the sum of their feerates.
+    tx_count: Vec<f64>,
+    fee_sum: Vec<f64>,
+    // Transactions in the mempool: the height they entered at and their feerate.
+    unconfirmed: HashMap<[u8; 32], (u32, u64)>,
+    best_height: u32,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        FeeEstimator::new()
+    }
+}
+
+impl FeeEstimator {
+    pub fn new() -> Self {
+        let mut buckets = Vec::new();
+        let mut bound = MIN_BUCKET_FEERATE;
+        while bound <= MAX_BUCKET_FEERATE {
+            buckets.push(bound);
+            bound *= FEE_SPACING;
+        }
+        buckets.push(f64::INFINITY);
+        FeeEstimator {
+            confirmed: vec![vec![0.0; buckets.len()]; MAX_CONFIRM_TARGET],
+            tx_count: vec![0.0; buckets.len()],
+            fee_sum: vec![0.0; buckets.len()],
+            buckets,
+            unconfirmed: HashMap::new(),
+            best_height: 0,
+        }
+    }
+
+    pub fn best_height(&self) -> u32 {
+        self.best_height
+    }
+
+    fn bucket(&self, feerate: u64) -> usize {
+        self.buckets
+            .partition_point(|bound| *bound < feerate as f64)
+    }
+
+    // Tracks a transaction the mempool accepted while the tip was at height.
+    pub fn process_transaction(&mut self, txid: [u8; 32], feerate: FeeRate, height: u32) {
+        self.unconfirmed
+            .insert(txid, (height, feerate.sat_per_kvb()));
+    }
+
+    // Stops tracking a transaction that left the mempool without confirming.
+    pub fn remove_transaction(&mut self, txid: &[u8; 32]) {
+        self.unconfirmed.remove(txid);
+    }
+
+    // Stops tracking the transactions keep returns false for, as those the mempool
+    // evicted.
+    pub fn retain_transactions(&mut self, keep: impl Fn(&[u8; 32]) -> bool) {
+        self.unconfirmed.retain(|txid, _| keep(txid));
+    }
+
+    // Counts the tracked transactions a new tip at height confirmed, after decaying
+    // what was counted before. Blocks not above the last one, as in a reorg, are
+    // ignored.
+    pub fn process_block(&mut self, height: u32, txids: impl IntoIterator<Item = [u8; 32]>) {
+        if height <= self.best_height {
+            return;
+        }
+        self.best_height = height;
+        for value in self
+            .confirmed
+            .iter_mut()
+            .flatten()
+            .chain(&mut self.tx_count)
+            .chain(&mut self.fee_sum)
+        {
+            *value *= DECAY;
+        }
+        for txid in txids {
+            let Some((entered, feerate)) = self.unconfirmed.remove(&txid) else {
+                continue;
+            };
+            let blocks = height.saturating_sub(entered) as usize;
+            if blocks == 0 {
+                continue;
+            }
+            let bucket = self.bucket(feerate);
+            for target in blocks..=MAX_CONFIRM_TARGET {
+                self.confirmed[target - 1][bucket] += 1.0;
+            }
+            self.tx_count[bucket] += 1.0;
+            self.fee_sum[bucket] += feerate as f64;
+        }
+    }
+
+    // The feerate for a transaction to confirm within target blocks, None without
+    // enough data or for targets beyond MAX_CONFIRM_TARGET.
+    pub fn estimate_fee(&self, target: usize) -> Option<FeeRate> {
+        if target == 0 || target > MAX_CONFIRM_TARGET {
+            return None;
+        }
+        // Still unconfirmed after target blocks, they count as failures.
+        let mut failed = vec![0.0; self.buckets.len()];
+        for (entered, feerate) in self.unconfirmed.values() {
+            if self.best_height.saturating_sub(*entered) as usize >= target {
+                failed[self.bucket(*feerate)] += 1.0;
+            }
+        }
+        let sufficient = SUFFICIENT_FEETXS / (1.0 - DECAY);
+        let (mut confirmed, mut total) = (0.0, 0.0);
+        let mut start = self.buckets.len() - 1;
+        let mut passing = None;
+        for bucket in (0..self.buckets.len()).rev() {
+            confirmed += self.confirmed[target - 1][bucket];
+            total += self.tx_count[bucket] + failed[bucket];
+            if total < sufficient {
+                continue;
+            }
+            if confirmed / total < SUCCESS_PCT {
+                break;
+            }
+            passing = Some((bucket, start));
+            (confirmed, total) = (0.0, 0.0);
+            start = bucket.saturating_sub(1);
+        }
+        let (low, high) = passing?;
+        // The median bucket of the range by transactions, and its average feerate.
+        let half = self.tx_count[low..=high].iter().sum::<f64>() / 2.0;
+        let mut count = 0.0;
+        for bucket in low..=high {
+            count += self.tx_count[bucket];
+            if count >= half && self.tx_count[bucket] > 0.0 {
+                let average = self.fee_sum[bucket] / self.tx_count[bucket];
+                return Some(FeeRate::from_sat_per_kvb(average.round() as u64));
+            }
+        }
+        None
+    }
+
+    // Writes the best height and the counts to path, followed by a checksum of them.
+    // Tracked transactions aren't kept, as the mempool may not be. The file is
+    // replaced in a single rename.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Errors> {
+        let path = path.as_ref();
+        let mut data = self.best_height.to_le_bytes().to_vec();
+        for value in self
+            .confirmed
+            .iter()
+            .flatten()
+            .chain(&self.tx_count)
+            .chain(&self.fee_sum)
+        {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(hash256(&data));
+        let temp_path = path.with_extension("new");
+        fs::write(&temp_path, data)
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|e| Errors::Io(e.to_string()))
+    }
+
+    // Reads counts saved with save, by an estimator with the same buckets.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Errors> {
+        let corrupted = || Errors::Io("fee estimates file is corrupted".to_string());
+        let mut data = fs::read(path.as_ref()).map_err(|e| Errors::Io(e.to_string()))?;
+        let mut estimator = FeeEstimator::new();
+        let values = (MAX_CONFIRM_TARGET + 2) * estimator.buckets.len();
+        if data.len() != 4 + values * 8 + 32 {
+            return Err(corrupted());
+        }
+        let checksum = data.split_off(data.len() - 32);
+        if checksum != hash256(&data) {
+            return Err(corrupted());
+        }
+        let mut reader = data.as_slice();
+        estimator.best_height = u32::from_le_bytes(read_array(&mut reader)?);
+        for value in estimator
+            .confirmed
+            .iter_mut()
+            .flatten()
+            .chain(&mut estimator.tx_count)
+            .chain(&mut estimator.fee_sum)
+        {
+            *value = f64::from_le_bytes(read_array(&mut reader)?);
+        }
+        Ok(estimator)
+    }
+}
+
+#[cfg(test)]
+mod fees_tests {
+    use super::*;
+    use crate::test_util::temp_path;
+
+    // Runs a hundred blocks, each taking in ten transactions at every feerate given
+    // and confirming them the given number of blocks later.
+    fn feed(estimator: &mut FeeEstimator, height: &mut u32, feerates: &[(u64, u32)]) {
+        let mut pending = Vec::new();
+        for _ in 0..100 {
+            for (index, (feerate, blocks)) in feerates.iter().enumerate() {
+                for n in 0..10u8 {
+                    let mut txid = [n; 32];
+                    txid[0] = index as u8;
+                    txid[1..5].copy_from_slice(&height.to_le_bytes());
+                    let feerate = FeeRate::from_sat_per_kvb(*feerate);
+                    estimator.process_transaction(txid, feerate, *height);
+                    pending.push((*height + blocks, txid));
+                }
+            }
+            *height += 1;
+            let (due, rest): (Vec<_>, Vec<_>) =
+                pending.into_iter().partition(|(at, _)| *at <= *height);
+            pending = rest;
+            estimator.process_block(*height, due.into_iter().map(|(_, txid)| txid));
+        }
+    }
+
+    #[test]
+    fn test_estimates_follow_confirmation_times() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate_fee(2), None);
+        let mut height = 100;
+        // High fees confirm in the next block, lower ones in three and in ten.
+        feed(
+            &mut estimator,
+            &mut height,
+            &[(50_000, 1), (10_000, 3), (2000, 10)],
+        );
+        let next = estimator.estimate_fee(1).unwrap();
+        assert!(next.sat_per_kvb().abs_diff(50_000) <= 1);
+        let three = estimator.estimate_fee(3).unwrap();
+        assert!(three.sat_per_kvb().abs_diff(10_000) <= 1);
+        let twelve = estimator.estimate_fee(12).unwrap();
+        assert!(twelve.sat_per_kvb().abs_diff(2000) <= 1);
+        assert_eq!(estimator.estimate_fee(0), None);
+        assert_eq!(estimator.estimate_fee(MAX_CONFIRM_TARGET + 1), None);
+    }
+
+    #[test]
+    fn test_unconfirmed_and_persistence() {
+        let mut estimator = FeeEstimator::new();
+        let mut height = 100;
+        feed(&mut estimator, &mut height, &[(5000, 1)]);
+        assert!(estimator.estimate_fee(1).is_some());
+        // Plenty stuck at the same feerate make it fail the target.
+        for n in 0..200u32 {
+            let mut txid = [0xff; 32];
+            txid[..4].copy_from_slice(&n.to_le_bytes());
+            estimator.process_transaction(txid, FeeRate::from_sat_per_kvb(5000), height - 5);
+        }
+        assert_eq!(estimator.estimate_fee(1), None);
+        // A block below the best one changes nothing.
+        let before = estimator.clone();
+        estimator.process_block(height - 1, []);
+        assert_eq!(estimator, before);
+
+        let path = temp_path("fee_estimates").with_extension("dat");
+        estimator.save(&path).unwrap();
+        let loaded = FeeEstimator::load(&path).unwrap();
+        assert_eq!(loaded.confirmed, estimator.confirmed);
+        assert_eq!(loaded.best_height(), height);
+        assert!(loaded.estimate_fee(1).is_some());
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[10] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(FeeEstimator::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+// Relay policy (standardness) rules. These are not consensus: a non-standard
+// transaction can be valid in a block, it is just not relayed by default nodes.
+pub mod dust;
+pub mod feerate;
+pub mod fees;
+
+pub use dust::DUST_RELAY_TX_FEE;
+pub use feerate::FeeRate;
+pub use fees::{FeeEstimator, MAX_CONFIRM_TARGET};
+
+use crate::script::{classify, is_push_only, ScriptType};
+use crate::transaction::weight::WITNESS_SCALE_FACTOR;
+use crate::transaction::{Transaction, TxOut};
+use crate::types::errors::PolicyError;
+use crate::validation::legacy_sigop_count;
+
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+pub const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+pub const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+pub const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+pub const MAX_STANDARD_TX_SIGOPS_COST: usize = 16_000;
+pub const MAX_STANDARD_VERSION: i32 = 2;
+
+// Whether taproot spends carrying an annex are relayed. Bitcoin Core rejects them
+// until the annex is given a meaning, so it can't be used to stuff witnesses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnnexPolicy {
+    #[default]
+    Reject,
+    Accept,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputKind {
+    Standard,
+    NullData,
+    BareMultisig,
+}
+
+// IsStandardTx from Bitcoin Core. dust_feerate is the rate used to decide which
+// outputs are too small to be worth spending (DUST_RELAY_TX_FEE by default).
+pub fn check_standard(tx: &Transaction, dust_feerate: FeeRate) -> Result<(), PolicyError> {
+    if tx.version < 1 || tx.version > MAX_STANDARD_VERSION {
+        return Err(PolicyError::Version);
+    }
+    if tx.weight() > MAX_STANDARD_TX_WEIGHT {
+        return Err(PolicyError::TxSize);
+    }
+    if tx.stripped_size() < MIN_STANDARD_TX_NONWITNESS_SIZE {
+        return Err(PolicyError::TxSizeSmall);
+    }
+
+    for input in &tx.inputs {
+        if input.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            return Err(PolicyError::ScriptSigSize);
+        }
+        if !is_push_only(&input.script_sig) {
+            return Err(PolicyError::ScriptSigNotPushOnly);
+        }
+    }
+
+    let mut null_data_outputs = 0;
+    for output in &tx.outputs {
+        match output_kind(&output.script_pubkey) {
+            None => return Err(PolicyError::ScriptPubKey),
+            Some(OutputKind::NullData) => null_data_outputs += 1,
+            Some(OutputKind::BareMultisig) | Some(OutputKind::Standard) => {
+                if output.is_dust(dust_feerate) {
+                    return Err(PolicyError::Dust);
+                }
+            }
+        }
+    }
+    if null_data_outputs > 1 {
+        return Err(PolicyError::MultiOpReturn);
+    }
+
+    if legacy_sigop_count(tx) * WITNESS_SCALE_FACTOR > MAX_STANDARD_TX_SIGOPS_COST {
+        return Err(PolicyError::TooManySigops);
+    }
+    Ok(())
+}
+
+pub fn is_standard(tx: &Transaction, dust_feerate: FeeRate) -> bool {
+    check_standard(tx, dust_feerate).is_ok()
+}
+
+// The annex part of IsWitnessStandard. prevouts are the outputs spent by every input,
+// as only the witness of a taproot spend can have an annex.
+pub fn check_annex(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    policy: AnnexPolicy,
+) -> Result<(), PolicyError> {
+    if policy == AnnexPolicy::Accept {
+        return Ok(());
+    }
+    for (input, prevout) in tx.inputs.iter().zip(prevouts) {
+        if matches!(
+            classify(&prevout.script_pubkey),
+            ScriptType::WitnessV1Taproot(_)
+        ) && input.witness.taproot_annex().is_some()
+        {
+            return Err(PolicyError::WitnessAnnex);
+        }
+    }
+    Ok(())
+}
+
+fn output_kind(script: &[u8]) -> Option<OutputKind> {
+    match classify(script) {
+        ScriptType::NonStandard => None,
+        ScriptType::NullData(_) => {
+            (script.len() <= MAX_OP_RETURN_RELAY).then_some(OutputKind::NullData)
+        }
+        // Bare multisig is only relayed up to 3 keys.
+        ScriptType::Multisig { pubkeys, .. } => {
+            (pubkeys.len() <= 3).then_some(OutputKind::BareMultisig)
+        }
+        _ => Some(OutputKind::Standard),
+    }
+}
+
+#[cfg(test)]
+mod policy_tests {
+    use super::*;
+    use crate::transaction::{OutPoint, TxIn, Witness};
+
+    fn p2pkh() -> Vec<u8> {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend_from_slice(&[0xab; 20]);
+        script.extend_from_slice(&[0x88, 0xac]);
+        script
+    }
+
+    fn p2wpkh() -> Vec<u8> {
+        let mut script = vec![0x00, 0x14];
+        script.extend_from_slice(&[0xab; 20]);
+        script
+    }
+
+    fn tx_paying(outputs: Vec<TxOut>) -> Transaction {
+        // A scriptSig of a realistic size keeps the transaction above the minimum size.
+        let mut script_sig = vec![72];
+        script_sig.extend_from_slice(&[0x30; 72]);
+        Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new([1; 32], 0),
+                script_sig.into(),
+                0xffffffff,
+            )],
+            outputs,
+            0,
+        )
+    }
+
+    fn dust_rate() -> FeeRate {
+        FeeRate::from_sat_per_kvb(DUST_RELAY_TX_FEE)
+    }
+
+    #[test]
+    fn test_standard_transaction() {
+        let tx = tx_paying(vec![
+            TxOut::new(10_000, p2pkh().into()),
+            TxOut::new(10_000, p2wpkh().into()),
+        ]);
+        assert_eq!(check_standard(&tx, dust_rate()), Ok(()));
+        assert!(is_standard(&tx, dust_rate()));
+    }
+
+    #[test]
+    fn test_version() {
+        let mut tx = tx_paying(vec![TxOut::new(10_000, p2pkh().into())]);
+        tx.version = 3;
+        assert_eq!(check_standard(&tx, dust_rate()), Err(PolicyError::Version));
+    }
+
+    #[test]
+    fn test_dust_output() {
+        let tx = tx_paying(vec![TxOut::new(545, p2pkh().into())]);
+        assert_eq!(check_standard(&tx, dust_rate()), Err(PolicyError::Dust));
+    }
+
+    #[test]
+    fn test_op_return_outputs() {
+        let mut data = vec![0x6a, 0x4c, 80];
+        data.extend_from_slice(&[0; 80]);
+        let tx = tx_paying(vec![
+            TxOut::new(0, data.clone().into()),
+            TxOut::new(10_000, p2pkh().into()),
+        ]);
+        assert_eq!(check_standard(&tx, dust_rate()), Ok(()));
+
+        let tx = tx_paying(vec![
+            TxOut::new(0, data.clone().into()),
+            TxOut::new(0, vec![0x6a].into()),
+        ]);
+        assert_eq!(
+            check_standard(&tx, dust_rate()),
+            Err(PolicyError::MultiOpReturn)
+        );
+
+        let mut oversized = vec![0x6a, 0x4c, 81];
+        oversized.extend_from_slice(&[0; 81]);
+        let tx = tx_paying(vec![TxOut::new(0, oversized.into())]);
+        assert_eq!(
+            check_standard(&tx, dust_rate()),
+            Err(PolicyError::ScriptPubKey)
+        );
+    }
+
+    #[test]
+    fn test_nonstandard_script_pubkey() {
+        let tx = tx_paying(vec![TxOut::new(10_000, vec![0x51].into())]);
+        assert_eq!(
+            check_standard(&tx, dust_rate()),
+            Err(PolicyError::ScriptPubKey)
+        );
+
+        // v0 witness programs must be 20 or 32 bytes.
+        let mut v0 = vec![0x00, 0x15];
+        v0.extend_from_slice(&[0; 21]);
+        assert_eq!(
+            check_standard(&tx_paying(vec![TxOut::new(10_000, v0.into())]), dust_rate()),
+            Err(PolicyError::ScriptPubKey)
+        );
+    }
+
+    #[test]
+    fn test_bare_multisig() {
+        let mut script = vec![0x51];
+        for _ in 0..3 {
+            script.push(33);
+            script.extend_from_slice(&[0x02; 33]);
+        }
+        script.extend_from_slice(&[0x53, 0xae]);
+        let tx = tx_paying(vec![TxOut::new(10_000, script.clone().into())]);
+        assert_eq!(check_standard(&tx, dust_rate()), Ok(()));
+
+        // 1-of-4 is beyond the standard limit.
+        let mut four = script[..script.len() - 2].to_vec();
+        four.push(33);
+        four.extend_from_slice(&[0x02; 33]);
+        four.extend_from_slice(&[0x54, 0xae]);
+        let tx = tx_paying(vec![TxOut::new(10_000, four.into())]);
+        assert_eq!(
+            check_standard(&tx, dust_rate()),
+            Err(PolicyError::ScriptPubKey)
+        );
+    }
+
+    #[test]
+    fn test_script_sig_rules() {
+        let mut tx = tx_paying(vec![TxOut::new(10_000, p2pkh().into())]);
+        tx.inputs[0].script_sig = vec![0x51, 0x76].into();
+        assert_eq!(
+            check_standard(&tx, dust_rate()),
+            Err(PolicyError::ScriptSigNotPushOnly)
+        );
+
+        let mut huge = vec![0x4d, 0x73, 0x06];
+        huge.extend_from_slice(&[0; 1651]);
+        tx.inputs[0].script_sig = huge.into();
+        assert_eq!(
+            check_standard(&tx, dust_rate()),
+            Err(PolicyError::ScriptSigSize)
+        );
+    }
+
+    #[test]
+    fn test_too_many_sigops() {
+        // Legacy counting charges 20 sigops for each CHECKMULTISIG, 80 once weighted.
+        let mut one_of_one = vec![0x51, 33];
+        one_of_one.extend_from_slice(&[0x02; 33]);
+        one_of_one.extend_from_slice(&[0x51, 0xae]);
+        let outputs = (0..201)
+            .map(|_| TxOut::new(10_000, one_of_one.clone().into()))
+            .collect();
+        let tx = tx_paying(outputs);
+
+        assert_eq!(legacy_sigop_count(&tx), 201 * 20);
+        assert_eq!(
+            check_standard(&tx, dust_rate()),
+            Err(PolicyError::TooManySigops)
+        );
+    }
+
+    #[test]
+    fn test_annex_policy() {
+        let mut tx = tx_paying(vec![TxOut::new(10_000, p2pkh().into())]);
+        tx.inputs[0].witness = Witness::p2tr_key_spend(&[1; 64])
+            .with_annex(vec![0x50, 0xaa])
+            .unwrap();
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend_from_slice(&[0xab; 32]);
+        let taproot_prevouts = vec![TxOut::new(10_000, p2tr.into())];
+        assert_eq!(
+            check_annex(&tx, &taproot_prevouts, AnnexPolicy::default()),
+            Err(PolicyError::WitnessAnnex)
+        );
+        assert_eq!(
+            check_annex(&tx, &taproot_prevouts, AnnexPolicy::Accept),
+            Ok(())
+        );
+        // The same last element means nothing when spending another kind of output.
+        let p2wpkh_prevouts = vec![TxOut::new(10_000, p2wpkh().into())];
+        assert_eq!(
+            check_annex(&tx, &p2wpkh_prevouts, AnnexPolicy::Reject),
+            Ok(())
+        );
+    }
+}
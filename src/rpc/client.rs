@@ -0,0 +1,433 @@
+// A JSON-RPC client for Bitcoin Core, letting the tools of this crate use a bitcoind
+// as their chain backend. Calls are blocking, as those of the transaction fetcher,
+// and authenticate with a user and password or bitcoind's cookie. Blocks and
+// transactions come back parsed and checked against the hash asked for.
+use super::server::COOKIE_USER;
+use crate::block::Block;
+use crate::helper::base64_encode;
+use crate::policy::FeeRate;
+use crate::transaction::{txid_from_hex, txid_to_hex, OutPoint, Transaction, TxOut, UtxoProvider};
+use crate::types::errors::{Errors, RpcError};
+use crate::validation::COIN;
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+// Sends a request body to url, returning the response body, errors included.
+pub trait RpcTransport {
+    fn post(&self, url: &str, authorization: &str, body: &str) -> Result<String, Errors>;
+}
+
+pub struct UreqTransport;
+
+impl RpcTransport for UreqTransport {
+    fn post(&self, url: &str, authorization: &str, body: &str) -> Result<String, Errors> {
+        let response = ureq::post(url)
+            .set("Authorization", authorization)
+            .set("Content-Type", "application/json")
+            .send_string(body);
+        match response {
+            Ok(response) => response
+                .into_string()
+                .map_err(|e| Errors::Http(e.to_string())),
+            // bitcoind reports JSON-RPC 1.0 errors with an HTTP error status, and
+            // failed logins without a body.
+            Err(ureq::Error::Status(status, response)) => response
+                .into_string()
+                .ok()
+                .filter(|body| !body.is_empty())
+                .ok_or_else(|| Errors::Http(format!("status {status}"))),
+            Err(e) => Err(Errors::Http(e.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockchainInfo {
+    pub chain: String,
+    pub blocks: u32,
+    pub headers: u32,
+    pub best_block_hash: [u8; 32],
+    pub difficulty: f64,
+    pub median_time: u32,
+    pub verification_progress: f64,
+    pub initial_block_download: bool,
+    pub pruned: bool,
+}
+
+// An unspent output as gettxout describes it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxOutInfo {
+    pub best_block: [u8; 32],
+    // 0 while in the mempool.
+    pub confirmations: u32,
+    pub output: TxOut,
+    pub coinbase: bool,
+}
+
+pub struct RpcClient<T: RpcTransport = UreqTransport> {
+    url: String,
+    authorization: String,
+    transport: T,
+    next_id: Cell<u64>,
+}
+
+impl RpcClient<UreqTransport> {
+    // url as http://127.0.0.1:8332, with the path of a wallet if any.
+    pub fn new(url: &str, user: &str, password: &str) -> Self {
+        RpcClient::with_transport(url, user, password, UreqTransport)
+    }
+
+    // Authenticates with the cookie bitcoind wrote to its data directory, which
+    // changes every time it starts.
+    pub fn with_cookie(url: &str, cookie_path: impl AsRef<Path>) -> Result<Self, Errors> {
+        let cookie = fs::read_to_string(cookie_path).map_err(|e| Errors::Io(e.to_string()))?;
+        match cookie.trim().split_once(':') {
+            Some((COOKIE_USER, password)) => Ok(RpcClient::new(url, COOKIE_USER, password)),
+            _ => Err(Errors::Io("cookie file is corrupted".to_string())),
+        }
+    }
+}
+
+impl<T: RpcTransport> RpcClient<T> {
+    pub fn with_transport(url: &str, user: &str, password: &str, transport: T) -> Self {
+        let credentials = base64_encode(format!("{user}:{password}").as_bytes());
+        RpcClient {
+            url: url.to_string(),
+            authorization: format!("Basic {credentials}"),
+            transport,
+            next_id: Cell::new(0),
+        }
+    }
+
+    fn request(&self, method: &str, params: Vec<Value>) -> (u64, Value) {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let request = json!({ "jsonrpc": "1.0", "id": id, "method": method, "params": params });
+        (id, request)
+    }
+
+    fn post(&self, request: &Value) -> Result<Value, Errors> {
+        let body = self
+            .transport
+            .post(&self.url, &self.authorization, &request.to_string())?;
+        serde_json::from_str(&body).map_err(|_| Errors::InvalidRpcResponse("not JSON"))
+    }
+
+    // Calls method with positional params, returning its result.
+    pub fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, Errors> {
+        let (_, request) = self.request(method, params);
+        result(self.post(&request)?).map_err(Errors::Rpc)
+    }
+
+    // Sends the calls in a single request, returning the result of each in order.
+    pub fn batch(
+        &self,
+        calls: Vec<(&str, Vec<Value>)>,
+    ) -> Result<Vec<Result<Value, RpcError>>, Errors> {
+        let (ids, requests): (Vec<u64>, Vec<Value>) = calls
+            .into_iter()
+            .map(|(method, params)| self.request(method, params))
+            .unzip();
+        let Value::Array(replies) = self.post(&Value::Array(requests))? else {
+            return Err(Errors::InvalidRpcResponse("batch reply is not an array"));
+        };
+        // Replies may come in any order, told apart by id.
+        let mut results: Vec<Option<Result<Value, RpcError>>> = vec![None; ids.len()];
+        for reply in replies {
+            let index = reply
+                .get("id")
+                .and_then(Value::as_u64)
+                .and_then(|id| ids.iter().position(|sent| *sent == id))
+                .ok_or(Errors::InvalidRpcResponse("unknown id in batch reply"))?;
+            results[index] = Some(result(reply));
+        }
+        results
+            .into_iter()
+            .map(|result| result.ok_or(Errors::InvalidRpcResponse("missing batch reply")))
+            .collect()
+    }
+
+    pub fn get_block_count(&self) -> Result<u32, Errors> {
+        as_u32(&self.call("getblockcount", vec![])?)
+    }
+
+    pub fn get_best_block_hash(&self) -> Result<[u8; 32], Errors> {
+        as_hash(&self.call("getbestblockhash", vec![])?)
+    }
+
+    pub fn get_block_hash(&self, height: u32) -> Result<[u8; 32], Errors> {
+        as_hash(&self.call("getblockhash", vec![json!(height)])?)
+    }
+
+    // The hashes of the active chain at heights, in one request.
+    pub fn get_block_hashes(&self, heights: Range<u32>) -> Result<Vec<[u8; 32]>, Errors> {
+        let calls = heights
+            .map(|height| ("getblockhash", vec![json!(height)]))
+            .collect();
+        self.batch(calls)?
+            .into_iter()
+            .map(|result| as_hash(&result.map_err(Errors::Rpc)?))
+            .collect()
+    }
+
+    pub fn get_block(&self, hash: &[u8; 32]) -> Result<Block, Errors> {
+        let hex = self.call("getblock", vec![json!(txid_to_hex(hash)), json!(0)])?;
+        let block = Block::from_hex(hex.as_str().ok_or(Errors::InvalidRpcResponse("not hex"))?)?;
+        if block.hash() != *hash {
+            return Err(Errors::InvalidRpcResponse("block does not hash as asked"));
+        }
+        Ok(block)
+    }
+
+    pub fn get_blockchain_info(&self) -> Result<BlockchainInfo, Errors> {
+        let info = self.call("getblockchaininfo", vec![])?;
+        let field = |name: &'static str| {
+            info.get(name).ok_or(Errors::InvalidRpcResponse(
+                "missing getblockchaininfo field",
+            ))
+        };
+        let flag = |name: &'static str| {
+            field(name)?
+                .as_bool()
+                .ok_or(Errors::InvalidRpcResponse("not a bool"))
+        };
+        Ok(BlockchainInfo {
+            chain: as_str(field("chain")?)?.to_string(),
+            blocks: as_u32(field("blocks")?)?,
+            headers: as_u32(field("headers")?)?,
+            best_block_hash: as_hash(field("bestblockhash")?)?,
+            difficulty: as_f64(field("difficulty")?)?,
+            median_time: as_u32(field("mediantime")?)?,
+            verification_progress: as_f64(field("verificationprogress")?)?,
+            initial_block_download: flag("initialblockdownload")?,
+            pruned: flag("pruned")?,
+        })
+    }
+
+    // Without -txindex, bitcoind finds confirmed transactions only in the block
+    // given.
+    pub fn get_raw_transaction(
+        &self,
+        txid: &[u8; 32],
+        block: Option<&[u8; 32]>,
+    ) -> Result<Transaction, Errors> {
+        let mut params = vec![json!(txid_to_hex(txid)), json!(false)];
+        if let Some(block) = block {
+            params.push(json!(txid_to_hex(block)));
+        }
+        let hex = self.call("getrawtransaction", params)?;
+        let tx = Transaction::from_hex(as_str(&hex)?)?;
+        if tx.txid() != *txid {
+            return Err(Errors::TxidMismatch(txid_to_hex(txid)));
+        }
+        Ok(tx)
+    }
+
+    pub fn get_raw_mempool(&self) -> Result<Vec<[u8; 32]>, Errors> {
+        match self.call("getrawmempool", vec![])? {
+            Value::Array(txids) => txids.iter().map(as_hash).collect(),
+            _ => Err(Errors::InvalidRpcResponse("not an array")),
+        }
+    }
+
+    // Broadcasts tx, returning its txid.
+    pub fn send_raw_transaction(&self, tx: &Transaction) -> Result<[u8; 32], Errors> {
+        let txid = self.call(
+            "sendrawtransaction",
+            vec![json!(hex::encode(tx.serialize()))],
+        )?;
+        as_hash(&txid)
+    }
+
+    // The output if unspent, counting spends in the mempool with include_mempool.
+    pub fn get_tx_out(
+        &self,
+        outpoint: &OutPoint,
+        include_mempool: bool,
+    ) -> Result<Option<TxOutInfo>, Errors> {
+        let params = vec![
+            json!(txid_to_hex(&outpoint.txid)),
+            json!(outpoint.vout),
+            json!(include_mempool),
+        ];
+        let info = match self.call("gettxout", params)? {
+            Value::Null => return Ok(None),
+            info => info,
+        };
+        let missing = Errors::InvalidRpcResponse("missing gettxout field");
+        let script = info["scriptPubKey"]["hex"].as_str().ok_or(missing)?;
+        let script = hex::decode(script).map_err(|_| Errors::InvalidHex)?;
+        Ok(Some(TxOutInfo {
+            best_block: as_hash(&info["bestblock"])?,
+            confirmations: as_u32(&info["confirmations"])?,
+            output: TxOut::new(as_amount(&info["value"])?, script.into()),
+            coinbase: info["coinbase"].as_bool().unwrap_or(false),
+        }))
+    }
+
+    // The feerate to confirm within target blocks, None while bitcoind has too
+    // little data.
+    pub fn estimate_smart_fee(&self, target: u32) -> Result<Option<FeeRate>, Errors> {
+        let estimate = self.call("estimatesmartfee", vec![json!(target)])?;
+        match estimate.get("feerate") {
+            Some(feerate) => Ok(Some(FeeRate::from_sat_per_kvb(as_amount(feerate)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+// Previous outputs straight from bitcoind's UTXO set and mempool, for fee
+// computation and signing.
+impl<T: RpcTransport> UtxoProvider for RpcClient<T> {
+    fn get_output(&self, outpoint: &OutPoint) -> Result<TxOut, Errors> {
+        self.get_tx_out(outpoint, true)?
+            .map(|info| info.output)
+            .ok_or_else(|| Errors::UnknownOutput(outpoint.to_string()))
+    }
+}
+
+fn result(mut reply: Value) -> Result<Value, RpcError> {
+    match reply.get("error") {
+        None | Some(Value::Null) => Ok(reply["result"].take()),
+        Some(error) => {
+            let code = error["code"].as_i64().unwrap_or(-1) as i32;
+            let message = error["message"].as_str().unwrap_or_default().to_string();
+            Err(RpcError::from_code(code, message))
+        }
+    }
+}
+
+fn as_str(value: &Value) -> Result<&str, Errors> {
+    value
+        .as_str()
+        .ok_or(Errors::InvalidRpcResponse("not a string"))
+}
+
+fn as_u32(value: &Value) -> Result<u32, Errors> {
+    value
+        .as_u64()
+        .and_then(|n| u32::try_from(n).ok())
+        .ok_or(Errors::InvalidRpcResponse("not a 32-bit number"))
+}
+
+fn as_f64(value: &Value) -> Result<f64, Errors> {
+    value
+        .as_f64()
+        .ok_or(Errors::InvalidRpcResponse("not a number"))
+}
+
+fn as_hash(value: &Value) -> Result<[u8; 32], Errors> {
+    txid_from_hex(as_str(value)?)
+}
+
+// An amount in BTC, in satoshis.
+fn as_amount(value: &Value) -> Result<u64, Errors> {
+    let amount = as_f64(value)?;
+    if amount < 0.0 {
+        return Err(Errors::InvalidRpcResponse("negative amount"));
+    }
+    Ok((amount * COIN as f64).round() as u64)
+}
+
+#[cfg(test)]
+mod client_tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_util::temp_path;
+    use std::cell::RefCell;
+
+    // Answers with the replies given in turn, keeping the requests.
+    struct StubTransport {
+        replies: RefCell<Vec<Value>>,
+        requests: RefCell<Vec<(String, Value)>>,
+    }
+
+    impl RpcTransport for &StubTransport {
+        fn post(&self, _: &str, authorization: &str, body: &str) -> Result<String, Errors> {
+            let request = serde_json::from_str(body).unwrap();
+            self.requests
+                .borrow_mut()
+                .push((authorization.to_string(), request));
+            Ok(self.replies.borrow_mut().remove(0).to_string())
+        }
+    }
+
+    fn stub(replies: Vec<Value>) -> StubTransport {
+        StubTransport {
+            replies: RefCell::new(replies),
+            requests: RefCell::new(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn test_typed_calls() {
+        let genesis = Network::Regtest.genesis_block();
+        let block_hex = hex::encode(genesis.serialize());
+        let transport = stub(vec![
+            json!({"result": 7, "error": null, "id": 0}),
+            json!({"result": block_hex, "error": null, "id": 1}),
+            json!({"result": null, "error": {"code": -5, "message": "Block not found"}, "id": 2}),
+            json!({"result": {"feerate": 0.00012, "blocks": 2}, "error": null, "id": 3}),
+            json!({"result": null, "error": null, "id": 4}),
+        ]);
+        let client =
+            RpcClient::with_transport("http://127.0.0.1:18443", "user", "pass", &transport);
+        assert_eq!(client.get_block_count(), Ok(7));
+        assert_eq!(client.get_block(&genesis.hash()), Ok(genesis.clone()));
+        // Errors come back with bitcoind's code.
+        assert_eq!(
+            client.get_block(&[0; 32]),
+            Err(Errors::Rpc(RpcError::InvalidAddressOrKey(
+                "Block not found".to_string()
+            )))
+        );
+        assert_eq!(
+            client.estimate_smart_fee(2),
+            Ok(Some(FeeRate::from_sat_per_kvb(12_000)))
+        );
+        let outpoint = OutPoint::new([1; 32], 0);
+        assert_eq!(
+            client.get_output(&outpoint),
+            Err(Errors::UnknownOutput(outpoint.to_string()))
+        );
+
+        let requests = transport.requests.borrow();
+        assert_eq!(requests[0].0, "Basic dXNlcjpwYXNz");
+        assert_eq!(
+            requests[1].1,
+            json!({"jsonrpc": "1.0", "id": 1, "method": "getblock", "params": [genesis.header.hash_hex(), 0]})
+        );
+        assert_eq!(
+            requests[4].1["params"],
+            json!([txid_to_hex(&[1; 32]), 0, true])
+        );
+    }
+
+    #[test]
+    fn test_batch_and_cookie() {
+        let hashes = [[1u8; 32], [2u8; 32]];
+        // Replies out of order are matched by id.
+        let transport = stub(vec![json!([
+            {"result": txid_to_hex(&hashes[1]), "error": null, "id": 1},
+            {"result": txid_to_hex(&hashes[0]), "error": null, "id": 0},
+        ])]);
+        let client = RpcClient::with_transport("http://localhost", "u", "p", &transport);
+        assert_eq!(client.get_block_hashes(5..7), Ok(hashes.to_vec()));
+        let requests = transport.requests.borrow();
+        assert_eq!(requests[0].1[1]["params"], json!([6]));
+
+        let path = temp_path("rpc_cookie");
+        fs::write(&path, "__cookie__:secret\n").unwrap();
+        let client = RpcClient::with_cookie("http://localhost", &path).unwrap();
+        assert_eq!(
+            client.authorization,
+            format!("Basic {}", base64_encode(b"__cookie__:secret"))
+        );
+        fs::write(&path, "nonsense").unwrap();
+        assert!(RpcClient::with_cookie("http://localhost", &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+// Just enough HTTP/1.1 for JSON-RPC and REST: requests with a Content-Length body,
+// kept alive between requests unless either side asks to close, and responses
+// written whole.
+use crate::types::errors::Errors;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// As Bitcoin Core's MAX_SIZE, which bounds a request to its HTTP server.
+pub const MAX_BODY_SIZE: usize = 0x0200_0000;
+// Across the request line and every header.
+pub const MAX_HEADERS_SIZE: usize = 8192;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    // The first header named name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        !self
+            .header("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn io_error(e: std::io::Error) -> Errors {
+    Errors::Io(e.to_string())
+}
+
+async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    read: &mut usize,
+) -> Result<String, Errors> {
+    let mut line = Vec::new();
+    let limit = (MAX_HEADERS_SIZE - *read) as u64;
+    (&mut *reader)
+        .take(limit)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(io_error)?;
+    *read += line.len();
+    if !line.ends_with(b"\n") {
+        return Err(Errors::InvalidHttpRequest(if *read >= MAX_HEADERS_SIZE {
+            "headers too large"
+        } else {
+            "unexpected end of request"
+        }));
+    }
+    let line = String::from_utf8(line).map_err(|_| Errors::InvalidHttpRequest("not UTF-8"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// The next request of the connection, None once the client closed it.
+pub async fn read_request(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> Result<Option<HttpRequest>, Errors> {
+    if reader.fill_buf().await.map_err(io_error)?.is_empty() {
+        return Ok(None);
+    }
+    let mut read = 0;
+    let request_line = read_line(reader, &mut read).await?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Errors::InvalidHttpRequest("bad request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Errors::InvalidHttpRequest("unsupported version"));
+    }
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut read).await?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(Errors::InvalidHttpRequest("bad header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    let length = match request.header("Content-Length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| Errors::InvalidHttpRequest("bad content length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(Errors::InvalidHttpRequest("body too large"));
+    }
+    request.body = vec![0; length];
+    reader
+        .read_exact(&mut request.body)
+        .await
+        .map_err(io_error)?;
+    Ok(Some(request))
+}
+
+pub async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    response: &HttpResponse,
+    keep_alive: bool,
+) -> Result<(), Errors> {
+    let mut data = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in &response.headers {
+        data.push_str(&format!("{name}: {value}\r\n"));
+    }
+    data.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    if !keep_alive {
+        data.push_str("Connection: close\r\n");
+    }
+    data.push_str("\r\n");
+    let mut data = data.into_bytes();
+    data.extend(&response.body);
+    writer.write_all(&data).await.map_err(io_error)?;
+    writer.flush().await.map_err(io_error)
+}
+
+#[cfg(test)]
+mod http_tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_requests_and_responses() {
+        let data = b"POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: 4\r\n\r\nbodyGET /rest/chaininfo.json HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut reader = BufReader::new(&data[..]);
+        let request = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.header("Content-Length"), Some("4"));
+        assert_eq!(request.body, b"body");
+        assert!(request.keep_alive());
+        let request = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(request.path, "/rest/chaininfo.json");
+        assert!(!request.keep_alive());
+        assert_eq!(read_request(&mut reader).await, Ok(None));
+
+        let mut reader = BufReader::new(&b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"[..]);
+        assert!(read_request(&mut reader).await.is_err());
+        let mut reader = BufReader::new(&b"nonsense\r\n\r\n"[..]);
+        assert_eq!(
+            read_request(&mut reader).await,
+            Err(Errors::InvalidHttpRequest("bad request line"))
+        );
+
+        let mut written = Vec::new();
+        let response = HttpResponse::new(404, "text/plain", "nope").with_header("X-Test", "1");
+        write_response(&mut written, &response, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nX-Test: 1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nnope"
+        );
+    }
+}
@@ -0,0 +1,11 @@
+// Serving the node to local tooling: JSON-RPC as bitcoind does and its REST
+// endpoints, over the small HTTP/1.1 of the http module. The client module talks to
+// a bitcoind in turn.
+pub mod client;
+pub mod http;
+pub mod rest;
+pub mod server;
+
+pub use client::{BlockchainInfo, RpcClient, RpcTransport, TxOutInfo, UreqTransport};
+pub use http::{HttpRequest, HttpResponse};
+pub use server::{serve, RpcAuth, RpcServer, DEFAULT_MAX_RAW_TX_FEE_RATE};
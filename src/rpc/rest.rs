@@ -0,0 +1,272 @@
+// Bitcoin Core's REST interface: read-only GETs without authentication, answering
+// in the format named by the extension, .bin for the raw bytes, .hex for them in hex
+// or .json for the shapes of the matching RPC methods. Transactions are found in the
+// mempool, or in blocks when the node keeps a transaction index.
+use super::http::{HttpRequest, HttpResponse};
+use super::server::{header_json, RpcServer};
+use crate::transaction::{txid_from_hex, txid_to_hex};
+use crate::types::errors::RpcError;
+use serde_json::{json, Value};
+
+// As Bitcoin Core's MAX_REST_HEADERS_RESULTS.
+pub const MAX_REST_HEADERS_RESULTS: u32 = 2000;
+const DEFAULT_HEADERS_COUNT: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Bin,
+    Hex,
+    Json,
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    HttpResponse::new(status, "text/plain", format!("{message}\r\n"))
+}
+
+fn format_not_found() -> HttpResponse {
+    error(
+        404,
+        "output format not found (available: .bin, .hex, .json)",
+    )
+}
+
+// Splits resource into its name and the format of its extension.
+fn split_format(resource: &str) -> (&str, Option<Format>) {
+    let Some((name, extension)) = resource.rsplit_once('.') else {
+        return (resource, None);
+    };
+    let format = match extension {
+        "bin" => Some(Format::Bin),
+        "hex" => Some(Format::Hex),
+        "json" => Some(Format::Json),
+        _ => None,
+    };
+    (name, format)
+}
+
+fn parse_hash(hex: &str) -> Result<[u8; 32], HttpResponse> {
+    match hex.len() {
+        64 => txid_from_hex(hex).map_err(|_| error(400, &format!("Invalid hash: {hex}"))),
+        _ => Err(error(400, &format!("Invalid hash: {hex}"))),
+    }
+}
+
+// Raw bytes in the format asked for, or the JSON given.
+fn reply(format: Format, bytes: Vec<u8>, json: impl FnOnce() -> Value) -> HttpResponse {
+    match format {
+        Format::Bin => HttpResponse::new(200, "application/octet-stream", bytes),
+        Format::Hex => HttpResponse::new(200, "text/plain", format!("{}\n", hex::encode(bytes))),
+        Format::Json => HttpResponse::new(200, "application/json", format!("{}\n", json())),
+    }
+}
+
+// The response to a GET below /rest/.
+pub fn respond(server: &RpcServer, request: &HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return error(405, "Method not allowed");
+    }
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    let path = path.strip_prefix("/rest/").unwrap_or(path);
+    let result = if let Some(resource) = path.strip_prefix("tx/") {
+        tx(server, resource)
+    } else if let Some(resource) = path.strip_prefix("block/notxdetails/") {
+        block(server, resource, false)
+    } else if let Some(resource) = path.strip_prefix("block/") {
+        block(server, resource, true)
+    } else if let Some(resource) = path.strip_prefix("headers/") {
+        headers(server, resource, query)
+    } else if let Some(resource) = path.strip_prefix("chaininfo") {
+        chain_info(server, resource)
+    } else {
+        Err(error(404, "Not found"))
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+// The value of a call, errors as 404s.
+fn call(
+    server: &RpcServer,
+    method: &str,
+    params: Value,
+    name: &str,
+) -> Result<Value, HttpResponse> {
+    server.call_method(method, &params).map_err(|e| match e {
+        RpcError::InvalidAddressOrKey(_) => error(404, &format!("{name} not found")),
+        e => error(404, &e.to_string()),
+    })
+}
+
+fn hex_bytes(value: &Value) -> Vec<u8> {
+    value
+        .as_str()
+        .and_then(|hex| hex::decode(hex).ok())
+        .unwrap_or_default()
+}
+
+fn tx(server: &RpcServer, resource: &str) -> Result<HttpResponse, HttpResponse> {
+    let (hex, format) = split_format(resource);
+    let format = format.ok_or_else(format_not_found)?;
+    let txid = txid_to_hex(&parse_hash(hex)?);
+    let tx = call(server, "getrawtransaction", json!([txid, 0]), hex)?;
+    let json = match format {
+        Format::Json => call(server, "getrawtransaction", json!([txid, 1]), hex)?,
+        _ => Value::Null,
+    };
+    Ok(reply(format, hex_bytes(&tx), || json))
+}
+
+fn block(
+    server: &RpcServer,
+    resource: &str,
+    tx_details: bool,
+) -> Result<HttpResponse, HttpResponse> {
+    let (hex, format) = split_format(resource);
+    let format = format.ok_or_else(format_not_found)?;
+    let hash = txid_to_hex(&parse_hash(hex)?);
+    let verbosity = match (format, tx_details) {
+        (Format::Json, true) => 2,
+        (Format::Json, false) => 1,
+        _ => 0,
+    };
+    let block = call(server, "getblock", json!([hash, verbosity]), hex)?;
+    match format {
+        Format::Json => Ok(reply(format, Vec::new(), || block)),
+        _ => Ok(reply(format, hex_bytes(&block), || Value::Null)),
+    }
+}
+
+// Headers of the active chain from the one given on, as
+// /rest/headers/<hash>.<ext>?count=<count> or the older
+// /rest/headers/<count>/<hash>.<ext>.
+fn headers(server: &RpcServer, resource: &str, query: &str) -> Result<HttpResponse, HttpResponse> {
+    let (count, resource) = match resource.split_once('/') {
+        Some((count, resource)) => (Some(count), resource),
+        None => (
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("count=")),
+            resource,
+        ),
+    };
+    let (hex, format) = split_format(resource);
+    let format = format.ok_or_else(format_not_found)?;
+    let count = match count {
+        Some(count) => count
+            .parse::<u32>()
+            .ok()
+            .filter(|count| (1..=MAX_REST_HEADERS_RESULTS).contains(count))
+            .ok_or_else(|| {
+                let range = format!("1-{MAX_REST_HEADERS_RESULTS}");
+                let message = format!(
+                    "Header count is invalid or out of acceptable range ({range}): {count}"
+                );
+                error(400, &message)
+            })?,
+        None => DEFAULT_HEADERS_COUNT,
+    };
+    let hash = parse_hash(hex)?;
+    let node = server.node();
+    let chain = node.chain();
+    let mut entries = Vec::new();
+    let mut next = chain.headers().get(&hash);
+    while let Some(entry) = next.filter(|_| entries.len() < count as usize) {
+        entries.push(entry);
+        next = match chain.is_active(&entry.hash) {
+            true => chain
+                .headers()
+                .at_height(entry.height + 1)
+                .filter(|next| chain.is_active(&next.hash)),
+            false => None,
+        };
+    }
+    let bytes = entries
+        .iter()
+        .flat_map(|entry| entry.header.serialize())
+        .collect();
+    Ok(reply(format, bytes, || {
+        Value::Array(
+            entries
+                .iter()
+                .map(|entry| Value::Object(header_json(&node, entry)))
+                .collect(),
+        )
+    }))
+}
+
+fn chain_info(server: &RpcServer, resource: &str) -> Result<HttpResponse, HttpResponse> {
+    if split_format(resource) != ("", Some(Format::Json)) {
+        return Err(error(404, "output format not found (available: json)"));
+    }
+    let info = call(server, "getblockchaininfo", Value::Null, "chaininfo")?;
+    Ok(reply(Format::Json, Vec::new(), || info))
+}
+
+#[cfg(test)]
+mod rest_tests {
+    use super::*;
+    use crate::block::BLOCK_HEADER_SIZE;
+    use crate::network::Network;
+    use crate::node::Node;
+    use crate::p2p::PeerManager;
+    use crate::rpc::RpcAuth;
+    use crate::test_util::temp_path;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    fn get(server: &RpcServer, path: &str) -> HttpResponse {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        respond(server, &request)
+    }
+
+    #[test]
+    fn test_rest_endpoints() {
+        let dir = temp_path("rest_tests");
+        let _ = fs::remove_dir_all(&dir);
+        let node = Node::open(Network::Regtest, &dir).unwrap();
+        let server = RpcServer::new(
+            Arc::new(Mutex::new(node)),
+            PeerManager::new(Network::Regtest),
+            RpcAuth::with_user("user", "pass"),
+        )
+        .with_rest();
+        let genesis = Network::Regtest.genesis_block();
+        let hash = genesis.header.hash_hex();
+
+        let response = get(&server, "/rest/chaininfo.json");
+        assert_eq!(response.status, 200);
+        let info: Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(info["chain"], json!("regtest"));
+        let response = get(&server, &format!("/rest/block/{hash}.bin"));
+        assert_eq!(response.body, genesis.serialize());
+        let response = get(&server, &format!("/rest/block/{hash}.hex"));
+        assert_eq!(
+            response.body,
+            format!("{}\n", hex::encode(genesis.serialize())).into_bytes()
+        );
+        let response = get(&server, &format!("/rest/block/notxdetails/{hash}.json"));
+        let block: Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(block["tx"], json!([genesis.transactions[0].txid_hex()]));
+
+        // Headers run up to the tip, whichever way the count is given.
+        let response = get(&server, &format!("/rest/headers/{hash}.bin?count=3"));
+        assert_eq!(response.body.len(), BLOCK_HEADER_SIZE);
+        let response = get(&server, &format!("/rest/headers/1/{hash}.json"));
+        let headers: Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(headers[0]["hash"], json!(hash));
+
+        let missing = "11".repeat(32);
+        let response = get(&server, &format!("/rest/tx/{missing}.hex"));
+        assert_eq!(response.status, 404);
+        assert_eq!(get(&server, "/rest/tx/1234.hex").status, 400);
+        assert_eq!(get(&server, &format!("/rest/block/{hash}.xml")).status, 404);
+        let response = get(&server, &format!("/rest/headers/{hash}.bin?count=2001"));
+        assert_eq!(response.status, 400);
+        assert_eq!(get(&server, "/rest/chaininfo.bin").status, 404);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
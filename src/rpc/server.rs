@@ -0,0 +1,1492 @@
+// The JSON-RPC server of the node, answering bitcoind's methods in its shapes so that
+// bitcoin-cli and other tooling work against it. Requests may be JSON-RPC 1.0, whose
+// errors set the HTTP status as in Bitcoin Core, or 2.0, always answered with 200 and
+// silent for notifications, alone or in batches. Parameters are positional or named.
+// Callers authenticate with HTTP basic auth, by user and password or with the cookie
+// written to the data directory. The REST endpoints of the rest module, when
+// enabled, are served alongside without authentication.
+use super::http::{read_request, write_response, HttpRequest, HttpResponse};
+use super::rest;
+use crate::address::script_from_address;
+use crate::block::{bits_to_target, Block};
+use crate::chain::{AddressIndex, HeaderEntry};
+use crate::descriptor::Descriptor;
+use crate::helper::{base64_decode, random_bytes};
+use crate::mempool::{signals_rbf, MAX_PACKAGE_COUNT};
+use crate::mining::{BlockAssembler, CpuMiner, DEFAULT_MAX_TRIES};
+use crate::network::Network;
+use crate::node::{apply, Node};
+use crate::p2p::PeerManager;
+use crate::policy::FeeRate;
+use crate::transaction::{txid_from_hex, txid_to_hex, OutPoint, Transaction};
+use crate::types::errors::{Errors, MempoolError, RpcError};
+use crate::validation::{UtxoView, COIN, MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_WEIGHT};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tracing::{debug, info_span, warn, Instrument};
+
+pub const COOKIE_USER: &str = "__cookie__";
+pub const COOKIE_FILE: &str = ".cookie";
+// As Bitcoin Core's DEFAULT_MAX_RAW_TX_FEE_RATE, in sat/kvB.
+pub const DEFAULT_MAX_RAW_TX_FEE_RATE: u64 = 10_000_000;
+// Wait before answering a failed login, slowing guesses down.
+const AUTH_FAILURE_DELAY: Duration = Duration::from_millis(250);
+
+// Named parameters of each method, in their positional order. Names after a '|'
+// are aliases.
+const METHODS: &[(&str, &[&str])] = &[
+    ("generatetoaddress", &["nblocks", "address", "maxtries"]),
+    ("getbestblockhash", &[]),
+    ("getaddressbalance", &["addresses"]),
+    ("getaddresstxids", &["addresses"]),
+    ("getblock", &["blockhash", "verbosity|verbose"]),
+    ("getblockchaininfo", &[]),
+    ("getblockcount", &[]),
+    ("getblockhash", &["height"]),
+    ("getblocktemplate", &["template_request"]),
+    ("getconnectioncount", &[]),
+    ("getrawmempool", &["verbose"]),
+    (
+        "getrawtransaction",
+        &["txid", "verbosity|verbose", "blockhash"],
+    ),
+    ("prioritisetransaction", &["txid", "dummy", "fee_delta"]),
+    ("scantxoutset", &["action", "scanobjects"]),
+    ("sendrawtransaction", &["hexstring", "maxfeerate"]),
+    ("submitblock", &["hexdata", "dummy"]),
+    ("submitpackage", &["package"]),
+    ("stop", &[]),
+];
+
+impl RpcError {
+    pub fn code(&self) -> i32 {
+        match self {
+            RpcError::Parse => -32700,
+            RpcError::InvalidRequest(_) => -32600,
+            RpcError::MethodNotFound => -32601,
+            RpcError::Type(_) => -3,
+            RpcError::InvalidParameter(_) => -8,
+            RpcError::InvalidAddressOrKey(_) => -5,
+            RpcError::Deserialization(_) => -22,
+            RpcError::Transaction(_) => -25,
+            RpcError::VerifyRejected(_) => -26,
+            RpcError::VerifyAlreadyInChain(_) => -27,
+            RpcError::Misc(_) => -1,
+            RpcError::ClientNotConnected(_) => -9,
+            RpcError::ClientInInitialDownload(_) => -10,
+            RpcError::Other(code, _) => *code,
+        }
+    }
+
+    // The error a server answered with code and message.
+    pub fn from_code(code: i32, message: String) -> Self {
+        match code {
+            -32700 => RpcError::Parse,
+            -32600 => RpcError::InvalidRequest(message),
+            -32601 => RpcError::MethodNotFound,
+            -3 => RpcError::Type(message),
+            -8 => RpcError::InvalidParameter(message),
+            -5 => RpcError::InvalidAddressOrKey(message),
+            -22 => RpcError::Deserialization(message),
+            -25 => RpcError::Transaction(message),
+            -26 => RpcError::VerifyRejected(message),
+            -27 => RpcError::VerifyAlreadyInChain(message),
+            -1 => RpcError::Misc(message),
+            -9 => RpcError::ClientNotConnected(message),
+            -10 => RpcError::ClientInInitialDownload(message),
+            code => RpcError::Other(code, message),
+        }
+    }
+
+    // The HTTP status of a JSON-RPC 1.0 reply carrying the error.
+    fn status(&self) -> u16 {
+        match self {
+            RpcError::InvalidRequest(_) => 400,
+            RpcError::MethodNotFound => 404,
+            _ => 500,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcAuth {
+    user: String,
+    password: String,
+    // Where the cookie was written, for it to be removed on shutdown.
+    cookie: Option<PathBuf>,
+}
+
+impl RpcAuth {
+    pub fn with_user(user: &str, password: &str) -> Self {
+        RpcAuth {
+            user: user.to_string(),
+            password: password.to_string(),
+            cookie: None,
+        }
+    }
+
+    // A fresh password for __cookie__ of 32 bytes from the operating system, written
+    // to the cookie file of datadir as bitcoind does for bitcoin-cli to read.
+    pub fn cookie(datadir: impl AsRef<Path>) -> Result<Self, Errors> {
+        let password = hex::encode(random_bytes::<32>()?);
+        let path = datadir.as_ref().join(COOKIE_FILE);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, format!("{COOKIE_USER}:{password}"))
+            .and_then(|_| fs::rename(&temp_path, &path))
+            .map_err(|e| Errors::Io(e.to_string()))?;
+        Ok(RpcAuth {
+            user: COOKIE_USER.to_string(),
+            password,
+            cookie: Some(path),
+        })
+    }
+
+    pub fn remove_cookie(&self) {
+        if let Some(path) = &self.cookie {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    // Whether the Authorization header of a request carries the credentials.
+    pub fn check(&self, authorization: Option<&str>) -> bool {
+        let Some(encoded) = authorization.and_then(|value| value.strip_prefix("Basic ")) else {
+            return false;
+        };
+        let Some(decoded) = base64_decode(encoded.trim()) else {
+            return false;
+        };
+        let expected = format!("{}:{}", self.user, self.password);
+        // Compared in full whatever the first difference, leaking nothing through
+        // timing but the length.
+        decoded.len() == expected.len()
+            && decoded
+                .iter()
+                .zip(expected.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Version {
+    // JSON-RPC 1.0, and 1.1 which bitcoin-cli sends.
+    Legacy,
+    V2,
+}
+
+fn reply(version: Version, id: Value, result: Result<Value, RpcError>) -> Value {
+    match (version, result) {
+        (Version::Legacy, Ok(result)) => json!({ "result": result, "error": null, "id": id }),
+        (Version::Legacy, Err(e)) => json!({ "result": null, "error": e.to_json(), "id": id }),
+        (Version::V2, Ok(result)) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        (Version::V2, Err(e)) => json!({ "jsonrpc": "2.0", "error": e.to_json(), "id": id }),
+    }
+}
+
+// Positional parameters of method from an array, or from an object by name.
+// Parameters not given are null.
+fn method_params(method: &str, params: &Value) -> Result<Vec<Value>, RpcError> {
+    let names = METHODS
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, names)| *names)
+        .ok_or(RpcError::MethodNotFound)?;
+    let mut values = match params {
+        Value::Null => Vec::new(),
+        Value::Array(values) => {
+            if values.len() > names.len() {
+                return Err(RpcError::Misc(format!(
+                    "{method} takes at most {} parameters",
+                    names.len()
+                )));
+            }
+            values.clone()
+        }
+        Value::Object(named) => {
+            let mut values = vec![Value::Null; names.len()];
+            for (key, value) in named {
+                let index = names
+                    .iter()
+                    .position(|name| name.split('|').any(|name| name == key))
+                    .ok_or_else(|| {
+                        RpcError::InvalidParameter(format!("Unknown named parameter {key}"))
+                    })?;
+                if !values[index].is_null() {
+                    return Err(RpcError::InvalidParameter(format!(
+                        "Parameter {key} specified twice"
+                    )));
+                }
+                values[index] = value.clone();
+            }
+            values
+        }
+        _ => {
+            return Err(RpcError::InvalidRequest(
+                "Params must be an array or object".to_string(),
+            ))
+        }
+    };
+    values.resize(names.len(), Value::Null);
+    Ok(values)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn type_error(value: &Value, expected: &str) -> RpcError {
+    RpcError::Type(format!(
+        "JSON value of type {} is not of expected type {expected}",
+        type_name(value)
+    ))
+}
+
+fn required<'a>(value: &'a Value, name: &str) -> Result<&'a Value, RpcError> {
+    match value {
+        Value::Null => Err(RpcError::Misc(format!("Missing required parameter {name}"))),
+        value => Ok(value),
+    }
+}
+
+fn string_param<'a>(value: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    let value = required(value, name)?;
+    value.as_str().ok_or_else(|| type_error(value, "string"))
+}
+
+fn int_param(value: &Value) -> Result<i64, RpcError> {
+    value.as_i64().ok_or_else(|| type_error(value, "number"))
+}
+
+// Verbosity as a number, or as a bool for the older verbose flag.
+fn verbosity_param(value: &Value, default: i64) -> Result<i64, RpcError> {
+    match value {
+        Value::Null => Ok(default),
+        Value::Bool(verbose) => Ok(*verbose as i64),
+        value => int_param(value),
+    }
+}
+
+fn bool_param(value: &Value) -> Result<bool, RpcError> {
+    match value {
+        Value::Null => Ok(false),
+        value => value.as_bool().ok_or_else(|| type_error(value, "bool")),
+    }
+}
+
+// A block hash or txid in its displayed hex.
+fn hash_param(value: &Value, name: &str) -> Result<[u8; 32], RpcError> {
+    let hex = string_param(value, name)?;
+    if hex.len() != 64 {
+        return Err(RpcError::InvalidParameter(format!(
+            "{name} must be of length 64 (not {}, for '{hex}')",
+            hex.len()
+        )));
+    }
+    txid_from_hex(hex).map_err(|_| {
+        RpcError::InvalidParameter(format!("{name} must be hexadecimal string (not '{hex}')"))
+    })
+}
+
+// An amount in BTC, as a number or a string, in satoshis.
+fn amount_param(value: &Value) -> Result<u64, RpcError> {
+    let amount = match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.parse::<f64>().ok(),
+        value => return Err(type_error(value, "number")),
+    };
+    match amount {
+        Some(amount) if (0.0..=21e6).contains(&amount) => Ok((amount * COIN as f64).round() as u64),
+        _ => Err(RpcError::Type("Invalid amount".to_string())),
+    }
+}
+
+fn btc(sats: u64) -> Value {
+    json!(sats as f64 / COIN as f64)
+}
+
+fn block_not_found() -> RpcError {
+    RpcError::InvalidAddressOrKey("Block not found".to_string())
+}
+
+pub struct RpcServer {
+    node: Arc<Mutex<Node>>,
+    manager: PeerManager,
+    auth: RpcAuth,
+    stop: Notify,
+    rest: bool,
+}
+
+impl RpcServer {
+    pub fn new(node: Arc<Mutex<Node>>, manager: PeerManager, auth: RpcAuth) -> Self {
+        RpcServer {
+            node,
+            manager,
+            auth,
+            stop: Notify::new(),
+            rest: false,
+        }
+    }
+
+    // Serves the REST endpoints under /rest/ too, to anyone.
+    pub fn with_rest(mut self) -> Self {
+        self.rest = true;
+        self
+    }
+
+    pub fn auth(&self) -> &RpcAuth {
+        &self.auth
+    }
+
+    // Completes once the stop method was called.
+    pub async fn stopped(&self) {
+        self.stop.notified().await
+    }
+
+    // Answers the body of a request: its HTTP status and the JSON of the reply, empty
+    // when there is none to give.
+    pub fn handle(&self, body: &[u8]) -> (u16, Vec<u8>) {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(_) => {
+                let error = reply(Version::Legacy, Value::Null, Err(RpcError::Parse));
+                return (500, format!("{error}\n").into_bytes());
+            }
+        };
+        let (status, response) = match &request {
+            Value::Array(batch) => {
+                let replies: Vec<Value> = batch
+                    .iter()
+                    .filter_map(|request| self.handle_request(request).1)
+                    .collect();
+                match replies.is_empty() && !batch.is_empty() {
+                    true => (204, None),
+                    false => (200, Some(Value::Array(replies))),
+                }
+            }
+            request => match self.handle_request(request) {
+                (_, None) => (204, None),
+                (status, Some(response)) => (status, Some(response)),
+            },
+        };
+        match response {
+            Some(response) => (status, format!("{response}\n").into_bytes()),
+            None => (status, Vec::new()),
+        }
+    }
+
+    // The status and reply to a single request, no reply for notifications.
+    fn handle_request(&self, request: &Value) -> (u16, Option<Value>) {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let version = match request.get("jsonrpc").and_then(Value::as_str) {
+            Some("2.0") => Version::V2,
+            Some("1.0") | Some("1.1") | None => Version::Legacy,
+            Some(_) => {
+                let error = RpcError::InvalidRequest("JSON-RPC version not supported".into());
+                return (error.status(), Some(reply(Version::Legacy, id, Err(error))));
+            }
+        };
+        let notification = version == Version::V2 && request.get("id").is_none();
+        let result = match (request.is_object(), request.get("method")) {
+            (false, _) => Err(RpcError::InvalidRequest(
+                "Invalid Request object".to_string(),
+            )),
+            (true, None) => Err(RpcError::InvalidRequest("Missing method".to_string())),
+            (true, Some(Value::String(method))) => {
+                let params = request.get("params").unwrap_or(&Value::Null);
+                self.call_method(method, params)
+            }
+            (true, Some(_)) => Err(RpcError::InvalidRequest(
+                "Method must be a string".to_string(),
+            )),
+        };
+        if notification {
+            return (204, None);
+        }
+        let status = match (&result, version) {
+            (Err(e), Version::Legacy) => e.status(),
+            _ => 200,
+        };
+        (status, Some(reply(version, id, result)))
+    }
+
+    // Calls method as a request would, with params positional or named.
+    pub(super) fn call_method(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        debug!(target: "rpc", method, "RPC call");
+        let result = method_params(method, params).and_then(|params| self.call(method, &params));
+        if let Err(e) = &result {
+            debug!(target: "rpc", method, code = e.code(), error = %e, "RPC call failed");
+        }
+        result
+    }
+
+    fn call(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "generatetoaddress" => self.generate_to_address(params),
+            "getaddressbalance" => self.get_address_balance(params),
+            "getaddresstxids" => self.get_address_txids(params),
+            "getbestblockhash" => Ok(json!(txid_to_hex(&self.node().chain().tip().hash))),
+            "getblock" => self.get_block(params),
+            "getblockchaininfo" => Ok(self.get_blockchain_info()),
+            "getblockcount" => Ok(json!(self.node().chain().height())),
+            "getblockhash" => self.get_block_hash(params),
+            "getblocktemplate" => self.get_block_template(params),
+            "getconnectioncount" => Ok(json!(self.manager.peers().len())),
+            "getrawmempool" => self.get_raw_mempool(params),
+            "getrawtransaction" => self.get_raw_transaction(params),
+            "prioritisetransaction" => self.prioritise_transaction(params),
+            "scantxoutset" => self.scan_tx_out_set(params),
+            "sendrawtransaction" => self.send_raw_transaction(params),
+            "submitblock" => self.submit_block(params),
+            "submitpackage" => self.submit_package(params),
+            "stop" => {
+                self.stop.notify_one();
+                Ok(json!("Bitcoin server stopping"))
+            }
+            _ => Err(RpcError::MethodNotFound),
+        }
+    }
+
+    pub(super) fn node(&self) -> std::sync::MutexGuard<'_, Node> {
+        self.node.lock().unwrap()
+    }
+
+    fn get_block_hash(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let height = int_param(required(&params[0], "height")?)?;
+        let node = self.node();
+        u32::try_from(height)
+            .ok()
+            .and_then(|height| node.chain().headers().at_height(height))
+            .filter(|entry| entry.height <= node.chain().height())
+            .map(|entry| json!(txid_to_hex(&entry.hash)))
+            .ok_or_else(|| RpcError::InvalidParameter("Block height out of range".to_string()))
+    }
+
+    fn get_block(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let hash = hash_param(&params[0], "blockhash")?;
+        let verbosity = verbosity_param(&params[1], 1)?;
+        let node = self.node();
+        let entry = node
+            .chain()
+            .headers()
+            .get(&hash)
+            .ok_or_else(block_not_found)?;
+        let block = node
+            .chain()
+            .get_block(&hash)
+            .map_err(|e| RpcError::Misc(e.to_string()))?
+            .ok_or_else(|| RpcError::Misc("Block not available (pruned data)".to_string()))?;
+        if verbosity <= 0 {
+            return Ok(json!(hex::encode(block.serialize())));
+        }
+        let network = node.network();
+        let tx: Vec<Value> = block
+            .transactions
+            .iter()
+            .map(|tx| match verbosity {
+                1 => json!(tx.txid_hex()),
+                _ => with_hex(tx.to_json(network), tx),
+            })
+            .collect();
+        let mut result = header_json(&node, entry);
+        result.insert("size".into(), json!(block.total_size()));
+        result.insert("strippedsize".into(), json!(block.stripped_size()));
+        result.insert("weight".into(), json!(block.weight()));
+        result.insert("tx".into(), json!(tx));
+        Ok(Value::Object(order_block_json(result)))
+    }
+
+    fn get_blockchain_info(&self) -> Value {
+        let node = self.node();
+        let chain = node.chain();
+        let tip = chain.tip();
+        let headers = chain.headers().height();
+        json!({
+            "chain": node.network().name(),
+            "blocks": tip.height,
+            "headers": headers,
+            "bestblockhash": txid_to_hex(&tip.hash),
+            "difficulty": tip.header.difficulty(),
+            "time": tip.header.timestamp,
+            "mediantime": chain.median_time_past(),
+            "verificationprogress": match headers {
+                0 => 1.0,
+                headers => tip.height as f64 / headers as f64,
+            },
+            "initialblockdownload": node.is_syncing(),
+            "chainwork": format!("{:064x}", tip.chain_work),
+            "pruned": false,
+            "warnings": "",
+        })
+    }
+
+    // BIP22 and BIP23 templates, which callers must ask for with the segwit rule as
+    // in Bitcoin Core. Block proposals and long polling aren't supported.
+    fn get_block_template(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let request = match &params[0] {
+            Value::Null => Map::new(),
+            Value::Object(request) => request.clone(),
+            value => return Err(type_error(value, "object")),
+        };
+        match request.get("mode") {
+            None => {}
+            Some(Value::String(mode)) if mode == "template" => {}
+            Some(_) => return Err(RpcError::InvalidParameter("Invalid mode".to_string())),
+        }
+        let rules = request.get("rules").and_then(Value::as_array);
+        if !rules.is_some_and(|rules| rules.iter().any(|rule| rule == "segwit")) {
+            return Err(RpcError::InvalidParameter(
+                "getblocktemplate must be called with the segwit rule set (call with \
+                 {\"rules\": [\"segwit\"]})"
+                    .to_string(),
+            ));
+        }
+        let node = self.node();
+        let network = node.network();
+        if network == Network::Mainnet {
+            if self.manager.peers().is_empty() {
+                return Err(RpcError::ClientNotConnected(
+                    "Bitcoin is not connected!".to_string(),
+                ));
+            }
+            if node.is_syncing() {
+                return Err(RpcError::ClientInInitialDownload(
+                    "Bitcoin is in initial sync and waiting for blocks...".to_string(),
+                ));
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as u32);
+        let template = BlockAssembler::new().create(node.chain(), node.mempool(), now);
+        let transactions: Vec<Value> = template
+            .transactions
+            .iter()
+            .map(|tx| {
+                json!({
+                    "data": hex::encode(tx.tx.serialize()),
+                    "txid": tx.tx.txid_hex(),
+                    "hash": txid_to_hex(&tx.tx.wtxid()),
+                    "depends": tx.depends.iter().map(|i| i + 1).collect::<Vec<_>>(),
+                    "fee": tx.fee,
+                    "sigops": tx.sigop_cost,
+                    "weight": tx.tx.weight(),
+                })
+            })
+            .collect();
+        let mut rules = Vec::new();
+        if template.height >= network.csv_height() {
+            rules.push("csv");
+        }
+        if template.witness_commitment.is_some() {
+            rules.push("!segwit");
+        }
+        rules.push("taproot");
+        let header = &template.header;
+        let target = bits_to_target(header.bits).map_err(|e| RpcError::Misc(e.to_string()))?;
+        let mut result = json!({
+            "version": header.version,
+            "rules": rules,
+            "vbavailable": {},
+            "vbrequired": 0,
+            "previousblockhash": txid_to_hex(&header.prev_block),
+            "transactions": transactions,
+            "coinbaseaux": {},
+            "coinbasevalue": template.coinbase_value,
+            "target": format!("{target:064x}"),
+            "mintime": template.min_time,
+            "mutable": ["time", "transactions", "prevblock"],
+            "noncerange": "00000000ffffffff",
+            "sigoplimit": MAX_BLOCK_SIGOPS_COST,
+            "sizelimit": MAX_BLOCK_WEIGHT,
+            "weightlimit": MAX_BLOCK_WEIGHT,
+            "curtime": header.timestamp,
+            "bits": format!("{:08x}", header.bits),
+            "height": template.height,
+        });
+        if let Some(commitment) = &template.witness_commitment {
+            let commitment = hex::encode(commitment.script_pubkey.as_bytes());
+            result["default_witness_commitment"] = json!(commitment);
+        }
+        Ok(result)
+    }
+
+    // Mines nblocks on the tip paying address, with the transactions of the mempool,
+    // trying maxtries hashes over all of them. Only on regtest, where a block takes
+    // a couple. The hashes of the blocks mined, fewer if the tries ran out.
+    fn generate_to_address(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let nblocks = int_param(required(&params[0], "nblocks")?)?;
+        let address = string_param(&params[1], "address")?;
+        let max_tries = match &params[2] {
+            Value::Null => DEFAULT_MAX_TRIES,
+            value => u64::try_from(int_param(value)?).map_err(|_| {
+                RpcError::InvalidParameter("maxtries must be non-negative".to_string())
+            })?,
+        };
+        let nblocks = u32::try_from(nblocks)
+            .map_err(|_| RpcError::InvalidParameter("nblocks must be non-negative".to_string()))?;
+        let mut node = self.node();
+        if node.network() != Network::Regtest {
+            return Err(RpcError::Misc(
+                "generatetoaddress is only available on regtest".to_string(),
+            ));
+        }
+        let script = script_from_address(address, node.network())
+            .ok_or_else(|| RpcError::InvalidAddressOrKey("Error: Invalid address".to_string()))?;
+        let miner = CpuMiner::new(script.into()).with_max_tries(max_tries);
+        let (hashes, actions) = miner
+            .generate(&mut node, nblocks)
+            .map_err(|e| RpcError::Misc(e.to_string()))?;
+        drop(node);
+        apply(&self.manager, actions);
+        let hashes: Vec<String> = hashes.iter().map(txid_to_hex).collect();
+        Ok(json!(hashes))
+    }
+
+    // Null once the block is connected, otherwise why not as BIP22 has it.
+    fn submit_block(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let hex = string_param(&params[0], "hexdata")?;
+        let block = Block::from_hex(hex)
+            .map_err(|_| RpcError::Deserialization("Block decode failed".to_string()))?;
+        if !block.coinbase().is_some_and(Transaction::is_coinbase) {
+            return Err(RpcError::Deserialization(
+                "Block does not start with a coinbase".to_string(),
+            ));
+        }
+        let hash = block.hash();
+        let mut node = self.node();
+        if node.chain().is_invalid(&hash) {
+            return Ok(json!("duplicate-invalid"));
+        }
+        if node.chain().is_active(&hash) {
+            return Ok(json!("duplicate"));
+        }
+        let actions = match node.submit_block(block) {
+            Ok(actions) => actions,
+            Err(e) => return Ok(json!(e.to_string())),
+        };
+        let connected = node.chain().is_active(&hash);
+        drop(node);
+        apply(&self.manager, actions);
+        match connected {
+            true => Ok(Value::Null),
+            false => Ok(json!("inconclusive")),
+        }
+    }
+
+    fn get_raw_mempool(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let verbose = bool_param(&params[0])?;
+        let node = self.node();
+        let mempool = node.mempool();
+        let mut txids: Vec<[u8; 32]> = mempool.transactions().map(Transaction::txid).collect();
+        txids.sort();
+        if !verbose {
+            let txids: Vec<String> = txids.iter().map(txid_to_hex).collect();
+            return Ok(json!(txids));
+        }
+        let mut result = Map::new();
+        for txid in txids {
+            let entry = mempool.get(&txid).unwrap();
+            let ancestors = mempool.ancestors(&txid);
+            let mut depends: Vec<[u8; 32]> = entry
+                .tx
+                .inputs
+                .iter()
+                .map(|input| input.previous_output.txid)
+                .filter(|parent| mempool.contains(parent))
+                .collect();
+            depends.sort();
+            depends.dedup();
+            let mut spent_by: Vec<[u8; 32]> = (0..entry.tx.outputs.len() as u32)
+                .filter_map(|vout| mempool.spender(&OutPoint::new(txid, vout)))
+                .collect();
+            spent_by.sort();
+            spent_by.dedup();
+            let replaceable = signals_rbf(&entry.tx)
+                || ancestors
+                    .iter()
+                    .any(|ancestor| signals_rbf(&mempool.get(ancestor).unwrap().tx));
+            result.insert(
+                txid_to_hex(&txid),
+                json!({
+                    "vsize": entry.vsize,
+                    "weight": entry.tx.weight(),
+                    "time": entry.time,
+                    "descendantcount": mempool.descendants(&txid).len() + 1,
+                    "ancestorcount": ancestors.len() + 1,
+                    "wtxid": txid_to_hex(&entry.tx.wtxid()),
+                    "fees": {
+                        "base": btc(entry.fee),
+                        "modified": btc(mempool.modified_fee(&txid, entry.fee)),
+                    },
+                    "depends": depends.iter().map(txid_to_hex).collect::<Vec<_>>(),
+                    "spentby": spent_by.iter().map(txid_to_hex).collect::<Vec<_>>(),
+                    "bip125-replaceable": replaceable,
+                }),
+            );
+        }
+        Ok(Value::Object(result))
+    }
+
+    // From the mempool, the transaction index if kept, or the block given.
+    fn get_raw_transaction(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let txid = hash_param(&params[0], "txid")?;
+        let verbosity = verbosity_param(&params[1], 0)?;
+        let node = self.node();
+        let (tx, entry) = match &params[2] {
+            Value::Null => match node.mempool().get(&txid) {
+                Some(entry) => (entry.tx.clone(), None),
+                None => {
+                    let (tx, entry) = indexed_transaction(&node, &txid)?;
+                    (tx, Some(entry))
+                }
+            },
+            blockhash => {
+                let hash = hash_param(blockhash, "blockhash")?;
+                let entry = node.chain().headers().get(&hash).ok_or_else(|| {
+                    RpcError::InvalidAddressOrKey("Block hash not found".to_string())
+                })?;
+                let block: Option<Block> = node
+                    .chain()
+                    .get_block(&hash)
+                    .map_err(|e| RpcError::Misc(e.to_string()))?;
+                let tx = block
+                    .and_then(|block| block.transactions.into_iter().find(|tx| tx.txid() == txid))
+                    .ok_or_else(|| {
+                        RpcError::InvalidAddressOrKey(
+                            "No such transaction found in the provided block. Use \
+                             gettransaction for wallet transactions."
+                                .to_string(),
+                        )
+                    })?;
+                (tx, Some(entry))
+            }
+        };
+        if verbosity <= 0 {
+            return Ok(json!(hex::encode(tx.serialize())));
+        }
+        let mut result = with_hex(tx.to_json(node.network()), &tx);
+        if let (Some(entry), Value::Object(fields)) = (entry, &mut result) {
+            // Only a block given may be off the active chain.
+            if !params[2].is_null() {
+                let active = node.chain().is_active(&entry.hash);
+                fields.insert("in_active_chain".into(), json!(active));
+            }
+            fields.insert("blockhash".into(), json!(txid_to_hex(&entry.hash)));
+            fields.insert("confirmations".into(), json!(confirmations(&node, entry)));
+            fields.insert("time".into(), json!(entry.header.timestamp));
+            fields.insert("blocktime".into(), json!(entry.header.timestamp));
+        }
+        Ok(result)
+    }
+
+    // In satoshis, as the address index of forks of Bitcoin Core answers.
+    fn get_address_balance(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let node = self.node();
+        let index = address_index(&node)?;
+        let (mut received, mut balance) = (0, 0);
+        for script in address_scripts(&node, &params[0])? {
+            let (script_received, script_balance) = index.balance(&script);
+            received += script_received;
+            balance += script_balance;
+        }
+        Ok(json!({"balance": balance, "received": received}))
+    }
+
+    // Transactions paying or spending any of the addresses, oldest first.
+    fn get_address_txids(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let node = self.node();
+        let index = address_index(&node)?;
+        let mut history = Vec::new();
+        for script in address_scripts(&node, &params[0])? {
+            history.extend(index.history(&script));
+        }
+        history.sort_by_key(|entry| entry.height);
+        let mut seen = HashSet::new();
+        let txids: Vec<String> = history
+            .iter()
+            .filter(|entry| seen.insert(entry.txid))
+            .map(|entry| txid_to_hex(&entry.txid))
+            .collect();
+        Ok(json!(txids))
+    }
+
+    // Coins of the UTXO set paying the descriptors of scanobjects, each a string or
+    // an object with its "desc". Scans run to completion within the call, so there
+    // is never one to abort or report the status of.
+    fn scan_tx_out_set(&self, params: &[Value]) -> Result<Value, RpcError> {
+        match string_param(&params[0], "action")? {
+            "start" => {}
+            "abort" => return Ok(json!(false)),
+            "status" => return Ok(Value::Null),
+            action => {
+                return Err(RpcError::InvalidParameter(format!(
+                    "Invalid action '{action}'"
+                )))
+            }
+        }
+        let objects = required(&params[1], "scanobjects")?;
+        let objects = objects
+            .as_array()
+            .ok_or_else(|| type_error(objects, "array"))?;
+        let node = self.node();
+        let mut descriptors = Vec::new();
+        for object in objects {
+            let text = match object {
+                Value::Object(fields) => {
+                    string_param(fields.get("desc").unwrap_or(&Value::Null), "desc")?
+                }
+                object => string_param(object, "scanobject")?,
+            };
+            let descriptor = Descriptor::parse(text, node.network())
+                .map_err(|e| RpcError::InvalidAddressOrKey(e.to_string()))?;
+            descriptors.push(descriptor);
+        }
+        let scripts: HashSet<&[u8]> = descriptors
+            .iter()
+            .flat_map(Descriptor::script_pubkeys)
+            .map(|script| script.as_bytes())
+            .collect();
+        let chain = node.chain();
+        let (count, coins) = chain.utxos().scan(&scripts);
+        let mut total = 0;
+        let unspents: Vec<Value> = coins
+            .iter()
+            .map(|(outpoint, coin)| {
+                total += coin.output.value;
+                let script = &coin.output.script_pubkey;
+                let descriptor = descriptors
+                    .iter()
+                    .find(|descriptor| descriptor.script_pubkeys().contains(script))
+                    .unwrap();
+                json!({
+                    "txid": txid_to_hex(&outpoint.txid),
+                    "vout": outpoint.vout,
+                    "scriptPubKey": hex::encode(script.as_bytes()),
+                    "desc": descriptor.to_string(),
+                    "amount": btc(coin.output.value),
+                    "coinbase": coin.is_coinbase,
+                    "height": coin.height,
+                    "blockhash": txid_to_hex(&chain.headers().at_height(coin.height).unwrap().hash),
+                    "confirmations": chain.height() - coin.height + 1,
+                })
+            })
+            .collect();
+        Ok(json!({
+            "success": true,
+            "txouts": count,
+            "height": chain.height(),
+            "bestblock": txid_to_hex(&chain.tip().hash),
+            "unspents": unspents,
+            "total_amount": btc(total),
+        }))
+    }
+
+    // Fee deltas are in satoshis, the dummy left over from priority must be zero.
+    fn prioritise_transaction(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let txid = hash_param(&params[0], "txid")?;
+        if !matches!(&params[1], Value::Null) && int_param(&params[1])? != 0 {
+            return Err(RpcError::InvalidParameter(
+                "Priority is no longer supported, dummy argument to prioritisetransaction must be 0."
+                    .to_string(),
+            ));
+        }
+        let delta = int_param(required(&params[2], "fee_delta")?)?;
+        self.node().prioritise_transaction(txid, delta);
+        Ok(json!(true))
+    }
+
+    fn send_raw_transaction(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let hex = string_param(&params[0], "hexstring")?;
+        let tx = Transaction::from_hex(hex).map_err(|_| {
+            RpcError::Deserialization(
+                "TX decode failed. Make sure the tx has at least one input.".to_string(),
+            )
+        })?;
+        let max_feerate = match &params[1] {
+            Value::Null => FeeRate::from_sat_per_kvb(DEFAULT_MAX_RAW_TX_FEE_RATE),
+            value => FeeRate::from_sat_per_kvb(amount_param(value)?),
+        };
+        let txid = tx.txid();
+        let mut node = self.node();
+        if node.mempool().contains(&txid) {
+            return Ok(json!(txid_to_hex(&txid)));
+        }
+        let utxos = node.chain().utxos();
+        if (0..tx.outputs.len() as u32)
+            .any(|vout| utxos.get_coin(&OutPoint::new(txid, vout)).is_some())
+        {
+            return Err(RpcError::VerifyAlreadyInChain(
+                "Transaction outputs already in utxo set".to_string(),
+            ));
+        }
+        if max_feerate.sat_per_kvb() > 0 {
+            if let Some(fee) = fee(&node, &tx) {
+                if fee > max_feerate.fee_for_vsize(tx.vsize()) {
+                    return Err(RpcError::Transaction(
+                        "Fee exceeds maximum configured by user (e.g. -maxtxfee, maxfeerate)"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+        let actions = match node.submit_transaction(tx) {
+            Ok(actions) => actions,
+            Err(MempoolError::AlreadyInMempool) => Vec::new(),
+            Err(e) => return Err(RpcError::VerifyRejected(e.to_string())),
+        };
+        drop(node);
+        apply(&self.manager, actions);
+        Ok(json!(txid_to_hex(&txid)))
+    }
+
+    // Submits a child with its parents, sorted, as a package its transactions are
+    // accepted by together or not at all. Rejections by the mempool are reported in
+    // package_msg, as Bitcoin Core does.
+    fn submit_package(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let package = required(&params[0], "package")?;
+        let package = package
+            .as_array()
+            .ok_or_else(|| type_error(package, "array"))?;
+        if package.is_empty() || package.len() > MAX_PACKAGE_COUNT {
+            return Err(RpcError::InvalidParameter(format!(
+                "Array must contain between 1 and {MAX_PACKAGE_COUNT} transactions."
+            )));
+        }
+        let mut txs = Vec::new();
+        for hex in package {
+            let tx = Transaction::from_hex(string_param(hex, "package")?).map_err(|_| {
+                RpcError::Deserialization(format!(
+                    "TX decode failed: {hex} Make sure the tx has at least one input."
+                ))
+            })?;
+            txs.push(tx);
+        }
+        let mut node = self.node();
+        let actions = match node.submit_package(txs.clone()) {
+            Ok(actions) => actions,
+            Err(e @ MempoolError::InvalidPackage(_)) => {
+                return Err(RpcError::Transaction(e.to_string()))
+            }
+            Err(e) => return Ok(json!({"package_msg": e.to_string(), "tx-results": {}})),
+        };
+        let mut results = Map::new();
+        for tx in &txs {
+            if let Some(entry) = node.mempool().get(&tx.txid()) {
+                results.insert(
+                    txid_to_hex(&tx.wtxid()),
+                    json!({
+                        "txid": tx.txid_hex(),
+                        "vsize": entry.vsize,
+                        "fees": {"base": btc(entry.fee)},
+                    }),
+                );
+            }
+        }
+        drop(node);
+        apply(&self.manager, actions);
+        Ok(json!({
+            "package_msg": "success",
+            "tx-results": results,
+            "replaced-transactions": [],
+        }))
+    }
+}
+
+// What tx pays, if its inputs are all in the chain or the mempool.
+fn fee(node: &Node, tx: &Transaction) -> Option<u64> {
+    let mut value = 0u64;
+    for input in &tx.inputs {
+        let outpoint = &input.previous_output;
+        value += match node.mempool().get(&outpoint.txid) {
+            Some(entry) => entry.tx.outputs.get(outpoint.vout as usize)?.value,
+            None => node.chain().utxos().get_coin(outpoint)?.output.value,
+        };
+    }
+    let spent: u64 = tx.outputs.iter().map(|output| output.value).sum();
+    value.checked_sub(spent)
+}
+
+fn with_hex(mut json: Value, tx: &Transaction) -> Value {
+    if let Value::Object(fields) = &mut json {
+        fields.insert("hex".into(), json!(hex::encode(tx.serialize())));
+    }
+    json
+}
+
+// As Bitcoin Core: -1 for blocks off the active chain.
+fn address_index(node: &Node) -> Result<&AddressIndex, RpcError> {
+    node.chain()
+        .address_index()
+        .ok_or_else(|| RpcError::Misc("Address index not enabled. Use -addressindex".to_string()))
+}
+
+// Scripts of an address, a list of them, or an object with the list as addresses.
+fn address_scripts(node: &Node, value: &Value) -> Result<Vec<Vec<u8>>, RpcError> {
+    let value = required(value, "addresses")?;
+    let addresses = match value {
+        Value::String(_) => std::slice::from_ref(value),
+        Value::Array(addresses) => addresses.as_slice(),
+        Value::Object(fields) => match fields.get("addresses") {
+            Some(Value::Array(addresses)) => addresses.as_slice(),
+            _ => return Err(type_error(value, "array")),
+        },
+        value => return Err(type_error(value, "array")),
+    };
+    addresses
+        .iter()
+        .map(|address| {
+            let address = string_param(address, "address")?;
+            script_from_address(address, node.network())
+                .ok_or_else(|| RpcError::InvalidAddressOrKey(format!("Invalid address: {address}")))
+        })
+        .collect()
+}
+
+// A transaction mined in the active chain, found through the transaction index,
+// and the header of its block.
+fn indexed_transaction<'a>(
+    node: &'a Node,
+    txid: &[u8; 32],
+) -> Result<(Transaction, &'a HeaderEntry), RpcError> {
+    let Some(index) = node.chain().tx_index() else {
+        return Err(RpcError::InvalidAddressOrKey(
+            "No such mempool transaction. Use -txindex or provide a block hash to enable \
+             blockchain transaction queries. Use gettransaction for wallet transactions."
+                .to_string(),
+        ));
+    };
+    let not_found = || {
+        RpcError::InvalidAddressOrKey(
+            "No such mempool or blockchain transaction. Use gettransaction for wallet \
+             transactions."
+                .to_string(),
+        )
+    };
+    let position = index.get(txid).ok_or_else(not_found)?;
+    let block = node
+        .chain()
+        .get_block(&position.block)
+        .map_err(|e| RpcError::Misc(e.to_string()))?
+        .ok_or_else(not_found)?;
+    let tx = block.transactions.into_iter().nth(position.index as usize);
+    let entry = node.chain().headers().get(&position.block);
+    tx.zip(entry).ok_or_else(not_found)
+}
+
+fn confirmations(node: &Node, entry: &HeaderEntry) -> i64 {
+    match node.chain().is_active(&entry.hash) {
+        true => node.chain().height() as i64 - entry.height as i64 + 1,
+        false => -1,
+    }
+}
+
+pub(super) fn header_json(node: &Node, entry: &HeaderEntry) -> Map<String, Value> {
+    let chain = node.chain();
+    let header = &entry.header;
+    let mut result = Map::new();
+    result.insert("hash".into(), json!(txid_to_hex(&entry.hash)));
+    result.insert("confirmations".into(), json!(confirmations(node, entry)));
+    result.insert("height".into(), json!(entry.height));
+    result.insert("version".into(), json!(header.version));
+    result.insert(
+        "versionHex".into(),
+        json!(format!("{:08x}", header.version as u32)),
+    );
+    result.insert("merkleroot".into(), json!(txid_to_hex(&header.merkle_root)));
+    result.insert("time".into(), json!(header.timestamp));
+    let median_time = chain.headers().median_time_past(entry);
+    result.insert("mediantime".into(), json!(median_time));
+    result.insert("nonce".into(), json!(header.nonce));
+    result.insert("bits".into(), json!(format!("{:08x}", header.bits)));
+    result.insert("difficulty".into(), json!(header.difficulty()));
+    result.insert(
+        "chainwork".into(),
+        json!(format!("{:064x}", entry.chain_work)),
+    );
+    if entry.height > 0 {
+        result.insert(
+            "previousblockhash".into(),
+            json!(txid_to_hex(&header.prev_block)),
+        );
+    }
+    let next = chain
+        .headers()
+        .at_height(entry.height + 1)
+        .filter(|next| chain.is_active(&entry.hash) && chain.is_active(&next.hash));
+    if let Some(next) = next {
+        result.insert("nextblockhash".into(), json!(txid_to_hex(&next.hash)));
+    }
+    result
+}
+
+// The fields of getblock in Bitcoin Core's order.
+fn order_block_json(mut fields: Map<String, Value>) -> Map<String, Value> {
+    let order = [
+        "hash",
+        "confirmations",
+        "size",
+        "strippedsize",
+        "weight",
+        "height",
+        "version",
+        "versionHex",
+        "merkleroot",
+        "tx",
+        "time",
+        "mediantime",
+        "nonce",
+        "bits",
+        "difficulty",
+        "chainwork",
+    ];
+    let mut result: Map<String, Value> = order
+        .iter()
+        .filter_map(|key| fields.remove(*key).map(|value| (key.to_string(), value)))
+        .collect();
+    result.insert(
+        "nTx".into(),
+        json!(result["tx"].as_array().map_or(0, Vec::len)),
+    );
+    result.extend(fields);
+    result
+}
+
+// Serves JSON-RPC on listener until the task is dropped, a task per connection.
+// Requests must be POSTs with the credentials of the server's auth, but for REST.
+pub async fn serve(listener: TcpListener, server: Arc<RpcServer>) {
+    loop {
+        let Ok((stream, address)) = listener.accept().await else {
+            continue;
+        };
+        let server = server.clone();
+        let connection = async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            loop {
+                let (response, keep_alive) = match read_request(&mut reader).await {
+                    Ok(Some(request)) => (
+                        respond(&server, &request, address).await,
+                        request.keep_alive(),
+                    ),
+                    Ok(None) => return,
+                    Err(e) => (HttpResponse::new(400, "text/plain", e.to_string()), false),
+                };
+                if write_response(&mut writer, &response, keep_alive)
+                    .await
+                    .is_err()
+                    || !keep_alive
+                {
+                    return;
+                }
+            }
+        };
+        tokio::spawn(connection.instrument(info_span!(target: "rpc", "connection", %address)));
+    }
+}
+
+async fn respond(server: &RpcServer, request: &HttpRequest, from: SocketAddr) -> HttpResponse {
+    if server.rest && request.path.starts_with("/rest/") {
+        return rest::respond(server, request);
+    }
+    if !server.auth.check(request.header("Authorization")) {
+        warn!(target: "rpc", %from, "Incorrect RPC credentials");
+        tokio::time::sleep(AUTH_FAILURE_DELAY).await;
+        return HttpResponse::new(401, "text/plain", "")
+            .with_header("WWW-Authenticate", "Basic realm=\"jsonrpc\"");
+    }
+    if request.method != "POST" {
+        return HttpResponse::new(
+            405,
+            "text/plain",
+            "JSONRPC server handles only POST requests",
+        );
+    }
+    let (status, body) = server.handle(&request.body);
+    HttpResponse::new(status, "application/json", body)
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use crate::chain::{BlockStorage, BlockStore};
+    use crate::helper::base64_encode;
+    use crate::network::Network;
+    use crate::test_util::{coinbase, mine, solve, temp_path};
+
+    fn server(name: &str) -> (RpcServer, PathBuf) {
+        let dir = temp_path(&format!("rpc_tests_{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        let node = Node::open(Network::Regtest, &dir).unwrap();
+        let auth = RpcAuth::with_user("user", "pass");
+        let server = RpcServer::new(
+            Arc::new(Mutex::new(node)),
+            PeerManager::new(Network::Regtest),
+            auth,
+        );
+        (server, dir)
+    }
+
+    fn call(server: &RpcServer, request: Value) -> (u16, Value) {
+        let (status, body) = server.handle(request.to_string().as_bytes());
+        let body = match body.is_empty() {
+            true => Value::Null,
+            false => serde_json::from_slice(&body).unwrap(),
+        };
+        (status, body)
+    }
+
+    #[test]
+    fn test_chain_methods() {
+        let (server, dir) = server("chain");
+        let genesis = txid_to_hex(&Network::Regtest.genesis_hash());
+        let (status, reply) = call(&server, json!({"id": 1, "method": "getblockcount"}));
+        assert_eq!(status, 200);
+        assert_eq!(reply, json!({"result": 0, "error": null, "id": 1}));
+        let (_, reply) = call(
+            &server,
+            json!({"id": 2, "method": "getblockhash", "params": [0]}),
+        );
+        assert_eq!(reply["result"], json!(genesis));
+
+        let request = json!({"id": 3, "method": "getblock", "params": {"blockhash": genesis}});
+        let (_, reply) = call(&server, request);
+        let block = &reply["result"];
+        assert_eq!(block["height"], json!(0));
+        assert_eq!(block["confirmations"], json!(1));
+        assert_eq!(block["nTx"], json!(1));
+        assert_eq!(block["bits"], json!("207fffff"));
+        assert!(block.get("previousblockhash").is_none());
+        let keys: Vec<&String> = block.as_object().unwrap().keys().collect();
+        assert_eq!(keys[..3], ["hash", "confirmations", "size"]);
+        let request = json!({"id": 4, "method": "getblock", "params": [genesis, 0]});
+        let (_, reply) = call(&server, request);
+        let hex = Network::Regtest.genesis_block().serialize();
+        assert_eq!(reply["result"], json!(hex::encode(hex)));
+
+        let (_, reply) = call(&server, json!({"id": 5, "method": "getblockchaininfo"}));
+        assert_eq!(reply["result"]["chain"], json!("regtest"));
+        assert_eq!(reply["result"]["bestblockhash"], json!(genesis));
+        let (_, reply) = call(&server, json!({"id": 6, "method": "getrawmempool"}));
+        assert_eq!(reply["result"], json!([]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Blocks stored before the node opens are connected and indexed from there.
+    #[test]
+    fn test_indexed_lookups() {
+        let dir = temp_path("rpc_tests_index");
+        let _ = fs::remove_dir_all(&dir);
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let script = script_from_address(address, Network::Regtest).unwrap();
+        let mut store = BlockStore::open(dir.join("blocks")).unwrap();
+        let mut prev = Network::Regtest.genesis_block().header;
+        let mut coinbases = Vec::new();
+        for height in 1..=2 {
+            let mut coinbase = coinbase(height, 0, Network::Regtest.block_subsidy(height));
+            coinbase.outputs[0].script_pubkey = script.clone().into();
+            let block = mine(&prev, vec![coinbase.clone()]);
+            store.put(&block).unwrap();
+            prev = block.header;
+            coinbases.push(coinbase);
+        }
+        drop(store);
+        let node = Node::open(Network::Regtest, &dir)
+            .unwrap()
+            .with_tx_index(true)
+            .with_address_index(true);
+        let server = RpcServer::new(
+            Arc::new(Mutex::new(node)),
+            PeerManager::new(Network::Regtest),
+            RpcAuth::with_user("user", "pass"),
+        );
+
+        let txid = coinbases[0].txid_hex();
+        let request = json!({"id": 1, "method": "getrawtransaction", "params": [txid, true]});
+        let tx = call(&server, request).1["result"].clone();
+        assert_eq!(tx["txid"], json!(txid));
+        assert_eq!(tx["confirmations"], json!(2));
+        assert!(tx.get("in_active_chain").is_none());
+        let request = json!({"id": 2, "method": "getaddresstxids", "params": [address]});
+        let txids: Vec<String> = coinbases.iter().map(Transaction::txid_hex).collect();
+        assert_eq!(call(&server, request).1["result"], json!(txids));
+        let params = json!({"addresses": [address]});
+        let request = json!({"id": 3, "method": "getaddressbalance", "params": [params]});
+        let balance = 2 * Network::Regtest.block_subsidy(1);
+        assert_eq!(
+            call(&server, request).1["result"],
+            json!({"balance": balance, "received": balance})
+        );
+
+        let request = json!({"id": 4, "method": "getaddresstxids", "params": ["1BoatSLRHtKNngkdXEeobR76b53LETtpyT"]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-5));
+        let request =
+            json!({"id": 5, "method": "getrawtransaction", "params": [hex::encode([1; 32])]});
+        let message = "No such mempool or blockchain transaction. Use gettransaction for wallet \
+                       transactions.";
+        assert_eq!(call(&server, request).1["error"]["message"], json!(message));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mining_methods() {
+        let (server, dir) = server("mining");
+        let request = json!({"id": 1, "method": "getblocktemplate"});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-8));
+        let params = json!([{"rules": ["segwit"]}]);
+        let request = json!({"id": 2, "method": "getblocktemplate", "params": params});
+        let template = call(&server, request).1["result"].clone();
+        assert_eq!(template["height"], json!(1));
+        assert_eq!(template["bits"], json!("207fffff"));
+        assert_eq!(
+            template["coinbasevalue"],
+            json!(Network::Regtest.block_subsidy(1))
+        );
+        assert_eq!(template["transactions"], json!([]));
+        assert!(template["default_witness_commitment"]
+            .as_str()
+            .unwrap()
+            .starts_with("6a24aa21a9ed"));
+
+        let node = server.node();
+        let template = BlockAssembler::new().create(node.chain(), node.mempool(), 0);
+        drop(node);
+        let mut block = template.block(template.coinbase(vec![0x51].into(), &[]));
+        solve(&mut block.header);
+        let hex = hex::encode(block.serialize());
+        let request = json!({"id": 3, "method": "submitblock", "params": [hex]});
+        assert_eq!(call(&server, request.clone()).1["result"], Value::Null);
+        assert_eq!(server.node().chain().tip().hash, block.hash());
+        assert_eq!(call(&server, request).1["result"], json!("duplicate"));
+        let request = json!({"id": 4, "method": "submitblock", "params": ["00"]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-22));
+
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let request = json!({"id": 5, "method": "generatetoaddress", "params": [2, address]});
+        let hashes = call(&server, request).1["result"].clone();
+        assert_eq!(hashes.as_array().unwrap().len(), 2);
+        assert_eq!(server.node().chain().height(), 3);
+        assert_eq!(
+            hashes[1],
+            json!(txid_to_hex(&server.node().chain().tip().hash))
+        );
+        let request = json!({"id": 6, "method": "generatetoaddress", "params": [1, "x"]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-5));
+
+        let objects = json!([format!("addr({address})"), {"desc": "raw(51)"}]);
+        let request = json!({"id": 7, "method": "scantxoutset", "params": ["start", objects]});
+        let scan = call(&server, request).1["result"].clone();
+        assert_eq!(scan["txouts"], json!(3));
+        assert_eq!(scan["unspents"].as_array().unwrap().len(), 3);
+        assert_eq!(scan["total_amount"], json!(150.0));
+        let first = scan["unspents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|unspent| unspent["height"] == json!(1))
+            .unwrap();
+        assert_eq!(first["desc"], json!("raw(51)#8lvh9jxk"));
+        assert_eq!(first["confirmations"], json!(3));
+        let request = json!({"id": 8, "method": "scantxoutset", "params": ["start", ["nope()"]]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-5));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors_and_versions() {
+        let (server, dir) = server("errors");
+        // JSON-RPC 1.0 errors set the HTTP status.
+        let (status, reply) = call(&server, json!({"id": 1, "method": "nope"}));
+        assert_eq!(status, 404);
+        assert_eq!(reply["error"]["code"], json!(-32601));
+        let request = json!({"id": 1, "method": "getblockhash", "params": [7]});
+        let (status, reply) = call(&server, request);
+        assert_eq!(status, 500);
+        assert_eq!(
+            reply["error"],
+            json!({"code": -8, "message": "Block height out of range"})
+        );
+        let request = json!({"id": 1, "method": "getblock", "params": ["00"]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-8));
+        let request = json!({"id": 1, "method": "sendrawtransaction", "params": ["00"]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-22));
+        let request = json!({"id": 1, "method": "submitpackage", "params": [[]]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-8));
+        let txid = hex::encode([1; 32]);
+        let request =
+            json!({"id": 1, "method": "prioritisetransaction", "params": [txid, 1, 1000]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-8));
+        let request =
+            json!({"id": 1, "method": "prioritisetransaction", "params": [txid, 0, 1000]});
+        assert_eq!(call(&server, request).1["result"], json!(true));
+        assert_eq!(server.node().mempool().fee_delta(&[1; 32]), 1000);
+        for _ in 0..2 {
+            let request = json!({
+                "id": 1, "method": "prioritisetransaction", "params": [txid, 0, i64::MAX]
+            });
+            assert_eq!(call(&server, request).1["result"], json!(true));
+        }
+        assert_eq!(server.node().mempool().fee_delta(&[1; 32]), i64::MAX);
+        let request =
+            json!({"id": 1, "method": "getrawtransaction", "params": [hex::encode([1; 32])]});
+        assert_eq!(call(&server, request).1["error"]["code"], json!(-5));
+        assert_eq!(server.handle(b"{").0, 500);
+
+        // JSON-RPC 2.0 answers with 200, and not at all to notifications.
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": "a", "method": "getblockcount"},
+            {"jsonrpc": "2.0", "method": "getblockcount"},
+            {"jsonrpc": "2.0", "id": "b", "method": "nope"},
+        ]);
+        let (status, reply) = call(&server, batch);
+        assert_eq!(status, 200);
+        assert_eq!(
+            reply,
+            json!([
+                {"jsonrpc": "2.0", "result": 0, "id": "a"},
+                {"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": "b"},
+            ])
+        );
+        let notification = json!({"jsonrpc": "2.0", "method": "getblockcount"});
+        assert_eq!(call(&server, notification), (204, Value::Null));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_auth() {
+        let auth = RpcAuth::with_user("user", "pass");
+        assert!(auth.check(Some(&format!("Basic {}", base64_encode(b"user:pass")))));
+        assert!(!auth.check(Some(&format!("Basic {}", base64_encode(b"user:pasz")))));
+        assert!(!auth.check(Some("Bearer token")));
+        assert!(!auth.check(None));
+
+        let dir = temp_path("rpc_tests_cookie");
+        fs::create_dir_all(&dir).unwrap();
+        let auth = RpcAuth::cookie(&dir).unwrap();
+        let cookie = fs::read_to_string(dir.join(COOKIE_FILE)).unwrap();
+        assert!(cookie.starts_with("__cookie__:"));
+        assert!(auth.check(Some(&format!("Basic {}", base64_encode(cookie.as_bytes())))));
+        assert_ne!(RpcAuth::cookie(&dir).unwrap(), auth);
+        auth.remove_cookie();
+        assert!(!dir.join(COOKIE_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+// Human readable script disassembly in the format of Bitcoin Core's ScriptToAsmStr,
+// and parsing it back.
+use super::instruction::read_instruction;
+use super::num::decode_script_num;
+use super::opcode::{opcode_name, Opcode};
+use super::Script;
+use crate::ecc::signature::is_valid_signature_encoding;
+use crate::transaction::sighash::SigHashType;
+use crate::types::errors::Errors;
+
+// Pushes of up to 4 bytes are printed as script numbers, longer ones as hex. With
+// attempt_sighash_decode, pushes that look like signatures get their sighash type
+// printed by name instead of as the last hex byte, as for scriptSigs in Core's RPCs.
+pub fn to_asm(script: &[u8], attempt_sighash_decode: bool) -> String {
+    let mut words = Vec::new();
+    let mut rest = script;
+    while !rest.is_empty() {
+        // Decode one instruction at a time so a truncated push still shows what comes before.
+        let Some((opcode, data, len)) = read_instruction(rest) else {
+            words.push("[error]".to_string());
+            break;
+        };
+        rest = &rest[len..];
+        if opcode > Opcode::OP_PUSHDATA4.to_u8() {
+            words.push(opcode_name(opcode).to_string());
+        } else if data.len() <= 4 {
+            words.push(decode_script_num(data).to_string());
+        } else if attempt_sighash_decode && script.first() != Some(&Opcode::OP_RETURN.to_u8()) {
+            words.push(signature_asm(data));
+        } else {
+            words.push(hex::encode(data));
+        }
+    }
+    words.join(" ")
+}
+
+impl Script {
+    pub fn to_asm(&self) -> String {
+        to_asm(self, false)
+    }
+
+    // Reads the output of to_asm: opcode names, decimal numbers for pushes of up to
+    // 4 bytes and hex for longer ones, optionally ending in a [SIGHASH] name. Pushes
+    // are encoded minimally, so only minimal scripts round trip byte for byte. A 5
+    // byte push whose hex has no leading zero and reads as a 32 bit number prints
+    // the same as that number, and comes back as the number.
+    pub fn from_asm(asm: &str) -> Result<Script, Errors> {
+        let mut script = Script::new();
+        for word in asm.split_whitespace() {
+            if let Some(n) = word
+                .parse::<i64>()
+                .ok()
+                .filter(|n| n.abs() <= i32::MAX as i64 && *n.to_string() == *word)
+            {
+                script.push_int(n);
+            } else if let Some(opcode) = word.strip_prefix("OP_").and(Opcode::from_name(word)) {
+                script.push_opcode(opcode);
+            } else {
+                script.push_slice(&parse_push(word)?);
+            }
+        }
+        Ok(script)
+    }
+}
+
+// Hex data, with the sighash type of a signature possibly spelled out at the end.
+fn parse_push(word: &str) -> Result<Vec<u8>, Errors> {
+    let invalid = || Errors::InvalidAsm(word.to_string());
+    let (data, sighash_type) = match word.strip_suffix(']').and_then(|w| w.split_once('[')) {
+        Some((data, name)) => {
+            let sighash_type = [0x01, 0x02, 0x03, 0x81, 0x82, 0x83]
+                .into_iter()
+                .filter_map(|flag| SigHashType::from_u32(flag).ok())
+                .find(|sighash_type| sighash_type.name() == name)
+                .ok_or_else(invalid)?;
+            (data, Some(sighash_type))
+        }
+        None => (word, None),
+    };
+    let mut bytes = hex::decode(data).map_err(|_| invalid())?;
+    if let Some(sighash_type) = sighash_type {
+        bytes.push(sighash_type.to_u32() as u8);
+    }
+    Ok(bytes)
+}
+
+fn signature_asm(data: &[u8]) -> String {
+    if is_valid_signature_encoding(data) {
+        let flag = data[data.len() - 1] as u32;
+        if let Ok(sighash_type) = SigHashType::from_u32(flag) {
+            if sighash_type != SigHashType::Default {
+                return format!(
+                    "{}[{}]",
+                    hex::encode(&data[..data.len() - 1]),
+                    sighash_type.name()
+                );
+            }
+        }
+    }
+    hex::encode(data)
+}
+
+#[cfg(test)]
+mod asm_tests {
+    use super::*;
+
+    #[test]
+    fn test_p2pkh_asm() {
+        let script = hex::decode("76a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac").unwrap();
+        assert_eq!(
+            to_asm(&script, false),
+            "OP_DUP OP_HASH160 bc3b654dca7e56b04dca18f2566cdaf02e8d9ada OP_EQUALVERIFY OP_CHECKSIG"
+        );
+    }
+
+    #[test]
+    fn test_numbers_and_errors() {
+        assert_eq!(to_asm(&[0x00, 0x51, 0x60, 0x4f], false), "0 1 16 -1");
+        assert_eq!(to_asm(&[0x02, 0xe8, 0x03, 0x01, 0x81], false), "1000 -1");
+        assert_eq!(
+            to_asm(&[0xb1, 0x05, 0x00], false),
+            "OP_CHECKLOCKTIMEVERIFY [error]"
+        );
+        assert_eq!(to_asm(&[0xbb], false), "OP_UNKNOWN");
+    }
+
+    #[test]
+    fn test_sighash_decode() {
+        let script_sig = hex::decode("483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278a").unwrap();
+        assert_eq!(
+            to_asm(&script_sig, true),
+            "3045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed[ALL] 0349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278a"
+        );
+        assert!(to_asm(&script_sig, false).starts_with("3045022100ed81"));
+        assert!(to_asm(&script_sig, false).contains("615bed01 "));
+    }
+
+    #[test]
+    fn test_from_asm_round_trip() {
+        for hex_script in [
+            "76a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac",
+            // 2 <key> <key> 2 OP_CHECKMULTISIG
+            "522102b4632d08485ff1df2db55b9dafd23347d1c47a457072a1e87be26896549a87372103b287eaf122eea69030a0e9feed096bed8045c8b98bec453e1ffac7fbdbd4bb7152ae",
+            // Numbers of every size up to 4 bytes.
+            "004f51600164028000033b0b7d04ffffff7f",
+        ] {
+            let script = Script::from(hex::decode(hex_script).unwrap());
+            assert_eq!(Script::from_asm(&script.to_asm()), Ok(script));
+        }
+        assert_eq!(
+            Script::from_asm("OP_DUP  OP_HASH160\n 0102030405").unwrap(),
+            vec![0x76, 0xa9, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05]
+        );
+    }
+
+    #[test]
+    fn test_from_asm_sighash_names() {
+        let script_sig = hex::decode("483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278a").unwrap();
+        let asm = to_asm(&script_sig, true);
+        assert_eq!(Script::from_asm(&asm).unwrap(), script_sig);
+        assert!(Script::from_asm("3045[ALL|ANYONECANPAY]")
+            .unwrap()
+            .ends_with(&[0x30, 0x45, 0x81]));
+    }
+
+    #[test]
+    fn test_from_asm_errors() {
+        for asm in [
+            "OP_UNKNOWN",
+            "OP_FOO",
+            "abc",
+            "zz",
+            "3045[DEFAULT]",
+            "[error]",
+        ] {
+            assert_eq!(
+                Script::from_asm(asm),
+                Err(Errors::InvalidAsm(asm.to_string()))
+            );
+        }
+        // Outside the range of a 4 byte script number, so read as hex.
+        assert_eq!(
+            Script::from_asm("2147483648").unwrap(),
+            vec![0x05, 0x21, 0x47, 0x48, 0x36, 0x48]
+        );
+    }
+}
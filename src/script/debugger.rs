@@ -0,0 +1,142 @@
+// Step by step execution traces, to see where and why a script fails.
+use super::asm::to_asm;
+use super::instruction::{Instruction, Instructions};
+use super::interpreter::{ExecutionContext, Interpreter};
+use super::Script;
+use crate::types::errors::ScriptError;
+use std::fmt;
+
+// The interpreter state right after one instruction, including instructions skipped
+// inside a branch that is not executed and the one that failed the script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep<'s> {
+    // Offset of the instruction in the script.
+    pub position: usize,
+    // None for a push running past the end of the script.
+    pub instruction: Option<Instruction<'s>>,
+    pub stack: Vec<Vec<u8>>,
+    pub altstack: Vec<Vec<u8>>,
+    // The script after this instruction.
+    pub remaining: &'s [u8],
+}
+
+impl Script {
+    // Like evaluate, calling on_step after every instruction. Returns the final stack.
+    pub fn evaluate_traced<'s>(
+        &'s self,
+        stack: Vec<Vec<u8>>,
+        context: &ExecutionContext,
+        mut on_step: impl FnMut(&TraceStep<'s>),
+    ) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let script = self.as_bytes();
+        let mut interpreter = Interpreter::new(self, stack, context);
+        while !interpreter.is_done() {
+            let position = interpreter.position();
+            let result = interpreter.step();
+            on_step(&TraceStep {
+                position,
+                instruction: Instructions::new(&script[position..])
+                    .next()
+                    .and_then(Result::ok),
+                stack: interpreter.stack().to_vec(),
+                altstack: interpreter.altstack().to_vec(),
+                remaining: &script[interpreter.position()..],
+            });
+            result?;
+        }
+        interpreter.finish()
+    }
+
+    // Every step of the execution, and how it ended.
+    pub fn trace(
+        &self,
+        stack: Vec<Vec<u8>>,
+        context: &ExecutionContext,
+    ) -> (Vec<TraceStep<'_>>, Result<Vec<Vec<u8>>, ScriptError>) {
+        let mut steps = Vec::new();
+        let result = self.evaluate_traced(stack, context, |step| steps.push(step.clone()));
+        (steps, result)
+    }
+}
+
+// One line per step: position, instruction, stack from bottom to top and what is
+// left to run, all in asm.
+impl fmt::Display for TraceStep<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = match self.instruction {
+            Some(Instruction::PushBytes(data)) => hex::encode(data),
+            Some(Instruction::Op(opcode)) => opcode.name().to_string(),
+            Some(Instruction::Unknown(_)) => "OP_UNKNOWN".to_string(),
+            None => "[error]".to_string(),
+        };
+        let stack: Vec<String> = self.stack.iter().map(hex::encode).collect();
+        write!(
+            f,
+            "{:>4} {:<20} [{}] | {}",
+            self.position,
+            instruction,
+            stack.join(" "),
+            to_asm(self.remaining, false)
+        )
+    }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::*;
+    use crate::script::flags::VerificationFlags;
+    use crate::script::interpreter::NoSignatureChecker;
+    use crate::script::Opcode;
+
+    fn context() -> ExecutionContext<'static> {
+        ExecutionContext::new(VerificationFlags::NONE, &NoSignatureChecker)
+    }
+
+    #[test]
+    fn test_trace_arithmetic() {
+        // 2 3 OP_ADD 5 OP_EQUAL
+        let script = Script::from(vec![0x52, 0x53, 0x93, 0x55, 0x87]);
+        let (steps, result) = script.trace(Vec::new(), &context());
+        assert_eq!(result, Ok(vec![vec![1]]));
+        assert_eq!(steps.len(), 5);
+        assert_eq!(steps[2].position, 2);
+        assert_eq!(steps[2].stack, vec![vec![5]]);
+        assert_eq!(steps[2].remaining, &[0x55, 0x87]);
+        assert_eq!(
+            steps[2].to_string(),
+            "   2 OP_ADD               [05] | 5 OP_EQUAL"
+        );
+        assert!(steps[4].remaining.is_empty());
+    }
+
+    #[test]
+    fn test_trace_stops_at_failure() {
+        // 0 OP_IF OP_RETURN OP_ENDIF 01 OP_TOALTSTACK OP_VERIFY
+        let script = Script::from(vec![0x00, 0x63, 0x6a, 0x68, 0x01, 0x01, 0x6b, 0x69]);
+        let mut positions = Vec::new();
+        let mut last = None;
+        let result = script.evaluate_traced(Vec::new(), &context(), |step| {
+            positions.push(step.position);
+            last = Some(step.clone());
+        });
+        assert_eq!(result, Err(ScriptError::InvalidStackOperation));
+        // The OP_RETURN in the skipped branch is still part of the trace.
+        assert_eq!(positions, vec![0, 1, 2, 3, 4, 6, 7]);
+        let last = last.unwrap();
+        assert_eq!(last.instruction, Some(Instruction::Op(Opcode::OP_VERIFY)));
+        assert_eq!(last.altstack, vec![vec![1]]);
+    }
+
+    #[test]
+    fn test_trace_truncated_push() {
+        let script = Script::from(vec![0x51, 0x4c, 0x05, 0x00]);
+        let (steps, result) = script.trace(Vec::new(), &context());
+        assert_eq!(result, Err(ScriptError::BadOpcode));
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[1].instruction, None);
+        assert_eq!(
+            steps[1].to_string(),
+            "   1 [error]              [01] | [error]"
+        );
+    }
+}
@@ -0,0 +1,201 @@
+// Script verification rules, with the bit values used by Bitcoin Core. Each soft fork
+// added rules, so older blocks are validated with some of them turned off, while
+// relay policy turns on more than consensus requires.
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitOrAssign, Sub};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct VerificationFlags(u32);
+
+macro_rules! flags {
+    ($($name:ident = $bit:literal,)*) => {
+        impl VerificationFlags {
+            $(pub const $name: VerificationFlags = VerificationFlags(1 << $bit);)*
+
+            // Names of the rules that are set, in bit order.
+            pub fn names(self) -> Vec<&'static str> {
+                let mut names = Vec::new();
+                $(if self.contains(VerificationFlags::$name) {
+                    names.push(stringify!($name));
+                })*
+                names
+            }
+        }
+    };
+}
+
+flags! {
+    P2SH = 0,
+    STRICTENC = 1,
+    DERSIG = 2,
+    LOW_S = 3,
+    NULLDUMMY = 4,
+    SIGPUSHONLY = 5,
+    MINIMALDATA = 6,
+    DISCOURAGE_UPGRADABLE_NOPS = 7,
+    CLEANSTACK = 8,
+    CHECKLOCKTIMEVERIFY = 9,
+    CHECKSEQUENCEVERIFY = 10,
+    WITNESS = 11,
+    DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM = 12,
+    MINIMALIF = 13,
+    NULLFAIL = 14,
+    WITNESS_PUBKEYTYPE = 15,
+    TAPROOT = 17,
+    DISCOURAGE_UPGRADABLE_TAPROOT_VERSION = 18,
+    DISCOURAGE_OP_SUCCESS = 19,
+    DISCOURAGE_UPGRADABLE_PUBKEYTYPE = 20,
+}
+
+impl VerificationFlags {
+    pub const NONE: VerificationFlags = VerificationFlags(0);
+
+    // Every soft fork up to taproot (MANDATORY_SCRIPT_VERIFY_FLAGS in Core).
+    pub const MANDATORY: VerificationFlags = VerificationFlags::P2SH
+        .union(VerificationFlags::DERSIG)
+        .union(VerificationFlags::NULLDUMMY)
+        .union(VerificationFlags::CHECKLOCKTIMEVERIFY)
+        .union(VerificationFlags::CHECKSEQUENCEVERIFY)
+        .union(VerificationFlags::WITNESS)
+        .union(VerificationFlags::TAPROOT);
+
+    // What default nodes require of transactions they relay
+    // (STANDARD_SCRIPT_VERIFY_FLAGS in Core).
+    pub const STANDARD: VerificationFlags = VerificationFlags::MANDATORY
+        .union(VerificationFlags::STRICTENC)
+        .union(VerificationFlags::MINIMALDATA)
+        .union(VerificationFlags::DISCOURAGE_UPGRADABLE_NOPS)
+        .union(VerificationFlags::CLEANSTACK)
+        .union(VerificationFlags::MINIMALIF)
+        .union(VerificationFlags::NULLFAIL)
+        .union(VerificationFlags::LOW_S)
+        .union(VerificationFlags::DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM)
+        .union(VerificationFlags::WITNESS_PUBKEYTYPE)
+        .union(VerificationFlags::DISCOURAGE_UPGRADABLE_TAPROOT_VERSION)
+        .union(VerificationFlags::DISCOURAGE_OP_SUCCESS)
+        .union(VerificationFlags::DISCOURAGE_UPGRADABLE_PUBKEYTYPE);
+
+    // Bits with no rule assigned are kept, and ignored by the interpreter.
+    pub const fn from_bits(bits: u32) -> Self {
+        VerificationFlags(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn union(self, other: VerificationFlags) -> Self {
+        VerificationFlags(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: VerificationFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: VerificationFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn insert(&mut self, other: VerificationFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: VerificationFlags) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for VerificationFlags {
+    type Output = VerificationFlags;
+
+    fn bitor(self, other: VerificationFlags) -> VerificationFlags {
+        self.union(other)
+    }
+}
+
+impl BitOrAssign for VerificationFlags {
+    fn bitor_assign(&mut self, other: VerificationFlags) {
+        self.insert(other);
+    }
+}
+
+impl BitAnd for VerificationFlags {
+    type Output = VerificationFlags;
+
+    fn bitand(self, other: VerificationFlags) -> VerificationFlags {
+        VerificationFlags(self.0 & other.0)
+    }
+}
+
+// The rules of self that are not in other.
+impl Sub for VerificationFlags {
+    type Output = VerificationFlags;
+
+    fn sub(self, other: VerificationFlags) -> VerificationFlags {
+        VerificationFlags(self.0 & !other.0)
+    }
+}
+
+// Comma separated names, as Core prints them (without the SCRIPT_VERIFY_ prefix).
+impl fmt::Display for VerificationFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.names().join(","))
+    }
+}
+
+impl fmt::Debug for VerificationFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VerificationFlags({})", self)
+    }
+}
+
+#[cfg(test)]
+mod flags_tests {
+    use super::*;
+
+    #[test]
+    fn test_bits() {
+        assert_eq!(VerificationFlags::P2SH.bits(), 1);
+        assert_eq!(VerificationFlags::WITNESS.bits(), 1 << 11);
+        assert_eq!(VerificationFlags::TAPROOT.bits(), 1 << 17);
+        // Core's MANDATORY_SCRIPT_VERIFY_FLAGS and STANDARD_SCRIPT_VERIFY_FLAGS
+        // without CONST_SCRIPTCODE, which is not implemented.
+        assert_eq!(VerificationFlags::MANDATORY.bits(), 0x20e15);
+        assert_eq!(VerificationFlags::STANDARD.bits(), 0x1effdf);
+        assert!(VerificationFlags::STANDARD.contains(VerificationFlags::MANDATORY));
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let mut flags = VerificationFlags::P2SH | VerificationFlags::WITNESS;
+        assert!(flags.contains(VerificationFlags::P2SH));
+        assert!(!flags.contains(VerificationFlags::P2SH | VerificationFlags::DERSIG));
+        assert!(flags.intersects(VerificationFlags::P2SH | VerificationFlags::DERSIG));
+        flags.remove(VerificationFlags::P2SH);
+        assert_eq!(flags, VerificationFlags::WITNESS);
+        flags |= VerificationFlags::DERSIG;
+        assert_eq!(
+            VerificationFlags::MANDATORY - flags,
+            VerificationFlags::P2SH
+                | VerificationFlags::NULLDUMMY
+                | VerificationFlags::CHECKLOCKTIMEVERIFY
+                | VerificationFlags::CHECKSEQUENCEVERIFY
+                | VerificationFlags::TAPROOT
+        );
+        assert!((flags & VerificationFlags::P2SH).is_empty());
+        assert!(VerificationFlags::default().is_empty());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            VerificationFlags::MANDATORY.to_string(),
+            "P2SH,DERSIG,NULLDUMMY,CHECKLOCKTIMEVERIFY,CHECKSEQUENCEVERIFY,WITNESS,TAPROOT"
+        );
+        assert_eq!(VerificationFlags::NONE.to_string(), "");
+    }
+}
@@ -0,0 +1,349 @@
+// Hash time-locked contract outputs in the offered and received forms of BOLT 3,
+// spendable with the payment preimage, after a timeout, or by the revocation key.
+use super::{Opcode, Script};
+use crate::helper::hash160;
+use crate::transaction::Witness;
+use crate::types::errors::Errors;
+use ripemd::{Digest, Ripemd160};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HtlcDirection {
+    // Paid by the local node: the remote node claims it with the preimage, the local
+    // node takes it back through an HTLC-timeout transaction locked to the expiry.
+    Offered,
+    // Paid to the local node: it claims it with the preimage through an
+    // HTLC-success transaction, the remote node takes it back after cltv_expiry.
+    Received { cltv_expiry: u32 },
+}
+
+// Keys are serialized compressed public keys, payment_hash is SHA256 of the preimage.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Htlc {
+    pub direction: HtlcDirection,
+    pub payment_hash: [u8; 32],
+    pub revocation_pubkey: Vec<u8>,
+    pub local_htlc_pubkey: Vec<u8>,
+    pub remote_htlc_pubkey: Vec<u8>,
+}
+
+// The ways to spend an HTLC output. Signatures end with their sighash type byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HtlcSpend<'a> {
+    // Either direction, by whoever holds the revocation key.
+    Revocation {
+        signature: &'a [u8],
+    },
+    // Offered HTLCs only.
+    RemoteSuccess {
+        remote_signature: &'a [u8],
+        preimage: &'a [u8],
+    },
+    LocalTimeout {
+        remote_signature: &'a [u8],
+        local_signature: &'a [u8],
+    },
+    // Received HTLCs only.
+    LocalSuccess {
+        remote_signature: &'a [u8],
+        local_signature: &'a [u8],
+        preimage: &'a [u8],
+    },
+    RemoteTimeout {
+        remote_signature: &'a [u8],
+    },
+}
+
+impl Htlc {
+    pub fn offered(
+        payment_hash: [u8; 32],
+        revocation_pubkey: &[u8],
+        local_htlc_pubkey: &[u8],
+        remote_htlc_pubkey: &[u8],
+    ) -> Self {
+        Htlc {
+            direction: HtlcDirection::Offered,
+            payment_hash,
+            revocation_pubkey: revocation_pubkey.to_vec(),
+            local_htlc_pubkey: local_htlc_pubkey.to_vec(),
+            remote_htlc_pubkey: remote_htlc_pubkey.to_vec(),
+        }
+    }
+
+    pub fn received(
+        payment_hash: [u8; 32],
+        cltv_expiry: u32,
+        revocation_pubkey: &[u8],
+        local_htlc_pubkey: &[u8],
+        remote_htlc_pubkey: &[u8],
+    ) -> Self {
+        Htlc {
+            direction: HtlcDirection::Received { cltv_expiry },
+            ..Htlc::offered(
+                payment_hash,
+                revocation_pubkey,
+                local_htlc_pubkey,
+                remote_htlc_pubkey,
+            )
+        }
+    }
+
+    pub fn witness_script(&self) -> Script {
+        let payment_hash160: [u8; 20] = Ripemd160::digest(self.payment_hash).into();
+        let mut script = Script::new();
+        script
+            .push_opcode(Opcode::OP_DUP)
+            .push_opcode(Opcode::OP_HASH160)
+            .push_slice(&hash160(&self.revocation_pubkey))
+            .push_opcode(Opcode::OP_EQUAL)
+            .push_opcode(Opcode::OP_IF)
+            .push_opcode(Opcode::OP_CHECKSIG)
+            .push_opcode(Opcode::OP_ELSE)
+            .push_slice(&self.remote_htlc_pubkey)
+            .push_opcode(Opcode::OP_SWAP)
+            .push_opcode(Opcode::OP_SIZE)
+            .push_int(32)
+            .push_opcode(Opcode::OP_EQUAL);
+        match self.direction {
+            HtlcDirection::Offered => {
+                script
+                    .push_opcode(Opcode::OP_NOTIF)
+                    .push_opcode(Opcode::OP_DROP)
+                    .push_int(2)
+                    .push_opcode(Opcode::OP_SWAP)
+                    .push_slice(&self.local_htlc_pubkey)
+                    .push_int(2)
+                    .push_opcode(Opcode::OP_CHECKMULTISIG)
+                    .push_opcode(Opcode::OP_ELSE)
+                    .push_opcode(Opcode::OP_HASH160)
+                    .push_slice(&payment_hash160)
+                    .push_opcode(Opcode::OP_EQUALVERIFY)
+                    .push_opcode(Opcode::OP_CHECKSIG);
+            }
+            HtlcDirection::Received { cltv_expiry } => {
+                script
+                    .push_opcode(Opcode::OP_IF)
+                    .push_opcode(Opcode::OP_HASH160)
+                    .push_slice(&payment_hash160)
+                    .push_opcode(Opcode::OP_EQUALVERIFY)
+                    .push_int(2)
+                    .push_opcode(Opcode::OP_SWAP)
+                    .push_slice(&self.local_htlc_pubkey)
+                    .push_int(2)
+                    .push_opcode(Opcode::OP_CHECKMULTISIG)
+                    .push_opcode(Opcode::OP_ELSE)
+                    .push_opcode(Opcode::OP_DROP)
+                    .push_int(cltv_expiry as i64)
+                    .push_opcode(Opcode::OP_CHECKLOCKTIMEVERIFY)
+                    .push_opcode(Opcode::OP_DROP)
+                    .push_opcode(Opcode::OP_CHECKSIG);
+            }
+        }
+        script
+            .push_opcode(Opcode::OP_ENDIF)
+            .push_opcode(Opcode::OP_ENDIF);
+        script
+    }
+
+    // The P2WSH output paying to the HTLC.
+    pub fn script_pubkey(&self) -> Script {
+        Script::new_p2wsh(&self.witness_script())
+    }
+
+    // The witness for a spend path, which must exist in this direction.
+    pub fn witness(&self, spend: HtlcSpend) -> Result<Witness, Errors> {
+        let mut elements = match (self.direction, spend) {
+            (_, HtlcSpend::Revocation { signature }) => {
+                vec![signature.to_vec(), self.revocation_pubkey.clone()]
+            }
+            (
+                HtlcDirection::Offered,
+                HtlcSpend::RemoteSuccess {
+                    remote_signature,
+                    preimage,
+                },
+            ) => vec![remote_signature.to_vec(), preimage.to_vec()],
+            (
+                HtlcDirection::Offered,
+                HtlcSpend::LocalTimeout {
+                    remote_signature,
+                    local_signature,
+                },
+            ) => vec![
+                vec![],
+                remote_signature.to_vec(),
+                local_signature.to_vec(),
+                vec![],
+            ],
+            (
+                HtlcDirection::Received { .. },
+                HtlcSpend::LocalSuccess {
+                    remote_signature,
+                    local_signature,
+                    preimage,
+                },
+            ) => vec![
+                vec![],
+                remote_signature.to_vec(),
+                local_signature.to_vec(),
+                preimage.to_vec(),
+            ],
+            (HtlcDirection::Received { .. }, HtlcSpend::RemoteTimeout { remote_signature }) => {
+                vec![remote_signature.to_vec(), vec![]]
+            }
+            _ => return Err(Errors::InvalidHtlcSpend),
+        };
+        elements.push(self.witness_script().into_bytes());
+        Ok(Witness::from_elements(elements))
+    }
+}
+
+#[cfg(test)]
+mod htlc_tests {
+    use super::*;
+    use crate::ecc::{from_bytes, PrivateKey};
+    use crate::helper::sha256;
+    use crate::script::interpreter::TransactionSignatureChecker;
+    use crate::script::verify::verify_script;
+    use crate::script::VerificationFlags;
+    use crate::transaction::sighash::SighashCache;
+    use crate::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use crate::types::errors::ScriptError;
+    use num_bigint::BigInt;
+
+    const AMOUNT: u64 = 50_000;
+    const PREIMAGE: [u8; 32] = [0x42; 32];
+
+    struct Keys {
+        revocation: PrivateKey,
+        local: PrivateKey,
+        remote: PrivateKey,
+    }
+
+    fn keys() -> Keys {
+        let key = |secret: u32| PrivateKey::new(BigInt::from(secret)).unwrap();
+        Keys {
+            revocation: key(11),
+            local: key(12),
+            remote: key(13),
+        }
+    }
+
+    fn htlc(direction: HtlcDirection) -> Htlc {
+        let keys = keys();
+        let htlc = Htlc::offered(
+            sha256(&PREIMAGE),
+            &keys.revocation.public_key().sec(true),
+            &keys.local.public_key().sec(true),
+            &keys.remote.public_key().sec(true),
+        );
+        Htlc { direction, ..htlc }
+    }
+
+    fn spending_tx(locktime: u32) -> Transaction {
+        Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new([9; 32], 0),
+                Script::new(),
+                0xfffffffe,
+            )],
+            vec![TxOut::new(AMOUNT - 1_000, Script::new_p2wpkh(&[1; 20]))],
+            locktime,
+        )
+    }
+
+    fn sign(tx: &Transaction, htlc: &Htlc, key: &PrivateKey) -> Vec<u8> {
+        let z = SighashCache::new(tx)
+            .segwit_v0_signature_hash(0, &htlc.witness_script(), AMOUNT, 1)
+            .unwrap();
+        let mut signature = key.sign(&from_bytes(&z)).der();
+        signature.push(1);
+        signature
+    }
+
+    fn verify(tx: &Transaction, htlc: &Htlc, witness: Witness) -> Result<(), ScriptError> {
+        let mut tx = tx.clone();
+        tx.inputs[0].witness = witness;
+        let checker = TransactionSignatureChecker::new(&tx, 0, AMOUNT);
+        verify_script(
+            &Script::new(),
+            &htlc.script_pubkey(),
+            &tx.inputs[0].witness,
+            VerificationFlags::STANDARD,
+            &checker,
+        )
+    }
+
+    #[test]
+    fn test_offered_htlc() {
+        let keys = keys();
+        let htlc = htlc(HtlcDirection::Offered);
+        let tx = spending_tx(0);
+        let remote = sign(&tx, &htlc, &keys.remote);
+        let local = sign(&tx, &htlc, &keys.local);
+
+        let success = HtlcSpend::RemoteSuccess {
+            remote_signature: &remote,
+            preimage: &PREIMAGE,
+        };
+        assert_eq!(verify(&tx, &htlc, htlc.witness(success).unwrap()), Ok(()));
+        let timeout = HtlcSpend::LocalTimeout {
+            remote_signature: &remote,
+            local_signature: &local,
+        };
+        assert_eq!(verify(&tx, &htlc, htlc.witness(timeout).unwrap()), Ok(()));
+        let revocation = sign(&tx, &htlc, &keys.revocation);
+        let revoke = HtlcSpend::Revocation {
+            signature: &revocation,
+        };
+        assert_eq!(verify(&tx, &htlc, htlc.witness(revoke).unwrap()), Ok(()));
+
+        let wrong = HtlcSpend::RemoteSuccess {
+            remote_signature: &remote,
+            preimage: &[0x43; 32],
+        };
+        assert_eq!(
+            verify(&tx, &htlc, htlc.witness(wrong).unwrap()),
+            Err(ScriptError::EqualVerify)
+        );
+        let expired = HtlcSpend::RemoteTimeout {
+            remote_signature: &remote,
+        };
+        assert_eq!(htlc.witness(expired), Err(Errors::InvalidHtlcSpend));
+    }
+
+    #[test]
+    fn test_received_htlc() {
+        let keys = keys();
+        let htlc = htlc(HtlcDirection::Received { cltv_expiry: 500 });
+        assert_ne!(
+            htlc.witness_script(),
+            self::htlc(HtlcDirection::Offered).witness_script()
+        );
+
+        let tx = spending_tx(0);
+        let remote = sign(&tx, &htlc, &keys.remote);
+        let local = sign(&tx, &htlc, &keys.local);
+        let success = HtlcSpend::LocalSuccess {
+            remote_signature: &remote,
+            local_signature: &local,
+            preimage: &PREIMAGE,
+        };
+        assert_eq!(verify(&tx, &htlc, htlc.witness(success).unwrap()), Ok(()));
+
+        // The timeout needs the spending transaction locked to the expiry.
+        let timeout = HtlcSpend::RemoteTimeout {
+            remote_signature: &remote,
+        };
+        assert_eq!(
+            verify(&tx, &htlc, htlc.witness(timeout).unwrap()),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        let tx = spending_tx(500);
+        let remote = sign(&tx, &htlc, &keys.remote);
+        let timeout = HtlcSpend::RemoteTimeout {
+            remote_signature: &remote,
+        };
+        assert_eq!(verify(&tx, &htlc, htlc.witness(timeout).unwrap()), Ok(()));
+    }
+}
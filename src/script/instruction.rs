@@ -0,0 +1,97 @@
+// Decoding scripts into a sequence of pushes and opcodes.
+use super::opcode::Opcode;
+use crate::types::errors::Errors;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    // Data pushed by OP_0, a direct push of 1 to 75 bytes or OP_PUSHDATA1/2/4.
+    PushBytes(&'a [u8]),
+    Op(Opcode),
+    // A byte with no opcode assigned to it.
+    Unknown(u8),
+}
+
+impl Instruction<'_> {
+    pub fn push_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Instruction::PushBytes(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn opcode(&self) -> Option<Opcode> {
+        match self {
+            Instruction::Op(opcode) => Some(*opcode),
+            _ => None,
+        }
+    }
+}
+
+// Iterator over the instructions of a script. A push running past the end of the
+// script yields an error and ends the iteration.
+#[derive(Clone, Debug)]
+pub struct Instructions<'a> {
+    script: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(script: &'a [u8]) -> Self {
+        Instructions {
+            script,
+            position: 0,
+        }
+    }
+
+    // Offset in the script of the next instruction.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, Errors>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.script[self.position..];
+        if rest.is_empty() {
+            return None;
+        }
+        match read_instruction(rest) {
+            Some((opcode, data, len)) => {
+                self.position += len;
+                Some(Ok(if opcode <= Opcode::OP_PUSHDATA4.to_u8() {
+                    Instruction::PushBytes(data)
+                } else {
+                    Opcode::from_u8(opcode).map_or(Instruction::Unknown(opcode), Instruction::Op)
+                }))
+            }
+            None => {
+                self.position = self.script.len();
+                Some(Err(Errors::UnexpectedEof))
+            }
+        }
+    }
+}
+
+// First opcode of script, the data it pushes and the total number of bytes it takes.
+// None if the script is empty or the push is truncated.
+pub(crate) fn read_instruction(script: &[u8]) -> Option<(u8, &[u8], usize)> {
+    let opcode = *script.first()?;
+    let (header, len): (usize, usize) = match opcode {
+        0x01..=0x4b => (1, opcode as usize),
+        0x4c => (2, *script.get(1)? as usize),
+        0x4d => (
+            3,
+            u16::from_le_bytes(script.get(1..3)?.try_into().ok()?) as usize,
+        ),
+        0x4e => (
+            5,
+            u32::from_le_bytes(script.get(1..5)?.try_into().ok()?) as usize,
+        ),
+        _ => (1, 0),
+    };
+    let total = header.checked_add(len)?;
+    let data = script.get(header..total)?;
+    Some((opcode, data, total))
+}
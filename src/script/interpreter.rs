@@ -0,0 +1,1520 @@
+// Stack machine executing scripts, following EvalScript in Bitcoin Core.
+use super::flags::VerificationFlags;
+use super::instruction::read_instruction;
+use super::num::{encode_script_num, read_script_num, DEFAULT_MAX_NUM_SIZE};
+use super::opcode::Opcode;
+use super::sigcache::SignatureCache;
+use super::{encode_push, Script};
+use crate::ecc::signature::is_valid_signature_encoding;
+use crate::ecc::{from_bytes, n, S256Point, SchnorrSignature, Signature};
+use crate::helper::{hash160, hash256, sha256};
+use crate::transaction::sighash::{
+    SighashCache, SIGHASH_ALL, SIGHASH_ANYONECANPAY, SIGHASH_DEFAULT, SIGHASH_SINGLE,
+};
+use crate::transaction::{LockTime, Sequence, Transaction, TxOut};
+use crate::types::errors::ScriptError;
+use num_bigint::BigInt;
+use ripemd::{Digest, Ripemd160};
+use sha1::Sha1;
+
+// Consensus limits on scripts.
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+// Items on the stack and altstack together.
+pub const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+
+// BIP342 signature budget: each tapscript signature check costs 50 weight units,
+// out of the witness size plus 50.
+pub const VALIDATION_WEIGHT_PER_SIGOP_PASSED: i64 = 50;
+pub const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+
+// Which rules a script runs under: legacy scripts, P2WSH/P2WPKH scripts or tapscript.
+// Taproot is the key path, which checks a signature without running any script.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigVersion {
+    Base,
+    WitnessV0,
+    Taproot,
+    Tapscript,
+}
+
+// State of a tapscript execution that signatures commit to or are limited by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptExecutionData {
+    pub tapleaf_hash: [u8; 32],
+    // Opcode position of the last executed OP_CODESEPARATOR, 0xffffffff if none.
+    pub codesep_pos: u32,
+    pub validation_weight_left: i64,
+    // Annex of the witness, tag byte included, signed by every signature if present.
+    pub annex: Option<Vec<u8>>,
+}
+
+impl Default for ScriptExecutionData {
+    fn default() -> Self {
+        ScriptExecutionData {
+            tapleaf_hash: [0; 32],
+            codesep_pos: u32::MAX,
+            validation_weight_left: 0,
+            annex: None,
+        }
+    }
+}
+
+// Everything a script can ask about the transaction spending it.
+pub trait SignatureChecker {
+    // sig is a DER signature followed by its sighash type byte, script_code the
+    // script being executed from the last OP_CODESEPARATOR on.
+    fn check_ecdsa_signature(
+        &self,
+        _sig: &[u8],
+        _pubkey: &[u8],
+        _script_code: &[u8],
+        _sig_version: SigVersion,
+    ) -> bool {
+        false
+    }
+
+    // sig is a 64 byte BIP340 signature, or 65 bytes ending with a sighash type
+    // other than SIGHASH_DEFAULT.
+    fn check_schnorr_signature(
+        &self,
+        _sig: &[u8],
+        _pubkey: &[u8; 32],
+        _sig_version: SigVersion,
+        _execution_data: &ScriptExecutionData,
+    ) -> bool {
+        false
+    }
+
+    fn check_lock_time(&self, _lock_time: LockTime) -> bool {
+        false
+    }
+
+    fn check_sequence(&self, _sequence: Sequence) -> bool {
+        false
+    }
+}
+
+// For scripts evaluated outside of any transaction: every check fails.
+pub struct NoSignatureChecker;
+
+impl SignatureChecker for NoSignatureChecker {}
+
+// Checks against input input_index of tx, which spends an output worth amount.
+// Taproot signatures also need the outputs spent by every input.
+pub struct TransactionSignatureChecker<'a> {
+    cache: SighashCache<'a>,
+    input_index: usize,
+    amount: u64,
+    prevouts: Option<&'a [TxOut]>,
+    signature_cache: Option<(&'a SignatureCache, bool)>,
+}
+
+impl<'a> TransactionSignatureChecker<'a> {
+    pub fn new(tx: &'a Transaction, input_index: usize, amount: u64) -> Self {
+        TransactionSignatureChecker {
+            cache: SighashCache::new(tx),
+            input_index,
+            amount,
+            prevouts: None,
+            signature_cache: None,
+        }
+    }
+
+    pub fn with_prevouts(mut self, prevouts: &'a [TxOut]) -> Self {
+        self.prevouts = Some(prevouts);
+        self
+    }
+
+    // Looks signatures up in cache before verifying them. With store, as when
+    // accepting to the mempool, valid ones are added. Otherwise, as when connecting a
+    // block, hits are removed since they won't be needed again.
+    pub fn with_signature_cache(mut self, cache: &'a SignatureCache, store: bool) -> Self {
+        self.signature_cache = Some((cache, store));
+        self
+    }
+
+    fn verify_with_cache(
+        &self,
+        sighash: &[u8; 32],
+        pubkey: &[u8],
+        signature: &[u8],
+        verify: impl FnOnce() -> bool,
+    ) -> bool {
+        let Some((cache, store)) = self.signature_cache else {
+            return verify();
+        };
+        let entry = SignatureCache::entry(sighash, pubkey, signature);
+        if cache.contains(&entry, !store) {
+            return true;
+        }
+        if !verify() {
+            return false;
+        }
+        if store {
+            cache.insert(entry);
+        }
+        true
+    }
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    fn check_ecdsa_signature(
+        &self,
+        sig: &[u8],
+        pubkey: &[u8],
+        script_code: &[u8],
+        sig_version: SigVersion,
+    ) -> bool {
+        let (Ok(point), Some((&hash_type, der))) = (S256Point::parse_sec(pubkey), sig.split_last())
+        else {
+            return false;
+        };
+        let Ok(signature) = Signature::parse_der(der) else {
+            return false;
+        };
+        let sighash = match sig_version {
+            SigVersion::Base => self.cache.legacy_signature_hash(
+                self.input_index,
+                &remove_code_separators(script_code),
+                hash_type as u32,
+            ),
+            _ => self.cache.segwit_v0_signature_hash(
+                self.input_index,
+                script_code,
+                self.amount,
+                hash_type as u32,
+            ),
+        };
+        sighash.is_ok_and(|z| {
+            self.verify_with_cache(&z, pubkey, der, || {
+                point.verify(&from_bytes(&z), &signature)
+            })
+        })
+    }
+
+    fn check_schnorr_signature(
+        &self,
+        sig: &[u8],
+        pubkey: &[u8; 32],
+        sig_version: SigVersion,
+        execution_data: &ScriptExecutionData,
+    ) -> bool {
+        let Some(prevouts) = self.prevouts else {
+            return false;
+        };
+        let (sig, hash_type) = match sig.split_at_checked(64) {
+            Some((sig, [])) => (sig, 0x00),
+            Some((sig, [hash_type])) => (sig, *hash_type as u32),
+            _ => return false,
+        };
+        let Ok(signature) = SchnorrSignature::parse(sig) else {
+            return false;
+        };
+        let sighash = match sig_version {
+            SigVersion::Taproot => self.cache.taproot_key_spend_signature_hash(
+                self.input_index,
+                prevouts,
+                execution_data.annex.as_deref(),
+                hash_type,
+            ),
+            _ => self.cache.taproot_script_spend_signature_hash(
+                self.input_index,
+                prevouts,
+                execution_data.tapleaf_hash,
+                execution_data.codesep_pos,
+                execution_data.annex.as_deref(),
+                hash_type,
+            ),
+        };
+        sighash.is_ok_and(|msg| {
+            self.verify_with_cache(&msg, pubkey, sig, || signature.verify(pubkey, &msg))
+        })
+    }
+
+    fn check_lock_time(&self, lock_time: LockTime) -> bool {
+        self.cache
+            .transaction()
+            .check_lock_time_verify(self.input_index, lock_time)
+    }
+
+    fn check_sequence(&self, sequence: Sequence) -> bool {
+        self.cache
+            .transaction()
+            .check_sequence_verify(self.input_index, sequence)
+    }
+}
+
+pub struct ExecutionContext<'a> {
+    pub flags: VerificationFlags,
+    pub sig_version: SigVersion,
+    pub checker: &'a dyn SignatureChecker,
+    // Only used by tapscript.
+    pub execution_data: ScriptExecutionData,
+}
+
+impl<'a> ExecutionContext<'a> {
+    pub fn new(flags: VerificationFlags, checker: &'a dyn SignatureChecker) -> Self {
+        ExecutionContext {
+            flags,
+            sig_version: SigVersion::Base,
+            checker,
+            execution_data: ScriptExecutionData::default(),
+        }
+    }
+
+    // True if any of the given rules is enabled.
+    fn has_flag(&self, flag: VerificationFlags) -> bool {
+        self.flags.intersects(flag)
+    }
+}
+
+impl Script {
+    // Runs the script on stack, which holds the final stack on success.
+    pub fn evaluate(
+        &self,
+        stack: &mut Vec<Vec<u8>>,
+        context: &ExecutionContext,
+    ) -> Result<(), ScriptError> {
+        let interpreter = Interpreter::new(self, std::mem::take(stack), context);
+        *stack = interpreter.run()?;
+        Ok(())
+    }
+}
+
+// Executes a script one instruction at a time.
+pub struct Interpreter<'s, 'c> {
+    script: &'s [u8],
+    position: usize,
+    context: &'c ExecutionContext<'c>,
+    stack: Vec<Vec<u8>>,
+    altstack: Vec<Vec<u8>>,
+    // One entry per open OP_IF, whether its current branch executes.
+    exec_stack: Vec<bool>,
+    // Position right after the last executed OP_CODESEPARATOR.
+    code_separator: usize,
+    // Instructions read so far, the current one included.
+    instruction_count: u32,
+    // Non-push opcodes seen so far plus the keys of every OP_CHECKMULTISIG run.
+    op_count: usize,
+    execution_data: ScriptExecutionData,
+}
+
+impl<'s, 'c> Interpreter<'s, 'c> {
+    pub fn new(script: &'s Script, stack: Vec<Vec<u8>>, context: &'c ExecutionContext<'c>) -> Self {
+        Interpreter {
+            script: script.as_bytes(),
+            position: 0,
+            context,
+            stack,
+            altstack: Vec::new(),
+            exec_stack: Vec::new(),
+            code_separator: 0,
+            instruction_count: 0,
+            op_count: 0,
+            execution_data: context.execution_data.clone(),
+        }
+    }
+
+    pub fn stack(&self) -> &[Vec<u8>] {
+        &self.stack
+    }
+
+    pub fn altstack(&self) -> &[Vec<u8>] {
+        &self.altstack
+    }
+
+    // Offset in the script of the next instruction.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position >= self.script.len()
+    }
+
+    pub fn run(mut self) -> Result<Vec<Vec<u8>>, ScriptError> {
+        while !self.is_done() {
+            self.step()?;
+        }
+        self.finish()
+    }
+
+    // The final stack once every instruction has been stepped through.
+    pub fn finish(self) -> Result<Vec<Vec<u8>>, ScriptError> {
+        if !self.exec_stack.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        Ok(self.stack)
+    }
+
+    pub fn step(&mut self) -> Result<(), ScriptError> {
+        // Tapscript is only bounded by the witness weight and the validation budget.
+        let legacy_limits = matches!(
+            self.context.sig_version,
+            SigVersion::Base | SigVersion::WitnessV0
+        );
+        if self.instruction_count == 0 && legacy_limits && self.script.len() > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::ScriptSize);
+        }
+        let (byte, data, len) =
+            read_instruction(&self.script[self.position..]).ok_or(ScriptError::BadOpcode)?;
+        self.position += len;
+        self.instruction_count += 1;
+
+        // Both limits apply to branches that are not executed too.
+        if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(ScriptError::PushSize);
+        }
+        if byte > Opcode::OP_16.to_u8() && legacy_limits {
+            self.add_ops(1)?;
+        }
+        self.step_instruction(byte, data)?;
+        if self.stack.len() + self.altstack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+        Ok(())
+    }
+
+    fn step_instruction(&mut self, byte: u8, data: &[u8]) -> Result<(), ScriptError> {
+        let executing = self.exec_stack.iter().all(|branch| *branch);
+        if byte <= Opcode::OP_PUSHDATA4.to_u8() {
+            if executing {
+                if self.context.has_flag(VerificationFlags::MINIMALDATA)
+                    && !is_minimal_push(byte, data)
+                {
+                    return Err(ScriptError::MinimalData);
+                }
+                self.stack.push(data.to_vec());
+            }
+            return Ok(());
+        }
+        let Some(opcode) = Opcode::from_u8(byte) else {
+            return if executing {
+                Err(ScriptError::BadOpcode)
+            } else {
+                Ok(())
+            };
+        };
+        // Disabled opcodes fail the script even in branches that are not executed.
+        if opcode.is_disabled() {
+            return Err(ScriptError::DisabledOpcode);
+        }
+        // Conditionals are looked at even when not executing, to track nesting.
+        if executing || opcode.is_conditional() {
+            self.execute(opcode, executing)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, opcode: Opcode, executing: bool) -> Result<(), ScriptError> {
+        use Opcode::*;
+
+        if let Some(n) = opcode.small_int() {
+            self.push_num(n);
+            return Ok(());
+        }
+        match opcode {
+            OP_NOP => {}
+            OP_IF | OP_NOTIF => {
+                let mut value = false;
+                if executing {
+                    let condition = self.stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                    if self.requires_minimal_if() && !is_minimal_if(&condition) {
+                        return Err(ScriptError::MinimalIf);
+                    }
+                    value = cast_to_bool(&condition) == (opcode == OP_IF);
+                }
+                self.exec_stack.push(value);
+            }
+            OP_ELSE => {
+                let branch = self
+                    .exec_stack
+                    .last_mut()
+                    .ok_or(ScriptError::UnbalancedConditional)?;
+                *branch = !*branch;
+            }
+            OP_ENDIF => {
+                self.exec_stack
+                    .pop()
+                    .ok_or(ScriptError::UnbalancedConditional)?;
+            }
+            OP_VERIFY => {
+                if !cast_to_bool(&self.pop()?) {
+                    return Err(ScriptError::Verify);
+                }
+            }
+            OP_RETURN => return Err(ScriptError::OpReturn),
+            OP_TOALTSTACK => {
+                let top = self.pop()?;
+                self.altstack.push(top);
+            }
+            OP_FROMALTSTACK => {
+                let top = self
+                    .altstack
+                    .pop()
+                    .ok_or(ScriptError::InvalidAltstackOperation)?;
+                self.stack.push(top);
+            }
+            OP_2DROP => {
+                self.pop()?;
+                self.pop()?;
+            }
+            OP_2DUP => self.copy_items(2, 2)?,
+            OP_3DUP => self.copy_items(3, 3)?,
+            OP_2OVER => self.copy_items(4, 2)?,
+            OP_2ROT => {
+                self.require(6)?;
+                let at = self.stack.len() - 6;
+                let items: Vec<_> = self.stack.drain(at..at + 2).collect();
+                self.stack.extend(items);
+            }
+            OP_2SWAP => {
+                self.require(4)?;
+                let at = self.stack.len() - 4;
+                self.stack[at..].rotate_left(2);
+            }
+            OP_IFDUP => {
+                let top = self.top(0)?.clone();
+                if cast_to_bool(&top) {
+                    self.stack.push(top);
+                }
+            }
+            OP_DEPTH => self.push_num(self.stack.len() as i64),
+            OP_DROP => {
+                self.pop()?;
+            }
+            OP_DUP => self.copy_items(1, 1)?,
+            OP_NIP => {
+                self.require(2)?;
+                let at = self.stack.len() - 2;
+                self.stack.remove(at);
+            }
+            OP_OVER => self.copy_items(2, 1)?,
+            OP_PICK | OP_ROLL => {
+                self.require(2)?;
+                let n = self.pop_num()?;
+                if n < 0 || n as usize >= self.stack.len() {
+                    return Err(ScriptError::InvalidStackOperation);
+                }
+                let at = self.stack.len() - 1 - n as usize;
+                let item = if opcode == OP_PICK {
+                    self.stack[at].clone()
+                } else {
+                    self.stack.remove(at)
+                };
+                self.stack.push(item);
+            }
+            OP_ROT => {
+                self.require(3)?;
+                let at = self.stack.len() - 3;
+                self.stack[at..].rotate_left(1);
+            }
+            OP_SWAP => {
+                self.require(2)?;
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+            }
+            OP_TUCK => {
+                self.require(2)?;
+                let top = self.top(0)?.clone();
+                let at = self.stack.len() - 2;
+                self.stack.insert(at, top);
+            }
+            OP_SIZE => {
+                let size = self.top(0)?.len();
+                self.push_num(size as i64);
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                self.require(2)?;
+                let (a, b) = (self.pop()?, self.pop()?);
+                self.push_bool(a == b);
+                if opcode == OP_EQUALVERIFY {
+                    self.verify_top(ScriptError::EqualVerify)?;
+                }
+            }
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let n = self.pop_num()?;
+                let result = match opcode {
+                    OP_1ADD => n + 1,
+                    OP_1SUB => n - 1,
+                    OP_NEGATE => -n,
+                    OP_ABS => n.abs(),
+                    OP_NOT => (n == 0) as i64,
+                    _ => (n != 0) as i64,
+                };
+                self.push_num(result);
+            }
+            OP_ADD
+            | OP_SUB
+            | OP_BOOLAND
+            | OP_BOOLOR
+            | OP_NUMEQUAL
+            | OP_NUMEQUALVERIFY
+            | OP_NUMNOTEQUAL
+            | OP_LESSTHAN
+            | OP_GREATERTHAN
+            | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL
+            | OP_MIN
+            | OP_MAX => {
+                self.require(2)?;
+                let b = self.pop_num()?;
+                let a = self.pop_num()?;
+                let result = match opcode {
+                    OP_ADD => a + b,
+                    OP_SUB => a - b,
+                    OP_BOOLAND => (a != 0 && b != 0) as i64,
+                    OP_BOOLOR => (a != 0 || b != 0) as i64,
+                    OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                    OP_NUMNOTEQUAL => (a != b) as i64,
+                    OP_LESSTHAN => (a < b) as i64,
+                    OP_GREATERTHAN => (a > b) as i64,
+                    OP_LESSTHANOREQUAL => (a <= b) as i64,
+                    OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                    OP_MIN => a.min(b),
+                    _ => a.max(b),
+                };
+                self.push_num(result);
+                if opcode == OP_NUMEQUALVERIFY {
+                    self.verify_top(ScriptError::NumEqualVerify)?;
+                }
+            }
+            OP_WITHIN => {
+                self.require(3)?;
+                let max = self.pop_num()?;
+                let min = self.pop_num()?;
+                let n = self.pop_num()?;
+                self.push_bool(min <= n && n < max);
+            }
+            OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                let data = self.pop()?;
+                let digest = match opcode {
+                    OP_RIPEMD160 => Ripemd160::digest(&data).to_vec(),
+                    OP_SHA1 => Sha1::digest(&data).to_vec(),
+                    OP_SHA256 => sha256(&data).to_vec(),
+                    OP_HASH160 => hash160(&data).to_vec(),
+                    _ => hash256(&data).to_vec(),
+                };
+                self.stack.push(digest);
+            }
+            OP_CODESEPARATOR => {
+                self.code_separator = self.position;
+                self.execution_data.codesep_pos = self.instruction_count - 1;
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY
+                if self.context.sig_version == SigVersion::Tapscript =>
+            {
+                self.require(2)?;
+                let pubkey = self.pop()?;
+                let sig = self.pop()?;
+                let success = self.check_tapscript_signature(&sig, &pubkey)?;
+                self.push_bool(success);
+                if opcode == OP_CHECKSIGVERIFY {
+                    self.verify_top(ScriptError::CheckSigVerify)?;
+                }
+            }
+            // <sig> <n> <pubkey> leaves n + 1 if the signature is valid, n if it is empty.
+            OP_CHECKSIGADD if self.context.sig_version == SigVersion::Tapscript => {
+                self.require(3)?;
+                let pubkey = self.pop()?;
+                let n = self.pop_num()?;
+                let sig = self.pop()?;
+                let success = self.check_tapscript_signature(&sig, &pubkey)?;
+                self.push_num(n + success as i64);
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                self.require(2)?;
+                let pubkey = self.pop()?;
+                let sig = self.pop()?;
+                let mut script_code = self.script_code();
+                if self.context.sig_version == SigVersion::Base {
+                    find_and_delete(&mut script_code, &encode_push(&sig));
+                }
+                let success = self.check_signature(&sig, &pubkey, &script_code)?;
+                if !success && self.context.has_flag(VerificationFlags::NULLFAIL) && !sig.is_empty()
+                {
+                    return Err(ScriptError::SigNullFail);
+                }
+                self.push_bool(success);
+                if opcode == OP_CHECKSIGVERIFY {
+                    self.verify_top(ScriptError::CheckSigVerify)?;
+                }
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                if self.context.sig_version == SigVersion::Tapscript {
+                    return Err(ScriptError::TapscriptCheckMultisig);
+                }
+                self.check_multisig()?;
+                if opcode == OP_CHECKMULTISIGVERIFY {
+                    self.verify_top(ScriptError::CheckMultisigVerify)?;
+                }
+            }
+            OP_CHECKLOCKTIMEVERIFY => {
+                if !self
+                    .context
+                    .has_flag(VerificationFlags::CHECKLOCKTIMEVERIFY)
+                {
+                    return self.upgradable_nop();
+                }
+                let lock_time = self.top_lock_value()?;
+                if lock_time > u32::MAX as i64
+                    || !self
+                        .context
+                        .checker
+                        .check_lock_time(LockTime::from_consensus(lock_time as u32))
+                {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            }
+            OP_CHECKSEQUENCEVERIFY => {
+                if !self
+                    .context
+                    .has_flag(VerificationFlags::CHECKSEQUENCEVERIFY)
+                {
+                    return self.upgradable_nop();
+                }
+                let sequence = self.top_lock_value()?;
+                // Sequences with the disable flag are reserved for future soft forks.
+                if sequence & Sequence::LOCKTIME_DISABLE_FLAG as i64 != 0 {
+                    return Ok(());
+                }
+                // Bits outside the type flag and the value are ignored, as in Bitcoin
+                // Core, even above 32 bits.
+                let mask = (Sequence::LOCKTIME_TYPE_FLAG | Sequence::LOCKTIME_MASK) as i64;
+                let sequence = Sequence((sequence & mask) as u32);
+                if !self.context.checker.check_sequence(sequence) {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            }
+            OP_NOP1 | OP_NOP4 | OP_NOP5 | OP_NOP6 | OP_NOP7 | OP_NOP8 | OP_NOP9 | OP_NOP10 => {
+                return self.upgradable_nop()
+            }
+            _ => return Err(ScriptError::BadOpcode),
+        }
+        Ok(())
+    }
+
+    // Stack: <dummy> <sig>... <sig count> <pubkey>... <key count>. Signatures must
+    // match keys in order, and an extra element is popped because of a bug in the
+    // original implementation that is now part of consensus.
+    fn check_multisig(&mut self) -> Result<(), ScriptError> {
+        let require_minimal = self.context.has_flag(VerificationFlags::MINIMALDATA);
+        let key_count = read_script_num(self.top(0)?, require_minimal, DEFAULT_MAX_NUM_SIZE)?;
+        if !(0..=MAX_PUBKEYS_PER_MULTISIG).contains(&key_count) {
+            return Err(ScriptError::PubKeyCount);
+        }
+        let key_count = key_count as usize;
+        self.add_ops(key_count)?;
+        let sig_count = read_script_num(
+            self.top(key_count + 1)?,
+            require_minimal,
+            DEFAULT_MAX_NUM_SIZE,
+        )?;
+        if sig_count < 0 || sig_count as usize > key_count {
+            return Err(ScriptError::SigCount);
+        }
+        let sig_count = sig_count as usize;
+        // The dummy element sits below the signatures.
+        self.require(key_count + sig_count + 3)?;
+
+        let len = self.stack.len();
+        let keys: Vec<Vec<u8>> = self.stack[len - 1 - key_count..len - 1]
+            .iter()
+            .rev()
+            .cloned()
+            .collect();
+        let sigs: Vec<Vec<u8>> = self.stack[len - 2 - key_count - sig_count..len - 2 - key_count]
+            .iter()
+            .rev()
+            .cloned()
+            .collect();
+
+        let mut script_code = self.script_code();
+        if self.context.sig_version == SigVersion::Base {
+            for sig in &sigs {
+                find_and_delete(&mut script_code, &encode_push(sig));
+            }
+        }
+
+        let (mut next_sig, mut next_key) = (0, 0);
+        let mut success = true;
+        while success && next_sig < sigs.len() {
+            if self.check_signature(&sigs[next_sig], &keys[next_key], &script_code)? {
+                next_sig += 1;
+            }
+            next_key += 1;
+            // Not enough keys left for the remaining signatures.
+            if sigs.len() - next_sig > keys.len() - next_key {
+                success = false;
+            }
+        }
+
+        if !success
+            && self.context.has_flag(VerificationFlags::NULLFAIL)
+            && sigs.iter().any(|sig| !sig.is_empty())
+        {
+            return Err(ScriptError::SigNullFail);
+        }
+        self.stack.truncate(len - 2 - key_count - sig_count);
+        let dummy = self.pop()?;
+        if self.context.has_flag(VerificationFlags::NULLDUMMY) && !dummy.is_empty() {
+            return Err(ScriptError::SigNullDummy);
+        }
+        self.push_bool(success);
+        Ok(())
+    }
+
+    // Validates the encodings required by the flags, then checks the signature.
+    fn check_signature(
+        &self,
+        sig: &[u8],
+        pubkey: &[u8],
+        script_code: &[u8],
+    ) -> Result<bool, ScriptError> {
+        self.check_signature_encoding(sig)?;
+        self.check_pubkey_encoding(pubkey)?;
+        Ok(self.context.checker.check_ecdsa_signature(
+            sig,
+            pubkey,
+            script_code,
+            self.context.sig_version,
+        ))
+    }
+
+    // BIP342 rules: empty signatures fail without error, any other signature for a
+    // 32 byte key must be valid and uses up some of the validation budget. Keys of
+    // other sizes are left for future upgrades and accept any signature.
+    fn check_tapscript_signature(
+        &mut self,
+        sig: &[u8],
+        pubkey: &[u8],
+    ) -> Result<bool, ScriptError> {
+        let success = !sig.is_empty();
+        if success {
+            self.execution_data.validation_weight_left -= VALIDATION_WEIGHT_PER_SIGOP_PASSED;
+            if self.execution_data.validation_weight_left < 0 {
+                return Err(ScriptError::TapscriptValidationWeight);
+            }
+        }
+        match pubkey.len() {
+            0 => return Err(ScriptError::PubKeyType),
+            32 => {
+                if success {
+                    check_schnorr_signature(
+                        self.context.checker,
+                        sig,
+                        pubkey.try_into().unwrap(),
+                        SigVersion::Tapscript,
+                        &self.execution_data,
+                    )?;
+                }
+            }
+            _ => {
+                if self
+                    .context
+                    .has_flag(VerificationFlags::DISCOURAGE_UPGRADABLE_PUBKEYTYPE)
+                {
+                    return Err(ScriptError::DiscourageUpgradablePubKeyType);
+                }
+            }
+        }
+        Ok(success)
+    }
+
+    // An empty signature is always allowed, so scripts can fail a check on purpose.
+    fn check_signature_encoding(&self, sig: &[u8]) -> Result<(), ScriptError> {
+        let Some((&hash_type, der)) = sig.split_last() else {
+            return Ok(());
+        };
+        let context = self.context;
+        if context.has_flag(
+            VerificationFlags::DERSIG | VerificationFlags::LOW_S | VerificationFlags::STRICTENC,
+        ) && !is_valid_signature_encoding(sig)
+        {
+            return Err(ScriptError::SigDer);
+        }
+        if context.has_flag(VerificationFlags::LOW_S) && !is_low_s(der) {
+            return Err(ScriptError::SigHighS);
+        }
+        let base_type = hash_type as u32 & !SIGHASH_ANYONECANPAY;
+        if context.has_flag(VerificationFlags::STRICTENC)
+            && !(SIGHASH_ALL..=SIGHASH_SINGLE).contains(&base_type)
+        {
+            return Err(ScriptError::SigHashType);
+        }
+        Ok(())
+    }
+
+    fn check_pubkey_encoding(&self, pubkey: &[u8]) -> Result<(), ScriptError> {
+        let compressed = pubkey.len() == 33 && (pubkey[0] == 0x02 || pubkey[0] == 0x03);
+        let uncompressed = pubkey.len() == 65 && pubkey[0] == 0x04;
+        if self.context.has_flag(VerificationFlags::STRICTENC) && !compressed && !uncompressed {
+            return Err(ScriptError::PubKeyType);
+        }
+        if self.context.has_flag(VerificationFlags::WITNESS_PUBKEYTYPE)
+            && self.context.sig_version == SigVersion::WitnessV0
+            && !compressed
+        {
+            return Err(ScriptError::WitnessPubKeyType);
+        }
+        Ok(())
+    }
+
+    // The part of the script signatures commit to.
+    fn script_code(&self) -> Vec<u8> {
+        self.script[self.code_separator..].to_vec()
+    }
+
+    fn requires_minimal_if(&self) -> bool {
+        match self.context.sig_version {
+            SigVersion::Base => false,
+            SigVersion::WitnessV0 => self.context.has_flag(VerificationFlags::MINIMALIF),
+            SigVersion::Taproot | SigVersion::Tapscript => true,
+        }
+    }
+
+    fn upgradable_nop(&self) -> Result<(), ScriptError> {
+        if self
+            .context
+            .has_flag(VerificationFlags::DISCOURAGE_UPGRADABLE_NOPS)
+        {
+            return Err(ScriptError::DiscourageUpgradableNops);
+        }
+        Ok(())
+    }
+
+    // Locktime operand of CLTV and CSV, left on the stack. 5 bytes are allowed
+    // so the whole unsigned 32 bit range can be expressed.
+    fn top_lock_value(&self) -> Result<i64, ScriptError> {
+        let require_minimal = self.context.has_flag(VerificationFlags::MINIMALDATA);
+        let value = read_script_num(self.top(0)?, require_minimal, 5)?;
+        if value < 0 {
+            return Err(ScriptError::NegativeLockTime);
+        }
+        Ok(value)
+    }
+
+    fn add_ops(&mut self, count: usize) -> Result<(), ScriptError> {
+        self.op_count += count;
+        if self.op_count > MAX_OPS_PER_SCRIPT {
+            return Err(ScriptError::OpCount);
+        }
+        Ok(())
+    }
+
+    fn require(&self, items: usize) -> Result<(), ScriptError> {
+        if self.stack.len() < items {
+            return Err(ScriptError::InvalidStackOperation);
+        }
+        Ok(())
+    }
+
+    // Element depth positions below the top of the stack.
+    fn top(&self, depth: usize) -> Result<&Vec<u8>, ScriptError> {
+        self.require(depth + 1)?;
+        Ok(&self.stack[self.stack.len() - 1 - depth])
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::InvalidStackOperation)
+    }
+
+    fn pop_num(&mut self) -> Result<i64, ScriptError> {
+        let require_minimal = self.context.has_flag(VerificationFlags::MINIMALDATA);
+        let n = read_script_num(self.top(0)?, require_minimal, DEFAULT_MAX_NUM_SIZE)?;
+        self.stack.pop();
+        Ok(n)
+    }
+
+    fn push_num(&mut self, n: i64) {
+        self.stack.push(encode_script_num(n));
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.stack.push(if value { vec![1] } else { Vec::new() });
+    }
+
+    // Pushes copies of count items starting depth items below the top.
+    fn copy_items(&mut self, depth: usize, count: usize) -> Result<(), ScriptError> {
+        self.require(depth)?;
+        let at = self.stack.len() - depth;
+        let items = self.stack[at..at + count].to_vec();
+        self.stack.extend(items);
+        Ok(())
+    }
+
+    // The *VERIFY variants: pop the result just pushed and fail if it is false.
+    fn verify_top(&mut self, error: ScriptError) -> Result<(), ScriptError> {
+        if !cast_to_bool(&self.pop()?) {
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+// Size and sighash type checks shared by key path spends and tapscript, before
+// handing the signature to the checker.
+pub(super) fn check_schnorr_signature(
+    checker: &dyn SignatureChecker,
+    sig: &[u8],
+    pubkey: &[u8; 32],
+    sig_version: SigVersion,
+    execution_data: &ScriptExecutionData,
+) -> Result<(), ScriptError> {
+    match sig.len() {
+        64 => {}
+        // SIGHASH_DEFAULT must be implicit so a signature has a single encoding.
+        65 if sig[64] as u32 != SIGHASH_DEFAULT => {}
+        65 => return Err(ScriptError::SchnorrSigHashType),
+        _ => return Err(ScriptError::SchnorrSigSize),
+    }
+    if !checker.check_schnorr_signature(sig, pubkey, sig_version, execution_data) {
+        return Err(ScriptError::SchnorrSig);
+    }
+    Ok(())
+}
+
+// False is any encoding of zero, including negative zero.
+pub fn cast_to_bool(data: &[u8]) -> bool {
+    match data.split_last() {
+        None => false,
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+    }
+}
+
+// Removes every occurrence of pattern that starts at an instruction boundary,
+// returning how many were found. Legacy signatures cannot sign themselves, so they
+// are taken out of the script code before hashing.
+fn find_and_delete(script: &mut Vec<u8>, pattern: &[u8]) -> usize {
+    let mut result = Vec::with_capacity(script.len());
+    let (mut found, mut position) = (0, 0);
+    while position < script.len() {
+        while script[position..].starts_with(pattern) {
+            position += pattern.len();
+            found += 1;
+        }
+        let Some((_, _, len)) = read_instruction(&script[position..]) else {
+            result.extend_from_slice(&script[position..]);
+            break;
+        };
+        result.extend_from_slice(&script[position..position + len]);
+        position += len;
+    }
+    if found > 0 {
+        *script = result;
+    }
+    found
+}
+
+// Legacy sighashes are computed over the script code without its OP_CODESEPARATORs.
+fn remove_code_separators(script: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(script.len());
+    let mut rest = script;
+    while let Some((opcode, _, len)) = read_instruction(rest) {
+        if opcode != Opcode::OP_CODESEPARATOR.to_u8() {
+            result.extend_from_slice(&rest[..len]);
+        }
+        rest = &rest[len..];
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+fn is_low_s(der: &[u8]) -> bool {
+    let half_order: BigInt = n() >> 1;
+    Signature::parse_der(der).is_ok_and(|sig| sig.s <= half_order)
+}
+
+// Whether data was pushed with the shortest possible opcode.
+fn is_minimal_push(opcode: u8, data: &[u8]) -> bool {
+    match data.len() {
+        0 => opcode == Opcode::OP_0.to_u8(),
+        // Should have used OP_1 to OP_16 or OP_1NEGATE.
+        1 if (1..=16).contains(&data[0]) || data[0] == 0x81 => false,
+        len @ 1..=75 => opcode as usize == len,
+        256..=65535 => opcode == Opcode::OP_PUSHDATA2.to_u8(),
+        76..=255 => opcode == Opcode::OP_PUSHDATA1.to_u8(),
+        _ => true,
+    }
+}
+
+// MINIMALIF: the argument of OP_IF/OP_NOTIF must be empty or exactly 0x01.
+fn is_minimal_if(data: &[u8]) -> bool {
+    data.is_empty() || data == [1]
+}
+
+#[cfg(test)]
+mod interpreter_tests {
+    use super::*;
+    use crate::ecc::PrivateKey;
+    use crate::transaction::{OutPoint, SigningData, TxIn, TxOut};
+
+    // Transaction from chapter 5 of Programming Bitcoin, spending a P2PKH output.
+    const P2PKH_SPEND: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+    // Signed native P2WPKH example from BIP143. Input 1 spends 6 BTC from a P2WPKH output.
+    const BIP143_P2WPKH_SPEND: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    fn p2pkh(pubkey: &[u8]) -> Vec<u8> {
+        Script::new_p2pkh(&hash160(pubkey)).into_bytes()
+    }
+
+    // Runs script_sig and then script_pubkey on the resulting stack.
+    fn run_spend(
+        script_sig: &[u8],
+        script_pubkey: &[u8],
+        context: &ExecutionContext,
+    ) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let mut stack = Vec::new();
+        Script::from(script_sig).evaluate(&mut stack, context)?;
+        Script::from(script_pubkey).evaluate(&mut stack, context)?;
+        Ok(stack)
+    }
+
+    fn run_with_flags(
+        script: &[u8],
+        flags: VerificationFlags,
+    ) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let context = ExecutionContext::new(flags, &NoSignatureChecker);
+        let mut stack = Vec::new();
+        Script::from_bytes(script.to_vec()).evaluate(&mut stack, &context)?;
+        Ok(stack)
+    }
+
+    fn run(script: &[u8]) -> Result<Vec<Vec<u8>>, ScriptError> {
+        run_with_flags(script, VerificationFlags::NONE)
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        // 2 3 OP_ADD 5 OP_EQUAL
+        assert_eq!(run(&[0x52, 0x53, 0x93, 0x55, 0x87]), Ok(vec![vec![1]]));
+        // 2 5 OP_SUB gives -3
+        assert_eq!(run(&[0x52, 0x55, 0x94]), Ok(vec![vec![0x83]]));
+        // 16 OP_1ADD OP_NEGATE OP_ABS
+        assert_eq!(run(&[0x60, 0x8b, 0x8f, 0x90]), Ok(vec![vec![17]]));
+        // 3 1 5 OP_WITHIN, 7 3 OP_MIN, 0 OP_NOT
+        assert_eq!(run(&[0x53, 0x51, 0x55, 0xa5]), Ok(vec![vec![1]]));
+        assert_eq!(run(&[0x57, 0x53, 0xa3]), Ok(vec![vec![3]]));
+        assert_eq!(run(&[0x00, 0x91]), Ok(vec![vec![1]]));
+        // 5 byte operands overflow.
+        assert_eq!(
+            run(&[0x05, 1, 1, 1, 1, 1, 0x8b]),
+            Err(ScriptError::NumberOverflow)
+        );
+        // Results may be 5 bytes long: 0x7fffffff + 0x7fffffff.
+        let max = [0x04, 0xff, 0xff, 0xff, 0x7f];
+        let mut script = [max, max].concat();
+        script.push(0x93);
+        assert_eq!(run(&script), Ok(vec![vec![0xfe, 0xff, 0xff, 0xff, 0x00]]));
+    }
+
+    #[test]
+    fn test_flow_control() {
+        // 1 OP_IF 2 OP_ELSE 3 OP_ENDIF
+        assert_eq!(
+            run(&[0x51, 0x63, 0x52, 0x67, 0x53, 0x68]),
+            Ok(vec![vec![2]])
+        );
+        // 0 OP_IF 2 OP_ELSE 3 OP_ENDIF
+        assert_eq!(
+            run(&[0x00, 0x63, 0x52, 0x67, 0x53, 0x68]),
+            Ok(vec![vec![3]])
+        );
+        // 0 OP_NOTIF 0 OP_IF 4 OP_ENDIF 5 OP_ENDIF: nested branch not taken.
+        assert_eq!(
+            run(&[0x00, 0x64, 0x00, 0x63, 0x54, 0x68, 0x55, 0x68]),
+            Ok(vec![vec![5]])
+        );
+        // Several OP_ELSE toggle the branch back and forth.
+        assert_eq!(
+            run(&[0x51, 0x63, 0x52, 0x67, 0x53, 0x67, 0x54, 0x68]),
+            Ok(vec![vec![2], vec![4]])
+        );
+        assert_eq!(run(&[0x51, 0x63]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(&[0x68]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(&[0x67]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(&[0x63]), Err(ScriptError::UnbalancedConditional));
+    }
+
+    #[test]
+    fn test_unexecuted_branches() {
+        // OP_RETURN and bad opcodes are fine when not executed...
+        assert_eq!(run(&[0x00, 0x63, 0x6a, 0x50, 0xff, 0x68]), Ok(vec![]));
+        // ...but disabled opcodes and OP_VERIF fail anyway.
+        assert_eq!(
+            run(&[0x00, 0x63, 0x7e, 0x68]),
+            Err(ScriptError::DisabledOpcode)
+        );
+        assert_eq!(run(&[0x00, 0x63, 0x65, 0x68]), Err(ScriptError::BadOpcode));
+        assert_eq!(run(&[0x6a]), Err(ScriptError::OpReturn));
+        assert_eq!(run(&[0x50]), Err(ScriptError::BadOpcode));
+    }
+
+    #[test]
+    fn test_stack_operations() {
+        let items = [0x51, 0x52, 0x53, 0x54, 0x55, 0x56];
+        let with = |ops: &[u8]| {
+            let script = [&items[..], ops].concat();
+            run(&script).map(|stack| stack.into_iter().map(|item| item[0]).collect::<Vec<_>>())
+        };
+        assert_eq!(with(&[0x71]), Ok(vec![3, 4, 5, 6, 1, 2]));
+        assert_eq!(with(&[0x72]), Ok(vec![1, 2, 5, 6, 3, 4]));
+        assert_eq!(with(&[0x70]), Ok(vec![1, 2, 3, 4, 5, 6, 3, 4]));
+        assert_eq!(with(&[0x6f]), Ok(vec![1, 2, 3, 4, 5, 6, 4, 5, 6]));
+        assert_eq!(with(&[0x7b]), Ok(vec![1, 2, 3, 5, 6, 4]));
+        assert_eq!(with(&[0x7d]), Ok(vec![1, 2, 3, 4, 6, 5, 6]));
+        assert_eq!(with(&[0x77]), Ok(vec![1, 2, 3, 4, 6]));
+        assert_eq!(with(&[0x7c]), Ok(vec![1, 2, 3, 4, 6, 5]));
+        // 3 OP_PICK and 3 OP_ROLL
+        assert_eq!(with(&[0x53, 0x79]), Ok(vec![1, 2, 3, 4, 5, 6, 3]));
+        assert_eq!(with(&[0x53, 0x7a]), Ok(vec![1, 2, 4, 5, 6, 3]));
+        assert_eq!(with(&[0x56, 0x79]), Err(ScriptError::InvalidStackOperation));
+        assert_eq!(with(&[0x74]).unwrap().last(), Some(&6));
+        assert_eq!(with(&[0x6b, 0x6b, 0x6c]), Ok(vec![1, 2, 3, 4, 5]));
+
+        assert_eq!(run(&[0x6c]), Err(ScriptError::InvalidAltstackOperation));
+        assert_eq!(run(&[0x76]), Err(ScriptError::InvalidStackOperation));
+        // OP_IFDUP only duplicates true values.
+        assert_eq!(run(&[0x00, 0x73]), Ok(vec![vec![]]));
+    }
+
+    #[test]
+    fn test_hashes() {
+        let hashed = |opcode: u8| run(&[0x00, opcode]).unwrap().pop().unwrap();
+        assert_eq!(
+            hex::encode(hashed(0xa6)),
+            "9c1185a5c5e9fc54612808977ee8f548b2258d31"
+        );
+        assert_eq!(
+            hex::encode(hashed(0xa7)),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            hex::encode(hashed(0xa8)),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex::encode(hashed(0xa9)),
+            "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb"
+        );
+        assert_eq!(
+            hex::encode(hashed(0xaa)),
+            "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456"
+        );
+    }
+
+    #[test]
+    fn test_verify_opcodes() {
+        assert_eq!(run(&[0x51, 0x52, 0x88]), Err(ScriptError::EqualVerify));
+        assert_eq!(run(&[0x51, 0x52, 0x9d]), Err(ScriptError::NumEqualVerify));
+        assert_eq!(run(&[0x00, 0x69]), Err(ScriptError::Verify));
+        assert_eq!(run(&[0x51, 0x51, 0x88]), Ok(vec![]));
+        // Negative zero is false.
+        assert!(!cast_to_bool(&[0x00, 0x80]));
+        assert!(cast_to_bool(&[0x80, 0x00]));
+    }
+
+    #[test]
+    fn test_minimal_data() {
+        // A one byte push of 0x05 should have been OP_5.
+        assert_eq!(run(&[0x01, 0x05]), Ok(vec![vec![5]]));
+        assert_eq!(
+            run_with_flags(&[0x01, 0x05], VerificationFlags::MINIMALDATA),
+            Err(ScriptError::MinimalData)
+        );
+        assert_eq!(
+            run_with_flags(&[0x4c, 0x02, 0xaa, 0xbb], VerificationFlags::MINIMALDATA),
+            Err(ScriptError::MinimalData)
+        );
+        // Non-minimal number operands.
+        assert_eq!(
+            run_with_flags(&[0x02, 0x01, 0x00, 0x8b], VerificationFlags::MINIMALDATA),
+            Err(ScriptError::NonMinimalNumber)
+        );
+        assert_eq!(run(&[0x02, 0x01, 0x00, 0x8b]), Ok(vec![vec![2]]));
+    }
+
+    #[test]
+    fn test_minimal_if_in_witness_scripts() {
+        let mut context = ExecutionContext::new(VerificationFlags::MINIMALIF, &NoSignatureChecker);
+        context.sig_version = SigVersion::WitnessV0;
+        let script = Script::from_bytes(vec![0x63, 0x51, 0x68]);
+        let mut stack = vec![vec![2]];
+        assert_eq!(
+            script.evaluate(&mut stack, &context),
+            Err(ScriptError::MinimalIf)
+        );
+        let mut stack = vec![vec![1]];
+        assert_eq!(script.evaluate(&mut stack, &context), Ok(()));
+    }
+
+    #[test]
+    fn test_nops_and_locktime() {
+        assert_eq!(run(&[0x51, 0xb0, 0xb9]), Ok(vec![vec![1]]));
+        assert_eq!(
+            run_with_flags(&[0xb0], VerificationFlags::DISCOURAGE_UPGRADABLE_NOPS),
+            Err(ScriptError::DiscourageUpgradableNops)
+        );
+
+        let mut tx = Transaction::new(
+            2,
+            vec![TxIn::new(OutPoint::new([1; 32], 0), Script::new(), 0)],
+            vec![],
+            500,
+        );
+        tx.inputs[0].sequence = 10;
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0);
+        let flags = VerificationFlags::CHECKLOCKTIMEVERIFY | VerificationFlags::CHECKSEQUENCEVERIFY;
+        let context = ExecutionContext::new(flags, &checker);
+        let eval = |script: Vec<u8>| Script::from_bytes(script).evaluate(&mut Vec::new(), &context);
+
+        // 400 OP_CLTV passes, 600 OP_CLTV does not, -1 OP_CLTV is negative.
+        assert_eq!(eval(vec![0x02, 0x90, 0x01, 0xb1]), Ok(()));
+        assert_eq!(
+            eval(vec![0x02, 0x58, 0x02, 0xb1]),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        assert_eq!(eval(vec![0x4f, 0xb1]), Err(ScriptError::NegativeLockTime));
+        assert_eq!(eval(vec![0xb1]), Err(ScriptError::InvalidStackOperation));
+        // 10 OP_CSV passes, 11 OP_CSV does not.
+        assert_eq!(eval(vec![0x5a, 0xb2]), Ok(()));
+        assert_eq!(
+            eval(vec![0x5b, 0xb2]),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        // 5-byte operands have their bits above the mask ignored: 2^32 + 10 passes,
+        // 2^32 + 11 does not.
+        assert_eq!(eval(vec![0x05, 0x0a, 0, 0, 0, 0x01, 0xb2]), Ok(()));
+        assert_eq!(eval(vec![0x05, 0, 0, 0, 0, 0x01, 0xb2]), Ok(()));
+        assert_eq!(
+            eval(vec![0x05, 0x0b, 0, 0, 0, 0x01, 0xb2]),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let mut big_push = vec![0x4d, 0x09, 0x02];
+        big_push.extend([0; 521]);
+        assert_eq!(run(&big_push), Err(ScriptError::PushSize));
+        // Even inside a branch that is not executed: 0 OP_IF <521 bytes> OP_ENDIF
+        let skipped = [&[0x00, 0x63][..], &big_push, &[0x68]].concat();
+        assert_eq!(run(&skipped), Err(ScriptError::PushSize));
+
+        // 0 0 <20 keys> 20 OP_CHECKMULTISIG counts as 21 opcodes.
+        let mut multisig = vec![0x00, 0x00];
+        for _ in 0..20 {
+            multisig.extend([0x01, 0x02]);
+        }
+        multisig.extend([0x01, 0x14, 0xae]);
+        let with_nops = |nops: usize| [vec![0x61; nops], multisig.clone()].concat();
+        assert_eq!(run(&with_nops(180)), Ok(vec![vec![1]]));
+        assert_eq!(run(&with_nops(181)), Err(ScriptError::OpCount));
+
+        let ones = vec![0x51; MAX_STACK_SIZE];
+        assert_eq!(run(&ones).map(|stack| stack.len()), Ok(MAX_STACK_SIZE));
+        // The altstack counts too: OP_TOALTSTACK OP_1
+        let one_more = [ones, vec![0x6b, 0x51]].concat();
+        assert_eq!(run(&one_more), Err(ScriptError::StackSize));
+
+        assert_eq!(
+            run(&vec![0x61; MAX_SCRIPT_SIZE + 1]),
+            Err(ScriptError::ScriptSize)
+        );
+    }
+
+    #[test]
+    fn test_checksig_p2pkh() {
+        let tx = Transaction::from_hex(P2PKH_SPEND).unwrap();
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0);
+        let flags =
+            VerificationFlags::STRICTENC | VerificationFlags::DERSIG | VerificationFlags::NULLFAIL;
+        let context = ExecutionContext::new(flags, &checker);
+        let script_sig = tx.inputs[0].script_sig.as_bytes();
+        let pubkey = &script_sig[script_sig.len() - 33..];
+        let script_pubkey = p2pkh(pubkey);
+        assert_eq!(
+            run_spend(script_sig, &script_pubkey, &context),
+            Ok(vec![vec![1]])
+        );
+
+        // Changing a byte of r breaks the signature.
+        let mut bad_sig = script_sig.to_vec();
+        bad_sig[10] ^= 1;
+        assert_eq!(
+            run_spend(&bad_sig, &script_pubkey, &context),
+            Err(ScriptError::SigNullFail)
+        );
+        let context = ExecutionContext::new(VerificationFlags::NONE, &checker);
+        assert_eq!(
+            run_spend(&bad_sig, &script_pubkey, &context),
+            Ok(vec![vec![]])
+        );
+        // OP_CHECKSIGVERIFY fails right away on a false result.
+        let mut verify_script = script_pubkey.clone();
+        *verify_script.last_mut().unwrap() = 0xad;
+        assert_eq!(
+            run_spend(&bad_sig, &verify_script, &context),
+            Err(ScriptError::CheckSigVerify)
+        );
+    }
+
+    #[test]
+    fn test_checksig_witness_v0() {
+        let tx = Transaction::from_hex(BIP143_P2WPKH_SPEND).unwrap();
+        let witness: Vec<Vec<u8>> = tx.inputs[1].witness.iter().map(<[u8]>::to_vec).collect();
+        let script_code = Script::from(p2pkh(&witness[1]));
+        let checker = TransactionSignatureChecker::new(&tx, 1, 600_000_000);
+        let mut context = ExecutionContext::new(VerificationFlags::NONE, &checker);
+        context.sig_version = SigVersion::WitnessV0;
+        let mut stack = witness.clone();
+        assert_eq!(script_code.evaluate(&mut stack, &context), Ok(()));
+        assert_eq!(stack, vec![vec![1]]);
+
+        // The amount is committed to.
+        let checker = TransactionSignatureChecker::new(&tx, 1, 600_000_001);
+        context.checker = &checker;
+        let mut stack = witness;
+        assert_eq!(script_code.evaluate(&mut stack, &context), Ok(()));
+        assert_eq!(stack, [Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn test_checkmultisig() {
+        let keys: Vec<PrivateKey> = (1..=3u32)
+            .map(|secret| PrivateKey::new(BigInt::from(secret)).unwrap())
+            .collect();
+        let mut script_pubkey = Script::new();
+        script_pubkey.push_int(2);
+        for key in &keys {
+            script_pubkey.push_slice(&key.public_key().sec(true));
+        }
+        script_pubkey
+            .push_int(3)
+            .push_opcode(Opcode::OP_CHECKMULTISIG);
+        let prevout = TxOut::new(10_000, script_pubkey.clone());
+        let mut tx = Transaction::new(
+            1,
+            vec![TxIn::new(
+                OutPoint::new([2; 32], 0),
+                Script::new(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(9_000, Script::new())],
+            0,
+        );
+        let signing_data = SigningData::new(vec![keys[0].clone(), keys[2].clone()]);
+        tx.sign_input(0, &[prevout], &signing_data).unwrap();
+
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0);
+        let flags = VerificationFlags::NULLDUMMY | VerificationFlags::NULLFAIL;
+        let context = ExecutionContext::new(flags, &checker);
+        let script_sig = tx.inputs[0].script_sig.as_bytes().to_vec();
+        assert_eq!(script_sig[0], 0x00);
+        assert_eq!(
+            run_spend(&script_sig, &script_pubkey, &context),
+            Ok(vec![vec![1]])
+        );
+
+        // The dummy element must be empty under NULLDUMMY.
+        let mut non_null_dummy = script_sig.clone();
+        non_null_dummy[0] = 0x51;
+        assert_eq!(
+            run_spend(&non_null_dummy, &script_pubkey, &context),
+            Err(ScriptError::SigNullDummy)
+        );
+        let lax = ExecutionContext::new(VerificationFlags::NONE, &checker);
+        assert_eq!(
+            run_spend(&non_null_dummy, &script_pubkey, &lax),
+            Ok(vec![vec![1]])
+        );
+
+        // Signatures in the wrong order do not match the keys.
+        let mut stack = Vec::new();
+        Script::from(script_sig).evaluate(&mut stack, &lax).unwrap();
+        stack.swap(1, 2);
+        script_pubkey.evaluate(&mut stack, &lax).unwrap();
+        assert_eq!(stack, [Vec::<u8>::new()]);
+
+        // Without the dummy element there are too few items on the stack.
+        assert_eq!(
+            run_spend(&tx.inputs[0].script_sig[1..], &script_pubkey, &lax),
+            Err(ScriptError::InvalidStackOperation)
+        );
+        // More signatures than keys.
+        assert_eq!(
+            run(&[0x00, 0x00, 0x00, 0x52, 0x51, 0x51, 0xae]),
+            Err(ScriptError::SigCount)
+        );
+        // 0-of-0 always succeeds.
+        assert_eq!(run(&[0x00, 0x00, 0x00, 0xae]), Ok(vec![vec![1]]));
+    }
+
+    #[test]
+    fn test_signature_encoding_flags() {
+        let context = |flags| ExecutionContext::new(flags, &NoSignatureChecker);
+        let eval = |stack: Vec<Vec<u8>>, flags: VerificationFlags| {
+            let mut stack = stack;
+            Script::from(vec![0xac]).evaluate(&mut stack, &context(flags))
+        };
+        let pubkey = PrivateKey::new(BigInt::from(1))
+            .unwrap()
+            .public_key()
+            .sec(true);
+        let mut sig = hex::decode("3045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01").unwrap();
+        assert_eq!(
+            eval(vec![sig.clone(), pubkey.clone()], VerificationFlags::LOW_S),
+            Ok(())
+        );
+
+        *sig.last_mut().unwrap() = 0x04;
+        assert_eq!(
+            eval(
+                vec![sig.clone(), pubkey.clone()],
+                VerificationFlags::STRICTENC
+            ),
+            Err(ScriptError::SigHashType)
+        );
+        assert_eq!(
+            eval(
+                vec![sig[1..].to_vec(), pubkey.clone()],
+                VerificationFlags::DERSIG
+            ),
+            Err(ScriptError::SigDer)
+        );
+        assert_eq!(
+            eval(vec![vec![], vec![0x05; 33]], VerificationFlags::STRICTENC),
+            Err(ScriptError::PubKeyType)
+        );
+        let uncompressed = PrivateKey::new(BigInt::from(1))
+            .unwrap()
+            .public_key()
+            .sec(false);
+        let mut witness_context = context(VerificationFlags::WITNESS_PUBKEYTYPE);
+        witness_context.sig_version = SigVersion::WitnessV0;
+        let mut stack = vec![vec![], uncompressed];
+        assert_eq!(
+            Script::from(vec![0xac]).evaluate(&mut stack, &witness_context),
+            Err(ScriptError::WitnessPubKeyType)
+        );
+    }
+
+    #[test]
+    fn test_find_and_delete() {
+        let mut script = vec![0x02, 0xff, 0x03, 0x02, 0xff, 0x03, 0x51];
+        assert_eq!(find_and_delete(&mut script, &[0x02, 0xff, 0x03]), 2);
+        assert_eq!(script, [0x51]);
+        // Matches that do not start at an instruction boundary are kept.
+        let mut script = vec![0x02, 0x51, 0x52, 0x52];
+        assert_eq!(find_and_delete(&mut script, &[0x51, 0x52]), 0);
+        assert_eq!(script, [0x02, 0x51, 0x52, 0x52]);
+        assert_eq!(
+            remove_code_separators(&[0x51, 0xab, 0x01, 0xab, 0xab]),
+            [0x51, 0x01, 0xab]
+        );
+    }
+}
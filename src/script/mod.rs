@@ -0,0 +1,333 @@
+// Scripts: the Script type and helpers to build and take apart raw scripts.
+pub mod asm;
+pub mod debugger;
+pub mod flags;
+pub mod htlc;
+pub mod instruction;
+pub mod interpreter;
+pub mod num;
+pub mod opcode;
+pub mod sigcache;
+pub mod sigops;
+pub mod templates;
+pub mod verify;
+
+pub use asm::to_asm;
+pub use flags::VerificationFlags;
+pub use instruction::{Instruction, Instructions};
+pub use opcode::Opcode;
+pub use sigcache::SignatureCache;
+pub use templates::{classify, ScriptType};
+
+use crate::helper::{encode_var_bytes, read_var_bytes};
+use crate::types::errors::Errors;
+use instruction::read_instruction;
+use num::encode_script_num;
+use std::fmt;
+use std::io::Read;
+use std::ops::Deref;
+
+// Minimal push of data: a direct push up to 75 bytes, OP_PUSHDATA1/2/4 above that.
+pub fn encode_push(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len() + 5);
+    match data.len() {
+        len @ 0..=0x4b => result.push(len as u8),
+        len @ 0x4c..=0xff => result.extend([Opcode::OP_PUSHDATA1.to_u8(), len as u8]),
+        len @ 0x100..=0xffff => {
+            result.push(Opcode::OP_PUSHDATA2.to_u8());
+            result.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+        len => {
+            result.push(Opcode::OP_PUSHDATA4.to_u8());
+            result.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+    }
+    result.extend_from_slice(data);
+    result
+}
+
+// OP_m <pubkey>... OP_n OP_CHECKMULTISIG, returning m and the pushed keys.
+pub fn parse_multisig(script: &[u8]) -> Option<(u8, Vec<&[u8]>)> {
+    let ops = parse_ops(script)?;
+    if ops.len() < 4 || ops[ops.len() - 1].0 != Opcode::OP_CHECKMULTISIG.to_u8() {
+        return None;
+    }
+    let m = small_int(ops[0].0)?;
+    let n = small_int(ops[ops.len() - 2].0)?;
+    let keys = &ops[1..ops.len() - 2];
+    if m > n || keys.len() != n as usize || keys.iter().any(|(opcode, _)| *opcode > 0x4e) {
+        return None;
+    }
+    Some((m, keys.iter().map(|(_, data)| *data).collect()))
+}
+
+pub fn is_p2pkh(script: &[u8]) -> bool {
+    script.len() == 25 && script[..3] == [0x76, 0xa9, 0x14] && script[23..] == [0x88, 0xac]
+}
+
+pub fn is_p2sh(script: &[u8]) -> bool {
+    script.len() == 23 && script[..2] == [0xa9, 0x14] && script[22] == 0x87
+}
+
+// The public key of a <pubkey> OP_CHECKSIG script.
+pub fn p2pk_pubkey(script: &[u8]) -> Option<&[u8]> {
+    let is_p2pk = (script.len() == 35 && script[0] == 33 || script.len() == 67 && script[0] == 65)
+        && script[script.len() - 1] == 0xac;
+    is_p2pk.then(|| &script[1..script.len() - 1])
+}
+
+// Returns the witness version and program if the script is OP_n <2 to 40 bytes>.
+pub fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 {
+        return None;
+    }
+    let version = if script[0] == 0x00 {
+        0
+    } else {
+        small_int(script[0]).filter(|v| *v >= 1)?
+    };
+    if script[1] as usize + 2 != script.len() {
+        return None;
+    }
+    Some((version, &script[2..]))
+}
+
+pub fn small_int(opcode: u8) -> Option<u8> {
+    match opcode {
+        0x00 => Some(0),
+        0x51..=0x60 => Some(opcode - 0x50),
+        _ => None,
+    }
+}
+
+pub fn is_push_only(script: &[u8]) -> bool {
+    parse_ops(script).is_some_and(|ops| ops.iter().all(|(opcode, _)| *opcode <= 0x60))
+}
+
+// Splits a script into (opcode, pushed data) pairs, None if a push runs past the end.
+pub fn parse_ops(script: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut ops = Vec::new();
+    let mut rest = script;
+    while !rest.is_empty() {
+        let (opcode, data, len) = read_instruction(rest)?;
+        ops.push((opcode, data));
+        rest = &rest[len..];
+    }
+    Some(ops)
+}
+
+// Raw script bytes. Instructions are decoded on demand, so scripts that do not
+// parse (which can appear in valid transactions) are stored as they are.
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Script(Vec<u8>);
+
+impl Script {
+    pub fn new() -> Self {
+        Script(Vec::new())
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Script(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions::new(&self.0)
+    }
+
+    // Length-prefixed, as scripts are encoded inside transactions.
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        Ok(Script(read_var_bytes(reader)?))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        encode_var_bytes(&self.0)
+    }
+
+    pub fn push_opcode(&mut self, opcode: Opcode) -> &mut Self {
+        self.0.push(opcode.to_u8());
+        self
+    }
+
+    pub fn push_slice(&mut self, data: &[u8]) -> &mut Self {
+        self.0.extend(encode_push(data));
+        self
+    }
+
+    // Pushes n with the shortest encoding: OP_1NEGATE, OP_0 to OP_16 or a number push.
+    pub fn push_int(&mut self, n: i64) -> &mut Self {
+        match n {
+            -1 => self.push_opcode(Opcode::OP_1NEGATE),
+            0 => self.push_opcode(Opcode::OP_0),
+            1..=16 => {
+                self.0.push(Opcode::OP_1.to_u8() + n as u8 - 1);
+                self
+            }
+            _ => self.push_slice(&encode_script_num(n)),
+        }
+    }
+}
+
+impl Deref for Script {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Script {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Script {
+    fn from(bytes: Vec<u8>) -> Self {
+        Script(bytes)
+    }
+}
+
+impl From<&[u8]> for Script {
+    fn from(bytes: &[u8]) -> Self {
+        Script(bytes.to_vec())
+    }
+}
+
+impl PartialEq<[u8]> for Script {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<Vec<u8>> for Script {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        &self.0 == other
+    }
+}
+
+// Prints the script in asm form.
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", to_asm(&self.0, false))
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Script({})", self)
+    }
+}
+
+#[cfg(test)]
+mod script_tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_push() {
+        assert_eq!(encode_push(&[]), vec![0x00]);
+        assert_eq!(encode_push(&[0xab; 3]), vec![0x03, 0xab, 0xab, 0xab]);
+        assert_eq!(encode_push(&[0u8; 76])[..2], [0x4c, 76]);
+        assert_eq!(encode_push(&[0u8; 256])[..3], [0x4d, 0x00, 0x01]);
+
+        for len in [0, 20, 75, 76, 255, 256, 520] {
+            let script = encode_push(&vec![7u8; len]);
+            let ops = parse_ops(&script).unwrap();
+            assert_eq!(ops.len(), 1);
+            assert_eq!(ops[0].1.len(), len);
+        }
+    }
+
+    #[test]
+    fn test_parse_multisig() {
+        let mut script = vec![0x52];
+        for key in [[2u8; 33], [3u8; 33], [4u8; 33]] {
+            script.extend(encode_push(&key));
+        }
+        script.extend([0x53, Opcode::OP_CHECKMULTISIG.to_u8()]);
+
+        let (m, keys) = parse_multisig(&script).unwrap();
+        assert_eq!(m, 2);
+        assert_eq!(keys, vec![&[2u8; 33][..], &[3u8; 33][..], &[4u8; 33][..]]);
+
+        // n does not match the number of keys.
+        let len = script.len();
+        script[len - 2] = 0x52;
+        assert_eq!(parse_multisig(&script), None);
+    }
+
+    #[test]
+    fn test_witness_program() {
+        let mut script = vec![0x00, 0x14];
+        script.extend([0u8; 20]);
+        assert_eq!(witness_program(&script), Some((0, &[0u8; 20][..])));
+        script[0] = 0x51;
+        assert_eq!(witness_program(&script).map(|(v, _)| v), Some(1));
+        script[1] = 0x15;
+        assert_eq!(witness_program(&script), None);
+    }
+
+    #[test]
+    fn test_script_instructions() {
+        let script = Script::from_bytes(
+            hex::decode("76a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac").unwrap(),
+        );
+        let instructions: Vec<Instruction> =
+            script.instructions().collect::<Result<_, _>>().unwrap();
+        assert_eq!(instructions.len(), 5);
+        assert_eq!(instructions[0], Instruction::Op(Opcode::OP_DUP));
+        assert_eq!(instructions[1].opcode(), Some(Opcode::OP_HASH160));
+        assert_eq!(instructions[2].push_bytes().unwrap().len(), 20);
+        assert_eq!(instructions[4], Instruction::Op(Opcode::OP_CHECKSIG));
+
+        // OP_0 is an empty push, and a truncated push ends the iteration with an error.
+        let script = Script::from_bytes(vec![0x00, 0x4c, 0x05, 0x01]);
+        let mut instructions = script.instructions();
+        assert_eq!(instructions.next(), Some(Ok(Instruction::PushBytes(&[]))));
+        assert_eq!(instructions.position(), 1);
+        assert_eq!(instructions.next(), Some(Err(Errors::UnexpectedEof)));
+        assert_eq!(instructions.next(), None);
+    }
+
+    #[test]
+    fn test_script_serialize_round_trip() {
+        let mut script = Script::new();
+        script
+            .push_int(2)
+            .push_slice(&[0x02; 33])
+            .push_int(1)
+            .push_opcode(Opcode::OP_CHECKMULTISIG);
+        let encoded = script.serialize();
+        assert_eq!(encoded[0] as usize, script.len());
+
+        let parsed = Script::parse(&mut encoded.as_slice()).unwrap();
+        assert_eq!(parsed, script);
+        assert_eq!(
+            Script::parse(&mut [0x05, 0x00].as_slice()),
+            Err(Errors::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn test_push_int() {
+        let mut script = Script::new();
+        script
+            .push_int(-1)
+            .push_int(0)
+            .push_int(16)
+            .push_int(17)
+            .push_int(-2);
+        assert_eq!(
+            script.as_bytes(),
+            [0x4f, 0x00, 0x60, 0x01, 0x11, 0x01, 0x82]
+        );
+        assert_eq!(script.to_string(), "-1 0 16 17 -2");
+    }
+}
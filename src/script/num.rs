@@ -0,0 +1,117 @@
+// Numbers on the script stack: little endian, sign and magnitude, minimally encoded.
+use crate::types::errors::ScriptError;
+
+// Arithmetic opcodes take operands of at most 4 bytes, locktime checks 5.
+pub const DEFAULT_MAX_NUM_SIZE: usize = 4;
+
+pub fn encode_script_num(value: i64) -> Vec<u8> {
+    if value == 0 {
+        return Vec::new();
+    }
+    let negative = value < 0;
+    let mut magnitude = value.unsigned_abs();
+    let mut result = Vec::new();
+    while magnitude > 0 {
+        result.push((magnitude & 0xff) as u8);
+        magnitude >>= 8;
+    }
+    // The sign goes in the high bit of the last byte, which needs an extra
+    // byte when that bit is already taken by the magnitude.
+    let last = result.len() - 1;
+    if result[last] & 0x80 != 0 {
+        result.push(if negative { 0x80 } else { 0x00 });
+    } else if negative {
+        result[last] |= 0x80;
+    }
+    result
+}
+
+pub fn decode_script_num(data: &[u8]) -> i64 {
+    let Some(last) = data.last() else {
+        return 0;
+    };
+    let mut result = 0i64;
+    for (i, byte) in data.iter().enumerate() {
+        result |= (*byte as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        -(result & !(0x80i64 << (8 * (data.len() - 1))))
+    } else {
+        result
+    }
+}
+
+// Number from a stack element as the interpreter reads it (CScriptNum in Bitcoin
+// Core). Results can exceed max_len bytes, operands cannot.
+pub fn read_script_num(
+    data: &[u8],
+    require_minimal: bool,
+    max_len: usize,
+) -> Result<i64, ScriptError> {
+    if data.len() > max_len {
+        return Err(ScriptError::NumberOverflow);
+    }
+    if require_minimal && !is_minimally_encoded(data) {
+        return Err(ScriptError::NonMinimalNumber);
+    }
+    Ok(decode_script_num(data))
+}
+
+// No trailing zero byte (or 0x80 sign byte) unless needed to keep the sign bit free.
+fn is_minimally_encoded(data: &[u8]) -> bool {
+    match data {
+        [] => true,
+        [.., last] if last & 0x7f != 0 => true,
+        [_] => false,
+        [.., before_last, _] => before_last & 0x80 != 0,
+    }
+}
+
+#[cfg(test)]
+mod num_tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_script_num() {
+        assert_eq!(encode_script_num(0), Vec::<u8>::new());
+        assert_eq!(encode_script_num(1), vec![0x01]);
+        assert_eq!(encode_script_num(-1), vec![0x81]);
+        assert_eq!(encode_script_num(127), vec![0x7f]);
+        assert_eq!(encode_script_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_script_num(-128), vec![0x80, 0x80]);
+        assert_eq!(encode_script_num(500_000), vec![0x20, 0xa1, 0x07]);
+    }
+
+    #[test]
+    fn test_decode_script_num() {
+        assert_eq!(decode_script_num(&[]), 0);
+        assert_eq!(decode_script_num(&[0x7f]), 127);
+        assert_eq!(decode_script_num(&[0x80, 0x00]), 128);
+        assert_eq!(decode_script_num(&[0xff]), -127);
+        assert_eq!(decode_script_num(&[0x00, 0x80]), 0);
+        assert_eq!(decode_script_num(&[0xff, 0xff, 0xff, 0xff]), -0x7fffffff);
+        for value in [-70_000, -255, -1, 1, 255, 256, 0x7fffffff] {
+            assert_eq!(decode_script_num(&encode_script_num(value)), value);
+        }
+    }
+
+    #[test]
+    fn test_read_script_num() {
+        assert_eq!(read_script_num(&[0x01, 0x02], true, 4), Ok(0x0201));
+        assert_eq!(read_script_num(&[0x80, 0x00], true, 4), Ok(128));
+        assert_eq!(
+            read_script_num(&[0x01, 0x00], true, 4),
+            Err(ScriptError::NonMinimalNumber)
+        );
+        assert_eq!(
+            read_script_num(&[0x80], true, 4),
+            Err(ScriptError::NonMinimalNumber)
+        );
+        assert_eq!(read_script_num(&[0x01, 0x00], false, 4), Ok(1));
+        assert_eq!(
+            read_script_num(&[1; 5], true, 4),
+            Err(ScriptError::NumberOverflow)
+        );
+        assert!(read_script_num(&[1; 5], true, 5).is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+// Every opcode defined by Bitcoin Core, with its byte value and the name Core prints.
+use std::fmt;
+
+macro_rules! opcodes {
+    ($($opcode:ident = $value:literal => $name:literal,)*) => {
+        // Opcodes that are not data pushes of 1 to 75 bytes. Bytes that have no
+        // opcode assigned (0xbb to 0xfe) are not part of the enum.
+        #[allow(non_camel_case_types)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(u8)]
+        pub enum Opcode {
+            $($opcode = $value,)*
+        }
+
+        impl Opcode {
+            pub fn from_u8(byte: u8) -> Option<Opcode> {
+                match byte {
+                    $($value => Some(Opcode::$opcode),)*
+                    _ => None,
+                }
+            }
+
+            // Small integers are named by their value, as in Core's GetOpName.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$opcode => $name,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    OP_0 = 0x00 => "0",
+    OP_PUSHDATA1 = 0x4c => "OP_PUSHDATA1",
+    OP_PUSHDATA2 = 0x4d => "OP_PUSHDATA2",
+    OP_PUSHDATA4 = 0x4e => "OP_PUSHDATA4",
+    OP_1NEGATE = 0x4f => "-1",
+    OP_RESERVED = 0x50 => "OP_RESERVED",
+    OP_1 = 0x51 => "1",
+    OP_2 = 0x52 => "2",
+    OP_3 = 0x53 => "3",
+    OP_4 = 0x54 => "4",
+    OP_5 = 0x55 => "5",
+    OP_6 = 0x56 => "6",
+    OP_7 = 0x57 => "7",
+    OP_8 = 0x58 => "8",
+    OP_9 = 0x59 => "9",
+    OP_10 = 0x5a => "10",
+    OP_11 = 0x5b => "11",
+    OP_12 = 0x5c => "12",
+    OP_13 = 0x5d => "13",
+    OP_14 = 0x5e => "14",
+    OP_15 = 0x5f => "15",
+    OP_16 = 0x60 => "16",
+    OP_NOP = 0x61 => "OP_NOP",
+    OP_VER = 0x62 => "OP_VER",
+    OP_IF = 0x63 => "OP_IF",
+    OP_NOTIF = 0x64 => "OP_NOTIF",
+    OP_VERIF = 0x65 => "OP_VERIF",
+    OP_VERNOTIF = 0x66 => "OP_VERNOTIF",
+    OP_ELSE = 0x67 => "OP_ELSE",
+    OP_ENDIF = 0x68 => "OP_ENDIF",
+    OP_VERIFY = 0x69 => "OP_VERIFY",
+    OP_RETURN = 0x6a => "OP_RETURN",
+    OP_TOALTSTACK = 0x6b => "OP_TOALTSTACK",
+    OP_FROMALTSTACK = 0x6c => "OP_FROMALTSTACK",
+    OP_2DROP = 0x6d => "OP_2DROP",
+    OP_2DUP = 0x6e => "OP_2DUP",
+    OP_3DUP = 0x6f => "OP_3DUP",
+    OP_2OVER = 0x70 => "OP_2OVER",
+    OP_2ROT = 0x71 => "OP_2ROT",
+    OP_2SWAP = 0x72 => "OP_2SWAP",
+    OP_IFDUP = 0x73 => "OP_IFDUP",
+    OP_DEPTH = 0x74 => "OP_DEPTH",
+    OP_DROP = 0x75 => "OP_DROP",
+    OP_DUP = 0x76 => "OP_DUP",
+    OP_NIP = 0x77 => "OP_NIP",
+    OP_OVER = 0x78 => "OP_OVER",
+    OP_PICK = 0x79 => "OP_PICK",
+    OP_ROLL = 0x7a => "OP_ROLL",
+    OP_ROT = 0x7b => "OP_ROT",
+    OP_SWAP = 0x7c => "OP_SWAP",
+    OP_TUCK = 0x7d => "OP_TUCK",
+    OP_CAT = 0x7e => "OP_CAT",
+    OP_SUBSTR = 0x7f => "OP_SUBSTR",
+    OP_LEFT = 0x80 => "OP_LEFT",
+    OP_RIGHT = 0x81 => "OP_RIGHT",
+    OP_SIZE = 0x82 => "OP_SIZE",
+    OP_INVERT = 0x83 => "OP_INVERT",
+    OP_AND = 0x84 => "OP_AND",
+    OP_OR = 0x85 => "OP_OR",
+    OP_XOR = 0x86 => "OP_XOR",
+    OP_EQUAL = 0x87 => "OP_EQUAL",
+    OP_EQUALVERIFY = 0x88 => "OP_EQUALVERIFY",
+    OP_RESERVED1 = 0x89 => "OP_RESERVED1",
+    OP_RESERVED2 = 0x8a => "OP_RESERVED2",
+    OP_1ADD = 0x8b => "OP_1ADD",
+    OP_1SUB = 0x8c => "OP_1SUB",
+    OP_2MUL = 0x8d => "OP_2MUL",
+    OP_2DIV = 0x8e => "OP_2DIV",
+    OP_NEGATE = 0x8f => "OP_NEGATE",
+    OP_ABS = 0x90 => "OP_ABS",
+    OP_NOT = 0x91 => "OP_NOT",
+    OP_0NOTEQUAL = 0x92 => "OP_0NOTEQUAL",
+    OP_ADD = 0x93 => "OP_ADD",
+    OP_SUB = 0x94 => "OP_SUB",
+    OP_MUL = 0x95 => "OP_MUL",
+    OP_DIV = 0x96 => "OP_DIV",
+    OP_MOD = 0x97 => "OP_MOD",
+    OP_LSHIFT = 0x98 => "OP_LSHIFT",
+    OP_RSHIFT = 0x99 => "OP_RSHIFT",
+    OP_BOOLAND = 0x9a => "OP_BOOLAND",
+    OP_BOOLOR = 0x9b => "OP_BOOLOR",
+    OP_NUMEQUAL = 0x9c => "OP_NUMEQUAL",
+    OP_NUMEQUALVERIFY = 0x9d => "OP_NUMEQUALVERIFY",
+    OP_NUMNOTEQUAL = 0x9e => "OP_NUMNOTEQUAL",
+    OP_LESSTHAN = 0x9f => "OP_LESSTHAN",
+    OP_GREATERTHAN = 0xa0 => "OP_GREATERTHAN",
+    OP_LESSTHANOREQUAL = 0xa1 => "OP_LESSTHANOREQUAL",
+    OP_GREATERTHANOREQUAL = 0xa2 => "OP_GREATERTHANOREQUAL",
+    OP_MIN = 0xa3 => "OP_MIN",
+    OP_MAX = 0xa4 => "OP_MAX",
+    OP_WITHIN = 0xa5 => "OP_WITHIN",
+    OP_RIPEMD160 = 0xa6 => "OP_RIPEMD160",
+    OP_SHA1 = 0xa7 => "OP_SHA1",
+    OP_SHA256 = 0xa8 => "OP_SHA256",
+    OP_HASH160 = 0xa9 => "OP_HASH160",
+    OP_HASH256 = 0xaa => "OP_HASH256",
+    OP_CODESEPARATOR = 0xab => "OP_CODESEPARATOR",
+    OP_CHECKSIG = 0xac => "OP_CHECKSIG",
+    OP_CHECKSIGVERIFY = 0xad => "OP_CHECKSIGVERIFY",
+    OP_CHECKMULTISIG = 0xae => "OP_CHECKMULTISIG",
+    OP_CHECKMULTISIGVERIFY = 0xaf => "OP_CHECKMULTISIGVERIFY",
+    OP_NOP1 = 0xb0 => "OP_NOP1",
+    OP_CHECKLOCKTIMEVERIFY = 0xb1 => "OP_CHECKLOCKTIMEVERIFY",
+    OP_CHECKSEQUENCEVERIFY = 0xb2 => "OP_CHECKSEQUENCEVERIFY",
+    OP_NOP4 = 0xb3 => "OP_NOP4",
+    OP_NOP5 = 0xb4 => "OP_NOP5",
+    OP_NOP6 = 0xb5 => "OP_NOP6",
+    OP_NOP7 = 0xb6 => "OP_NOP7",
+    OP_NOP8 = 0xb7 => "OP_NOP8",
+    OP_NOP9 = 0xb8 => "OP_NOP9",
+    OP_NOP10 = 0xb9 => "OP_NOP10",
+    OP_CHECKSIGADD = 0xba => "OP_CHECKSIGADD",
+    OP_INVALIDOPCODE = 0xff => "OP_INVALIDOPCODE",
+}
+
+impl Opcode {
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    // Inverse of name.
+    pub fn from_name(name: &str) -> Option<Opcode> {
+        (0..=255)
+            .filter_map(Opcode::from_u8)
+            .find(|opcode| opcode.name() == name)
+    }
+
+    // OP_0 and OP_PUSHDATA1/2/4, which push the data that follows them.
+    pub fn is_push(self) -> bool {
+        self <= Opcode::OP_PUSHDATA4
+    }
+
+    // The value pushed by OP_1NEGATE and OP_1 to OP_16.
+    pub fn small_int(self) -> Option<i64> {
+        match self {
+            Opcode::OP_1NEGATE => Some(-1),
+            op if (Opcode::OP_1..=Opcode::OP_16).contains(&op) => Some(op as i64 - 0x50),
+            _ => None,
+        }
+    }
+
+    // OP_IF to OP_ENDIF, which are looked at even inside branches not executed.
+    pub fn is_conditional(self) -> bool {
+        (Opcode::OP_IF..=Opcode::OP_ENDIF).contains(&self)
+    }
+
+    // Opcodes that fail the script wherever they appear, even when not executed.
+    pub fn is_disabled(self) -> bool {
+        matches!(
+            self,
+            Opcode::OP_CAT
+                | Opcode::OP_SUBSTR
+                | Opcode::OP_LEFT
+                | Opcode::OP_RIGHT
+                | Opcode::OP_INVERT
+                | Opcode::OP_AND
+                | Opcode::OP_OR
+                | Opcode::OP_XOR
+                | Opcode::OP_2MUL
+                | Opcode::OP_2DIV
+                | Opcode::OP_MUL
+                | Opcode::OP_DIV
+                | Opcode::OP_MOD
+                | Opcode::OP_LSHIFT
+                | Opcode::OP_RSHIFT
+        )
+    }
+}
+
+// Bytes that make a tapscript succeed as soon as they appear anywhere in it, so
+// BIP342 can give them any meaning in future soft forks.
+pub fn is_op_success(byte: u8) -> bool {
+    matches!(
+        byte,
+        0x50 | 0x62 | 0x7e..=0x81 | 0x83..=0x86 | 0x89..=0x8a | 0x8d..=0x8e | 0x95..=0x99 | 0xbb..=0xfe
+    )
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> u8 {
+        opcode as u8
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Name of any byte in opcode position, OP_UNKNOWN for bytes without an opcode.
+pub fn opcode_name(byte: u8) -> &'static str {
+    Opcode::from_u8(byte).map_or("OP_UNKNOWN", Opcode::name)
+}
+
+#[cfg(test)]
+mod opcode_tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_round_trip() {
+        let mut defined = 0;
+        for byte in 0..=255u8 {
+            if let Some(opcode) = Opcode::from_u8(byte) {
+                assert_eq!(opcode.to_u8(), byte);
+                defined += 1;
+            }
+        }
+        // OP_0, OP_PUSHDATA1 to OP_CHECKSIGADD and OP_INVALIDOPCODE.
+        assert_eq!(defined, 1 + (0xba - 0x4c + 1) + 1);
+        assert_eq!(Opcode::from_u8(0x14), None);
+        assert_eq!(Opcode::from_u8(0xbb), None);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Opcode::OP_0.name(), "0");
+        assert_eq!(Opcode::OP_16.to_string(), "16");
+        assert_eq!(Opcode::OP_CHECKSIGADD.name(), "OP_CHECKSIGADD");
+        assert_eq!(opcode_name(0xfe), "OP_UNKNOWN");
+        assert_eq!(Opcode::from_name("OP_CHECKSIG"), Some(Opcode::OP_CHECKSIG));
+        assert_eq!(Opcode::from_name("-1"), Some(Opcode::OP_1NEGATE));
+        assert_eq!(Opcode::from_name("OP_UNKNOWN"), None);
+    }
+
+    #[test]
+    fn test_classification() {
+        assert!(Opcode::OP_PUSHDATA2.is_push());
+        assert!(!Opcode::OP_1NEGATE.is_push());
+        assert_eq!(Opcode::OP_1NEGATE.small_int(), Some(-1));
+        assert_eq!(Opcode::OP_16.small_int(), Some(16));
+        assert_eq!(Opcode::OP_RESERVED.small_int(), None);
+        assert!(Opcode::OP_VERIF.is_conditional());
+        assert!(!Opcode::OP_VERIFY.is_conditional());
+        let disabled = (0..=255)
+            .filter_map(Opcode::from_u8)
+            .filter(|op| op.is_disabled());
+        assert_eq!(disabled.count(), 15);
+        assert!(!Opcode::OP_SIZE.is_disabled());
+    }
+
+    #[test]
+    fn test_op_success() {
+        // BIP342 redefines these 87 bytes, OP_CAT and OP_RESERVED among them.
+        assert_eq!((0..=255).filter(|byte| is_op_success(*byte)).count(), 87);
+        assert!(is_op_success(Opcode::OP_CAT.to_u8()));
+        assert!(is_op_success(Opcode::OP_RESERVED.to_u8()));
+        assert!(!is_op_success(Opcode::OP_CHECKSIGADD.to_u8()));
+        assert!(!is_op_success(Opcode::OP_INVALIDOPCODE.to_u8()));
+    }
+}
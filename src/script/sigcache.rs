@@ -0,0 +1,147 @@
+// Cache of signatures already found valid, as CSignatureCache in Bitcoin Core. A
+// transaction checked when it entered the mempool doesn't need its signatures
+// verified again when the block including it is connected.
+use crate::helper::{encode_var_bytes, tagged_hash};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+pub const DEFAULT_SIGNATURE_CACHE_SIZE: usize = 50_000;
+
+// Entries are kept by hash of (sighash, pubkey, signature), evicting the least
+// recently used one once capacity is reached.
+#[derive(Debug)]
+pub struct SignatureCache {
+    capacity: usize,
+    inner: Mutex<Lru>,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    // Entry to the tick it was last used at, and ticks back to entries by age.
+    entries: HashMap<[u8; 32], u64>,
+    by_age: BTreeMap<u64, [u8; 32]>,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, entry: [u8; 32]) {
+        self.tick += 1;
+        if let Some(old) = self.entries.insert(entry, self.tick) {
+            self.by_age.remove(&old);
+        }
+        self.by_age.insert(self.tick, entry);
+    }
+
+    fn remove(&mut self, entry: &[u8; 32]) {
+        if let Some(tick) = self.entries.remove(entry) {
+            self.by_age.remove(&tick);
+        }
+    }
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        SignatureCache::new(DEFAULT_SIGNATURE_CACHE_SIZE)
+    }
+}
+
+impl SignatureCache {
+    pub fn new(capacity: usize) -> Self {
+        SignatureCache {
+            capacity,
+            inner: Mutex::new(Lru::default()),
+        }
+    }
+
+    // Pubkey and signature are length prefixed so no two triples share an entry.
+    pub fn entry(sighash: &[u8; 32], pubkey: &[u8], signature: &[u8]) -> [u8; 32] {
+        let mut data = sighash.to_vec();
+        data.extend(encode_var_bytes(pubkey));
+        data.extend(encode_var_bytes(signature));
+        tagged_hash("SignatureCache", &data)
+    }
+
+    // A hit counts as a use. With erase set the entry is dropped instead, as the
+    // signatures of a transaction being connected in a block won't be seen again.
+    pub fn contains(&self, entry: &[u8; 32], erase: bool) -> bool {
+        let mut lru = self.inner.lock().unwrap();
+        if !lru.entries.contains_key(entry) {
+            return false;
+        }
+        if erase {
+            lru.remove(entry);
+        } else {
+            lru.touch(*entry);
+        }
+        true
+    }
+
+    pub fn insert(&self, entry: [u8; 32]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lru = self.inner.lock().unwrap();
+        lru.touch(entry);
+        while lru.entries.len() > self.capacity {
+            let (_, oldest) = lru.by_age.pop_first().unwrap();
+            lru.entries.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod sigcache_tests {
+    use super::*;
+
+    fn entry(i: u8) -> [u8; 32] {
+        SignatureCache::entry(&[i; 32], &[2; 33], &[0x30; 71])
+    }
+
+    #[test]
+    fn test_entry_commits_to_every_field() {
+        let base = SignatureCache::entry(&[1; 32], &[2; 33], &[3; 64]);
+        assert_ne!(base, SignatureCache::entry(&[0; 32], &[2; 33], &[3; 64]));
+        assert_ne!(base, SignatureCache::entry(&[1; 32], &[2; 32], &[3; 64]));
+        assert_ne!(base, SignatureCache::entry(&[1; 32], &[2; 33], &[3; 65]));
+        // Moving a byte from the pubkey to the signature changes the entry too.
+        assert_ne!(
+            SignatureCache::entry(&[1; 32], &[2, 2], &[2]),
+            SignatureCache::entry(&[1; 32], &[2], &[2, 2])
+        );
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = SignatureCache::new(2);
+        cache.insert(entry(1));
+        cache.insert(entry(2));
+        // Using 1 makes 2 the oldest.
+        assert!(cache.contains(&entry(1), false));
+        cache.insert(entry(3));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&entry(1), false));
+        assert!(!cache.contains(&entry(2), false));
+        assert!(cache.contains(&entry(3), false));
+    }
+
+    #[test]
+    fn test_erase_on_hit() {
+        let cache = SignatureCache::new(10);
+        cache.insert(entry(1));
+        assert!(cache.contains(&entry(1), true));
+        assert!(!cache.contains(&entry(1), false));
+        assert!(cache.is_empty());
+
+        let disabled = SignatureCache::new(0);
+        disabled.insert(entry(1));
+        assert!(disabled.is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+// Signature operation counting, as done by Bitcoin Core to bound the work of
+// validating a block (GetSigOpCount and CountWitnessSigOps).
+use super::flags::VerificationFlags;
+use super::instruction::read_instruction;
+use super::interpreter::MAX_PUBKEYS_PER_MULTISIG;
+use super::{is_p2sh, parse_ops, small_int, witness_program, Opcode, Script};
+use crate::transaction::Witness;
+
+impl Script {
+    // OP_CHECKSIG counts as one. OP_CHECKMULTISIG counts as its number of keys when
+    // accurate and the script pushes it just before, otherwise as the maximum of 20.
+    // Counting stops at the first push running past the end of the script.
+    pub fn sigop_count(&self, accurate: bool) -> usize {
+        let mut count = 0;
+        let mut last_opcode = Opcode::OP_INVALIDOPCODE.to_u8();
+        let mut rest = self.as_bytes();
+        while let Some((opcode, _, len)) = read_instruction(rest) {
+            match Opcode::from_u8(opcode) {
+                Some(Opcode::OP_CHECKSIG | Opcode::OP_CHECKSIGVERIFY) => count += 1,
+                Some(Opcode::OP_CHECKMULTISIG | Opcode::OP_CHECKMULTISIGVERIFY) => {
+                    count += match small_int(last_opcode).filter(|n| accurate && *n >= 1) {
+                        Some(keys) => keys as usize,
+                        None => MAX_PUBKEYS_PER_MULTISIG as usize,
+                    }
+                }
+                _ => {}
+            }
+            last_opcode = opcode;
+            rest = &rest[len..];
+        }
+        count
+    }
+
+    // Sigops of the redeem script when this is a P2SH scriptPubKey spent by
+    // script_sig, which are invisible to sigop_count. Any other scriptPubKey has
+    // none, its own being counted by sigop_count already.
+    pub fn p2sh_sigop_count(&self, script_sig: &Script) -> usize {
+        if !is_p2sh(self) {
+            return 0;
+        }
+        redeem_script(script_sig).map_or(0, |redeem_script| redeem_script.sigop_count(true))
+    }
+}
+
+// Sigops of the witness program paid to by script_pubkey, directly or nested in P2SH.
+// They are counted at a quarter of the cost of legacy ones.
+pub fn witness_sigop_count(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    witness: &Witness,
+    flags: VerificationFlags,
+) -> usize {
+    if !flags.contains(VerificationFlags::WITNESS) {
+        return 0;
+    }
+    if let Some((version, program)) = witness_program(script_pubkey) {
+        return witness_program_sigops(version, program, witness);
+    }
+    if is_p2sh(script_pubkey) {
+        if let Some(redeem_script) = redeem_script(script_sig) {
+            if let Some((version, program)) = witness_program(&redeem_script) {
+                return witness_program_sigops(version, program, witness);
+            }
+        }
+    }
+    0
+}
+
+// Taproot spends are bounded by the validation budget instead.
+fn witness_program_sigops(version: u8, program: &[u8], witness: &Witness) -> usize {
+    match (version, program.len()) {
+        (0, 20) => 1,
+        (0, 32) => witness
+            .last()
+            .map_or(0, |script| Script::from(script).sigop_count(true)),
+        _ => 0,
+    }
+}
+
+// The last push of a push only scriptSig.
+fn redeem_script(script_sig: &Script) -> Option<Script> {
+    let ops = parse_ops(script_sig)?;
+    if ops
+        .iter()
+        .any(|(opcode, _)| *opcode > Opcode::OP_16.to_u8())
+    {
+        return None;
+    }
+    let (_, data) = ops.last()?;
+    Some(Script::from(*data))
+}
+
+#[cfg(test)]
+mod sigops_tests {
+    use super::*;
+    use crate::helper::hash160;
+
+    fn multisig(required: i64, keys: i64) -> Script {
+        let mut script = Script::new();
+        script.push_int(required);
+        for _ in 0..keys {
+            script.push_slice(&[0x02; 33]);
+        }
+        script.push_int(keys).push_opcode(Opcode::OP_CHECKMULTISIG);
+        script
+    }
+
+    #[test]
+    fn test_sigop_count() {
+        assert_eq!(Script::new_p2pkh(&[0; 20]).sigop_count(false), 1);
+        assert_eq!(multisig(2, 3).sigop_count(true), 3);
+        assert_eq!(multisig(2, 3).sigop_count(false), 20);
+        // OP_0 OP_CHECKMULTISIG, zero keys still counts the maximum.
+        assert_eq!(Script::from(vec![0x00, 0xae]).sigop_count(true), 20);
+        // OP_CHECKSIG OP_CHECKSIGVERIFY, then a truncated push hiding the rest.
+        assert_eq!(
+            Script::from(vec![0xac, 0xad, 0x4c, 0x10, 0xac]).sigop_count(true),
+            2
+        );
+    }
+
+    #[test]
+    fn test_p2sh_sigop_count() {
+        let redeem_script = multisig(1, 2);
+        let script_pubkey = Script::new_p2sh(&hash160(&redeem_script));
+        let mut script_sig = Script::new();
+        script_sig.push_int(0).push_slice(&[0x30; 72]);
+        script_sig.push_slice(&redeem_script);
+        assert_eq!(script_pubkey.sigop_count(true), 0);
+        assert_eq!(script_pubkey.p2sh_sigop_count(&script_sig), 2);
+
+        // Nothing is counted for a scriptSig that is not push only.
+        script_sig.push_opcode(Opcode::OP_NOP);
+        assert_eq!(script_pubkey.p2sh_sigop_count(&script_sig), 0);
+        // Nor for a scriptPubKey that is not P2SH.
+        assert_eq!(redeem_script.p2sh_sigop_count(&Script::new()), 0);
+    }
+
+    #[test]
+    fn test_witness_sigop_count() {
+        let flags = VerificationFlags::WITNESS;
+        let p2wpkh = Script::new_p2wpkh(&[0; 20]);
+        let empty = Witness::new();
+        assert_eq!(
+            witness_sigop_count(&Script::new(), &p2wpkh, &empty, flags),
+            1
+        );
+        assert_eq!(
+            witness_sigop_count(&Script::new(), &p2wpkh, &empty, VerificationFlags::NONE),
+            0
+        );
+
+        let witness_script = multisig(2, 3);
+        let p2wsh = Script::new_p2wsh(&witness_script);
+        let witness = Witness::from_elements(vec![vec![], witness_script.to_vec()]);
+        assert_eq!(
+            witness_sigop_count(&Script::new(), &p2wsh, &witness, flags),
+            3
+        );
+
+        // P2SH wrapping a P2WSH program.
+        let script_pubkey = Script::new_p2sh(&hash160(&p2wsh));
+        let mut script_sig = Script::new();
+        script_sig.push_slice(&p2wsh);
+        assert_eq!(
+            witness_sigop_count(&script_sig, &script_pubkey, &witness, flags),
+            3
+        );
+        assert_eq!(
+            witness_sigop_count(&script_sig, &Script::new_p2tr(&[1; 32]), &witness, flags),
+            0
+        );
+    }
+}
@@ -0,0 +1,424 @@
+// Standard script templates: building them and recognizing them in arbitrary scripts.
+use super::interpreter::MAX_PUBKEYS_PER_MULTISIG;
+use super::{
+    is_p2pkh, is_p2sh, is_push_only, p2pk_pubkey, parse_multisig, parse_ops, witness_program,
+    Opcode, Script,
+};
+use crate::helper::{hash160, sha256};
+use crate::types::errors::Errors;
+
+// The standard output types of Bitcoin Core's Solver, with what each one pays to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptType<'a> {
+    PubKey(&'a [u8]),
+    PubKeyHash([u8; 20]),
+    ScriptHash([u8; 20]),
+    Multisig {
+        required: u8,
+        pubkeys: Vec<&'a [u8]>,
+    },
+    // OP_RETURN followed by pushes only, the data carried in those pushes.
+    NullData(&'a [u8]),
+    WitnessV0KeyHash([u8; 20]),
+    WitnessV0ScriptHash([u8; 32]),
+    WitnessV1Taproot([u8; 32]),
+    // Witness versions and program sizes given a meaning by future soft forks.
+    WitnessUnknown {
+        version: u8,
+        program: &'a [u8],
+    },
+    NonStandard,
+}
+
+impl ScriptType<'_> {
+    // Names used by Bitcoin Core (GetTxnOutputType), as in the "type" field of RPCs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptType::PubKey(_) => "pubkey",
+            ScriptType::PubKeyHash(_) => "pubkeyhash",
+            ScriptType::ScriptHash(_) => "scripthash",
+            ScriptType::Multisig { .. } => "multisig",
+            ScriptType::NullData(_) => "nulldata",
+            ScriptType::WitnessV0KeyHash(_) => "witness_v0_keyhash",
+            ScriptType::WitnessV0ScriptHash(_) => "witness_v0_scripthash",
+            ScriptType::WitnessV1Taproot(_) => "witness_v1_taproot",
+            ScriptType::WitnessUnknown { .. } => "witness_unknown",
+            ScriptType::NonStandard => "nonstandard",
+        }
+    }
+
+    pub fn is_witness(&self) -> bool {
+        matches!(
+            self,
+            ScriptType::WitnessV0KeyHash(_)
+                | ScriptType::WitnessV0ScriptHash(_)
+                | ScriptType::WitnessV1Taproot(_)
+                | ScriptType::WitnessUnknown { .. }
+        )
+    }
+}
+
+// Free function form of Script::classify, for scripts held as plain bytes.
+pub fn classify(script: &[u8]) -> ScriptType<'_> {
+    if is_p2pkh(script) {
+        return ScriptType::PubKeyHash(script[3..23].try_into().unwrap());
+    }
+    if is_p2sh(script) {
+        return ScriptType::ScriptHash(script[2..22].try_into().unwrap());
+    }
+    if let Some((version, program)) = witness_program(script) {
+        return match (version, program.len()) {
+            (0, 20) => ScriptType::WitnessV0KeyHash(program.try_into().unwrap()),
+            (0, 32) => ScriptType::WitnessV0ScriptHash(program.try_into().unwrap()),
+            (0, _) => ScriptType::NonStandard,
+            (1, 32) => ScriptType::WitnessV1Taproot(program.try_into().unwrap()),
+            _ => ScriptType::WitnessUnknown { version, program },
+        };
+    }
+    if let Some(pubkey) = p2pk_pubkey(script) {
+        return ScriptType::PubKey(pubkey);
+    }
+    if script.first() == Some(&Opcode::OP_RETURN.to_u8()) && is_push_only(&script[1..]) {
+        return ScriptType::NullData(&script[1..]);
+    }
+    match parse_multisig(script) {
+        Some((required, pubkeys))
+            if required >= 1 && pubkeys.iter().all(|key| key.len() == 33 || key.len() == 65) =>
+        {
+            ScriptType::Multisig { required, pubkeys }
+        }
+        _ => ScriptType::NonStandard,
+    }
+}
+
+impl Script {
+    pub fn classify(&self) -> ScriptType<'_> {
+        classify(self)
+    }
+
+    // OP_DUP OP_HASH160 <pubkey_hash> OP_EQUALVERIFY OP_CHECKSIG
+    pub fn new_p2pkh(pubkey_hash: &[u8; 20]) -> Script {
+        let mut script = Script::new();
+        script
+            .push_opcode(Opcode::OP_DUP)
+            .push_opcode(Opcode::OP_HASH160)
+            .push_slice(pubkey_hash)
+            .push_opcode(Opcode::OP_EQUALVERIFY)
+            .push_opcode(Opcode::OP_CHECKSIG);
+        script
+    }
+
+    // OP_HASH160 <script_hash> OP_EQUAL (BIP16)
+    pub fn new_p2sh(script_hash: &[u8; 20]) -> Script {
+        let mut script = Script::new();
+        script
+            .push_opcode(Opcode::OP_HASH160)
+            .push_slice(script_hash)
+            .push_opcode(Opcode::OP_EQUAL);
+        script
+    }
+
+    // OP_0 <pubkey_hash> (BIP141)
+    pub fn new_p2wpkh(pubkey_hash: &[u8; 20]) -> Script {
+        let mut script = Script::new();
+        script.push_opcode(Opcode::OP_0).push_slice(pubkey_hash);
+        script
+    }
+
+    // OP_0 <sha256(witness_script)> (BIP141)
+    pub fn new_p2wsh(witness_script: &[u8]) -> Script {
+        let mut script = Script::new();
+        script
+            .push_opcode(Opcode::OP_0)
+            .push_slice(&sha256(witness_script));
+        script
+    }
+
+    // OP_1 <output_key> (BIP341)
+    pub fn new_p2tr(output_key: &[u8; 32]) -> Script {
+        let mut script = Script::new();
+        script.push_opcode(Opcode::OP_1).push_slice(output_key);
+        script
+    }
+
+    // OP_m <pubkey>... OP_n OP_CHECKMULTISIG, with 1 <= m <= n <= 20. sorted orders the
+    // keys by their encoding (BIP67, as in the sortedmulti descriptor), which then
+    // have to be compressed.
+    pub fn multisig(required: usize, pubkeys: &[&[u8]], sorted: bool) -> Result<Script, Errors> {
+        if pubkeys.len() > MAX_PUBKEYS_PER_MULTISIG as usize {
+            return Err(Errors::InvalidMultisig("more than 20 keys"));
+        }
+        if required == 0 || required > pubkeys.len() {
+            return Err(Errors::InvalidMultisig("threshold out of range"));
+        }
+        for pubkey in pubkeys {
+            if !is_pubkey_encoding(pubkey) || sorted && pubkey.len() != 33 {
+                return Err(Errors::InvalidMultisig("invalid public key"));
+            }
+        }
+        let mut pubkeys = pubkeys.to_vec();
+        if sorted {
+            pubkeys.sort();
+        }
+        let mut script = Script::new();
+        script.push_int(required as i64);
+        for pubkey in &pubkeys {
+            script.push_slice(pubkey);
+        }
+        script
+            .push_int(pubkeys.len() as i64)
+            .push_opcode(Opcode::OP_CHECKMULTISIG);
+        Ok(script)
+    }
+
+    // P2SH scriptPubKey with this script as the redeem script. Redeem scripts over
+    // 520 bytes cannot be spent.
+    pub fn to_p2sh(&self) -> Script {
+        Script::new_p2sh(&hash160(self))
+    }
+
+    // P2WSH scriptPubKey with this script as the witness script.
+    pub fn to_p2wsh(&self) -> Script {
+        Script::new_p2wsh(self)
+    }
+
+    // <signature> <pubkey>, where signature already ends with its sighash type byte.
+    pub fn new_p2pkh_script_sig(signature: &[u8], pubkey: &[u8]) -> Script {
+        let mut script = Script::new();
+        script.push_slice(signature).push_slice(pubkey);
+        script
+    }
+
+    // The hash paid to if this is a P2PKH scriptPubKey.
+    pub fn p2pkh_pubkey_hash(&self) -> Option<[u8; 20]> {
+        is_p2pkh(self).then(|| self[3..23].try_into().unwrap())
+    }
+
+    pub fn p2sh_script_hash(&self) -> Option<[u8; 20]> {
+        is_p2sh(self).then(|| self[2..22].try_into().unwrap())
+    }
+
+    // Signature and public key of a scriptSig shaped like a P2PKH spend.
+    pub fn p2pkh_script_sig_parts(&self) -> Option<(&[u8], &[u8])> {
+        match parse_ops(self)?.as_slice() {
+            [(0x01..=0x4e, signature), (0x01..=0x4e, pubkey)]
+                if !signature.is_empty() && is_pubkey_encoding(pubkey) =>
+            {
+                Some((signature, pubkey))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Compressed or uncompressed SEC encoding, judged by length and prefix only.
+fn is_pubkey_encoding(pubkey: &[u8]) -> bool {
+    match pubkey.first() {
+        Some(0x02 | 0x03) => pubkey.len() == 33,
+        Some(0x04) => pubkey.len() == 65,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod templates_tests {
+    use super::*;
+    use crate::address::address_from_script;
+    use crate::ecc::PrivateKey;
+    use crate::network::Network;
+    use num_bigint::BigInt;
+
+    #[test]
+    fn test_p2pkh_round_trip() {
+        let pubkey_hash: [u8; 20] = hex::decode("bc3b654dca7e56b04dca18f2566cdaf02e8d9ada")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let script = Script::new_p2pkh(&pubkey_hash);
+        assert_eq!(
+            hex::encode(&script),
+            "76a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac"
+        );
+        assert_eq!(script.p2pkh_pubkey_hash(), Some(pubkey_hash));
+
+        // Same opcodes but a non-minimal push of the hash is not P2PKH.
+        let mut pushdata = vec![0x76, 0xa9, 0x4c, 0x14];
+        pushdata.extend(pubkey_hash);
+        pushdata.extend([0x88, 0xac]);
+        assert_eq!(Script::from(pushdata).p2pkh_pubkey_hash(), None);
+        assert_eq!(Script::from(vec![0x51]).p2pkh_pubkey_hash(), None);
+    }
+
+    #[test]
+    fn test_p2sh() {
+        let script = Script::new_p2sh(&[0xab; 20]);
+        assert_eq!(script.len(), 23);
+        assert_eq!(script.p2sh_script_hash(), Some([0xab; 20]));
+        assert_eq!(script.p2pkh_pubkey_hash(), None);
+    }
+
+    #[test]
+    fn test_segwit_v0() {
+        assert_eq!(
+            hex::encode(Script::new_p2wpkh(&[0x11; 20])),
+            format!("0014{}", "11".repeat(20))
+        );
+        // BIP141 example: the witness script OP_1.
+        assert_eq!(
+            hex::encode(Script::new_p2wsh(&[0x51])),
+            "00204ae81572f06e1b88fd5ced7a1a000945432e83e1551e6f721ee9c00b8cc33260"
+        );
+        assert_eq!(
+            hex::encode(Script::new_p2tr(&[0x22; 32])),
+            format!("5120{}", "22".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_p2pkh_script_sig() {
+        let pubkey = PrivateKey::new(BigInt::from(5))
+            .unwrap()
+            .public_key()
+            .sec(true);
+        let signature = [0x30; 71];
+        let script_sig = Script::new_p2pkh_script_sig(&signature, &pubkey);
+        assert_eq!(script_sig[0], 71);
+        assert_eq!(
+            script_sig.p2pkh_script_sig_parts(),
+            Some((&signature[..], &pubkey[..]))
+        );
+
+        // Three pushes, or a second push that is not a public key.
+        let mut three = script_sig.clone();
+        three.push_slice(&[1]);
+        assert_eq!(three.p2pkh_script_sig_parts(), None);
+        let not_a_key = Script::new_p2pkh_script_sig(&signature, &[0x05; 33]);
+        assert_eq!(not_a_key.p2pkh_script_sig_parts(), None);
+    }
+
+    #[test]
+    fn test_multisig() {
+        // BIP67 test vector 1.
+        let first =
+            hex::decode("02ff12471208c14bd580709cb2358d98975247d8765f92bc25eab3b2763ed605f8")
+                .unwrap();
+        let second =
+            hex::decode("02fe6f0a5a297eb38c391581c4413e084773ea23954d93f7753db7dc0adc188b2f")
+                .unwrap();
+        let keys = [&first[..], &second[..]];
+        let sorted = Script::multisig(2, &keys, true).unwrap();
+        assert_eq!(
+            hex::encode(&sorted),
+            "522102fe6f0a5a297eb38c391581c4413e084773ea23954d93f7753db7dc0adc188b2f2102ff12471208c14bd580709cb2358d98975247d8765f92bc25eab3b2763ed605f852ae"
+        );
+        assert_eq!(
+            address_from_script(&sorted.to_p2sh(), Network::Mainnet).unwrap(),
+            "39bgKC7RFbpoCRbtD5KEdkYKtNyhpsNa3Z"
+        );
+        let unsorted = Script::multisig(2, &keys, false).unwrap();
+        assert_ne!(unsorted, sorted);
+        assert_eq!(
+            parse_multisig(&unsorted),
+            Some((2, vec![&first[..], &second[..]]))
+        );
+        assert_eq!(
+            sorted.to_p2wsh().classify(),
+            ScriptType::WitnessV0ScriptHash(sha256(&sorted))
+        );
+        assert_eq!(sorted.to_p2sh().p2sh_script_hash(), Some(hash160(&sorted)));
+
+        assert_eq!(
+            Script::multisig(3, &keys, false),
+            Err(Errors::InvalidMultisig("threshold out of range"))
+        );
+        assert!(Script::multisig(0, &keys, false).is_err());
+        assert!(Script::multisig(1, &[&first[..]; 21], false).is_err());
+        assert!(Script::multisig(1, &[&first[..], &[0x05; 33]], false).is_err());
+        // Uncompressed keys are allowed, but not in sorted form.
+        let uncompressed = PrivateKey::new(BigInt::from(5))
+            .unwrap()
+            .public_key()
+            .sec(false);
+        assert!(Script::multisig(1, &[&uncompressed[..]], false).is_ok());
+        assert!(Script::multisig(1, &[&uncompressed[..]], true).is_err());
+        // 16 and more are pushed as numbers.
+        let many = Script::multisig(16, &[&first[..]; 20], false).unwrap();
+        assert_eq!(many[0], 0x60);
+        assert_eq!(many[many.len() - 3..], [0x01, 20, 0xae]);
+    }
+
+    #[test]
+    fn test_classify() {
+        let pubkey = PrivateKey::new(BigInt::from(5))
+            .unwrap()
+            .public_key()
+            .sec(true);
+        let mut p2pk = Script::new();
+        p2pk.push_slice(&pubkey).push_opcode(Opcode::OP_CHECKSIG);
+        assert_eq!(p2pk.classify(), ScriptType::PubKey(&pubkey));
+        assert_eq!(
+            Script::new_p2pkh(&[1; 20]).classify(),
+            ScriptType::PubKeyHash([1; 20])
+        );
+        assert_eq!(
+            Script::new_p2sh(&[2; 20]).classify(),
+            ScriptType::ScriptHash([2; 20])
+        );
+        assert_eq!(
+            Script::new_p2wpkh(&[3; 20]).classify(),
+            ScriptType::WitnessV0KeyHash([3; 20])
+        );
+        let p2wsh = Script::new_p2wsh(&[0x51]);
+        assert_eq!(
+            p2wsh.classify(),
+            ScriptType::WitnessV0ScriptHash(p2wsh[2..].try_into().unwrap())
+        );
+        let p2tr = Script::new_p2tr(&[4; 32]);
+        assert_eq!(p2tr.classify(), ScriptType::WitnessV1Taproot([4; 32]));
+        assert_eq!(p2tr.classify().name(), "witness_v1_taproot");
+        assert!(p2tr.classify().is_witness());
+        assert_eq!(
+            Script::from(vec![0x52, 0x02, 0xaa, 0xbb]).classify(),
+            ScriptType::WitnessUnknown {
+                version: 2,
+                program: &[0xaa, 0xbb]
+            }
+        );
+
+        let mut multisig = Script::new();
+        multisig
+            .push_int(1)
+            .push_slice(&pubkey)
+            .push_int(1)
+            .push_opcode(Opcode::OP_CHECKMULTISIG);
+        assert_eq!(
+            multisig.classify(),
+            ScriptType::Multisig {
+                required: 1,
+                pubkeys: vec![&pubkey[..]]
+            }
+        );
+        assert_eq!(
+            Script::from(vec![0x6a, 0x02, 0xca, 0xfe]).classify(),
+            ScriptType::NullData(&[0x02, 0xca, 0xfe])
+        );
+    }
+
+    #[test]
+    fn test_classify_nonstandard() {
+        for script in [
+            vec![],
+            vec![0x51],
+            // OP_RETURN followed by something other than pushes.
+            vec![0x6a, 0x61],
+            // A v0 program of neither 20 nor 32 bytes.
+            [vec![0x00, 0x15], vec![0; 21]].concat(),
+            // 0-of-1 multisig.
+            [vec![0x00, 0x21], vec![0x02; 33], vec![0x51, 0xae]].concat(),
+        ] {
+            let script = Script::from(script);
+            assert_eq!(script.classify(), ScriptType::NonStandard);
+            assert_eq!(script.classify().name(), "nonstandard");
+        }
+    }
+}
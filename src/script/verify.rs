@@ -0,0 +1,770 @@
+// Full verification of an input's scripts, following VerifyScript in Bitcoin Core:
+// scriptSig, scriptPubKey, then the P2SH redeem script and witness program if any.
+use super::flags::VerificationFlags;
+use super::instruction::read_instruction;
+use super::interpreter::{
+    cast_to_bool, check_schnorr_signature, ExecutionContext, ScriptExecutionData, SigVersion,
+    SignatureChecker, MAX_SCRIPT_ELEMENT_SIZE, MAX_STACK_SIZE, VALIDATION_WEIGHT_OFFSET,
+};
+use super::opcode::is_op_success;
+use super::{encode_push, is_p2sh, is_push_only, witness_program, Script};
+use crate::helper::sha256;
+use crate::taproot::{tap_leaf_hash, ControlBlock, TAPROOT_ANNEX_TAG, TAPROOT_LEAF_TAPSCRIPT};
+use crate::transaction::Witness;
+use crate::types::errors::ScriptError;
+
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    witness: &Witness,
+    flags: VerificationFlags,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if flags.contains(VerificationFlags::SIGPUSHONLY) && !is_push_only(script_sig) {
+        return Err(ScriptError::SigPushOnly);
+    }
+    let context = ExecutionContext::new(flags, checker);
+    let mut stack = Vec::new();
+    script_sig.evaluate(&mut stack, &context)?;
+    let script_sig_stack = stack.clone();
+    script_pubkey.evaluate(&mut stack, &context)?;
+    require_true(&stack)?;
+
+    let mut had_witness = false;
+    if flags.contains(VerificationFlags::WITNESS) {
+        if let Some((version, program)) = witness_program(script_pubkey) {
+            had_witness = true;
+            // Native witness spends carry everything in the witness, which the txid
+            // does not cover, so any scriptSig would be malleable.
+            if !script_sig.is_empty() {
+                return Err(ScriptError::WitnessMalleated);
+            }
+            verify_witness_program(witness, version, program, false, flags, checker)?;
+            // The scriptPubKey left its program on the stack, keep a single item
+            // so CLEANSTACK holds.
+            stack.truncate(1);
+        }
+    }
+
+    // The scriptPubKey only checked the hash of the last push, the redeem script.
+    // It is now deserialized and run on the rest of the scriptSig stack.
+    if flags.contains(VerificationFlags::P2SH) && is_p2sh(script_pubkey) {
+        if !is_push_only(script_sig) {
+            return Err(ScriptError::SigPushOnly);
+        }
+        stack = script_sig_stack;
+        let redeem_script = Script::from(stack.pop().ok_or(ScriptError::EvalFalse)?);
+        redeem_script.evaluate(&mut stack, &context)?;
+        require_true(&stack)?;
+
+        if flags.contains(VerificationFlags::WITNESS) {
+            if let Some((version, program)) = witness_program(&redeem_script) {
+                had_witness = true;
+                if script_sig[..] != encode_push(&redeem_script)[..] {
+                    return Err(ScriptError::WitnessMalleatedP2SH);
+                }
+                verify_witness_program(witness, version, program, true, flags, checker)?;
+                stack.truncate(1);
+            }
+        }
+    }
+
+    // Only meaningful together with P2SH and WITNESS, as both legitimately leave
+    // extra items when evaluated on their own.
+    if flags.contains(VerificationFlags::CLEANSTACK) && stack.len() != 1 {
+        return Err(ScriptError::CleanStack);
+    }
+    if flags.contains(VerificationFlags::WITNESS) && !had_witness && !witness.is_empty() {
+        return Err(ScriptError::WitnessUnexpected);
+    }
+    Ok(())
+}
+
+// BIP141 version 0 programs: a 20 byte key hash spent like P2PKH, or the sha256 of a
+// witness script that is the last witness element. BIP341 version 1 programs of 32
+// bytes are taproot outputs. Other programs are not defined yet and succeed unless
+// discouraged.
+fn verify_witness_program(
+    witness: &Witness,
+    version: u8,
+    program: &[u8],
+    is_p2sh: bool,
+    flags: VerificationFlags,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    let mut stack = witness.to_vec();
+    let script = match (version, program.len()) {
+        (0, 32) => {
+            let script = stack.pop().ok_or(ScriptError::WitnessProgramWitnessEmpty)?;
+            if sha256(&script)[..] != *program {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+            Script::from(script)
+        }
+        (0, 20) => {
+            if stack.len() != 2 {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+            Script::new_p2pkh(program.try_into().unwrap())
+        }
+        (0, _) => return Err(ScriptError::WitnessProgramWrongLength),
+        (1, 32) if !is_p2sh => {
+            // Before taproot, anyone can spend them, discouraged or not.
+            if !flags.contains(VerificationFlags::TAPROOT) {
+                return Ok(());
+            }
+            return verify_taproot(witness, program.try_into().unwrap(), flags, checker);
+        }
+        _ => {
+            if flags.contains(VerificationFlags::DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM) {
+                return Err(ScriptError::DiscourageUpgradableWitnessProgram);
+            }
+            return Ok(());
+        }
+    };
+    execute_witness_script(
+        script,
+        stack,
+        SigVersion::WitnessV0,
+        ScriptExecutionData::default(),
+        flags,
+        checker,
+    )
+}
+
+// A single witness element is a key path signature for the output key. Otherwise the
+// last two are the control block and a leaf script the output key commits to.
+fn verify_taproot(
+    witness: &Witness,
+    output_key: &[u8; 32],
+    flags: VerificationFlags,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    let mut stack = witness.to_vec();
+    if stack.is_empty() {
+        return Err(ScriptError::WitnessProgramWitnessEmpty);
+    }
+    // An annex, marked by its first byte, is not part of the script inputs but is
+    // signed by every signature.
+    let mut execution_data = ScriptExecutionData::default();
+    if stack.len() >= 2 && stack.last().unwrap().first() == Some(&TAPROOT_ANNEX_TAG) {
+        execution_data.annex = stack.pop();
+    }
+    if stack.len() == 1 {
+        return check_schnorr_signature(
+            checker,
+            &stack[0],
+            output_key,
+            SigVersion::Taproot,
+            &execution_data,
+        );
+    }
+
+    let control = stack.pop().unwrap();
+    let script = stack.pop().unwrap();
+    let control_block =
+        ControlBlock::parse(&control).map_err(|_| ScriptError::TaprootWrongControlSize)?;
+    if !control_block.verify(output_key, &script) {
+        return Err(ScriptError::WitnessProgramMismatch);
+    }
+    if control_block.leaf_version != TAPROOT_LEAF_TAPSCRIPT {
+        if flags.contains(VerificationFlags::DISCOURAGE_UPGRADABLE_TAPROOT_VERSION) {
+            return Err(ScriptError::DiscourageUpgradableTaprootVersion);
+        }
+        return Ok(());
+    }
+    execution_data.tapleaf_hash = tap_leaf_hash(control_block.leaf_version, &script);
+    execution_data.validation_weight_left =
+        witness.serialized_size() as i64 + VALIDATION_WEIGHT_OFFSET;
+    execute_witness_script(
+        Script::from(script),
+        stack,
+        SigVersion::Tapscript,
+        execution_data,
+        flags,
+        checker,
+    )
+}
+
+fn execute_witness_script(
+    script: Script,
+    mut stack: Vec<Vec<u8>>,
+    sig_version: SigVersion,
+    execution_data: ScriptExecutionData,
+    flags: VerificationFlags,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if sig_version == SigVersion::Tapscript {
+        // OP_SUCCESSx anywhere makes the script succeed without running it, as long as
+        // the whole script decodes.
+        let mut rest = &script[..];
+        while !rest.is_empty() {
+            let (opcode, _, len) = read_instruction(rest).ok_or(ScriptError::BadOpcode)?;
+            if is_op_success(opcode) {
+                if flags.contains(VerificationFlags::DISCOURAGE_OP_SUCCESS) {
+                    return Err(ScriptError::DiscourageOpSuccess);
+                }
+                return Ok(());
+            }
+            rest = &rest[len..];
+        }
+        // Legacy scripts can only grow the stack one push at a time, here the
+        // initial stack comes straight from the witness.
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+    if stack
+        .iter()
+        .any(|item| item.len() > MAX_SCRIPT_ELEMENT_SIZE)
+    {
+        return Err(ScriptError::PushSize);
+    }
+    let mut context = ExecutionContext::new(flags, checker);
+    context.sig_version = sig_version;
+    context.execution_data = execution_data;
+    script.evaluate(&mut stack, &context)?;
+    // Witness scripts must leave exactly one item, whatever the flags.
+    if stack.len() != 1 {
+        return Err(ScriptError::CleanStack);
+    }
+    require_true(&stack)
+}
+
+fn require_true(stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use super::*;
+    use crate::ecc::PrivateKey;
+    use crate::helper::hash160;
+    use crate::script::interpreter::{
+        NoSignatureChecker, TransactionSignatureChecker, MAX_SCRIPT_SIZE,
+    };
+    use crate::script::{Opcode, SignatureCache};
+    use crate::transaction::{OutPoint, SigningData, Transaction, TxIn, TxOut};
+    use num_bigint::BigInt;
+
+    const FLAGS: VerificationFlags = VerificationFlags::P2SH
+        .union(VerificationFlags::WITNESS)
+        .union(VerificationFlags::TAPROOT);
+
+    fn keys() -> Vec<PrivateKey> {
+        (1..=3u32)
+            .map(|secret| PrivateKey::new(BigInt::from(secret)).unwrap())
+            .collect()
+    }
+
+    fn multisig(required: i64, keys: &[PrivateKey]) -> Script {
+        let mut script = Script::new();
+        script.push_int(required);
+        for key in keys {
+            script.push_slice(&key.public_key().sec(true));
+        }
+        script
+            .push_int(keys.len() as i64)
+            .push_opcode(Opcode::OP_CHECKMULTISIG);
+        script
+    }
+
+    fn p2sh(script: &[u8]) -> Script {
+        Script::new_p2sh(&hash160(script))
+    }
+
+    // P2TR output with a single leaf, and the control block to spend it.
+    fn p2tr_leaf(script: &[u8]) -> (Script, Vec<u8>) {
+        let internal_key = PrivateKey::new(BigInt::from(7)).unwrap();
+        let leaf_hash = tap_leaf_hash(TAPROOT_LEAF_TAPSCRIPT, script);
+        let output_key = internal_key
+            .public_key()
+            .tap_tweak(Some(&leaf_hash))
+            .unwrap();
+        let control_block = ControlBlock {
+            leaf_version: TAPROOT_LEAF_TAPSCRIPT,
+            output_key_parity: !output_key.has_even_y(),
+            internal_key: internal_key.public_key().xonly(),
+            merkle_branch: Vec::new(),
+        };
+        (
+            Script::new_p2tr(&output_key.xonly()),
+            control_block.serialize(),
+        )
+    }
+
+    // Signs the only input of a transaction spending prevout.
+    fn signed_spend(prevout: &TxOut, data: &SigningData) -> Transaction {
+        let mut tx = Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new([3; 32], 1),
+                Script::new(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(prevout.value - 1_000, Script::new())],
+            0,
+        );
+        tx.sign_input(0, std::slice::from_ref(prevout), data)
+            .unwrap();
+        tx
+    }
+
+    fn verify(
+        tx: &Transaction,
+        prevout: &TxOut,
+        flags: VerificationFlags,
+    ) -> Result<(), ScriptError> {
+        let input = &tx.inputs[0];
+        let checker = TransactionSignatureChecker::new(tx, 0, prevout.value)
+            .with_prevouts(std::slice::from_ref(prevout));
+        verify_script(
+            &input.script_sig,
+            &prevout.script_pubkey,
+            &input.witness,
+            flags,
+            &checker,
+        )
+    }
+
+    #[test]
+    fn test_p2sh_multisig() {
+        let redeem_script = multisig(2, &keys());
+        let prevout = TxOut::new(50_000, p2sh(&redeem_script));
+        let data =
+            SigningData::new(keys()[1..].to_vec()).with_redeem_script(redeem_script.to_vec());
+        let mut tx = signed_spend(&prevout, &data);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+
+        // Without BIP16 only the redeem script hash is checked.
+        let mut script_sig = Script::new();
+        script_sig.push_int(0).push_slice(&redeem_script);
+        tx.inputs[0].script_sig = script_sig;
+        assert_eq!(verify(&tx, &prevout, VerificationFlags::NONE), Ok(()));
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::InvalidStackOperation)
+        );
+
+        // A different redeem script does not match the hash.
+        let mut script_sig = Script::new();
+        script_sig.push_slice(&multisig(1, &keys()));
+        tx.inputs[0].script_sig = script_sig;
+        assert_eq!(verify(&tx, &prevout, FLAGS), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn test_p2sh_requires_push_only_script_sig() {
+        // <OP_1> OP_NOP pushes the redeem script OP_1, but is not push only.
+        let prevout = TxOut::new(1_000, p2sh(&[0x51]));
+        let script_sig = Script::from(vec![0x01, 0x51, 0x61]);
+        let checker = NoSignatureChecker;
+        assert_eq!(
+            verify_script(
+                &script_sig,
+                &prevout.script_pubkey,
+                &Witness::new(),
+                FLAGS,
+                &checker
+            ),
+            Err(ScriptError::SigPushOnly)
+        );
+        assert_eq!(
+            verify_script(
+                &script_sig,
+                &prevout.script_pubkey,
+                &Witness::new(),
+                VerificationFlags::NONE,
+                &checker
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            verify_script(
+                &Script::from(vec![0x01, 0x51]),
+                &prevout.script_pubkey,
+                &Witness::new(),
+                FLAGS,
+                &checker
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_p2sh_wrapped_witness_programs() {
+        let key = keys()[0].clone();
+        let redeem_script = Script::new_p2wpkh(&key.public_key().hash160(true));
+        let prevout = TxOut::new(80_000, p2sh(&redeem_script));
+        let data = SigningData::new(vec![key.clone()]).with_redeem_script(redeem_script.to_vec());
+        let mut tx = signed_spend(&prevout, &data);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+        // The amount is part of the signed data.
+        let mut more = prevout.clone();
+        more.value += 1;
+        assert_eq!(verify(&tx, &more, FLAGS), Err(ScriptError::EvalFalse));
+
+        // Extra pushes in front of the redeem script.
+        let mut script_sig = Script::new();
+        script_sig.push_int(1).push_slice(&redeem_script);
+        tx.inputs[0].script_sig = script_sig;
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::WitnessMalleatedP2SH)
+        );
+
+        let witness_script = multisig(2, &keys());
+        let redeem_script = Script::new_p2wsh(&witness_script);
+        let prevout = TxOut::new(80_000, p2sh(&redeem_script));
+        let data = SigningData::new(keys())
+            .with_redeem_script(redeem_script.to_vec())
+            .with_witness_script(witness_script.to_vec());
+        let mut tx = signed_spend(&prevout, &data);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+
+        let mut witness = tx.inputs[0].witness.to_vec();
+        *witness.last_mut().unwrap() = multisig(1, &keys()).into_bytes();
+        tx.inputs[0].witness = Witness::from_elements(witness);
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::WitnessProgramMismatch)
+        );
+        tx.inputs[0].witness = Witness::new();
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::WitnessProgramWitnessEmpty)
+        );
+        // Before segwit the program is just a push that leaves true on the stack.
+        assert_eq!(verify(&tx, &prevout, VerificationFlags::P2SH), Ok(()));
+    }
+
+    #[test]
+    fn test_native_p2wpkh() {
+        let key = keys()[0].clone();
+        let prevout = TxOut::new(60_000, Script::new_p2wpkh(&key.public_key().hash160(true)));
+        let mut tx = signed_spend(&prevout, &SigningData::new(vec![key]));
+        assert!(tx.inputs[0].script_sig.is_empty());
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+
+        // Anything in the scriptSig could be changed without touching the txid.
+        tx.inputs[0].script_sig.push_int(1);
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::WitnessMalleated)
+        );
+        tx.inputs[0].script_sig = Script::new();
+
+        // The implicit P2PKH script takes exactly a signature and a key.
+        let mut witness = tx.inputs[0].witness.to_vec();
+        witness.insert(0, vec![]);
+        tx.inputs[0].witness = Witness::from_elements(witness);
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::WitnessProgramMismatch)
+        );
+    }
+
+    #[test]
+    fn test_signature_cache() {
+        let key = keys()[0].clone();
+        let p2wpkh = TxOut::new(60_000, Script::new_p2wpkh(&key.public_key().hash160(true)));
+        let output_key = key.public_key().tap_tweak(None).unwrap().xonly();
+        let p2tr = TxOut::new(70_000, Script::new_p2tr(&output_key));
+        let cache = SignatureCache::new(10);
+        let verify_cached = |tx: &Transaction, prevout: &TxOut, store: bool| {
+            let input = &tx.inputs[0];
+            let checker = TransactionSignatureChecker::new(tx, 0, prevout.value)
+                .with_prevouts(std::slice::from_ref(prevout))
+                .with_signature_cache(&cache, store);
+            verify_script(
+                &input.script_sig,
+                &prevout.script_pubkey,
+                &input.witness,
+                FLAGS,
+                &checker,
+            )
+        };
+
+        for prevout in [&p2wpkh, &p2tr] {
+            let tx = signed_spend(prevout, &SigningData::new(vec![key.clone()]));
+            // Mempool acceptance stores the signature, block connection uses it up.
+            assert_eq!(verify_cached(&tx, prevout, true), Ok(()));
+            assert_eq!(cache.len(), 1);
+            assert_eq!(verify_cached(&tx, prevout, false), Ok(()));
+            assert!(cache.is_empty());
+            // Without an entry the signature is still verified.
+            assert_eq!(verify_cached(&tx, prevout, false), Ok(()));
+        }
+
+        // Invalid signatures are never stored.
+        let tx = signed_spend(&p2tr, &SigningData::new(vec![key]));
+        let mut other = p2tr.clone();
+        other.value += 1;
+        assert_eq!(
+            verify_cached(&tx, &other, true),
+            Err(ScriptError::SchnorrSig)
+        );
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_native_p2wsh() {
+        let witness_script = multisig(2, &keys());
+        let prevout = TxOut::new(90_000, Script::new_p2wsh(&witness_script));
+        let data = SigningData::new(keys()).with_witness_script(witness_script.to_vec());
+        let mut tx = signed_spend(&prevout, &data);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS | VerificationFlags::CLEANSTACK),
+            Ok(())
+        );
+
+        // A leftover item fails even without CLEANSTACK.
+        let mut witness = tx.inputs[0].witness.to_vec();
+        witness.insert(0, vec![1]);
+        tx.inputs[0].witness = Witness::from_elements(witness);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Err(ScriptError::CleanStack));
+    }
+
+    #[test]
+    fn test_witness_size_limits() {
+        let checker = NoSignatureChecker;
+        // OP_DROP OP_1 accepts any single item.
+        let witness_script = vec![0x75, 0x51];
+        let script_pubkey = Script::new_p2wsh(&witness_script);
+        let run = |item: Vec<u8>| {
+            let witness = Witness::from_elements(vec![item, witness_script.clone()]);
+            verify_script(&Script::new(), &script_pubkey, &witness, FLAGS, &checker)
+        };
+        assert_eq!(run(vec![0; MAX_SCRIPT_ELEMENT_SIZE]), Ok(()));
+        assert_eq!(
+            run(vec![0; MAX_SCRIPT_ELEMENT_SIZE + 1]),
+            Err(ScriptError::PushSize)
+        );
+
+        let mut big_script = vec![0x61; MAX_SCRIPT_SIZE];
+        big_script.push(0x51);
+        let witness = Witness::from_elements(vec![big_script.clone()]);
+        assert_eq!(
+            verify_script(
+                &Script::new(),
+                &Script::new_p2wsh(&big_script),
+                &witness,
+                FLAGS,
+                &checker
+            ),
+            Err(ScriptError::ScriptSize)
+        );
+    }
+
+    #[test]
+    fn test_witness_flags() {
+        let checker = NoSignatureChecker;
+        let witness = Witness::from_elements(vec![vec![1]]);
+        // OP_1 <2 bytes>, a version 1 program of an undefined length.
+        let future = Script::from(vec![0x51, 0x02, 0xaa, 0xbb]);
+        assert_eq!(
+            verify_script(&Script::new(), &future, &witness, FLAGS, &checker),
+            Ok(())
+        );
+        assert_eq!(
+            verify_script(
+                &Script::new(),
+                &future,
+                &witness,
+                FLAGS | VerificationFlags::DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM,
+                &checker
+            ),
+            Err(ScriptError::DiscourageUpgradableWitnessProgram)
+        );
+        // A taproot output without TAPROOT isn't an upgradable program to discourage.
+        let mut taproot = vec![0x51, 0x20];
+        taproot.extend([0xaa; 32]);
+        assert_eq!(
+            verify_script(
+                &Script::new(),
+                &Script::from(taproot),
+                &witness,
+                VerificationFlags::P2SH
+                    | VerificationFlags::WITNESS
+                    | VerificationFlags::DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM,
+                &checker
+            ),
+            Ok(())
+        );
+
+        // A witness on an input whose scriptPubKey is not a witness program.
+        let op_true = Script::from(vec![0x51]);
+        assert_eq!(
+            verify_script(&Script::new(), &op_true, &witness, FLAGS, &checker),
+            Err(ScriptError::WitnessUnexpected)
+        );
+        assert_eq!(
+            verify_script(
+                &Script::new(),
+                &op_true,
+                &witness,
+                VerificationFlags::P2SH,
+                &checker
+            ),
+            Ok(())
+        );
+
+        // CLEANSTACK only looks at what is left after the scriptPubKey.
+        let script_sig = Script::from(vec![0x51]);
+        assert_eq!(
+            verify_script(&script_sig, &op_true, &Witness::new(), FLAGS, &checker),
+            Ok(())
+        );
+        assert_eq!(
+            verify_script(
+                &script_sig,
+                &op_true,
+                &Witness::new(),
+                FLAGS | VerificationFlags::CLEANSTACK,
+                &checker
+            ),
+            Err(ScriptError::CleanStack)
+        );
+    }
+
+    #[test]
+    fn test_taproot_key_path() {
+        let key = keys()[0].clone();
+        let output_key = key.public_key().tap_tweak(None).unwrap().xonly();
+        let prevout = TxOut::new(70_000, Script::new_p2tr(&output_key));
+        let mut tx = signed_spend(&prevout, &SigningData::new(vec![key]));
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+        let mut other = prevout.clone();
+        other.value += 1;
+        assert_eq!(verify(&tx, &other, FLAGS), Err(ScriptError::SchnorrSig));
+        // Before taproot the program is anyone can spend.
+        assert_eq!(
+            verify(
+                &tx,
+                &other,
+                VerificationFlags::P2SH | VerificationFlags::WITNESS
+            ),
+            Ok(())
+        );
+
+        // SIGHASH_DEFAULT must not be written out.
+        let mut signature = tx.inputs[0].witness.to_vec().remove(0);
+        signature.push(0x00);
+        tx.inputs[0].witness = Witness::from_elements(vec![signature.clone()]);
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::SchnorrSigHashType)
+        );
+        signature.push(0x00);
+        tx.inputs[0].witness = Witness::from_elements(vec![signature]);
+        assert_eq!(
+            verify(&tx, &prevout, FLAGS),
+            Err(ScriptError::SchnorrSigSize)
+        );
+    }
+
+    #[test]
+    fn test_taproot_annex_is_signed() {
+        let key = keys()[0].clone();
+        let output_key = key.public_key().tap_tweak(None).unwrap().xonly();
+        let prevout = TxOut::new(70_000, Script::new_p2tr(&output_key));
+        let data = SigningData::new(vec![key]).with_annex(vec![TAPROOT_ANNEX_TAG, 1, 2]);
+        let mut tx = signed_spend(&prevout, &data);
+        assert_eq!(tx.inputs[0].witness.len(), 2);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+
+        // Changing or dropping the annex invalidates the signature.
+        let signature = tx.inputs[0].witness[0].to_vec();
+        tx.inputs[0].witness = Witness::p2tr_key_spend(&signature)
+            .with_annex(vec![TAPROOT_ANNEX_TAG, 1, 3])
+            .unwrap();
+        assert_eq!(verify(&tx, &prevout, FLAGS), Err(ScriptError::SchnorrSig));
+        tx.inputs[0].witness = Witness::p2tr_key_spend(&signature);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Err(ScriptError::SchnorrSig));
+    }
+
+    #[test]
+    fn test_tapscript_checksigadd() {
+        // <k1> OP_CHECKSIG <k2> OP_CHECKSIGADD <k3> OP_CHECKSIGADD 2 OP_NUMEQUAL
+        let mut script = Script::new();
+        for (i, key) in keys().iter().enumerate() {
+            script.push_slice(&key.public_key().xonly());
+            script.push_opcode(if i == 0 {
+                Opcode::OP_CHECKSIG
+            } else {
+                Opcode::OP_CHECKSIGADD
+            });
+        }
+        script.push_int(2).push_opcode(Opcode::OP_NUMEQUAL);
+        let (script_pubkey, control_block) = p2tr_leaf(&script);
+        let prevout = TxOut::new(70_000, script_pubkey);
+
+        let data = SigningData::new(vec![keys()[0].clone(), keys()[2].clone()])
+            .with_tap_leaf(script.to_vec(), control_block.clone());
+        let tx = signed_spend(&prevout, &data);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Ok(()));
+
+        let data =
+            SigningData::new(vec![keys()[1].clone()]).with_tap_leaf(script.to_vec(), control_block);
+        let tx = signed_spend(&prevout, &data);
+        assert_eq!(verify(&tx, &prevout, FLAGS), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn test_tapscript_rules() {
+        let checker = NoSignatureChecker;
+        let spend = |script: &[u8], inputs: Vec<Vec<u8>>, flags: VerificationFlags| {
+            let (script_pubkey, control_block) = p2tr_leaf(script);
+            let witness = Witness::p2tr_script_spend(inputs, script, &control_block);
+            verify_script(&Script::new(), &script_pubkey, &witness, flags, &checker)
+        };
+
+        // 1 0 0 OP_CHECKMULTISIG
+        assert_eq!(
+            spend(&[0x51, 0x00, 0x00, 0xae], vec![], FLAGS),
+            Err(ScriptError::TapscriptCheckMultisig)
+        );
+        // OP_RETURN OP_SUCCESS80 succeeds before anything runs.
+        assert_eq!(spend(&[0x6a, 0x50], vec![], FLAGS), Ok(()));
+        assert_eq!(
+            spend(
+                &[0x6a, 0x50],
+                vec![],
+                FLAGS | VerificationFlags::DISCOURAGE_OP_SUCCESS
+            ),
+            Err(ScriptError::DiscourageOpSuccess)
+        );
+        // Not when it is the length of a truncated push.
+        assert_eq!(
+            spend(&[0x4c, 0x50], vec![], FLAGS),
+            Err(ScriptError::BadOpcode)
+        );
+
+        // Keys that are not 32 bytes accept any non-empty signature, but every
+        // signature still takes 50 units of the budget.
+        let unknown_key = vec![vec![1], vec![2]];
+        assert_eq!(spend(&[0xac], unknown_key.clone(), FLAGS), Ok(()));
+        assert_eq!(
+            spend(
+                &[0xac],
+                unknown_key.clone(),
+                FLAGS | VerificationFlags::DISCOURAGE_UPGRADABLE_PUBKEYTYPE
+            ),
+            Err(ScriptError::DiscourageUpgradablePubKeyType)
+        );
+        let mut many_checks = [0x6e, 0xad].repeat(20);
+        many_checks.push(0xac);
+        assert_eq!(
+            spend(&many_checks, unknown_key, FLAGS),
+            Err(ScriptError::TapscriptValidationWeight)
+        );
+        assert_eq!(
+            spend(&[0xac], vec![vec![1], vec![]], FLAGS),
+            Err(ScriptError::PubKeyType)
+        );
+    }
+}
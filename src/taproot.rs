@@ -0,0 +1,503 @@
+// BIP341 taproot commitments: leaf and branch hashes, key tweaking and control blocks.
+use crate::ecc::{from_bytes, modulo, n, PrivateKey, S256Point};
+use crate::helper::{encode_varint, tagged_hash};
+use crate::script::Script;
+use crate::types::errors::Errors;
+
+pub const TAPROOT_LEAF_MASK: u8 = 0xfe;
+pub const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+pub const TAPROOT_CONTROL_BASE_SIZE: usize = 33;
+pub const TAPROOT_CONTROL_NODE_SIZE: usize = 32;
+pub const TAPROOT_CONTROL_MAX_NODE_COUNT: usize = 128;
+pub const TAPROOT_ANNEX_TAG: u8 = 0x50;
+
+pub fn tap_leaf_hash(leaf_version: u8, script: &[u8]) -> [u8; 32] {
+    let mut data = vec![leaf_version];
+    data.extend(encode_varint(script.len() as u64));
+    data.extend_from_slice(script);
+    tagged_hash("TapLeaf", &data)
+}
+
+// Children are sorted so a branch does not depend on which side each one is on.
+pub fn tap_branch_hash(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    let mut data = left.to_vec();
+    data.extend_from_slice(right);
+    tagged_hash("TapBranch", &data)
+}
+
+// Key path only outputs commit to no merkle root at all.
+pub fn tap_tweak_hash(internal_key: &[u8; 32], merkle_root: Option<&[u8; 32]>) -> [u8; 32] {
+    let mut data = internal_key.to_vec();
+    if let Some(root) = merkle_root {
+        data.extend_from_slice(root);
+    }
+    tagged_hash("TapTweak", &data)
+}
+
+impl S256Point {
+    // Output key Q = lift_x(P) + tG. Its x-only encoding goes in the
+    // scriptPubKey, the parity of its y ends up in the control block.
+    pub fn tap_tweak(&self, merkle_root: Option<&[u8; 32]>) -> Result<S256Point, Errors> {
+        let internal_key = self.xonly();
+        let tweak = from_bytes(&tap_tweak_hash(&internal_key, merkle_root));
+        if &tweak >= n() {
+            return Err(Errors::InvalidPoint);
+        }
+        let output_key = S256Point::lift_x(&internal_key)? + S256Point::generator().mul(&tweak);
+        if output_key.is_infinity() {
+            return Err(Errors::InvalidPoint);
+        }
+        Ok(output_key)
+    }
+}
+
+impl PrivateKey {
+    // Secret for the output key, used to sign key path spends.
+    pub fn tap_tweak(&self, merkle_root: Option<&[u8; 32]>) -> Result<PrivateKey, Errors> {
+        let n = n();
+        let internal_key = self.public_key().xonly();
+        let tweak = from_bytes(&tap_tweak_hash(&internal_key, merkle_root));
+        if &tweak >= n {
+            return Err(Errors::InvalidPrivateKey);
+        }
+        let secret = if self.public_key().has_even_y() {
+            self.secret().clone()
+        } else {
+            n - self.secret()
+        };
+        PrivateKey::new(modulo(&(secret + tweak), n))
+    }
+}
+
+// Leaf version, internal key and merkle path of a script path spend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControlBlock {
+    pub leaf_version: u8,
+    pub output_key_parity: bool,
+    pub internal_key: [u8; 32],
+    pub merkle_branch: Vec<[u8; 32]>,
+}
+
+impl ControlBlock {
+    pub fn parse(bytes: &[u8]) -> Result<Self, Errors> {
+        let path_len = bytes.len().wrapping_sub(TAPROOT_CONTROL_BASE_SIZE);
+        if bytes.len() < TAPROOT_CONTROL_BASE_SIZE
+            || !path_len.is_multiple_of(TAPROOT_CONTROL_NODE_SIZE)
+            || path_len / TAPROOT_CONTROL_NODE_SIZE > TAPROOT_CONTROL_MAX_NODE_COUNT
+        {
+            return Err(Errors::InvalidControlBlock);
+        }
+        Ok(ControlBlock {
+            leaf_version: bytes[0] & TAPROOT_LEAF_MASK,
+            output_key_parity: bytes[0] & 1 == 1,
+            internal_key: bytes[1..33].try_into().unwrap(),
+            merkle_branch: bytes[33..]
+                .chunks(TAPROOT_CONTROL_NODE_SIZE)
+                .map(|node| node.try_into().unwrap())
+                .collect(),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.leaf_version | self.output_key_parity as u8];
+        result.extend_from_slice(&self.internal_key);
+        for node in &self.merkle_branch {
+            result.extend_from_slice(node);
+        }
+        result
+    }
+
+    // Root of the script tree obtained by hashing the leaf up the merkle branch.
+    pub fn merkle_root(&self, script: &[u8]) -> [u8; 32] {
+        self.merkle_branch
+            .iter()
+            .fold(tap_leaf_hash(self.leaf_version, script), |node, sibling| {
+                tap_branch_hash(&node, sibling)
+            })
+    }
+
+    // Checks that script is committed to by the x-only output key.
+    pub fn verify(&self, output_key: &[u8; 32], script: &[u8]) -> bool {
+        let internal_key = match S256Point::lift_x(&self.internal_key) {
+            Ok(point) => point,
+            Err(_) => return false,
+        };
+        match internal_key.tap_tweak(Some(&self.merkle_root(script))) {
+            Ok(point) => {
+                point.xonly() == *output_key && point.has_even_y() != self.output_key_parity
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+// A subtree under construction: its hash, and the leaves below it with the
+// merkle branch from each leaf up to this node.
+#[derive(Clone, Debug)]
+struct TreeNode {
+    hash: [u8; 32],
+    leaves: Vec<TapLeaf>,
+}
+
+impl TreeNode {
+    fn combine(mut a: TreeNode, mut b: TreeNode) -> TreeNode {
+        for leaf in &mut a.leaves {
+            leaf.merkle_branch.push(b.hash);
+        }
+        for leaf in &mut b.leaves {
+            leaf.merkle_branch.push(a.hash);
+        }
+        a.leaves.append(&mut b.leaves);
+        TreeNode {
+            hash: tap_branch_hash(&a.hash, &b.hash),
+            leaves: a.leaves,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TapLeaf {
+    pub leaf_version: u8,
+    pub script: Vec<u8>,
+    pub merkle_branch: Vec<[u8; 32]>,
+}
+
+// Builds a script tree from leaves given in depth first order, left to right,
+// each with its depth in the tree, as TaprootBuilder in Bitcoin Core.
+#[derive(Clone, Debug, Default)]
+pub struct TaprootBuilder {
+    // Entry d holds a complete subtree at depth d still waiting for its sibling.
+    branch: Vec<Option<TreeNode>>,
+}
+
+impl TaprootBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_leaf(self, depth: usize, script: Vec<u8>) -> Result<Self, Errors> {
+        self.add_leaf_with_version(depth, script, TAPROOT_LEAF_TAPSCRIPT)
+    }
+
+    pub fn add_leaf_with_version(
+        self,
+        depth: usize,
+        script: Vec<u8>,
+        leaf_version: u8,
+    ) -> Result<Self, Errors> {
+        if leaf_version & !TAPROOT_LEAF_MASK != 0 || leaf_version == TAPROOT_ANNEX_TAG {
+            return Err(Errors::InvalidTaprootTree("invalid leaf version"));
+        }
+        let node = TreeNode {
+            hash: tap_leaf_hash(leaf_version, &script),
+            leaves: vec![TapLeaf {
+                leaf_version,
+                script,
+                merkle_branch: Vec::new(),
+            }],
+        };
+        self.insert(node, depth)
+    }
+
+    // Adds a subtree known only by its hash, its scripts won't be spendable.
+    pub fn add_hidden(self, depth: usize, hash: [u8; 32]) -> Result<Self, Errors> {
+        self.insert(
+            TreeNode {
+                hash,
+                leaves: Vec::new(),
+            },
+            depth,
+        )
+    }
+
+    fn insert(mut self, mut node: TreeNode, mut depth: usize) -> Result<Self, Errors> {
+        if depth > TAPROOT_CONTROL_MAX_NODE_COUNT {
+            return Err(Errors::InvalidTaprootTree("leaf too deep"));
+        }
+        // Nodes must be added left to right, so nothing may be pending below depth.
+        if depth + 1 < self.branch.len() {
+            return Err(Errors::InvalidTaprootTree("leaf out of order"));
+        }
+        // Merge with pending left siblings for as long as subtrees complete.
+        while depth < self.branch.len() {
+            let sibling = match self.branch.pop().flatten() {
+                Some(sibling) => sibling,
+                None => break,
+            };
+            if depth == 0 {
+                return Err(Errors::InvalidTaprootTree("tree already complete"));
+            }
+            node = TreeNode::combine(sibling, node);
+            depth -= 1;
+        }
+        self.branch.resize(depth + 1, None);
+        self.branch[depth] = Some(node);
+        Ok(self)
+    }
+
+    // True once every branch has both children, or no leaves were added.
+    pub fn is_complete(&self) -> bool {
+        self.branch.is_empty() || (self.branch.len() == 1 && self.branch[0].is_some())
+    }
+
+    pub fn finalize(mut self, internal_key: &S256Point) -> Result<TaprootSpendInfo, Errors> {
+        if !self.is_complete() {
+            return Err(Errors::InvalidTaprootTree("incomplete tree"));
+        }
+        let root = self.branch.pop().flatten();
+        let merkle_root = root.as_ref().map(|node| node.hash);
+        let output_key = internal_key.tap_tweak(merkle_root.as_ref())?;
+        Ok(TaprootSpendInfo {
+            internal_key: internal_key.xonly(),
+            merkle_root,
+            output_key,
+            leaves: root.map(|node| node.leaves).unwrap_or_default(),
+        })
+    }
+}
+
+// Everything needed to pay to a taproot output and spend it by any path.
+#[derive(Clone, Debug)]
+pub struct TaprootSpendInfo {
+    pub internal_key: [u8; 32],
+    pub merkle_root: Option<[u8; 32]>,
+    pub output_key: S256Point,
+    pub leaves: Vec<TapLeaf>,
+}
+
+impl TaprootSpendInfo {
+    pub fn output_key(&self) -> [u8; 32] {
+        self.output_key.xonly()
+    }
+
+    pub fn script_pubkey(&self) -> Script {
+        Script::new_p2tr(&self.output_key())
+    }
+
+    // Control block for the first leaf with this script and version, if any.
+    pub fn control_block(&self, script: &[u8], leaf_version: u8) -> Option<ControlBlock> {
+        let leaf = self
+            .leaves
+            .iter()
+            .find(|leaf| leaf.script == script && leaf.leaf_version == leaf_version)?;
+        Some(ControlBlock {
+            leaf_version,
+            output_key_parity: !self.output_key.has_even_y(),
+            internal_key: self.internal_key,
+            merkle_branch: leaf.merkle_branch.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod taproot_tests {
+    use super::*;
+    use num_bigint::BigInt;
+
+    fn xonly(hex_str: &str) -> [u8; 32] {
+        hex::decode(hex_str).unwrap().try_into().unwrap()
+    }
+
+    // BIP341 wallet test vectors, scriptPubKey section.
+    #[test]
+    fn test_key_path_only_tweak() {
+        let internal_key =
+            xonly("d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d");
+        assert_eq!(
+            hex::encode(tap_tweak_hash(&internal_key, None)),
+            "b86e7be8f39bab32a6f2c0443abbc210f0edac0e2c53d501b36b64437d9c6c70"
+        );
+        let output_key = S256Point::lift_x(&internal_key)
+            .unwrap()
+            .tap_tweak(None)
+            .unwrap();
+        assert_eq!(
+            hex::encode(output_key.xonly()),
+            "53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343"
+        );
+    }
+
+    #[test]
+    fn test_single_leaf_tweak_and_control_block() {
+        let internal_key =
+            xonly("187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27");
+        let script =
+            hex::decode("20d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8ac")
+                .unwrap();
+        let leaf_hash = tap_leaf_hash(TAPROOT_LEAF_TAPSCRIPT, &script);
+        assert_eq!(
+            hex::encode(leaf_hash),
+            "5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"
+        );
+        assert_eq!(
+            hex::encode(tap_tweak_hash(&internal_key, Some(&leaf_hash))),
+            "cbd8679ba636c1110ea247542cfbd964131a6be84f873f7f3b62a777528ed001"
+        );
+
+        let output_key = xonly("147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3");
+        let mut control_block_bytes = vec![0xc1];
+        control_block_bytes.extend_from_slice(&internal_key);
+        let control_block = ControlBlock::parse(&control_block_bytes).unwrap();
+        assert!(control_block.verify(&output_key, &script));
+        assert_eq!(control_block.serialize(), control_block_bytes);
+
+        let mut wrong_parity = control_block.clone();
+        wrong_parity.output_key_parity = false;
+        assert!(!wrong_parity.verify(&output_key, &script));
+        assert!(!control_block.verify(&output_key, &script[1..]));
+    }
+
+    #[test]
+    fn test_control_block_length() {
+        assert_eq!(
+            ControlBlock::parse(&[0xc0; 32]),
+            Err(Errors::InvalidControlBlock)
+        );
+        assert_eq!(
+            ControlBlock::parse(&[0xc0; 34]),
+            Err(Errors::InvalidControlBlock)
+        );
+        assert_eq!(
+            ControlBlock::parse(&[0xc0; 65])
+                .unwrap()
+                .merkle_branch
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_private_key_tweak_matches_public_tweak() {
+        let merkle_root = [7u8; 32];
+        // Secrets whose public keys have both even and odd y.
+        for secret in [1u32, 2, 3, 8675309] {
+            let key = PrivateKey::new(BigInt::from(secret)).unwrap();
+            let tweaked = key.tap_tweak(Some(&merkle_root)).unwrap();
+            let expected = key.public_key().tap_tweak(Some(&merkle_root)).unwrap();
+            assert_eq!(tweaked.public_key(), &expected);
+        }
+    }
+
+    #[test]
+    fn test_builder_matches_bip341_vectors() {
+        let internal_key = S256Point::lift_x(&xonly(
+            "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+        ))
+        .unwrap();
+        let info = TaprootBuilder::new().finalize(&internal_key).unwrap();
+        assert_eq!(info.merkle_root, None);
+        assert_eq!(
+            hex::encode(info.output_key()),
+            "53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343"
+        );
+
+        let internal_key = S256Point::lift_x(&xonly(
+            "187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+        ))
+        .unwrap();
+        let script =
+            hex::decode("20d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8ac")
+                .unwrap();
+        let info = TaprootBuilder::new()
+            .add_leaf(0, script.clone())
+            .unwrap()
+            .finalize(&internal_key)
+            .unwrap();
+        assert_eq!(
+            hex::encode(info.merkle_root.unwrap()),
+            "5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"
+        );
+        assert_eq!(
+            hex::encode(info.script_pubkey().as_bytes()),
+            "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3"
+        );
+        let control_block = info.control_block(&script, TAPROOT_LEAF_TAPSCRIPT).unwrap();
+        assert_eq!(
+            hex::encode(control_block.serialize()),
+            "c1187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27"
+        );
+    }
+
+    #[test]
+    fn test_builder_tree() {
+        let scripts: Vec<Vec<u8>> = (0..4u8).map(|i| vec![0x51 + i]).collect();
+        let hidden = [9u8; 32];
+        // ((A, (B, C)), (hidden, D))
+        let info = TaprootBuilder::new()
+            .add_leaf(2, scripts[0].clone())
+            .unwrap()
+            .add_leaf(3, scripts[1].clone())
+            .unwrap()
+            .add_leaf(3, scripts[2].clone())
+            .unwrap()
+            .add_hidden(2, hidden)
+            .unwrap()
+            .add_leaf_with_version(2, scripts[3].clone(), 0xc2)
+            .unwrap()
+            .finalize(
+                &PrivateKey::new(BigInt::from(3))
+                    .unwrap()
+                    .public_key()
+                    .clone(),
+            )
+            .unwrap();
+
+        let leaf = |i: usize, version| tap_leaf_hash(version, &scripts[i]);
+        let expected_root = tap_branch_hash(
+            &tap_branch_hash(
+                &leaf(0, TAPROOT_LEAF_TAPSCRIPT),
+                &tap_branch_hash(
+                    &leaf(1, TAPROOT_LEAF_TAPSCRIPT),
+                    &leaf(2, TAPROOT_LEAF_TAPSCRIPT),
+                ),
+            ),
+            &tap_branch_hash(&hidden, &leaf(3, 0xc2)),
+        );
+        assert_eq!(info.merkle_root, Some(expected_root));
+        assert_eq!(info.leaves.len(), 4);
+
+        let output_key = info.output_key();
+        for (i, version) in [(0, 0xc0), (1, 0xc0), (2, 0xc0), (3, 0xc2)] {
+            let control_block = info.control_block(&scripts[i], version).unwrap();
+            assert_eq!(control_block.merkle_root(&scripts[i]), expected_root);
+            assert!(control_block.verify(&output_key, &scripts[i]));
+        }
+        assert_eq!(info.control_block(&scripts[3], 0xc0), None);
+    }
+
+    #[test]
+    fn test_builder_invalid_trees() {
+        let script = vec![0x51];
+        // A lone leaf below the root leaves its sibling missing.
+        let builder = TaprootBuilder::new().add_leaf(1, script.clone()).unwrap();
+        assert!(!builder.is_complete());
+        let key = PrivateKey::new(BigInt::from(3))
+            .unwrap()
+            .public_key()
+            .clone();
+        assert_eq!(
+            builder.finalize(&key).unwrap_err(),
+            Errors::InvalidTaprootTree("incomplete tree")
+        );
+        // Two roots.
+        let builder = TaprootBuilder::new().add_leaf(0, script.clone()).unwrap();
+        assert!(builder.is_complete());
+        assert_eq!(
+            builder.add_leaf(0, script.clone()).unwrap_err(),
+            Errors::InvalidTaprootTree("tree already complete")
+        );
+        // A shallower leaf after deeper pending ones is out of order.
+        let builder = TaprootBuilder::new().add_leaf(2, script.clone()).unwrap();
+        assert_eq!(
+            builder.add_leaf(0, script.clone()).unwrap_err(),
+            Errors::InvalidTaprootTree("leaf out of order")
+        );
+        assert!(TaprootBuilder::new()
+            .add_leaf_with_version(0, script.clone(), 0xc1)
+            .is_err());
+        assert!(TaprootBuilder::new()
+            .add_leaf(TAPROOT_CONTROL_MAX_NODE_COUNT + 1, script)
+            .is_err());
+    }
+}
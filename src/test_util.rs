@@ -0,0 +1,103 @@
+// Fixtures shared by the tests: regtest coinbases, spends and blocks mined on top of a
+// chain, and paths in the temporary directory.
+use crate::block::{Block, BlockHeader};
+use crate::chain::{BlockStorage, ChainState, MemoryBackend, UtxoBackend};
+use crate::network::Network;
+use crate::script::Script;
+use crate::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use crate::validation::COINBASE_MATURITY;
+use std::path::PathBuf;
+
+// A path in the temporary directory, told apart from other test processes'.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}_{}", name, std::process::id()))
+}
+
+// Coinbase of a block at height paying value to OP_TRUE. tag tells apart those of
+// blocks competing at a height.
+pub fn coinbase(height: u32, tag: u8, value: u64) -> Transaction {
+    let mut script_sig = Script::new();
+    script_sig.push_int(height as i64).push_int(tag as i64);
+    Transaction::new(
+        1,
+        vec![TxIn::new(OutPoint::null(), script_sig, 0xffffffff)],
+        vec![TxOut::new(value, vec![0x51].into())],
+        0,
+    )
+}
+
+// Spends outpoints with empty scriptSigs into one output of value to OP_2.
+pub fn spend(outpoints: &[OutPoint], value: u64, sequence: u32) -> Transaction {
+    let inputs = outpoints
+        .iter()
+        .map(|outpoint| TxIn::new(*outpoint, Script::new(), sequence))
+        .collect();
+    Transaction::new(2, inputs, vec![TxOut::new(value, vec![0x52].into())], 0)
+}
+
+// Takes the first nonce meeting header's regtest target.
+pub fn solve(header: &mut BlockHeader) {
+    header.nonce = 0;
+    while !header.check_pow() {
+        header.nonce += 1;
+    }
+}
+
+// Block of transactions on top of prev, ten minutes after it.
+pub fn mine(prev: &BlockHeader, transactions: Vec<Transaction>) -> Block {
+    let mut block = Block::new(*prev, transactions);
+    block.header.prev_block = prev.hash();
+    block.header.timestamp = prev.timestamp + 600;
+    block.header.merkle_root = block.compute_merkle_root();
+    solve(&mut block.header);
+    block
+}
+
+// A regtest chain of count blocks on top of genesis, each paying the subsidy to
+// OP_TRUE.
+pub fn blocks(count: u32) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut prev = Network::Regtest.genesis_block().header;
+    for height in 1..=count {
+        let coinbase = coinbase(height, 1, Network::Regtest.block_subsidy(height));
+        let block = mine(&prev, vec![coinbase]);
+        prev = block.header;
+        blocks.push(block);
+    }
+    blocks
+}
+
+// Block on top of prev in chain, its coinbase paying the subsidy, followed by spends.
+pub fn mine_on<B: UtxoBackend, S: BlockStorage>(
+    chain: &ChainState<B, S>,
+    prev: [u8; 32],
+    tag: u8,
+    spends: Vec<Transaction>,
+) -> Block {
+    let prev = chain.headers().get(&prev).unwrap();
+    let height = prev.height + 1;
+    let mut transactions = vec![coinbase(
+        height,
+        tag,
+        Network::Regtest.block_subsidy(height),
+    )];
+    transactions.extend(spends);
+    mine(&prev.header, transactions)
+}
+
+// The coin paid to by block's coinbase.
+pub fn funding(block: &Block) -> OutPoint {
+    OutPoint::new(block.transactions[0].txid(), 0)
+}
+
+// A regtest chain whose first coinbase is just mature, and that coin.
+pub fn mature_chain() -> (ChainState, OutPoint) {
+    let mut chain = ChainState::new(Network::Regtest, MemoryBackend::default());
+    let first = mine_on(&chain, chain.tip().hash, 0, vec![]);
+    chain.accept_block(first.clone()).unwrap();
+    for _ in 0..COINBASE_MATURITY {
+        let block = mine_on(&chain, chain.tip().hash, 0, vec![]);
+        chain.accept_block(block).unwrap();
+    }
+    (chain, funding(&first))
+}
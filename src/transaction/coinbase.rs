@@ -0,0 +1,155 @@
+// Coinbase transaction recognition and BIP34 height commitments.
+use super::{OutPoint, Transaction};
+use crate::types::errors::Errors;
+
+impl OutPoint {
+    // The outpoint referenced by coinbase inputs: zero txid and index 0xffffffff.
+    pub fn null() -> Self {
+        OutPoint::new([0u8; 32], 0xffffffff)
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::null()
+    }
+}
+
+impl Transaction {
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous_output.is_null()
+    }
+
+    // Height pushed at the start of the coinbase scriptSig (BIP34). None if this is
+    // not a coinbase or the script does not start with a height push.
+    pub fn coinbase_height(&self) -> Option<u32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let script_sig = &self.inputs[0].script_sig;
+        match *script_sig.first()? {
+            // OP_0 and OP_1..OP_16, used by heights up to 16.
+            0x00 => Some(0),
+            op @ 0x51..=0x60 => Some((op - 0x50) as u32),
+            len @ 0x01..=0x05 => {
+                let bytes = script_sig.get(1..1 + len as usize)?;
+                decode_height(bytes)
+            }
+            _ => None,
+        }
+    }
+
+    // Structural checks on the inputs of a coinbase, or on the absence of null
+    // prevouts for any other transaction.
+    pub fn check_coinbase_inputs(&self) -> Result<(), Errors> {
+        if self.is_coinbase() {
+            let len = self.inputs[0].script_sig.len();
+            if !(2..=100).contains(&len) {
+                return Err(Errors::BadCoinbaseLength(len));
+            }
+            return Ok(());
+        }
+        if self
+            .inputs
+            .iter()
+            .any(|input| input.previous_output.is_null())
+        {
+            return Err(Errors::NullPrevout);
+        }
+        Ok(())
+    }
+}
+
+// Height pushes are minimally encoded little-endian script numbers, never negative.
+fn decode_height(bytes: &[u8]) -> Option<u32> {
+    let last = *bytes.last()?;
+    if last & 0x80 != 0 {
+        return None;
+    }
+    let mut height: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        height |= (*byte as u64) << (8 * i);
+    }
+    u32::try_from(height).ok()
+}
+
+#[cfg(test)]
+mod coinbase_tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::transaction::{TxIn, TxOut};
+
+    // Coinbase of block 465879, from chapter 9 of Programming Bitcoin.
+    const COINBASE_TX: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff5e03d71b07254d696e656420627920416e74506f6f6c20626a31312f4542312f4144362f43205914293101fabe6d6d678e2c8c34afc36896e7d9402824ed38e856676ee94bfdb0c6c4bcd8b2e5666a0400000000000000c7270000a5e00e00ffffffff01faf20b58000000001976a914338c84849423992471bffb1a54a8d9b1d69dc28a88ac00000000";
+
+    fn coinbase_with_script(script_sig: Vec<u8>) -> Transaction {
+        Transaction::new(
+            1,
+            vec![TxIn::new(OutPoint::null(), script_sig.into(), 0xffffffff)],
+            vec![TxOut::new(50, Script::new())],
+            0,
+        )
+    }
+
+    #[test]
+    fn test_is_coinbase() {
+        let tx = Transaction::from_hex(COINBASE_TX).unwrap();
+        assert!(tx.is_coinbase());
+
+        let mut spend = tx.clone();
+        spend.inputs[0].previous_output.vout = 0;
+        assert!(!spend.is_coinbase());
+    }
+
+    #[test]
+    fn test_coinbase_height() {
+        let tx = Transaction::from_hex(COINBASE_TX).unwrap();
+        assert_eq!(tx.coinbase_height(), Some(465879));
+    }
+
+    #[test]
+    fn test_small_coinbase_heights() {
+        assert_eq!(
+            coinbase_with_script(vec![0x00, 0x00]).coinbase_height(),
+            Some(0)
+        );
+        assert_eq!(
+            coinbase_with_script(vec![0x5a, 0x00]).coinbase_height(),
+            Some(10)
+        );
+        assert_eq!(
+            coinbase_with_script(vec![0x01, 0x11]).coinbase_height(),
+            Some(17)
+        );
+        // Negative and truncated pushes are not heights.
+        assert_eq!(
+            coinbase_with_script(vec![0x01, 0x81]).coinbase_height(),
+            None
+        );
+        assert_eq!(
+            coinbase_with_script(vec![0x03, 0x01]).coinbase_height(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check_coinbase_inputs() {
+        assert_eq!(
+            coinbase_with_script(vec![0x01, 0x11]).check_coinbase_inputs(),
+            Ok(())
+        );
+        assert_eq!(
+            coinbase_with_script(vec![0x00]).check_coinbase_inputs(),
+            Err(Errors::BadCoinbaseLength(1))
+        );
+        assert_eq!(
+            coinbase_with_script(vec![0x00; 101]).check_coinbase_inputs(),
+            Err(Errors::BadCoinbaseLength(101))
+        );
+
+        // A regular transaction sneaking in a null prevout.
+        let mut tx = coinbase_with_script(vec![0x01, 0x11]);
+        tx.inputs
+            .push(TxIn::new(OutPoint::new([1; 32], 0), Script::new(), 0));
+        assert!(!tx.is_coinbase());
+        assert_eq!(tx.check_coinbase_inputs(), Err(Errors::NullPrevout));
+    }
+}
@@ -0,0 +1,212 @@
+// Child-pays-for-parent: bump a stuck transaction by spending one of its outputs with
+// a child paying enough fee for both.
+use super::weight::WITNESS_SCALE_FACTOR;
+use super::{OutPoint, Sequence, Transaction, TxIn, TxOut};
+use crate::policy::{FeeRate, DUST_RELAY_TX_FEE};
+use crate::script::Script;
+use crate::types::errors::Errors;
+
+// How the output being spent will be unlocked, which determines how much weight the
+// signature data adds to the child.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpendKind {
+    P2pkh,
+    P2wpkh,
+    P2trKeySpend,
+}
+
+impl SpendKind {
+    // Worst case weight of the scriptSig contents and witness, including the segwit
+    // marker and flag for witness spends.
+    pub fn satisfaction_weight(self) -> usize {
+        match self {
+            // <73 byte signature> <33 byte pubkey> pushes, all non-witness data.
+            SpendKind::P2pkh => (1 + 73 + 1 + 33) * WITNESS_SCALE_FACTOR,
+            // Item count plus both length-prefixed items.
+            SpendKind::P2wpkh => 2 + 1 + 1 + 73 + 1 + 33,
+            // A single 64 byte SIGHASH_DEFAULT signature.
+            SpendKind::P2trKeySpend => 2 + 1 + 1 + 64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpfpChild {
+    // Unsigned child transaction.
+    pub tx: Transaction,
+    pub fee: u64,
+    // Feerate of parent and child together once the child is signed.
+    pub package_feerate: FeeRate,
+}
+
+pub struct CpfpBuilder<'a> {
+    parent: &'a Transaction,
+    parent_fee: u64,
+    vout: u32,
+    spend_kind: SpendKind,
+    destination: Script,
+    target_feerate: FeeRate,
+    dust_feerate: FeeRate,
+}
+
+impl<'a> CpfpBuilder<'a> {
+    // parent_fee is the fee already paid by the parent, vout the output we can spend,
+    // and destination the scriptPubKey receiving what remains after fees.
+    pub fn new(parent: &'a Transaction, parent_fee: u64, vout: u32, destination: Script) -> Self {
+        CpfpBuilder {
+            parent,
+            parent_fee,
+            vout,
+            spend_kind: SpendKind::P2wpkh,
+            destination,
+            target_feerate: FeeRate::from_sat_per_vb(1),
+            dust_feerate: FeeRate::from_sat_per_kvb(DUST_RELAY_TX_FEE),
+        }
+    }
+
+    pub fn with_spend_kind(mut self, spend_kind: SpendKind) -> Self {
+        self.spend_kind = spend_kind;
+        self
+    }
+
+    pub fn with_target_feerate(mut self, target_feerate: FeeRate) -> Self {
+        self.target_feerate = target_feerate;
+        self
+    }
+
+    pub fn with_dust_feerate(mut self, dust_feerate: FeeRate) -> Self {
+        self.dust_feerate = dust_feerate;
+        self
+    }
+
+    pub fn build(&self) -> Result<CpfpChild, Errors> {
+        let spent = self.parent.outputs.get(self.vout as usize).ok_or_else(|| {
+            Errors::UnknownOutput(format!("{}:{}", self.parent.txid_hex(), self.vout))
+        })?;
+
+        let input = TxIn::new(
+            OutPoint::new(self.parent.txid(), self.vout),
+            Script::new(),
+            Sequence::ENABLE_RBF_NO_LOCKTIME.0,
+        );
+        let mut child = Transaction::new(
+            2,
+            vec![input],
+            vec![TxOut::new(0, self.destination.clone())],
+            0,
+        );
+
+        let child_weight = child.weight() + self.spend_kind.satisfaction_weight();
+        let child_vsize = child_weight.div_ceil(WITNESS_SCALE_FACTOR);
+        let package_vsize = self.parent.vsize() + child_vsize;
+
+        // The child pays for the whole package, but never less than the target for itself
+        // (when the parent alone already meets the target).
+        let package_fee = self.target_feerate.fee_for_vsize(package_vsize);
+        let fee = package_fee
+            .saturating_sub(self.parent_fee)
+            .max(self.target_feerate.fee_for_vsize(child_vsize));
+
+        let value = spent
+            .value
+            .checked_sub(fee)
+            .ok_or(Errors::InsufficientFunds)?;
+        child.outputs[0].value = value;
+        if child.outputs[0].is_dust(self.dust_feerate) {
+            return Err(Errors::InsufficientFunds);
+        }
+
+        Ok(CpfpChild {
+            tx: child,
+            fee,
+            package_feerate: FeeRate::from_fee_and_vsize(self.parent_fee + fee, package_vsize),
+        })
+    }
+}
+
+#[cfg(test)]
+mod cpfp_tests {
+    use super::*;
+
+    fn p2wpkh(byte: u8) -> Vec<u8> {
+        let mut script = vec![0x00, 0x14];
+        script.extend_from_slice(&[byte; 20]);
+        script
+    }
+
+    fn parent() -> Transaction {
+        let mut script_sig = vec![72];
+        script_sig.extend_from_slice(&[0x30; 72]);
+        Transaction::new(
+            2,
+            vec![TxIn::new(
+                OutPoint::new([7; 32], 0),
+                script_sig.into(),
+                0xfffffffd,
+            )],
+            vec![
+                TxOut::new(50_000, p2wpkh(1).into()),
+                TxOut::new(20_000, p2wpkh(2).into()),
+            ],
+            0,
+        )
+    }
+
+    #[test]
+    fn test_child_brings_package_to_target() {
+        let parent = parent();
+        let target = FeeRate::from_sat_per_vb(20);
+        let child = CpfpBuilder::new(&parent, 100, 1, p2wpkh(3).into())
+            .with_target_feerate(target)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            child.tx.inputs[0].previous_output,
+            OutPoint::new(parent.txid(), 1)
+        );
+        assert_eq!(child.tx.outputs[0].value, 20_000 - child.fee);
+        assert!(child.package_feerate >= target);
+        // Within a satoshi per vbyte of the target, no gross overpayment.
+        assert!(child.package_feerate < FeeRate::from_sat_per_vb(21));
+    }
+
+    #[test]
+    fn test_child_pays_own_fee_when_parent_is_enough() {
+        let parent = parent();
+        let child = CpfpBuilder::new(&parent, 100_000, 0, p2wpkh(3).into())
+            .with_target_feerate(FeeRate::from_sat_per_vb(2))
+            .with_spend_kind(SpendKind::P2trKeySpend)
+            .build()
+            .unwrap();
+
+        let child_vsize =
+            (child.tx.weight() + SpendKind::P2trKeySpend.satisfaction_weight()).div_ceil(4);
+        assert_eq!(child.fee, 2 * child_vsize as u64);
+    }
+
+    #[test]
+    fn test_insufficient_value() {
+        let parent = parent();
+        let builder = CpfpBuilder::new(&parent, 0, 1, p2wpkh(3).into())
+            .with_target_feerate(FeeRate::from_sat_per_vb(100));
+        assert_eq!(builder.build(), Err(Errors::InsufficientFunds));
+    }
+
+    #[test]
+    fn test_unknown_output() {
+        let parent = parent();
+        assert!(matches!(
+            CpfpBuilder::new(&parent, 0, 5, p2wpkh(3).into()).build(),
+            Err(Errors::UnknownOutput(_))
+        ));
+    }
+
+    #[test]
+    fn test_satisfaction_weights() {
+        assert!(SpendKind::P2pkh.satisfaction_weight() > SpendKind::P2wpkh.satisfaction_weight());
+        assert!(
+            SpendKind::P2wpkh.satisfaction_weight() > SpendKind::P2trKeySpend.satisfaction_weight()
+        );
+    }
+}
@@ -0,0 +1,124 @@
+// Fee computation against an arbitrary source of previous outputs.
+use super::{OutPoint, Transaction, TxOut};
+use crate::types::errors::Errors;
+use std::collections::HashMap;
+
+// Anything able to look up the output an input spends: a local UTXO set, a wallet,
+// or a remote API. Returns Errors::UnknownOutput when the output cannot be found.
+pub trait UtxoProvider {
+    fn get_output(&self, outpoint: &OutPoint) -> Result<TxOut, Errors>;
+}
+
+impl UtxoProvider for HashMap<OutPoint, TxOut> {
+    fn get_output(&self, outpoint: &OutPoint) -> Result<TxOut, Errors> {
+        self.get(outpoint)
+            .cloned()
+            .ok_or_else(|| Errors::UnknownOutput(outpoint.to_string()))
+    }
+}
+
+impl<T: UtxoProvider + ?Sized> UtxoProvider for &T {
+    fn get_output(&self, outpoint: &OutPoint) -> Result<TxOut, Errors> {
+        (**self).get_output(outpoint)
+    }
+}
+
+impl Transaction {
+    // Sum of the values of every output spent by this transaction.
+    pub fn input_value(&self, utxos: &impl UtxoProvider) -> Result<u64, Errors> {
+        self.inputs.iter().try_fold(0u64, |total, input| {
+            let prevout = utxos.get_output(&input.previous_output)?;
+            total
+                .checked_add(prevout.value)
+                .ok_or(Errors::ValueOutOfRange)
+        })
+    }
+
+    pub fn output_value(&self) -> Result<u64, Errors> {
+        self.outputs.iter().try_fold(0u64, |total, output| {
+            total
+                .checked_add(output.value)
+                .ok_or(Errors::ValueOutOfRange)
+        })
+    }
+
+    // Fee paid in satoshis. Fails if an input's previous output is unknown or the
+    // outputs spend more than the inputs provide.
+    pub fn fee(&self, utxos: &impl UtxoProvider) -> Result<u64, Errors> {
+        self.input_value(utxos)?
+            .checked_sub(self.output_value()?)
+            .ok_or(Errors::NegativeFee)
+    }
+}
+
+#[cfg(test)]
+mod fee_tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::transaction::TxIn;
+
+    fn spending_tx(prevouts: &[OutPoint], output_values: &[u64]) -> Transaction {
+        let inputs = prevouts
+            .iter()
+            .map(|outpoint| TxIn::new(*outpoint, Script::new(), 0xffffffff))
+            .collect();
+        let outputs = output_values
+            .iter()
+            .map(|value| TxOut::new(*value, vec![0x51].into()))
+            .collect();
+        Transaction::new(2, inputs, outputs, 0)
+    }
+
+    fn utxo_set(entries: &[(OutPoint, u64)]) -> HashMap<OutPoint, TxOut> {
+        entries
+            .iter()
+            .map(|(outpoint, value)| (*outpoint, TxOut::new(*value, vec![0x51].into())))
+            .collect()
+    }
+
+    #[test]
+    fn test_fee() {
+        let a = OutPoint::new([1; 32], 0);
+        let b = OutPoint::new([2; 32], 3);
+        let utxos = utxo_set(&[(a, 50_000), (b, 25_000)]);
+        let tx = spending_tx(&[a, b], &[40_000, 30_000]);
+
+        assert_eq!(tx.input_value(&utxos), Ok(75_000));
+        assert_eq!(tx.output_value(), Ok(70_000));
+        assert_eq!(tx.fee(&utxos), Ok(5_000));
+    }
+
+    #[test]
+    fn test_fee_unknown_input() {
+        let a = OutPoint::new([1; 32], 0);
+        let tx = spending_tx(&[a], &[1_000]);
+
+        assert_eq!(
+            tx.fee(&HashMap::new()),
+            Err(Errors::UnknownOutput(a.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_fee_negative() {
+        let a = OutPoint::new([1; 32], 0);
+        let utxos = utxo_set(&[(a, 1_000)]);
+        let tx = spending_tx(&[a], &[1_001]);
+
+        assert_eq!(tx.fee(&utxos), Err(Errors::NegativeFee));
+    }
+
+    #[test]
+    fn test_custom_provider() {
+        // A provider that knows every output and values it at 1 BTC.
+        struct Generous;
+        impl UtxoProvider for Generous {
+            fn get_output(&self, _outpoint: &OutPoint) -> Result<TxOut, Errors> {
+                Ok(TxOut::new(100_000_000, Script::new()))
+            }
+        }
+
+        let tx = spending_tx(&[OutPoint::new([9; 32], 1)], &[99_999_000]);
+        assert_eq!(tx.fee(&Generous), Ok(1_000));
+    }
+}
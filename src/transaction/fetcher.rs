@@ -0,0 +1,220 @@
+// Retrieves raw transactions from a block explorer, checking that the returned
+// bytes really hash to the requested txid before trusting them.
+use super::{txid_from_hex, txid_to_hex, OutPoint, Transaction, TxOut, UtxoProvider};
+use crate::network::Network;
+use crate::types::errors::Errors;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+pub trait HttpClient {
+    fn get(&self, url: &str) -> Result<String, Errors>;
+}
+
+pub struct UreqClient;
+
+impl HttpClient for UreqClient {
+    fn get(&self, url: &str) -> Result<String, Errors> {
+        ureq::get(url)
+            .call()
+            .map_err(|e| Errors::Http(e.to_string()))?
+            .into_string()
+            .map_err(|e| Errors::Http(e.to_string()))
+    }
+}
+
+pub struct TxFetcher<C: HttpClient = UreqClient> {
+    base_url: String,
+    cache_dir: Option<PathBuf>,
+    client: C,
+    cache: RefCell<HashMap<[u8; 32], Transaction>>,
+}
+
+impl TxFetcher<UreqClient> {
+    // Uses the blockstream.info esplora API for the given network.
+    pub fn new(network: Network) -> Self {
+        TxFetcher::with_client(default_base_url(network), UreqClient)
+    }
+}
+
+impl<C: HttpClient> TxFetcher<C> {
+    // base_url must expose the esplora `GET {base_url}/tx/{txid}/hex` endpoint.
+    pub fn with_client(base_url: &str, client: C) -> Self {
+        TxFetcher {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cache_dir: None,
+            client,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Persists every fetched transaction as <txid>.hex inside dir.
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    // txid is given in display byte order, as block explorers show it.
+    // With fresh set, both caches are bypassed.
+    pub fn fetch(&self, txid: &str, fresh: bool) -> Result<Transaction, Errors> {
+        let id = txid_from_hex(txid)?;
+        if !fresh {
+            if let Some(tx) = self.cache.borrow().get(&id) {
+                return Ok(tx.clone());
+            }
+            if let Some(tx) = self.load_from_disk(&id)? {
+                self.cache.borrow_mut().insert(id, tx.clone());
+                return Ok(tx);
+            }
+        }
+
+        let url = format!("{}/tx/{}/hex", self.base_url, txid_to_hex(&id));
+        let raw = self.client.get(&url)?;
+        let tx = parse_and_verify(&id, &raw)?;
+        self.store_on_disk(&id, &raw)?;
+        self.cache.borrow_mut().insert(id, tx.clone());
+        Ok(tx)
+    }
+
+    fn cache_path(&self, id: &[u8; 32]) -> Option<PathBuf> {
+        self.cache_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.hex", txid_to_hex(id))))
+    }
+
+    fn load_from_disk(&self, id: &[u8; 32]) -> Result<Option<Transaction>, Errors> {
+        match self.cache_path(id) {
+            Some(path) if path.exists() => {
+                let raw = fs::read_to_string(path).map_err(|e| Errors::Io(e.to_string()))?;
+                // Files on disk could have been tampered with, so they get verified too.
+                parse_and_verify(id, &raw).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn store_on_disk(&self, id: &[u8; 32], raw: &str) -> Result<(), Errors> {
+        if let (Some(dir), Some(path)) = (&self.cache_dir, self.cache_path(id)) {
+            fs::create_dir_all(dir).map_err(|e| Errors::Io(e.to_string()))?;
+            fs::write(path, raw.trim()).map_err(|e| Errors::Io(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+// Lets fee computation and script lookup pull previous outputs straight from the explorer.
+impl<C: HttpClient> UtxoProvider for TxFetcher<C> {
+    fn get_output(&self, outpoint: &OutPoint) -> Result<TxOut, Errors> {
+        let tx = self.fetch(&txid_to_hex(&outpoint.txid), false)?;
+        tx.outputs
+            .get(outpoint.vout as usize)
+            .cloned()
+            .ok_or_else(|| Errors::UnknownOutput(outpoint.to_string()))
+    }
+}
+
+fn parse_and_verify(id: &[u8; 32], raw: &str) -> Result<Transaction, Errors> {
+    let tx = Transaction::from_hex(raw)?;
+    // txid() hashes the witness-stripped serialization, so segwit responses verify too.
+    if &tx.txid() != id {
+        return Err(Errors::TxidMismatch(txid_to_hex(id)));
+    }
+    Ok(tx)
+}
+
+fn default_base_url(network: Network) -> &'static str {
+    match network {
+        Network::Mainnet => "https://blockstream.info/api",
+        Network::Testnet => "https://blockstream.info/testnet/api",
+        Network::Signet => "https://mempool.space/signet/api",
+        // There is no public explorer for regtest, callers should use with_client.
+        Network::Regtest => "http://127.0.0.1:3002",
+    }
+}
+
+#[cfg(test)]
+mod fetcher_tests {
+    use super::*;
+    use crate::test_util::temp_path;
+    use std::cell::Cell;
+
+    const RAW_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+    const TXID: &str = "452c629d67e41baec3ac6f04fe744b4b9617f8f859c63b3002f8684e7a4fee03";
+
+    struct StubClient {
+        response: String,
+        calls: Cell<usize>,
+    }
+
+    impl HttpClient for &StubClient {
+        fn get(&self, url: &str) -> Result<String, Errors> {
+            assert!(url.ends_with(&format!("/tx/{}/hex", TXID)));
+            self.calls.set(self.calls.get() + 1);
+            Ok(self.response.clone())
+        }
+    }
+
+    fn stub(response: &str) -> StubClient {
+        StubClient {
+            response: response.to_string(),
+            calls: Cell::new(0),
+        }
+    }
+
+    #[test]
+    fn test_fetch_uses_memory_cache() {
+        let client = stub(RAW_TX);
+        let fetcher = TxFetcher::with_client("http://explorer/api/", &client);
+
+        let tx = fetcher.fetch(TXID, false).unwrap();
+        assert_eq!(tx.txid_hex(), TXID);
+        fetcher.fetch(TXID, false).unwrap();
+        assert_eq!(client.calls.get(), 1);
+
+        fetcher.fetch(TXID, true).unwrap();
+        assert_eq!(client.calls.get(), 2);
+    }
+
+    #[test]
+    fn test_fetch_rejects_wrong_transaction() {
+        // Same transaction with a different locktime hashes to another txid.
+        let tampered = format!("{}00000000", &RAW_TX[..RAW_TX.len() - 8]);
+        let client = stub(&tampered);
+        let fetcher = TxFetcher::with_client("http://explorer/api", &client);
+
+        assert_eq!(
+            fetcher.fetch(TXID, false),
+            Err(Errors::TxidMismatch(TXID.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_fetch_uses_disk_cache() {
+        let dir = temp_path("tx_fetcher_test");
+        let client = stub(RAW_TX);
+        TxFetcher::with_client("http://explorer/api", &client)
+            .with_cache_dir(&dir)
+            .fetch(TXID, false)
+            .unwrap();
+
+        // A new fetcher starts with an empty memory cache but finds the file.
+        let fetcher = TxFetcher::with_client("http://explorer/api", &client).with_cache_dir(&dir);
+        fetcher.fetch(TXID, false).unwrap();
+        assert_eq!(client.calls.get(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetcher_as_utxo_provider() {
+        let client = stub(RAW_TX);
+        let fetcher = TxFetcher::with_client("http://explorer/api", &client);
+        let outpoint = OutPoint::new(txid_from_hex(TXID).unwrap(), 1);
+
+        assert_eq!(fetcher.get_output(&outpoint).unwrap().value, 10011545);
+        assert!(fetcher
+            .get_output(&OutPoint::new(outpoint.txid, 2))
+            .is_err());
+    }
+}
@@ -0,0 +1,647 @@
+// Signing an input by looking at the output it spends: picks the sighash algorithm,
+// signs with whichever provided keys the script asks for and fills scriptSig/witness.
+use super::sighash::{p2wpkh_script_code, SigHashType, SighashCache};
+use super::{Transaction, TxOut, Witness};
+use crate::ecc::PrivateKey;
+use crate::helper::{hash160, sha256};
+use crate::script::{encode_push, is_p2sh, parse_multisig, parse_ops, witness_program};
+use crate::taproot::{tap_leaf_hash, ControlBlock};
+use crate::types::errors::Errors;
+
+// Everything besides the transaction needed to sign one input. Only the scripts the
+// spent output commits to by hash have to be provided.
+#[derive(Clone, Debug)]
+pub struct SigningData {
+    pub keys: Vec<PrivateKey>,
+    pub redeem_script: Option<Vec<u8>>,
+    pub witness_script: Option<Vec<u8>>,
+    // Merkle root of the script tree, needed to tweak the key for a key path spend.
+    pub tap_merkle_root: Option<[u8; 32]>,
+    // Leaf script and control block, set to spend through the script path.
+    pub tap_leaf: Option<(Vec<u8>, Vec<u8>)>,
+    // Annex appended to a taproot witness and committed to by its signatures.
+    pub annex: Option<Vec<u8>>,
+    // SIGHASH_ALL for ECDSA and SIGHASH_DEFAULT for taproot when not set.
+    pub sighash_type: Option<SigHashType>,
+}
+
+impl SigningData {
+    pub fn new(keys: Vec<PrivateKey>) -> Self {
+        SigningData {
+            keys,
+            redeem_script: None,
+            witness_script: None,
+            tap_merkle_root: None,
+            tap_leaf: None,
+            annex: None,
+            sighash_type: None,
+        }
+    }
+
+    pub fn with_redeem_script(mut self, redeem_script: Vec<u8>) -> Self {
+        self.redeem_script = Some(redeem_script);
+        self
+    }
+
+    pub fn with_witness_script(mut self, witness_script: Vec<u8>) -> Self {
+        self.witness_script = Some(witness_script);
+        self
+    }
+
+    pub fn with_tap_merkle_root(mut self, merkle_root: [u8; 32]) -> Self {
+        self.tap_merkle_root = Some(merkle_root);
+        self
+    }
+
+    pub fn with_tap_leaf(mut self, script: Vec<u8>, control_block: Vec<u8>) -> Self {
+        self.tap_leaf = Some((script, control_block));
+        self
+    }
+
+    // annex must start with TAPROOT_ANNEX_TAG.
+    pub fn with_annex(mut self, annex: Vec<u8>) -> Self {
+        self.annex = Some(annex);
+        self
+    }
+
+    pub fn with_sighash_type(mut self, sighash_type: SigHashType) -> Self {
+        self.sighash_type = Some(sighash_type);
+        self
+    }
+}
+
+impl Transaction {
+    // Signs input_index, replacing its scriptSig and witness. prevouts are the
+    // outputs spent by every input, in order, as taproot signatures commit to all of them.
+    pub fn sign_input(
+        &mut self,
+        input_index: usize,
+        prevouts: &[TxOut],
+        data: &SigningData,
+    ) -> Result<(), Errors> {
+        if input_index >= self.inputs.len() {
+            return Err(Errors::InputIndexOutOfRange(input_index));
+        }
+        if prevouts.len() != self.inputs.len() {
+            return Err(Errors::PrevoutsMismatch);
+        }
+        let (script_sig, witness) = {
+            let cache = SighashCache::new(self);
+            let signer = InputSigner {
+                cache: &cache,
+                input_index,
+                prevouts,
+                data,
+            };
+            signer.sign()?
+        };
+        let input = &mut self.inputs[input_index];
+        input.script_sig = script_sig.into();
+        input.witness = witness;
+        Ok(())
+    }
+}
+
+type SignFn<'f> = &'f dyn Fn(&PrivateKey) -> Result<Vec<u8>, Errors>;
+
+struct InputSigner<'a> {
+    cache: &'a SighashCache<'a>,
+    input_index: usize,
+    prevouts: &'a [TxOut],
+    data: &'a SigningData,
+}
+
+impl InputSigner<'_> {
+    fn sign(&self) -> Result<(Vec<u8>, Witness), Errors> {
+        let prevout = &self.prevouts[self.input_index];
+        let script_pubkey = &prevout.script_pubkey;
+
+        if let Some((version, program)) = witness_program(script_pubkey) {
+            let witness = match (version, program.len()) {
+                (1, 32) => self.sign_taproot(program.try_into().unwrap())?,
+                _ => self.sign_segwit_v0(version, program, prevout.value)?,
+            };
+            return Ok((Vec::new(), witness));
+        }
+
+        if is_p2sh(script_pubkey) {
+            let redeem_script = self
+                .data
+                .redeem_script
+                .as_ref()
+                .ok_or(Errors::MissingScript("redeem script"))?;
+            if hash160(redeem_script)[..] != script_pubkey[2..22] {
+                return Err(Errors::ScriptMismatch("redeem script"));
+            }
+            let redeem_push = encode_push(redeem_script);
+            if let Some((version, program)) = witness_program(redeem_script) {
+                let witness = self.sign_segwit_v0(version, program, prevout.value)?;
+                return Ok((redeem_push, witness));
+            }
+            let mut script_sig =
+                push_all(self.satisfy(redeem_script, &|key| self.legacy(key, redeem_script))?);
+            script_sig.extend(redeem_push);
+            return Ok((script_sig, Witness::new()));
+        }
+
+        let items = self.satisfy(script_pubkey, &|key| self.legacy(key, script_pubkey))?;
+        Ok((push_all(items), Witness::new()))
+    }
+
+    fn sign_segwit_v0(&self, version: u8, program: &[u8], value: u64) -> Result<Witness, Errors> {
+        match (version, program.len()) {
+            (0, 20) => {
+                let pubkey_hash: [u8; 20] = program.try_into().unwrap();
+                // Only compressed keys are standard in segwit.
+                let key = self
+                    .data
+                    .keys
+                    .iter()
+                    .find(|key| key.public_key().hash160(true) == pubkey_hash)
+                    .ok_or_else(|| Errors::MissingKey(hex::encode(pubkey_hash)))?;
+                let script_code = p2wpkh_script_code(&pubkey_hash);
+                let signature = self.segwit(key, &script_code, value)?;
+                Ok(Witness::p2wpkh(&signature, &key.public_key().sec(true)))
+            }
+            (0, 32) => {
+                let witness_script = self
+                    .data
+                    .witness_script
+                    .as_ref()
+                    .ok_or(Errors::MissingScript("witness script"))?;
+                if sha256(witness_script)[..] != *program {
+                    return Err(Errors::ScriptMismatch("witness script"));
+                }
+                let mut items = self.satisfy(witness_script, &|key| {
+                    self.segwit(key, witness_script, value)
+                })?;
+                items.push(witness_script.clone());
+                Ok(Witness::from_elements(items))
+            }
+            _ => Err(Errors::UnsupportedScript(hex::encode(
+                &self.prevouts[self.input_index].script_pubkey,
+            ))),
+        }
+    }
+
+    fn sign_taproot(&self, output_key: [u8; 32]) -> Result<Witness, Errors> {
+        let witness = self.sign_taproot_paths(output_key)?;
+        match &self.data.annex {
+            Some(annex) => witness.with_annex(annex.clone()),
+            None => Ok(witness),
+        }
+    }
+
+    fn sign_taproot_paths(&self, output_key: [u8; 32]) -> Result<Witness, Errors> {
+        let sighash_type = self.data.sighash_type.unwrap_or(SigHashType::Default);
+        let annex = self.data.annex.as_deref();
+
+        let (script, control_block_bytes) = match &self.data.tap_leaf {
+            Some(leaf) => leaf,
+            None => {
+                let merkle_root = self.data.tap_merkle_root.as_ref();
+                let key = self
+                    .data
+                    .keys
+                    .iter()
+                    .filter_map(|key| key.tap_tweak(merkle_root).ok())
+                    .find(|tweaked| tweaked.public_key().xonly() == output_key)
+                    .ok_or_else(|| Errors::MissingKey(hex::encode(output_key)))?;
+                let signature = self.cache.sign_taproot_key_spend(
+                    self.input_index,
+                    &key,
+                    self.prevouts,
+                    annex,
+                    sighash_type,
+                )?;
+                return Ok(Witness::p2tr_key_spend(&signature));
+            }
+        };
+
+        let control_block = ControlBlock::parse(control_block_bytes)?;
+        if !control_block.verify(&output_key, script) {
+            return Err(Errors::ScriptMismatch("tapscript leaf"));
+        }
+        let leaf_hash = tap_leaf_hash(control_block.leaf_version, script);
+        let ops =
+            parse_ops(script).ok_or_else(|| Errors::UnsupportedScript(hex::encode(script)))?;
+
+        // One signature (or an empty one for keys we don't hold) per key checked by
+        // OP_CHECKSIG, OP_CHECKSIGVERIFY or OP_CHECKSIGADD, in the order they are popped.
+        let mut signatures = Vec::new();
+        for pair in ops.windows(2) {
+            let ((_, pubkey), (opcode, _)) = (&pair[0], &pair[1]);
+            if pubkey.len() != 32 || !matches!(opcode, 0xac | 0xad | 0xba) {
+                continue;
+            }
+            let signature = match self
+                .data
+                .keys
+                .iter()
+                .find(|key| key.public_key().xonly()[..] == **pubkey)
+            {
+                Some(key) => self.cache.sign_taproot_script_spend(
+                    self.input_index,
+                    key,
+                    self.prevouts,
+                    leaf_hash,
+                    annex,
+                    sighash_type,
+                )?,
+                None => Vec::new(),
+            };
+            signatures.push(signature);
+        }
+        if signatures.iter().all(|signature| signature.is_empty()) {
+            return Err(Errors::MissingKey(hex::encode(script)));
+        }
+        signatures.reverse();
+        Ok(Witness::p2tr_script_spend(
+            signatures,
+            script,
+            control_block_bytes,
+        ))
+    }
+
+    // Stack items satisfying a P2PK, P2PKH or bare multisig script.
+    fn satisfy(&self, script: &[u8], sign: SignFn) -> Result<Vec<Vec<u8>>, Errors> {
+        let unsupported = || Errors::UnsupportedScript(hex::encode(script));
+        let missing_key = || Errors::MissingKey(hex::encode(script));
+        let ops = parse_ops(script).ok_or_else(unsupported)?;
+
+        match ops.as_slice() {
+            [(33 | 65, pubkey), (0xac, _)] => {
+                let key = self.key_for_pubkey(pubkey).ok_or_else(missing_key)?;
+                Ok(vec![sign(key)?])
+            }
+            [(0x76, _), (0xa9, _), (0x14, pubkey_hash), (0x88, _), (0xac, _)] => {
+                let (key, compressed) = self
+                    .data
+                    .keys
+                    .iter()
+                    .flat_map(|key| [(key, true), (key, false)])
+                    .find(|(key, compressed)| {
+                        key.public_key().hash160(*compressed)[..] == **pubkey_hash
+                    })
+                    .ok_or_else(missing_key)?;
+                Ok(vec![sign(key)?, key.public_key().sec(compressed)])
+            }
+            _ => {
+                let (required, pubkeys) = parse_multisig(script).ok_or_else(unsupported)?;
+                // The extra element consumed by the CHECKMULTISIG off-by-one bug.
+                let mut items = vec![Vec::new()];
+                for pubkey in pubkeys {
+                    if items.len() > required as usize {
+                        break;
+                    }
+                    if let Some(key) = self.key_for_pubkey(pubkey) {
+                        items.push(sign(key)?);
+                    }
+                }
+                if items.len() <= required as usize {
+                    return Err(missing_key());
+                }
+                Ok(items)
+            }
+        }
+    }
+
+    fn key_for_pubkey(&self, pubkey: &[u8]) -> Option<&PrivateKey> {
+        self.data.keys.iter().find(|key| {
+            key.public_key().sec(true) == pubkey || key.public_key().sec(false) == pubkey
+        })
+    }
+
+    fn legacy(&self, key: &PrivateKey, script_code: &[u8]) -> Result<Vec<u8>, Errors> {
+        let sighash_type = self.data.sighash_type.unwrap_or(SigHashType::All);
+        self.cache
+            .sign_legacy_input(self.input_index, key, script_code, sighash_type)
+    }
+
+    fn segwit(&self, key: &PrivateKey, script_code: &[u8], value: u64) -> Result<Vec<u8>, Errors> {
+        let sighash_type = self.data.sighash_type.unwrap_or(SigHashType::All);
+        self.cache
+            .sign_segwit_v0_input(self.input_index, key, script_code, value, sighash_type)
+    }
+}
+
+fn push_all(items: Vec<Vec<u8>>) -> Vec<u8> {
+    items.iter().flat_map(|item| encode_push(item)).collect()
+}
+
+#[cfg(test)]
+mod input_signer_tests {
+    use super::*;
+    use crate::ecc::{from_bytes, SchnorrSignature, Signature};
+    use crate::script::Script;
+    use crate::taproot::TAPROOT_LEAF_TAPSCRIPT;
+    use num_bigint::BigInt;
+
+    // Native P2WPKH example from BIP143: input 0 spends a P2PK output, input 1 a P2WPKH one.
+    const BIP143_UNSIGNED_TX: &str = "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+
+    fn key_from_hex(hex_str: &str) -> PrivateKey {
+        PrivateKey::from_bytes(&hex::decode(hex_str).unwrap().try_into().unwrap()).unwrap()
+    }
+
+    fn key(secret: u32) -> PrivateKey {
+        PrivateKey::new(BigInt::from(secret)).unwrap()
+    }
+
+    fn bip143_prevouts() -> Vec<TxOut> {
+        vec![
+            TxOut::new(
+                625_000_000,
+                hex::decode(
+                    "2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac",
+                )
+                .unwrap()
+                .into(),
+            ),
+            TxOut::new(
+                600_000_000,
+                hex::decode("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1")
+                    .unwrap()
+                    .into(),
+            ),
+        ]
+    }
+
+    fn multisig(required: u8, keys: &[&PrivateKey]) -> Vec<u8> {
+        let mut script = vec![0x50 + required];
+        for key in keys {
+            script.extend(encode_push(&key.public_key().sec(true)));
+        }
+        script.extend([0x50 + keys.len() as u8, 0xae]);
+        script
+    }
+
+    fn p2sh(script: &[u8]) -> Vec<u8> {
+        let mut script_pubkey = vec![0xa9, 0x14];
+        script_pubkey.extend(hash160(script));
+        script_pubkey.push(0x87);
+        script_pubkey
+    }
+
+    fn p2wsh(script: &[u8]) -> Vec<u8> {
+        let mut script_pubkey = vec![0x00, 0x20];
+        script_pubkey.extend(sha256(script));
+        script_pubkey
+    }
+
+    fn p2tr(output_key: &[u8; 32]) -> Vec<u8> {
+        let mut script_pubkey = vec![0x51, 0x20];
+        script_pubkey.extend_from_slice(output_key);
+        script_pubkey
+    }
+
+    // Single input, single output transaction spending the given output.
+    fn spending(prevout: TxOut) -> (Transaction, Vec<TxOut>) {
+        let tx = Transaction::from_hex(BIP143_UNSIGNED_TX).unwrap();
+        let tx = Transaction::new(
+            2,
+            vec![tx.inputs[0].clone()],
+            vec![tx.outputs[0].clone()],
+            0,
+        );
+        (tx, vec![prevout])
+    }
+
+    fn verify_segwit(
+        tx: &Transaction,
+        prevout: &TxOut,
+        script_code: &[u8],
+        key: &PrivateKey,
+        signature: &[u8],
+    ) -> bool {
+        let (der, flag) = signature.split_at(signature.len() - 1);
+        let digest = SighashCache::new(tx)
+            .segwit_v0_signature_hash(0, script_code, prevout.value, flag[0] as u32)
+            .unwrap();
+        key.public_key()
+            .verify(&from_bytes(&digest), &Signature::parse_der(der).unwrap())
+    }
+
+    #[test]
+    fn test_bip143_p2pk_and_p2wpkh() {
+        let mut tx = Transaction::from_hex(BIP143_UNSIGNED_TX).unwrap();
+        let prevouts = bip143_prevouts();
+        let p2pk_key =
+            key_from_hex("bbc27228ddcb9209d7fd6f36b02f7dfa6252af40bb2f1cbc7a557da8027ff866");
+        let p2wpkh_key =
+            key_from_hex("619c335025c7f4012e556c2a58b2506e30b8511b53ade95ea316fd8c3286feb9");
+
+        tx.sign_input(0, &prevouts, &SigningData::new(vec![p2pk_key]))
+            .unwrap();
+        tx.sign_input(1, &prevouts, &SigningData::new(vec![p2wpkh_key]))
+            .unwrap();
+
+        assert!(tx.inputs[1].script_sig.is_empty());
+        assert_eq!(
+            hex::encode(tx.serialize()),
+            "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000"
+        );
+    }
+
+    #[test]
+    fn test_p2pkh_compressed_and_uncompressed() {
+        let key = key(8675309);
+        for compressed in [true, false] {
+            let script_pubkey = Script::new_p2pkh(&key.public_key().hash160(compressed));
+            let (mut tx, prevouts) = spending(TxOut::new(100_000, script_pubkey.clone()));
+
+            tx.sign_input(0, &prevouts, &SigningData::new(vec![key.clone()]))
+                .unwrap();
+
+            let ops = parse_ops(&tx.inputs[0].script_sig).unwrap();
+            assert_eq!(ops.len(), 2);
+            assert_eq!(ops[1].1, key.public_key().sec(compressed));
+            let (der, flag) = ops[0].1.split_at(ops[0].1.len() - 1);
+            let digest = SighashCache::new(&tx)
+                .legacy_signature_hash(0, &script_pubkey, flag[0] as u32)
+                .unwrap();
+            assert!(key
+                .public_key()
+                .verify(&from_bytes(&digest), &Signature::parse_der(der).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_p2sh_p2wpkh() {
+        let key = key(8675309);
+        let mut redeem_script = vec![0x00, 0x14];
+        redeem_script.extend(key.public_key().hash160(true));
+        let prevout = TxOut::new(100_000, p2sh(&redeem_script).into());
+        let (mut tx, prevouts) = spending(prevout.clone());
+
+        let data = SigningData::new(vec![key.clone()]).with_redeem_script(redeem_script.clone());
+        tx.sign_input(0, &prevouts, &data).unwrap();
+
+        assert_eq!(tx.inputs[0].script_sig, encode_push(&redeem_script));
+        assert_eq!(tx.inputs[0].witness.len(), 2);
+        let script_code = p2wpkh_script_code(&key.public_key().hash160(true));
+        assert!(verify_segwit(
+            &tx,
+            &prevout,
+            &script_code,
+            &key,
+            &tx.inputs[0].witness[0]
+        ));
+    }
+
+    #[test]
+    fn test_p2wsh_multisig_uses_key_order() {
+        let keys = [key(1), key(2), key(3)];
+        let witness_script = multisig(2, &[&keys[0], &keys[1], &keys[2]]);
+        let prevout = TxOut::new(100_000, p2wsh(&witness_script).into());
+        let (mut tx, prevouts) = spending(prevout.clone());
+
+        // Keys given out of order, and more than required.
+        let data = SigningData::new(vec![keys[2].clone(), keys[0].clone(), keys[1].clone()])
+            .with_witness_script(witness_script.clone());
+        tx.sign_input(0, &prevouts, &data).unwrap();
+
+        let witness = &tx.inputs[0].witness;
+        assert_eq!(witness.len(), 4);
+        assert!(witness[0].is_empty());
+        assert!(verify_segwit(
+            &tx,
+            &prevout,
+            &witness_script,
+            &keys[0],
+            &witness[1]
+        ));
+        assert!(verify_segwit(
+            &tx,
+            &prevout,
+            &witness_script,
+            &keys[1],
+            &witness[2]
+        ));
+        assert_eq!(witness[3], witness_script[..]);
+    }
+
+    #[test]
+    fn test_p2sh_multisig_and_p2sh_p2wsh() {
+        let keys = [key(1), key(2)];
+        let script = multisig(1, &[&keys[0], &keys[1]]);
+
+        let (mut tx, prevouts) = spending(TxOut::new(100_000, p2sh(&script).into()));
+        let data = SigningData::new(vec![keys[1].clone()]).with_redeem_script(script.clone());
+        tx.sign_input(0, &prevouts, &data).unwrap();
+        let ops = parse_ops(&tx.inputs[0].script_sig).unwrap();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0], (0x00, &[][..]));
+        assert_eq!(ops[2].1, script);
+        assert!(tx.inputs[0].witness.is_empty());
+
+        let redeem_script = p2wsh(&script);
+        let (mut tx, prevouts) = spending(TxOut::new(100_000, p2sh(&redeem_script).into()));
+        let data = SigningData::new(vec![keys[0].clone()])
+            .with_redeem_script(redeem_script.clone())
+            .with_witness_script(script.clone());
+        tx.sign_input(0, &prevouts, &data).unwrap();
+        assert_eq!(tx.inputs[0].script_sig, encode_push(&redeem_script));
+        assert_eq!(tx.inputs[0].witness.len(), 3);
+        assert_eq!(tx.inputs[0].witness[2], script[..]);
+    }
+
+    #[test]
+    fn test_taproot_key_path() {
+        let key = key(8675309);
+        let merkle_root = [9u8; 32];
+        for root in [None, Some(merkle_root)] {
+            let output_key = key.public_key().tap_tweak(root.as_ref()).unwrap().xonly();
+            let (mut tx, prevouts) = spending(TxOut::new(100_000, p2tr(&output_key).into()));
+            let mut data = SigningData::new(vec![key.clone()]);
+            data.tap_merkle_root = root;
+            tx.sign_input(0, &prevouts, &data).unwrap();
+
+            let witness = &tx.inputs[0].witness;
+            assert_eq!(witness.len(), 1);
+            let digest = SighashCache::new(&tx)
+                .taproot_key_spend_signature_hash(0, &prevouts, None, 0x00)
+                .unwrap();
+            let signature = SchnorrSignature::parse(&witness[0]).unwrap();
+            assert!(signature.verify(&output_key, &digest));
+        }
+    }
+
+    #[test]
+    fn test_taproot_script_path() {
+        let internal_key = key(1);
+        let leaf_key = key(8675309);
+        let mut script = encode_push(&leaf_key.public_key().xonly());
+        script.push(0xac);
+        let leaf_hash = tap_leaf_hash(TAPROOT_LEAF_TAPSCRIPT, &script);
+        let output = internal_key
+            .public_key()
+            .tap_tweak(Some(&leaf_hash))
+            .unwrap();
+        let control_block = ControlBlock {
+            leaf_version: TAPROOT_LEAF_TAPSCRIPT,
+            output_key_parity: !output.has_even_y(),
+            internal_key: internal_key.public_key().xonly(),
+            merkle_branch: Vec::new(),
+        }
+        .serialize();
+        let (mut tx, prevouts) = spending(TxOut::new(100_000, p2tr(&output.xonly()).into()));
+
+        let data = SigningData::new(vec![leaf_key.clone()])
+            .with_tap_leaf(script.clone(), control_block.clone())
+            .with_sighash_type(SigHashType::All);
+        tx.sign_input(0, &prevouts, &data).unwrap();
+
+        let witness = &tx.inputs[0].witness;
+        assert_eq!(witness.len(), 3);
+        assert_eq!(witness[1], script[..]);
+        assert_eq!(witness[2], control_block[..]);
+        assert_eq!(witness[0].len(), 65);
+        let digest = SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(0, &prevouts, leaf_hash, 0xffffffff, None, 0x01)
+            .unwrap();
+        let signature = SchnorrSignature::parse(&witness[0][..64]).unwrap();
+        assert!(signature.verify(&leaf_key.public_key().xonly(), &digest));
+
+        // A leaf the output key does not commit to.
+        let mut other_script = script.clone();
+        other_script.insert(0, 0x61);
+        let data = SigningData::new(vec![leaf_key]).with_tap_leaf(other_script, control_block);
+        assert_eq!(
+            tx.sign_input(0, &prevouts, &data),
+            Err(Errors::ScriptMismatch("tapscript leaf"))
+        );
+    }
+
+    #[test]
+    fn test_sign_input_errors() {
+        let script = multisig(1, &[&key(1)]);
+        let (mut tx, prevouts) = spending(TxOut::new(100_000, p2sh(&script).into()));
+
+        assert_eq!(
+            tx.sign_input(0, &prevouts, &SigningData::new(vec![key(1)])),
+            Err(Errors::MissingScript("redeem script"))
+        );
+        let data = SigningData::new(vec![key(1)]).with_redeem_script(script[1..].to_vec());
+        assert_eq!(
+            tx.sign_input(0, &prevouts, &data),
+            Err(Errors::ScriptMismatch("redeem script"))
+        );
+        let data = SigningData::new(vec![key(2)]).with_redeem_script(script.clone());
+        assert_eq!(
+            tx.sign_input(0, &prevouts, &data),
+            Err(Errors::MissingKey(hex::encode(&script)))
+        );
+        assert_eq!(
+            tx.sign_input(1, &prevouts, &data),
+            Err(Errors::InputIndexOutOfRange(1))
+        );
+        assert_eq!(tx.sign_input(0, &[], &data), Err(Errors::PrevoutsMismatch));
+
+        let (mut tx, prevouts) = spending(TxOut::new(0, vec![0x6a, 0x01, 0x00].into()));
+        assert_eq!(
+            tx.sign_input(0, &prevouts, &SigningData::new(vec![key(1)])),
+            Err(Errors::UnsupportedScript("6a0100".to_string()))
+        );
+    }
+}
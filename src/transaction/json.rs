@@ -0,0 +1,171 @@
+// Readable JSON view of a transaction in the shape of Bitcoin Core's decoderawtransaction.
+use super::{txid_to_hex, Transaction, TxIn, TxOut};
+use crate::address::address_from_script;
+use crate::network::Network;
+use crate::script::{classify, to_asm};
+use crate::validation::COIN;
+use serde_json::{json, Map, Value};
+
+impl Transaction {
+    // Addresses are encoded for the given network. Output descriptors ("desc") are
+    // not included.
+    pub fn to_json(&self, network: Network) -> Value {
+        let vin: Vec<Value> = self
+            .inputs
+            .iter()
+            .map(|input| input_json(input, self.is_coinbase()))
+            .collect();
+        let vout: Vec<Value> = self
+            .outputs
+            .iter()
+            .enumerate()
+            .map(|(n, output)| output_json(output, n, network))
+            .collect();
+        json!({
+            "txid": self.txid_hex(),
+            "hash": txid_to_hex(&self.wtxid()),
+            "version": self.version,
+            "size": self.total_size(),
+            "vsize": self.vsize(),
+            "weight": self.weight(),
+            "locktime": self.locktime,
+            "vin": vin,
+            "vout": vout,
+        })
+    }
+}
+
+fn input_json(input: &TxIn, coinbase: bool) -> Value {
+    let mut result = Map::new();
+    if coinbase {
+        result.insert("coinbase".into(), json!(hex::encode(&input.script_sig)));
+    } else {
+        result.insert(
+            "txid".into(),
+            json!(txid_to_hex(&input.previous_output.txid)),
+        );
+        result.insert("vout".into(), json!(input.previous_output.vout));
+        result.insert(
+            "scriptSig".into(),
+            json!({
+                "asm": to_asm(&input.script_sig, true),
+                "hex": hex::encode(&input.script_sig),
+            }),
+        );
+    }
+    if !input.witness.is_empty() {
+        let items: Vec<String> = input.witness.iter().map(hex::encode).collect();
+        result.insert("txinwitness".into(), json!(items));
+    }
+    result.insert("sequence".into(), json!(input.sequence));
+    Value::Object(result)
+}
+
+fn output_json(output: &TxOut, n: usize, network: Network) -> Value {
+    let script = &output.script_pubkey;
+    let mut script_pubkey = Map::new();
+    script_pubkey.insert("asm".into(), json!(to_asm(script, false)));
+    script_pubkey.insert("hex".into(), json!(hex::encode(script)));
+    if let Some(address) = address_from_script(script, network) {
+        script_pubkey.insert("address".into(), json!(address));
+    }
+    script_pubkey.insert("type".into(), json!(classify(script).name()));
+    json!({
+        "value": output.value as f64 / COIN as f64,
+        "n": n,
+        "scriptPubKey": script_pubkey,
+    })
+}
+
+#[cfg(test)]
+mod json_tests {
+    use super::*;
+
+    // Transaction from chapter 5 of Programming Bitcoin.
+    const RAW_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+    // Signed native P2WPKH example from BIP143.
+    const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    #[test]
+    fn test_legacy_transaction_json() {
+        let tx = Transaction::from_hex(RAW_TX).unwrap();
+        let json = tx.to_json(Network::Mainnet);
+
+        assert_eq!(
+            json["txid"],
+            "452c629d67e41baec3ac6f04fe744b4b9617f8f859c63b3002f8684e7a4fee03"
+        );
+        assert_eq!(json["hash"], json["txid"]);
+        assert_eq!(json["size"], 226);
+        assert_eq!(json["vsize"], 226);
+        assert_eq!(json["weight"], 904);
+        assert_eq!(json["locktime"], 410393);
+
+        let input = &json["vin"][0];
+        assert_eq!(
+            input["txid"],
+            "d1c789a9c60383bf715f3f6ad9d14b91fe55f3deb369fe5d9280cb1a01793f81"
+        );
+        assert_eq!(input["vout"], 0);
+        assert!(input["scriptSig"]["asm"]
+            .as_str()
+            .unwrap()
+            .starts_with("3045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed[ALL] 0349fc4e"));
+        assert_eq!(input["sequence"], 0xfffffffeu32);
+        assert!(input.get("txinwitness").is_none());
+
+        let output = &json["vout"][0];
+        assert_eq!(output["value"], 0.32454049);
+        assert_eq!(output["n"], 0);
+        assert_eq!(
+            output["scriptPubKey"]["address"],
+            "1JAHBxA51vwp5C2zpSB15VbxSZK3hVJs2H"
+        );
+        assert_eq!(output["scriptPubKey"]["type"], "pubkeyhash");
+        assert_eq!(
+            json["vout"][1]["scriptPubKey"]["address"],
+            "13achaY7hdFTEHCzWC1Cvuo1FDKzDtAvRt"
+        );
+
+        let keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            ["txid", "hash", "version", "size", "vsize", "weight", "locktime", "vin", "vout"]
+        );
+    }
+
+    #[test]
+    fn test_segwit_transaction_json() {
+        let tx = Transaction::from_hex(SEGWIT_TX).unwrap();
+        let json = tx.to_json(Network::Mainnet);
+
+        assert_ne!(json["hash"], json["txid"]);
+        assert_eq!(
+            json["vin"][0]["scriptSig"]["asm"].as_str().unwrap().len(),
+            142 + 5
+        );
+        assert!(json["vin"][0].get("txinwitness").is_none());
+        let witness = json["vin"][1]["txinwitness"].as_array().unwrap();
+        assert_eq!(witness.len(), 2);
+        assert_eq!(
+            witness[1],
+            "025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357"
+        );
+        assert_eq!(json["vin"][1]["scriptSig"]["hex"], "");
+    }
+
+    #[test]
+    fn test_script_types() {
+        assert_eq!(classify(&[0x6a, 0x01, 0x00]).name(), "nulldata");
+        assert_eq!(
+            classify(&[0x51, 0x02, 0x4e, 0x73]).name(),
+            "witness_unknown"
+        );
+        let mut multisig = vec![0x51, 0x21];
+        multisig.extend([0x02; 33]);
+        multisig.extend([0x51, 0xae]);
+        assert_eq!(classify(&multisig).name(), "multisig");
+        assert_eq!(classify(&[0x76]).name(), "nonstandard");
+    }
+}
@@ -0,0 +1,312 @@
+// Absolute (nLockTime, BIP65) and relative (nSequence, BIP68/BIP112) timelocks.
+use super::Transaction;
+
+// nLockTime values below this are block heights, the rest unix timestamps.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockTime {
+    Blocks(u32),
+    Seconds(u32),
+}
+
+impl LockTime {
+    pub fn from_consensus(value: u32) -> Self {
+        if value < LOCKTIME_THRESHOLD {
+            LockTime::Blocks(value)
+        } else {
+            LockTime::Seconds(value)
+        }
+    }
+
+    pub fn to_consensus_u32(self) -> u32 {
+        match self {
+            LockTime::Blocks(value) | LockTime::Seconds(value) => value,
+        }
+    }
+
+    pub fn is_same_unit(&self, other: &LockTime) -> bool {
+        matches!(
+            (self, other),
+            (LockTime::Blocks(_), LockTime::Blocks(_))
+                | (LockTime::Seconds(_), LockTime::Seconds(_))
+        )
+    }
+
+    // A locktime is satisfied once the chain is strictly past it. block_time is the
+    // median time past of the previous block when BIP113 is active.
+    pub fn is_satisfied_by(&self, block_height: u32, block_time: u32) -> bool {
+        match *self {
+            LockTime::Blocks(height) => height < block_height,
+            LockTime::Seconds(time) => time < block_time,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sequence(pub u32);
+
+impl Sequence {
+    pub const FINAL: Sequence = Sequence(0xffffffff);
+    // Highest value that still lets nLockTime apply.
+    pub const ENABLE_LOCKTIME_NO_RBF: Sequence = Sequence(0xfffffffe);
+    // Highest value signaling BIP125 replaceability.
+    pub const ENABLE_RBF_NO_LOCKTIME: Sequence = Sequence(0xfffffffd);
+
+    pub const LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+    pub const LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+    pub const LOCKTIME_MASK: u32 = 0x0000ffff;
+    // Time based relative locks are expressed in units of 512 seconds.
+    pub const LOCKTIME_GRANULARITY: u32 = 9;
+
+    pub fn is_final(&self) -> bool {
+        *self == Sequence::FINAL
+    }
+
+    pub fn signals_rbf(&self) -> bool {
+        self.0 < Sequence::ENABLE_LOCKTIME_NO_RBF.0
+    }
+
+    pub fn is_relative_lock_time_disabled(&self) -> bool {
+        self.0 & Sequence::LOCKTIME_DISABLE_FLAG != 0
+    }
+
+    pub fn from_height(blocks: u16) -> Self {
+        Sequence(blocks as u32)
+    }
+
+    // Rounds up to the next multiple of 512 seconds.
+    pub fn from_seconds_ceil(seconds: u32) -> Self {
+        let intervals = seconds.div_ceil(1 << Sequence::LOCKTIME_GRANULARITY);
+        Sequence(Sequence::LOCKTIME_TYPE_FLAG | intervals.min(Sequence::LOCKTIME_MASK))
+    }
+
+    pub fn relative_lock_time(&self) -> Option<RelativeLockTime> {
+        if self.is_relative_lock_time_disabled() {
+            return None;
+        }
+        let value = (self.0 & Sequence::LOCKTIME_MASK) as u16;
+        if self.0 & Sequence::LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLockTime::Time(value))
+        } else {
+            Some(RelativeLockTime::Blocks(value))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelativeLockTime {
+    Blocks(u16),
+    // Number of 512 second intervals.
+    Time(u16),
+}
+
+impl RelativeLockTime {
+    pub fn is_same_unit(&self, other: &RelativeLockTime) -> bool {
+        matches!(
+            (self, other),
+            (RelativeLockTime::Blocks(_), RelativeLockTime::Blocks(_))
+                | (RelativeLockTime::Time(_), RelativeLockTime::Time(_))
+        )
+    }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            RelativeLockTime::Blocks(value) | RelativeLockTime::Time(value) => value,
+        }
+    }
+}
+
+// Earliest height and median time past (exclusive) after which a transaction's
+// BIP68 constraints are met. -1 means unconstrained, as in Bitcoin Core.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SequenceLocks {
+    pub min_height: i64,
+    pub min_time: i64,
+}
+
+impl SequenceLocks {
+    // block_mtp is the median time past of the block preceding the one at block_height.
+    pub fn is_satisfied_by(&self, block_height: u32, block_mtp: u32) -> bool {
+        self.min_height < block_height as i64 && self.min_time < block_mtp as i64
+    }
+}
+
+impl Transaction {
+    pub fn lock_time(&self) -> LockTime {
+        LockTime::from_consensus(self.locktime)
+    }
+
+    // IsFinalTx: a transaction can be included in a block at the given height and time
+    // if its locktime has passed or every input opts out with a final sequence.
+    pub fn is_final(&self, block_height: u32, block_time: u32) -> bool {
+        self.locktime == 0
+            || self.lock_time().is_satisfied_by(block_height, block_time)
+            || self
+                .inputs
+                .iter()
+                .all(|input| Sequence(input.sequence).is_final())
+    }
+
+    // BIP68 locks. coin_heights holds the height of the block that created each spent
+    // output, and median_time_past returns the MTP of the block at a given height.
+    pub fn sequence_locks(
+        &self,
+        coin_heights: &[u32],
+        median_time_past: impl Fn(u32) -> u32,
+    ) -> SequenceLocks {
+        let mut locks = SequenceLocks {
+            min_height: -1,
+            min_time: -1,
+        };
+        // The version is compared unsigned, so negative ones enforce BIP68 too.
+        if (self.version as u32) < 2 {
+            return locks;
+        }
+        for (input, coin_height) in self.inputs.iter().zip(coin_heights) {
+            match Sequence(input.sequence).relative_lock_time() {
+                None => {}
+                Some(RelativeLockTime::Blocks(blocks)) => {
+                    locks.min_height = locks
+                        .min_height
+                        .max(*coin_height as i64 + blocks as i64 - 1);
+                }
+                Some(RelativeLockTime::Time(intervals)) => {
+                    // Time is measured from the MTP of the block before the coin's.
+                    let coin_time = median_time_past(coin_height.saturating_sub(1)) as i64;
+                    let lock = (intervals as i64) << Sequence::LOCKTIME_GRANULARITY;
+                    locks.min_time = locks.min_time.max(coin_time + lock - 1);
+                }
+            }
+        }
+        locks
+    }
+
+    // OP_CHECKLOCKTIMEVERIFY semantics: the script's locktime must be of the same kind
+    // as the transaction's, not in its future, and enforced (input not final).
+    pub fn check_lock_time_verify(&self, input_index: usize, lock: LockTime) -> bool {
+        let tx_lock = self.lock_time();
+        if !lock.is_same_unit(&tx_lock) || lock.to_consensus_u32() > tx_lock.to_consensus_u32() {
+            return false;
+        }
+        self.inputs
+            .get(input_index)
+            .is_some_and(|input| !Sequence(input.sequence).is_final())
+    }
+
+    // OP_CHECKSEQUENCEVERIFY semantics for the sequence pushed by the script.
+    pub fn check_sequence_verify(&self, input_index: usize, script_sequence: Sequence) -> bool {
+        let required = match script_sequence.relative_lock_time() {
+            // The disable flag turns CSV into a NOP.
+            None => return true,
+            Some(lock) => lock,
+        };
+        if (self.version as u32) < 2 {
+            return false;
+        }
+        let Some(input) = self.inputs.get(input_index) else {
+            return false;
+        };
+        match Sequence(input.sequence).relative_lock_time() {
+            Some(actual) => required.is_same_unit(&actual) && required.value() <= actual.value(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod locktime_tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::transaction::{OutPoint, TxIn, TxOut};
+
+    fn tx_with(version: i32, sequences: &[u32], locktime: u32) -> Transaction {
+        let inputs = sequences
+            .iter()
+            .map(|sequence| TxIn::new(OutPoint::new([0; 32], 0), Script::new(), *sequence))
+            .collect();
+        Transaction::new(
+            version,
+            inputs,
+            vec![TxOut::new(0, Script::new())],
+            locktime,
+        )
+    }
+
+    #[test]
+    fn test_locktime_units() {
+        assert_eq!(
+            LockTime::from_consensus(499_999_999),
+            LockTime::Blocks(499_999_999)
+        );
+        assert_eq!(
+            LockTime::from_consensus(500_000_000),
+            LockTime::Seconds(500_000_000)
+        );
+        assert!(!LockTime::Blocks(1).is_same_unit(&LockTime::Seconds(LOCKTIME_THRESHOLD)));
+    }
+
+    #[test]
+    fn test_is_final() {
+        assert!(tx_with(1, &[0], 0).is_final(1, 0));
+        assert!(!tx_with(1, &[0], 100).is_final(100, 0));
+        assert!(tx_with(1, &[0], 100).is_final(101, 0));
+        assert!(tx_with(1, &[0xffffffff], 100).is_final(1, 0));
+        assert!(!tx_with(1, &[0], 1_600_000_000).is_final(1_000_000, 1_600_000_000));
+        assert!(tx_with(1, &[0], 1_600_000_000).is_final(1, 1_600_000_001));
+    }
+
+    #[test]
+    fn test_sequence_flags() {
+        assert!(Sequence::ENABLE_RBF_NO_LOCKTIME.signals_rbf());
+        assert!(!Sequence::ENABLE_LOCKTIME_NO_RBF.signals_rbf());
+        assert!(Sequence::FINAL.is_relative_lock_time_disabled());
+        assert_eq!(
+            Sequence::from_height(10).relative_lock_time(),
+            Some(RelativeLockTime::Blocks(10))
+        );
+        assert_eq!(
+            Sequence::from_seconds_ceil(513).relative_lock_time(),
+            Some(RelativeLockTime::Time(2))
+        );
+    }
+
+    #[test]
+    fn test_sequence_locks() {
+        let tx = tx_with(2, &[10, Sequence::LOCKTIME_TYPE_FLAG | 2, 0xffffffff], 0);
+        let locks = tx.sequence_locks(&[100, 200, 300], |height| height * 600);
+
+        assert_eq!(locks.min_height, 109);
+        assert_eq!(locks.min_time, 199 * 600 + 1024 - 1);
+        assert!(!locks.is_satisfied_by(109, 200_000));
+        assert!(locks.is_satisfied_by(110, 199 * 600 + 1024));
+        assert!(!locks.is_satisfied_by(110, 199 * 600 + 1023));
+
+        // Version 1 transactions do not enforce BIP68.
+        let v1 = tx_with(1, &[10], 0);
+        assert!(v1.sequence_locks(&[100], |_| 0).is_satisfied_by(0, 0));
+        // Negative versions read as large unsigned ones and do.
+        let negative = tx_with(-1, &[10], 0);
+        assert_eq!(negative.sequence_locks(&[100], |_| 0).min_height, 109);
+    }
+
+    #[test]
+    fn test_check_lock_time_verify() {
+        let tx = tx_with(1, &[0xfffffffe], 500);
+        assert!(tx.check_lock_time_verify(0, LockTime::Blocks(500)));
+        assert!(!tx.check_lock_time_verify(0, LockTime::Blocks(501)));
+        assert!(!tx.check_lock_time_verify(0, LockTime::Seconds(LOCKTIME_THRESHOLD)));
+        assert!(!tx_with(1, &[0xffffffff], 500).check_lock_time_verify(0, LockTime::Blocks(1)));
+    }
+
+    #[test]
+    fn test_check_sequence_verify() {
+        let tx = tx_with(2, &[20], 0);
+        assert!(tx.check_sequence_verify(0, Sequence::from_height(20)));
+        assert!(!tx.check_sequence_verify(0, Sequence::from_height(21)));
+        assert!(!tx.check_sequence_verify(0, Sequence(Sequence::LOCKTIME_TYPE_FLAG | 1)));
+        assert!(tx.check_sequence_verify(0, Sequence(Sequence::LOCKTIME_DISABLE_FLAG)));
+        assert!(!tx_with(1, &[20], 0).check_sequence_verify(0, Sequence::from_height(1)));
+        assert!(tx_with(-1, &[20], 0).check_sequence_verify(0, Sequence::from_height(20)));
+    }
+}
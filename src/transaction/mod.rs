@@ -0,0 +1,340 @@
+pub mod coinbase;
+pub mod cpfp;
+pub mod fee;
+pub mod fetcher;
+pub mod input_signer;
+pub mod json;
+pub mod locktime;
+pub mod op_return;
+pub mod sighash;
+pub mod signer;
+pub mod weight;
+pub mod witness;
+
+use crate::helper::{encode_varint, hash256, read_array, read_varint};
+use crate::script::Script;
+use crate::types::errors::Errors;
+pub use fee::UtxoProvider;
+pub use input_signer::SigningData;
+pub use locktime::{LockTime, Sequence};
+use std::fmt;
+use std::io::Read;
+pub use witness::Witness;
+
+// Reference to a specific output of a previous transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    // Txid in internal byte order (the reverse of how explorers display it).
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: [u8; 32], vout: u32) -> Self {
+        OutPoint { txid, vout }
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let txid = read_array::<32>(reader)?;
+        let vout = u32::from_le_bytes(read_array(reader)?);
+        Ok(OutPoint { txid, vout })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.txid.to_vec();
+        result.extend_from_slice(&self.vout.to_le_bytes());
+        result
+    }
+}
+
+// Formats as <txid>:<vout> with the txid in display byte order.
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", txid_to_hex(&self.txid), self.vout)
+    }
+}
+
+// Txids are displayed byte-reversed with respect to their internal order.
+pub fn txid_to_hex(txid: &[u8; 32]) -> String {
+    let mut reversed = *txid;
+    reversed.reverse();
+    hex::encode(reversed)
+}
+
+pub fn txid_from_hex(hex_str: &str) -> Result<[u8; 32], Errors> {
+    let mut txid: [u8; 32] = hex::decode(hex_str.trim())
+        .map_err(|_| Errors::InvalidHex)?
+        .try_into()
+        .map_err(|_| Errors::InvalidHex)?;
+    txid.reverse();
+    Ok(txid)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Script,
+    pub sequence: u32,
+    pub witness: Witness,
+}
+
+impl TxIn {
+    pub fn new(previous_output: OutPoint, script_sig: Script, sequence: u32) -> Self {
+        TxIn {
+            previous_output,
+            script_sig,
+            sequence,
+            witness: Witness::new(),
+        }
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let previous_output = OutPoint::parse(reader)?;
+        let script_sig = Script::parse(reader)?;
+        let sequence = u32::from_le_bytes(read_array(reader)?);
+        Ok(TxIn::new(previous_output, script_sig, sequence))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.previous_output.serialize();
+        result.extend(self.script_sig.serialize());
+        result.extend_from_slice(&self.sequence.to_le_bytes());
+        result
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxOut {
+    // Amount in satoshis.
+    pub value: u64,
+    pub script_pubkey: Script,
+}
+
+impl TxOut {
+    pub fn new(value: u64, script_pubkey: Script) -> Self {
+        TxOut {
+            value,
+            script_pubkey,
+        }
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let value = u64::from_le_bytes(read_array(reader)?);
+        let script_pubkey = Script::parse(reader)?;
+        Ok(TxOut::new(value, script_pubkey))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.value.to_le_bytes().to_vec();
+        result.extend(self.script_pubkey.serialize());
+        result
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub locktime: u32,
+}
+
+impl Transaction {
+    pub fn new(version: i32, inputs: Vec<TxIn>, outputs: Vec<TxOut>, locktime: u32) -> Self {
+        Transaction {
+            version,
+            inputs,
+            outputs,
+            locktime,
+        }
+    }
+
+    // Accepts both the legacy encoding and the BIP144 segwit encoding
+    // (marker 0x00 and flag 0x01 after the version, witnesses before the locktime).
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let version = i32::from_le_bytes(read_array(reader)?);
+        let mut num_inputs = read_varint(reader)?;
+        let segwit = num_inputs == 0;
+        if segwit {
+            if read_array::<1>(reader)?[0] != 0x01 {
+                return Err(Errors::InvalidSegwitFlag);
+            }
+            num_inputs = read_varint(reader)?;
+        }
+        let mut inputs = (0..num_inputs)
+            .map(|_| TxIn::parse(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        let num_outputs = read_varint(reader)?;
+        let outputs = (0..num_outputs)
+            .map(|_| TxOut::parse(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        if segwit {
+            for input in inputs.iter_mut() {
+                input.witness = Witness::parse(reader)?;
+            }
+            // A segwit encoding without any witness data is not allowed, it must use the legacy one.
+            if inputs.iter().all(|input| input.witness.is_empty()) {
+                return Err(Errors::InvalidSegwitFlag);
+            }
+        }
+        let locktime = u32::from_le_bytes(read_array(reader)?);
+        Ok(Transaction::new(version, inputs, outputs, locktime))
+    }
+
+    // Parses a transaction that must span the whole buffer.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Errors> {
+        let mut reader = bytes;
+        let tx = Transaction::parse(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(Errors::TrailingData);
+        }
+        Ok(tx)
+    }
+
+    pub fn from_hex(hex_str: &str) -> Result<Self, Errors> {
+        let bytes = hex::decode(hex_str.trim()).map_err(|_| Errors::InvalidHex)?;
+        Transaction::from_bytes(&bytes)
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    // Full serialization, using the segwit encoding when any input carries witness data.
+    pub fn serialize(&self) -> Vec<u8> {
+        if !self.has_witness() {
+            return self.serialize_legacy();
+        }
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.extend_from_slice(&[0x00, 0x01]);
+        self.serialize_inputs_and_outputs(&mut result);
+        for input in &self.inputs {
+            result.extend(input.witness.serialize());
+        }
+        result.extend_from_slice(&self.locktime.to_le_bytes());
+        result
+    }
+
+    // Serialization without witness data, as committed to by the txid.
+    pub fn serialize_legacy(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        self.serialize_inputs_and_outputs(&mut result);
+        result.extend_from_slice(&self.locktime.to_le_bytes());
+        result
+    }
+
+    fn serialize_inputs_and_outputs(&self, result: &mut Vec<u8>) {
+        result.extend(encode_varint(self.inputs.len() as u64));
+        for input in &self.inputs {
+            result.extend(input.serialize());
+        }
+        result.extend(encode_varint(self.outputs.len() as u64));
+        for output in &self.outputs {
+            result.extend(output.serialize());
+        }
+    }
+
+    // Txid in internal byte order.
+    pub fn txid(&self) -> [u8; 32] {
+        hash256(&self.serialize_legacy())
+    }
+
+    // Witness txid, equal to the txid for transactions without witness data.
+    pub fn wtxid(&self) -> [u8; 32] {
+        hash256(&self.serialize())
+    }
+
+    // Txid as shown by block explorers (byte-reversed hex).
+    pub fn txid_hex(&self) -> String {
+        txid_to_hex(&self.txid())
+    }
+}
+
+#[cfg(test)]
+mod transaction_tests {
+    use super::*;
+
+    // First transaction of chapter 5 of Programming Bitcoin.
+    const RAW_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+    #[test]
+    fn test_parse_transaction() {
+        let tx = Transaction::from_hex(RAW_TX).unwrap();
+
+        assert_eq!(tx.version, 1);
+        assert_eq!(tx.inputs.len(), 1);
+        assert_eq!(tx.inputs[0].previous_output.vout, 0);
+        assert_eq!(tx.inputs[0].sequence, 0xfffffffe);
+        assert_eq!(tx.outputs.len(), 2);
+        assert_eq!(tx.outputs[0].value, 32454049);
+        assert_eq!(tx.outputs[1].value, 10011545);
+        assert_eq!(tx.locktime, 410393);
+    }
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        let tx = Transaction::from_hex(RAW_TX).unwrap();
+        assert_eq!(hex::encode(tx.serialize()), RAW_TX);
+    }
+
+    #[test]
+    fn test_txid() {
+        let tx = Transaction::from_hex(RAW_TX).unwrap();
+        assert_eq!(
+            tx.txid_hex(),
+            "452c629d67e41baec3ac6f04fe744b4b9617f8f859c63b3002f8684e7a4fee03"
+        );
+    }
+
+    // Signed native P2WPKH transaction from BIP143.
+    const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    #[test]
+    fn test_parse_segwit_transaction() {
+        let tx = Transaction::from_hex(SEGWIT_TX).unwrap();
+
+        assert!(tx.has_witness());
+        assert!(tx.inputs[0].witness.is_empty());
+        assert_eq!(tx.inputs[1].witness.len(), 2);
+        assert_eq!(tx.inputs[1].witness[1].len(), 33);
+        assert_eq!(tx.locktime, 17);
+        assert_eq!(hex::encode(tx.serialize()), SEGWIT_TX);
+        assert_ne!(tx.txid(), tx.wtxid());
+    }
+
+    #[test]
+    fn test_legacy_transaction_wtxid_equals_txid() {
+        let tx = Transaction::from_hex(RAW_TX).unwrap();
+        assert!(!tx.has_witness());
+        assert_eq!(tx.txid(), tx.wtxid());
+    }
+
+    #[test]
+    fn test_parse_rejects_segwit_marker_without_witnesses() {
+        // Marker and flag set but every witness is empty.
+        let tx = Transaction::from_hex(RAW_TX).unwrap();
+        let mut bytes = tx.version.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0x00, 0x01]);
+        let legacy = tx.serialize_legacy();
+        bytes.extend_from_slice(&legacy[4..legacy.len() - 4]);
+        bytes.push(0x00);
+        bytes.extend_from_slice(&tx.locktime.to_le_bytes());
+
+        assert_eq!(
+            Transaction::from_bytes(&bytes),
+            Err(Errors::InvalidSegwitFlag)
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_and_trailing_data() {
+        assert_eq!(
+            Transaction::from_hex(&RAW_TX[..RAW_TX.len() - 2]),
+            Err(Errors::UnexpectedEof)
+        );
+        assert_eq!(
+            Transaction::from_hex(&format!("{}00", RAW_TX)),
+            Err(Errors::TrailingData)
+        );
+    }
+}
@@ -0,0 +1,116 @@
+// Embedding data in transactions with provably unspendable OP_RETURN outputs.
+use super::{Transaction, TxOut};
+use crate::policy::MAX_OP_RETURN_RELAY;
+use crate::script::{parse_ops, Opcode, Script};
+use crate::types::errors::Errors;
+
+impl TxOut {
+    // Zero value OP_RETURN <data> output. The script must fit in MAX_OP_RETURN_RELAY
+    // bytes to be relayed, which leaves room for 80 bytes of data.
+    pub fn op_return(data: &[u8]) -> Result<TxOut, Errors> {
+        let mut script_pubkey = Script::new();
+        script_pubkey
+            .push_opcode(Opcode::OP_RETURN)
+            .push_slice(data);
+        if script_pubkey.len() > MAX_OP_RETURN_RELAY {
+            return Err(Errors::OpReturnTooLarge(data.len()));
+        }
+        Ok(TxOut::new(0, script_pubkey))
+    }
+
+    // Data pushed after OP_RETURN, concatenated. None if this is not an OP_RETURN
+    // output or anything but pushes follows it.
+    pub fn op_return_payload(&self) -> Option<Vec<u8>> {
+        let (first, rest) = self.script_pubkey.split_first()?;
+        if *first != Opcode::OP_RETURN.to_u8() {
+            return None;
+        }
+        let ops = parse_ops(rest)?;
+        if ops.iter().any(|(opcode, _)| *opcode > 0x4e) {
+            return None;
+        }
+        Some(ops.iter().flat_map(|(_, data)| *data).copied().collect())
+    }
+}
+
+impl Transaction {
+    // Appends an OP_RETURN output. Only one is standard per transaction.
+    pub fn add_op_return(&mut self, data: &[u8]) -> Result<(), Errors> {
+        if self
+            .outputs
+            .iter()
+            .any(|output| output.script_pubkey.first() == Some(&Opcode::OP_RETURN.to_u8()))
+        {
+            return Err(Errors::MultipleOpReturn);
+        }
+        self.outputs.push(TxOut::op_return(data)?);
+        Ok(())
+    }
+
+    // (output index, payload) of every OP_RETURN output.
+    pub fn op_return_payloads(&self) -> Vec<(usize, Vec<u8>)> {
+        self.outputs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, output)| Some((index, output.op_return_payload()?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod op_return_tests {
+    use super::*;
+    use crate::policy::{check_standard, FeeRate, DUST_RELAY_TX_FEE};
+
+    const RAW_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+    #[test]
+    fn test_op_return_round_trip() {
+        for len in [0, 1, 75, 76, 80] {
+            let data = vec![0x42; len];
+            let output = TxOut::op_return(&data).unwrap();
+            assert_eq!(output.value, 0);
+            assert_eq!(output.op_return_payload(), Some(data));
+        }
+        assert_eq!(
+            TxOut::op_return(&[0u8; 81]),
+            Err(Errors::OpReturnTooLarge(81))
+        );
+    }
+
+    #[test]
+    fn test_op_return_payload_parsing() {
+        // Several pushes are concatenated.
+        let output = TxOut::new(
+            0,
+            vec![Opcode::OP_RETURN.to_u8(), 0x02, 0xaa, 0xbb, 0x01, 0xcc].into(),
+        );
+        assert_eq!(output.op_return_payload(), Some(vec![0xaa, 0xbb, 0xcc]));
+        assert_eq!(
+            TxOut::new(0, vec![Opcode::OP_RETURN.to_u8()].into()).op_return_payload(),
+            Some(vec![])
+        );
+        // Non-push opcode, truncated push and non OP_RETURN scripts.
+        assert_eq!(
+            TxOut::new(0, vec![Opcode::OP_RETURN.to_u8(), 0x76].into()).op_return_payload(),
+            None
+        );
+        assert_eq!(
+            TxOut::new(0, vec![Opcode::OP_RETURN.to_u8(), 0x05, 0x00].into()).op_return_payload(),
+            None
+        );
+        assert_eq!(TxOut::new(0, vec![0x51].into()).op_return_payload(), None);
+    }
+
+    #[test]
+    fn test_add_op_return_stays_standard() {
+        let mut tx = Transaction::from_hex(RAW_TX).unwrap();
+        tx.add_op_return(&[0x42; 80]).unwrap();
+        assert_eq!(tx.op_return_payloads(), vec![(2, vec![0x42; 80])]);
+        let dust_feerate = FeeRate::from_sat_per_kvb(DUST_RELAY_TX_FEE);
+        assert_eq!(check_standard(&tx, dust_feerate), Ok(()));
+
+        assert_eq!(tx.add_op_return(b"again"), Err(Errors::MultipleOpReturn));
+        assert_eq!(tx.outputs.len(), 3);
+    }
+}
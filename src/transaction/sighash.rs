@@ -0,0 +1,806 @@
+// Signature hash computation: legacy, segwit v0 (BIP143) and taproot (BIP341).
+use super::{Transaction, TxIn, TxOut};
+use crate::helper::{encode_var_bytes, hash256, sha256, tagged_hash};
+use crate::script::Script;
+use crate::types::errors::Errors;
+use std::cell::OnceCell;
+
+pub const SIGHASH_DEFAULT: u32 = 0x00;
+pub const SIGHASH_ALL: u32 = 0x01;
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+// The sighash types a signer can choose. Default only exists for taproot, where it
+// behaves like All but is encoded without a trailing byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigHashType {
+    Default,
+    All,
+    None,
+    Single,
+    AllPlusAnyoneCanPay,
+    NonePlusAnyoneCanPay,
+    SinglePlusAnyoneCanPay,
+}
+
+impl SigHashType {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            SigHashType::Default => SIGHASH_DEFAULT,
+            SigHashType::All => SIGHASH_ALL,
+            SigHashType::None => SIGHASH_NONE,
+            SigHashType::Single => SIGHASH_SINGLE,
+            SigHashType::AllPlusAnyoneCanPay => SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+            SigHashType::NonePlusAnyoneCanPay => SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+            SigHashType::SinglePlusAnyoneCanPay => SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Result<Self, Errors> {
+        match value {
+            0x00 => Ok(SigHashType::Default),
+            0x01 => Ok(SigHashType::All),
+            0x02 => Ok(SigHashType::None),
+            0x03 => Ok(SigHashType::Single),
+            0x81 => Ok(SigHashType::AllPlusAnyoneCanPay),
+            0x82 => Ok(SigHashType::NonePlusAnyoneCanPay),
+            0x83 => Ok(SigHashType::SinglePlusAnyoneCanPay),
+            _ => Err(Errors::InvalidSighashType(value)),
+        }
+    }
+
+    // Name used by Bitcoin Core when decoding signatures, "ALL|ANYONECANPAY" and so on.
+    pub fn name(self) -> &'static str {
+        match self {
+            SigHashType::Default => "DEFAULT",
+            SigHashType::All => "ALL",
+            SigHashType::None => "NONE",
+            SigHashType::Single => "SINGLE",
+            SigHashType::AllPlusAnyoneCanPay => "ALL|ANYONECANPAY",
+            SigHashType::NonePlusAnyoneCanPay => "NONE|ANYONECANPAY",
+            SigHashType::SinglePlusAnyoneCanPay => "SINGLE|ANYONECANPAY",
+        }
+    }
+
+    pub fn is_anyone_can_pay(self) -> bool {
+        self.to_u32() & SIGHASH_ANYONECANPAY != 0
+    }
+}
+
+// Caches the parts of the preimages that are shared by every input, so signing a
+// transaction with n inputs hashes the prevouts, sequences and outputs once instead of
+// n times. The single sha256 values are kept: taproot uses them directly and BIP143
+// hashes them once more.
+pub struct SighashCache<'a> {
+    tx: &'a Transaction,
+    sha_prevouts: OnceCell<[u8; 32]>,
+    sha_sequences: OnceCell<[u8; 32]>,
+    sha_outputs: OnceCell<[u8; 32]>,
+    sha_amounts: OnceCell<[u8; 32]>,
+    sha_script_pubkeys: OnceCell<[u8; 32]>,
+}
+
+impl<'a> SighashCache<'a> {
+    pub fn new(tx: &'a Transaction) -> Self {
+        SighashCache {
+            tx,
+            sha_prevouts: OnceCell::new(),
+            sha_sequences: OnceCell::new(),
+            sha_outputs: OnceCell::new(),
+            sha_amounts: OnceCell::new(),
+            sha_script_pubkeys: OnceCell::new(),
+        }
+    }
+
+    pub fn transaction(&self) -> &'a Transaction {
+        self.tx
+    }
+
+    fn sha_prevouts(&self) -> [u8; 32] {
+        *self.sha_prevouts.get_or_init(|| {
+            let data: Vec<u8> = self
+                .tx
+                .inputs
+                .iter()
+                .flat_map(|input| input.previous_output.serialize())
+                .collect();
+            sha256(&data)
+        })
+    }
+
+    fn sha_sequences(&self) -> [u8; 32] {
+        *self.sha_sequences.get_or_init(|| {
+            let data: Vec<u8> = self
+                .tx
+                .inputs
+                .iter()
+                .flat_map(|input| input.sequence.to_le_bytes())
+                .collect();
+            sha256(&data)
+        })
+    }
+
+    fn sha_outputs(&self) -> [u8; 32] {
+        *self.sha_outputs.get_or_init(|| {
+            let data: Vec<u8> = self
+                .tx
+                .outputs
+                .iter()
+                .flat_map(|output| output.serialize())
+                .collect();
+            sha256(&data)
+        })
+    }
+
+    pub fn hash_prevouts(&self) -> [u8; 32] {
+        sha256(&self.sha_prevouts())
+    }
+
+    pub fn hash_sequence(&self) -> [u8; 32] {
+        sha256(&self.sha_sequences())
+    }
+
+    pub fn hash_outputs(&self) -> [u8; 32] {
+        sha256(&self.sha_outputs())
+    }
+
+    fn input(&self, input_index: usize) -> Result<&'a TxIn, Errors> {
+        self.tx
+            .inputs
+            .get(input_index)
+            .ok_or(Errors::InputIndexOutOfRange(input_index))
+    }
+
+    // Original SignatureHash algorithm. script_code is the subscript being executed
+    // with any OP_CODESEPARATOR already removed.
+    pub fn legacy_signature_hash(
+        &self,
+        input_index: usize,
+        script_code: &[u8],
+        sighash_type: u32,
+    ) -> Result<[u8; 32], Errors> {
+        self.input(input_index)?;
+        let base_type = sighash_type & 0x1f;
+
+        // SIGHASH_SINGLE without a matching output signs the number one, a well known
+        // quirk that consensus has to preserve.
+        if base_type == SIGHASH_SINGLE && input_index >= self.tx.outputs.len() {
+            let mut one = [0u8; 32];
+            one[0] = 1;
+            return Ok(one);
+        }
+
+        let mut tx = self.tx.clone();
+        for (i, input) in tx.inputs.iter_mut().enumerate() {
+            input.witness = Default::default();
+            if i == input_index {
+                input.script_sig = script_code.into();
+            } else {
+                input.script_sig = Script::new();
+                if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+                    input.sequence = 0;
+                }
+            }
+        }
+        if base_type == SIGHASH_NONE {
+            tx.outputs.clear();
+        } else if base_type == SIGHASH_SINGLE {
+            tx.outputs.truncate(input_index + 1);
+            for output in tx.outputs.iter_mut().take(input_index) {
+                *output = TxOut::new(u64::MAX, Script::new());
+            }
+        }
+        if sighash_type & SIGHASH_ANYONECANPAY != 0 {
+            tx.inputs = vec![tx.inputs.swap_remove(input_index)];
+        }
+
+        let mut preimage = tx.serialize_legacy();
+        preimage.extend_from_slice(&sighash_type.to_le_bytes());
+        Ok(hash256(&preimage))
+    }
+
+    // script_code is given without its length prefix. For P2WPKH it is the implicit
+    // P2PKH script (see p2wpkh_script_code), for P2WSH the witness script.
+    pub fn segwit_v0_signature_hash(
+        &self,
+        input_index: usize,
+        script_code: &[u8],
+        value: u64,
+        sighash_type: u32,
+    ) -> Result<[u8; 32], Errors> {
+        let input = self.input(input_index)?;
+        let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+        let base_type = sighash_type & 0x1f;
+
+        let hash_prevouts = if anyone_can_pay {
+            [0u8; 32]
+        } else {
+            self.hash_prevouts()
+        };
+
+        let hash_sequence =
+            if anyone_can_pay || base_type == SIGHASH_SINGLE || base_type == SIGHASH_NONE {
+                [0u8; 32]
+            } else {
+                self.hash_sequence()
+            };
+
+        let hash_outputs = if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+            self.hash_outputs()
+        } else if base_type == SIGHASH_SINGLE && input_index < self.tx.outputs.len() {
+            hash256(&self.tx.outputs[input_index].serialize())
+        } else {
+            [0u8; 32]
+        };
+
+        let mut preimage = self.tx.version.to_le_bytes().to_vec();
+        preimage.extend_from_slice(&hash_prevouts);
+        preimage.extend_from_slice(&hash_sequence);
+        preimage.extend(input.previous_output.serialize());
+        preimage.extend(encode_var_bytes(script_code));
+        preimage.extend_from_slice(&value.to_le_bytes());
+        preimage.extend_from_slice(&input.sequence.to_le_bytes());
+        preimage.extend_from_slice(&hash_outputs);
+        preimage.extend_from_slice(&self.tx.locktime.to_le_bytes());
+        preimage.extend_from_slice(&sighash_type.to_le_bytes());
+
+        Ok(hash256(&preimage))
+    }
+
+    // BIP341 digest for a key path spend. prevouts holds the output spent by every input,
+    // and must be the same on every call made through one cache. annex is the annex of
+    // the witness being signed, tag byte included, if it has one.
+    pub fn taproot_key_spend_signature_hash(
+        &self,
+        input_index: usize,
+        prevouts: &[TxOut],
+        annex: Option<&[u8]>,
+        sighash_type: u32,
+    ) -> Result<[u8; 32], Errors> {
+        self.taproot_signature_hash(input_index, prevouts, None, annex, sighash_type)
+    }
+
+    // BIP342 digest for a script path spend of the leaf with the given hash.
+    // codesep_pos is the position of the last executed OP_CODESEPARATOR, or 0xffffffff.
+    pub fn taproot_script_spend_signature_hash(
+        &self,
+        input_index: usize,
+        prevouts: &[TxOut],
+        leaf_hash: [u8; 32],
+        codesep_pos: u32,
+        annex: Option<&[u8]>,
+        sighash_type: u32,
+    ) -> Result<[u8; 32], Errors> {
+        self.taproot_signature_hash(
+            input_index,
+            prevouts,
+            Some((leaf_hash, codesep_pos)),
+            annex,
+            sighash_type,
+        )
+    }
+
+    fn taproot_signature_hash(
+        &self,
+        input_index: usize,
+        prevouts: &[TxOut],
+        script_path: Option<([u8; 32], u32)>,
+        annex: Option<&[u8]>,
+        sighash_type: u32,
+    ) -> Result<[u8; 32], Errors> {
+        let msg = self.taproot_signature_message(
+            input_index,
+            prevouts,
+            script_path,
+            annex,
+            sighash_type,
+        )?;
+        Ok(tagged_hash("TapSighash", &msg))
+    }
+
+    // What the BIP341 digest is the tagged hash of.
+    fn taproot_signature_message(
+        &self,
+        input_index: usize,
+        prevouts: &[TxOut],
+        script_path: Option<([u8; 32], u32)>,
+        annex: Option<&[u8]>,
+        sighash_type: u32,
+    ) -> Result<Vec<u8>, Errors> {
+        let input = self.input(input_index)?;
+        SigHashType::from_u32(sighash_type)?;
+        if prevouts.len() != self.tx.inputs.len() {
+            return Err(Errors::PrevoutsMismatch);
+        }
+        let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+        let base_type = sighash_type & 0x03;
+
+        // Epoch 0 followed by SigMsg.
+        let mut msg = vec![0x00, sighash_type as u8];
+        msg.extend_from_slice(&self.tx.version.to_le_bytes());
+        msg.extend_from_slice(&self.tx.locktime.to_le_bytes());
+        if !anyone_can_pay {
+            msg.extend_from_slice(&self.sha_prevouts());
+            msg.extend_from_slice(&self.sha_amounts(prevouts));
+            msg.extend_from_slice(&self.sha_script_pubkeys(prevouts));
+            msg.extend_from_slice(&self.sha_sequences());
+        }
+        if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+            msg.extend_from_slice(&self.sha_outputs());
+        }
+
+        let ext_flag = if script_path.is_some() { 1 } else { 0 };
+        msg.push(ext_flag * 2 + annex.is_some() as u8);
+        if anyone_can_pay {
+            let prevout = &prevouts[input_index];
+            msg.extend(input.previous_output.serialize());
+            msg.extend_from_slice(&prevout.value.to_le_bytes());
+            msg.extend(encode_var_bytes(&prevout.script_pubkey));
+            msg.extend_from_slice(&input.sequence.to_le_bytes());
+        } else {
+            msg.extend_from_slice(&(input_index as u32).to_le_bytes());
+        }
+        if let Some(annex) = annex {
+            msg.extend_from_slice(&sha256(&encode_var_bytes(annex)));
+        }
+        if base_type == SIGHASH_SINGLE {
+            let output = self
+                .tx
+                .outputs
+                .get(input_index)
+                .ok_or(Errors::SighashSingleWithoutOutput)?;
+            msg.extend_from_slice(&sha256(&output.serialize()));
+        }
+        if let Some((leaf_hash, codesep_pos)) = script_path {
+            msg.extend_from_slice(&leaf_hash);
+            // key_version 0, the only one defined by BIP342.
+            msg.push(0x00);
+            msg.extend_from_slice(&codesep_pos.to_le_bytes());
+        }
+        Ok(msg)
+    }
+
+    fn sha_amounts(&self, prevouts: &[TxOut]) -> [u8; 32] {
+        *self.sha_amounts.get_or_init(|| {
+            let data: Vec<u8> = prevouts
+                .iter()
+                .flat_map(|prevout| prevout.value.to_le_bytes())
+                .collect();
+            sha256(&data)
+        })
+    }
+
+    fn sha_script_pubkeys(&self, prevouts: &[TxOut]) -> [u8; 32] {
+        *self.sha_script_pubkeys.get_or_init(|| {
+            let data: Vec<u8> = prevouts
+                .iter()
+                .flat_map(|prevout| encode_var_bytes(&prevout.script_pubkey))
+                .collect();
+            sha256(&data)
+        })
+    }
+}
+
+// The scriptCode used when signing a P2WPKH input: the P2PKH script for the same hash.
+pub fn p2wpkh_script_code(pubkey_hash: &[u8; 20]) -> Vec<u8> {
+    Script::new_p2pkh(pubkey_hash).into_bytes()
+}
+
+impl Transaction {
+    // Convenience wrapper for a single BIP143 digest. Use SighashCache directly when
+    // signing several inputs of the same transaction.
+    pub fn sig_hash_bip143(
+        &self,
+        input_index: usize,
+        script_code: &[u8],
+        value: u64,
+        sighash_type: u32,
+    ) -> Result<[u8; 32], Errors> {
+        SighashCache::new(self).segwit_v0_signature_hash(
+            input_index,
+            script_code,
+            value,
+            sighash_type,
+        )
+    }
+
+    pub fn sig_hash_legacy(
+        &self,
+        input_index: usize,
+        script_code: &[u8],
+        sighash_type: u32,
+    ) -> Result<[u8; 32], Errors> {
+        SighashCache::new(self).legacy_signature_hash(input_index, script_code, sighash_type)
+    }
+}
+
+#[cfg(test)]
+mod sighash_tests {
+    use super::*;
+
+    // Test vectors from BIP143.
+    const NATIVE_P2WPKH_TX: &str = "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+    const P2SH_P2WPKH_TX: &str = "0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a54770100000000feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000";
+    // Chapter 7 of Programming Bitcoin, with the scriptSig removed.
+    const LEGACY_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d10000000000feffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+    fn pubkey_hash(hex_str: &str) -> [u8; 20] {
+        hex::decode(hex_str).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_sighash_type_conversions() {
+        for value in [0x00, 0x01, 0x02, 0x03, 0x81, 0x82, 0x83] {
+            assert_eq!(SigHashType::from_u32(value).unwrap().to_u32(), value);
+        }
+        assert_eq!(
+            SigHashType::from_u32(0x04),
+            Err(Errors::InvalidSighashType(0x04))
+        );
+        assert!(SigHashType::NonePlusAnyoneCanPay.is_anyone_can_pay());
+        assert!(!SigHashType::Single.is_anyone_can_pay());
+    }
+
+    #[test]
+    fn test_legacy_sighash() {
+        let tx = Transaction::from_hex(LEGACY_TX).unwrap();
+        let script_pubkey =
+            hex::decode("76a914a802fc56c704ce87c42d7c92eb75e7896bdc41ae88ac").unwrap();
+
+        assert_eq!(
+            hex::encode(tx.sig_hash_legacy(0, &script_pubkey, SIGHASH_ALL).unwrap()),
+            "27e0c5994dec7824e56dec6b2fcb342eb7cdb0d0957c2fce9882f715e85d81a6"
+        );
+    }
+
+    #[test]
+    fn test_legacy_sighash_single_bug() {
+        let mut tx = Transaction::from_hex(LEGACY_TX).unwrap();
+        tx.outputs.truncate(0);
+        let mut one = [0u8; 32];
+        one[0] = 1;
+
+        assert_eq!(tx.sig_hash_legacy(0, &[], SIGHASH_SINGLE).unwrap(), one);
+    }
+
+    #[test]
+    fn test_legacy_sighash_none_ignores_outputs() {
+        let tx = Transaction::from_hex(LEGACY_TX).unwrap();
+        let mut modified = tx.clone();
+        modified.outputs[0].value = 1;
+
+        assert_eq!(
+            tx.sig_hash_legacy(0, &[0x51], SIGHASH_NONE).unwrap(),
+            modified.sig_hash_legacy(0, &[0x51], SIGHASH_NONE).unwrap()
+        );
+        assert_ne!(
+            tx.sig_hash_legacy(0, &[0x51], SIGHASH_ALL).unwrap(),
+            modified.sig_hash_legacy(0, &[0x51], SIGHASH_ALL).unwrap()
+        );
+        // SINGLE only commits to the output with the same index.
+        let mut modified = tx.clone();
+        modified.outputs[1].value = 1;
+        assert_eq!(
+            tx.sig_hash_legacy(0, &[0x51], SIGHASH_SINGLE).unwrap(),
+            modified
+                .sig_hash_legacy(0, &[0x51], SIGHASH_SINGLE)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_native_p2wpkh_intermediate_hashes() {
+        let tx = Transaction::from_hex(NATIVE_P2WPKH_TX).unwrap();
+        let cache = SighashCache::new(&tx);
+
+        assert_eq!(
+            hex::encode(cache.hash_prevouts()),
+            "96b827c8483d4e9b96712b6713a7b68d6e8003a781feba36c31143470b4efd37"
+        );
+        assert_eq!(
+            hex::encode(cache.hash_sequence()),
+            "52b0a642eea2fb7ae638c36f6252b6750293dbe574a806984b8e4d8548339a3b"
+        );
+        assert_eq!(
+            hex::encode(cache.hash_outputs()),
+            "863ef3e1a92afbfdb97f31ad0fc7683ee943e9abcf2501590ff8f6551f47e5e5"
+        );
+    }
+
+    #[test]
+    fn test_native_p2wpkh_sighash() {
+        let tx = Transaction::from_hex(NATIVE_P2WPKH_TX).unwrap();
+        let script_code =
+            p2wpkh_script_code(&pubkey_hash("1d0f172a0ecb48aee1be1f2687d2963ae33f71a1"));
+        let sighash = tx
+            .sig_hash_bip143(1, &script_code, 600_000_000, SIGHASH_ALL)
+            .unwrap();
+
+        assert_eq!(
+            hex::encode(sighash),
+            "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+        );
+    }
+
+    #[test]
+    fn test_p2sh_p2wpkh_sighash() {
+        let tx = Transaction::from_hex(P2SH_P2WPKH_TX).unwrap();
+        let cache = SighashCache::new(&tx);
+        let script_code =
+            p2wpkh_script_code(&pubkey_hash("79091972186c449eb1ded22b78e40d009bdf0089"));
+
+        assert_eq!(
+            hex::encode(cache.hash_prevouts()),
+            "b0287b4a252ac05af83d2dcef00ba313af78a3e9c329afa216eb3aa2a7b4613a"
+        );
+        assert_eq!(
+            hex::encode(cache.hash_sequence()),
+            "18606b350cd8bf565266bc352f0caddcf01e8fa789dd8a15386327cf8cabe198"
+        );
+        assert_eq!(
+            hex::encode(cache.hash_outputs()),
+            "de984f44532e2173ca0d64314fcefe6d30da6f8cf27bafa706da61df8a226c83"
+        );
+        assert_eq!(
+            hex::encode(
+                cache
+                    .segwit_v0_signature_hash(0, &script_code, 1_000_000_000, SIGHASH_ALL)
+                    .unwrap()
+            ),
+            "64f3b0f4dd2bb3aa1ce8566d220cc74dda9df97d8490cc81d89d735c92e59fb6"
+        );
+    }
+
+    #[test]
+    fn test_sighash_types_commit_to_different_data() {
+        let tx = Transaction::from_hex(NATIVE_P2WPKH_TX).unwrap();
+        let cache = SighashCache::new(&tx);
+        let script_code = p2wpkh_script_code(&[0u8; 20]);
+        let prevouts = vec![
+            TxOut::new(1, vec![0x51].into()),
+            TxOut::new(2, vec![0x51].into()),
+        ];
+        let types = [0x01, 0x02, 0x03, 0x81, 0x82, 0x83];
+        let v0: Vec<_> = types
+            .iter()
+            .map(|t| {
+                cache
+                    .segwit_v0_signature_hash(0, &script_code, 1, *t)
+                    .unwrap()
+            })
+            .collect();
+        let taproot: Vec<_> = types
+            .iter()
+            .map(|t| {
+                cache
+                    .taproot_key_spend_signature_hash(0, &prevouts, None, *t)
+                    .unwrap()
+            })
+            .collect();
+
+        for hashes in [v0, taproot] {
+            for i in 0..hashes.len() {
+                for j in i + 1..hashes.len() {
+                    assert_ne!(hashes[i], hashes[j]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_anyonecanpay_ignores_other_inputs() {
+        let tx = Transaction::from_hex(NATIVE_P2WPKH_TX).unwrap();
+        let mut modified = tx.clone();
+        modified.inputs[0].sequence = 0;
+        modified.inputs[0].previous_output.vout = 7;
+        let script_code = p2wpkh_script_code(&[0u8; 20]);
+        let sighash_type = SIGHASH_ALL | SIGHASH_ANYONECANPAY;
+
+        assert_eq!(
+            tx.sig_hash_bip143(1, &script_code, 1, sighash_type)
+                .unwrap(),
+            modified
+                .sig_hash_bip143(1, &script_code, 1, sighash_type)
+                .unwrap()
+        );
+        assert_ne!(
+            tx.sig_hash_bip143(1, &script_code, 1, SIGHASH_ALL).unwrap(),
+            modified
+                .sig_hash_bip143(1, &script_code, 1, SIGHASH_ALL)
+                .unwrap()
+        );
+        assert_eq!(
+            tx.sig_hash_legacy(1, &script_code, sighash_type).unwrap(),
+            modified
+                .sig_hash_legacy(1, &script_code, sighash_type)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_taproot_sighash_errors() {
+        let tx = Transaction::from_hex(P2SH_P2WPKH_TX).unwrap();
+        let cache = SighashCache::new(&tx);
+        let prevouts = vec![TxOut::new(1, vec![0x51].into())];
+
+        assert_eq!(
+            cache.taproot_key_spend_signature_hash(0, &[], None, SIGHASH_DEFAULT),
+            Err(Errors::PrevoutsMismatch)
+        );
+        assert_eq!(
+            cache.taproot_key_spend_signature_hash(0, &prevouts, None, 0x04),
+            Err(Errors::InvalidSighashType(0x04))
+        );
+        let mut no_outputs = tx.clone();
+        no_outputs.outputs.clear();
+        assert_eq!(
+            SighashCache::new(&no_outputs).taproot_key_spend_signature_hash(
+                0,
+                &prevouts,
+                None,
+                SIGHASH_SINGLE
+            ),
+            Err(Errors::SighashSingleWithoutOutput)
+        );
+    }
+
+    #[test]
+    fn test_taproot_script_path_commits_to_leaf() {
+        let tx = Transaction::from_hex(P2SH_P2WPKH_TX).unwrap();
+        let cache = SighashCache::new(&tx);
+        let prevouts = vec![TxOut::new(1, vec![0x51].into())];
+        let key_spend = cache
+            .taproot_key_spend_signature_hash(0, &prevouts, None, SIGHASH_DEFAULT)
+            .unwrap();
+        let leaf_a = cache
+            .taproot_script_spend_signature_hash(
+                0,
+                &prevouts,
+                [1; 32],
+                0xffffffff,
+                None,
+                SIGHASH_DEFAULT,
+            )
+            .unwrap();
+        let leaf_b = cache
+            .taproot_script_spend_signature_hash(
+                0,
+                &prevouts,
+                [2; 32],
+                0xffffffff,
+                None,
+                SIGHASH_DEFAULT,
+            )
+            .unwrap();
+
+        assert_ne!(key_spend, leaf_a);
+        assert_ne!(leaf_a, leaf_b);
+
+        // The annex, when present, is committed to by hash.
+        let with_annex = cache
+            .taproot_key_spend_signature_hash(0, &prevouts, Some(&[0x50, 1]), SIGHASH_DEFAULT)
+            .unwrap();
+        let other_annex = cache
+            .taproot_key_spend_signature_hash(0, &prevouts, Some(&[0x50, 2]), SIGHASH_DEFAULT)
+            .unwrap();
+        assert_ne!(key_spend, with_annex);
+        assert_ne!(with_annex, other_annex);
+    }
+
+    // keyPathSpending of BIP341's wallet-test-vectors.json: the spent outputs, then
+    // for each input signed its index, hash type, sigMsg and sigHash.
+    const KEY_PATH_TX: &str = "02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c010000000000000000d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000000000fffffffff8e1f583384333689228c5d28eac13366be082dc57441760d957275419a418420000000000fffffffff0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b0100000000feffffffaa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba6c0000000000feffffff956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32acd050000000000000000000e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d5f4c94010000000000000000e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf0000000000ffffffffa778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af10100000000ffffffff0200ca9a3b000000001976a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac807840cb0000000020ac9a87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b0065cd1d";
+    const KEY_PATH_UTXOS: [(&str, u64); 9] = [
+        (
+            "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+            420000000,
+        ),
+        (
+            "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+            462000000,
+        ),
+        (
+            "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac",
+            294000000,
+        ),
+        (
+            "5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+            504000000,
+        ),
+        (
+            "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605",
+            630000000,
+        ),
+        ("00147dd65592d0ab2fe0d0257d571abf032cd9db93dc", 378000000),
+        (
+            "512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831",
+            672000000,
+        ),
+        (
+            "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5",
+            546000000,
+        ),
+        (
+            "512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220",
+            588000000,
+        ),
+    ];
+    const KEY_PATH_SPENDS: [(usize, u32, &str, &str); 7] = [
+        (
+            0,
+            0x03,
+            "0003020000000065cd1de3b33bb4ef3a52ad1fffb555c0d82828eb22737036eaeb02a235d82b909c4c3f58a6964a4f5f8f0b642ded0a8a553be7622a719da71d1f5befcefcdee8e0fde623ad0f61ad2bca5ba6a7693f50fce988e17c3780bf2b1e720cfbb38fbdd52e2118959c7221ab5ce9e26c3cd67b22c24f8baa54bac281d8e6b05e400e6c3a957e0000000000d0418f0e9a36245b9a50ec87f8bf5be5bcae434337b87139c3a5b1f56e33cba0",
+            "2514a6272f85cfa0f45eb907fcb0d121b808ed37c6ea160a5a9046ed5526d555",
+        ),
+        (
+            1,
+            0x83,
+            "0083020000000065cd1d00d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd9900000000808f891b00000000225120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3ffffffffffcef8fb4ca7efc5433f591ecfc57391811ce1e186a3793024def5c884cba51d",
+            "325a644af47e8a5a2591cda0ab0723978537318f10e6a63d4eed783b96a71a4d",
+        ),
+        (
+            3,
+            0x01,
+            "0001020000000065cd1de3b33bb4ef3a52ad1fffb555c0d82828eb22737036eaeb02a235d82b909c4c3f58a6964a4f5f8f0b642ded0a8a553be7622a719da71d1f5befcefcdee8e0fde623ad0f61ad2bca5ba6a7693f50fce988e17c3780bf2b1e720cfbb38fbdd52e2118959c7221ab5ce9e26c3cd67b22c24f8baa54bac281d8e6b05e400e6c3a957ea2e6dab7c1f0dcd297c8d61647fd17d821541ea69c3cc37dcbad7f90d4eb4bc50003000000",
+            "bf013ea93474aa67815b1b6cc441d23b64fa310911d991e713cd34c7f5d46669",
+        ),
+        (
+            4,
+            0x00,
+            "0000020000000065cd1de3b33bb4ef3a52ad1fffb555c0d82828eb22737036eaeb02a235d82b909c4c3f58a6964a4f5f8f0b642ded0a8a553be7622a719da71d1f5befcefcdee8e0fde623ad0f61ad2bca5ba6a7693f50fce988e17c3780bf2b1e720cfbb38fbdd52e2118959c7221ab5ce9e26c3cd67b22c24f8baa54bac281d8e6b05e400e6c3a957ea2e6dab7c1f0dcd297c8d61647fd17d821541ea69c3cc37dcbad7f90d4eb4bc50004000000",
+            "4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef",
+        ),
+        (
+            6,
+            0x02,
+            "0002020000000065cd1de3b33bb4ef3a52ad1fffb555c0d82828eb22737036eaeb02a235d82b909c4c3f58a6964a4f5f8f0b642ded0a8a553be7622a719da71d1f5befcefcdee8e0fde623ad0f61ad2bca5ba6a7693f50fce988e17c3780bf2b1e720cfbb38fbdd52e2118959c7221ab5ce9e26c3cd67b22c24f8baa54bac281d8e6b05e400e6c3a957e0006000000",
+            "15f25c298eb5cdc7eb1d638dd2d45c97c4c59dcaec6679cfc16ad84f30876b85",
+        ),
+        (
+            7,
+            0x82,
+            "0082020000000065cd1d00e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf00000000804c8b2000000000225120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5ffffffff",
+            "cd292de50313804dabe4685e83f923d2969577191a3e1d2882220dca88cbeb10",
+        ),
+        (
+            8,
+            0x81,
+            "0081020000000065cd1da2e6dab7c1f0dcd297c8d61647fd17d821541ea69c3cc37dcbad7f90d4eb4bc500a778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af101000000002b0c230000000022512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220ffffffff",
+            "cccb739eca6c13a8a89e6e5cd317ffe55669bbda23f2fd37b0f18755e008edd2",
+        ),
+    ];
+
+    #[test]
+    fn test_bip341_key_path_vectors() {
+        let tx = Transaction::from_hex(KEY_PATH_TX).unwrap();
+        let prevouts: Vec<TxOut> = KEY_PATH_UTXOS
+            .iter()
+            .map(|(script, value)| TxOut::new(*value, hex::decode(script).unwrap().into()))
+            .collect();
+        let cache = SighashCache::new(&tx);
+        for (index, sighash_type, sig_msg, sig_hash) in KEY_PATH_SPENDS {
+            let msg = cache
+                .taproot_signature_message(index, &prevouts, None, None, sighash_type)
+                .unwrap();
+            assert_eq!(hex::encode(msg), sig_msg);
+            let hash = cache
+                .taproot_key_spend_signature_hash(index, &prevouts, None, sighash_type)
+                .unwrap();
+            assert_eq!(hex::encode(hash), sig_hash);
+        }
+    }
+
+    #[test]
+    fn test_input_index_out_of_range() {
+        let tx = Transaction::from_hex(P2SH_P2WPKH_TX).unwrap();
+        assert_eq!(
+            tx.sig_hash_bip143(1, &[], 0, SIGHASH_ALL),
+            Err(Errors::InputIndexOutOfRange(1))
+        );
+        assert_eq!(
+            tx.sig_hash_legacy(1, &[], SIGHASH_ALL),
+            Err(Errors::InputIndexOutOfRange(1))
+        );
+    }
+}
@@ -0,0 +1,195 @@
+// Producing signatures for transaction inputs with any sighash type.
+use super::sighash::{SigHashType, SighashCache};
+use super::TxOut;
+use crate::ecc::{from_bytes, PrivateKey};
+use crate::types::errors::Errors;
+
+impl<'a> SighashCache<'a> {
+    // Signature for a legacy (P2PK, P2PKH, P2SH) input: DER encoding followed by the
+    // sighash byte, ready to be pushed in the scriptSig.
+    pub fn sign_legacy_input(
+        &self,
+        input_index: usize,
+        key: &PrivateKey,
+        script_code: &[u8],
+        sighash_type: SigHashType,
+    ) -> Result<Vec<u8>, Errors> {
+        let flag = ecdsa_flag(sighash_type)?;
+        let digest = self.legacy_signature_hash(input_index, script_code, flag)?;
+        Ok(ecdsa_signature(key, &digest, flag))
+    }
+
+    // Signature for a segwit v0 input, value being the amount of the spent output.
+    pub fn sign_segwit_v0_input(
+        &self,
+        input_index: usize,
+        key: &PrivateKey,
+        script_code: &[u8],
+        value: u64,
+        sighash_type: SigHashType,
+    ) -> Result<Vec<u8>, Errors> {
+        let flag = ecdsa_flag(sighash_type)?;
+        let digest = self.segwit_v0_signature_hash(input_index, script_code, value, flag)?;
+        Ok(ecdsa_signature(key, &digest, flag))
+    }
+
+    // Schnorr signature for a taproot key path spend. key must already be tweaked with
+    // the output's merkle root. SIGHASH_DEFAULT signatures are 64 bytes, the rest 65.
+    pub fn sign_taproot_key_spend(
+        &self,
+        input_index: usize,
+        key: &PrivateKey,
+        prevouts: &[TxOut],
+        annex: Option<&[u8]>,
+        sighash_type: SigHashType,
+    ) -> Result<Vec<u8>, Errors> {
+        let flag = sighash_type.to_u32();
+        let digest = self.taproot_key_spend_signature_hash(input_index, prevouts, annex, flag)?;
+        Ok(schnorr_signature(key, &digest, sighash_type))
+    }
+
+    // Schnorr signature for a key inside the tapscript leaf with the given hash.
+    pub fn sign_taproot_script_spend(
+        &self,
+        input_index: usize,
+        key: &PrivateKey,
+        prevouts: &[TxOut],
+        leaf_hash: [u8; 32],
+        annex: Option<&[u8]>,
+        sighash_type: SigHashType,
+    ) -> Result<Vec<u8>, Errors> {
+        let flag = sighash_type.to_u32();
+        let digest = self.taproot_script_spend_signature_hash(
+            input_index,
+            prevouts,
+            leaf_hash,
+            0xffffffff,
+            annex,
+            flag,
+        )?;
+        Ok(schnorr_signature(key, &digest, sighash_type))
+    }
+}
+
+// SIGHASH_DEFAULT has no meaning outside of taproot.
+fn ecdsa_flag(sighash_type: SigHashType) -> Result<u32, Errors> {
+    match sighash_type {
+        SigHashType::Default => Err(Errors::InvalidSighashType(0)),
+        other => Ok(other.to_u32()),
+    }
+}
+
+fn ecdsa_signature(key: &PrivateKey, digest: &[u8; 32], flag: u32) -> Vec<u8> {
+    let mut signature = key.sign(&from_bytes(digest)).der();
+    signature.push(flag as u8);
+    signature
+}
+
+fn schnorr_signature(key: &PrivateKey, digest: &[u8; 32], sighash_type: SigHashType) -> Vec<u8> {
+    let mut signature = key.sign_schnorr(digest, &[0u8; 32]).serialize().to_vec();
+    if sighash_type != SigHashType::Default {
+        signature.push(sighash_type.to_u32() as u8);
+    }
+    signature
+}
+
+#[cfg(test)]
+mod signer_tests {
+    use super::*;
+    use crate::ecc::{SchnorrSignature, Signature};
+    use crate::transaction::sighash::p2wpkh_script_code;
+    use crate::transaction::Transaction;
+    use num_bigint::BigInt;
+
+    // Unsigned transaction from chapter 7 of Programming Bitcoin, spending an output
+    // locked to the P2PKH of the compressed public key of secret 8675309.
+    const UNSIGNED_TX: &str = "010000000199a24308080ab26e6fb65c4eccfadf76749bb5bfa8cb08f291320b3c21e56f0d0d00000000ffffffff02408af701000000001976a914d52ad7ca9b3d096a38e752c2018e6fbc40cdf26f88ac80969800000000001976a914507b27411ccf7f16f10297de6cef3f291623eddf88ac00000000";
+
+    fn key() -> PrivateKey {
+        PrivateKey::new(BigInt::from(8675309)).unwrap()
+    }
+
+    // The P2WPKH script code is exactly the P2PKH scriptPubKey.
+    fn p2pkh(key: &PrivateKey) -> Vec<u8> {
+        p2wpkh_script_code(&key.public_key().hash160(true))
+    }
+
+    #[test]
+    fn test_sign_legacy_input_matches_book() {
+        let tx = Transaction::from_hex(UNSIGNED_TX).unwrap();
+        let key = key();
+        let cache = SighashCache::new(&tx);
+        let signature = cache
+            .sign_legacy_input(0, &key, &p2pkh(&key), SigHashType::All)
+            .unwrap();
+
+        assert_eq!(
+            hex::encode(signature),
+            "30450221008ed46aa2cf12d6d81065bfabe903670165b538f65ee9a3385e6327d80c66d3b502203124f804410527497329ec4715e18558082d489b218677bd029e7fa306a7223601"
+        );
+    }
+
+    #[test]
+    fn test_signature_ends_with_sighash_byte() {
+        let tx = Transaction::from_hex(UNSIGNED_TX).unwrap();
+        let key = key();
+        let cache = SighashCache::new(&tx);
+        let script_code = p2pkh(&key);
+
+        for sighash_type in [
+            SigHashType::None,
+            SigHashType::Single,
+            SigHashType::AllPlusAnyoneCanPay,
+            SigHashType::SinglePlusAnyoneCanPay,
+        ] {
+            let signature = cache
+                .sign_segwit_v0_input(0, &key, &script_code, 1000, sighash_type)
+                .unwrap();
+            let (der, flag) = signature.split_at(signature.len() - 1);
+            assert_eq!(flag[0] as u32, sighash_type.to_u32());
+
+            let digest = cache
+                .segwit_v0_signature_hash(0, &script_code, 1000, sighash_type.to_u32())
+                .unwrap();
+            let sig = Signature::parse_der(der).unwrap();
+            assert!(key.public_key().verify(&from_bytes(&digest), &sig));
+        }
+    }
+
+    #[test]
+    fn test_ecdsa_rejects_default_sighash() {
+        let tx = Transaction::from_hex(UNSIGNED_TX).unwrap();
+        let cache = SighashCache::new(&tx);
+        assert_eq!(
+            cache.sign_legacy_input(0, &key(), &[], SigHashType::Default),
+            Err(Errors::InvalidSighashType(0))
+        );
+    }
+
+    #[test]
+    fn test_taproot_signature_lengths() {
+        let tx = Transaction::from_hex(UNSIGNED_TX).unwrap();
+        let key = key();
+        let cache = SighashCache::new(&tx);
+        let mut script_pubkey = vec![0x51, 0x20];
+        script_pubkey.extend_from_slice(&key.public_key().xonly());
+        let prevouts = vec![TxOut::new(100_000, script_pubkey.into())];
+
+        let default = cache
+            .sign_taproot_key_spend(0, &key, &prevouts, None, SigHashType::Default)
+            .unwrap();
+        let all = cache
+            .sign_taproot_key_spend(0, &key, &prevouts, None, SigHashType::All)
+            .unwrap();
+        assert_eq!(default.len(), 64);
+        assert_eq!(all.len(), 65);
+        assert_eq!(all[64], 0x01);
+
+        let digest = cache
+            .taproot_key_spend_signature_hash(0, &prevouts, None, 0x00)
+            .unwrap();
+        let sig = SchnorrSignature::parse(&default).unwrap();
+        let xonly: [u8; 32] = key.public_key().xonly();
+        assert!(sig.verify(&xonly, &digest));
+    }
+}
@@ -0,0 +1,65 @@
+// Size accounting following the BIP141 weight formula.
+use super::Transaction;
+
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+
+impl Transaction {
+    // Size of the serialization without witness data.
+    pub fn stripped_size(&self) -> usize {
+        self.serialize_legacy().len()
+    }
+
+    // Size of the full serialization, including marker, flag and witnesses when present.
+    pub fn total_size(&self) -> usize {
+        if !self.has_witness() {
+            return self.stripped_size();
+        }
+        let witness_size: usize = self
+            .inputs
+            .iter()
+            .map(|input| input.witness.serialized_size())
+            .sum();
+        self.stripped_size() + 2 + witness_size
+    }
+
+    // Non-witness bytes weigh 4 units and witness bytes 1.
+    pub fn weight(&self) -> usize {
+        self.stripped_size() * (WITNESS_SCALE_FACTOR - 1) + self.total_size()
+    }
+
+    // Virtual size in vbytes, rounded up, as used for feerates.
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(WITNESS_SCALE_FACTOR)
+    }
+}
+
+#[cfg(test)]
+mod weight_tests {
+    use super::*;
+
+    const LEGACY_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+    const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    #[test]
+    fn test_legacy_weight() {
+        let tx = Transaction::from_hex(LEGACY_TX).unwrap();
+        let size = LEGACY_TX.len() / 2;
+
+        assert_eq!(tx.stripped_size(), size);
+        assert_eq!(tx.total_size(), size);
+        assert_eq!(tx.weight(), size * 4);
+        assert_eq!(tx.vsize(), size);
+    }
+
+    #[test]
+    fn test_segwit_weight() {
+        let tx = Transaction::from_hex(SEGWIT_TX).unwrap();
+
+        assert_eq!(tx.total_size(), 343);
+        assert_eq!(tx.total_size(), tx.serialize().len());
+        assert_eq!(tx.stripped_size(), 233);
+        assert_eq!(tx.weight(), 1042);
+        // 1042 / 4 = 260.5 is rounded up.
+        assert_eq!(tx.vsize(), 261);
+    }
+}
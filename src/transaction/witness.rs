@@ -0,0 +1,190 @@
+// Segregated witness data attached to a transaction input (BIP141).
+use crate::helper::{encode_var_bytes, encode_varint, read_var_bytes, read_varint};
+use crate::taproot::TAPROOT_ANNEX_TAG;
+use crate::types::errors::Errors;
+use std::io::Read;
+use std::ops::Index;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Witness {
+    elements: Vec<Vec<u8>>,
+}
+
+impl Witness {
+    pub fn new() -> Self {
+        Witness {
+            elements: Vec::new(),
+        }
+    }
+
+    pub fn from_elements(elements: Vec<Vec<u8>>) -> Self {
+        Witness { elements }
+    }
+
+    // Witness spending a P2WPKH output: <signature> <pubkey>.
+    pub fn p2wpkh(signature: &[u8], pubkey: &[u8]) -> Self {
+        Witness::from_elements(vec![signature.to_vec(), pubkey.to_vec()])
+    }
+
+    // Taproot key path spend: a single schnorr signature.
+    pub fn p2tr_key_spend(signature: &[u8]) -> Self {
+        Witness::from_elements(vec![signature.to_vec()])
+    }
+
+    // Taproot script path spend: <inputs...> <leaf script> <control block>.
+    pub fn p2tr_script_spend(inputs: Vec<Vec<u8>>, script: &[u8], control_block: &[u8]) -> Self {
+        let mut elements = inputs;
+        elements.push(script.to_vec());
+        elements.push(control_block.to_vec());
+        Witness::from_elements(elements)
+    }
+
+    // Appends an annex to a taproot witness. It must start with TAPROOT_ANNEX_TAG so
+    // verifiers tell it apart from the other elements.
+    pub fn with_annex(mut self, annex: Vec<u8>) -> Result<Self, Errors> {
+        if self.is_empty() || annex.first() != Some(&TAPROOT_ANNEX_TAG) {
+            return Err(Errors::InvalidAnnex);
+        }
+        self.elements.push(annex);
+        Ok(self)
+    }
+
+    // The annex, if this witness spends a taproot output. Other witness versions have
+    // no annex, their last element may start with the tag byte by chance.
+    pub fn taproot_annex(&self) -> Option<&[u8]> {
+        let last = self.last()?;
+        (self.len() >= 2 && last.first() == Some(&TAPROOT_ANNEX_TAG)).then_some(last)
+    }
+
+    pub fn push(&mut self, element: Vec<u8>) {
+        self.elements.push(element);
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        self.elements.get(index).map(Vec::as_slice)
+    }
+
+    pub fn last(&self) -> Option<&[u8]> {
+        self.elements.last().map(Vec::as_slice)
+    }
+
+    // Second to last element, the witness script or tapscript in script path spends.
+    pub fn second_to_last(&self) -> Option<&[u8]> {
+        self.len().checked_sub(2).and_then(|index| self.get(index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.elements.iter().map(Vec::as_slice)
+    }
+
+    pub fn to_vec(&self) -> Vec<Vec<u8>> {
+        self.elements.clone()
+    }
+
+    pub fn parse(reader: &mut impl Read) -> Result<Self, Errors> {
+        let count = read_varint(reader)?;
+        let elements = (0..count)
+            .map(|_| read_var_bytes(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Witness { elements })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = encode_varint(self.elements.len() as u64);
+        for element in &self.elements {
+            result.extend(encode_var_bytes(element));
+        }
+        result
+    }
+
+    // Size in bytes of the serialized witness, without building it. Every witness byte
+    // counts as one weight unit, so this feeds directly into the weight calculation.
+    pub fn serialized_size(&self) -> usize {
+        encode_varint(self.elements.len() as u64).len()
+            + self
+                .elements
+                .iter()
+                .map(|e| encode_varint(e.len() as u64).len() + e.len())
+                .sum::<usize>()
+    }
+}
+
+impl Index<usize> for Witness {
+    type Output = [u8];
+
+    fn index(&self, index: usize) -> &[u8] {
+        &self.elements[index]
+    }
+}
+
+impl From<Vec<Vec<u8>>> for Witness {
+    fn from(elements: Vec<Vec<u8>>) -> Self {
+        Witness::from_elements(elements)
+    }
+}
+
+#[cfg(test)]
+mod witness_tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_witness() {
+        let witness = Witness::new();
+        assert!(witness.is_empty());
+        assert_eq!(witness.serialize(), vec![0x00]);
+        assert_eq!(witness.serialized_size(), 1);
+        assert_eq!(witness.last(), None);
+    }
+
+    #[test]
+    fn test_p2wpkh_witness() {
+        let witness = Witness::p2wpkh(&[0x30; 71], &[0x02; 33]);
+        assert_eq!(witness.len(), 2);
+        assert_eq!(&witness[0], &[0x30; 71][..]);
+        assert_eq!(witness.last(), Some(&[0x02; 33][..]));
+        assert_eq!(witness.serialized_size(), 1 + 1 + 71 + 1 + 33);
+    }
+
+    #[test]
+    fn test_p2tr_script_spend_layout() {
+        let witness = Witness::p2tr_script_spend(vec![vec![1], vec![2]], &[0x51], &[0xc0; 33]);
+        assert_eq!(witness.len(), 4);
+        assert_eq!(witness.second_to_last(), Some(&[0x51][..]));
+        assert_eq!(witness.last(), Some(&[0xc0; 33][..]));
+    }
+
+    #[test]
+    fn test_annex() {
+        let witness = Witness::p2tr_key_spend(&[1; 64]);
+        assert_eq!(witness.taproot_annex(), None);
+        let with_annex = witness.clone().with_annex(vec![0x50, 7]).unwrap();
+        assert_eq!(with_annex.len(), 2);
+        assert_eq!(with_annex.taproot_annex(), Some(&[0x50, 7][..]));
+        // A lone element is a signature, whatever its first byte.
+        assert_eq!(Witness::p2tr_key_spend(&[0x50; 64]).taproot_annex(), None);
+        assert_eq!(
+            witness.clone().with_annex(vec![0x51]),
+            Err(Errors::InvalidAnnex)
+        );
+        assert_eq!(
+            Witness::new().with_annex(vec![0x50]),
+            Err(Errors::InvalidAnnex)
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let witness = Witness::from_elements(vec![vec![], vec![0xab; 300], vec![1, 2, 3]]);
+        let serialized = witness.serialize();
+        assert_eq!(serialized.len(), witness.serialized_size());
+        assert_eq!(Witness::parse(&mut serialized.as_slice()).unwrap(), witness);
+    }
+}
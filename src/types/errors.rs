@@ -5,4 +5,592 @@ pub enum Errors {
     #[error("Point is not included in the curve")]
     InvalidPoint,
 
-}
\ No newline at end of file
+    #[error("Private key must be between 1 and the curve order")]
+    InvalidPrivateKey,
+
+    #[error("Invalid signature encoding")]
+    InvalidSignature,
+
+    #[error("Unexpected end of data")]
+    UnexpectedEof,
+
+    #[error("Trailing data after the end of the encoded object")]
+    TrailingData,
+
+    #[error("Invalid hex string")]
+    InvalidHex,
+
+    #[error("Invalid segwit marker or flag")]
+    InvalidSegwitFlag,
+
+    #[error("Unknown output {0}")]
+    UnknownOutput(String),
+
+    #[error("Value out of range")]
+    ValueOutOfRange,
+
+    #[error("Outputs spend more than the inputs provide")]
+    NegativeFee,
+
+    #[error("Not enough value to pay the required fee")]
+    InsufficientFunds,
+
+    #[error("HTTP request failed: {0}")]
+    Http(String),
+
+    #[error("I/O error: {0}")]
+    Io(String),
+
+    #[error("Fetched transaction does not hash to {0}")]
+    TxidMismatch(String),
+
+    #[error("Coinbase scriptSig length {0} is outside 2..=100")]
+    BadCoinbaseLength(usize),
+
+    #[error("Non-coinbase transaction spends the null outpoint")]
+    NullPrevout,
+
+    #[error("Input index {0} is out of range")]
+    InputIndexOutOfRange(usize),
+
+    #[error("Invalid sighash type {0:#x}")]
+    InvalidSighashType(u32),
+
+    #[error("One spent output is needed per input")]
+    PrevoutsMismatch,
+
+    #[error("SIGHASH_SINGLE used without a corresponding output")]
+    SighashSingleWithoutOutput,
+
+    #[error("Invalid taproot control block")]
+    InvalidControlBlock,
+
+    #[error("A taproot annex must start with 0x50 and follow other witness elements")]
+    InvalidAnnex,
+
+    #[error("Compact target {0:#010x} is negative or overflows 256 bits")]
+    InvalidCompactTarget(u32),
+
+    #[error("Undo data does not match the block being disconnected")]
+    BadUndoData,
+
+    #[error("Invalid UTXO snapshot: {0}")]
+    InvalidSnapshot(&'static str),
+
+    #[error("Invalid taproot script tree: {0}")]
+    InvalidTaprootTree(&'static str),
+
+    #[error("Don't know how to sign script {0}")]
+    UnsupportedScript(String),
+
+    #[error("No key available to sign for {0}")]
+    MissingKey(String),
+
+    #[error("The spent output requires a {0} to be provided")]
+    MissingScript(&'static str),
+
+    #[error("Provided {0} does not match the spent output")]
+    ScriptMismatch(&'static str),
+
+    #[error("OP_RETURN payload of {0} bytes exceeds the standard size")]
+    OpReturnTooLarge(usize),
+
+    #[error("Transaction already has an OP_RETURN output")]
+    MultipleOpReturn,
+
+    #[error("Invalid script asm {0}")]
+    InvalidAsm(String),
+
+    #[error("Invalid miniscript: {0}")]
+    InvalidMiniscript(String),
+
+    #[error("Invalid descriptor: {0}")]
+    InvalidDescriptor(String),
+
+    #[error("Miniscript cannot be satisfied")]
+    MiniscriptUnsatisfiable,
+
+    #[error("Miniscript satisfaction is missing {0}")]
+    MiniscriptMissing(String),
+
+    #[error("Miniscript has no non-malleable satisfaction")]
+    MiniscriptMalleable,
+
+    #[error("The HTLC has no such spend path")]
+    InvalidHtlcSpend,
+
+    #[error("Invalid multisig: {0}")]
+    InvalidMultisig(&'static str),
+
+    #[error("Invalid compact block: {0}")]
+    InvalidCompactBlock(&'static str),
+
+    #[error("Bad block filter request: {0}")]
+    BadFilterRequest(&'static str),
+
+    #[error("Invalid merkle block: {0}")]
+    InvalidMerkleBlock(&'static str),
+
+    #[error("Invalid network message: {0}")]
+    InvalidEnvelope(&'static str),
+
+    #[error("Handshake failed: {0}")]
+    HandshakeFailed(&'static str),
+
+    #[error("Proxy connection failed: {0}")]
+    ProxyFailed(&'static str),
+
+    #[error("Connection limit reached")]
+    TooManyPeers,
+
+    #[error("No peer is connected")]
+    NoPeers,
+
+    #[error("Peer did not answer in time")]
+    PeerTimeout,
+
+    #[error("Peer sent invalid headers: {0}")]
+    InvalidPeerHeaders(ValidationError),
+
+    #[error("Peer sent an invalid block: {0}")]
+    InvalidPeerBlock(ValidationError),
+
+    #[error("Mined block was rejected: {0}")]
+    BlockRejected(ValidationError),
+
+    #[error("Cannot rescan: {0}")]
+    Rescan(String),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Upload target reached")]
+    UploadTargetReached,
+
+    #[error("Peer send buffer full")]
+    SendBufferFull,
+
+    #[error("Invalid HTTP request: {0}")]
+    InvalidHttpRequest(&'static str),
+
+    #[error("RPC error {}: {0}", .0.code())]
+    Rpc(RpcError),
+
+    #[error("Invalid RPC response: {0}")]
+    InvalidRpcResponse(&'static str),
+
+    #[error("Invalid ZMQ traffic: {0}")]
+    InvalidZmq(&'static str),
+}
+
+// Reasons a transaction, block or header fails consensus validation.
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationError {
+    #[error("Transaction has no inputs")]
+    NoInputs,
+
+    #[error("Transaction has no outputs")]
+    NoOutputs,
+
+    #[error("Transaction exceeds the maximum block weight")]
+    Oversize,
+
+    #[error("Output value out of range")]
+    OutputValueOutOfRange,
+
+    #[error("Input value out of range")]
+    InputValueOutOfRange,
+
+    #[error("Input {0} is spent twice")]
+    DuplicateInput(String),
+
+    #[error("Coinbase scriptSig length {0} is outside 2..=100")]
+    BadCoinbaseLength(usize),
+
+    #[error("Non-coinbase transaction spends the null outpoint")]
+    NullPrevout,
+
+    #[error("Coinbase transactions cannot spend existing outputs")]
+    UnexpectedCoinbase,
+
+    #[error("Input {0} is missing or already spent")]
+    MissingInput(String),
+
+    #[error("Input {0} spends an immature coinbase")]
+    PrematureCoinbaseSpend(String),
+
+    #[error("Inputs ({value_in}) are less than outputs ({value_out})")]
+    InsufficientFunds { value_in: u64, value_out: u64 },
+
+    #[error("Script verification failed for input {input_index}: {reason}")]
+    ScriptFailed { input_index: usize, reason: String },
+
+    #[error("Previous block {0} is unknown")]
+    PrevBlockNotFound(String),
+
+    #[error("Block bits do not match the expected proof of work target")]
+    BadDiffBits,
+
+    #[error("Block hash does not meet its proof of work target")]
+    HighHash,
+
+    #[error("Block timestamp is not after the median time of the previous blocks")]
+    TimeTooOld,
+
+    #[error("Block timestamp is more than two hours in the future")]
+    TimeTooNew,
+
+    #[error("Block at height {0} does not match the checkpoint")]
+    CheckpointMismatch(u32),
+
+    #[error("Header forks the chain below the last checkpoint")]
+    ForkBeforeCheckpoint,
+
+    #[error("Block merkle root does not match its transactions")]
+    BadMerkleRoot,
+
+    #[error("Block repeats transactions without changing its merkle root")]
+    DuplicateTransactions,
+
+    #[error("Block exceeds the maximum block weight")]
+    BlockOversize,
+
+    #[error("First transaction of the block is not a coinbase")]
+    MissingCoinbase,
+
+    #[error("Block has more than one coinbase")]
+    MultipleCoinbase,
+
+    #[error("Block exceeds the maximum sigop cost")]
+    TooManySigops,
+
+    #[error("Coinbase does not start with the block height")]
+    BadCoinbaseHeight,
+
+    #[error("Transaction {0} is not final")]
+    NonFinalTransaction(String),
+
+    #[error("UTXO set at the snapshot block does not match the loaded snapshot")]
+    SnapshotMismatch,
+
+    #[error("Coinbase pays {value}, more than the {limit} allowed")]
+    BadCoinbaseAmount { value: u64, limit: u64 },
+
+    #[error("Coinbase witness must be a single 32 byte reserved value")]
+    BadWitnessNonceSize,
+
+    #[error("Witness commitment does not match the block's witness data")]
+    BadWitnessMerkleMatch,
+
+    #[error("Block has witness data but no witness commitment")]
+    UnexpectedWitness,
+
+    #[error("Block could not be stored: {0}")]
+    Storage(String),
+
+    #[error("Headers do not form a chain")]
+    NonContinuousHeaders,
+
+    #[error("Invalid signet block solution: {0}")]
+    BadSignetSolution(String),
+}
+
+// Reasons a transaction is rejected by relay policy, named after Bitcoin Core's.
+#[derive(Debug, Error, PartialEq)]
+pub enum PolicyError {
+    #[error("version")]
+    Version,
+
+    #[error("tx-size")]
+    TxSize,
+
+    #[error("tx-size-small")]
+    TxSizeSmall,
+
+    #[error("scriptsig-size")]
+    ScriptSigSize,
+
+    #[error("scriptsig-not-pushonly")]
+    ScriptSigNotPushOnly,
+
+    #[error("scriptpubkey")]
+    ScriptPubKey,
+
+    #[error("dust")]
+    Dust,
+
+    #[error("multi-op-return")]
+    MultiOpReturn,
+
+    #[error("bad-txns-too-many-sigops")]
+    TooManySigops,
+
+    #[error("bad-witness-nonstandard")]
+    WitnessAnnex,
+}
+
+// Reasons the mempool refuses a transaction, named after Bitcoin Core's.
+#[derive(Debug, Error, PartialEq)]
+pub enum MempoolError {
+    #[error("txn-already-in-mempool")]
+    AlreadyInMempool,
+
+    #[error("{0}")]
+    Policy(PolicyError),
+
+    #[error("{0}")]
+    Invalid(ValidationError),
+
+    #[error("min relay fee not met")]
+    MinRelayFee,
+
+    #[error("mempool min fee not met")]
+    MempoolMinFee,
+
+    // Accepted, then evicted at once to keep the pool within its size.
+    #[error("mempool full")]
+    MempoolFull,
+
+    // A conflicting transaction doesn't signal replaceability.
+    #[error("txn-mempool-conflict")]
+    Conflict,
+
+    #[error("bad-txns-spends-conflicting-tx")]
+    SpendsConflictingTx,
+
+    #[error("replacement-adds-unconfirmed")]
+    ReplacementAddsUnconfirmed,
+
+    #[error("insufficient fee, {0}")]
+    InsufficientFee(String),
+
+    #[error("too many potential replacements: {0}")]
+    TooManyReplacements(usize),
+
+    // Not a child with its parents sorted before it, within the package limits.
+    #[error("{0}")]
+    InvalidPackage(&'static str),
+}
+
+// Errors returned to JSON-RPC callers, each with Bitcoin Core's error code.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum RpcError {
+    #[error("Parse error")]
+    Parse,
+
+    #[error("{0}")]
+    InvalidRequest(String),
+
+    #[error("Method not found")]
+    MethodNotFound,
+
+    #[error("{0}")]
+    Type(String),
+
+    #[error("{0}")]
+    InvalidParameter(String),
+
+    #[error("{0}")]
+    InvalidAddressOrKey(String),
+
+    #[error("{0}")]
+    Deserialization(String),
+
+    #[error("{0}")]
+    Transaction(String),
+
+    #[error("{0}")]
+    VerifyRejected(String),
+
+    #[error("{0}")]
+    VerifyAlreadyInChain(String),
+
+    #[error("{0}")]
+    Misc(String),
+
+    #[error("{0}")]
+    ClientNotConnected(String),
+
+    #[error("{0}")]
+    ClientInInitialDownload(String),
+
+    // Any other code a remote server answered with.
+    #[error("{1}")]
+    Other(i32, String),
+}
+
+// Errors answered to stratum miners, each with the code pools use for it.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum StratumError {
+    #[error("{0}")]
+    Other(String),
+
+    #[error("Job not found")]
+    JobNotFound,
+
+    #[error("Duplicate share")]
+    DuplicateShare,
+
+    #[error("Low difficulty share")]
+    LowDifficulty,
+
+    #[error("Unauthorized worker")]
+    Unauthorized,
+
+    #[error("Not subscribed")]
+    NotSubscribed,
+}
+
+// Reasons a script fails to execute, named after Bitcoin Core's ScriptError values.
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum ScriptError {
+    #[error("Script evaluated without error but finished with a false/empty top stack element")]
+    EvalFalse,
+
+    #[error("OP_RETURN was encountered")]
+    OpReturn,
+
+    #[error("Script failed an OP_VERIFY operation")]
+    Verify,
+
+    #[error("Script failed an OP_EQUALVERIFY operation")]
+    EqualVerify,
+
+    #[error("Script failed an OP_NUMEQUALVERIFY operation")]
+    NumEqualVerify,
+
+    #[error("Opcode missing or not understood")]
+    BadOpcode,
+
+    #[error("Attempted to use a disabled opcode")]
+    DisabledOpcode,
+
+    #[error("Operation not valid with the current stack size")]
+    InvalidStackOperation,
+
+    #[error("Operation not valid with the current altstack size")]
+    InvalidAltstackOperation,
+
+    #[error("Invalid OP_IF construction")]
+    UnbalancedConditional,
+
+    #[error("Negative locktime")]
+    NegativeLockTime,
+
+    #[error("Locktime requirement not satisfied")]
+    UnsatisfiedLockTime,
+
+    #[error("Data push larger than necessary")]
+    MinimalData,
+
+    #[error("OP_IF/NOTIF argument must be minimal")]
+    MinimalIf,
+
+    #[error("Script number overflow")]
+    NumberOverflow,
+
+    #[error("Non-minimally encoded script number")]
+    NonMinimalNumber,
+
+    #[error("NOPx reserved for soft-fork upgrades")]
+    DiscourageUpgradableNops,
+
+    #[error("Script failed an OP_CHECKSIGVERIFY operation")]
+    CheckSigVerify,
+
+    #[error("Script failed an OP_CHECKMULTISIGVERIFY operation")]
+    CheckMultisigVerify,
+
+    #[error("Pubkey count negative or limit exceeded")]
+    PubKeyCount,
+
+    #[error("Signature count negative or greater than pubkey count")]
+    SigCount,
+
+    #[error("Non-canonical DER signature")]
+    SigDer,
+
+    #[error("Non-canonical signature: S value is unnecessarily high")]
+    SigHighS,
+
+    #[error("Signature hash type missing or not understood")]
+    SigHashType,
+
+    #[error("Public key is neither compressed or uncompressed")]
+    PubKeyType,
+
+    #[error("Using non-compressed keys in segwit")]
+    WitnessPubKeyType,
+
+    #[error("Dummy CHECKMULTISIG argument must be zero")]
+    SigNullDummy,
+
+    #[error("Signature must be zero for failed CHECK(MULTI)SIG operation")]
+    SigNullFail,
+
+    #[error("Only push operators allowed in signatures")]
+    SigPushOnly,
+
+    #[error("Witness program has incorrect length")]
+    WitnessProgramWrongLength,
+
+    #[error("Witness program was passed an empty witness")]
+    WitnessProgramWitnessEmpty,
+
+    #[error("Witness program hash mismatch")]
+    WitnessProgramMismatch,
+
+    #[error("Witness requires only-redeemscript scriptSig")]
+    WitnessMalleatedP2SH,
+
+    #[error("Witness requires empty scriptSig")]
+    WitnessMalleated,
+
+    #[error("Witness provided for non-witness script")]
+    WitnessUnexpected,
+
+    #[error("Stack size must be exactly one after execution")]
+    CleanStack,
+
+    #[error("Script is too big")]
+    ScriptSize,
+
+    #[error("Push value size limit exceeded")]
+    PushSize,
+
+    #[error("Operation limit exceeded")]
+    OpCount,
+
+    #[error("Stack size limit exceeded")]
+    StackSize,
+
+    #[error("Witness version reserved for soft-fork upgrades")]
+    DiscourageUpgradableWitnessProgram,
+
+    #[error("Invalid Schnorr signature size")]
+    SchnorrSigSize,
+
+    #[error("Invalid Schnorr signature hash type")]
+    SchnorrSigHashType,
+
+    #[error("Invalid Schnorr signature")]
+    SchnorrSig,
+
+    #[error("Invalid Taproot control block size")]
+    TaprootWrongControlSize,
+
+    #[error("Too much signature validation relative to witness weight")]
+    TapscriptValidationWeight,
+
+    #[error("OP_CHECKMULTISIG(VERIFY) is not available in tapscript")]
+    TapscriptCheckMultisig,
+
+    #[error("OP_SUCCESSx reserved for soft-fork upgrades")]
+    DiscourageOpSuccess,
+
+    #[error("Taproot version reserved for soft-fork upgrades")]
+    DiscourageUpgradableTaprootVersion,
+
+    #[error("Public key version reserved for soft-fork upgrades")]
+    DiscourageUpgradablePubKeyType,
+}
@@ -1 +1 @@
-pub mod errors;
\ No newline at end of file
+pub mod errors;
@@ -0,0 +1,491 @@
+// Consensus validation of transactions against a view of the UTXO set.
+use crate::script::flags::VerificationFlags;
+use crate::script::interpreter::TransactionSignatureChecker;
+use crate::script::sigops::witness_sigop_count;
+use crate::script::verify::verify_script;
+use crate::script::SignatureCache;
+use crate::transaction::weight::WITNESS_SCALE_FACTOR;
+use crate::transaction::{OutPoint, Transaction, TxOut};
+use crate::types::errors::{Errors, ValidationError};
+use std::collections::{HashMap, HashSet};
+
+pub const COIN: u64 = 100_000_000;
+pub const MAX_MONEY: u64 = 21_000_000 * COIN;
+pub const COINBASE_MATURITY: u32 = 100;
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+
+// An unspent output together with the context needed to validate spending it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coin {
+    pub output: TxOut,
+    // Height of the block that created the output.
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+pub trait UtxoView {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin>;
+}
+
+impl UtxoView for HashMap<OutPoint, Coin> {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.get(outpoint).cloned()
+    }
+}
+
+// Executes the scriptSig/witness of one input against the output it spends.
+// prevouts holds the output spent by each input, as taproot signatures commit to all
+// of them. flags is the bitset of script verification rules in force.
+pub trait ScriptVerifier {
+    fn verify_input(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        flags: VerificationFlags,
+    ) -> Result<(), String>;
+}
+
+// Accepts every input, for blocks whose scripts are assumed valid.
+pub struct NoScriptVerification;
+
+impl ScriptVerifier for NoScriptVerification {
+    fn verify_input(
+        &self,
+        _: &Transaction,
+        _: usize,
+        _: &[TxOut],
+        _: VerificationFlags,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Verifies inputs with this crate's script interpreter, optionally looking
+// signatures up in a cache (see TransactionSignatureChecker::with_signature_cache).
+#[derive(Default)]
+pub struct ScriptInterpreter<'a> {
+    signature_cache: Option<(&'a SignatureCache, bool)>,
+}
+
+impl<'a> ScriptInterpreter<'a> {
+    pub fn new() -> Self {
+        ScriptInterpreter::default()
+    }
+
+    pub fn with_signature_cache(mut self, cache: &'a SignatureCache, store: bool) -> Self {
+        self.signature_cache = Some((cache, store));
+        self
+    }
+}
+
+impl ScriptVerifier for ScriptInterpreter<'_> {
+    fn verify_input(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        flags: VerificationFlags,
+    ) -> Result<(), String> {
+        let input = &tx.inputs[input_index];
+        let prevout = &prevouts[input_index];
+        let mut checker = TransactionSignatureChecker::new(tx, input_index, prevout.value)
+            .with_prevouts(prevouts);
+        if let Some((cache, store)) = self.signature_cache {
+            checker = checker.with_signature_cache(cache, store);
+        }
+        verify_script(
+            &input.script_sig,
+            &prevout.script_pubkey,
+            &input.witness,
+            flags,
+            &checker,
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+// Context free checks (CheckTransaction in Bitcoin Core).
+pub fn check_transaction(tx: &Transaction) -> Result<(), ValidationError> {
+    if tx.inputs.is_empty() {
+        return Err(ValidationError::NoInputs);
+    }
+    if tx.outputs.is_empty() {
+        return Err(ValidationError::NoOutputs);
+    }
+    if tx.stripped_size() * 4 > MAX_BLOCK_WEIGHT {
+        return Err(ValidationError::Oversize);
+    }
+
+    let mut total: u64 = 0;
+    for output in &tx.outputs {
+        if output.value > MAX_MONEY {
+            return Err(ValidationError::OutputValueOutOfRange);
+        }
+        total += output.value;
+        if total > MAX_MONEY {
+            return Err(ValidationError::OutputValueOutOfRange);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for input in &tx.inputs {
+        if !seen.insert(input.previous_output) {
+            return Err(ValidationError::DuplicateInput(
+                input.previous_output.to_string(),
+            ));
+        }
+    }
+
+    tx.check_coinbase_inputs().map_err(|e| match e {
+        Errors::BadCoinbaseLength(len) => ValidationError::BadCoinbaseLength(len),
+        _ => ValidationError::NullPrevout,
+    })
+}
+
+// Full validation of a non-coinbase transaction to be included at spend_height.
+// Returns the fee paid on success.
+pub fn validate_transaction(
+    tx: &Transaction,
+    utxos: &impl UtxoView,
+    spend_height: u32,
+    flags: VerificationFlags,
+    verifier: &impl ScriptVerifier,
+) -> Result<u64, ValidationError> {
+    check_transaction(tx)?;
+    if tx.is_coinbase() {
+        return Err(ValidationError::UnexpectedCoinbase);
+    }
+
+    let mut coins = Vec::with_capacity(tx.inputs.len());
+    let mut value_in: u64 = 0;
+    for input in &tx.inputs {
+        let outpoint = &input.previous_output;
+        let coin = utxos
+            .get_coin(outpoint)
+            .ok_or_else(|| ValidationError::MissingInput(outpoint.to_string()))?;
+        if coin.is_coinbase && spend_height.saturating_sub(coin.height) < COINBASE_MATURITY {
+            return Err(ValidationError::PrematureCoinbaseSpend(
+                outpoint.to_string(),
+            ));
+        }
+        if coin.output.value > MAX_MONEY {
+            return Err(ValidationError::InputValueOutOfRange);
+        }
+        value_in += coin.output.value;
+        if value_in > MAX_MONEY {
+            return Err(ValidationError::InputValueOutOfRange);
+        }
+        coins.push(coin);
+    }
+
+    // check_transaction already bounded the output total by MAX_MONEY.
+    let value_out: u64 = tx.outputs.iter().map(|output| output.value).sum();
+    if value_in < value_out {
+        return Err(ValidationError::InsufficientFunds {
+            value_in,
+            value_out,
+        });
+    }
+
+    let prevouts: Vec<TxOut> = coins.into_iter().map(|coin| coin.output).collect();
+    for input_index in 0..prevouts.len() {
+        verifier
+            .verify_input(tx, input_index, &prevouts, flags)
+            .map_err(|reason| ValidationError::ScriptFailed {
+                input_index,
+                reason,
+            })?;
+    }
+
+    Ok(value_in - value_out)
+}
+
+// Sigops of every scriptSig and scriptPubKey, counted without looking at what is
+// spent (GetLegacySigOpCount in Bitcoin Core).
+pub fn legacy_sigop_count(tx: &Transaction) -> usize {
+    let script_sigs = tx.inputs.iter().map(|input| &input.script_sig);
+    let script_pubkeys = tx.outputs.iter().map(|output| &output.script_pubkey);
+    script_sigs
+        .chain(script_pubkeys)
+        .map(|script| script.sigop_count(false))
+        .sum()
+}
+
+// Sigops of the redeem scripts of P2SH outputs spent. prevouts holds the output
+// spent by each input.
+pub fn p2sh_sigop_count(tx: &Transaction, prevouts: &[TxOut]) -> usize {
+    if tx.is_coinbase() {
+        return 0;
+    }
+    tx.inputs
+        .iter()
+        .zip(prevouts)
+        .map(|(input, prevout)| prevout.script_pubkey.p2sh_sigop_count(&input.script_sig))
+        .sum()
+}
+
+// Weighted sigops of a transaction, limited to MAX_BLOCK_SIGOPS_COST per block.
+// Legacy and P2SH sigops cost four times as much as witness ones, like bytes.
+pub fn transaction_sigop_cost(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    flags: VerificationFlags,
+) -> usize {
+    let mut cost = legacy_sigop_count(tx) * WITNESS_SCALE_FACTOR;
+    if tx.is_coinbase() {
+        return cost;
+    }
+    if flags.contains(VerificationFlags::P2SH) {
+        cost += p2sh_sigop_count(tx, prevouts) * WITNESS_SCALE_FACTOR;
+    }
+    for (input, prevout) in tx.inputs.iter().zip(prevouts) {
+        cost += witness_sigop_count(
+            &input.script_sig,
+            &prevout.script_pubkey,
+            &input.witness,
+            flags,
+        );
+    }
+    cost
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use super::*;
+    use crate::helper::hash160;
+    use crate::script::{Opcode, Script};
+    use crate::transaction::TxIn;
+
+    struct AcceptAll;
+    impl ScriptVerifier for AcceptAll {
+        fn verify_input(
+            &self,
+            _: &Transaction,
+            _: usize,
+            _: &[TxOut],
+            _: VerificationFlags,
+        ) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    // Only accepts spends of outputs whose script is OP_TRUE.
+    struct OpTrueOnly;
+    impl ScriptVerifier for OpTrueOnly {
+        fn verify_input(
+            &self,
+            _: &Transaction,
+            input_index: usize,
+            prevouts: &[TxOut],
+            _: VerificationFlags,
+        ) -> Result<(), String> {
+            if prevouts[input_index].script_pubkey[..] == [0x51] {
+                Ok(())
+            } else {
+                Err("script evaluated to false".to_string())
+            }
+        }
+    }
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint::new([n; 32], 0)
+    }
+
+    fn coin(value: u64, height: u32, is_coinbase: bool) -> Coin {
+        Coin {
+            output: TxOut::new(value, vec![0x51].into()),
+            height,
+            is_coinbase,
+        }
+    }
+
+    fn spend(prevouts: &[OutPoint], values: &[u64]) -> Transaction {
+        Transaction::new(
+            2,
+            prevouts
+                .iter()
+                .map(|p| TxIn::new(*p, Script::new(), 0xffffffff))
+                .collect(),
+            values
+                .iter()
+                .map(|v| TxOut::new(*v, vec![0x51].into()))
+                .collect(),
+            0,
+        )
+    }
+
+    fn utxos() -> HashMap<OutPoint, Coin> {
+        HashMap::from([
+            (outpoint(1), coin(10_000, 50, false)),
+            (outpoint(2), coin(5_000, 50, false)),
+            (outpoint(3), coin(50 * COIN, 150, true)),
+        ])
+    }
+
+    #[test]
+    fn test_valid_transaction_returns_fee() {
+        let tx = spend(&[outpoint(1), outpoint(2)], &[14_000]);
+        assert_eq!(
+            validate_transaction(&tx, &utxos(), 200, VerificationFlags::NONE, &AcceptAll),
+            Ok(1_000)
+        );
+    }
+
+    #[test]
+    fn test_context_free_failures() {
+        assert_eq!(
+            check_transaction(&spend(&[], &[1])),
+            Err(ValidationError::NoInputs)
+        );
+        assert_eq!(
+            check_transaction(&spend(&[outpoint(1)], &[])),
+            Err(ValidationError::NoOutputs)
+        );
+        assert_eq!(
+            check_transaction(&spend(&[outpoint(1)], &[MAX_MONEY + 1])),
+            Err(ValidationError::OutputValueOutOfRange)
+        );
+        assert_eq!(
+            check_transaction(&spend(&[outpoint(1)], &[MAX_MONEY, 1])),
+            Err(ValidationError::OutputValueOutOfRange)
+        );
+        assert_eq!(
+            check_transaction(&spend(&[outpoint(1)], &[MAX_MONEY, u64::MAX])),
+            Err(ValidationError::OutputValueOutOfRange)
+        );
+        assert_eq!(
+            check_transaction(&spend(&[outpoint(1), outpoint(1)], &[1])),
+            Err(ValidationError::DuplicateInput(outpoint(1).to_string()))
+        );
+        assert_eq!(
+            check_transaction(&spend(&[outpoint(1), OutPoint::null()], &[1])),
+            Err(ValidationError::NullPrevout)
+        );
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let tx = spend(&[outpoint(9)], &[1]);
+        assert_eq!(
+            validate_transaction(&tx, &utxos(), 200, VerificationFlags::NONE, &AcceptAll),
+            Err(ValidationError::MissingInput(outpoint(9).to_string()))
+        );
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let tx = spend(&[outpoint(3)], &[COIN]);
+        assert_eq!(
+            validate_transaction(&tx, &utxos(), 249, VerificationFlags::NONE, &AcceptAll),
+            Err(ValidationError::PrematureCoinbaseSpend(
+                outpoint(3).to_string()
+            ))
+        );
+        assert_eq!(
+            validate_transaction(&tx, &utxos(), 250, VerificationFlags::NONE, &AcceptAll),
+            Ok(49 * COIN)
+        );
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        let tx = spend(&[outpoint(2)], &[5_001]);
+        assert_eq!(
+            validate_transaction(&tx, &utxos(), 200, VerificationFlags::NONE, &AcceptAll),
+            Err(ValidationError::InsufficientFunds {
+                value_in: 5_000,
+                value_out: 5_001
+            })
+        );
+    }
+
+    #[test]
+    fn test_script_failure_reports_input() {
+        let mut view = utxos();
+        view.get_mut(&outpoint(2)).unwrap().output.script_pubkey = vec![0x00].into();
+        let tx = spend(&[outpoint(1), outpoint(2)], &[1_000]);
+
+        assert_eq!(
+            validate_transaction(&tx, &view, 200, VerificationFlags::NONE, &OpTrueOnly),
+            Err(ValidationError::ScriptFailed {
+                input_index: 1,
+                reason: "script evaluated to false".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_coinbase_is_not_validated_against_utxos() {
+        let coinbase = Transaction::new(
+            1,
+            vec![TxIn::new(
+                OutPoint::null(),
+                vec![0x01, 0x01].into(),
+                0xffffffff,
+            )],
+            vec![TxOut::new(50 * COIN, Script::new())],
+            0,
+        );
+        assert_eq!(check_transaction(&coinbase), Ok(()));
+        assert_eq!(
+            validate_transaction(&coinbase, &utxos(), 1, VerificationFlags::NONE, &AcceptAll),
+            Err(ValidationError::UnexpectedCoinbase)
+        );
+    }
+
+    #[test]
+    fn test_transaction_sigop_cost() {
+        // 1 <key> <key> 2 OP_CHECKMULTISIG behind P2SH, and a P2WPKH.
+        let mut redeem_script = Script::new();
+        redeem_script
+            .push_int(1)
+            .push_slice(&[0x02; 33])
+            .push_slice(&[0x03; 33])
+            .push_int(2)
+            .push_opcode(Opcode::OP_CHECKMULTISIG);
+        let mut script_sig = Script::new();
+        script_sig.push_int(0).push_slice(&redeem_script);
+        let prevouts = vec![
+            TxOut::new(10_000, Script::new_p2sh(&hash160(&redeem_script))),
+            TxOut::new(10_000, Script::new_p2wpkh(&[0; 20])),
+        ];
+        let mut tx = spend(&[outpoint(1), outpoint(2)], &[1_000]);
+        tx.inputs[0].script_sig = script_sig;
+        tx.outputs[0].script_pubkey = Script::new_p2pkh(&[0; 20]);
+
+        assert_eq!(legacy_sigop_count(&tx), 1);
+        assert_eq!(p2sh_sigop_count(&tx, &prevouts), 2);
+        assert_eq!(
+            transaction_sigop_cost(&tx, &prevouts, VerificationFlags::NONE),
+            4
+        );
+        assert_eq!(
+            transaction_sigop_cost(&tx, &prevouts, VerificationFlags::P2SH),
+            4 + 8
+        );
+        assert_eq!(
+            transaction_sigop_cost(
+                &tx,
+                &prevouts,
+                VerificationFlags::P2SH | VerificationFlags::WITNESS
+            ),
+            4 + 8 + 1
+        );
+
+        // Spending P2PKH and bare multisig outputs counts no P2SH sigops, those of the
+        // scripts spent having been counted in the transactions creating them.
+        let prevouts = vec![
+            TxOut::new(10_000, Script::new_p2pkh(&[0; 20])),
+            TxOut::new(10_000, redeem_script),
+        ];
+        let mut tx = spend(&[outpoint(1), outpoint(2)], &[1_000]);
+        tx.outputs[0].script_pubkey = Script::new_p2pkh(&[0; 20]);
+        assert_eq!(p2sh_sigop_count(&tx, &prevouts), 0);
+        assert_eq!(
+            transaction_sigop_cost(&tx, &prevouts, VerificationFlags::P2SH),
+            4
+        );
+    }
+}